@@ -17,11 +17,17 @@ use std::path::PathBuf;
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
-    // on the linker search path.
+    // on the linker search path. The `fwupdate` feature keeps the
+    // application out of the upper half of flash, used for staging updates.
+    let memory: &[u8] = if env::var_os("CARGO_FEATURE_FWUPDATE").is_some() {
+        include_bytes!("memory-fwupdate.x")
+    } else {
+        include_bytes!("memory.x")
+    };
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(memory)
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
@@ -30,6 +36,7 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=memory-fwupdate.x");
 
     // Specify linker arguments.
 
@@ -0,0 +1,7 @@
+MEMORY
+{
+  /* NOTE 1 K = 1 KiBi = 1024 bytes */
+  /* The upper 128K of flash (from 0x20000) is the fwupdate staging area */
+  FLASH : ORIGIN = 0x00004000, LENGTH = 112K
+  RAM : ORIGIN = 0x20000000, LENGTH = 32K
+}
@@ -0,0 +1,113 @@
+//! Self-monitoring CAN node.
+//!
+//! Prints the die temperature next to the load of the bus and the error
+//! counters once a second, so heating from a busy bus or errors that rise
+//! with temperature show up in the same log.
+
+#![no_std]
+#![no_main]
+
+use core::fmt::Write as _;
+
+use embedded_can::Frame as _;
+use embedded_io::Write as W;
+use panic_halt as _;
+
+use cortex_m_rt::entry;
+use heapless::String;
+use uno_r4_rust::{adc, bind_interrupts, can, clk, cycles, gpio, uart};
+
+bind_interrupts!(struct Irq {
+    IEL4 => uart::TXI_Handler<ra4m1::SCI2>;
+    IEL5 => uart::TEI_Handler<ra4m1::SCI2>;
+    IEL6 => uart::RXI_Handler<ra4m1::SCI2>;
+    IEL7 => uart::ERI_Handler<ra4m1::SCI2>;
+    IEL8 => can::TxHandler<ra4m1::CAN0>;
+});
+
+const ICLK_HZ: u32 = 48_000_000;
+const PCLKB_HZ: u32 = 48_000_000;
+// 12 clocks per time quantum and 8 time quanta per bit
+const BRP: u16 = 12;
+const BITRATE: u32 = PCLKB_HZ / (BRP as u32 * 8);
+
+// Bits of a frame on the bus, without stuff bits, so the load is a lower bound
+fn frame_bits(frame: &can::Frame) -> u32 {
+    let data = if frame.is_remote_frame() {
+        0
+    } else {
+        8 * frame.dlc() as u32
+    };
+    // SOF to the end of the interframe space, extended IDs add 20 bits
+    let overhead = if frame.is_extended() { 67 } else { 47 };
+    overhead + data
+}
+
+#[entry]
+fn main() -> ! {
+    let p = unsafe { ra4m1::Peripherals::steal() };
+    let pins = gpio::Pins::new(p.PFS);
+
+    let uart: uart::Uart<_, 128, 16> = uart::Uart::new(p.SCI2, pins.p301, pins.p302, Irq);
+    let (mut tx, _rx) = uart.split();
+
+    unsafe { cortex_m::interrupt::enable() }
+
+    // Enable usb 3.3V to rs232 converter
+    clk::enable_peripheral(clk::Peripheral::Usbfs);
+    p.USBFS.usbmc.write(|w| w.vdcen()._1());
+    cycles::start(ICLK_HZ);
+    cycles::busy_wait_ms(20);
+
+    let mut adc = adc::Adc::new(p.ADC140, Default::default());
+
+    let mut can = can::Can::new(
+        p.CAN0,
+        pins.p102,
+        pins.p103,
+        can::BitConfig::new_checked(false, BRP, 5, 2, 1).unwrap(),
+        Irq,
+    )
+    .unwrap();
+    // Receive everything into mailbox 0
+    let mut mailbox = can::MailboxConfig::default();
+    mailbox.set_mailbox_receiver(0);
+    can.configure_mailboxes(mailbox).unwrap();
+    let can = can.start().unwrap();
+
+    // Time the reports with the cycle counter
+    let mut last = cycles::now();
+    let mut bits: u32 = 0;
+
+    loop {
+        if let Some(frame) = can.try_receive_frame() {
+            bits += frame_bits(&frame);
+        }
+
+        if cycles::elapsed(last) < ICLK_HZ {
+            continue;
+        }
+        last = cycles::now();
+
+        let temperature = adc.read_temperature();
+        let snapshot = can.register_snapshot();
+        // Hundredths of a percent of the bus time
+        let load = bits as u64 * 10_000 / BITRATE as u64;
+        bits = 0;
+
+        let sign = if temperature < 0 { "-" } else { "" };
+        let mut line: String<96> = String::new();
+        let _ = writeln!(
+            line,
+            "temp {}{}.{} C  load {}.{:02} %  REC {} TEC {}",
+            sign,
+            temperature.unsigned_abs() / 10,
+            temperature.unsigned_abs() % 10,
+            load / 100,
+            load % 100,
+            snapshot.recr,
+            snapshot.tecr,
+        );
+        let _ = tx.write_all(line.as_bytes());
+    }
+}
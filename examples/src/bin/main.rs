@@ -6,7 +6,7 @@ use embedded_io::Write as W;
 use panic_halt as _;
 
 use cortex_m_rt::entry;
-use uno_r4_rust::{bind_interrupts, can, uart};
+use uno_r4_rust::{bind_interrupts, can, clk, cycles, gpio, uart};
 
 bind_interrupts!(struct Irq {
     IEL4 => uart::TXI_Handler<ra4m1::SCI2>;
@@ -20,45 +20,46 @@ bind_interrupts!(struct Irq {
 fn main() -> ! {
     // Get access to the peripherals
     let p = unsafe { ra4m1::Peripherals::steal() };
+    let pins = gpio::Pins::new(p.PFS);
 
     // Set p111 as an output
     p.PORT1.pdr().write(|w| unsafe { w.bits(1 << 11) });
 
-    let mut tx_buf = [0u8; 64];
-    let mut rx_buf = [0u8; 64];
-    let uart = uart::Uart::new(p.SCI2, &mut tx_buf, &mut rx_buf, Irq);
+    let uart: uart::Uart<_, 64, 64> = uart::Uart::new(p.SCI2, pins.p301, pins.p302, Irq);
     let (mut tx, rx) = uart.split();
 
     // Enable interrupts
     unsafe { cortex_m::interrupt::enable() }
 
     // Enable usb 3.3V to rs232 converter
-    p.MSTP.mstpcrb.modify(|_, w| {
-        // Enable USBFS
-        w.mstpb11()._0()
-    });
+    // Enable USBFS
+    clk::enable_peripheral(clk::Peripheral::Usbfs);
     p.USBFS.usbmc.write(|w| w.vdcen()._1());
 
     // wait for a bit to stabilize the USB power
-    cortex_m::asm::delay(1_000_000);
+    cycles::start(48_000_000);
+    cycles::busy_wait_ms(20);
 
     tx.write_all("\nHello from RA4M1!\n".as_bytes()).unwrap();
 
     // can init
     let mut can = can::Can::new(
         p.CAN0,
+        pins.p102,
+        pins.p103,
         can::BitConfig::new_checked(false, 3, 5, 2, 1).unwrap(),
         Irq,
-    );
+    )
+    .unwrap();
 
     tx.write_all(b"CAN initialized\n").unwrap();
 
     let mut mailbox = can::MailboxConfig::default();
     mailbox.set_mailbox_receiver(0);
     mailbox.enable_all_interrupts();
-    can.configure_mailboxes(mailbox);
+    can.configure_mailboxes(mailbox).unwrap();
 
-    can.start();
+    let can = can.start().unwrap();
 
     // Send a test frame
     // let test_frame = Frame::new(Id::Standard(StandardId::new(0x123).unwrap()), &[0xFF]).unwrap();
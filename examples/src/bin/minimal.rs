@@ -0,0 +1,32 @@
+//! Smallest useful image, blinks the D13 LED.
+//!
+//! Only the pins and the cycle counter are used, so none of the drivers end
+//! up in the binary. `just size_minimal` builds it with the `minimal` profile
+//! and checks it stays under 8 KB, the size left for a bootloader.
+
+#![no_std]
+#![no_main]
+
+use panic_halt as _;
+
+use cortex_m_rt::entry;
+use uno_r4_rust::cycles;
+use uno_r4_rust::gpio::{self, PinConfig};
+
+const ICLK_HZ: u32 = 48_000_000;
+
+#[entry]
+fn main() -> ! {
+    let p = unsafe { ra4m1::Peripherals::steal() };
+    let mut pins = gpio::Pins::new(p.PFS);
+
+    gpio::configure(&mut pins.p111, PinConfig::output());
+    cycles::start(ICLK_HZ);
+
+    let mut high = false;
+    loop {
+        high = !high;
+        gpio::set_level(&mut pins.p111, high);
+        cycles::busy_wait_ms(500);
+    }
+}
@@ -21,7 +21,7 @@ mod app {
 
     use cortex_m::asm::wfi;
     use embedded_io::Write as _;
-    use uno_r4_rust::{bind_interrupts, can, uart};
+    use uno_r4_rust::{bind_interrupts, can, clk, cycles, gpio, uart};
 
     use rtic_monotonics::{
         fugit::Duration, rtic_time::embedded_hal::delay::DelayNs, systick::prelude::*,
@@ -46,13 +46,15 @@ mod app {
     // Local resources go here
     #[local]
     struct Local {
-        // TODO: Add resources
+        // Kept running, dropping it stops the CAN module
+        can: can::Can<can::Running, ra4m1::CAN0>,
     }
 
     #[init]
     fn init(cx: init::Context) -> (Shared, Local) {
         // Get access to the peripherals
         let p = unsafe { ra4m1::Peripherals::steal() };
+        let pins = gpio::Pins::new(p.PFS);
 
         // Start monotonic
         Mono::start(cx.core.SYST, 48_000_000);
@@ -60,38 +62,38 @@ mod app {
         // Set p111 as an output
         p.PORT1.pdr().write(|w| unsafe { w.bits(1 << 11) });
 
-        let mut tx_buf = [0u8; 64];
-        let mut rx_buf = [0u8; 64];
-        let uart = uart::Uart::new(p.SCI2, &mut tx_buf, &mut rx_buf, Irq);
+        let uart: uart::Uart<_, 64, 64> = uart::Uart::new(p.SCI2, pins.p301, pins.p302, Irq);
         let (mut tx, rx) = uart.split();
 
         // Enable usb 3.3V to rs232 converter
-        p.MSTP.mstpcrb.modify(|_, w| {
-            // Enable USBFS
-            w.mstpb11()._0()
-        });
+        // Enable USBFS
+        clk::enable_peripheral(clk::Peripheral::Usbfs);
         p.USBFS.usbmc.write(|w| w.vdcen()._1());
 
         // wait for a bit to stabilize the USB power
-        cortex_m::asm::delay(1_000_000);
+        cycles::start(48_000_000);
+        cycles::busy_wait_ms(20);
 
         tx.write_all("\nHello from RA4M1!\n".as_bytes()).unwrap();
 
         // can init
         let mut can = can::Can::new(
             p.CAN0,
+            pins.p102,
+            pins.p103,
             can::BitConfig::new_checked(false, 3, 5, 2, 1).unwrap(),
             Irq,
-        );
+        )
+        .unwrap();
 
         tx.write_all(b"CAN initialized\n").unwrap();
 
         let mut mailbox = can::MailboxConfig::default();
         mailbox.set_mailbox_receiver(0);
         mailbox.enable_all_interrupts();
-        can.configure_mailboxes(mailbox);
+        can.configure_mailboxes(mailbox).unwrap();
 
-        can.start();
+        let can = can.start().unwrap();
 
         // Send a test frame
         // let test_frame = Frame::new(Id::Standard(StandardId::new(0x123).unwrap()), &[0xFF]).unwrap();
@@ -106,17 +108,19 @@ mod app {
                 // Initialization of shared resources go here
                 uart_tx: tx,
             },
-            Local {
-                // Initialization of local resources go here
-            },
+            Local { can },
         )
     }
 
     // Optional idle, can be removed if not needed.
-    #[idle()]
-    fn idle(_cx: idle::Context) -> ! {
+    #[idle(local = [can])]
+    fn idle(cx: idle::Context) -> ! {
         // This is the idle task, it runs when no other tasks are ready to run.
+        let can = cx.local.can;
         loop {
+            if let Some(frame) = can.try_receive_frame() {
+                can.send_frame(frame).ok();
+            }
             unsafe {
                 ra4m1::Peripherals::steal().PORT1.podr().write(
                     |w| w.bits(1 << 11), // Set p111 high
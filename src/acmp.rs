@@ -0,0 +1,337 @@
+//! Low-power analog comparator (ACMPLP)
+//!
+//! Two comparators, each comparing its CMPINn pin with a reference: the
+//! internal 1.44 V reference, a channel of the 8-bit DAC or a CMPREFn pin.
+//! Edges of the result raise an interrupt and an event, which can be linked
+//! through the [ELC](crate::elc) to start a timer or an ADC conversion
+//! without the CPU.
+//!
+//! There is no hysteresis in the comparator itself. The noise filter only
+//! changes the result once three samples agree, which stops chatter from
+//! fast noise, but a slowly crossing input still needs hysteresis from
+//! outside, e.g. by moving the DAC reference in the edge callback.
+//!
+//! ```ignore
+//! bind_interrupts!(struct Irq {
+//!     IEL11 => acmp::EdgeHandler<acmp::Ch0>;
+//! });
+//!
+//! let (mut cmp, _) = acmp::split(p.ACMPLP, acmp::Speed::High);
+//! cmp.enable(pins.p100, acmp::Reference::Dac8(128), Default::default());
+//! cmp.enable_interrupt(Irq, Some(on_edge));
+//! ```
+
+use core::cell::Cell;
+use core::marker::PhantomData;
+
+use critical_section::Mutex;
+
+use crate::clk::{ClockGuard, Peripheral};
+use crate::events::Event;
+use crate::gpio::{self, Pin};
+use crate::interrupts::{Binding, Handler, clear_interrupt, map_and_enable_interrupt};
+
+const ACMPLP: usize = 0x4008_5E00;
+const COMPMDR: *mut u8 = ACMPLP as *mut u8;
+const COMPFIR: *mut u8 = (ACMPLP + 0x01) as *mut u8;
+const COMPOCR: *mut u8 = (ACMPLP + 0x02) as *mut u8;
+const COMPSEL0: *mut u8 = (ACMPLP + 0x04) as *mut u8;
+const COMPSEL1: *mut u8 = (ACMPLP + 0x05) as *mut u8;
+
+const DAC8: usize = 0x4009_E000;
+// DACSn, one byte per channel
+const DACS0: *mut u8 = DAC8 as *mut u8;
+const DAM: *mut u8 = (DAC8 + 0x03) as *mut u8;
+
+// COMPMDR bits of channel 0, channel 1 is 4 bits up
+const COMPMDR_ENB: u8 = 1 << 0;
+const COMPMDR_VRF: u8 = 1 << 2;
+const COMPMDR_MON: u8 = 1 << 3;
+// COMPOCR bits of channel 0, channel 1 is 4 bits up
+const COMPOCR_OE: u8 = 1 << 1;
+const COMPOCR_OP: u8 = 1 << 2;
+const COMPOCR_SPDMD: u8 = 1 << 7;
+// COMPSEL0/1 selection of the port 1 and port 5 pins, and the DAC
+const SEL_PORT1: u8 = 0b001;
+const SEL_DAC8: u8 = 0b010;
+const SEL_PORT5: u8 = 0b100;
+// DAM.DACE0, channel 1 is the next bit
+const DAM_DACE0: u8 = 1 << 4;
+
+/// Comparator response speed, shared by both comparators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Speed {
+    /// Up to 5 us delay, lowest current
+    Low,
+    /// Up to 1.2 us delay
+    High,
+}
+
+/// Reference voltage of a comparator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reference {
+    /// Internal reference, 1.44 V typical
+    Vref,
+    /// DAC8 channel of the comparator, VCC * value / 256
+    Dac8(u8),
+    /// CMPREFn pin routed with [`Comparator::route_reference`]
+    Pin,
+}
+
+/// Sampling clock of the noise filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Filter {
+    Off = 0b00,
+    Pclkb = 0b01,
+    Pclkb8 = 0b10,
+    Pclkb32 = 0b11,
+}
+
+/// Edges of the result that raise the interrupt and event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    /// Input goes above the reference
+    Rising,
+    /// Input goes below the reference
+    Falling,
+    Both,
+}
+
+/// Comparator configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Frequency of ICLK in Hz, to wait for the comparator to settle
+    pub iclk_hz: u32,
+    pub filter: Filter,
+    pub edge: Edge,
+    /// Invert the result on the VCOUT pin
+    pub invert_output: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            iclk_hz: 48_000_000,
+            filter: Filter::Pclkb8,
+            edge: Edge::Both,
+            invert_output: false,
+        }
+    }
+}
+
+/// A comparator, [`Ch0`] or [`Ch1`].
+pub trait Channel {
+    // Channel number, 0 or 1
+    fn index() -> u8;
+    // Callback of the edge handler
+    fn callback() -> &'static Mutex<Cell<Option<fn(bool)>>>;
+    /// Event of the edge interrupt, also for [`elc::link`](crate::elc::link).
+    fn event() -> Event {
+        Event::ACMP_LP0.offset(Self::index())
+    }
+}
+
+/// ACMPLP0
+pub struct Ch0;
+/// ACMPLP1
+pub struct Ch1;
+
+macro_rules! impl_channel {
+    ($($ch:ident: $index:literal;)*) => {
+        $(
+            impl Channel for $ch {
+                fn index() -> u8 {
+                    $index
+                }
+
+                fn callback() -> &'static Mutex<Cell<Option<fn(bool)>>> {
+                    static CALLBACK: Mutex<Cell<Option<fn(bool)>>> = Mutex::new(Cell::new(None));
+                    &CALLBACK
+                }
+            }
+        )*
+    };
+}
+
+impl_channel! {
+    Ch0: 0;
+    Ch1: 1;
+}
+
+/// Pin that can be used as CMPINn of comparator `C`.
+pub trait InputPin<C: Channel>: Pin {}
+
+/// Pin that can be used as CMPREFn of comparator `C`.
+pub trait RefPin<C: Channel>: Pin {}
+
+/// Pin that can be used as VCOUT, the result of either comparator.
+pub trait OutputPin: Pin {}
+
+impl InputPin<Ch0> for gpio::P100 {}
+impl InputPin<Ch1> for gpio::P102 {}
+impl InputPin<Ch1> for gpio::P501 {}
+impl RefPin<Ch0> for gpio::P101 {}
+impl RefPin<Ch0> for gpio::P502 {}
+impl RefPin<Ch1> for gpio::P103 {}
+impl RefPin<Ch1> for gpio::P500 {}
+impl OutputPin for gpio::P110 {}
+
+/// Calls the callback set with [`Comparator::enable_interrupt`] with the
+/// result, true when the input is above the reference.
+pub struct EdgeHandler<C: Channel> {
+    _phantom: PhantomData<C>,
+}
+
+impl<C: Channel> Handler for EdgeHandler<C> {
+    unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
+        clear_interrupt(interrupt);
+        let callback = critical_section::with(|cs| C::callback().borrow(cs).get());
+        if let Some(callback) = callback {
+            callback(is_high::<C>());
+        }
+    }
+}
+
+/// One of the two comparators.
+pub struct Comparator<C: Channel> {
+    _clock: ClockGuard,
+    _dac: Option<ClockGuard>,
+    _phantom: PhantomData<C>,
+}
+
+/// Take both comparators, disabled.
+///
+/// The speed can only be changed while both are disabled, so is set once here.
+pub fn split(_acmp: ra4m1::ACMPLP, speed: Speed) -> (Comparator<Ch0>, Comparator<Ch1>) {
+    let clock = ClockGuard::new(Peripheral::Acmplp);
+    unsafe {
+        COMPMDR.write_volatile(0);
+        COMPOCR.write_volatile(if speed == Speed::High {
+            COMPOCR_SPDMD
+        } else {
+            0
+        });
+    }
+    (
+        Comparator {
+            _clock: clock.clone(),
+            _dac: None,
+            _phantom: PhantomData,
+        },
+        Comparator {
+            _clock: clock,
+            _dac: None,
+            _phantom: PhantomData,
+        },
+    )
+}
+
+impl<C: Channel> Comparator<C> {
+    /// Route a CMPREFn pin for [`Reference::Pin`], before [`enable`](Self::enable).
+    pub fn route_reference<P: RefPin<C>>(&mut self, _pin: P) {
+        gpio::set_analog::<P>();
+        let sel = if P::port() == 1 { SEL_PORT1 } else { SEL_PORT5 };
+        self.modify_sel(COMPSEL1, sel);
+    }
+
+    /// Start comparing the `input` pin with `reference`.
+    ///
+    /// Waits the 100 us the comparator takes to settle before setting up the
+    /// edge detection. Map the interrupt afterwards, as settling may raise it.
+    pub fn enable<P: InputPin<C>>(&mut self, _input: P, reference: Reference, config: Config) {
+        self.disable();
+        gpio::set_analog::<P>();
+        let sel = if P::port() == 1 { SEL_PORT1 } else { SEL_PORT5 };
+        self.modify_sel(COMPSEL0, sel);
+
+        let shift = 4 * C::index();
+        let mut mdr = COMPMDR_ENB;
+        match reference {
+            Reference::Vref => mdr |= COMPMDR_VRF,
+            Reference::Dac8(value) => {
+                if self._dac.is_none() {
+                    self._dac = Some(ClockGuard::new(Peripheral::Dac8));
+                }
+                unsafe {
+                    DACS0.add(C::index() as usize).write_volatile(value);
+                    DAM.write_volatile(DAM.read_volatile() | (DAM_DACE0 << C::index()));
+                }
+                self.modify_sel(COMPSEL1, SEL_DAC8);
+            }
+            Reference::Pin => {}
+        }
+
+        let (edg, epo) = match config.edge {
+            Edge::Rising => (0, 0),
+            Edge::Falling => (0, 1),
+            Edge::Both => (1, 0),
+        };
+        let fir = config.filter as u8 | (epo << 2) | (edg << 3);
+        unsafe {
+            // C1VRF2 picks IVREF1 for comparator 1, rather than IVREF0
+            if C::index() == 1 {
+                COMPSEL1.write_volatile(COMPSEL1.read_volatile() | (1 << 7));
+            }
+            COMPMDR.write_volatile((COMPMDR.read_volatile() & !(0x0F << shift)) | (mdr << shift));
+            cortex_m::asm::delay(config.iclk_hz / 10_000);
+            COMPFIR.write_volatile((COMPFIR.read_volatile() & !(0x0F << shift)) | (fir << shift));
+            let ocr = if config.invert_output { COMPOCR_OP } else { 0 };
+            COMPOCR.write_volatile(
+                (COMPOCR.read_volatile() & !((COMPOCR_OE | COMPOCR_OP) << shift)) | (ocr << shift),
+            );
+        }
+    }
+
+    /// Stop comparing, the DAC channel is stopped too.
+    pub fn disable(&mut self) {
+        let shift = 4 * C::index();
+        unsafe {
+            COMPMDR.write_volatile(COMPMDR.read_volatile() & !(COMPMDR_ENB << shift));
+        }
+        if self._dac.is_some() {
+            unsafe { DAM.write_volatile(DAM.read_volatile() & !(DAM_DACE0 << C::index())) };
+        }
+    }
+
+    /// Change the DAC reference while enabled.
+    ///
+    /// The DAC can't be stopped while the comparator uses it, but its value
+    /// can change, e.g. to add hysteresis after an edge.
+    pub fn set_dac(&mut self, value: u8) {
+        unsafe { DACS0.add(C::index() as usize).write_volatile(value) };
+    }
+
+    /// Whether the input is above the reference.
+    pub fn is_high(&self) -> bool {
+        is_high::<C>()
+    }
+
+    /// Drive the result onto VCOUT, shared with the other comparator.
+    pub fn enable_output<P: OutputPin>(&mut self, _pin: P) {
+        gpio::set_function::<P>(gpio::PinFunction::Clkout);
+        let shift = 4 * C::index();
+        unsafe { COMPOCR.write_volatile(COMPOCR.read_volatile() | (COMPOCR_OE << shift)) };
+    }
+
+    /// Map the edge interrupt to [`EdgeHandler`] and set its callback.
+    pub fn enable_interrupt<IRQ>(&mut self, _irq: IRQ, callback: Option<fn(bool)>)
+    where
+        IRQ: Binding<EdgeHandler<C>>,
+    {
+        critical_section::with(|cs| C::callback().borrow(cs).set(callback));
+        let interrupt = <IRQ as Binding<EdgeHandler<C>>>::interrupt();
+        clear_interrupt(interrupt);
+        map_and_enable_interrupt(interrupt, C::event());
+    }
+
+    // Set the 3-bit selection of this channel in COMPSEL0 or COMPSEL1
+    fn modify_sel(&mut self, reg: *mut u8, sel: u8) {
+        let shift = 4 * C::index();
+        unsafe { reg.write_volatile((reg.read_volatile() & !(0x07 << shift)) | (sel << shift)) };
+    }
+}
+
+fn is_high<C: Channel>() -> bool {
+    unsafe { COMPMDR.read_volatile() & (COMPMDR_MON << (4 * C::index())) != 0 }
+}
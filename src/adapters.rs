@@ -0,0 +1,147 @@
+//! Wiring of the drivers to sensor crates through `embedded-hal-bus`.
+//!
+//! Sensor crates take an embedded-hal `SpiDevice`, which owns the chip
+//! select and wraps each transaction in it. [`spi_device`] makes one from
+//! the [`SpiMaster`] and a pin, for a bus with one device:
+//!
+//! ```ignore
+//! let spi = SpiMaster::new(p.SPI1, pins.p111, pins.p109, pins.p110, Config::default());
+//! let device = adapters::spi_device(spi, pins.p112);
+//! // e.g. the bme280 crate
+//! let mut bme280 = bme280::spi::BME280::new(device)?;
+//! bme280.init(&mut cycles::Delay)?;
+//! let measurements = bme280.measure(&mut cycles::Delay)?;
+//! ```
+//!
+//! Devices sharing a bus each get a [`SharedSpiDevice`] over one
+//! [`SharedSpi`], locked in a critical section for each transaction, so
+//! drivers in interrupts can use the bus too:
+//!
+//! ```ignore
+//! let spi = SpiMaster::new(p.SPI1, pins.p111, pins.p109, pins.p110, Config::default());
+//! let bus = adapters::share_spi(spi);
+//! let (pressure, flash) = adapters::spi_devices(&bus, (pins.p112, pins.p103));
+//! ```
+//!
+//! When every device is used from one context, a [`LocalSpi`] from
+//! [`local_spi`] does without the critical sections, so interrupts aren't
+//! held off for the length of each transaction.
+//!
+//! Delays a device needs inside a transaction are busy waits on
+//! [`cycles::Delay`](crate::cycles::Delay), so
+//! [`cycles::start`](crate::cycles::start) must have been called. I2C
+//! sensors, e.g. the MPU-6050 or VL53L0X, need an I2C driver this crate
+//! doesn't have yet.
+
+use core::cell::RefCell;
+use core::convert::Infallible;
+
+use critical_section::Mutex;
+use embedded_hal::digital::OutputPin;
+use embedded_hal_bus::spi::{CriticalSectionDevice, ExclusiveDevice, RefCellDevice};
+
+use crate::cycles::Delay;
+use crate::spi::Instance;
+use crate::spi::master::SpiMaster;
+
+/// The only device on an SPI bus, from [`spi_device`].
+pub type SpiDevice<T, CS> = ExclusiveDevice<SpiMaster<T>, CS, Delay>;
+
+/// SPI bus shared between devices, from [`share_spi`].
+pub type SharedSpi<T> = Mutex<RefCell<SpiMaster<T>>>;
+
+/// One of several devices on a [`SharedSpi`], from [`shared_spi_device`].
+pub type SharedSpiDevice<'a, T, CS> = CriticalSectionDevice<'a, SpiMaster<T>, CS, Delay>;
+
+/// SPI bus shared between devices used from one context, from
+/// [`local_spi`].
+pub type LocalSpi<T> = RefCell<SpiMaster<T>>;
+
+/// One of several devices on a [`LocalSpi`], from [`local_spi_device`].
+pub type LocalSpiDevice<'a, T, CS> = RefCellDevice<'a, SpiMaster<T>, CS, Delay>;
+
+/// Make `spi` a device selected by `cs`, which is driven high first.
+///
+/// `cs` is any pin, the pins are `OutputPin`s with the `embedded-hal`
+/// feature.
+pub fn spi_device<T: Instance, CS: OutputPin<Error = Infallible>>(
+    spi: SpiMaster<T>,
+    cs: CS,
+) -> SpiDevice<T, CS> {
+    let Ok(device) = ExclusiveDevice::new(spi, cs, Delay);
+    device
+}
+
+/// Share `spi` between devices from [`shared_spi_device`]. Keep the bus in
+/// `main`, or in a `static` for devices used from interrupts.
+pub const fn share_spi<T: Instance>(spi: SpiMaster<T>) -> SharedSpi<T> {
+    Mutex::new(RefCell::new(spi))
+}
+
+/// A device on `bus` selected by `cs`, which is driven high first.
+pub fn shared_spi_device<T: Instance, CS: OutputPin<Error = Infallible>>(
+    bus: &SharedSpi<T>,
+    cs: CS,
+) -> SharedSpiDevice<'_, T, CS> {
+    let Ok(device) = CriticalSectionDevice::new(bus, cs, Delay);
+    device
+}
+
+/// Chip select pins of the devices on a [`SharedSpi`], tuples of up to 4
+/// pins, see [`spi_devices`].
+pub trait ChipSelects<'a, T: Instance> {
+    /// A [`SharedSpiDevice`] for each pin
+    type Devices;
+
+    /// Make the devices, see [`spi_devices`]
+    fn devices(self, bus: &'a SharedSpi<T>) -> Self::Devices;
+}
+
+macro_rules! impl_chip_selects {
+    ($(($($cs:ident),*);)*) => {
+        $(
+            impl<'a, T: Instance, $($cs: OutputPin<Error = Infallible>),*> ChipSelects<'a, T>
+                for ($($cs,)*)
+            {
+                type Devices = ($(SharedSpiDevice<'a, T, $cs>,)*);
+
+                #[allow(non_snake_case)]
+                fn devices(self, bus: &'a SharedSpi<T>) -> Self::Devices {
+                    let ($($cs,)*) = self;
+                    ($(shared_spi_device(bus, $cs),)*)
+                }
+            }
+        )*
+    };
+}
+
+impl_chip_selects! {
+    (CS0);
+    (CS0, CS1);
+    (CS0, CS1, CS2);
+    (CS0, CS1, CS2, CS3);
+}
+
+/// A device on `bus` for each pin of `cs`, in order. Every pin is driven
+/// high before any device is used.
+pub fn spi_devices<'a, T: Instance, C: ChipSelects<'a, T>>(
+    bus: &'a SharedSpi<T>,
+    cs: C,
+) -> C::Devices {
+    cs.devices(bus)
+}
+
+/// Share `spi` between devices from [`local_spi_device`], all used from
+/// the same context.
+pub const fn local_spi<T: Instance>(spi: SpiMaster<T>) -> LocalSpi<T> {
+    RefCell::new(spi)
+}
+
+/// A device on `bus` selected by `cs`, which is driven high first.
+pub fn local_spi_device<T: Instance, CS: OutputPin<Error = Infallible>>(
+    bus: &LocalSpi<T>,
+    cs: CS,
+) -> LocalSpiDevice<'_, T, CS> {
+    let Ok(device) = RefCellDevice::new(bus, cs, Delay);
+    device
+}
@@ -0,0 +1,635 @@
+//! 14-bit A/D converter (ADC14)
+//!
+//! Single conversions started from software, or a scan of several channels
+//! started by a GPT channel through the [ELC](crate::elc) at a fixed rate.
+//! At the end of each scan a DMAC channel moves the results into a circular
+//! buffer in memory, so the CPU only runs once per pass of the buffer.
+//!
+//! The internal temperature sensor is read with
+//! [`Adc::read_temperature`], which applies the factory calibration.
+//!
+//! The window comparator checks every result of its channels in hardware
+//! and interrupts when one is outside the window, so a limit is caught at
+//! the scan it happens in without reading the buffer.
+//!
+//! ```ignore
+//! bind_interrupts!(struct Irq {
+//!     IEL12 => adc::DmaEndHandler<ra4m1::DMAC0>;
+//!     IEL13 => adc::WindowHandler;
+//! });
+//!
+//! let buffer = cortex_m::singleton!(: [u16; 256] = [0; 256]).unwrap();
+//! let mut adc = adc::Adc::new(p.ADC140, Default::default());
+//! let a0 = adc.channel(pins.p014);
+//! let a1 = adc.channel(pins.p000);
+//! adc.set_window(&[a0], 1_000, 15_000, Irq, Some(out_of_range));
+//! let mut scan = adc.scan(&[a0, a1], p.GPT164, p.DMAC0, buffer, Default::default(), Irq)?;
+//! let len = scan.read(&mut values)?;
+//! ```
+
+use core::cell::Cell;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+
+use critical_section::Mutex;
+
+use crate::clk::{ClockGuard, Peripheral};
+use crate::dmac;
+use crate::elc::{self, Target};
+use crate::events::Event;
+use crate::gpio::{self, Pin};
+use crate::gpt::{self, Prescaler};
+use crate::interrupts::{Binding, Handler, clear_interrupt, map_and_enable_interrupt};
+
+const ADC: usize = 0x4005_C000;
+const ADCSR: *mut u16 = ADC as *mut u16;
+const ADANSA0: *mut u16 = (ADC + 0x04) as *mut u16;
+const ADANSA1: *mut u16 = (ADC + 0x06) as *mut u16;
+const ADCER: *mut u16 = (ADC + 0x0E) as *mut u16;
+const ADSTRGR: *mut u16 = (ADC + 0x10) as *mut u16;
+const ADEXICR: *mut u16 = (ADC + 0x12) as *mut u16;
+const ADTSDR: *const u16 = (ADC + 0x1A) as *const u16;
+// ADDRn are 16 bits, in channel order from AN000
+const ADDR0: usize = ADC + 0x20;
+const ADCMPCR: *mut u16 = (ADC + 0x90) as *mut u16;
+const ADCMPANSR0: *mut u16 = (ADC + 0x94) as *mut u16;
+const ADCMPANSR1: *mut u16 = (ADC + 0x96) as *mut u16;
+const ADCMPLR0: *mut u16 = (ADC + 0x98) as *mut u16;
+const ADCMPLR1: *mut u16 = (ADC + 0x9A) as *mut u16;
+const ADCMPDR0: *mut u16 = (ADC + 0x9C) as *mut u16;
+const ADCMPDR1: *mut u16 = (ADC + 0x9E) as *mut u16;
+const ADCMPSR0: *mut u16 = (ADC + 0xA0) as *mut u16;
+const ADCMPSR1: *mut u16 = (ADC + 0xA2) as *mut u16;
+const ADSSTRT: *mut u8 = (ADC + 0xDE) as *mut u8;
+
+// TSCDRL and TSCDRH, factory calibration of the temperature sensor
+const TSCDRL: *const u8 = 0x407E_C228 as *const u8;
+const TSCDRH: *const u8 = 0x407E_C229 as *const u8;
+
+// ADCSR.TRGE, start scans on the ELC trigger
+const ADCSR_TRGE: u16 = 1 << 9;
+// ADCSR.ADST, start a scan and set until it ends
+const ADCSR_ADST: u16 = 1 << 15;
+// ADSTRGR, TRSA and TRSB deselected
+const ADSTRGR_NONE: u16 = 0x3F3F;
+// ADSTRGR, TRSA is ELC_AD00 and TRSB deselected
+const ADSTRGR_ELC: u16 = (0x09 << 8) | 0x3F;
+// ADEXICR.TSSA, convert the temperature sensor
+const ADEXICR_TSSA: u16 = 1 << 8;
+// ADCMPCR.CMPAE, window A enable
+const ADCMPCR_CMPAE: u16 = 1 << 11;
+// ADCMPCR.WCMPE, compare with a window rather than a single level
+const ADCMPCR_WCMPE: u16 = 1 << 14;
+// ADCMPCR.CMPAIE, window A interrupt enable
+const ADCMPCR_CMPAIE: u16 = 1 << 15;
+
+// Temperature sensor, 3.65 mV per degree falling with temperature
+const TSN_SLOPE_UV: i64 = -3_650;
+// Shortest temperature sensor sampling time
+const TSN_SAMPLE_NS: u32 = 5_000;
+// Calibration is at 125 degrees with AVCC0 = 3.3 V and 12 bits
+const TSN_CAL_TEMP: i64 = 125;
+const TSN_CAL_AVCC_MV: i64 = 3_300;
+
+// Event offset of the overflow from the first event of the GPT channel
+const EVENT_OVF: u8 = 6;
+
+/// Conversion resolution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum Resolution {
+    // ADCER.ADPRC
+    Bits12 = 0b00 << 1,
+    Bits14 = 0b11 << 1,
+}
+
+/// ADC configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub resolution: Resolution,
+    /// Frequency of PCLKC (ADCLK) in Hz, for the temperature sensor
+    /// sampling time
+    pub pclkc_hz: u32,
+    /// AVCC0 in millivolts, the reference for the temperature sensor
+    pub avcc_mv: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            resolution: Resolution::Bits14,
+            pclkc_hz: 48_000_000,
+            avcc_mv: 5_000,
+        }
+    }
+}
+
+/// Timed scan configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanConfig {
+    /// Frequency of PCLKD in Hz, clocks the GPT
+    pub pclkd_hz: u32,
+    /// Scans per second. Each scan must end before the next starts, about
+    /// 1 us per channel, or the next start is missed.
+    pub rate_hz: u32,
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        ScanConfig {
+            pclkd_hz: 48_000_000,
+            rate_hz: 1_000,
+        }
+    }
+}
+
+/// ADC error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// No channels were given
+    NoChannels,
+    /// The buffer doesn't hold at least two scans, or holds too many for
+    /// the DMAC
+    BufferLength,
+    /// The scan rate can't be made from PCLKD with the GPT channel
+    Rate,
+    /// Scans were overwritten before they were read, reading resumes from
+    /// the oldest scan still in the buffer
+    Overrun,
+}
+
+/// Pin that can be used as an analog input.
+pub trait AnalogPin: Pin {
+    /// Channel number, n in ANn
+    fn channel() -> u8;
+}
+
+macro_rules! impl_analog_pin {
+    ($($pin:ident: $channel:literal;)*) => {
+        $(
+            impl AnalogPin for gpio::$pin {
+                fn channel() -> u8 {
+                    $channel
+                }
+            }
+        )*
+    };
+}
+
+impl_analog_pin! {
+    P000: 0;
+    P001: 1;
+    P002: 2;
+    P003: 3;
+    P004: 4;
+    P010: 5;
+    P011: 6;
+    P012: 7;
+    P013: 8;
+    P014: 9;
+    P015: 10;
+    P500: 16;
+    P501: 17;
+    P502: 18;
+    P103: 19;
+    P102: 20;
+    P101: 21;
+    P100: 22;
+}
+
+/// An analog input channel, from [`Adc::channel`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Channel(u8);
+
+impl Channel {
+    // For inputs routed by other drivers
+    pub(crate) const fn new(number: u8) -> Self {
+        Channel(number)
+    }
+
+    /// Channel number, n in ANn.
+    pub fn number(self) -> u8 {
+        self.0
+    }
+
+    /// Bit of the channel in the flags passed to the window callback.
+    pub fn mask(self) -> u32 {
+        1 << self.0
+    }
+}
+
+// Channels as a mask, ADANSA0 and similar in the low half and ADANSA1 in the high half
+fn mask(channels: &[Channel]) -> u32 {
+    channels
+        .iter()
+        .fold(0, |mask, channel| mask | channel.mask())
+}
+
+fn result(channel: u8) -> u16 {
+    unsafe { ((ADDR0 + 2 * channel as usize) as *const u16).read_volatile() }
+}
+
+struct State {
+    // Circular buffer, a whole number of scans
+    buffer: AtomicPtr<u16>,
+    // Values per scan and scans in the buffer
+    scan_len: AtomicUsize,
+    scans: AtomicUsize,
+    // First ADDRn of a scan
+    src: AtomicUsize,
+    // Passes of the DMAC through the buffer
+    wraps: AtomicU32,
+}
+
+static STATE: State = State {
+    buffer: AtomicPtr::new(core::ptr::null_mut()),
+    scan_len: AtomicUsize::new(0),
+    scans: AtomicUsize::new(0),
+    src: AtomicUsize::new(0),
+    wraps: AtomicU32::new(0),
+};
+
+static WINDOW_CALLBACK: Mutex<Cell<Option<fn(u32)>>> = Mutex::new(Cell::new(None));
+
+/// Triggers at the end of each pass through the scan buffer, starts the
+/// next pass from the start of the buffer.
+///
+/// Must run before the next scan ends or that scan is lost.
+pub struct DmaEndHandler<C: dmac::Channel> {
+    _phantom: PhantomData<C>,
+}
+
+impl<C: dmac::Channel> Handler for DmaEndHandler<C> {
+    unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
+        clear_interrupt(interrupt);
+        dmac::clear_end_flag::<C>();
+        // Safety: the buffer is 'static and only written by the DMAC
+        unsafe {
+            dmac::start_block_read::<C>(
+                STATE.src.load(Ordering::Relaxed) as *const u16,
+                STATE.buffer.load(Ordering::Relaxed),
+                STATE.scan_len.load(Ordering::Relaxed),
+                STATE.scans.load(Ordering::Relaxed),
+            )
+        };
+        dmac::enable_end_interrupt::<C>();
+        STATE.wraps.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Calls the callback set with [`Adc::set_window`] with the channels that
+/// were outside the window, as a mask of [`Channel::mask`].
+pub struct WindowHandler;
+
+impl Handler for WindowHandler {
+    unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
+        clear_interrupt(interrupt);
+        let (sr0, sr1) = unsafe { (ADCMPSR0.read_volatile(), ADCMPSR1.read_volatile()) };
+        // Flags clear by writing 0 after reading 1, keep any set since
+        unsafe {
+            ADCMPSR0.write_volatile(!sr0);
+            ADCMPSR1.write_volatile(!sr1);
+        }
+        let callback = critical_section::with(|cs| WINDOW_CALLBACK.borrow(cs).get());
+        if let Some(callback) = callback {
+            callback(sr0 as u32 | ((sr1 as u32) << 16));
+        }
+    }
+}
+
+/// The A/D converter.
+pub struct Adc {
+    resolution: Resolution,
+    avcc_mv: u32,
+    _clock: ClockGuard,
+}
+
+impl Adc {
+    /// Enable the ADC for single conversions.
+    ///
+    /// The reference is AVCC0, which is VCC on the UNO R4.
+    pub fn new(_adc: ra4m1::ADC140, config: Config) -> Self {
+        let clock = ClockGuard::new(Peripheral::Adc140);
+        // 1 us from the module start before the first conversion, ICLK is
+        // at most 48 MHz
+        cortex_m::asm::delay(48);
+        unsafe {
+            ADCSR.write_volatile(0);
+            ADCER.write_volatile(config.resolution as u16);
+            ADSTRGR.write_volatile(ADSTRGR_NONE);
+        }
+        // Sampling time of the temperature sensor in ADCLK cycles
+        let states = (config.pclkc_hz as u64 * TSN_SAMPLE_NS as u64).div_ceil(1_000_000_000);
+        unsafe { ADSSTRT.write_volatile(states.min(0xFF) as u8) };
+        select(0);
+        Self {
+            resolution: config.resolution,
+            avcc_mv: config.avcc_mv,
+            _clock: clock,
+        }
+    }
+
+    /// Make `pin` an analog input.
+    pub fn channel<P: AnalogPin>(&mut self, _pin: P) -> Channel {
+        gpio::set_analog::<P>();
+        Channel(P::channel())
+    }
+
+    /// Largest result at the configured resolution.
+    pub fn max_value(&self) -> u16 {
+        match self.resolution {
+            Resolution::Bits12 => 0x0FFF,
+            Resolution::Bits14 => 0x3FFF,
+        }
+    }
+
+    /// Convert one channel, blocking for the conversion.
+    pub fn read(&mut self, channel: Channel) -> u16 {
+        select(channel.mask());
+        unsafe {
+            ADCSR.write_volatile(ADCSR_ADST);
+            while ADCSR.read_volatile() & ADCSR_ADST != 0 {}
+        }
+        result(channel.0)
+    }
+
+    /// Die temperature in tenths of a degree C, from the temperature sensor
+    /// with the factory calibration applied.
+    ///
+    /// The calibration is a single point at 125 C and uses the typical
+    /// slope, so the result is within about 3 C at room temperature.
+    pub fn read_temperature(&mut self) -> i16 {
+        let raw = self.read_temperature_raw();
+        temperature(
+            raw,
+            self.max_value(),
+            self.avcc_mv,
+            temperature_calibration(),
+        )
+    }
+
+    /// Convert the temperature sensor, blocking for the conversion.
+    pub fn read_temperature_raw(&mut self) -> u16 {
+        // Only in single scan mode and with no channels selected
+        select(0);
+        unsafe {
+            ADEXICR.write_volatile(ADEXICR_TSSA);
+            ADCSR.write_volatile(ADCSR_ADST);
+            while ADCSR.read_volatile() & ADCSR_ADST != 0 {}
+            ADEXICR.write_volatile(0);
+            ADTSDR.read_volatile()
+        }
+    }
+
+    /// Compare every result of `channels` with the window from `low` to
+    /// `high`, calling `callback` from [`WindowHandler`] when one is outside.
+    ///
+    /// The interrupt is raised at each scan for as long as a channel stays
+    /// outside, so the callback may want to move the window or clear it.
+    pub fn set_window<IRQ: Binding<WindowHandler>>(
+        &mut self,
+        channels: &[Channel],
+        low: u16,
+        high: u16,
+        _irq: IRQ,
+        callback: Option<fn(u32)>,
+    ) {
+        let mask = mask(channels);
+        critical_section::with(|cs| WINDOW_CALLBACK.borrow(cs).set(callback));
+        unsafe {
+            ADCMPCR.write_volatile(0);
+            ADCMPANSR0.write_volatile(mask as u16);
+            ADCMPANSR1.write_volatile((mask >> 16) as u16);
+            // Condition met below ADCMPDR0 or above ADCMPDR1
+            ADCMPLR0.write_volatile(0);
+            ADCMPLR1.write_volatile(0);
+            ADCMPDR0.write_volatile(low);
+            ADCMPDR1.write_volatile(high);
+            ADCMPSR0.write_volatile(0);
+            ADCMPSR1.write_volatile(0);
+            ADCMPCR.write_volatile(ADCMPCR_CMPAE | ADCMPCR_WCMPE | ADCMPCR_CMPAIE);
+        }
+        let interrupt = <IRQ as Binding<WindowHandler>>::interrupt();
+        clear_interrupt(interrupt);
+        map_and_enable_interrupt(interrupt, Event::ADC140_CMPAI);
+    }
+
+    /// Stop the window comparison.
+    pub fn clear_window(&mut self) {
+        unsafe { ADCMPCR.write_volatile(0) };
+        critical_section::with(|cs| WINDOW_CALLBACK.borrow(cs).set(None));
+    }
+
+    /// Scan `channels` at `config.rate_hz`, started by the overflow of the
+    /// GPT channel, with the DMAC channel moving each scan into `buffer`.
+    ///
+    /// Each scan is the results of the lowest to the highest channel, with
+    /// any channels in between, see [`Scan::index`]. The buffer is used for
+    /// as many whole scans as fit and must hold at least two.
+    pub fn scan<G, C, IRQ>(
+        &mut self,
+        channels: &[Channel],
+        _timer: G,
+        _dma: C,
+        buffer: &'static mut [u16],
+        config: ScanConfig,
+        _irq: IRQ,
+    ) -> Result<Scan<'_, G, C>, Error>
+    where
+        G: gpt::Instance,
+        C: dmac::Channel,
+        IRQ: Binding<DmaEndHandler<C>>,
+    {
+        let mask = mask(channels);
+        if mask == 0 {
+            return Err(Error::NoChannels);
+        }
+        let first = mask.trailing_zeros() as usize;
+        let scan_len = 32 - mask.leading_zeros() as usize - first;
+        let scans = buffer.len() / scan_len;
+        if !(2..=dmac::MAX_BLOCKS).contains(&scans) {
+            return Err(Error::BufferLength);
+        }
+        let (prescaler, period) = period_for::<G>(config).ok_or(Error::Rate)?;
+
+        unsafe {
+            ADCSR.write_volatile(0);
+            ADSTRGR.write_volatile(ADSTRGR_ELC);
+        }
+        select(mask);
+        unsafe { ADCSR.write_volatile(ADCSR_TRGE) };
+
+        let src = ADDR0 + 2 * first;
+        STATE.buffer.store(buffer.as_mut_ptr(), Ordering::Relaxed);
+        STATE.scan_len.store(scan_len, Ordering::Relaxed);
+        STATE.scans.store(scans, Ordering::Relaxed);
+        STATE.src.store(src, Ordering::Relaxed);
+        STATE.wraps.store(0, Ordering::Relaxed);
+
+        dmac::enable();
+        dmac::link_event::<C>(Event::ADC140_ADI);
+        map_and_enable_interrupt(<IRQ as Binding<DmaEndHandler<C>>>::interrupt(), C::event());
+        // Safety: the buffer is 'static and the transfer stops when the scan is dropped
+        unsafe {
+            dmac::start_block_read::<C>(src as *const u16, buffer.as_mut_ptr(), scan_len, scans)
+        };
+        dmac::enable_end_interrupt::<C>();
+
+        let clock = gpt::init::<G>(prescaler);
+        let gpt = unsafe { &*G::peripheral() };
+        gpt.gtpr.write(|w| unsafe { w.bits(period - 1) });
+        elc::link(Target::Adc140A, G::event_base().offset(EVENT_OVF));
+        gpt::start::<G>();
+
+        Ok(Scan {
+            buffer,
+            scan_len,
+            first: first as u8,
+            read: 0,
+            slot: 0,
+            _adc: self,
+            _clock: clock,
+            _phantom: PhantomData,
+        })
+    }
+}
+
+/// Factory calibration of the temperature sensor, the 12-bit result at
+/// 125 C with AVCC0 at 3.3 V.
+pub fn temperature_calibration() -> u16 {
+    unsafe { ((TSCDRH.read_volatile() as u16 & 0x0F) << 8) | TSCDRL.read_volatile() as u16 }
+}
+
+// Temperature in tenths of a degree from a sensor result, with the single
+// point calibration and the typical slope
+fn temperature(raw: u16, max: u16, avcc_mv: u32, calibration: u16) -> i16 {
+    let sensor_uv = raw as i64 * avcc_mv as i64 * 1000 / (max as i64 + 1);
+    let calibration_uv = calibration as i64 * TSN_CAL_AVCC_MV * 1000 / 4096;
+    let tenths = TSN_CAL_TEMP * 10 + (sensor_uv - calibration_uv) * 10 / TSN_SLOPE_UV;
+    tenths.clamp(i16::MIN as i64, i16::MAX as i64) as i16
+}
+
+// Select the channels of a scan, with the ADC stopped
+fn select(mask: u32) {
+    unsafe {
+        ADANSA0.write_volatile(mask as u16);
+        ADANSA1.write_volatile((mask >> 16) as u16);
+    }
+}
+
+// Smallest prescaler and the period in count clocks for the scan rate
+fn period_for<G: gpt::Instance>(config: ScanConfig) -> Option<(Prescaler, u32)> {
+    if config.rate_hz == 0 {
+        return None;
+    }
+    [
+        Prescaler::Div1,
+        Prescaler::Div4,
+        Prescaler::Div16,
+        Prescaler::Div64,
+        Prescaler::Div256,
+        Prescaler::Div1024,
+    ]
+    .into_iter()
+    .map(|p| (p, config.pclkd_hz / p.divisor() / config.rate_hz))
+    .find(|(_, period)| (1..=G::max_count()).contains(period))
+}
+
+/// Timed scan into a circular buffer, stopped when dropped.
+pub struct Scan<'a, G: gpt::Instance, C: dmac::Channel> {
+    // Written by the DMAC, only read through pointers
+    buffer: &'static mut [u16],
+    scan_len: usize,
+    first: u8,
+    // Scans read so far, and the slot of the next one
+    read: u32,
+    slot: usize,
+    _adc: &'a mut Adc,
+    _clock: ClockGuard,
+    _phantom: PhantomData<(G, C)>,
+}
+
+impl<G: gpt::Instance, C: dmac::Channel> Scan<'_, G, C> {
+    /// Number of values in each scan.
+    pub fn scan_len(&self) -> usize {
+        self.scan_len
+    }
+
+    /// Position of `channel` within each scan, if it's part of the scan.
+    pub fn index(&self, channel: Channel) -> Option<usize> {
+        channel
+            .0
+            .checked_sub(self.first)
+            .map(usize::from)
+            .filter(|index| *index < self.scan_len)
+    }
+
+    /// Number of scans completed since the start, wrapping.
+    pub fn completed(&self) -> u32 {
+        self.position().0
+    }
+
+    // Scans completed, and the slot in the buffer being written
+    fn position(&self) -> (u32, usize) {
+        let scans = self.buffer.len() / self.scan_len;
+        let start = self.buffer.as_ptr() as usize;
+        loop {
+            let wraps = STATE.wraps.load(Ordering::Acquire);
+            let next = dmac::destination(C::index());
+            // Read again if a pass ended in between
+            if STATE.wraps.load(Ordering::Acquire) == wraps {
+                let slot = (next - start) / 2 / self.scan_len;
+                let completed = wraps.wrapping_mul(scans as u32).wrapping_add(slot as u32);
+                return (completed, slot % scans);
+            }
+        }
+    }
+
+    /// Most recent result of `channel`, straight from the ADC.
+    pub fn latest(&self, channel: Channel) -> u16 {
+        result(channel.0)
+    }
+
+    /// Copy the scans completed since the last read into `values`, oldest
+    /// first, and return the number of values copied.
+    ///
+    /// Only whole scans are copied, any that don't fit are left for the next
+    /// read. Reading less often than once per pass through the buffer loses
+    /// scans, which returns [`Error::Overrun`] and copies nothing.
+    pub fn read(&mut self, values: &mut [u16]) -> Result<usize, Error> {
+        let scans = self.buffer.len() / self.scan_len;
+        let (completed, writing) = self.position();
+        let unread = completed.wrapping_sub(self.read) as usize;
+        if unread >= scans {
+            // Everything but the slot being written is unread
+            self.read = completed.wrapping_sub(scans as u32 - 1);
+            self.slot = (writing + 1) % scans;
+            return Err(Error::Overrun);
+        }
+        let count = unread.min(values.len() / self.scan_len);
+        let buffer = self.buffer.as_ptr();
+        for out in values.chunks_exact_mut(self.scan_len).take(count) {
+            let scan = unsafe { buffer.add(self.slot * self.scan_len) };
+            for (i, value) in out.iter_mut().enumerate() {
+                *value = unsafe { scan.add(i).read_volatile() };
+            }
+            self.slot = (self.slot + 1) % scans;
+        }
+        self.read = self.read.wrapping_add(count as u32);
+        Ok(count * self.scan_len)
+    }
+}
+
+impl<G: gpt::Instance, C: dmac::Channel> Drop for Scan<'_, G, C> {
+    fn drop(&mut self) {
+        gpt::stop::<G>();
+        elc::unlink(Target::Adc140A);
+        dmac::stop(C::index());
+        unsafe {
+            ADCSR.write_volatile(0);
+            ADSTRGR.write_volatile(ADSTRGR_NONE);
+        }
+    }
+}
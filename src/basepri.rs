@@ -0,0 +1,90 @@
+//! Critical sections that mask interrupts up to a priority ceiling.
+//!
+//! With the `critical-section-basepri` feature this is the
+//! `critical-section` implementation, in place of the one from cortex-m
+//! that disables all interrupts. A critical section raises BASEPRI to the
+//! ceiling instead, so interrupts of a higher priority (a lower priority
+//! value) than the ceiling still run, e.g. motor commutation that mustn't
+//! wait for UART buffer bookkeeping.
+//!
+//! Those interrupts aren't excluded by critical sections, so they must not
+//! use data shared through them, other than data only written before the
+//! interrupt is enabled. Priority 0 can't be masked by BASEPRI, so the
+//! ceiling is at least [`PRIORITY_STEP`].
+//!
+//! Every driver handler in this crate shares state with the driver through
+//! critical sections, e.g. [`can::RxHandler`](crate::can::RxHandler) reads
+//! its callbacks and ID filter in one, so the interrupts drivers enable are
+//! lowered to the ceiling if they were above it. Driver handlers, CAN
+//! reception included, therefore never run above the ceiling.
+//!
+//! Only a handler of your own that shares nothing through critical sections
+//! can stay above it, opted in with [`allow_above_ceiling`] before the
+//! interrupt is enabled, e.g. motor commutation touching only its timer:
+//!
+//! ```ignore
+//! // Everything at priority 0x40 and below is masked, 0x00 - 0x30 run
+//! basepri::set_ceiling(0x40);
+//! unsafe { basepri::allow_above_ceiling(commutation_interrupt) };
+//! nvic.set_priority(commutation_interrupt, 0x20);
+//! let can = Can::new(p.CAN0, rx, tx, bit_config, Irq)?; // CAN at 0x40
+//! ```
+
+use core::sync::atomic::{AtomicU8, AtomicU32, Ordering};
+
+use cortex_m::register::{basepri, basepri_max};
+
+/// Difference between adjacent priorities, the RA4M1 implements the top 4
+/// bits of each priority.
+pub const PRIORITY_STEP: u8 = 1 << 4;
+
+static CEILING: AtomicU8 = AtomicU8::new(PRIORITY_STEP);
+
+// IELn interrupts left at their priority when enabled, bit n for IELn
+static ABOVE_CEILING: AtomicU32 = AtomicU32::new(0);
+
+/// Mask interrupts with priority values of `priority` and above in
+/// critical sections, in the units of `NVIC::set_priority`.
+///
+/// Set before starting anything that uses critical sections from its
+/// interrupts, drivers lower the interrupts they enable to the ceiling.
+pub fn set_ceiling(priority: u8) {
+    CEILING.store(priority.max(PRIORITY_STEP), Ordering::Relaxed);
+}
+
+/// The highest priority masked by a critical section.
+pub fn ceiling() -> u8 {
+    CEILING.load(Ordering::Relaxed)
+}
+
+/// Keep the priority of `interrupt` when it's enabled, instead of lowering
+/// it to the ceiling, so it can be set above.
+///
+/// ## Safety
+/// The handler of `interrupt` must not use anything shared through
+/// critical sections, which includes every driver handler in this crate.
+pub unsafe fn allow_above_ceiling(interrupt: ra4m1::Interrupt) {
+    ABOVE_CEILING.fetch_or(1 << interrupt as u32, Ordering::Relaxed);
+}
+
+/// Whether `interrupt` was opted in with [`allow_above_ceiling`].
+pub fn is_allowed_above_ceiling(interrupt: ra4m1::Interrupt) -> bool {
+    ABOVE_CEILING.load(Ordering::Relaxed) & (1 << interrupt as u32) != 0
+}
+
+struct BasepriCriticalSection;
+critical_section::set_impl!(BasepriCriticalSection);
+
+unsafe impl critical_section::Impl for BasepriCriticalSection {
+    unsafe fn acquire() -> critical_section::RawRestoreState {
+        let previous = basepri::read();
+        // Only ever raises the mask, so a nested section inside a higher
+        // priority mask keeps it
+        basepri_max::write(ceiling());
+        previous
+    }
+
+    unsafe fn release(previous: critical_section::RawRestoreState) {
+        unsafe { basepri::write(previous) };
+    }
+}
@@ -0,0 +1,224 @@
+//! Update mode entry at boot, from a pin or a magic sequence on a UART.
+//!
+//! Called first thing in `main`, [`Entry::wait`] watches a pin and a serial
+//! port for [`WINDOW_MS`]. If the pin is held low or the magic sequence
+//! arrives the application goes to update mode instead of starting, so a
+//! board with broken firmware can still be updated in the field:
+//!
+//! ```ignore
+//! let mut uart = Uart::new(p.SCI2, pins.p302, pins.p301, 115_200, Irq)?;
+//! gpio::configure(&mut pins.p111, PinConfig::input().pull_up());
+//! if bootload::Entry::new().pin(&pins.p111).wait(&mut uart).is_some() {
+//!     // Stays in the Arduino bootloader, for `dfu-util` or the Arduino IDE
+//!     bootload::enter_bootloader();
+//!     // Or receive an image with YMODEM, e.g. `sb firmware.bin`
+//!     let error = unsafe { bootload::run_updater(uart, Flash::new(24_000_000, 48_000_000), ticker::millis) };
+//! }
+//! ```
+//!
+//! The window delays every boot, keep it short where start up time matters.
+
+use embedded_io::{Read, ReadReady, Write};
+
+use crate::clk::Clocks;
+use crate::flash::Flash;
+use crate::fwupdate::{self, Updater, crc32_update};
+use crate::gpio::{self, Pin};
+use crate::xmodem::{self, Event, Mode, Receiver};
+
+/// Default time to wait for the pin or the magic sequence.
+pub const WINDOW_MS: u32 = 500;
+
+/// Default magic sequence, two escapes and "BOOT".
+pub const MAGIC: &[u8] = b"\x1B\x1BBOOT";
+
+/// Time the pin must stay low.
+pub const HOLD_MS: u32 = 20;
+
+/// Value the Arduino bootloader looks for in the first backup register to
+/// stay in update mode, as after a double tap of reset.
+pub const DOUBLE_TAP_MAGIC: u32 = 0x0773_8135;
+
+// VBATT backup registers 0 - 3, kept across resets
+const VBTBKR: *mut u32 = 0x4001_E500 as *mut u32;
+
+/// Update error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The transfer failed
+    Xmodem(xmodem::Error),
+    /// The image couldn't be stored or didn't verify
+    Update(fwupdate::Error),
+    /// The YMODEM header has no file size
+    MissingSize,
+    /// The transfer ended without an image
+    NoImage,
+}
+
+impl From<xmodem::Error> for Error {
+    fn from(e: xmodem::Error) -> Self {
+        Error::Xmodem(e)
+    }
+}
+
+impl From<fwupdate::Error> for Error {
+    fn from(e: fwupdate::Error) -> Self {
+        Error::Update(e)
+    }
+}
+
+/// What asked for update mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Trigger {
+    /// The pin was held low for [`HOLD_MS`]
+    Pin,
+    /// The magic sequence arrived
+    Magic,
+}
+
+/// Conditions checked by [`wait`](Self::wait).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry<'a> {
+    // Port and pin number
+    pin: Option<(u8, u8)>,
+    magic: &'a [u8],
+    window_ms: u32,
+}
+
+impl Default for Entry<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Entry<'a> {
+    /// Watch for [`MAGIC`] for [`WINDOW_MS`], without a pin.
+    pub const fn new() -> Self {
+        Self {
+            pin: None,
+            magic: MAGIC,
+            window_ms: WINDOW_MS,
+        }
+    }
+
+    /// Also enter when `pin` is held low, e.g. a button to ground with the
+    /// pull-up enabled.
+    pub fn pin<P: Pin>(mut self, _pin: &P) -> Self {
+        self.pin = Some((P::port(), P::pin()));
+        self
+    }
+
+    /// Watch for `magic` instead of [`MAGIC`].
+    pub const fn magic(mut self, magic: &'a [u8]) -> Self {
+        self.magic = magic;
+        self
+    }
+
+    /// Watch for `ms` instead of [`WINDOW_MS`].
+    pub const fn window_ms(mut self, ms: u32) -> Self {
+        self.window_ms = ms;
+        self
+    }
+
+    /// Watch the pin and `port` for the window, returning as soon as one
+    /// asks for update mode.
+    ///
+    /// Busy waits on the CPU clock, so works before any timer is set up.
+    /// Bytes read from the port are consumed.
+    pub fn wait<R: Read + ReadReady>(&self, port: &mut R) -> Option<Trigger> {
+        let cycles_per_ms = Clocks::read().iclk() / 1000;
+        let mut matched = 0;
+        let mut low_ms = 0;
+        for _ in 0..self.window_ms {
+            while !self.magic.is_empty() && port.read_ready().unwrap_or(false) {
+                let mut byte = [0u8; 1];
+                if port.read(&mut byte).unwrap_or(0) == 0 {
+                    break;
+                }
+                matched = match byte[0] {
+                    b if b == self.magic[matched] => matched + 1,
+                    b if b == self.magic[0] => 1,
+                    _ => 0,
+                };
+                if matched == self.magic.len() {
+                    return Some(Trigger::Magic);
+                }
+            }
+            if let Some((port, pin)) = self.pin {
+                low_ms = if gpio::is_high_at(port, pin) {
+                    0
+                } else {
+                    low_ms + 1
+                };
+                if low_ms >= HOLD_MS {
+                    return Some(Trigger::Pin);
+                }
+            }
+            cortex_m::asm::delay(cycles_per_ms);
+        }
+        None
+    }
+}
+
+/// Reset into the Arduino bootloader and stay there, as after a double tap
+/// of the reset button, for `dfu-util` or the Arduino IDE.
+pub fn enter_bootloader() -> ! {
+    unsafe { VBTBKR.write_volatile(DOUBLE_TAP_MAGIC) };
+    crate::reset::software_reset()
+}
+
+/// Receive an image with YMODEM on `port`, install it with
+/// [`fwupdate`](crate::fwupdate) and reset.
+///
+/// `millis` gives the time in milliseconds, e.g.
+/// [`ticker::millis`](crate::ticker::millis). The image is checked by
+/// reading it back against the CRC of the data received. Only returns on
+/// failure, with the running application untouched.
+///
+/// ## Safety
+/// As [`Updater::apply`], the application is overwritten.
+pub unsafe fn run_updater<P: Read + ReadReady + Write>(
+    port: P,
+    flash: Flash,
+    millis: fn() -> u32,
+) -> Error {
+    let mut rx = Receiver::new(port, Mode::Ymodem);
+    let mut updater = Updater::new(flash);
+    let mut files = 0;
+    let mut crc = 0;
+    while !rx.is_done() {
+        let result = match rx.poll(millis()) {
+            Ok(Some(Event::File {
+                size: Some(size), ..
+            })) => {
+                files += 1;
+                // Only the first file is used
+                if files == 1 {
+                    updater.begin(size).map_err(Error::from)
+                } else {
+                    Ok(())
+                }
+            }
+            Ok(Some(Event::File { size: None, .. })) => Err(Error::MissingSize),
+            Ok(Some(Event::Data(data))) if files == 1 => {
+                crc = crc32_update(crc, data);
+                updater.write(data).map_err(Error::from)
+            }
+            Ok(_) => Ok(()),
+            Err(e) => return e.into(),
+        };
+        if let Err(e) = result {
+            let _ = rx.cancel();
+            return e;
+        }
+    }
+    if files == 0 {
+        return Error::NoImage;
+    }
+    if let Err(e) = updater.finish(crc) {
+        return e.into();
+    }
+    unsafe { updater.apply() }.into()
+}
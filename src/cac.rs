@@ -0,0 +1,258 @@
+//! Clock frequency accuracy measurement circuit (CAC)
+//!
+//! Counts a target clock over a period of a reference clock and flags a
+//! frequency error when the count is outside a window. Measurement repeats
+//! on every reference edge, so once started the target is checked without
+//! the CPU and [`ErrorHandler`] runs on a deviation.
+//!
+//! The UNO R4 runs from the HOCO, which drifts with temperature. With a
+//! crystal on the sub-clock or main oscillator as the reference, the CAC
+//! checks the HOCO is within what the CAN bit timing tolerates:
+//!
+//! ```ignore
+//! bind_interrupts!(struct Irq {
+//!     IEL13 => cac::ErrorHandler;
+//! });
+//!
+//! let config = cac::Config {
+//!     target: cac::Source::Hoco,
+//!     target_hz: 48_000_000,
+//!     reference: cac::Source::SubClock,
+//!     reference_hz: 32_768,
+//!     tolerance_ppm: bit_config.tolerance_ppm(),
+//! };
+//! let mut cac = cac::Cac::new(p.CAC, config)?;
+//! cac.enable_interrupt(Irq, Some(on_clock_error));
+//! cac.start();
+//! ```
+
+use core::cell::Cell;
+
+use critical_section::Mutex;
+
+use crate::clk::{ClockGuard, Peripheral};
+use crate::events::Event;
+use crate::interrupts::{Binding, Handler, clear_interrupt, map_and_enable_interrupt};
+
+const CAC: usize = 0x4004_4600;
+const CACR0: *mut u8 = CAC as *mut u8;
+const CACR1: *mut u8 = (CAC + 0x01) as *mut u8;
+const CACR2: *mut u8 = (CAC + 0x02) as *mut u8;
+const CAICR: *mut u8 = (CAC + 0x03) as *mut u8;
+const CASTR: *const u8 = (CAC + 0x04) as *const u8;
+const CAULVR: *mut u16 = (CAC + 0x06) as *mut u16;
+const CALLVR: *mut u16 = (CAC + 0x08) as *mut u16;
+const CACNTBR: *const u16 = (CAC + 0x0A) as *const u16;
+
+// CACR0.CFME, measurement enable
+const CACR0_CFME: u8 = 1 << 0;
+// CACR2.RPS, internal reference clock
+const CACR2_RPS: u8 = 1 << 0;
+// CAICR interrupt enables and flag clears
+const CAICR_FERRIE: u8 = 1 << 0;
+const CAICR_FERRFCL: u8 = 1 << 4;
+const CAICR_MENDFCL: u8 = 1 << 5;
+const CAICR_OVFFCL: u8 = 1 << 6;
+// CASTR flags
+const CASTR_FERRF: u8 = 1 << 0;
+const CASTR_MENDF: u8 = 1 << 1;
+const CASTR_OVFF: u8 = 1 << 2;
+
+// Division ratios of TCSS and RCDS, in register order
+const TARGET_DIVS: [u32; 4] = [1, 4, 8, 32];
+const REFERENCE_DIVS: [u32; 4] = [32, 128, 1024, 8192];
+
+static CALLBACK: Mutex<Cell<Option<fn(u16)>>> = Mutex::new(Cell::new(None));
+
+/// Clock measured or used as the reference, FMCS and RSCS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Source {
+    MainOsc = 0b000,
+    SubClock = 0b001,
+    Hoco = 0b010,
+    Moco = 0b011,
+    Loco = 0b100,
+    Pclkb = 0b101,
+    /// The IWDT's own 15 kHz oscillator
+    Iwdt = 0b110,
+}
+
+/// Measurement configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Clock to check
+    pub target: Source,
+    /// Nominal frequency of the target in Hz
+    pub target_hz: u32,
+    /// Clock to measure against, usually a crystal
+    pub reference: Source,
+    /// Frequency of the reference in Hz
+    pub reference_hz: u32,
+    /// Allowed deviation of the target in ppm, e.g. from
+    /// [`BitConfig::tolerance_ppm`](crate::can::BitConfig::tolerance_ppm)
+    pub tolerance_ppm: u32,
+}
+
+/// CAC error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// No division ratios give a count that fits the 16-bit counter and
+    /// resolves the tolerance
+    Range,
+}
+
+/// Calls the callback set with [`Cac::enable_interrupt`] with the count
+/// that was outside the window.
+pub struct ErrorHandler;
+
+impl Handler for ErrorHandler {
+    unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
+        clear_interrupt(interrupt);
+        let count = unsafe { CACNTBR.read_volatile() };
+        clear_flags();
+        let callback = critical_section::with(|cs| CALLBACK.borrow(cs).get());
+        if let Some(callback) = callback {
+            callback(count);
+        }
+    }
+}
+
+/// Result of the last measurement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Measurement {
+    /// Target clock counts in one reference period
+    pub count: u16,
+    /// Deviation of the target from its nominal frequency
+    pub error_ppm: i32,
+    /// The count was outside the window
+    pub out_of_tolerance: bool,
+}
+
+/// The CAC, stopped until [`start`](Self::start).
+pub struct Cac {
+    config: Config,
+    // Count expected at the nominal frequency
+    expected: u32,
+    _clock: ClockGuard,
+}
+
+impl Cac {
+    /// Set up the measurement, picking the division ratios with the largest
+    /// count that fits the counter.
+    ///
+    /// Both clocks must be running, see [`clk`](crate::clk).
+    pub fn new(_cac: ra4m1::CAC, config: Config) -> Result<Self, Error> {
+        let (tcss, rcds, expected) = divisions(&config).ok_or(Error::Range)?;
+        let margin = (expected as u64 * config.tolerance_ppm as u64).div_ceil(1_000_000) as u32;
+        let clock = ClockGuard::new(Peripheral::Cac);
+        unsafe {
+            CACR0.write_volatile(0);
+            CACR1.write_volatile(((tcss as u8) << 4) | ((config.target as u8) << 1));
+            CACR2.write_volatile(((rcds as u8) << 4) | ((config.reference as u8) << 1) | CACR2_RPS);
+            CAULVR.write_volatile((expected + margin).min(u16::MAX as u32) as u16);
+            CALLVR.write_volatile(expected.saturating_sub(margin) as u16);
+        }
+        clear_flags();
+        Ok(Self {
+            config,
+            expected,
+            _clock: clock,
+        })
+    }
+
+    /// Start measuring, repeating on every reference period.
+    pub fn start(&mut self) {
+        unsafe { CACR0.write_volatile(CACR0_CFME) };
+    }
+
+    /// Stop measuring.
+    pub fn stop(&mut self) {
+        unsafe { CACR0.write_volatile(0) };
+    }
+
+    /// The measurement since the last call, `None` if none has finished.
+    pub fn measurement(&mut self) -> Option<Measurement> {
+        let status = unsafe { CASTR.read_volatile() };
+        if status & CASTR_MENDF == 0 {
+            return None;
+        }
+        let count = unsafe { CACNTBR.read_volatile() };
+        clear_flags();
+        Some(Measurement {
+            count,
+            error_ppm: self.error_ppm(count),
+            out_of_tolerance: status & (CASTR_FERRF | CASTR_OVFF) != 0,
+        })
+    }
+
+    /// Deviation of the target for a `count` from the counter.
+    pub fn error_ppm(&self, count: u16) -> i32 {
+        let expected = self.expected as i64;
+        ((count as i64 - expected) * 1_000_000 / expected) as i32
+    }
+
+    /// Measured frequency of the target in Hz for a `count`.
+    pub fn frequency(&self, count: u16) -> u32 {
+        (self.config.target_hz as u64 * count as u64 / self.expected as u64) as u32
+    }
+
+    /// Map the frequency error interrupt to [`ErrorHandler`] and set its
+    /// callback.
+    pub fn enable_interrupt<IRQ>(&mut self, _irq: IRQ, callback: Option<fn(u16)>)
+    where
+        IRQ: Binding<ErrorHandler>,
+    {
+        critical_section::with(|cs| CALLBACK.borrow(cs).set(callback));
+        let interrupt = <IRQ as Binding<ErrorHandler>>::interrupt();
+        clear_interrupt(interrupt);
+        map_and_enable_interrupt(interrupt, Event::CAC_FERRI);
+        unsafe { CAICR.write_volatile(CAICR_FERRIE) };
+    }
+}
+
+impl Drop for Cac {
+    fn drop(&mut self) {
+        unsafe {
+            CACR0.write_volatile(0);
+            CAICR.write_volatile(0);
+        }
+    }
+}
+
+// Write the flag clear bits, keeping the interrupt enables
+fn clear_flags() {
+    unsafe {
+        let enables = CAICR.read_volatile() & 0x0F;
+        CAICR.write_volatile(enables | CAICR_FERRFCL | CAICR_MENDFCL | CAICR_OVFFCL);
+    }
+}
+
+// TCSS and RCDS codes and the expected count with the largest count that
+// fits with the tolerance above it and resolves the tolerance
+fn divisions(config: &Config) -> Option<(usize, usize, u32)> {
+    if config.reference_hz == 0 {
+        return None;
+    }
+    let tolerance = config.tolerance_ppm as u64;
+    let mut best: Option<(usize, usize, u32)> = None;
+    for (tcss, tdiv) in TARGET_DIVS.iter().enumerate() {
+        for (rcds, rdiv) in REFERENCE_DIVS.iter().enumerate() {
+            let expected = config.target_hz as u64 * *rdiv as u64
+                / (*tdiv as u64 * config.reference_hz as u64);
+            let upper = expected + (expected * tolerance).div_ceil(1_000_000);
+            if upper > u16::MAX as u64 {
+                continue;
+            }
+            if best.is_none_or(|(_, _, count)| expected as u32 > count) {
+                best = Some((tcss, rcds, expected as u32));
+            }
+        }
+    }
+    // One count must be finer than the tolerance
+    best.filter(|(_, _, count)| {
+        *count > 0 && (tolerance == 0 || *count as u64 * tolerance >= 1_000_000)
+    })
+}
@@ -1,12 +1,44 @@
+use core::cell::{Cell, RefCell};
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use critical_section::Mutex;
 use embedded_io::Write;
 use ra4m1::CAN0;
 
 use embedded_can::{ExtendedId, Id, StandardId};
 
+use crate::clk::{ClockGuard, Clocks, Peripheral};
+use crate::events::Event;
+use crate::gpio::{self, Pin};
 use crate::interrupts::{Binding, Handler, clear_interrupt, map_and_enable_interrupt};
 
-trait Instance {
+pub mod router;
+#[cfg(feature = "gpt")]
+mod scheduler;
+pub mod signals;
+#[cfg(feature = "gpt")]
+mod timed;
+
+#[cfg(feature = "gpt")]
+pub use scheduler::{MAX_SCHEDULED, ScheduleHandler, ScheduleId, Scheduler};
+#[cfg(feature = "gpt")]
+pub use timed::{SendAtHandler, SendAtTimer};
+
+/// A CAN module, the RA4M1 has one, CAN0.
+pub trait Instance {
+    // Get access to the peripheral's register block.
     fn peripheral() -> *const ra4m1::can0::RegisterBlock;
+    fn tx_queue() -> &'static Mutex<RefCell<TxQueue>>;
+    fn rx_callbacks() -> &'static Mutex<Cell<[Option<fn(&Frame)>; 32]>>;
+    fn error_events() -> &'static Mutex<RefCell<ErrorEvents>>;
+    fn rx_filter() -> &'static Mutex<RefCell<IdFilter>>;
+    // Transmit mailboxes loaded for a timed send, skipped when sending
+    fn held() -> &'static AtomicU32;
+    // Module stop bit
+    fn clock() -> Peripheral;
+    // First event of this instance (ERS), followed by RXF, TXF, RXM and TXM
+    fn event_base() -> Event;
 }
 
 impl Instance for ra4m1::CAN0 {
@@ -14,8 +46,59 @@ impl Instance for ra4m1::CAN0 {
         // Return the pointer to the CAN0 peripheral
         CAN0::ptr()
     }
+
+    fn tx_queue() -> &'static Mutex<RefCell<TxQueue>> {
+        static QUEUE: Mutex<RefCell<TxQueue>> = Mutex::new(RefCell::new(TxQueue::new()));
+        &QUEUE
+    }
+
+    fn rx_callbacks() -> &'static Mutex<Cell<[Option<fn(&Frame)>; 32]>> {
+        static CALLBACKS: Mutex<Cell<[Option<fn(&Frame)>; 32]>> = Mutex::new(Cell::new([None; 32]));
+        &CALLBACKS
+    }
+
+    fn error_events() -> &'static Mutex<RefCell<ErrorEvents>> {
+        static EVENTS: Mutex<RefCell<ErrorEvents>> = Mutex::new(RefCell::new(ErrorEvents::new()));
+        &EVENTS
+    }
+
+    fn rx_filter() -> &'static Mutex<RefCell<IdFilter>> {
+        static FILTER: Mutex<RefCell<IdFilter>> = Mutex::new(RefCell::new(IdFilter::new()));
+        &FILTER
+    }
+
+    fn held() -> &'static AtomicU32 {
+        static HELD: AtomicU32 = AtomicU32::new(0);
+        &HELD
+    }
+
+    fn clock() -> Peripheral {
+        Peripheral::Can0
+    }
+
+    fn event_base() -> Event {
+        Event::CAN0_ERS
+    }
 }
 
+// Offsets of the events from `Instance::event_base`
+const EVENT_ERS: u8 = 0;
+const EVENT_RXM: u8 = 3;
+const EVENT_TXM: u8 = 4;
+
+/// Pin that can be used as CRX of CAN module `I`.
+pub trait RxPin<I: Instance>: Pin {}
+
+/// Pin that can be used as CTX of CAN module `I`.
+pub trait TxPin<I: Instance>: Pin {}
+
+impl RxPin<CAN0> for gpio::P102 {}
+impl RxPin<CAN0> for gpio::P110 {}
+impl RxPin<CAN0> for gpio::P402 {}
+impl TxPin<CAN0> for gpio::P103 {}
+impl TxPin<CAN0> for gpio::P109 {}
+impl TxPin<CAN0> for gpio::P401 {}
+
 /// Triggers on transmission of a frame.
 pub struct TxHandler<I: Instance> {
     _phantom: core::marker::PhantomData<I>,
@@ -23,9 +106,6 @@ pub struct TxHandler<I: Instance> {
 
 impl<I: Instance> Handler for TxHandler<I> {
     unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
-        // led
-        let p = unsafe { ra4m1::Peripherals::steal() };
-        p.PORT1.podr().modify(|_, w| w.bits(1 << 11)); // Set P111 high
         clear_interrupt(interrupt);
         // Get access to can registers
         let can = unsafe { &*I::peripheral() };
@@ -43,6 +123,395 @@ impl<I: Instance> Handler for TxHandler<I> {
         }
         // Restore msmr state
         can.msmr.write(|w| unsafe { w.bits(msmr) });
+        // Refill the free mailboxes from the queue
+        drain_tx_queue::<I>();
+    }
+}
+
+/// Triggers on reception of a frame in a mailbox, calls the callbacks set
+/// with [`Can::on_receive`] with the frames the software filter passes, see
+/// [`Can::add_filter`].
+///
+/// Frames in mailboxes without a callback are left for
+/// [`Can::try_receive_frame`].
+pub struct RxHandler<I: Instance> {
+    _phantom: core::marker::PhantomData<I>,
+}
+
+impl<I: Instance> Handler for RxHandler<I> {
+    unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
+        clear_interrupt(interrupt);
+        let can = unsafe { &*I::peripheral() };
+        let callbacks = critical_section::with(|cs| I::rx_callbacks().borrow(cs).get());
+        for (i, callback) in callbacks.iter().enumerate() {
+            if let Some(callback) = callback {
+                if let Some(frame) = read_mailbox(can, i).filter(accepts::<I>) {
+                    callback(&frame);
+                }
+            }
+        }
+    }
+}
+
+/// Number of [`CanEvent`]s kept for [`Can::next_error_event`].
+pub const ERROR_EVENTS: usize = 8;
+
+// EIFR / EIER bits, in the order of the events
+const EIFR_BEIF: u8 = 1 << 0;
+const EIFR_ALL: u8 = 0xFF;
+// ECSR.EDPM, error display mode, the rest are error flags
+const ECSR_EDPM: u8 = 1 << 7;
+
+/// Error or status change of the CAN module, from [`ErrorHandler`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CanEvent {
+    /// An error was detected on the bus, with the ECSR flags: bit 0 stuff,
+    /// 1 form, 2 ack, 3 CRC, 4 bit recessive, 5 bit dominant, 6 ack
+    /// delimiter
+    BusError(u8),
+    /// An error count passed 95
+    ErrorWarning,
+    /// An error count passed 127
+    ErrorPassive,
+    /// The transmit error count passed 255, the module is off the bus
+    BusOff,
+    /// The module is back on the bus after bus-off
+    BusOffRecovered,
+    /// A frame was received into a mailbox that still held an unread one
+    Overrun,
+    /// An overload frame was sent
+    Overload,
+    /// The bus has been dominant for 32 bits, e.g. a short circuit
+    BusLock,
+}
+
+impl CanEvent {
+    // Events for the EIFR flags set in `eifr`, in bit order
+    fn from_flags(eifr: u8, ecsr: u8) -> impl Iterator<Item = CanEvent> {
+        [
+            CanEvent::BusError(ecsr & !ECSR_EDPM),
+            CanEvent::ErrorWarning,
+            CanEvent::ErrorPassive,
+            CanEvent::BusOff,
+            CanEvent::BusOffRecovered,
+            CanEvent::Overrun,
+            CanEvent::Overload,
+            CanEvent::BusLock,
+        ]
+        .into_iter()
+        .enumerate()
+        .filter(move |(bit, _)| eifr & (1 << bit) != 0)
+        .map(|(_, event)| event)
+    }
+}
+
+/// Maximum number of IDs in the software receive filter.
+pub const MAX_FILTER_IDS: usize = 32;
+
+/// How the software receive filter uses its IDs, see [`Can::add_filter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FilterMode {
+    /// Only frames with a listed ID are received
+    Allow,
+    /// Frames with a listed ID are dropped
+    Deny,
+}
+
+// Software receive filter, exact IDs kept sorted for a binary search in
+// the receive handler
+struct IdFilter {
+    mode: Option<FilterMode>,
+    ids: heapless::Vec<u32, MAX_FILTER_IDS>,
+}
+
+impl IdFilter {
+    const fn new() -> Self {
+        Self {
+            mode: None,
+            ids: heapless::Vec::new(),
+        }
+    }
+
+    // Standard and extended IDs with the same value are different IDs
+    fn key(id: Id) -> u32 {
+        match id {
+            Id::Standard(id) => id.as_raw() as u32,
+            Id::Extended(id) => id.as_raw() | 1 << 31,
+        }
+    }
+
+    fn add(&mut self, id: Id) -> Result<(), Error> {
+        let key = Self::key(id);
+        if let Err(index) = self.ids.binary_search(&key) {
+            self.ids.insert(index, key).map_err(|_| Error::FilterFull)?;
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, id: Id) -> bool {
+        match self.ids.binary_search(&Self::key(id)) {
+            Ok(index) => {
+                self.ids.remove(index);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn accepts(&self, id: Id) -> bool {
+        match self.mode {
+            None => true,
+            Some(mode) => {
+                self.ids.binary_search(&Self::key(id)).is_ok() == (mode == FilterMode::Allow)
+            }
+        }
+    }
+}
+
+// Whether the software filter of `I` passes `frame`
+fn accepts<I: Instance>(frame: &Frame) -> bool {
+    let id = embedded_can::Frame::id(frame);
+    critical_section::with(|cs| I::rx_filter().borrow_ref(cs).accepts(id))
+}
+
+// Callback or queue for the error events
+struct ErrorEvents {
+    callback: Option<fn(CanEvent)>,
+    queue: heapless::Deque<CanEvent, ERROR_EVENTS>,
+}
+
+impl ErrorEvents {
+    const fn new() -> Self {
+        Self {
+            callback: None,
+            queue: heapless::Deque::new(),
+        }
+    }
+}
+
+/// Triggers on CAN errors and error state changes, calls the callback set
+/// with [`Can::on_error`] or queues the events for
+/// [`Can::next_error_event`].
+///
+/// When the queue is full the oldest event is dropped.
+pub struct ErrorHandler<I: Instance> {
+    _phantom: core::marker::PhantomData<I>,
+}
+
+impl<I: Instance> Handler for ErrorHandler<I> {
+    unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
+        clear_interrupt(interrupt);
+        let can = unsafe { &*I::peripheral() };
+        let eifr = can.eifr.read().bits();
+        let ecsr = can.ecsr.read().bits();
+        // Flags are cleared by writing 0, writing 1 leaves them
+        can.eifr.write(|w| unsafe { w.bits(!eifr) });
+        if eifr & EIFR_BEIF != 0 {
+            can.ecsr
+                .write(|w| unsafe { w.bits((ecsr & ECSR_EDPM) | !(ecsr | ECSR_EDPM)) });
+        }
+        let callback = critical_section::with(|cs| I::error_events().borrow_ref(cs).callback);
+        for event in CanEvent::from_flags(eifr, ecsr) {
+            match callback {
+                Some(callback) => callback(event),
+                None => critical_section::with(|cs| {
+                    let queue = &mut I::error_events().borrow_ref_mut(cs).queue;
+                    if queue.is_full() {
+                        queue.pop_front();
+                    }
+                    let _ = queue.push_back(event);
+                }),
+            }
+        }
+    }
+}
+
+/// Error from [`Can::queue_frame`], the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct QueueFull;
+
+/// CAN errors.
+///
+/// Functions that can only fail one way return that type, e.g.
+/// [`QueueFull`] or [`ModeTimeout`], which convert into this.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// Every transmit mailbox is busy
+    TxMailboxFull,
+    /// The software transmit queue is full, or has no buffer
+    QueueFull,
+    /// Too many errors, the module is off the bus and can't send
+    BusOff,
+    /// More than 8 data bytes
+    DataLength,
+    /// Bit timing values out of range
+    InvalidConfig,
+    /// A mode change didn't complete in time
+    ModeTimeout(ModeTimeout),
+    /// The [`Scheduler`] already has [`MAX_SCHEDULED`] frames
+    ScheduleFull,
+    /// The time given to [`Can::send_at`] has passed
+    Late,
+    /// The [`SendAtTimer`] already has a frame waiting
+    TimerBusy,
+    /// The software receive filter already has [`MAX_FILTER_IDS`] IDs
+    FilterFull,
+    /// Every subscriber of the [`Router`](router::Router) is taken
+    RouterFull,
+    /// [`Can::flush`] didn't see every frame sent within
+    /// [`FLUSH_TIMEOUT_CYCLES`]
+    FlushTimeout,
+}
+
+impl From<QueueFull> for Error {
+    fn from(_: QueueFull) -> Self {
+        Error::QueueFull
+    }
+}
+
+impl From<ModeTimeout> for Error {
+    fn from(e: ModeTimeout) -> Self {
+        Error::ModeTimeout(e)
+    }
+}
+
+// Frames waiting for a free transmit mailbox
+struct TxQueue {
+    buf: Option<&'static mut [Frame]>,
+    head: usize,
+    len: usize,
+    watermark: usize,
+    on_watermark: Option<fn(usize)>,
+}
+
+impl TxQueue {
+    const fn new() -> Self {
+        TxQueue {
+            buf: None,
+            head: 0,
+            len: 0,
+            watermark: 0,
+            on_watermark: None,
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.buf.as_ref().map_or(0, |buf| buf.len())
+    }
+
+    fn push(&mut self, frame: Frame) -> Result<(), QueueFull> {
+        let capacity = self.capacity();
+        match &mut self.buf {
+            Some(buf) if self.len < capacity => {
+                buf[(self.head + self.len) % capacity] = frame;
+                self.len += 1;
+                Ok(())
+            }
+            _ => Err(QueueFull),
+        }
+    }
+}
+
+// Move queued frames into free mailboxes, then call the watermark callback
+// if the queue dropped to the watermark
+fn drain_tx_queue<I: Instance>() {
+    let can = unsafe { &*I::peripheral() };
+    let held = I::held().load(Ordering::Relaxed);
+    let notify = critical_section::with(|cs| {
+        let mut queue = I::tx_queue().borrow_ref_mut(cs);
+        let before = queue.len;
+        let capacity = queue.capacity();
+        while queue.len > 0 {
+            let head = queue.head;
+            let Some(buf) = &queue.buf else {
+                break;
+            };
+            if !load_mailbox(can, held, &buf[head]) {
+                break;
+            }
+            queue.head = (head + 1) % capacity;
+            queue.len -= 1;
+        }
+        match queue.on_watermark {
+            Some(callback) if before > queue.watermark && queue.len <= queue.watermark => {
+                Some((callback, queue.len))
+            }
+            _ => None,
+        }
+    });
+    // Outside the critical section, so the callback can queue more frames
+    if let Some((callback, len)) = notify {
+        callback(len);
+    }
+}
+
+// Send a frame, or queue it behind the frames already queued
+fn queue_frame<I: Instance>(frame: Frame) -> Result<(), QueueFull> {
+    let can = unsafe { &*I::peripheral() };
+    reclaim_mailboxes(can);
+    drain_tx_queue::<I>();
+    critical_section::with(|cs| {
+        let mut queue = I::tx_queue().borrow_ref_mut(cs);
+        if queue.len == 0 && load_mailbox(can, I::held().load(Ordering::Relaxed), &frame) {
+            return Ok(());
+        }
+        queue.push(frame)
+    })
+}
+
+// Free transmit mailboxes whose frame has been sent
+fn reclaim_mailboxes(can: &ra4m1::can0::RegisterBlock) {
+    for i in 0..32 {
+        let r = can.mctl_tx()[i].read();
+        if r.trmreq().bit_is_set() && r.sentdata().bit_is_set() {
+            // TRMREQ must be cleared before SENTDATA
+            can.mctl_tx()[i].write(|w| unsafe { w.bits(0) });
+            can.mctl_tx()[i].write(|w| unsafe { w.bits(0) });
+        }
+    }
+}
+
+// Write a frame into the first free transmit mailbox and request transmission,
+// false if none is free. Mailboxes in `held` are skipped.
+fn load_mailbox(can: &ra4m1::can0::RegisterBlock, held: u32, frame: &Frame) -> bool {
+    let Some(i) = free_mailbox(can, held) else {
+        return false;
+    };
+    write_mailbox(can, i, frame);
+    // Request transmission
+    can.mctl_tx()[i].write(|w| w.trmreq()._1());
+    true
+}
+
+// First mailbox available for transmission that isn't in `held`
+fn free_mailbox(can: &ra4m1::can0::RegisterBlock, held: u32) -> Option<usize> {
+    (0..32).find(|&i| {
+        let r = can.mctl_tx()[i].read();
+        held & (1 << i) == 0 && r.trmreq().bit_is_clear() && r.recreq().bit_is_clear()
+    })
+}
+
+// Write a frame into mailbox `i`, without requesting transmission
+fn write_mailbox(can: &ra4m1::can0::RegisterBlock, i: usize, frame: &Frame) {
+    // Write the ID to the mailbox ID register
+    let id = IdMode::read(can).encode(frame.id);
+    unsafe {
+        mb_id(can, i).write_volatile(id.into_bits());
+    }
+    // write the dlc
+    unsafe {
+        mb_dl(can, i).write_volatile(frame.dlc);
+    }
+    // Write the data to the mailbox data registers
+    let data_ptr = unsafe { mb_d0(can, i) };
+    for (j, &byte) in frame.data[..frame.dlc as usize].iter().enumerate() {
+        unsafe {
+            data_ptr.add(j).write_volatile(byte);
+        }
     }
 }
 
@@ -51,7 +520,7 @@ impl<I: Instance> Handler for TxHandler<I> {
 /// Each mailbox is 16 bytes, with the first 4 bytes being the ID register,
 /// byte 4 is unused, byte 5 is the DLC register, bytes 6-13 are the data registers
 /// and the last 2 bytes are the timestamp registers.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy)]
 pub struct Frame {
     id: MailboxId,
     dlc: u8,       // Data Length Code, 0-8 bytes
@@ -59,6 +528,95 @@ pub struct Frame {
     ts: u16,
 }
 
+impl Frame {
+    /// Empty frame with ID 0, e.g. to initialise a transmit queue buffer.
+    pub const EMPTY: Frame = Frame {
+        id: MailboxId::new(),
+        dlc: 0,
+        data: [0; 8],
+        ts: 0,
+    };
+
+    /// Data frame with an 11 bit ID, `None` if the ID or data is too long.
+    pub fn new_standard(id: u16, data: &[u8]) -> Option<Self> {
+        embedded_can::Frame::new(StandardId::new(id)?, data)
+    }
+
+    /// Data frame with a 29 bit ID, `None` if the ID or data is too long.
+    pub fn new_extended(id: u32, data: &[u8]) -> Option<Self> {
+        embedded_can::Frame::new(ExtendedId::new(id)?, data)
+    }
+
+    /// Change the ID, keeping the data and frame type.
+    pub fn set_id(&mut self, id: impl Into<Id>) {
+        let rtr = self.id.RTR();
+        self.id = MailboxId::from(id.into()).with_RTR(rtr);
+    }
+
+    /// The data bytes, `dlc` long.
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data[..self.dlc as usize]
+    }
+
+    /// Replace the data, [`Error::DataLength`] if longer than 8 bytes.
+    pub fn set_data(&mut self, data: &[u8]) -> Result<(), Error> {
+        if data.len() > 8 {
+            return Err(Error::DataLength);
+        }
+        self.data = [0; 8];
+        self.data[..data.len()].copy_from_slice(data);
+        self.dlc = data.len() as u8;
+        Ok(())
+    }
+
+    /// Value of the CAN timer when the frame was received, 0 for frames
+    /// created by the application.
+    pub fn timestamp(&self) -> u16 {
+        self.ts
+    }
+
+    // Raw ID value, 11 or 29 bits
+    fn raw_id(&self) -> u32 {
+        match Id::from(self.id) {
+            Id::Standard(id) => id.as_raw() as u32,
+            Id::Extended(id) => id.as_raw(),
+        }
+    }
+}
+
+/// candump style, `123 [2] 01 02` or `12345678 [0] remote`
+impl core::fmt::Display for Frame {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.id.is_extended() {
+            write!(f, "{:08X} [{}]", self.raw_id(), self.dlc)?;
+        } else {
+            write!(f, "{:03X} [{}]", self.raw_id(), self.dlc)?;
+        }
+        if self.id.RTR() {
+            return write!(f, " remote");
+        }
+        for b in &self.data[..self.dlc as usize] {
+            write!(f, " {:02X}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl core::fmt::Debug for Frame {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Frame")
+            .field("id", &format_args!("{:#X}", self.raw_id()))
+            .field("extended", &self.id.is_extended())
+            .field("remote", &self.id.RTR())
+            .field(
+                "data",
+                &format_args!("{:02X?}", &self.data[..self.dlc as usize]),
+            )
+            .field("ts", &self.ts)
+            .finish()
+    }
+}
+
 impl embedded_can::Frame for Frame {
     fn new(id: impl Into<Id>, data: &[u8]) -> Option<Self> {
         // Create a new Frame with the given ID and data
@@ -85,7 +643,7 @@ impl embedded_can::Frame for Frame {
         }
         let id: Id = id.into();
         Some(Self {
-            id: MailboxId::from(id),
+            id: MailboxId::from(id).with_RTR(true),
             dlc: dlc as u8,
             data: [0; 8], // Initialize data to zero
             ts: 0,        // Timestamp is not used here
@@ -118,8 +676,8 @@ impl embedded_can::Frame for Frame {
 ///
 /// Used in frame and for configuration of mailboxes.
 ///
-/// On Construction the IDE bit is set based on the ID type.
-/// The library will clear it if not in mixed mode.
+/// On Construction the IDE bit is set based on the ID type, the register
+/// value is from [`IdMode::encode`].
 #[bitfield_struct::bitfield(u32, order = Msb)]
 struct MailboxId {
     #[bits(1, default = false)]
@@ -147,9 +705,9 @@ impl From<Id> for MailboxId {
         match id {
             Id::Standard(standard_id) => Self::new().with_SID(standard_id.as_raw()),
             Id::Extended(extended_id) => {
-                // Get upper 18 bits of the extended ID
-                let eid = extended_id.as_raw() >> 11;
-                // Get 11 bits of the standard ID
+                // Get lower 18 bits of the extended ID
+                let eid = extended_id.as_raw() & 0x3FFFF;
+                // Get upper 11 bits, the standard ID
                 let sid = extended_id.standard_id().as_raw() as u32;
                 Self::new()
                     .with_IDE(true) // Set the IDE bit for extended IDs
@@ -166,7 +724,7 @@ impl From<MailboxId> for Id {
         let eid = mailbox_id.EID();
         if mailbox_id.IDE() || eid != 0 {
             Id::Extended(unsafe {
-                ExtendedId::new_unchecked((eid << 11) | (mailbox_id.SID() as u32))
+                ExtendedId::new_unchecked(((mailbox_id.SID() as u32) << 18) | eid)
             })
         } else {
             // Standard ID, must be less than 0x7FF (11 bits)
@@ -175,6 +733,71 @@ impl From<MailboxId> for Id {
     }
 }
 
+/// ID format of the frames sent and received, CTLR.IDFM.
+///
+/// Set with [`MailboxConfig::set_id_mode`]. Outside [`Mixed`](Self::Mixed)
+/// frames of the other format are neither received nor sent correctly, a
+/// standard ID goes out as the top 11 bits of an extended one and the
+/// reverse.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum IdMode {
+    /// 11 bit IDs, the mode after reset
+    #[default]
+    Standard,
+    /// 29 bit IDs
+    Extended,
+    /// Both, each mailbox has the format of its ID
+    Mixed,
+}
+
+impl IdMode {
+    // Mode the module is in
+    fn read(can: &ra4m1::can0::RegisterBlock) -> Self {
+        match can.ctlr.read().idfm().bits() {
+            0b00 => IdMode::Standard,
+            0b01 => IdMode::Extended,
+            _ => IdMode::Mixed,
+        }
+    }
+
+    fn idfm(self) -> u8 {
+        match self {
+            IdMode::Standard => 0b00,
+            IdMode::Extended => 0b01,
+            IdMode::Mixed => 0b10,
+        }
+    }
+
+    // Whether IDs of this format are received and sent
+    fn accepts(self, id: Id) -> bool {
+        matches!(
+            (self, id),
+            (IdMode::Mixed, _)
+                | (IdMode::Standard, Id::Standard(_))
+                | (IdMode::Extended, Id::Extended(_))
+        )
+    }
+
+    // Register value of `id`, IDE must be 0 outside mixed mode
+    fn encode(self, id: MailboxId) -> MailboxId {
+        match self {
+            IdMode::Mixed => id,
+            _ => id.with_IDE(false),
+        }
+    }
+
+    // ID of a received frame, with IDE set for extended IDs
+    fn decode(self, id: MailboxId) -> MailboxId {
+        match self {
+            IdMode::Standard => id.with_IDE(false).with_EID(0),
+            IdMode::Extended => id.with_IDE(true),
+            IdMode::Mixed if id.IDE() => id,
+            IdMode::Mixed => id.with_EID(0),
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 enum MailboxMode {
     Tx(MailboxTxConfig),
@@ -238,6 +861,7 @@ impl Mask {
 /// Contains 8 masks and 32 mailboxes.
 /// Mask 0 is used for mailboxes 0-3, mask 1 for mailboxes 4-7, and so on.
 pub struct MailboxConfig {
+    id_mode: IdMode,
     masks: [Mask; 8],
     mailboxes: [MailboxMode; 32],
 }
@@ -246,6 +870,7 @@ impl Default for MailboxConfig {
     fn default() -> Self {
         // Create a default configuration with all mailboxes configured for transmission
         MailboxConfig {
+            id_mode: IdMode::Standard,
             masks: [Mask::accept_all(); 8],
             mailboxes: [MailboxMode::Tx(MailboxTxConfig {
                 interrupt: false,
@@ -268,6 +893,38 @@ impl MailboxConfig {
         }
     }
 
+    /// Make mailbox `index` a receiver of frames with `id`, compared through
+    /// its mask if `use_mask`, otherwise exactly.
+    ///
+    /// The ID must be of a format the [`IdMode`] receives, checked by
+    /// [`Can::configure_mailboxes`].
+    pub fn set_mailbox_filter(&mut self, index: usize, id: impl Into<Id>, use_mask: bool) {
+        if index < 32 {
+            self.mailboxes[index] = MailboxMode::Rx(MailboxRxConfig {
+                interrupt: self.mailboxes[index].interrupt(),
+                one_shot: false,
+                mask_valid: use_mask,
+                id: id.into(),
+            });
+        }
+    }
+
+    /// Set mask `index`, used by mailboxes `4 * index` to `4 * index + 3`.
+    /// Bits set in `mask` are compared, the others accept anything.
+    ///
+    /// An extended mask needs [`IdMode::Extended`] or [`IdMode::Mixed`], a
+    /// standard one compares the top 11 bits of extended IDs.
+    pub fn set_mask(&mut self, index: usize, mask: impl Into<Id>) {
+        if index < 8 {
+            self.masks[index] = Mask { id: mask.into() };
+        }
+    }
+
+    /// Set the ID format of the module, [`IdMode::Standard`] by default.
+    pub fn set_id_mode(&mut self, mode: IdMode) {
+        self.id_mode = mode;
+    }
+
     pub fn enable_all_interrupts(&mut self) {
         // Enable interrupts for all mailboxes
         for mailbox in &mut self.mailboxes {
@@ -278,6 +935,44 @@ impl MailboxConfig {
         }
     }
 
+    /// The register values [`Can::configure_mailboxes`] writes.
+    ///
+    /// Receive mailbox IDs have the IDE bit of extended IDs only in
+    /// [`IdMode::Mixed`].
+    pub fn render(&self) -> RegisterImage {
+        let mut image = RegisterImage {
+            idfm: self.id_mode.idfm(),
+            mkr: [0; 8],
+            mier: self.mier(),
+            mkivlr: self.mkivlr(),
+            mctl: [0; 32],
+            mb_id: [0; 32],
+        };
+        for (mkr, mask) in image.mkr.iter_mut().zip(&self.masks) {
+            *mkr = mask.mkr();
+        }
+        for (i, mailbox) in self.mailboxes.iter().enumerate() {
+            if let MailboxMode::Rx(config) = mailbox {
+                image.mctl[i] = MCTL_RECREQ;
+                image.mb_id[i] = self.id_mode.encode(config.id.into()).into_bits();
+            }
+        }
+        image
+    }
+
+    // Whether every receive ID and mask is of a format the mode takes
+    fn is_valid(&self) -> bool {
+        let masks = self
+            .masks
+            .iter()
+            .all(|mask| self.id_mode != IdMode::Standard || matches!(mask.id, Id::Standard(_)));
+        let ids = self.mailboxes.iter().all(|mailbox| match mailbox {
+            MailboxMode::Rx(config) => self.id_mode.accepts(config.id),
+            MailboxMode::Tx(_) => true,
+        });
+        masks && ids
+    }
+
     fn mier(&self) -> u32 {
         // Generate the Mailbox Interrupt Enable Register (MIER) value
         // based on the mailbox configuration.
@@ -308,10 +1003,50 @@ impl MailboxConfig {
     }
 }
 
+// MCTL.RECREQ, receive request
+const MCTL_RECREQ: u8 = 1 << 6;
+
+/// Register values of a [`MailboxConfig`], from [`MailboxConfig::render`].
+///
+/// Displays as the masks and the receive mailboxes, e.g. for a shell
+/// command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RegisterImage {
+    /// CTLR.IDFM, the [`IdMode`]
+    pub idfm: u8,
+    /// Mask Registers, MKR0 - MKR7
+    pub mkr: [u32; 8],
+    /// Mailbox Interrupt Enable Register
+    pub mier: u32,
+    /// Mask Invalid Register
+    pub mkivlr: u32,
+    /// Message Control Registers, RECREQ for receive mailboxes
+    pub mctl: [u8; 32],
+    /// Mailbox ID registers of the receive mailboxes, 0 for the others
+    pub mb_id: [u32; 32],
+}
+
+impl core::fmt::Display for RegisterImage {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "IDFM: {:02b}\nMKR:", self.idfm)?;
+        for mkr in &self.mkr {
+            write!(f, " {:08X}", mkr)?;
+        }
+        write!(f, "\nMIER: {:08X} MKIVLR: {:08X}", self.mier, self.mkivlr)?;
+        for (i, (&mctl, &id)) in self.mctl.iter().zip(&self.mb_id).enumerate() {
+            if mctl != 0 {
+                write!(f, "\nMB{:02}: {:02X} id={:08X}", i, mctl, id)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 // Get a ptr to the mailbox ID register of mailbox `index`
 // ## Safety
 // The caller must ensure that `index` is within the range of 0 to 31
-unsafe fn mb_id(can0: &CAN0, index: usize) -> *mut u32 {
+unsafe fn mb_id(can0: &ra4m1::can0::RegisterBlock, index: usize) -> *mut u32 {
     let base = can0.mb0_id.as_ptr();
     // Calculate the address of the mailbox ID register
     unsafe { base.add(4 * index) }
@@ -320,7 +1055,7 @@ unsafe fn mb_id(can0: &CAN0, index: usize) -> *mut u32 {
 // Get a ptr to the first mailbox DLC register if mailbox `index`
 // ## Safety
 // The caller must ensure that `index` is within the range of 0 to 31
-unsafe fn mb_dl(can0: &CAN0, index: usize) -> *mut u8 {
+unsafe fn mb_dl(can0: &ra4m1::can0::RegisterBlock, index: usize) -> *mut u8 {
     let base = can0.mb0_id.as_ptr() as *mut u8;
     // Based on Table 30.4 in section 30.2.6 Mailbox Register
     unsafe { base.add((16 * index) + 5) }
@@ -329,13 +1064,22 @@ unsafe fn mb_dl(can0: &CAN0, index: usize) -> *mut u8 {
 // Get a ptr to the first mailbox data register if mailbox `index`
 // ## Safety
 // The caller must ensure that `index` is within the range of 0 to 31
-unsafe fn mb_d0(can0: &CAN0, index: usize) -> *mut u8 {
+unsafe fn mb_d0(can0: &ra4m1::can0::RegisterBlock, index: usize) -> *mut u8 {
     // Get a ptr to the base of the mailbox data registers
     let base = can0.mb0_id.as_ptr() as *mut u8;
     // Based on Table 30.4 in section 30.2.6 Mailbox Register
     unsafe { base.add((16 * index) + 6) }
 }
 
+// Get a ptr to the timestamp register of mailbox `index`
+// ## Safety
+// The caller must ensure that `index` is within the range of 0 to 31
+unsafe fn mb_ts(can0: &ra4m1::can0::RegisterBlock, index: usize) -> *mut u16 {
+    let base = can0.mb0_id.as_ptr() as *mut u8;
+    // MBj_TS, the last 2 bytes of the mailbox
+    unsafe { base.add((16 * index) + 14) as *mut u16 }
+}
+
 /// Layout of the Bit Configuration Register (BCR)
 #[bitfield_struct::bitfield(u32)]
 pub struct BitConfig {
@@ -374,7 +1118,7 @@ impl BitConfig {
         tseg1_tq: u8,
         tseg2_tq: u8,
         sjw_tq: u8,
-    ) -> Option<Self> {
+    ) -> Result<Self, Error> {
         // Check if the values are within the valid ranges
         if brp_scale > 1024
             || brp_scale == 0
@@ -385,191 +1129,458 @@ impl BitConfig {
             || sjw_tq < 1
             || sjw_tq > 4
         {
-            return None; // Invalid configuration
-        }
-        Some(
-            Self::new()
-                .with_CCLKS(cclks)
-                .with_BRP(brp_scale - 1)
-                .with_SJW(sjw_tq - 1)
-                .with_TSEG1(tseg1_tq - 1)
-                .with_TSEG2(tseg2_tq - 1),
-        )
+            return Err(Error::InvalidConfig);
+        }
+        Ok(Self::new()
+            .with_CCLKS(cclks)
+            .with_BRP(brp_scale - 1)
+            .with_SJW(sjw_tq - 1)
+            .with_TSEG1(tseg1_tq - 1)
+            .with_TSEG2(tseg2_tq - 1))
+    }
+
+    /// Timing for `bitrate` from the current PCLKB frequency, see
+    /// [`from_bitrate_with`](Self::from_bitrate_with).
+    pub fn from_bitrate(bitrate: u32) -> Result<Self, Error> {
+        Self::from_bitrate_with(Clocks::read().pclkb(), bitrate)
+    }
+
+    /// Timing for exactly `bitrate` from a PCLKB of `pclkb_hz`.
+    ///
+    /// Uses the most time quanta per bit that divide the clock, with the
+    /// sample point near 80%. [`Error::InvalidConfig`] if no prescaler
+    /// gives the exact rate.
+    pub fn from_bitrate_with(pclkb_hz: u32, bitrate: u32) -> Result<Self, Error> {
+        if bitrate == 0 {
+            return Err(Error::InvalidConfig);
+        }
+        // 1 TQ sync segment, TSEG1 4 - 16 and TSEG2 2 - 8
+        for tq in (8..=25u32).rev() {
+            let Some(per_bit) = bitrate.checked_mul(tq) else {
+                continue;
+            };
+            if pclkb_hz % per_bit != 0 || pclkb_hz / per_bit > 1024 {
+                continue;
+            }
+            let tseg2 = (tq / 5).clamp(2, 8);
+            let tseg1 = tq - 1 - tseg2;
+            if tseg1 > 16 {
+                continue;
+            }
+            let sjw = tseg2.min(4);
+            return Self::new_checked(
+                false,
+                (pclkb_hz / per_bit) as u16,
+                tseg1 as u8,
+                tseg2 as u8,
+                sjw as u8,
+            );
+        }
+        Err(Error::InvalidConfig)
+    }
+
+    /// Largest clock tolerance this timing allows, in ppm of each node's
+    /// oscillator, from the CAN 2.0 resynchronisation limits.
+    ///
+    /// Compare with the measured error, e.g. from [`cac`](crate::cac).
+    pub fn tolerance_ppm(&self) -> u32 {
+        let tseg1 = self.TSEG1() as u32 + 1;
+        let tseg2 = self.TSEG2() as u32 + 1;
+        let sjw = self.SJW() as u32 + 1;
+        let bit = 1 + tseg1 + tseg2;
+        // df <= min(PB1, PB2) / (2 * (13 * bit - PB2)) and df <= SJW / (20 * bit)
+        let phase = (tseg1.min(tseg2) as u64 * 1_000_000) / (2 * (13 * bit - tseg2)) as u64;
+        let jump = (sjw as u64 * 1_000_000) / (20 * bit) as u64;
+        phase.min(jump) as u32
     }
 }
 
-enum CanMode {
+/// Milliseconds [`Can::detect_bitrate`] listens with each candidate. Long
+/// enough to see a frame on most buses.
+pub const DETECT_DWELL_MS: u32 = 100;
+
+/// CPU cycles to wait for a mode change, 10 ms with a 48 MHz ICLK.
+///
+/// Halt mode is entered at the end of the frame on the bus, so this must be
+/// longer than the longest frame at the slowest bitrate in use.
+pub const MODE_TIMEOUT_CYCLES: u32 = 480_000;
+
+/// CPU cycles [`Can::flush`] waits for the queued frames to be sent, 100 ms
+/// with a 48 MHz ICLK.
+pub const FLUSH_TIMEOUT_CYCLES: u32 = 4_800_000;
+
+/// Mode of the CAN module, from the status register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CanMode {
     Sleep,
     Reset,
     Halt,
     Operation,
+    /// In operation mode but disconnected from the bus after too many errors
     BusOff,
 }
 
-pub struct Can {
-    reg: CAN0,
+/// Error from a mode change, the module didn't reach `requested` within
+/// [`MODE_TIMEOUT_CYCLES`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ModeTimeout {
+    pub requested: CanMode,
+    pub current: CanMode,
 }
 
-impl Can {
-    /// Create a new CAN interface with the given CAN0 peripheral and bit configuration.
-    ///
-    /// Will enter reset mode, configure the peripheral, then go to halt mode ready
-    /// for mailbox configuration.
-    pub fn new<IRQ>(can: CAN0, bit_config: BitConfig, irq: IRQ) -> Self
-    where
-        IRQ: Binding<TxHandler<ra4m1::CAN0>>,
-    {
-        // TX pin is D4 / p103
-        // RX pin is D5 / p102
-        let p = unsafe { ra4m1::Peripherals::steal() };
-
-        // Enable and map interrupts
-        map_and_enable_interrupt(<IRQ as Binding<TxHandler<ra4m1::CAN0>>>::interrupt(), 0x4E);
-
-        // Set the pins for CAN0
-
-        // For some reason access to the PSEL bits can't be done in PAC
-        // Get access to them with ptr offsets from p100
-        let p100: *mut u32 = p.PFS.p100pfs().as_ptr() as *mut u32; // PSEL for P102 is at offset 2, P103 is at offset 3
-
-        unsafe {
-            let p102 = p100.add(2);
-            let p103 = p100.add(3);
-            let rx_bits = 0b10000 << 24; // PSEL for CAN0 RX
-            let tx_bits = 0b10000 << 24; // PSEL for CAN0 TX
-            // clear first
-            p102.write_volatile(0); // PSEL for CAN0 RX
-            p103.write_volatile(0); // PSEL for CAN0 TX
-            p102.write_volatile(rx_bits); // PSEL for CAN0 RX
-            p103.write_volatile(tx_bits); // PSEL for CAN0 TX
-            p102.write_volatile(rx_bits | (1 << 16)); // Now with PMR = 1
-            p103.write_volatile(tx_bits | (1 << 16)); // Now with PMR = 1
+/// Take the CAN module off the bus in halt mode, without a [`Can`].
+///
+/// For shutdown code that can't reach the driver, e.g. a
+/// [`shutdown`](crate::shutdown) hook. A frame being sent is finished first,
+/// so this waits up to [`MODE_TIMEOUT_CYCLES`]. Does nothing if the module
+/// is stopped or already off the bus.
+pub fn halt() -> Result<(), ModeTimeout> {
+    if !crate::clk::is_peripheral_enabled(Peripheral::Can0) {
+        return Ok(());
+    }
+    let can = unsafe { &*CAN0::peripheral() };
+    if !matches!(read_mode(can), CanMode::Operation | CanMode::BusOff) {
+        return Ok(());
+    }
+    can.ctlr.modify(|_, w| w.canm()._10().slpm()._0());
+    let mut waited = 0;
+    while read_mode(can) != CanMode::Halt {
+        if waited >= MODE_TIMEOUT_CYCLES {
+            return Err(ModeTimeout {
+                requested: CanMode::Halt,
+                current: read_mode(can),
+            });
         }
+        cortex_m::asm::delay(100);
+        waited += 100;
+    }
+    Ok(())
+}
+
+/// Send a frame without a [`Can`], waiting up to [`MODE_TIMEOUT_CYCLES`]
+/// for a free transmit mailbox.
+///
+/// For fault handlers that can't reach the driver, e.g. a
+/// [`crash`](crate::crash) report. Fails with [`Error::BusOff`] if the
+/// module is stopped or not in operation mode.
+pub fn send_unowned(frame: &Frame) -> Result<(), Error> {
+    if !crate::clk::is_peripheral_enabled(Peripheral::Can0) {
+        return Err(Error::BusOff);
+    }
+    let can = unsafe { &*CAN0::peripheral() };
+    if read_mode(can) != CanMode::Operation {
+        return Err(Error::BusOff);
+    }
+    let mut waited = 0;
+    loop {
+        reclaim_mailboxes(can);
+        if load_mailbox(can, CAN0::held().load(Ordering::Relaxed), frame) {
+            return Ok(());
+        }
+        if waited >= MODE_TIMEOUT_CYCLES {
+            return Err(Error::TxMailboxFull);
+        }
+        cortex_m::asm::delay(100);
+        waited += 100;
+    }
+}
+
+/// Read the CAN registers without a [`Can`], `None` if the module is
+/// stopped.
+///
+/// For diagnostics that can't reach the driver, e.g. a
+/// [`shell`](crate::shell) command.
+pub fn snapshot() -> Option<CanSnapshot> {
+    if !crate::clk::is_peripheral_enabled(Peripheral::Can0) {
+        return None;
+    }
+    Some(read_snapshot(unsafe { &*CAN0::peripheral() }))
+}
+
+fn read_snapshot(can: &ra4m1::can0::RegisterBlock) -> CanSnapshot {
+    let mut mctl = [0; 32];
+    for (i, m) in mctl.iter_mut().enumerate() {
+        *m = can.mctl_rx()[i].read().bits();
+    }
+    CanSnapshot {
+        ctlr: can.ctlr.read().bits(),
+        str: can.str.read().bits(),
+        bcr: can.bcr.read().bits(),
+        eifr: can.eifr.read().bits(),
+        ecsr: can.ecsr.read().bits(),
+        recr: can.recr.read().bits(),
+        tecr: can.tecr.read().bits(),
+        tcr: can.tcr.read().bits(),
+        mier: can.mier().read().bits(),
+        mkivlr: can.mkivlr.read().bits(),
+        mctl,
+    }
+}
+
+fn read_mode(can: &ra4m1::can0::RegisterBlock) -> CanMode {
+    let str = can.str.read();
+    if str.slpst().bit_is_set() {
+        CanMode::Sleep
+    } else if str.rstst().bit_is_set() {
+        CanMode::Reset
+    } else if str.hltst().bit_is_set() {
+        CanMode::Halt
+    } else if str.bost().bit_is_set() {
+        CanMode::BusOff
+    } else {
+        CanMode::Operation
+    }
+}
+
+/// Marker for a [`Can`] in halt mode, being configured.
+pub struct Config;
+
+/// Marker for a [`Can`] in operation mode, on the bus.
+pub struct Running;
+
+/// CAN driver, `MODE` is [`Config`] or [`Running`], `I` the module.
+///
+/// Mailboxes, masks, bit timing and test modes can only be set in [`Config`],
+/// frames can only be sent and received in [`Running`].
+pub struct Can<MODE = Running, I: Instance = CAN0> {
+    _clock: ClockGuard,
+    transceiver: Option<TransceiverControl>,
+    _mode: PhantomData<(MODE, I)>,
+}
+
+/// Standby or enable pin of the bus transceiver, driven by [`Can`] so the
+/// transceiver is in normal mode exactly while the module is on the bus.
+///
+/// Given with [`Can::with_transceiver`]. Without it a transceiver whose
+/// STB / EN pin is wired to a GPIO stays in whatever state the pin resets
+/// to, and frames are never acknowledged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TransceiverControl {
+    port: u8,
+    pin: u8,
+    // Pin level for normal mode
+    normal_high: bool,
+}
+
+impl TransceiverControl {
+    /// Active high standby pin, e.g. STB of the TJA1042 or MCP2562.
+    pub fn standby<P: Pin>(_pin: P) -> Self {
+        Self::new::<P>(false)
+    }
+
+    /// Active high enable pin, e.g. EN of the TJA1051T/E or TCAN1051V.
+    pub fn enable<P: Pin>(_pin: P) -> Self {
+        Self::new::<P>(true)
+    }
+
+    // Output pin, starting in standby
+    fn new<P: Pin>(normal_high: bool) -> Self {
+        gpio::set_output::<P>(!normal_high);
+        Self {
+            port: P::port(),
+            pin: P::pin(),
+            normal_high,
+        }
+    }
+
+    // Drive the pin for normal mode or standby
+    fn set_normal(&self, normal: bool) {
+        gpio::write_at(self.port, self.pin, normal == self.normal_high);
+    }
+}
+
+impl<MODE, I: Instance> core::fmt::Debug for Can<MODE, I> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Can")
+            .field("mode", &self.current_mode())
+            .finish()
+    }
+}
+
+impl<I: Instance> Can<Config, I> {
+    /// Create a new CAN interface with the given CAN peripheral and bit configuration.
+    ///
+    /// `rx` and `tx` can be any pins with the CRX0 and CTX0 functions, other
+    /// pins don't compile. On the UNO R4 the CAN pins D5 / D4 are P102 and P103.
+    ///
+    /// Will enter reset mode, configure the peripheral, then go to halt mode ready
+    /// for mailbox configuration. Call [`start`](Self::start) to join the bus.
+    /// A transceiver with a standby or enable pin on a GPIO is given with
+    /// [`with_transceiver`](Self::with_transceiver).
+    ///
+    /// Fails if the module doesn't reach reset or halt mode in time.
+    pub fn new<RX: RxPin<I>, TX: TxPin<I>, IRQ>(
+        _can: I,
+        _rx: RX,
+        _tx: TX,
+        bit_config: BitConfig,
+        irq: IRQ,
+    ) -> Result<Can<Config, I>, ModeTimeout>
+    where
+        IRQ: Binding<TxHandler<I>>,
+    {
+        // Enable and map interrupts
+        map_and_enable_interrupt(
+            <IRQ as Binding<TxHandler<I>>>::interrupt(),
+            I::event_base().offset(EVENT_TXM),
+        );
+
+        // Set the pins for the module
+        gpio::set_function::<RX>(gpio::PinFunction::Can);
+        gpio::set_function::<TX>(gpio::PinFunction::Can);
 
         // Ensure that the can module is enabled
-        p.MSTP.mstpcrb.modify(|_, w| {
-            // Enable CAN0
-            w.mstpb2()._0()
-        });
+        let clock = ClockGuard::new(I::clock());
 
-        let can = Can { reg: can };
+        let can = Can {
+            _clock: clock,
+            transceiver: None,
+            _mode: PhantomData,
+        };
 
         // After MCU reset CAN is in sleep mode.
         // Go to reset mode by setting CANM to 01
         // when the SLPM bit is 0.
         // Will reset from any mode
-        can.go_to_mode(CanMode::Reset);
-
-        // Wait for STR.RSTST to go to 1
-        while can.reg.str.read().rstst().bit_is_clear() {}
+        can.go_to_mode(CanMode::Reset)?;
 
         // Set the bit configuration register (BCR)
-        p.CAN0
+        can.reg()
             .bcr
             .write(|w| unsafe { w.bits(bit_config.into_bits()) });
 
         // Go to halt mode
-        can.go_to_mode(CanMode::Halt);
-        can
+        can.go_to_mode(CanMode::Halt)?;
+        Ok(can)
     }
 
-    // Write the mode bits to the control register
-    // Does not check current mode
-    fn go_to_mode(&self, mode: CanMode) {
-        // Set the CAN mode
-        match mode {
-            CanMode::Sleep => {
-                self.reg.ctlr.modify(|_, w| w.slpm()._1()); // Set sleep mode
-            }
-            CanMode::Reset => {
-                self.reg.ctlr.modify(|_, w| {
-                    w.slpm()
-                        ._0() // Not in sleep mode
-                        .canm()
-                        ._01() // Reset mode
-                });
-            }
-            CanMode::Halt => {
-                self.reg.ctlr.modify(|_, w| w.canm()._10().slpm()._0()); // Halt mode
-            }
-            CanMode::Operation => {
-                self.reg.ctlr.modify(|_, w| w.canm()._00().slpm()._0()); // Operation mode
-            }
-            CanMode::BusOff => {
-                // Not implemented, bus off is a state that can be entered by the hardware
-            }
+    /// Write the ID mode, masks and mailboxes, the registers of
+    /// [`MailboxConfig::render`].
+    ///
+    /// Changing the [`IdMode`] goes through reset mode, like
+    /// [`set_bit_timing`](Self::set_bit_timing). [`Error::InvalidConfig`] if
+    /// a receive ID or mask is of a format the mode doesn't take.
+    pub fn configure_mailboxes(&mut self, config: MailboxConfig) -> Result<(), Error> {
+        if !config.is_valid() {
+            return Err(Error::InvalidConfig);
         }
-    }
-
-    pub fn configure_mailboxes(&mut self, config: MailboxConfig) {
-        // Must be in halt mode to configure mailboxes and masks
-        self.go_to_mode(CanMode::Halt);
-
-        for (i, mask) in config.masks.iter().enumerate() {
+        let image = config.render();
+        if IdMode::read(self.reg()) != config.id_mode {
+            // IDFM can only be written in reset mode
+            let tcr = self.reg().tcr.read().bits();
+            self.go_to_mode(CanMode::Reset)?;
+            self.reg().ctlr.modify(|_, w| match config.id_mode {
+                IdMode::Standard => w.idfm()._00(),
+                IdMode::Extended => w.idfm()._01(),
+                IdMode::Mixed => w.idfm()._10(),
+            });
+            self.restore(tcr, 0)?;
+        }
+        // In halt mode, needed to configure mailboxes and masks
+        for (i, mkr) in image.mkr.iter().enumerate() {
             // Write to the mkr register
-            self.reg.mkr[i].write(|w| unsafe { w.bits(mask.mkr()) });
+            self.reg().mkr[i].write(|w| unsafe { w.bits(*mkr) });
         }
         // Write to the MIER register
-        self.reg.mier().write(|w| unsafe { w.bits(config.mier()) });
+        self.reg().mier().write(|w| unsafe { w.bits(image.mier) });
         // Write to the MKIVLR register
-        self.reg
-            .mkivlr
-            .write(|w| unsafe { w.bits(config.mkivlr()) });
+        self.reg().mkivlr.write(|w| unsafe { w.bits(image.mkivlr) });
         // The PAC does not provide access to the mailbox registers by index,
         // the numbers are part of the register name.
         // Each mailbox is 16 bytes
 
-        // Configure each mailbox depending on its mode
-        for (i, mailbox) in config.mailboxes.iter().enumerate() {
+        // Configure each mailbox, transmit mailboxes are left at 0
+        for (i, &mctl) in image.mctl.iter().enumerate() {
             // Clear first, twice because some bits can't be cleared at the same time
-            self.reg.mctl_tx()[i].write(|w| unsafe { w.bits(0) });
-            self.reg.mctl_rx()[i].write(|w| unsafe { w.bits(0) });
-            match mailbox {
-                MailboxMode::Tx(_) => {
-                    // Just leave at 0, one-shot mode is not supported yet
-                }
-                MailboxMode::Rx(config) => {
-                    // Enable the RECREQ bit for the mailbox
-                    self.reg.mctl_rx()[i].modify(|_, w| {
-                        w.recreq()._1() // Enable receive request
-                    });
-                    // Turn the ID into a register value
-                    let mut id = MailboxId::from(config.id);
-                    // Clear IDE bit if not in mixed mode
-                    self.configure_ide_bit(&mut id);
-
-                    // Write the ID to the mailbox ID register
-                    unsafe {
-                        mb_id(&self.reg, i).write_volatile(id.into_bits()); // Write the ID to the mailbox ID register
-                    }
-                }
+            self.reg().mctl_tx()[i].write(|w| unsafe { w.bits(0) });
+            self.reg().mctl_rx()[i].write(|w| unsafe { w.bits(0) });
+            if mctl == 0 {
+                continue;
+            }
+            // Enable the RECREQ bit for the mailbox
+            self.reg().mctl_rx()[i].write(|w| unsafe { w.bits(mctl) });
+            // Write the ID to the mailbox ID register
+            unsafe {
+                mb_id(self.reg(), i).write_volatile(image.mb_id[i]);
             }
         }
+        Ok(())
     }
 
-    /// Clears IDE bit if not in mixed mode.
-    #[inline(always)]
-    fn configure_ide_bit(&self, id: &mut MailboxId) {
-        // Clear IDE bit if not in mixed mode
-        if self.reg.ctlr.read().idfm().variant() != ra4m1::can0::ctlr::IDFM_A::_10 {
-            id.set_IDE(false);
+    /// Pattern test the RAM of the 32 mailboxes, for a power-on self test.
+    ///
+    /// Writes every mailbox with a pattern unique to it before reading any
+    /// back, so address faults show up as well as stuck bits, then again
+    /// inverted. The timestamps are read-only so aren't tested. The mailbox
+    /// control registers are cleared and the mailboxes left zeroed, so
+    /// configure them afterwards. Returns the first mailbox that failed.
+    pub fn test_mailbox_ram(&mut self) -> Result<(), u8> {
+        // MBj_ID bit 29 and the top 4 bits of MBj_DL are reserved
+        const ID_MASK: u32 = !(1 << 29);
+        const DLC_MASK: u8 = 0x0F;
+        for i in 0..32 {
+            self.reg().mctl_tx()[i].write(|w| unsafe { w.bits(0) });
+            self.reg().mctl_tx()[i].write(|w| unsafe { w.bits(0) });
         }
+        let pattern = |i: usize, j: usize, invert: bool| {
+            let byte = 0x55 ^ (i as u8) ^ (j as u8).wrapping_mul(0x1D);
+            if invert { !byte } else { byte }
+        };
+        let mut result = Ok(());
+        for invert in [false, true] {
+            for i in 0..32 {
+                let id = u32::from_le_bytes(core::array::from_fn(|j| pattern(i, j, invert)));
+                unsafe {
+                    mb_id(self.reg(), i).write_volatile(id & ID_MASK);
+                    mb_dl(self.reg(), i).write_volatile(pattern(i, 4, invert) & DLC_MASK);
+                    for j in 0..8 {
+                        mb_d0(self.reg(), i)
+                            .add(j)
+                            .write_volatile(pattern(i, 5 + j, invert));
+                    }
+                }
+            }
+            for i in 0..32 {
+                let id = u32::from_le_bytes(core::array::from_fn(|j| pattern(i, j, invert)));
+                let ok = unsafe {
+                    mb_id(self.reg(), i).read_volatile() & ID_MASK == id & ID_MASK
+                        && mb_dl(self.reg(), i).read_volatile() & DLC_MASK
+                            == pattern(i, 4, invert) & DLC_MASK
+                        && (0..8).all(|j| {
+                            mb_d0(self.reg(), i).add(j).read_volatile() == pattern(i, 5 + j, invert)
+                        })
+                };
+                if !ok && result.is_ok() {
+                    result = Err(i as u8);
+                }
+            }
+        }
+        for i in 0..32 {
+            unsafe {
+                mb_id(self.reg(), i).write_volatile(0);
+                mb_dl(self.reg(), i).write_volatile(0);
+                for j in 0..8 {
+                    mb_d0(self.reg(), i).add(j).write_volatile(0);
+                }
+            }
+        }
+        result
     }
 
     pub fn internal_self_test(&self) {
-        self.go_to_mode(CanMode::Halt);
-        self.reg.tcr.write(|w| w.tste()._1().tstm()._11());
+        self.reg().tcr.write(|w| w.tste()._1().tstm()._11());
     }
 
     pub fn external_self_test(&self) {
-        self.go_to_mode(CanMode::Halt);
-        self.reg.tcr.write(|w| w.tste()._1().tstm()._10());
+        self.reg().tcr.write(|w| w.tste()._1().tstm()._10());
     }
 
     pub fn listen_only_mode(&self) {
         // Set the listen-only mode
-        self.go_to_mode(CanMode::Halt);
-        self.reg.tcr.write(|w| {
+        self.reg().tcr.write(|w| {
             w.tste()
                 ._1() // Enable test mode
                 .tstm()
@@ -579,272 +1590,989 @@ impl Can {
 
     pub fn disable_test_mode(&self) {
         // Disable test mode
-        self.go_to_mode(CanMode::Halt);
-        self.reg.tcr.write(|w| w.tste()._0().tstm()._00());
+        self.reg().tcr.write(|w| w.tste()._0().tstm()._00());
+    }
+
+    /// Change the bit timing without recreating the driver.
+    ///
+    /// BCR can only be written in reset mode, which clears the mailbox control
+    /// registers and test mode. Receive mailboxes and test mode are restored
+    /// afterwards, but unread frames and pending transmissions are lost.
+    /// Mailbox IDs and masks are kept by the hardware.
+    pub fn set_bit_timing(&mut self, bit_config: BitConfig) -> Result<(), ModeTimeout> {
+        let tcr = self.reg().tcr.read().bits();
+        let receivers = self.receive_mailboxes();
+
+        self.go_to_mode(CanMode::Reset)?;
+        self.reg()
+            .bcr
+            .write(|w| unsafe { w.bits(bit_config.into_bits()) });
+
+        self.restore(tcr, receivers)
+    }
+
+    /// Find the bitrate of a bus by listening with each candidate in turn.
+    ///
+    /// See [`detect_bitrate_with`](Self::detect_bitrate_with), listens for
+    /// [`DETECT_DWELL_MS`] per candidate, timed on the configured ICLK.
+    pub fn detect_bitrate(
+        &mut self,
+        candidates: &[BitConfig],
+    ) -> Result<Option<BitConfig>, ModeTimeout> {
+        let dwell_cycles = Clocks::read().iclk() / 1000 * DETECT_DWELL_MS;
+        self.detect_bitrate_with(candidates, dwell_cycles)
+    }
+
+    /// Find the bitrate of a bus by listening with each candidate in turn.
+    ///
+    /// Each candidate is tried in listen-only mode, so nothing is ever driven
+    /// onto the bus, for up to `dwell_cycles` CPU cycles. The first candidate
+    /// that receives a frame without a bus error is kept and the previous
+    /// test mode is restored, in halt mode. If none match, the original
+    /// timing is restored and None is returned.
+    ///
+    /// Fails if a mode change times out, leaving the module in that mode.
+    ///
+    /// At least one mailbox must be configured as a receiver that accepts
+    /// the traffic on the bus, e.g. with a [`Mask::accept_all`] mask.
+    pub fn detect_bitrate_with(
+        &mut self,
+        candidates: &[BitConfig],
+        dwell_cycles: u32,
+    ) -> Result<Option<BitConfig>, ModeTimeout> {
+        let tcr = self.reg().tcr.read().bits();
+        let receivers = self.receive_mailboxes();
+        let original = BitConfig::from_bits(self.reg().bcr.read().bits());
+
+        for candidate in candidates {
+            self.set_bit_timing(*candidate)?;
+            self.listen_only_mode();
+            self.run()?;
+            let found = self.wait_for_clean_frame(dwell_cycles);
+            self.go_to_mode(CanMode::Halt)?;
+            if found {
+                // Keep the frame that was received, only leave listen-only mode
+                self.reg().tcr.write(|w| unsafe { w.bits(tcr) });
+                return Ok(Some(*candidate));
+            }
+        }
+
+        self.set_bit_timing(original)?;
+        self.restore(tcr, receivers)?;
+        Ok(None)
+    }
+
+    // Wait for a frame in any mailbox, giving up on the first bus error
+    fn wait_for_clean_frame(&self, dwell_cycles: u32) -> bool {
+        const POLL_CYCLES: u32 = 1_000;
+        let mut waited = 0;
+        while waited < dwell_cycles {
+            // EIFR.BEIF, bus error detected
+            if self.reg().eifr.read().bits() & 1 != 0 {
+                return false;
+            }
+            if (0..32).any(|i| self.reg().mctl_rx()[i].read().newdata().bit_is_set()) {
+                return true;
+            }
+            cortex_m::asm::delay(POLL_CYCLES);
+            waited += POLL_CYCLES;
+        }
+        false
+    }
+
+    // Mask of the mailboxes waiting to receive
+    fn receive_mailboxes(&self) -> u32 {
+        (0..32)
+            .filter(|i| self.reg().mctl_rx()[*i].read().recreq().bit_is_set())
+            .fold(0, |mask, i| mask | (1 << i))
+    }
+
+    // Go to halt mode, restore test mode and receive requests
+    fn restore(&mut self, tcr: u8, receivers: u32) -> Result<(), ModeTimeout> {
+        self.go_to_mode(CanMode::Halt)?;
+        self.reg().tcr.write(|w| unsafe { w.bits(tcr) });
+        for i in (0..32).filter(|i| receivers & (1 << i) != 0) {
+            self.reg().mctl_rx()[i].write(|w| w.recreq()._1());
+        }
+        Ok(())
+    }
+
+    /// Go to operation mode and join the bus.
+    ///
+    /// Gives the driver back if operation mode isn't reached in time, see
+    /// [`current_mode`](Self::current_mode).
+    pub fn start(self) -> Result<Can<Running, I>, Self> {
+        if self.run().is_err() {
+            return Err(self);
+        }
+        Ok(Can {
+            _clock: self._clock,
+            transceiver: self.transceiver,
+            _mode: PhantomData,
+        })
+    }
+
+    /// Drive the transceiver's standby or enable pin with the module mode,
+    /// normal mode while on the bus and standby otherwise, starting now in
+    /// standby.
+    pub fn with_transceiver(mut self, control: TransceiverControl) -> Self {
+        control.set_normal(false);
+        self.transceiver = Some(control);
+        self
+    }
+
+    /// Put the module in sleep mode, with its clock stopped, until
+    /// [`wake`](Self::wake). The transceiver stays in standby.
+    pub fn sleep(&mut self) -> Result<(), ModeTimeout> {
+        self.go_to_mode(CanMode::Sleep)
+    }
+
+    /// Leave sleep mode for halt mode.
+    pub fn wake(&mut self) -> Result<(), ModeTimeout> {
+        self.go_to_mode(CanMode::Halt)
+    }
+
+    // Run `f` in operation mode, then go back to halt mode
+    pub(crate) fn while_running<R>(
+        &mut self,
+        f: impl FnOnce(&Can<Running, I>) -> R,
+    ) -> Result<R, ModeTimeout> {
+        let running = Can {
+            _clock: self._clock.clone(),
+            transceiver: self.transceiver,
+            _mode: PhantomData,
+        };
+        let result = self.run().map(|()| f(&running));
+        self.go_to_mode(CanMode::Halt)?;
+        self.set_transceiver(false);
+        result
+    }
+}
+
+impl<MODE, I: Instance> Can<MODE, I> {
+    // Get access to the module's registers
+    fn reg(&self) -> &ra4m1::can0::RegisterBlock {
+        unsafe { &*I::peripheral() }
+    }
+
+    // Put the transceiver in normal mode or standby, if it is controlled
+    fn set_transceiver(&self, normal: bool) {
+        if let Some(control) = &self.transceiver {
+            control.set_normal(normal);
+        }
+    }
+
+    /// Mode the module is in.
+    pub fn current_mode(&self) -> CanMode {
+        read_mode(self.reg())
+    }
+
+    /// Value of the timestamp counter, the time [`Frame::timestamp`] gives
+    /// for received frames. Counts bit times and is reset by
+    /// [`start`](Can::start).
+    pub fn timestamp(&self) -> u16 {
+        self.reg().tsr.read().bits()
+    }
+
+    // Write the mode bits to the control register, then wait for the status
+    // register to show the mode. Bus off can't be requested, it's entered by
+    // the hardware, so counts as operation.
+    fn go_to_mode(&self, mode: CanMode) -> Result<(), ModeTimeout> {
+        // Set the CAN mode
+        match mode {
+            CanMode::Sleep => {
+                self.reg().ctlr.modify(|_, w| w.slpm()._1()); // Set sleep mode
+            }
+            CanMode::Reset => {
+                self.reg().ctlr.modify(|_, w| {
+                    w.slpm()
+                        ._0() // Not in sleep mode
+                        .canm()
+                        ._01() // Reset mode
+                });
+            }
+            CanMode::Halt => {
+                self.reg().ctlr.modify(|_, w| w.canm()._10().slpm()._0()); // Halt mode
+            }
+            CanMode::Operation => {
+                self.reg().ctlr.modify(|_, w| w.canm()._00().slpm()._0()); // Operation mode
+            }
+            CanMode::BusOff => {
+                // Not implemented, bus off is a state that can be entered by the hardware
+                return Ok(());
+            }
+        }
+
+        const POLL_CYCLES: u32 = 100;
+        let mut waited = 0;
+        loop {
+            let current = self.current_mode();
+            if current == mode || (mode == CanMode::Operation && current == CanMode::BusOff) {
+                return Ok(());
+            }
+            if waited >= MODE_TIMEOUT_CYCLES {
+                return Err(ModeTimeout {
+                    requested: mode,
+                    current,
+                });
+            }
+            cortex_m::asm::delay(POLL_CYCLES);
+            waited += POLL_CYCLES;
+        }
     }
 
-    pub fn start(&self) {
+    /// Use `buf` as the software transmit queue of [`queue_frame`](Self::queue_frame),
+    /// its length is the depth of the queue. Frames still queued are dropped.
+    ///
+    /// The queue is drained by [`TxHandler`] as mailboxes free up, so the
+    /// transmit mailbox interrupts must be enabled, e.g. with
+    /// [`MailboxConfig::enable_all_interrupts`].
+    pub fn set_tx_queue(&mut self, buf: &'static mut [Frame]) {
+        critical_section::with(|cs| {
+            let mut queue = I::tx_queue().borrow_ref_mut(cs);
+            queue.buf = Some(buf);
+            queue.head = 0;
+            queue.len = 0;
+        });
+    }
+
+    /// Number of frames waiting in the queue.
+    pub fn queued(&self) -> usize {
+        critical_section::with(|cs| I::tx_queue().borrow_ref(cs).len)
+    }
+
+    /// Call `callback` with the queue length from the transmit interrupt
+    /// when the queue drains to `level` frames, e.g. to queue the next burst.
+    pub fn set_tx_watermark(&mut self, level: usize, callback: Option<fn(usize)>) {
+        critical_section::with(|cs| {
+            let mut queue = I::tx_queue().borrow_ref_mut(cs);
+            queue.watermark = level;
+            queue.on_watermark = callback;
+        });
+    }
+
+    /// Call `callback` from [`RxHandler`] with each frame received in
+    /// receive mailbox `index`, or stop with `None`.
+    ///
+    /// Enable the receive interrupts with [`enable_rx_interrupt`](Self::enable_rx_interrupt)
+    /// and the mailbox interrupt in its [`MailboxConfig`]. The callback runs
+    /// in the interrupt, so should be short.
+    pub fn on_receive(&mut self, index: usize, callback: Option<fn(&Frame)>) {
+        if index < 32 {
+            critical_section::with(|cs| {
+                let cell = I::rx_callbacks().borrow(cs);
+                let mut callbacks = cell.get();
+                callbacks[index] = callback;
+                cell.set(callbacks);
+            });
+        }
+    }
+
+    /// Turn the software receive filter on with `mode`, or off with `None`
+    /// so every frame is received. The IDs are kept while it's off.
+    ///
+    /// The filter is checked by [`RxHandler`] and
+    /// [`try_receive_frame`](Can::try_receive_frame) for frames that passed
+    /// the mailbox masks, so exact IDs can be picked out of a mask shared by
+    /// four mailboxes.
+    pub fn set_filter_mode(&mut self, mode: Option<FilterMode>) {
+        critical_section::with(|cs| I::rx_filter().borrow_ref_mut(cs).mode = mode);
+    }
+
+    /// Add `id` to the software receive filter, see
+    /// [`set_filter_mode`](Self::set_filter_mode).
+    ///
+    /// Safe to use while running, frames being handled see the filter
+    /// before or after the change. [`Error::FilterFull`] if it already has
+    /// [`MAX_FILTER_IDS`] IDs.
+    pub fn add_filter(&mut self, id: impl Into<Id>) -> Result<(), Error> {
+        critical_section::with(|cs| I::rx_filter().borrow_ref_mut(cs).add(id.into()))
+    }
+
+    /// Remove `id` from the software receive filter, false if it wasn't
+    /// there.
+    pub fn remove_filter(&mut self, id: impl Into<Id>) -> bool {
+        critical_section::with(|cs| I::rx_filter().borrow_ref_mut(cs).remove(id.into()))
+    }
+
+    /// Remove every ID from the software receive filter.
+    pub fn clear_filters(&mut self) {
+        critical_section::with(|cs| I::rx_filter().borrow_ref_mut(cs).ids.clear());
+    }
+
+    /// Call `callback` from [`ErrorHandler`] with each [`CanEvent`], or
+    /// queue them for [`next_error_event`](Self::next_error_event) with
+    /// `None`.
+    ///
+    /// The callback runs in the interrupt, so should be short.
+    pub fn on_error(&mut self, callback: Option<fn(CanEvent)>) {
+        critical_section::with(|cs| I::error_events().borrow_ref_mut(cs).callback = callback);
+    }
+
+    /// Take the oldest queued [`CanEvent`], while there is no
+    /// [`on_error`](Self::on_error) callback.
+    pub fn next_error_event(&self) -> Option<CanEvent> {
+        critical_section::with(|cs| I::error_events().borrow_ref_mut(cs).queue.pop_front())
+    }
+
+    /// Map and enable the error interrupt to [`ErrorHandler`].
+    ///
+    /// Every error and state change raises it, bus errors only if
+    /// `bus_errors` is set, as a disconnected bus raises one for each
+    /// attempt to send.
+    pub fn enable_error_interrupt<IRQ>(&mut self, _irq: IRQ, bus_errors: bool)
+    where
+        IRQ: Binding<ErrorHandler<I>>,
+    {
+        let sources = if bus_errors {
+            EIFR_ALL
+        } else {
+            EIFR_ALL & !EIFR_BEIF
+        };
+        self.reg().eier.write(|w| unsafe { w.bits(sources) });
+        map_and_enable_interrupt(
+            <IRQ as Binding<ErrorHandler<I>>>::interrupt(),
+            I::event_base().offset(EVENT_ERS),
+        );
+    }
+
+    /// Map and enable the mailbox receive interrupt to [`RxHandler`].
+    pub fn enable_rx_interrupt<IRQ>(&mut self, _irq: IRQ)
+    where
+        IRQ: Binding<RxHandler<I>>,
+    {
+        map_and_enable_interrupt(
+            <IRQ as Binding<RxHandler<I>>>::interrupt(),
+            I::event_base().offset(EVENT_RXM),
+        );
+    }
+
+    // Go to operation mode and reset the timestamp counter
+    fn run(&self) -> Result<(), ModeTimeout> {
+        // The transceiver first, the module waits for 11 recessive bits
+        // before joining so there is time for it to wake
+        self.set_transceiver(true);
         // Go to operation mode
-        self.go_to_mode(CanMode::Operation);
+        self.go_to_mode(CanMode::Operation)
+            .inspect_err(|_| self.set_transceiver(false))?;
         // reset the timer
-        self.reg.ctlr.modify(|_, w| w.tsrc()._1()); // Reset timer
+        self.reg().ctlr.modify(|_, w| w.tsrc()._1()); // Reset timer
+        Ok(())
+    }
+}
+
+impl<I: Instance> Can<Running, I> {
+    /// Send a frame in the first free transmit mailbox.
+    pub fn send_frame(&self, frame: Frame) -> Result<(), Error> {
+        if self.reg().str.read().bost().bit_is_set() {
+            Err(Error::BusOff)
+        } else if load_mailbox(self.reg(), I::held().load(Ordering::Relaxed), &frame) {
+            Ok(())
+        } else {
+            Err(Error::TxMailboxFull)
+        }
     }
 
-    pub fn send_frame(&self, frame: Frame) -> Result<(), ()> {
-        // Find the first available mailbox for transmission
-        for i in 0..32 {
-            let r = self.reg.mctl_tx()[i].read();
-            // Check if the mailbox is available for transmission
-            if r.trmreq().bit_is_clear() && r.recreq().bit_is_clear() {
-                {
-                    // Write the ID to the mailbox ID register
-                    unsafe {
-                        mb_id(&self.reg, i).write_volatile(frame.id.into_bits());
-                    }
-                    // write the dlc
-                    unsafe {
-                        mb_dl(&self.reg, i).write_volatile(frame.dlc);
-                    }
-                    // Write the data to the mailbox data registers
-                    let data_ptr = unsafe { mb_d0(&self.reg, i) };
-                    for (j, &byte) in <Frame as embedded_can::Frame>::data(&frame)
-                        .iter()
-                        .enumerate()
-                    {
-                        unsafe {
-                            data_ptr.add(j).write_volatile(byte);
-                        }
-                    }
-                    // Put mailbox id into first byte
-                    // unsafe { data_ptr.write_volatile(i as u8) };
-                    // Request transmission
-                    self.reg.mctl_tx()[i].write(|w| w.trmreq()._1());
-                    return Ok(()); // Exit after sending the frame
-                }
+    /// Send a frame, or queue it if all mailboxes are busy.
+    ///
+    /// Queued frames are loaded into mailboxes as they free up, but the
+    /// controller sends loaded mailboxes in ID priority order, so frames with
+    /// different IDs may go out in a different order than queued.
+    pub fn queue_frame(&self, frame: Frame) -> Result<(), QueueFull> {
+        queue_frame::<I>(frame)
+    }
+
+    /// Wait until the queue is empty and every mailbox has been sent.
+    ///
+    /// Fails with [`Error::BusOff`] if the module goes off the bus, or
+    /// [`Error::FlushTimeout`] if frames are still waiting after
+    /// [`FLUSH_TIMEOUT_CYCLES`], e.g. as nothing on the bus acknowledges them.
+    /// Frames not sent stay queued.
+    pub fn flush(&self) -> Result<(), Error> {
+        let mut waited = 0;
+        loop {
+            if read_mode(self.reg()) == CanMode::BusOff {
+                return Err(Error::BusOff);
+            }
+            reclaim_mailboxes(self.reg());
+            drain_tx_queue::<I>();
+            let pending = (0..32).any(|i| {
+                let r = self.reg().mctl_tx()[i].read();
+                r.trmreq().bit_is_set() && r.recreq().bit_is_clear()
+            });
+            if !pending && self.queued() == 0 {
+                return Ok(());
             }
+            if waited >= FLUSH_TIMEOUT_CYCLES {
+                return Err(Error::FlushTimeout);
+            }
+            cortex_m::asm::delay(100);
+            waited += 100;
         }
-        Err(())
     }
 
+    /// Take a received frame from the first receive mailbox with one.
+    ///
+    /// Frames the software filter rejects are dropped from their mailbox
+    /// here, see [`add_filter`](Can::add_filter).
     pub fn try_receive_frame(&self) -> Option<Frame> {
         // Check each mailbox for received frames
-        for i in 0..32 {
-            let r = self.reg.mctl_rx()[i].read();
-            // Check if the mailbox has a received frame
-            if r.newdata().bit_is_set() && r.trmreq().bit_is_clear() {
-                // clear register
-                self.reg.mctl_rx()[i].write(|w| unsafe {
-                    w.bits(0) // Clear the mailbox control register
-                });
-                // Read the ID from the mailbox ID register
-                let id = unsafe { mb_id(&self.reg, i).read_volatile() };
-                let id = MailboxId::from_bits(id);
-                // Read the DLC
-                let dlc = unsafe { mb_dl(&self.reg, i).read_volatile() };
-                // Read the data from the mailbox data registers
-                let mut data = [0; 8];
-                let data_ptr = unsafe { mb_d0(&self.reg, i) };
-                for (j, b) in data[..(dlc as usize)].iter_mut().enumerate() {
-                    *b = unsafe { data_ptr.add(j).read_volatile() };
-                }
-                // Go back to ready state
-                self.reg.mctl_rx()[i].write(|w| w.recreq()._1()); // Clear the receive request
-                return Some(Frame {
-                    id,
-                    dlc,
-                    data,
-                    ts: 0, // Timestamp is not used here
-                });
+        (0..32).find_map(|i| read_mailbox(self.reg(), i).filter(accepts::<I>))
+    }
+
+    /// Go to halt mode, e.g. to change the mailbox configuration.
+    ///
+    /// Waits for the frames being sent or received to finish. Gives the
+    /// driver back if halt mode isn't reached in time.
+    pub fn stop(self) -> Result<Can<Config, I>, Self> {
+        if self.go_to_mode(CanMode::Halt).is_err() {
+            return Err(self);
+        }
+        self.set_transceiver(false);
+        Ok(Can {
+            _clock: self._clock,
+            transceiver: self.transceiver,
+            _mode: PhantomData,
+        })
+    }
+}
+
+// Read the frame from receive mailbox `index`, if it has a new one, and
+// make it ready for the next
+fn read_mailbox(can: &ra4m1::can0::RegisterBlock, i: usize) -> Option<Frame> {
+    let r = can.mctl_rx()[i].read();
+    // Check if the mailbox has a received frame. RECREQ as the same bit
+    // is SENTDATA in a transmit mailbox.
+    if r.recreq().bit_is_set() && r.newdata().bit_is_set() && r.trmreq().bit_is_clear() {
+        // clear register
+        can.mctl_rx()[i].write(|w| unsafe {
+            w.bits(0) // Clear the mailbox control register
+        });
+        // Read the ID from the mailbox ID register
+        let id = unsafe { mb_id(can, i).read_volatile() };
+        let id = IdMode::read(can).decode(MailboxId::from_bits(id));
+        // Read the DLC, 9 - 15 are valid on the bus and mean 8 bytes
+        let dlc = (unsafe { mb_dl(can, i).read_volatile() } & 0x0F).min(8);
+        // Read the data from the mailbox data registers
+        let mut data = [0; 8];
+        let data_ptr = unsafe { mb_d0(can, i) };
+        for (j, b) in data[..(dlc as usize)].iter_mut().enumerate() {
+            *b = unsafe { data_ptr.add(j).read_volatile() };
+        }
+        let ts = unsafe { mb_ts(can, i).read_volatile() };
+        // Go back to ready state
+        can.mctl_rx()[i].write(|w| w.recreq()._1()); // Clear the receive request
+        return Some(Frame { id, dlc, data, ts });
+    }
+    None
+}
+
+/// Raw copy of the CAN control and status registers.
+///
+/// Taken with [`Can::register_snapshot`] or [`snapshot`], decode helpers
+/// are provided for the commonly needed fields.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CanSnapshot {
+    /// Control Register
+    pub ctlr: u16,
+    /// Status Register
+    pub str: u16,
+    /// Bit Configuration Register
+    pub bcr: u32,
+    /// Error Interrupt Factor Judge Register
+    pub eifr: u8,
+    /// Error Code Store Register
+    pub ecsr: u8,
+    /// Receive Error Count Register
+    pub recr: u8,
+    /// Transmit Error Count Register
+    pub tecr: u8,
+    /// Test Control Register
+    pub tcr: u8,
+    /// Mailbox Interrupt Enable Register
+    pub mier: u32,
+    /// Mask Invalid Register
+    pub mkivlr: u32,
+    /// Message Control Register of each mailbox
+    pub mctl: [u8; 32],
+}
+
+impl CanSnapshot {
+    /// Name of the operating mode from CTLR.CANM and CTLR.SLPM
+    pub fn mode_name(&self) -> &'static str {
+        if self.ctlr & (1 << 10) != 0 {
+            return "sleep";
+        }
+        match (self.ctlr >> 8) & 0b11 {
+            0b00 => "operation",
+            0b10 => "halt",
+            _ => "reset",
+        }
+    }
+
+    /// Error state from STR.EPST and STR.BOST, error-warning when REC or
+    /// TEC is at least 96, the EWIF threshold
+    pub fn error_state(&self) -> &'static str {
+        if self.str & (1 << 12) != 0 {
+            "bus-off"
+        } else if self.str & (1 << 11) != 0 {
+            "error-passive"
+        } else if self.recr >= ERROR_WARNING_COUNT || self.tecr >= ERROR_WARNING_COUNT {
+            "error-warning"
+        } else {
+            "error-active"
+        }
+    }
+
+    /// Decoded bit timing from BCR
+    pub fn bit_config(&self) -> BitConfig {
+        BitConfig::from_bits(self.bcr)
+    }
+}
+
+// Error count from which the controller is error-warning
+const ERROR_WARNING_COUNT: u8 = 96;
+
+const STR_FLAGS: [(u16, &str); 15] = [
+    (1 << 0, "NDST"),
+    (1 << 1, "SDST"),
+    (1 << 2, "RFST"),
+    (1 << 3, "TFST"),
+    (1 << 4, "NMLST"),
+    (1 << 5, "FMLST"),
+    (1 << 6, "TABST"),
+    (1 << 7, "EST"),
+    (1 << 8, "RSTST"),
+    (1 << 9, "HLTST"),
+    (1 << 10, "SLPST"),
+    (1 << 11, "EPST"),
+    (1 << 12, "BOST"),
+    (1 << 13, "TRMST"),
+    (1 << 14, "RECST"),
+];
+
+const EIFR_FLAGS: [(u8, &str); 8] = [
+    (1 << 0, "BEIF"),
+    (1 << 1, "EWIF"),
+    (1 << 2, "EPIF"),
+    (1 << 3, "BOEIF"),
+    (1 << 4, "BORIF"),
+    (1 << 5, "ORIF"),
+    (1 << 6, "OLIF"),
+    (1 << 7, "BLIF"),
+];
+
+const ECSR_FLAGS: [(u8, &str); 7] = [
+    (1 << 0, "stuff"),
+    (1 << 1, "form"),
+    (1 << 2, "ack"),
+    (1 << 3, "crc"),
+    (1 << 4, "bit-recessive"),
+    (1 << 5, "bit-dominant"),
+    (1 << 6, "ack-delimiter"),
+];
+
+// Write the names of the set flags, or "-" if none are set
+fn write_flags<W: Write, T: Copy + Into<u16>>(
+    tx: &mut W,
+    value: T,
+    flags: &[(T, &str)],
+) -> Result<(), embedded_io::WriteFmtError<W::Error>> {
+    let value: u16 = value.into();
+    let mut any = false;
+    for &(mask, name) in flags {
+        if value & mask.into() != 0 {
+            tx.write_fmt(format_args!(" {}", name))?;
+            any = true;
+        }
+    }
+    if !any {
+        tx.write_fmt(format_args!(" -"))?;
+    }
+    tx.write_fmt(format_args!("\n"))
+}
+
+impl<MODE, I: Instance> Can<MODE, I> {
+    /// Read the control, status and mailbox control registers.
+    pub fn register_snapshot(&self) -> CanSnapshot {
+        read_snapshot(self.reg())
+    }
+
+    /// Write a human readable decode of the peripheral state.
+    ///
+    /// Covers mode, error state and counters, bit timing, test mode and
+    /// the state of every mailbox that is not idle.
+    pub fn debug_dump<W: Write>(
+        &self,
+        tx: &mut W,
+    ) -> Result<(), embedded_io::WriteFmtError<W::Error>> {
+        let snap = self.register_snapshot();
+        let bcr = snap.bit_config();
+
+        tx.write_fmt(format_args!(
+            "CAN mode: {} ({})\n",
+            snap.mode_name(),
+            snap.error_state()
+        ))?;
+        tx.write_fmt(format_args!("  CTLR: {:04X}\n", snap.ctlr))?;
+        tx.write_fmt(format_args!("  STR:  {:04X}", snap.str))?;
+        write_flags(tx, snap.str, &STR_FLAGS)?;
+        tx.write_fmt(format_args!("  EIFR: {:02X}", snap.eifr))?;
+        write_flags(tx, snap.eifr, &EIFR_FLAGS)?;
+        tx.write_fmt(format_args!("  ECSR: {:02X}", snap.ecsr))?;
+        write_flags(tx, snap.ecsr, &ECSR_FLAGS)?;
+        tx.write_fmt(format_args!("  REC: {} TEC: {}\n", snap.recr, snap.tecr))?;
+        tx.write_fmt(format_args!(
+            "  BCR:  {:08X} brp={} tseg1={} tseg2={} sjw={} {}\n",
+            snap.bcr,
+            bcr.BRP() + 1,
+            bcr.TSEG1() + 1,
+            bcr.TSEG2() + 1,
+            bcr.SJW() + 1,
+            if bcr.CCLKS() { "CANMCLK" } else { "PCLKB" }
+        ))?;
+        let test = match (snap.tcr & 1 != 0, (snap.tcr >> 1) & 0b11) {
+            (false, _) => "off",
+            (true, 0b01) => "listen-only",
+            (true, 0b10) => "external loopback",
+            (true, 0b11) => "internal loopback",
+            (true, _) => "enabled",
+        };
+        tx.write_fmt(format_args!(
+            "  TCR:  {:02X} test mode {}\n",
+            snap.tcr, test
+        ))?;
+        tx.write_fmt(format_args!(
+            "  MIER: {:08X} MKIVLR: {:08X}\n",
+            snap.mier, snap.mkivlr
+        ))?;
+        for (i, &mctl) in snap.mctl.iter().enumerate() {
+            if mctl == 0 {
+                continue;
             }
+            // b7 is TRMREQ and b6 is RECREQ, the meaning of b2..b0 depends on which is set
+            let state = if mctl & (1 << 7) != 0 {
+                match (mctl & (1 << 1) != 0, mctl & (1 << 0) != 0) {
+                    (_, true) => "tx sent",
+                    (true, _) => "tx pending",
+                    _ => "tx requested",
+                }
+            } else if mctl & (1 << 6) != 0 {
+                match (mctl & (1 << 2) != 0, mctl & (1 << 0) != 0) {
+                    (true, _) => "rx overrun",
+                    (_, true) => "rx new data",
+                    _ => "rx waiting",
+                }
+            } else {
+                "idle"
+            };
+            let id = MailboxId::from_bits(unsafe { mb_id(self.reg(), i).read_volatile() });
+            tx.write_fmt(format_args!(
+                "  MB{:02}: {:02X} {} id={:?}\n",
+                i,
+                mctl,
+                state,
+                Id::from(id)
+            ))?;
         }
-        None // No frame received
+        Ok(())
     }
 }
 
-pub fn init(tx: &mut impl Write) {
-    tx.write_all("\nInitialising CAN\n".as_bytes()).unwrap();
-    // TX pin is D4 / p103
-    // RX pin is D5 / p102
-    let p = unsafe { ra4m1::Peripherals::steal() };
+#[cfg(test)]
+mod tests {
+    use core::cell::UnsafeCell;
 
-    tx.write_fmt(format_args!(
-        "P102: {:08X}\n",
-        p.PFS.p102pfs_ha().read().bits()
-    ))
-    .unwrap();
+    use super::*;
 
-    tx.write_fmt(format_args!(
-        "P103: {:08X}\n",
-        p.PFS.p103pfs_ha().read().bits()
-    ))
-    .unwrap();
+    const REGISTERS_SIZE: usize = core::mem::size_of::<ra4m1::can0::RegisterBlock>();
 
-    // Set the pins for CAN0
-    // For some reason access to the PSEL bits can't be done in PAC
-    // Get access to them with ptr offsets from p100
-    let p100: *mut u32 = (p.PFS.p100pfs().as_ptr() as *mut u32); // PSEL for P102 is at offset 2, P103 is at offset 3
-    // print pointer address
-    tx.write_fmt(format_args!("P100 ptr: {:08X}\n", p100 as usize))
-        .unwrap();
+    // CAN registers in RAM, so the register functions run on the host
+    #[repr(C, align(4))]
+    struct Registers(UnsafeCell<[u8; REGISTERS_SIZE]>);
 
-    unsafe {
-        let p102 = p100.add(2);
-        let p103 = p100.add(3);
-        // print the pointer addresses
-        tx.write_fmt(format_args!("P102 ptr: {:08X}\n", p102 as usize))
-            .unwrap();
-        tx.write_fmt(format_args!("P103 ptr: {:08X}\n", p103 as usize))
-            .unwrap();
-        let rx_bits = 0b10000 << 24; // PSEL for CAN0 RX
-        let tx_bits = (0b10000 << 24); // PSEL for CAN0 TX
-        // clear first
-        p102.write_volatile(0); // PSEL for CAN0 RX
-        p103.write_volatile(0); // PSEL for CAN0 TX
-        p102.write_volatile(rx_bits); // PSEL for CAN0 RX
-        p103.write_volatile(tx_bits); // PSEL for CAN0 TX
-        p102.write_volatile(rx_bits | (1 << 16)); // Now with PMR = 1
-        p103.write_volatile(tx_bits | (1 << 16)); // Now with PMR = 1
-    }
-
-    // read back 16 bits registers to see if anything happened
-    tx.write_fmt(format_args!(
-        "P102 16: {:08X}\n",
-        p.PFS.p102pfs_ha().read().bits()
-    ))
-    .unwrap();
-
-    tx.write_fmt(format_args!(
-        "P103 16: {:08X}\n",
-        p.PFS.p103pfs_ha().read().bits()
-    ))
-    .unwrap();
-
-    // Ensure that the can module is enabled
-    p.MSTP.mstpcrb.modify(|_, w| {
-        // Enable CAN0
-        w.mstpb2()._0()
-    });
+    unsafe impl Sync for Registers {}
 
-    status(tx);
-
-    tx.write_all("Entering CAN reset mode...\n".as_bytes())
-        .unwrap();
-    // After reset CAN is in sleep mode.
-    // Go to reset mode by setting CANM to 01
-    // when the SLPM bit is 0.
-    p.CAN0.ctlr.modify(|_, w| {
-        w.slpm()
-            ._0() // Not in sleep mode
-            .canm()
-            ._01() // Reset mode
-    });
+    // Only used by `mock_queue_and_receive`, tests run in parallel
+    struct MockCan;
 
-    status(tx);
-
-    // Wait for STR.RSTST to go to 1
-    while p.CAN0.str.read().rstst().bit_is_clear() {}
-
-    // By default CAN runs from PCLKB, which is set to 24 MHz (im pretty sure)
-    // The prescaler value in BCR determines the time quanta of the CAN bus.
-    // The baud rate is PCLKB / (prescaler * time quanta). where time quanta is the
-    // sum of the SS, TSEG1, and TSEG2 values.
-    // Aim for sample point at 75% of the bit time. (TSEG1->TSEG2 boundary)
-    // SS is always 1, TSEG1 of 5 and TSEG2 of 2 gives a total of 8 time quanta.
-    // TSEGx must be larger than SJW, which can be 1.
-    p.CAN0.bcr.modify(|_, w| {
-        // Set the prescaler 2, (24 / (2 + 1) = 8 MHz)
-        // 8 / (tq = 8) = 1 MHz
-        unsafe { w.brp().bits(2).sjw()._00().tseg1()._0100().tseg2()._001() }
-    });
+    impl Instance for MockCan {
+        fn peripheral() -> *const ra4m1::can0::RegisterBlock {
+            static REGISTERS: Registers = Registers(UnsafeCell::new([0; REGISTERS_SIZE]));
+            REGISTERS.0.get() as *const _
+        }
 
-    tx.write_fmt(format_args!("CAN0 BCR: {:08X}\n", p.CAN0.bcr.read().bits()))
-        .unwrap();
+        fn tx_queue() -> &'static Mutex<RefCell<TxQueue>> {
+            static QUEUE: Mutex<RefCell<TxQueue>> = Mutex::new(RefCell::new(TxQueue::new()));
+            &QUEUE
+        }
 
-    status(tx);
+        fn rx_callbacks() -> &'static Mutex<Cell<[Option<fn(&Frame)>; 32]>> {
+            static CALLBACKS: Mutex<Cell<[Option<fn(&Frame)>; 32]>> =
+                Mutex::new(Cell::new([None; 32]));
+            &CALLBACKS
+        }
 
-    // Got to halt mode
-    tx.write_all("Entering CAN halt mode...\n".as_bytes())
-        .unwrap();
+        fn error_events() -> &'static Mutex<RefCell<ErrorEvents>> {
+            static EVENTS: Mutex<RefCell<ErrorEvents>> =
+                Mutex::new(RefCell::new(ErrorEvents::new()));
+            &EVENTS
+        }
 
-    p.CAN0.ctlr.modify(|_, w| w.canm()._10());
+        fn rx_filter() -> &'static Mutex<RefCell<IdFilter>> {
+            static FILTER: Mutex<RefCell<IdFilter>> = Mutex::new(RefCell::new(IdFilter::new()));
+            &FILTER
+        }
 
-    status(tx);
+        fn held() -> &'static AtomicU32 {
+            static HELD: AtomicU32 = AtomicU32::new(0);
+            &HELD
+        }
 
-    tx.write_all("Enabling Test mode loopback...\n".as_bytes())
-        .unwrap();
-    // p.CAN0.tcr.write(|w| w.tste()._1().tstm()._10());
+        fn clock() -> Peripheral {
+            Peripheral::Can0
+        }
 
-    status(tx);
+        fn event_base() -> Event {
+            Event::CAN0_ERS
+        }
+    }
 
-    // Go to operation mode
-    tx.write_all("Entering CAN operation mode...\n".as_bytes())
-        .unwrap();
-    p.CAN0.ctlr.modify(|_, w| {
-        w.canm()._00() // Operation mode
-    });
+    #[test]
+    fn bit_timing_from_bitrate() {
+        // 16 TQ, sample point at 81%
+        let config = BitConfig::from_bitrate_with(24_000_000, 500_000).unwrap();
+        assert_eq!(config.BRP() + 1, 3);
+        assert_eq!(config.TSEG1() + 1, 12);
+        assert_eq!(config.TSEG2() + 1, 3);
+        assert_eq!(config.SJW() + 1, 3);
+        assert!(!config.CCLKS());
+        assert_eq!(config.tolerance_ppm(), 7317);
+
+        let config = BitConfig::from_bitrate_with(24_000_000, 1_000_000).unwrap();
+        assert_eq!(config.BRP() + 1, 2);
+        assert_eq!(config.TSEG1() + 1, 9);
+        assert_eq!(config.TSEG2() + 1, 2);
+
+        assert_eq!(
+            BitConfig::from_bitrate_with(24_000_000, 0).err(),
+            Some(Error::InvalidConfig)
+        );
+        assert_eq!(
+            BitConfig::from_bitrate_with(24_000_000, 1_000_001).err(),
+            Some(Error::InvalidConfig)
+        );
+        assert_eq!(
+            BitConfig::new_checked(false, 1, 3, 2, 1).err(),
+            Some(Error::InvalidConfig)
+        );
+    }
 
-    status(tx);
+    #[test]
+    fn mailbox_config_registers() {
+        let mut config = MailboxConfig::default();
+        assert_eq!((config.mier(), config.mkivlr()), (0, 0));
+        config.set_mailbox_receiver(4);
+        config.mailboxes[5] = MailboxMode::Rx(MailboxRxConfig {
+            interrupt: false,
+            one_shot: false,
+            mask_valid: false,
+            id: Id::Standard(StandardId::ZERO),
+        });
+        assert_eq!(config.mkivlr(), 1 << 5);
+        config.enable_all_interrupts();
+        assert_eq!(config.mier(), u32::MAX);
+
+        assert_eq!(Mask::accept_all().mkr(), 0);
+        let mask = Mask {
+            id: Id::Extended(ExtendedId::MAX),
+        };
+        assert_eq!(mask.mkr(), 0x1FFF_FFFF);
+    }
 
-    tx.write_all("Clearing Errors...\n".as_bytes()).unwrap();
-    // clear bus lock flag like a savage
-    p.CAN0.eifr.modify(|_, w| {
-        w.blif()._0() // Clear bus lock flag
-    });
+    #[test]
+    fn mailbox_config_render() {
+        let mut config = MailboxConfig::default();
+        config.set_mailbox_receiver(1);
+        config.masks[0] = Mask {
+            id: Id::Standard(StandardId::new(0x7F0).unwrap()),
+        };
+        let image = config.render();
+        assert_eq!(image.mkr[0], 0x7F0 << 18);
+        assert_eq!(&image.mkr[1..], &[0; 7]);
+        assert_eq!((image.mier, image.mkivlr), (0, 0));
+        assert_eq!((image.mctl[0], image.mctl[1]), (0, MCTL_RECREQ));
+        assert_eq!(image.mb_id, [0; 32]);
+        assert_eq!(
+            image.to_string(),
+            "IDFM: 00\n\
+             MKR: 1FC00000 00000000 00000000 00000000 00000000 00000000 00000000 00000000\n\
+             MIER: 00000000 MKIVLR: 00000000\n\
+             MB01: 40 id=00000000"
+        );
+    }
+
+    #[test]
+    fn id_modes() {
+        let standard = Id::Standard(StandardId::new(0x123).unwrap());
+        let extended = Id::Extended(ExtendedId::new(0x1234_5678).unwrap());
+        let mut config = MailboxConfig::default();
+        config.set_mailbox_filter(0, extended, true);
+        assert!(!config.is_valid());
+        config.set_id_mode(IdMode::Extended);
+        assert!(config.is_valid());
+        assert_eq!(config.render().mb_id[0], 0x1234_5678);
+        config.set_mailbox_filter(1, standard, true);
+        assert!(!config.is_valid());
+        config.set_id_mode(IdMode::Mixed);
+        config.set_mask(0, ExtendedId::MAX);
+        assert!(config.is_valid());
+        let image = config.render();
+        assert_eq!(
+            (image.idfm, image.mb_id[0], image.mb_id[1]),
+            (0b10, 0x9234_5678, 0x123 << 18)
+        );
+
+        // Received IDs, IDE only read back in mixed mode
+        let raw = MailboxId::from_bits(0x1234_5678);
+        assert_eq!(Id::from(IdMode::Extended.decode(raw)), extended);
+        assert_eq!(
+            Id::from(IdMode::Standard.decode(raw)),
+            Id::Standard(StandardId::new(0x48D).unwrap())
+        );
+        assert_eq!(Id::from(IdMode::Mixed.decode(raw.with_IDE(true))), extended);
+    }
+
+    #[test]
+    fn mailbox_id_round_trip() {
+        let id = Id::Standard(StandardId::new(0x123).unwrap());
+        let mailbox_id = MailboxId::from(id);
+        assert_eq!(mailbox_id.into_bits(), 0x123 << 18);
+        assert_eq!(Id::from(mailbox_id), id);
+
+        // SID is the top 11 bits of the ID, EID the lower 18, IDE set
+        let id = Id::Extended(ExtendedId::new(0x1234_5678).unwrap());
+        let mailbox_id = MailboxId::from(id);
+        assert_eq!(mailbox_id.into_bits(), 0x9234_5678);
+        assert_eq!(Id::from(mailbox_id), id);
+    }
+
+    #[test]
+    fn frame_conversion() {
+        use embedded_can::Frame as _;
+
+        let frame = Frame::new_standard(0x123, &[1, 2]).unwrap();
+        assert_eq!(frame.to_string(), "123 [2] 01 02");
+        assert_eq!(frame.data(), &[1, 2]);
+        assert!(!frame.is_extended() && !frame.is_remote_frame());
+        assert!(Frame::new_standard(0x800, &[]).is_none());
+        assert!(Frame::new_extended(0x1234_5678, &[0; 9]).is_none());
+
+        let mut frame = Frame::new_remote(ExtendedId::new(0x1234_5678).unwrap(), 4).unwrap();
+        assert_eq!(frame.to_string(), "12345678 [4] remote");
+        frame.set_id(StandardId::new(0x7FF).unwrap());
+        assert!(frame.is_remote_frame() && !frame.is_extended());
+
+        let mut frame = Frame::EMPTY;
+        assert_eq!(frame.set_data(&[0; 9]), Err(Error::DataLength));
+        frame.set_data(&[0xAA; 3]).unwrap();
+        assert_eq!(frame.dlc(), 3);
+    }
+
+    #[test]
+    fn tx_queue_wraps() {
+        let mut queue = TxQueue::new();
+        assert_eq!(queue.push(Frame::EMPTY), Err(QueueFull));
+        queue.buf = Some(Box::leak(Box::new([Frame::EMPTY; 3])));
+        for id in 0..3 {
+            queue.push(Frame::new_standard(id, &[]).unwrap()).unwrap();
+        }
+        assert_eq!(queue.push(Frame::EMPTY), Err(QueueFull));
+        // Two frames taken, the next goes in the first slot
+        queue.head = 2;
+        queue.len = 1;
+        queue.push(Frame::new_standard(3, &[]).unwrap()).unwrap();
+        assert_eq!(queue.buf.as_ref().unwrap()[0].raw_id(), 3);
+    }
 
-    status(tx);
-
-    tx.write_all("Resetting CAN timer...\n".as_bytes()).unwrap();
-    // Rest timer as per docs
-    p.CAN0.ctlr.modify(|_, w| w.tsrc()._1()); // Reset timer
-    status(tx);
-
-    // wait a bit for the bus lock flag to maybe come back
-    tx.write_all("Waiting...\n".as_bytes()).unwrap();
-    cortex_m::asm::delay(1_000_000);
-    status(tx);
-
-    // Write some data into the mailbox i guess
-    p.CAN0.mb0_d0.write(|w| unsafe { w.data0().bits(0x55) });
-    p.CAN0.mb0_dl.write(|w| unsafe { w.dlc().bits(1) });
-
-    //
-    p.CAN0.mb0_id.write(|w| unsafe { w.sid().bits(0x0) });
-
-    p.CAN0.mctl_tx()[0].write(|w| w.trmreq()._1());
-
-    // Loop through IDS
-    for i in 0..1 {
-        // Wait for sent data flag to be set
-        while p.CAN0.mctl_tx()[0].read().sentdata().bit_is_clear() {
-            // Wait for the transmission to complete
-        }
-        // Clear the register
-        p.CAN0.mctl_tx()[0].modify(|_, w| unsafe { w.bits(0) });
-        // Set ID
-        p.CAN0.mb0_id.write(|w| unsafe { w.sid().bits(i) });
-        // Trigger the transmission
-        p.CAN0.mctl_tx()[0].write(|w| w.trmreq()._1());
-
-        status(tx);
-    }
-}
-
-fn status(tx: &mut impl Write) {
-    // Print the STR reg
-    let p = unsafe { ra4m1::Peripherals::steal() };
-    tx.write_all("State\n".as_bytes()).unwrap();
-    tx.write_fmt(format_args!("CAN0 STR: {:08X}\n", p.CAN0.str.read().bits()))
-        .unwrap();
-    // Print EIFR reg
-    tx.write_fmt(format_args!(
-        "CAN0 EIFR: {:08X}\n",
-        p.CAN0.eifr.read().bits()
-    ))
-    .unwrap();
-    // Print ctlr
-    tx.write_fmt(format_args!(
-        "CAN0 CTLR: {:08X}\n",
-        p.CAN0.ctlr.read().bits()
-    ))
-    .unwrap();
+    #[test]
+    fn id_filter() {
+        let standard = Id::Standard(StandardId::new(0x123).unwrap());
+        let extended = Id::Extended(ExtendedId::new(0x123).unwrap());
+        let mut filter = IdFilter::new();
+        filter.add(extended).unwrap();
+        assert!(filter.accepts(standard));
+
+        // Standard and extended IDs with the same value are told apart
+        filter.mode = Some(FilterMode::Allow);
+        assert!(filter.accepts(extended) && !filter.accepts(standard));
+        filter.mode = Some(FilterMode::Deny);
+        assert!(!filter.accepts(extended) && filter.accepts(standard));
+
+        // IDs kept sorted without duplicates until full
+        for raw in (1..MAX_FILTER_IDS as u16).rev() {
+            filter
+                .add(Id::Standard(StandardId::new(raw).unwrap()))
+                .unwrap();
+        }
+        assert_eq!(filter.add(extended), Ok(()));
+        assert_eq!(filter.add(standard), Err(Error::FilterFull));
+        assert!(filter.ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(filter.remove(extended));
+        assert!(!filter.remove(extended));
+        filter.add(standard).unwrap();
+        assert!(!filter.accepts(standard));
+    }
+
+    #[test]
+    fn mock_queue_and_receive() {
+        let can = unsafe { &*MockCan::peripheral() };
+        critical_section::with(|cs| {
+            MockCan::tx_queue().borrow_ref_mut(cs).buf =
+                Some(Box::leak(Box::new([Frame::EMPTY; 2])));
+        });
+        let queued = || critical_section::with(|cs| MockCan::tx_queue().borrow_ref(cs).len);
+
+        // Every mailbox requested after 32 frames, then the queue fills
+        let frame = Frame::new_standard(0x123, &[1, 2, 3]).unwrap();
+        for _ in 0..34 {
+            assert_eq!(queue_frame::<MockCan>(frame), Ok(()));
+        }
+        assert_eq!(queue_frame::<MockCan>(frame), Err(QueueFull));
+        assert_eq!(queued(), 2);
+        assert_eq!(can.mctl_tx()[0].read().bits(), 1 << 7);
+        assert_eq!(unsafe { mb_id(can, 0).read_volatile() }, 0x123 << 18);
+        assert_eq!(unsafe { mb_dl(can, 0).read_volatile() }, 3);
+        assert_eq!(unsafe { mb_d0(can, 0).add(2).read_volatile() }, 3);
+
+        // Mailbox 0 sent, TRMREQ and SENTDATA, the queue moves into it
+        can.mctl_tx()[0].write(|w| unsafe { w.bits(0x81) });
+        reclaim_mailboxes(can);
+        drain_tx_queue::<MockCan>();
+        assert_eq!(queued(), 1);
+        assert_eq!(can.mctl_tx()[0].read().bits(), 1 << 7);
+
+        // Mailbox 31 received a frame, RECREQ and NEWDATA, in mixed ID mode
+        assert!(read_mailbox(can, 31).is_none());
+        can.ctlr.write(|w| w.idfm()._10());
+        let received = Frame::new_extended(0x1ABC_DEF0, &[9, 8]).unwrap();
+        unsafe {
+            mb_id(can, 31).write_volatile(received.id.into_bits());
+            mb_dl(can, 31).write_volatile(2);
+            mb_d0(can, 31).write_volatile(9);
+            mb_d0(can, 31).add(1).write_volatile(8);
+            mb_ts(can, 31).write_volatile(0x1234);
+        }
+        can.mctl_rx()[31].write(|w| unsafe { w.bits(0x41) });
+        let frame = read_mailbox(can, 31).unwrap();
+        assert_eq!(frame.to_string(), "1ABCDEF0 [2] 09 08");
+        assert_eq!(frame.timestamp(), 0x1234);
+        assert_eq!(can.mctl_rx()[31].read().bits(), 1 << 6);
+
+        // A DLC above 8 is taken as 8
+        unsafe { mb_dl(can, 31).write_volatile(15) };
+        can.mctl_rx()[31].write(|w| unsafe { w.bits(0x41) });
+        assert_eq!(read_mailbox(can, 31).unwrap().dlc, 8);
+    }
 }
@@ -0,0 +1,246 @@
+//! Received frames shared out between protocol stacks by ID.
+//!
+//! Each stack subscribes to a range of IDs on a [`Router`] and gets a
+//! [`Subscriber`] with its own queue. The receive interrupt routes every
+//! frame into the queue of each subscriber whose range has its ID, so ISO-TP,
+//! J1939 and application signals can read the one bus without knowing of each
+//! other:
+//!
+//! ```ignore
+//! use can::router::{IdRange, Router};
+//!
+//! static ROUTER: Router<4, 16> = Router::new();
+//!
+//! fn on_frame(frame: &Frame) {
+//!     ROUTER.route(frame);
+//! }
+//!
+//! let mut diagnostics = ROUTER.subscribe(IdRange::standard(0x7E0, 0x7EF))?;
+//! let mut j1939 = ROUTER.subscribe(IdRange::extended(0, 0x1FFF_FFFF))?;
+//! can.on_receive(0, Some(on_frame));
+//! can.enable_rx_interrupt(Irq);
+//! loop {
+//!     while let Some(frame) = diagnostics.try_receive() {
+//!         isotp.on_frame(&frame);
+//!     }
+//! }
+//! ```
+//!
+//! The queues are single producer, single consumer, so a subscriber is read
+//! without a critical section. A queue of `LEN` holds `LEN - 1` frames,
+//! frames arriving when it's full are dropped and counted, see
+//! [`Subscriber::dropped`].
+
+use core::cell::{RefCell, UnsafeCell};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use critical_section::Mutex;
+use embedded_can::{Frame as _, Id};
+use heapless::spsc::{Consumer, Producer, Queue};
+
+use super::{Error, Frame};
+
+/// IDs a [`Subscriber`] receives, standard or extended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct IdRange {
+    extended: bool,
+    first: u32,
+    last: u32,
+}
+
+impl IdRange {
+    /// Standard IDs from `first` to `last`, inclusive.
+    pub const fn standard(first: u16, last: u16) -> Self {
+        Self {
+            extended: false,
+            first: first as u32,
+            last: last as u32,
+        }
+    }
+
+    /// Extended IDs from `first` to `last`, inclusive.
+    pub const fn extended(first: u32, last: u32) -> Self {
+        Self {
+            extended: true,
+            first,
+            last,
+        }
+    }
+
+    /// Whether `id` is in the range.
+    pub fn contains(&self, id: Id) -> bool {
+        let (extended, raw) = match id {
+            Id::Standard(id) => (false, id.as_raw() as u32),
+            Id::Extended(id) => (true, id.as_raw()),
+        };
+        extended == self.extended && (self.first..=self.last).contains(&raw)
+    }
+}
+
+// The interrupt side of a subscription
+struct Route<'a, const LEN: usize> {
+    ids: IdRange,
+    producer: Producer<'a, Frame, LEN>,
+}
+
+/// Shares received frames out between up to `N` [`Subscriber`]s, each with a
+/// queue of `LEN`.
+///
+/// Keep it in a `static` so the receive callback can reach it.
+pub struct Router<const N: usize, const LEN: usize> {
+    routes: Mutex<RefCell<[Option<Route<'static, LEN>>; N]>>,
+    queues: [UnsafeCell<Queue<Frame, LEN>>; N],
+    dropped: [AtomicU32; N],
+}
+
+// The queues are only reached through the producer in `routes` and the
+// consumer of one subscriber, split while neither exists
+unsafe impl<const N: usize, const LEN: usize> Sync for Router<N, LEN> {}
+
+impl<const N: usize, const LEN: usize> Router<N, LEN> {
+    /// A router without subscribers.
+    pub const fn new() -> Self {
+        Self {
+            routes: Mutex::new(RefCell::new([const { None }; N])),
+            queues: [const { UnsafeCell::new(Queue::new()) }; N],
+            dropped: [const { AtomicU32::new(0) }; N],
+        }
+    }
+
+    /// Receive the frames with an ID in `ids` from now on.
+    ///
+    /// Ranges may overlap, a frame goes to every subscriber it matches.
+    /// [`Error::RouterFull`] if there are already `N` subscribers.
+    pub fn subscribe(&'static self, ids: IdRange) -> Result<Subscriber<N, LEN>, Error> {
+        critical_section::with(|cs| {
+            let mut routes = self.routes.borrow_ref_mut(cs);
+            let index = routes
+                .iter()
+                .position(Option::is_none)
+                .ok_or(Error::RouterFull)?;
+            // Safety: the slot is free, so the last subscriber of the queue
+            // and its producer are gone
+            let queue = unsafe { &mut *self.queues[index].get() };
+            *queue = Queue::new();
+            let (producer, consumer) = queue.split();
+            routes[index] = Some(Route { ids, producer });
+            self.dropped[index].store(0, Ordering::Relaxed);
+            Ok(Subscriber {
+                router: self,
+                index,
+                ids,
+                consumer,
+            })
+        })
+    }
+
+    /// Queue `frame` for each subscriber whose range has its ID, returning
+    /// how many took it. Call from the [`Can::on_receive`](super::Can::on_receive)
+    /// callback.
+    pub fn route(&self, frame: &Frame) -> usize {
+        let id = frame.id();
+        critical_section::with(|cs| {
+            let mut routes = self.routes.borrow_ref_mut(cs);
+            let mut delivered = 0;
+            for (index, route) in routes.iter_mut().enumerate() {
+                let Some(route) = route.as_mut().filter(|route| route.ids.contains(id)) else {
+                    continue;
+                };
+                match route.producer.enqueue(*frame) {
+                    Ok(()) => delivered += 1,
+                    Err(_) => {
+                        self.dropped[index].fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+            delivered
+        })
+    }
+}
+
+impl<const N: usize, const LEN: usize> Default for Router<N, LEN> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Frames routed to one subscriber of a [`Router`], from
+/// [`Router::subscribe`]. Dropping it unsubscribes.
+pub struct Subscriber<const N: usize, const LEN: usize> {
+    router: &'static Router<N, LEN>,
+    index: usize,
+    ids: IdRange,
+    consumer: Consumer<'static, Frame, LEN>,
+}
+
+impl<const N: usize, const LEN: usize> Subscriber<N, LEN> {
+    /// Take the oldest queued frame.
+    pub fn try_receive(&mut self) -> Option<Frame> {
+        self.consumer.dequeue()
+    }
+
+    /// Number of frames queued.
+    pub fn len(&self) -> usize {
+        self.consumer.len()
+    }
+
+    /// Whether no frames are queued.
+    pub fn is_empty(&self) -> bool {
+        self.consumer.is_empty()
+    }
+
+    /// The IDs received.
+    pub fn ids(&self) -> IdRange {
+        self.ids
+    }
+
+    /// Frames dropped because the queue was full, since subscribing.
+    pub fn dropped(&self) -> u32 {
+        self.router.dropped[self.index].load(Ordering::Relaxed)
+    }
+}
+
+impl<const N: usize, const LEN: usize> Drop for Subscriber<N, LEN> {
+    fn drop(&mut self) {
+        critical_section::with(|cs| self.router.routes.borrow_ref_mut(cs)[self.index] = None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_can::Frame as _;
+
+    use super::*;
+
+    #[test]
+    fn router_subscribers() {
+        static ROUTER: Router<2, 3> = Router::new();
+        let mut diagnostics = ROUTER.subscribe(IdRange::standard(0x7E0, 0x7EF)).unwrap();
+        let mut all = ROUTER.subscribe(IdRange::standard(0, 0x7FF)).unwrap();
+        assert_eq!(
+            ROUTER.subscribe(IdRange::extended(0, 0)).err(),
+            Some(Error::RouterFull)
+        );
+
+        // Overlapping ranges both get the frame, extended IDs match neither
+        assert_eq!(ROUTER.route(&Frame::new_standard(0x7E8, &[1]).unwrap()), 2);
+        assert_eq!(ROUTER.route(&Frame::new_standard(0x100, &[2]).unwrap()), 1);
+        assert_eq!(ROUTER.route(&Frame::new_extended(0x7E8, &[3]).unwrap()), 0);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics.try_receive().unwrap().data(), &[1]);
+        assert!(diagnostics.is_empty());
+
+        // A queue of 3 holds 2 frames
+        assert_eq!(ROUTER.route(&Frame::new_standard(0x101, &[4]).unwrap()), 0);
+        assert_eq!(all.dropped(), 1);
+        assert_eq!(all.try_receive().unwrap().raw_id(), 0x7E8);
+
+        // Dropping a subscriber frees its slot with an empty queue
+        drop(all);
+        let mut extended = ROUTER.subscribe(IdRange::extended(0x7E8, 0x7E8)).unwrap();
+        assert!(extended.is_empty() && extended.dropped() == 0);
+        assert_eq!(ROUTER.route(&Frame::new_extended(0x7E8, &[3]).unwrap()), 1);
+        assert_eq!(extended.try_receive().unwrap().data(), &[3]);
+    }
+}
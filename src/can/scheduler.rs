@@ -0,0 +1,225 @@
+//! Periodic transmission of frames from a GPT interrupt.
+//!
+//! A GPT channel overflows every millisecond and sends the frames that are
+//! due, so the timing doesn't depend on the main loop:
+//!
+//! ```ignore
+//! bind_interrupts!(struct Irq {
+//!     IEL12 => can::ScheduleHandler<GPT163>;
+//! });
+//!
+//! let mut scheduler = can::Scheduler::new(p.GPT163, Irq);
+//! let status = scheduler.add(Frame::new_standard(0x100, &[0; 8]).unwrap(), 10, 0)?;
+//! scheduler.add(Frame::new_standard(0x700, &[0x05]).unwrap(), 100, 5)?;
+//! loop {
+//!     scheduler.set_data(status, &read_engine())?;
+//! }
+//! ```
+//!
+//! Frames go through the transmit queue of the [`Can`](super::Can) driver,
+//! see [`Can::set_tx_queue`](super::Can::set_tx_queue), and are skipped
+//! while it isn't in operation mode.
+
+use core::cell::RefCell;
+use core::marker::PhantomData;
+
+use critical_section::Mutex;
+use ra4m1::CAN0;
+
+use super::{CanMode, Error, Frame, Instance as _, queue_frame, read_mode};
+use crate::clk::{ClockGuard, Clocks, Peripheral};
+use crate::gpt::{self, Prescaler};
+use crate::interrupts::{Binding, Handler, clear_interrupt, map_and_enable_interrupt};
+
+/// Number of frames a [`Scheduler`] can send.
+pub const MAX_SCHEDULED: usize = 16;
+
+// GTST.TCFPO, overflow flag
+const GTST_TCFPO: u32 = 1 << 6;
+// Overflow event offset from the first event of the channel
+const EVENT_OVF: u8 = 6;
+
+static SCHEDULE: Mutex<RefCell<Schedule>> = Mutex::new(RefCell::new(Schedule::new()));
+
+#[derive(Clone, Copy)]
+struct Entry {
+    frame: Frame,
+    period: u32,
+    next: u32,
+    enabled: bool,
+}
+
+struct Schedule {
+    entries: [Option<Entry>; MAX_SCHEDULED],
+    now: u32,
+    // Frames the transmit queue had no room for
+    missed: u32,
+}
+
+impl Schedule {
+    const fn new() -> Self {
+        Self {
+            entries: [None; MAX_SCHEDULED],
+            now: 0,
+            missed: 0,
+        }
+    }
+
+    fn entry(&mut self, id: ScheduleId) -> Option<&mut Entry> {
+        self.entries[id.0].as_mut()
+    }
+}
+
+/// A frame added to the [`Scheduler`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ScheduleId(usize);
+
+/// Sends the frames that are due, bind to an interrupt of the GPT channel.
+pub struct ScheduleHandler<T: gpt::Instance> {
+    _phantom: PhantomData<T>,
+}
+
+impl<T: gpt::Instance> Handler for ScheduleHandler<T> {
+    unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
+        clear_interrupt(interrupt);
+        let gpt = unsafe { &*T::peripheral() };
+        gpt.gtst
+            .modify(|r, w| unsafe { w.bits(r.bits() & !GTST_TCFPO) });
+
+        let running = crate::clk::is_peripheral_enabled(Peripheral::Can0)
+            && read_mode(unsafe { &*CAN0::peripheral() }) == CanMode::Operation;
+        critical_section::with(|cs| {
+            let mut schedule = SCHEDULE.borrow_ref_mut(cs);
+            schedule.now = schedule.now.wrapping_add(1);
+            let now = schedule.now;
+            let mut missed = 0;
+            for entry in schedule.entries.iter_mut().flatten() {
+                if !entry.enabled || (now.wrapping_sub(entry.next) as i32) < 0 {
+                    continue;
+                }
+                entry.next = entry.next.wrapping_add(entry.period);
+                if running && queue_frame::<CAN0>(entry.frame).is_err() {
+                    missed += 1;
+                }
+            }
+            schedule.missed += missed;
+        });
+    }
+}
+
+/// Sends frames periodically, timed by GPT channel `T`.
+///
+/// The schedule is shared, so only one scheduler should exist at a time.
+pub struct Scheduler<T: gpt::Instance> {
+    _clock: ClockGuard,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: gpt::Instance> Scheduler<T> {
+    /// Start the channel overflowing every millisecond from the current
+    /// PCLKD, with an empty schedule.
+    pub fn new<IRQ: Binding<ScheduleHandler<T>>>(_gpt: T, _irq: IRQ) -> Self {
+        critical_section::with(|cs| *SCHEDULE.borrow_ref_mut(cs) = Schedule::new());
+        let clock = gpt::init::<T>(Prescaler::Div4);
+        let period =
+            (Clocks::read().pclkd() / Prescaler::Div4.divisor() / 1000).clamp(1, T::max_count());
+        let reg = unsafe { &*T::peripheral() };
+        reg.gtpr.write(|w| unsafe { w.bits(period - 1) });
+        let interrupt = <IRQ as Binding<ScheduleHandler<T>>>::interrupt();
+        clear_interrupt(interrupt);
+        map_and_enable_interrupt(interrupt, T::event_base().offset(EVENT_OVF));
+        gpt::start::<T>();
+        Self {
+            _clock: clock,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Send `frame` every `period_ms`, first after `offset_ms`.
+    ///
+    /// Offsets spread frames with the same period over different
+    /// milliseconds, rather than queueing them all at once.
+    pub fn add(
+        &mut self,
+        frame: Frame,
+        period_ms: u32,
+        offset_ms: u32,
+    ) -> Result<ScheduleId, Error> {
+        critical_section::with(|cs| {
+            let mut schedule = SCHEDULE.borrow_ref_mut(cs);
+            let index = schedule
+                .entries
+                .iter()
+                .position(Option::is_none)
+                .ok_or(Error::ScheduleFull)?;
+            let next = schedule.now.wrapping_add(offset_ms.max(1));
+            schedule.entries[index] = Some(Entry {
+                frame,
+                period: period_ms.max(1),
+                next,
+                enabled: true,
+            });
+            Ok(ScheduleId(index))
+        })
+    }
+
+    /// Stop sending a frame and free its place.
+    pub fn remove(&mut self, id: ScheduleId) {
+        critical_section::with(|cs| SCHEDULE.borrow_ref_mut(cs).entries[id.0] = None);
+    }
+
+    /// Start sending a frame again, one period from now.
+    pub fn enable(&mut self, id: ScheduleId) {
+        critical_section::with(|cs| {
+            let mut schedule = SCHEDULE.borrow_ref_mut(cs);
+            let now = schedule.now;
+            if let Some(entry) = schedule.entry(id) {
+                if !entry.enabled {
+                    entry.enabled = true;
+                    entry.next = now.wrapping_add(entry.period);
+                }
+            }
+        });
+    }
+
+    /// Pause sending a frame, keeping its place.
+    pub fn disable(&mut self, id: ScheduleId) {
+        critical_section::with(|cs| {
+            if let Some(entry) = SCHEDULE.borrow_ref_mut(cs).entry(id) {
+                entry.enabled = false;
+            }
+        });
+    }
+
+    /// Replace the data sent from the next transmission,
+    /// [`Error::DataLength`] if longer than 8 bytes.
+    pub fn set_data(&mut self, id: ScheduleId, data: &[u8]) -> Result<(), Error> {
+        self.update(id, |frame| frame.set_data(data))
+            .unwrap_or(Ok(()))
+    }
+
+    /// Change a frame in place, e.g. a counter byte. `None` if it was
+    /// removed.
+    ///
+    /// `f` runs in a critical section, so should be short.
+    pub fn update<R>(&mut self, id: ScheduleId, f: impl FnOnce(&mut Frame) -> R) -> Option<R> {
+        critical_section::with(|cs| {
+            SCHEDULE
+                .borrow_ref_mut(cs)
+                .entry(id)
+                .map(|entry| f(&mut entry.frame))
+        })
+    }
+
+    /// Transmissions dropped because the transmit queue was full.
+    pub fn missed(&self) -> u32 {
+        critical_section::with(|cs| SCHEDULE.borrow_ref(cs).missed)
+    }
+}
+
+impl<T: gpt::Instance> Drop for Scheduler<T> {
+    fn drop(&mut self) {
+        gpt::stop::<T>();
+    }
+}
@@ -0,0 +1,166 @@
+//! Signals packed in CAN payloads, as described by a DBC file.
+//!
+//! A [`Signal`] gives the position, byte order and scaling of one value, so
+//! signals can be declared as constants from the DBC by hand:
+//!
+//! ```ignore
+//! use can::signals::Signal;
+//!
+//! // SG_ EngineSpeed : 24|16@1+ (0.25,0) [0|16383.75] "rpm"
+//! const ENGINE_SPEED: Signal = Signal::intel(24, 16).scale(0.25, 0.0);
+//! // SG_ CoolantTemp : 7|8@0+ (1,-40) [-40|215] "degC"
+//! const COOLANT_TEMP: Signal = Signal::motorola(7, 8).scale(1.0, -40.0);
+//!
+//! let rpm = ENGINE_SPEED.decode(frame.data());
+//! let mut data = [0u8; 8];
+//! COOLANT_TEMP.encode(&mut data, 90.0);
+//! ```
+//!
+//! Start bits use the DBC numbering, bit `n` of byte `b` is `8 * b + n`. An
+//! Intel signal starts at its least significant bit, a Motorola one at its
+//! most significant bit. Payloads shorter than 8 bytes read as zero past
+//! their end and bits past their end aren't written.
+
+/// Byte order of a signal, `@1` and `@0` in a DBC file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ByteOrder {
+    /// Little endian, the start bit is the least significant bit
+    Intel,
+    /// Big endian, the start bit is the most significant bit
+    Motorola,
+}
+
+/// Position and scaling of a value in a payload.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Signal {
+    // Position of the least significant bit in the little endian word
+    // for Intel signals, in the big endian word for Motorola signals
+    lsb: u8,
+    len: u8,
+    order: ByteOrder,
+    signed: bool,
+    factor: f32,
+    offset: f32,
+}
+
+impl Signal {
+    /// Unsigned, unscaled little endian signal of `len` bits from
+    /// `start`. Panics, at compile time in a constant, if it doesn't fit in
+    /// 8 bytes.
+    pub const fn intel(start: u8, len: u8) -> Self {
+        assert!(len != 0 && len <= 64 && start as u32 + len as u32 <= 64);
+        Self::with(start, len, ByteOrder::Intel)
+    }
+
+    /// Unsigned, unscaled big endian signal of `len` bits, its most
+    /// significant bit at `start`. Panics, at compile time in a constant, if
+    /// it doesn't fit in 8 bytes.
+    pub const fn motorola(start: u8, len: u8) -> Self {
+        assert!(len != 0 && len <= 64 && start < 64);
+        // Position of the start bit counting from the end of byte 7
+        let msb = (7 - start / 8) * 8 + start % 8;
+        assert!(msb + 1 >= len);
+        Self::with(msb + 1 - len, len, ByteOrder::Motorola)
+    }
+
+    const fn with(lsb: u8, len: u8, order: ByteOrder) -> Self {
+        Self {
+            lsb,
+            len,
+            order,
+            signed: false,
+            factor: 1.0,
+            offset: 0.0,
+        }
+    }
+
+    /// Two's complement signed, `-` in a DBC file.
+    pub const fn signed(mut self) -> Self {
+        self.signed = true;
+        self
+    }
+
+    /// Physical value = raw * `factor` + `offset`.
+    pub const fn scale(mut self, factor: f32, offset: f32) -> Self {
+        self.factor = factor;
+        self.offset = offset;
+        self
+    }
+
+    /// Byte order.
+    pub const fn byte_order(&self) -> ByteOrder {
+        self.order
+    }
+
+    /// Length in bits.
+    pub const fn bits(&self) -> u8 {
+        self.len
+    }
+
+    /// Raw bits of the signal, unsigned.
+    pub fn raw(&self, data: &[u8]) -> u64 {
+        (self.word(data) >> self.lsb) & self.mask()
+    }
+
+    /// Raw value, sign extended if the signal is [`signed`](Self::signed).
+    pub fn raw_value(&self, data: &[u8]) -> i64 {
+        let raw = self.raw(data);
+        if self.signed && self.len < 64 {
+            // Shift the sign bit to the top and back
+            let shift = 64 - self.len as u32;
+            ((raw << shift) as i64) >> shift
+        } else {
+            raw as i64
+        }
+    }
+
+    /// Physical value.
+    pub fn decode(&self, data: &[u8]) -> f32 {
+        self.raw_value(data) as f32 * self.factor + self.offset
+    }
+
+    /// Write the raw bits of the signal, the ones above its length are
+    /// ignored.
+    pub fn set_raw(&self, data: &mut [u8], raw: u64) {
+        let mask = self.mask() << self.lsb;
+        let word = (self.word(data) & !mask) | ((raw << self.lsb) & mask);
+        let bytes = match self.order {
+            ByteOrder::Intel => word.to_le_bytes(),
+            ByteOrder::Motorola => word.to_be_bytes(),
+        };
+        let len = data.len().min(8);
+        data[..len].copy_from_slice(&bytes[..len]);
+    }
+
+    /// Write a physical value, rounded to the nearest raw value and clamped
+    /// to the range of the signal.
+    pub fn encode(&self, data: &mut [u8], value: f32) {
+        let raw = (value - self.offset) / self.factor;
+        // Round half away from zero, `as` saturates
+        let raw = (if raw < 0.0 { raw - 0.5 } else { raw + 0.5 }) as i64;
+        let raw = if self.signed {
+            let max = (self.mask() >> 1) as i64;
+            raw.clamp(-max - 1, max) as u64
+        } else {
+            raw.clamp(0, self.mask().min(i64::MAX as u64) as i64) as u64
+        };
+        self.set_raw(data, raw);
+    }
+
+    fn mask(&self) -> u64 {
+        u64::MAX >> (64 - self.len as u32)
+    }
+
+    // The payload as a word in the byte order of the signal
+    fn word(&self, data: &[u8]) -> u64 {
+        let mut bytes = [0u8; 8];
+        let len = data.len().min(8);
+        bytes[..len].copy_from_slice(&data[..len]);
+        match self.order {
+            ByteOrder::Intel => u64::from_le_bytes(bytes),
+            ByteOrder::Motorola => u64::from_be_bytes(bytes),
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Transmission at a value of the CAN timestamp counter, for time triggered
+//! schedules and latency measurements.
+//!
+//! [`Can::send_at`] loads the frame into a transmit mailbox straight away and
+//! starts a GPT channel counting down to the requested time. Its overflow
+//! interrupt sets TRMREQ, so the frame goes out within a few microseconds of
+//! the time, or as soon as the bus is free:
+//!
+//! ```ignore
+//! bind_interrupts!(struct Irq {
+//!     IEL13 => can::SendAtHandler<GPT163>;
+//! });
+//!
+//! let mut timer = can::SendAtTimer::new(p.GPT163, Irq);
+//! let at = can.timestamp().wrapping_add(1000);
+//! can.send_at(&mut timer, Frame::new_standard(0x100, &[1]).unwrap(), at)?;
+//! ```
+//!
+//! The GPT counts PCLKD, so the bit timing must be from PCLKB, as
+//! [`BitConfig::from_bitrate`](super::BitConfig::from_bitrate) gives it.
+//! One frame can wait at a time for each timer.
+
+use core::cell::Cell;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use critical_section::Mutex;
+
+use super::{BitConfig, Can, Error, Frame, Instance, Running, free_mailbox, write_mailbox};
+use crate::clk::{ClockGuard, Clocks};
+use crate::gpt::{self, Prescaler};
+use crate::interrupts::{Binding, Handler, clear_interrupt, map_and_enable_interrupt};
+
+// GTST.TCFPO, overflow flag
+const GTST_TCFPO: u32 = 1 << 6;
+// Overflow event offset from the first event of the channel
+const EVENT_OVF: u8 = 6;
+// Timestamps further ahead than this are taken as passed
+const MAX_AHEAD: u16 = 0x8000;
+
+static PENDING: Mutex<Cell<Option<Pending>>> = Mutex::new(Cell::new(None));
+
+// A mailbox loaded and waiting for its time
+#[derive(Clone, Copy)]
+struct Pending {
+    mailbox: usize,
+    peripheral: fn() -> *const ra4m1::can0::RegisterBlock,
+    held: &'static AtomicU32,
+}
+
+impl Pending {
+    // Request transmission of the mailbox, or give it back with `send` false
+    fn release(self, send: bool) {
+        if send {
+            let can = unsafe { &*(self.peripheral)() };
+            can.mctl_tx()[self.mailbox].write(|w| w.trmreq()._1());
+        }
+        self.held.fetch_and(!(1 << self.mailbox), Ordering::Relaxed);
+    }
+}
+
+/// Sends the waiting frame, bind to an interrupt of the GPT channel.
+pub struct SendAtHandler<T: gpt::Instance> {
+    _phantom: PhantomData<T>,
+}
+
+impl<T: gpt::Instance> Handler for SendAtHandler<T> {
+    unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
+        gpt::stop::<T>();
+        if let Some(pending) = critical_section::with(|cs| PENDING.borrow(cs).take()) {
+            pending.release(true);
+        }
+        let gpt = unsafe { &*T::peripheral() };
+        gpt.gtst
+            .modify(|r, w| unsafe { w.bits(r.bits() & !GTST_TCFPO) });
+        clear_interrupt(interrupt);
+    }
+}
+
+/// GPT channel `T` timing [`Can::send_at`].
+///
+/// The waiting frame is shared, so only one timer should exist at a time.
+pub struct SendAtTimer<T: gpt::Instance> {
+    _clock: ClockGuard,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: gpt::Instance> SendAtTimer<T> {
+    /// Set up the channel stopped, with its overflow interrupt.
+    pub fn new<IRQ: Binding<SendAtHandler<T>>>(_gpt: T, _irq: IRQ) -> Self {
+        critical_section::with(|cs| PENDING.borrow(cs).set(None));
+        let clock = gpt::init::<T>(Prescaler::Div1);
+        let interrupt = <IRQ as Binding<SendAtHandler<T>>>::interrupt();
+        clear_interrupt(interrupt);
+        map_and_enable_interrupt(interrupt, T::event_base().offset(EVENT_OVF));
+        Self {
+            _clock: clock,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Whether a frame is waiting for its time.
+    pub fn is_pending(&self) -> bool {
+        critical_section::with(|cs| PENDING.borrow(cs).get().is_some())
+    }
+
+    /// Drop the waiting frame, true if there was one. Its mailbox is freed
+    /// without sending.
+    pub fn cancel(&mut self) -> bool {
+        gpt::stop::<T>();
+        match critical_section::with(|cs| PENDING.borrow(cs).take()) {
+            Some(pending) => {
+                pending.release(false);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Count `ticks` of PCLKD then overflow, with the smallest prescaler
+    // that fits
+    fn arm(&mut self, ticks: u64) -> Result<(), Error> {
+        let prescaler = [
+            Prescaler::Div1,
+            Prescaler::Div4,
+            Prescaler::Div16,
+            Prescaler::Div64,
+            Prescaler::Div256,
+            Prescaler::Div1024,
+        ]
+        .into_iter()
+        .find(|p| ticks / p.divisor() as u64 <= T::max_count() as u64)
+        .ok_or(Error::InvalidConfig)?;
+        let count = (ticks / prescaler.divisor() as u64).max(1) as u32;
+        let gpt = unsafe { &*T::peripheral() };
+        gpt.gtcr
+            .write(|w| unsafe { w.bits((prescaler as u32) << 24) });
+        gpt.gtpr.write(|w| unsafe { w.bits(count - 1) });
+        gpt.gtcnt.write(|w| unsafe { w.bits(0) });
+        gpt.gtst.write(|w| unsafe { w.bits(0) });
+        gpt::start::<T>();
+        Ok(())
+    }
+}
+
+impl<T: gpt::Instance> Drop for SendAtTimer<T> {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+impl<I: Instance> Can<Running, I> {
+    /// Send `frame` when the timestamp counter reaches `timestamp`, see
+    /// [`timestamp`](Self::timestamp).
+    ///
+    /// The frame takes a transmit mailbox until then, which
+    /// [`send_frame`](Self::send_frame) and the queue leave alone.
+    /// [`Error::Late`] if the time has passed or is more than 32768 counts
+    /// ahead, [`Error::TimerBusy`] if another frame is waiting and
+    /// [`Error::InvalidConfig`] if the bit timing is from CANMCLK or the wait
+    /// doesn't fit in the channel.
+    pub fn send_at<T: gpt::Instance>(
+        &self,
+        timer: &mut SendAtTimer<T>,
+        frame: Frame,
+        timestamp: u16,
+    ) -> Result<(), Error> {
+        let can = self.reg();
+        if can.str.read().bost().bit_is_set() {
+            return Err(Error::BusOff);
+        }
+        if timer.is_pending() {
+            return Err(Error::TimerBusy);
+        }
+        let bit_config = BitConfig::from_bits(can.bcr.read().bits());
+        if bit_config.CCLKS() {
+            return Err(Error::InvalidConfig);
+        }
+        // PCLKB cycles per count, CTLR.TSPS counts every 1, 2, 4 or 8 bits
+        let tq_per_bit = 3 + bit_config.TSEG1() as u64 + bit_config.TSEG2() as u64;
+        let bits_per_count = 1 << can.ctlr.read().tsps().bits();
+        let cycles_per_count = (bit_config.BRP() as u64 + 1) * tq_per_bit * bits_per_count;
+
+        let held = I::held();
+        let mailbox =
+            free_mailbox(can, held.load(Ordering::Relaxed)).ok_or(Error::TxMailboxFull)?;
+        write_mailbox(can, mailbox, &frame);
+        held.fetch_or(1 << mailbox, Ordering::Relaxed);
+        let pending = Pending {
+            mailbox,
+            peripheral: I::peripheral,
+            held,
+        };
+
+        let clocks = Clocks::read();
+        let ahead = timestamp.wrapping_sub(self.timestamp());
+        if ahead == 0 || ahead > MAX_AHEAD {
+            pending.release(false);
+            return Err(Error::Late);
+        }
+        let ticks = ahead as u64 * cycles_per_count * clocks.pclkd() as u64 / clocks.pclkb() as u64;
+        critical_section::with(|cs| PENDING.borrow(cs).set(Some(pending)));
+        timer.arm(ticks).inspect_err(|_| {
+            critical_section::with(|cs| PENDING.borrow(cs).take());
+            pending.release(false);
+        })
+    }
+}
@@ -0,0 +1,524 @@
+//! Standalone CAN logging to the data flash or an SD card.
+//!
+//! Received frames are stored with a millisecond timestamp as fixed size
+//! records in a ring of segments, each erased as a unit. When the newest
+//! segment is full the oldest is erased and reused, so the log always holds
+//! the most recent frames. Each segment starts with a sequence number, so
+//! the order survives a reset and [`CanLog::open`] carries on where the log
+//! left off.
+//!
+//! ```ignore
+//! let storage = canlog::DataFlash::new(flash, 0, 6)?;
+//! let mut log = CanLog::open(storage)?;
+//! loop {
+//!     while let Some(frame) = can.try_receive_frame() {
+//!         log.record(&frame, ticker::millis())?;
+//!     }
+//!     log.poll_command(&mut uart)?;
+//! }
+//! ```
+//!
+//! [`CanLog::poll_command`] answers single character commands on a UART:
+//! `d` dumps the log in the candump log format, oldest first, for
+//! `canplayer` or `log2asc`, `c` clears it and `s` reports the number of
+//! frames held.
+//!
+//! ```text
+//! (12.345000) can0 123#0102
+//! (12.346000) can0 18DAF110#R
+//! ```
+
+use embedded_can::{Frame, Id};
+use embedded_io::{Read, ReadReady, Write};
+
+use crate::flash::{self, DATA_BLOCK_SIZE, DATA_FLASH_SIZE, DATA_FLASH_START, Flash};
+
+/// Bytes per frame in the storage.
+pub const RECORD_SIZE: u32 = 20;
+
+// Segment header, magic then the sequence number
+const HEADER_SIZE: u32 = 8;
+const SEGMENT_MAGIC: u32 = 0x474C_4E43;
+// First byte of a record, erased storage reads 0xFF
+const RECORD_MAGIC: u8 = 0xC5;
+// Record flags
+const FLAG_EXTENDED: u8 = 1 << 0;
+const FLAG_REMOTE: u8 = 1 << 1;
+
+/// CAN log error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// Reading, writing or erasing the data flash failed
+    Flash(flash::Error),
+    /// Reading or writing the SD card failed
+    #[cfg(feature = "sdcard")]
+    SdCard(crate::sdcard::Error),
+    /// The storage has fewer than 2 segments or they can't hold a record
+    Geometry,
+    /// Writing to the UART failed
+    Output,
+}
+
+impl From<flash::Error> for Error {
+    fn from(e: flash::Error) -> Self {
+        Error::Flash(e)
+    }
+}
+
+/// Memory the log is kept in, a ring of equal segments.
+pub trait Storage {
+    /// Bytes in a segment, erased as a unit
+    fn segment_size(&self) -> u32;
+    /// Number of segments
+    fn segments(&self) -> u32;
+    /// Set every byte of `segment` to 0xFF
+    fn erase(&mut self, segment: u32) -> Result<(), Error>;
+    /// Write `data` at `offset` in `segment`, only to erased bytes
+    fn write(&mut self, segment: u32, offset: u32, data: &[u8]) -> Result<(), Error>;
+    /// Read `buf.len()` bytes from `offset` in `segment`
+    fn read(&mut self, segment: u32, offset: u32, buf: &mut [u8]) -> Result<(), Error>;
+    /// Make everything written so far permanent
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Blocks of the data flash, a segment each.
+pub struct DataFlash {
+    flash: Flash,
+    start: u32,
+    blocks: u32,
+}
+
+impl DataFlash {
+    /// Use `blocks` data flash blocks from block `first`, leaving the rest
+    /// e.g. for [`shutdown`](crate::shutdown) records.
+    pub fn new(flash: Flash, first: u32, blocks: u32) -> Result<Self, Error> {
+        if (first + blocks) * DATA_BLOCK_SIZE > DATA_FLASH_SIZE {
+            return Err(Error::Flash(flash::Error::Range));
+        }
+        Ok(Self {
+            flash,
+            start: DATA_FLASH_START + first * DATA_BLOCK_SIZE,
+            blocks,
+        })
+    }
+
+    /// Release the flash.
+    pub fn free(self) -> Flash {
+        self.flash
+    }
+
+    fn address(&self, segment: u32, offset: u32) -> u32 {
+        self.start + segment * DATA_BLOCK_SIZE + offset
+    }
+}
+
+impl Storage for DataFlash {
+    fn segment_size(&self) -> u32 {
+        DATA_BLOCK_SIZE
+    }
+
+    fn segments(&self) -> u32 {
+        self.blocks
+    }
+
+    fn erase(&mut self, segment: u32) -> Result<(), Error> {
+        let address = self.address(segment, 0);
+        Ok(self.flash.erase_data(address, DATA_BLOCK_SIZE)?)
+    }
+
+    fn write(&mut self, segment: u32, offset: u32, data: &[u8]) -> Result<(), Error> {
+        let address = self.address(segment, offset);
+        Ok(self.flash.program_data(address, data)?)
+    }
+
+    fn read(&mut self, segment: u32, offset: u32, buf: &mut [u8]) -> Result<(), Error> {
+        let address = self.address(segment, offset);
+        for (n, byte) in buf.iter_mut().enumerate() {
+            *byte = unsafe { ((address as usize + n) as *const u8).read_volatile() };
+        }
+        Ok(())
+    }
+}
+
+/// A frame read back from the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Entry {
+    /// Time given to [`CanLog::record`], in milliseconds
+    pub time_ms: u32,
+    /// Raw ID, 11 or 29 bits
+    pub id: u32,
+    pub extended: bool,
+    pub remote: bool,
+    pub dlc: u8,
+    pub data: [u8; 8],
+}
+
+impl Entry {
+    fn from_frame(frame: &impl Frame, time_ms: u32) -> Self {
+        let (id, extended) = match frame.id() {
+            Id::Standard(id) => (id.as_raw() as u32, false),
+            Id::Extended(id) => (id.as_raw(), true),
+        };
+        let mut data = [0; 8];
+        data[..frame.data().len()].copy_from_slice(frame.data());
+        Entry {
+            time_ms,
+            id,
+            extended,
+            remote: frame.is_remote_frame(),
+            dlc: frame.dlc() as u8,
+            data,
+        }
+    }
+
+    fn to_bytes(self) -> [u8; RECORD_SIZE as usize] {
+        let mut bytes = [0xFF; RECORD_SIZE as usize];
+        bytes[0] = RECORD_MAGIC;
+        bytes[1] = if self.extended { FLAG_EXTENDED } else { 0 }
+            | if self.remote { FLAG_REMOTE } else { 0 };
+        bytes[2] = self.dlc;
+        bytes[4..8].copy_from_slice(&self.time_ms.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.id.to_le_bytes());
+        bytes[12..20].copy_from_slice(&self.data);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; RECORD_SIZE as usize]) -> Option<Self> {
+        if bytes[0] != RECORD_MAGIC {
+            return None;
+        }
+        let mut data = [0; 8];
+        data.copy_from_slice(&bytes[12..20]);
+        Some(Entry {
+            time_ms: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            id: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            extended: bytes[1] & FLAG_EXTENDED != 0,
+            remote: bytes[1] & FLAG_REMOTE != 0,
+            dlc: bytes[2].min(8),
+            data,
+        })
+    }
+}
+
+/// candump log format frame, `123#0102` or `18DAF110#R`
+impl core::fmt::Display for Entry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.extended {
+            write!(f, "{:08X}#", self.id)?;
+        } else {
+            write!(f, "{:03X}#", self.id)?;
+        }
+        if self.remote {
+            return write!(f, "R");
+        }
+        for b in &self.data[..self.dlc as usize] {
+            write!(f, "{:02X}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// A log of CAN frames in storage `S`.
+pub struct CanLog<S: Storage> {
+    storage: S,
+    // Segment being written, its sequence number and next record
+    segment: u32,
+    sequence: u32,
+    slot: u32,
+}
+
+impl<S: Storage> CanLog<S> {
+    /// Continue the log in `storage`, or start one if it holds none.
+    pub fn open(mut storage: S) -> Result<Self, Error> {
+        if storage.segments() < 2 || storage.segment_size() < HEADER_SIZE + RECORD_SIZE {
+            return Err(Error::Geometry);
+        }
+        let mut newest = None;
+        for segment in 0..storage.segments() {
+            if let Some(sequence) = read_sequence(&mut storage, segment)? {
+                if newest.is_none_or(|(_, newest)| sequence > newest) {
+                    newest = Some((segment, sequence));
+                }
+            }
+        }
+
+        let mut log = Self {
+            storage,
+            segment: 0,
+            sequence: 0,
+            slot: 0,
+        };
+        match newest {
+            Some((segment, sequence)) => {
+                log.segment = segment;
+                log.sequence = sequence;
+                // First erased record
+                while log.slot < log.slots() && log.read_entry(segment, log.slot)?.is_some() {
+                    log.slot += 1;
+                }
+            }
+            None => log.start_segment(0, 0)?,
+        }
+        Ok(log)
+    }
+
+    /// Append `frame`, received at `time_ms`, e.g. [`ticker::millis`](crate::ticker::millis).
+    ///
+    /// Erases the oldest segment when the newest is full, which takes about
+    /// 20 ms for a data flash block.
+    pub fn record(&mut self, frame: &impl Frame, time_ms: u32) -> Result<(), Error> {
+        if self.slot >= self.slots() {
+            let next = (self.segment + 1) % self.storage.segments();
+            self.start_segment(next, self.sequence.wrapping_add(1))?;
+        }
+        let bytes = Entry::from_frame(frame, time_ms).to_bytes();
+        let offset = HEADER_SIZE + self.slot * RECORD_SIZE;
+        self.storage.write(self.segment, offset, &bytes)?;
+        self.slot += 1;
+        Ok(())
+    }
+
+    /// Make the frames recorded so far permanent, for storage that caches.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.storage.flush()
+    }
+
+    /// Number of frames held.
+    pub fn len(&mut self) -> Result<u32, Error> {
+        let mut count = 0;
+        self.for_each(|_| {
+            count += 1;
+            Ok(())
+        })?;
+        Ok(count)
+    }
+
+    /// Whether no frames are held.
+    pub fn is_empty(&mut self) -> Result<bool, Error> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Call `f` with each frame held, oldest first.
+    pub fn for_each(&mut self, mut f: impl FnMut(Entry) -> Result<(), Error>) -> Result<(), Error> {
+        self.storage.flush()?;
+        let segments = self.storage.segments();
+        // The oldest is after the newest, where it's still in use
+        for n in 1..=segments {
+            let segment = (self.segment + n) % segments;
+            if read_sequence(&mut self.storage, segment)?.is_none() {
+                continue;
+            }
+            for slot in 0..self.slots() {
+                match self.read_entry(segment, slot)? {
+                    Some(entry) => f(entry)?,
+                    None => break,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write every frame held to `out` in the candump log format, with
+    /// `interface` as the interface name. Returns the number written.
+    pub fn dump<W: Write>(&mut self, out: &mut W, interface: &str) -> Result<u32, Error> {
+        let mut count = 0;
+        self.for_each(|entry| {
+            let (seconds, ms) = (entry.time_ms / 1000, entry.time_ms % 1000);
+            out.write_fmt(format_args!(
+                "({}.{:03}000) {} {}\n",
+                seconds, ms, interface, entry
+            ))
+            .map_err(|_| Error::Output)?;
+            count += 1;
+            Ok(())
+        })?;
+        out.flush().map_err(|_| Error::Output)?;
+        Ok(count)
+    }
+
+    /// Erase every segment and start the log again.
+    pub fn clear(&mut self) -> Result<(), Error> {
+        for segment in 1..self.storage.segments() {
+            self.storage.erase(segment)?;
+        }
+        self.start_segment(0, 0)
+    }
+
+    /// Answer a command waiting on `port`, if there is one: `d` to dump
+    /// the log, `c` to clear it or `s` for the number of frames. Other
+    /// characters, e.g. line endings, are ignored.
+    pub fn poll_command<P: Read + ReadReady + Write>(&mut self, port: &mut P) -> Result<(), Error> {
+        if !port.read_ready().map_err(|_| Error::Output)? {
+            return Ok(());
+        }
+        let mut command = [0u8; 1];
+        if port.read(&mut command).map_err(|_| Error::Output)? == 0 {
+            return Ok(());
+        }
+        let reply = match command[0] {
+            b'd' => {
+                let count = self.dump(port, "can0")?;
+                port.write_fmt(format_args!("# {} frames\n", count))
+            }
+            b'c' => {
+                self.clear()?;
+                port.write_fmt(format_args!("# cleared\n"))
+            }
+            b's' => {
+                let count = self.len()?;
+                port.write_fmt(format_args!("# {} frames\n", count))
+            }
+            _ => return Ok(()),
+        };
+        reply.map_err(|_| Error::Output)?;
+        port.flush().map_err(|_| Error::Output)
+    }
+
+    /// Release the storage, flushing it first.
+    pub fn free(mut self) -> Result<S, Error> {
+        self.storage.flush()?;
+        Ok(self.storage)
+    }
+
+    // Records that fit in a segment after the header
+    fn slots(&self) -> u32 {
+        (self.storage.segment_size() - HEADER_SIZE) / RECORD_SIZE
+    }
+
+    fn start_segment(&mut self, segment: u32, sequence: u32) -> Result<(), Error> {
+        self.storage.erase(segment)?;
+        let mut header = [0; HEADER_SIZE as usize];
+        header[..4].copy_from_slice(&SEGMENT_MAGIC.to_le_bytes());
+        header[4..].copy_from_slice(&sequence.to_le_bytes());
+        self.storage.write(segment, 0, &header)?;
+        self.segment = segment;
+        self.sequence = sequence;
+        self.slot = 0;
+        Ok(())
+    }
+
+    fn read_entry(&mut self, segment: u32, slot: u32) -> Result<Option<Entry>, Error> {
+        let mut bytes = [0; RECORD_SIZE as usize];
+        let offset = HEADER_SIZE + slot * RECORD_SIZE;
+        self.storage.read(segment, offset, &mut bytes)?;
+        Ok(Entry::from_bytes(&bytes))
+    }
+}
+
+// Sequence number of a segment, None if it's not part of a log
+fn read_sequence<S: Storage>(storage: &mut S, segment: u32) -> Result<Option<u32>, Error> {
+    let mut header = [0; HEADER_SIZE as usize];
+    storage.read(segment, 0, &mut header)?;
+    let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let sequence = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    Ok((magic == SEGMENT_MAGIC).then_some(sequence))
+}
+
+#[cfg(feature = "sdcard")]
+pub use sd::SdBlocks;
+
+#[cfg(feature = "sdcard")]
+mod sd {
+    use embedded_sdmmc::{Block, BlockDevice, BlockIdx};
+
+    use super::{Error, Storage};
+    use crate::gpio::Pin;
+    use crate::sdcard::SdCard;
+    use crate::spi::Instance;
+
+    const BLOCK_SIZE: u32 = 512;
+
+    /// A range of raw SD card blocks, a segment each, e.g. a partition
+    /// kept for the log. The block being written is cached in RAM until
+    /// the next block is started or the log is flushed.
+    pub struct SdBlocks<T: Instance, CS: Pin> {
+        card: SdCard<T, CS>,
+        start: u32,
+        blocks: u32,
+        cache: Block,
+        // Block in the cache and whether it has unwritten changes
+        cached: Option<u32>,
+        dirty: bool,
+    }
+
+    impl<T: Instance, CS: Pin> SdBlocks<T, CS> {
+        /// Use `blocks` blocks of `card` from block `start`.
+        pub fn new(card: SdCard<T, CS>, start: u32, blocks: u32) -> Self {
+            Self {
+                card,
+                start,
+                blocks,
+                cache: Block::new(),
+                cached: None,
+                dirty: false,
+            }
+        }
+
+        /// Release the card, without flushing.
+        pub fn free(self) -> SdCard<T, CS> {
+            self.card
+        }
+
+        // Load `segment` into the cache, writing back the block there
+        fn load(&mut self, segment: u32) -> Result<(), Error> {
+            if self.cached == Some(segment) {
+                return Ok(());
+            }
+            self.flush()?;
+            let index = BlockIdx(self.start + segment);
+            self.card
+                .read(core::slice::from_mut(&mut self.cache), index)
+                .map_err(Error::SdCard)?;
+            self.cached = Some(segment);
+            Ok(())
+        }
+    }
+
+    impl<T: Instance, CS: Pin> Storage for SdBlocks<T, CS> {
+        fn segment_size(&self) -> u32 {
+            BLOCK_SIZE
+        }
+
+        fn segments(&self) -> u32 {
+            self.blocks
+        }
+
+        fn erase(&mut self, segment: u32) -> Result<(), Error> {
+            self.flush()?;
+            self.cache.contents.fill(0xFF);
+            self.cached = Some(segment);
+            self.dirty = true;
+            Ok(())
+        }
+
+        fn write(&mut self, segment: u32, offset: u32, data: &[u8]) -> Result<(), Error> {
+            self.load(segment)?;
+            let offset = offset as usize;
+            self.cache.contents[offset..offset + data.len()].copy_from_slice(data);
+            self.dirty = true;
+            Ok(())
+        }
+
+        fn read(&mut self, segment: u32, offset: u32, buf: &mut [u8]) -> Result<(), Error> {
+            self.load(segment)?;
+            let offset = offset as usize;
+            buf.copy_from_slice(&self.cache.contents[offset..offset + buf.len()]);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            if let (Some(segment), true) = (self.cached, self.dirty) {
+                let index = BlockIdx(self.start + segment);
+                self.card
+                    .write(core::slice::from_ref(&self.cache), index)
+                    .map_err(Error::SdCard)?;
+                self.dirty = false;
+            }
+            Ok(())
+        }
+    }
+}
@@ -1,3 +1,8 @@
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use critical_section::Mutex;
+
 /// Clock config
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Config {
@@ -11,6 +16,7 @@ pub struct Config {
     pub pckd: u8,  // PCKD frequency
     pub cksel: u8, // Clock select
     pub hoco: Hoco,
+    pub pll: Pll,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -19,8 +25,57 @@ pub struct Hoco {
     pub hcfrq: u8,
 }
 
+/// PLL settings, the output is the main oscillator times `pllmul + 1`
+/// divided by `2^plodiv`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pll {
+    pub plstp: bool,
+    pub pllmul: u8,
+    pub plodiv: u8,
+}
+
+/// Clock errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The oscillator is the system clock so can't be stopped or changed
+    InUse,
+    /// The main oscillator is needed but its frequency isn't set, see
+    /// [`set_main_osc_hz`]
+    NoMainOsc,
+    /// A clock would be above its maximum, or the PLL outside its range
+    OutOfRange,
+    /// The sub-clock isn't running, see [`start_sub_clock`]
+    NotRunning,
+    /// An oscillator didn't stabilize within [`OSC_TIMEOUT_CYCLES`]
+    Timeout,
+}
+
+impl From<InUse> for Error {
+    fn from(_: InUse) -> Self {
+        Error::InUse
+    }
+}
+
+/// CPU cycles to wait for the main oscillator, PLL or HOCO to stabilize,
+/// 100 ms with a 48 MHz ICLK.
+pub const OSC_TIMEOUT_CYCLES: u32 = 4_800_000;
+
+// Maximum frequencies in high-speed mode
+const MAX_ICLK: u32 = 48_000_000;
+const MAX_FCLK: u32 = 32_000_000;
+const MAX_PCLKA: u32 = 48_000_000;
+const MAX_PCLKB: u32 = 32_000_000;
+const MAX_PCLKC: u32 = 64_000_000;
+const MAX_PCLKD: u32 = 64_000_000;
+// ICLK above this needs a memory wait cycle
+const MEMWAIT_ICLK: u32 = 32_000_000;
+// PLL input and output ranges
+const PLL_IN: core::ops::RangeInclusive<u32> = 4_000_000..=12_500_000;
+const PLL_OUT: core::ops::RangeInclusive<u32> = 24_000_000..=64_000_000;
+
 impl Config {
-    /// Create a new clock config
+    /// Read the current clock config.
     pub fn from_system(sys: &ra4m1::SYSTEM) -> Self {
         let divcr = sys.sckdivcr.read();
         let iclk = divcr.ick().bits();
@@ -33,9 +88,9 @@ impl Config {
         let cksel = sys.sckscr.read().cksel().bits();
         let hoco = sys.hococr.read();
         let hcstp = hoco.hcstp().bit_is_set();
-        let hcfrq = (unsafe { core::ptr::read_volatile(0x4001_E037 as *const u8) } >> 3);
+        let hcfrq = unsafe { HOCOCR2.read_volatile() } >> 3;
+        let pllccr2 = sys.pllccr2.read().bits();
         Config {
-            // Set default values or read from system registers if needed
             iclk,
             fck,
             pcka,
@@ -44,6 +99,699 @@ impl Config {
             pckd,
             cksel,
             hoco: Hoco { hcstp, hcfrq },
+            pll: Pll {
+                plstp: sys.pllcr.read().bits() & 1 != 0,
+                pllmul: pllccr2 & 0x1F,
+                plodiv: pllccr2 >> 6,
+            },
+        }
+    }
+
+    /// Fastest clocks from the HOCO, 48 MHz ICLK on the UNO R4.
+    ///
+    /// FCLK and PCLKB are divided to stay within 32 MHz.
+    pub fn max_performance() -> Self {
+        Self::with_dividers(CKSEL_HOCO, read_hoco(), Pll::stopped())
+    }
+
+    /// Everything at 8 MHz from the MOCO, for low current.
+    pub fn low_power_8mhz() -> Self {
+        Self::with_dividers(CKSEL_MOCO, read_hoco(), Pll::stopped())
+    }
+
+    /// Clocks from the PLL, the main oscillator times `mul` (8 - 32)
+    /// divided by `div` (1, 2 or 4).
+    ///
+    /// Set the main oscillator frequency with [`set_main_osc_hz`] first.
+    pub fn main_pll(mul: u8, div: u8) -> Self {
+        let pll = Pll {
+            plstp: false,
+            pllmul: mul.saturating_sub(1),
+            plodiv: div.trailing_zeros() as u8,
+        };
+        Self::with_dividers(CKSEL_PLL, read_hoco(), pll)
+    }
+
+    // Slowest divider for each clock that keeps it within its maximum
+    fn with_dividers(cksel: u8, hoco: Hoco, pll: Pll) -> Self {
+        let mut config = Config {
+            iclk: 0,
+            fck: 0,
+            pcka: 0,
+            pckb: 0,
+            pckc: 0,
+            pckd: 0,
+            cksel,
+            hoco,
+            pll,
+        };
+        let source = source_hz(&config);
+        let div = |max: u32| (0..6).find(|code| source >> code <= max).unwrap_or(6);
+        config.iclk = div(MAX_ICLK);
+        config.fck = div(MAX_FCLK);
+        config.pcka = div(MAX_PCLKA);
+        config.pckb = div(MAX_PCLKB);
+        config.pckc = div(MAX_PCLKC);
+        config.pckd = div(MAX_PCLKD);
+        config
+    }
+
+    /// Switch the system clock to this config.
+    ///
+    /// Starts the oscillators it needs and sets the memory wait cycle for
+    /// the new ICLK, stepping so no clock is ever above both its old and new
+    /// frequency. The old oscillator is left running. Drivers read
+    /// [`Clocks`] when setting rates, so set UART and CAN rates again after.
+    /// [`cycles::start`](crate::cycles::start) needs the new ICLK too.
+    ///
+    /// The HOCO frequency is set by the option bytes so isn't changed.
+    pub fn apply(&self) -> Result<(), Error> {
+        let target = Clocks::from_config(self);
+        if target.source == 0 {
+            return Err(Error::NoMainOsc);
+        }
+        if target.iclk > MAX_ICLK
+            || target.fclk > MAX_FCLK
+            || target.pclka > MAX_PCLKA
+            || target.pclkb > MAX_PCLKB
+            || target.pclkc > MAX_PCLKC
+            || target.pclkd > MAX_PCLKD
+        {
+            return Err(Error::OutOfRange);
+        }
+        let current = Clocks::read();
+
+        match self.cksel {
+            CKSEL_HOCO => start_hoco()?,
+            CKSEL_MOCO => {
+                with_clock_protection_off(|sys| sys.mococr.write(|w| unsafe { w.bits(0) }))
+            }
+            CKSEL_LOCO => start_loco(),
+            CKSEL_MOSC => start_main_osc()?,
+            CKSEL_SOSC if !is_sub_clock_running() => return Err(Error::NotRunning),
+            CKSEL_PLL => {
+                let main = MAIN_OSC_HZ.load(Ordering::Relaxed);
+                if !PLL_IN.contains(&main)
+                    || !PLL_OUT.contains(&target.source)
+                    || self.pll.pllmul < 7
+                    || self.pll.plodiv > 2
+                {
+                    return Err(Error::OutOfRange);
+                }
+                start_main_osc()?;
+                start_pll(self.pll)?;
+            }
+            _ => {}
+        }
+
+        let divcr = ((self.fck as u32 & 0b111) << 28)
+            | ((self.iclk as u32 & 0b111) << 24)
+            | ((self.pcka as u32 & 0b111) << 12)
+            | ((self.pckb as u32 & 0b111) << 8)
+            | ((self.pckc as u32 & 0b111) << 4)
+            | (self.pckd as u32 & 0b111);
+        with_clock_protection_off(|sys| unsafe {
+            if target.iclk > MEMWAIT_ICLK {
+                sys.memwaitcr.write(|w| w.bits(1));
+            }
+            // Faster source, divide first. Slower source, switch first.
+            if target.source > current.source {
+                sys.sckdivcr.write(|w| w.bits(divcr));
+                sys.sckscr.write(|w| w.bits(self.cksel));
+            } else {
+                sys.sckscr.write(|w| w.bits(self.cksel));
+                sys.sckdivcr.write(|w| w.bits(divcr));
+            }
+            if target.iclk <= MEMWAIT_ICLK {
+                sys.memwaitcr.write(|w| w.bits(0));
+            }
+        });
+        Ok(())
+    }
+}
+
+impl Pll {
+    fn stopped() -> Self {
+        let mut pll = read_pll();
+        pll.plstp = true;
+        pll
+    }
+}
+
+fn read_hoco() -> Hoco {
+    let p = unsafe { ra4m1::Peripherals::steal() };
+    Config::from_system(&p.SYSTEM).hoco
+}
+
+fn read_pll() -> Pll {
+    let p = unsafe { ra4m1::Peripherals::steal() };
+    Config::from_system(&p.SYSTEM).pll
+}
+
+// Poll OSCSF until `flag` is set
+fn wait_stable(flag: u8) -> Result<(), Error> {
+    const POLL_CYCLES: u32 = 100;
+    let mut waited = 0;
+    while system().oscsf.read().bits() & flag == 0 {
+        if waited >= OSC_TIMEOUT_CYCLES {
+            return Err(Error::Timeout);
         }
+        cortex_m::asm::delay(POLL_CYCLES);
+        waited += POLL_CYCLES;
     }
+    Ok(())
+}
+
+fn start_hoco() -> Result<(), Error> {
+    with_clock_protection_off(|sys| sys.hococr.write(|w| unsafe { w.bits(0) }));
+    wait_stable(OSCSF_HOCOSF)
+}
+
+// Start the main oscillator as a resonator, MOMCR only written while stopped
+fn start_main_osc() -> Result<(), Error> {
+    if system().mosccr.read().bits() & 1 == 0 {
+        return wait_stable(OSCSF_MOSCSF);
+    }
+    let main = MAIN_OSC_HZ.load(Ordering::Relaxed);
+    if main == 0 {
+        return Err(Error::NoMainOsc);
+    }
+    // MODRV1 set for 1 - 10 MHz
+    let momcr = if main <= 10_000_000 { MOMCR_MODRV1 } else { 0 };
+    with_clock_protection_off(|sys| unsafe {
+        sys.momcr.write(|w| w.bits(momcr));
+        sys.mosccr.write(|w| w.bits(0));
+    });
+    wait_stable(OSCSF_MOSCSF)
+}
+
+// Start the PLL, PLLCCR2 can only be written while it's stopped
+fn start_pll(pll: Pll) -> Result<(), Error> {
+    let current = read_pll();
+    if !current.plstp {
+        if current.pllmul == pll.pllmul && current.plodiv == pll.plodiv {
+            return wait_stable(OSCSF_PLLSF);
+        }
+        if system_clock_source() == CKSEL_PLL {
+            return Err(Error::InUse);
+        }
+    }
+    with_clock_protection_off(|sys| unsafe {
+        sys.pllcr.write(|w| w.bits(1));
+        sys.pllccr2
+            .write(|w| w.bits((pll.plodiv << 6) | (pll.pllmul & 0x1F)));
+        sys.pllcr.write(|w| w.bits(0));
+    });
+    wait_stable(OSCSF_PLLSF)
+}
+
+// Frequency of the main oscillator, 0 until set as the UNO R4 has no crystal
+static MAIN_OSC_HZ: AtomicU32 = AtomicU32::new(0);
+
+/// Set the frequency of a crystal or external clock on EXTAL, used by
+/// [`Clocks`] when the main oscillator or the PLL is the system clock.
+pub fn set_main_osc_hz(hz: u32) {
+    MAIN_OSC_HZ.store(hz, Ordering::Relaxed);
+}
+
+/// Clock frequencies in Hz, from the clock registers.
+///
+/// Drivers read these when calculating rates, e.g. the UART baud rate and
+/// [`BitConfig::from_bitrate`](crate::can::BitConfig::from_bitrate), so they
+/// follow a change to the system clock. Read again after changing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Clocks {
+    source: u32,
+    iclk: u32,
+    fclk: u32,
+    pclka: u32,
+    pclkb: u32,
+    pclkc: u32,
+    pclkd: u32,
+}
+
+impl Clocks {
+    /// Read the current frequencies.
+    pub fn read() -> Self {
+        let p = unsafe { ra4m1::Peripherals::steal() };
+        Self::from_config(&Config::from_system(&p.SYSTEM))
+    }
+
+    /// Frequencies for a clock config.
+    pub fn from_config(config: &Config) -> Self {
+        let source = source_hz(config);
+        // Dividers are powers of two, 0 is /1 up to 6 for /64
+        let div = |code: u8| source >> code.min(6);
+        Self {
+            source,
+            iclk: div(config.iclk),
+            fclk: div(config.fck),
+            pclka: div(config.pcka),
+            pclkb: div(config.pckb),
+            pclkc: div(config.pckc),
+            pclkd: div(config.pckd),
+        }
+    }
+
+    /// The selected system clock source, before the dividers.
+    pub fn source(&self) -> u32 {
+        self.source
+    }
+
+    /// CPU, DMAC and SRAM clock.
+    pub fn iclk(&self) -> u32 {
+        self.iclk
+    }
+
+    /// Flash interface clock.
+    pub fn fclk(&self) -> u32 {
+        self.fclk
+    }
+
+    /// Peripheral clock A, USB and SPI.
+    pub fn pclka(&self) -> u32 {
+        self.pclka
+    }
+
+    /// Peripheral clock B, SCI, IIC, CAN and most other peripherals.
+    pub fn pclkb(&self) -> u32 {
+        self.pclkb
+    }
+
+    /// Peripheral clock C, the ADC conversion clock.
+    pub fn pclkc(&self) -> u32 {
+        self.pclkc
+    }
+
+    /// Peripheral clock D, GPT count clock and ADC.
+    pub fn pclkd(&self) -> u32 {
+        self.pclkd
+    }
+}
+
+// Frequency of the clock selected by SCKSCR.CKSEL
+fn source_hz(config: &Config) -> u32 {
+    match config.cksel {
+        CKSEL_HOCO => match config.hoco.hcfrq {
+            0 => 24_000_000,
+            2 => 32_000_000,
+            5 => 64_000_000,
+            _ => 48_000_000,
+        },
+        CKSEL_MOCO => 8_000_000,
+        CKSEL_LOCO | CKSEL_SOSC => 32_768,
+        CKSEL_MOSC => MAIN_OSC_HZ.load(Ordering::Relaxed),
+        _ => {
+            let pll = config.pll;
+            (MAIN_OSC_HZ.load(Ordering::Relaxed) * (pll.pllmul as u32 + 1)) >> pll.plodiv.min(2)
+        }
+    }
+}
+
+// HOCOCR2, HCFRQ in bits 3 - 5, not in the PAC
+const HOCOCR2: *const u8 = 0x4001_E037 as *const u8;
+const PRCR_KEY: u16 = 0xA5 << 8;
+const PRCR_PRC0: u16 = 1 << 0;
+const OSCSF_HOCOSF: u8 = 1 << 0;
+const OSCSF_MOSCSF: u8 = 1 << 3;
+const OSCSF_PLLSF: u8 = 1 << 5;
+const MOMCR_MODRV1: u8 = 1 << 3;
+// SCKSCR.CKSEL values
+const CKSEL_HOCO: u8 = 0;
+const CKSEL_MOCO: u8 = 1;
+const CKSEL_LOCO: u8 = 2;
+const CKSEL_MOSC: u8 = 3;
+const CKSEL_SOSC: u8 = 4;
+const CKSEL_PLL: u8 = 5;
+
+// RTC.RCR4, RCKSEL selects LOCO when set
+const RCR4: *mut u8 = 0x4004_4028 as *mut u8;
+// AGTMR1 of AGT0 and AGT1, TCK in bits 4 - 6
+const AGTMR1: [*mut u8; 2] = [0x4008_4009 as *mut u8, 0x4008_4109 as *mut u8];
+const TCK_AGTLCLK: u8 = 0b100;
+const TCK_AGTSCLK: u8 = 0b110;
+
+/// LOCO oscillation stabilization time, tLOCOWT.
+pub const LOCO_STABILIZATION_US: u32 = 61;
+
+/// Error from stopping an oscillator that is the system clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct InUse;
+
+/// Sub-clock oscillator drive capability, SOMCR.SODRV.
+///
+/// Lower drive uses less current but needs a crystal with a lower load
+/// capacitance, see the crystal's data sheet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SubClockDrive {
+    Normal = 0b00,
+    LowPower1 = 0b01,
+    LowPower2 = 0b10,
+    LowPower3 = 0b11,
+}
+
+/// 32.768 kHz clock for the RTC and AGT.
+///
+/// The IWDT isn't selectable, it always counts its own 15 kHz IWDTCLK.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LowSpeedClock {
+    /// Sub-clock oscillator, a crystal on XCIN / XCOUT
+    SubClock,
+    /// Low-speed on-chip oscillator, less accurate but always fitted
+    Loco,
+}
+
+/// Asynchronous general purpose timer channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Agt {
+    Agt0,
+    Agt1,
+}
+
+fn system() -> &'static ra4m1::system::RegisterBlock {
+    unsafe { &*ra4m1::SYSTEM::ptr() }
+}
+
+// Write clock generation registers with PRC0 set
+fn with_clock_protection_off(f: impl FnOnce(&ra4m1::system::RegisterBlock)) {
+    let sys = system();
+    critical_section::with(|_| {
+        sys.prcr.write(|w| unsafe { w.bits(PRCR_KEY | PRCR_PRC0) });
+        f(sys);
+        sys.prcr.write(|w| unsafe { w.bits(PRCR_KEY) });
+    });
+}
+
+fn system_clock_source() -> u8 {
+    system().sckscr.read().cksel().bits()
+}
+
+/// Start the sub-clock oscillator, waiting `stabilization_ms` for the
+/// crystal to settle.
+///
+/// The RA4M1 has no stabilization flag for the sub-clock, so the wait is
+/// from the crystal's data sheet, usually hundreds of ms up to 2 s. `drive`
+/// is only changed if the oscillator is stopped. Uses [`cycles`] for the
+/// wait.
+///
+/// [`cycles`]: crate::cycles
+pub fn start_sub_clock(drive: SubClockDrive, stabilization_ms: u32) {
+    if is_sub_clock_running() {
+        return;
+    }
+    with_clock_protection_off(|sys| unsafe {
+        // SODRV can only be written while the oscillator is stopped
+        sys.somcr.write(|w| w.bits(drive as u8));
+        sys.sosccr.write(|w| w.bits(0));
+    });
+    crate::cycles::busy_wait_ms(stabilization_ms);
+}
+
+/// Stop the sub-clock oscillator, [`InUse`] if it's the system clock.
+pub fn stop_sub_clock() -> Result<(), InUse> {
+    if system_clock_source() == CKSEL_SOSC {
+        return Err(InUse);
+    }
+    with_clock_protection_off(|sys| sys.sosccr.write(|w| unsafe { w.bits(1) }));
+    Ok(())
+}
+
+/// Whether the sub-clock oscillator is running, SOSCCR.SOSTP clear.
+pub fn is_sub_clock_running() -> bool {
+    system().sosccr.read().bits() & 1 == 0
+}
+
+/// Start the LOCO and wait [`LOCO_STABILIZATION_US`].
+pub fn start_loco() {
+    if is_loco_running() {
+        return;
+    }
+    with_clock_protection_off(|sys| sys.lococr.write(|w| unsafe { w.bits(0) }));
+    crate::cycles::busy_wait_us(LOCO_STABILIZATION_US);
+}
+
+/// Stop the LOCO, [`InUse`] if it's the system clock.
+pub fn stop_loco() -> Result<(), InUse> {
+    if system_clock_source() == CKSEL_LOCO {
+        return Err(InUse);
+    }
+    with_clock_protection_off(|sys| sys.lococr.write(|w| unsafe { w.bits(1) }));
+    Ok(())
+}
+
+/// Whether the LOCO is running, LOCOCR.LCSTP clear.
+pub fn is_loco_running() -> bool {
+    system().lococr.read().bits() & 1 == 0
+}
+
+/// Select the RTC count source.
+///
+/// Only change it with the RTC stopped, RCR2.START clear. The clock must
+/// be started first.
+pub fn set_rtc_clock(source: LowSpeedClock) {
+    let rcksel = match source {
+        LowSpeedClock::SubClock => 0,
+        LowSpeedClock::Loco => 1,
+    };
+    unsafe { RCR4.write_volatile((RCR4.read_volatile() & !1) | rcksel) };
+}
+
+/// Select the count source of an AGT channel, AGTMR1.TCK.
+///
+/// The AGT must be enabled, e.g. with [`ClockGuard`], and stopped.
+pub fn set_agt_clock(agt: Agt, source: LowSpeedClock) {
+    let tck = match source {
+        LowSpeedClock::SubClock => TCK_AGTSCLK,
+        LowSpeedClock::Loco => TCK_AGTLCLK,
+    };
+    let agtmr1 = AGTMR1[agt as usize];
+    unsafe { agtmr1.write_volatile((agtmr1.read_volatile() & !(0b111 << 4)) | (tck << 4)) };
+}
+
+/// Peripheral with a module stop bit in MSTPCRA - MSTPCRD.
+///
+/// Modules are stopped after reset, except SRAM0 and ECCSRAM, and must be
+/// enabled before their registers are accessed. Drivers do this in their
+/// constructor with a [`ClockGuard`] and stop the module again when dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Peripheral {
+    Sram0,
+    EccSram,
+    /// DMAC and DTC
+    Dmac,
+    Can0,
+    Iic1,
+    Iic0,
+    Usbfs,
+    Spi1,
+    Spi0,
+    Sci9,
+    Sci2,
+    Sci1,
+    Sci0,
+    /// Clock frequency accuracy measurement circuit
+    Cac,
+    Crc,
+    /// Capacitive touch sensing unit
+    Ctsu,
+    /// Segment LCD controller
+    Slcdc,
+    Ssie0,
+    /// Data operation circuit
+    Doc,
+    /// Event link controller
+    Elc,
+    Sce5,
+    Agt1,
+    Agt0,
+    /// GPT320 and GPT321
+    Gpt32,
+    /// GPT162 - GPT167
+    Gpt16,
+    /// Port output enable for GPT
+    Poeg,
+    Adc140,
+    Dac8,
+    Dac12,
+    Acmplp,
+    Opamp,
+}
+
+const PERIPHERALS: usize = Peripheral::Opamp as usize + 1;
+
+#[derive(Clone, Copy)]
+enum Mstpcr {
+    A,
+    B,
+    C,
+    D,
+}
+
+impl Peripheral {
+    // Register and bit of the module stop bit
+    fn stop_bit(self) -> (Mstpcr, u32) {
+        use Peripheral::*;
+        match self {
+            Sram0 => (Mstpcr::A, 0),
+            EccSram => (Mstpcr::A, 6),
+            Dmac => (Mstpcr::A, 22),
+            Can0 => (Mstpcr::B, 2),
+            Iic1 => (Mstpcr::B, 8),
+            Iic0 => (Mstpcr::B, 9),
+            Usbfs => (Mstpcr::B, 11),
+            Spi1 => (Mstpcr::B, 18),
+            Spi0 => (Mstpcr::B, 19),
+            Sci9 => (Mstpcr::B, 22),
+            Sci2 => (Mstpcr::B, 29),
+            Sci1 => (Mstpcr::B, 30),
+            Sci0 => (Mstpcr::B, 31),
+            Cac => (Mstpcr::C, 0),
+            Crc => (Mstpcr::C, 1),
+            Ctsu => (Mstpcr::C, 3),
+            Slcdc => (Mstpcr::C, 4),
+            Ssie0 => (Mstpcr::C, 8),
+            Doc => (Mstpcr::C, 13),
+            Elc => (Mstpcr::C, 14),
+            Sce5 => (Mstpcr::C, 31),
+            Agt1 => (Mstpcr::D, 2),
+            Agt0 => (Mstpcr::D, 3),
+            Gpt32 => (Mstpcr::D, 5),
+            Gpt16 => (Mstpcr::D, 6),
+            Poeg => (Mstpcr::D, 14),
+            Adc140 => (Mstpcr::D, 16),
+            Dac8 => (Mstpcr::D, 19),
+            Dac12 => (Mstpcr::D, 20),
+            Acmplp => (Mstpcr::D, 29),
+            Opamp => (Mstpcr::D, 31),
+        }
+    }
+}
+
+// Number of guards holding each module out of the stop state. SRAM0 and
+// ECCSRAM run from reset and start with a user that's never dropped.
+static USERS: Mutex<Cell<[u8; PERIPHERALS]>> = Mutex::new(Cell::new(initial_users()));
+
+const fn initial_users() -> [u8; PERIPHERALS] {
+    let mut users = [0; PERIPHERALS];
+    users[Peripheral::Sram0 as usize] = 1;
+    users[Peripheral::EccSram as usize] = 1;
+    users
+}
+
+// Set (stop) or clear (run) a module stop bit
+fn set_stop(peripheral: Peripheral, stop: bool) {
+    let p = unsafe { ra4m1::Peripherals::steal() };
+    let (register, bit) = peripheral.stop_bit();
+    let update = |bits: u32| {
+        if stop {
+            bits | (1 << bit)
+        } else {
+            bits & !(1 << bit)
+        }
+    };
+    match register {
+        Mstpcr::A => p
+            .SYSTEM
+            .mstpcra
+            .modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+        Mstpcr::B => p
+            .MSTP
+            .mstpcrb
+            .modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+        Mstpcr::C => p
+            .MSTP
+            .mstpcrc
+            .modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+        Mstpcr::D => p
+            .MSTP
+            .mstpcrd
+            .modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+    }
+}
+
+/// Keeps a module out of the stop state while it exists.
+///
+/// Guards are counted per module, so modules shared between drivers,
+/// e.g. the GPT32 channels or the DMAC, stop when the last one is dropped.
+pub struct ClockGuard {
+    peripheral: Peripheral,
+}
+
+impl ClockGuard {
+    /// Enable the module if it isn't already.
+    pub fn new(peripheral: Peripheral) -> Self {
+        critical_section::with(|cs| {
+            let users = USERS.borrow(cs);
+            let mut counts = users.get();
+            let count = &mut counts[peripheral as usize];
+            if *count == 0 {
+                set_stop(peripheral, false);
+            }
+            *count = count.saturating_add(1);
+            users.set(counts);
+        });
+        Self { peripheral }
+    }
+
+    pub fn peripheral(&self) -> Peripheral {
+        self.peripheral
+    }
+}
+
+impl Clone for ClockGuard {
+    fn clone(&self) -> Self {
+        Self::new(self.peripheral)
+    }
+}
+
+impl Drop for ClockGuard {
+    fn drop(&mut self) {
+        critical_section::with(|cs| {
+            let users = USERS.borrow(cs);
+            let mut counts = users.get();
+            let count = &mut counts[self.peripheral as usize];
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                set_stop(self.peripheral, true);
+            }
+            users.set(counts);
+        });
+    }
+}
+
+/// Enable a module for good, e.g. one without a driver in this crate.
+pub fn enable_peripheral(peripheral: Peripheral) {
+    core::mem::forget(ClockGuard::new(peripheral));
+}
+
+/// Stop a module, even if guards for it exist.
+///
+/// Their drivers must not be used until the module is enabled again.
+pub fn disable_peripheral(peripheral: Peripheral) {
+    critical_section::with(|cs| {
+        let users = USERS.borrow(cs);
+        let mut counts = users.get();
+        counts[peripheral as usize] = 0;
+        users.set(counts);
+        set_stop(peripheral, true);
+    });
+}
+
+/// Whether a module is out of the stop state.
+pub fn is_peripheral_enabled(peripheral: Peripheral) -> bool {
+    let p = unsafe { ra4m1::Peripherals::steal() };
+    let (register, bit) = peripheral.stop_bit();
+    let bits = match register {
+        Mstpcr::A => p.SYSTEM.mstpcra.read().bits(),
+        Mstpcr::B => p.MSTP.mstpcrb.read().bits(),
+        Mstpcr::C => p.MSTP.mstpcrc.read().bits(),
+        Mstpcr::D => p.MSTP.mstpcrd.read().bits(),
+    };
+    bits & (1 << bit) == 0
 }
@@ -0,0 +1,79 @@
+//! Global UART console for diagnostics.
+//!
+//! Register a [`UartTx`] once with [`init`], then use the [`console!`](crate::console!)
+//! and [`sprintln!`](crate::sprintln!) macros from anywhere, including interrupt
+//! handlers and panic paths.
+//!
+//! Writes happen inside a critical section and never wait for the transmit
+//! buffer to drain, so output that does not fit in the buffer is dropped
+//! rather than deadlocking.
+
+use core::cell::RefCell;
+use core::fmt;
+
+use critical_section::Mutex;
+use ra4m1::SCI2;
+
+use crate::uart::UartTx;
+
+static CONSOLE: Mutex<RefCell<Option<UartTx<SCI2>>>> = Mutex::new(RefCell::new(None));
+
+/// Use `tx` as the global console.
+///
+/// Replaces and returns any previously registered transmitter.
+pub fn init(tx: UartTx<SCI2>) -> Option<UartTx<SCI2>> {
+    critical_section::with(|cs| CONSOLE.borrow_ref_mut(cs).replace(tx))
+}
+
+/// Remove the global console, returning the transmitter.
+pub fn release() -> Option<UartTx<SCI2>> {
+    critical_section::with(|cs| CONSOLE.borrow_ref_mut(cs).take())
+}
+
+/// Write a string to the console, if one is registered.
+pub fn write_str(s: &str) {
+    write_fmt(format_args!("{}", s));
+}
+
+/// Write formatted output to the console, if one is registered.
+///
+/// Used by the [`console!`](crate::console!) and [`sprintln!`](crate::sprintln!) macros.
+pub fn write_fmt(args: fmt::Arguments) {
+    critical_section::with(|cs| {
+        // try_borrow_mut guards against reentrancy, e.g. a panic while formatting
+        if let Ok(mut console) = CONSOLE.borrow(cs).try_borrow_mut() {
+            if let Some(tx) = console.as_mut() {
+                let _ = fmt::Write::write_fmt(&mut Writer(tx), args);
+            }
+        }
+    });
+}
+
+/// Adapter from [`fmt::Write`] to the non-blocking UART write.
+struct Writer<'a>(&'a mut UartTx<SCI2>);
+
+impl fmt::Write for Writer<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.try_write(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Print to the global console.
+#[macro_export]
+macro_rules! console {
+    ($($arg:tt)*) => {
+        $crate::console::write_fmt(format_args!($($arg)*))
+    };
+}
+
+/// Print to the global console, with a newline.
+#[macro_export]
+macro_rules! sprintln {
+    () => {
+        $crate::console::write_str("\n")
+    };
+    ($($arg:tt)*) => {
+        $crate::console::write_fmt(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
@@ -0,0 +1,281 @@
+//! Crash reports from the HardFault handler.
+//!
+//! [`hard_fault`] captures the exception frame and the fault status
+//! registers, prints them on the [`console`](crate::console), and optionally
+//! sends them in CAN frames and stores them in a data flash block, then
+//! resets. After the reset [`last_report`] returns the stored report:
+//!
+//! ```ignore
+//! crash::init(crash::Config {
+//!     flash_block: Some(flash::DATA_FLASH_START + 7 * flash::DATA_BLOCK_SIZE),
+//!     can_id: Some(StandardId::new(0x7F0).unwrap().into()),
+//! });
+//! if let Some(report) = crash::last_report() {
+//!     sprintln!("previous run crashed:\n{}", report);
+//!     crash::clear_report(&mut flash)?;
+//! }
+//!
+//! #[exception]
+//! unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+//!     crash::hard_fault(frame)
+//! }
+//! ```
+//!
+//! ```text
+//! HardFault: stack overflow
+//!   PC 00004F2A LR 00004E11 xPSR 61000000
+//!   R0 20007F80 R1 00000000 R2 00000010 R3 00000000 R12 00000000
+//!   CFSR 00000010 HFSR 40000000 MMFAR 00000000 BFAR 00000000
+//!   cause: MSTKERR FORCED
+//! ```
+//!
+//! On CAN the [`REPORT_SIZE`] byte report is split into 8 byte frames, the
+//! last one shorter, all with the same ID. It starts with [`MAGIC`].
+
+use core::cell::Cell;
+use core::fmt;
+
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::ExceptionFrame;
+use critical_section::Mutex;
+#[cfg(feature = "can")]
+use embedded_can::Frame as _;
+use embedded_can::Id;
+#[cfg(feature = "uart")]
+use ra4m1::SCI2;
+
+#[cfg(feature = "can")]
+use crate::can::{self, Frame};
+use crate::clk::Clocks;
+use crate::flash::{self, DATA_BLOCK_SIZE, Flash};
+use crate::fwupdate::crc32;
+
+/// Size of an encoded [`Report`].
+pub const REPORT_SIZE: usize = 15 * 4;
+
+/// First word of an encoded report, "CRSH".
+pub const MAGIC: u32 = 0x4853_5243;
+
+// Report flags
+const FLAG_STACK_OVERFLOW: u32 = 1 << 0;
+
+const CFSR_FLAGS: [(u32, &str); 17] = [
+    (1 << 0, "IACCVIOL"),
+    (1 << 1, "DACCVIOL"),
+    (1 << 3, "MUNSTKERR"),
+    (1 << 4, "MSTKERR"),
+    (1 << 5, "MLSPERR"),
+    (1 << 8, "IBUSERR"),
+    (1 << 9, "PRECISERR"),
+    (1 << 10, "IMPRECISERR"),
+    (1 << 11, "UNSTKERR"),
+    (1 << 12, "STKERR"),
+    (1 << 13, "LSPERR"),
+    (1 << 16, "UNDEFINSTR"),
+    (1 << 17, "INVSTATE"),
+    (1 << 18, "INVPC"),
+    (1 << 19, "NOCP"),
+    (1 << 24, "UNALIGNED"),
+    (1 << 25, "DIVBYZERO"),
+];
+
+const HFSR_FLAGS: [(u32, &str); 2] = [(1 << 1, "VECTTBL"), (1 << 30, "FORCED")];
+
+static CONFIG: Mutex<Cell<Config>> = Mutex::new(Cell::new(Config {
+    flash_block: None,
+    can_id: None,
+}));
+
+/// Where [`hard_fault`] sends the report, besides the console.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Config {
+    /// Data flash block to store the report in, an address from
+    /// [`DATA_FLASH_START`](flash::DATA_FLASH_START) that is a multiple of
+    /// [`DATA_BLOCK_SIZE`]. The whole block is erased.
+    pub flash_block: Option<u32>,
+    /// ID of the CAN frames carrying the report, while the driver is running
+    pub can_id: Option<Id>,
+}
+
+/// State of the CPU at a fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Report {
+    pub r0: u32,
+    pub r1: u32,
+    pub r2: u32,
+    pub r3: u32,
+    pub r12: u32,
+    pub lr: u32,
+    pub pc: u32,
+    pub xpsr: u32,
+    /// Configurable Fault Status Register
+    pub cfsr: u32,
+    /// HardFault Status Register
+    pub hfsr: u32,
+    /// MemManage Fault Address Register
+    pub mmfar: u32,
+    /// BusFault Address Register
+    pub bfar: u32,
+    /// The fault was the [`stackcheck`](crate::stackcheck) guard
+    pub stack_overflow: bool,
+}
+
+impl Report {
+    /// Capture the state from the exception frame and fault registers.
+    pub fn capture(frame: &ExceptionFrame) -> Self {
+        let scb = unsafe { &*SCB::PTR };
+        Self {
+            r0: frame.r0(),
+            r1: frame.r1(),
+            r2: frame.r2(),
+            r3: frame.r3(),
+            r12: frame.r12(),
+            lr: frame.lr(),
+            pc: frame.pc(),
+            xpsr: frame.xpsr(),
+            cfsr: scb.cfsr.read(),
+            hfsr: scb.hfsr.read(),
+            mmfar: scb.mmfar.read(),
+            bfar: scb.bfar.read(),
+            stack_overflow: crate::stackcheck::is_guard_fault(),
+        }
+    }
+
+    /// Encode as little endian words, starting with [`MAGIC`] and ending
+    /// with the [`crc32`] of the rest.
+    pub fn to_bytes(&self) -> [u8; REPORT_SIZE] {
+        let flags = if self.stack_overflow {
+            FLAG_STACK_OVERFLOW
+        } else {
+            0
+        };
+        let words = [
+            MAGIC, self.r0, self.r1, self.r2, self.r3, self.r12, self.lr, self.pc, self.xpsr,
+            self.cfsr, self.hfsr, self.mmfar, self.bfar, flags,
+        ];
+        let mut bytes = [0; REPORT_SIZE];
+        for (chunk, word) in bytes.chunks_exact_mut(4).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        let crc = crc32(&bytes[..REPORT_SIZE - 4]);
+        bytes[REPORT_SIZE - 4..].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    /// Decode a report, `None` without the magic or if the CRC is wrong.
+    pub fn from_bytes(bytes: &[u8; REPORT_SIZE]) -> Option<Self> {
+        let mut words = [0u32; REPORT_SIZE / 4];
+        for (word, chunk) in words.iter_mut().zip(bytes.chunks_exact(4)) {
+            *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        if words[0] != MAGIC || words[14] != crc32(&bytes[..REPORT_SIZE - 4]) {
+            return None;
+        }
+        Some(Self {
+            r0: words[1],
+            r1: words[2],
+            r2: words[3],
+            r3: words[4],
+            r12: words[5],
+            lr: words[6],
+            pc: words[7],
+            xpsr: words[8],
+            cfsr: words[9],
+            hfsr: words[10],
+            mmfar: words[11],
+            bfar: words[12],
+            stack_overflow: words[13] & FLAG_STACK_OVERFLOW != 0,
+        })
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.stack_overflow {
+            writeln!(f, "HardFault: stack overflow")?;
+        } else {
+            writeln!(f, "HardFault")?;
+        }
+        writeln!(
+            f,
+            "  PC {:08X} LR {:08X} xPSR {:08X}",
+            self.pc, self.lr, self.xpsr
+        )?;
+        writeln!(
+            f,
+            "  R0 {:08X} R1 {:08X} R2 {:08X} R3 {:08X} R12 {:08X}",
+            self.r0, self.r1, self.r2, self.r3, self.r12
+        )?;
+        writeln!(
+            f,
+            "  CFSR {:08X} HFSR {:08X} MMFAR {:08X} BFAR {:08X}",
+            self.cfsr, self.hfsr, self.mmfar, self.bfar
+        )?;
+        write!(f, "  cause:")?;
+        for (_, name) in CFSR_FLAGS.iter().filter(|(mask, _)| self.cfsr & mask != 0) {
+            write!(f, " {}", name)?;
+        }
+        for (_, name) in HFSR_FLAGS.iter().filter(|(mask, _)| self.hfsr & mask != 0) {
+            write!(f, " {}", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Set where the report goes, besides the console.
+pub fn init(config: Config) {
+    critical_section::with(|cs| CONFIG.borrow(cs).set(config));
+}
+
+/// The report stored in the configured data flash block, if there is one.
+pub fn last_report() -> Option<Report> {
+    let block = critical_section::with(|cs| CONFIG.borrow(cs).get()).flash_block?;
+    // Data flash is memory mapped for reading
+    let bytes = unsafe { (block as *const [u8; REPORT_SIZE]).read_volatile() };
+    Report::from_bytes(&bytes)
+}
+
+/// Erase the stored report, so the next [`last_report`] is `None`.
+pub fn clear_report(flash: &mut Flash) -> Result<(), flash::Error> {
+    match critical_section::with(|cs| CONFIG.borrow(cs).get()).flash_block {
+        Some(block) => flash.erase_data(block, DATA_BLOCK_SIZE),
+        None => Ok(()),
+    }
+}
+
+/// Report a fault, then reset. Call from the HardFault handler.
+///
+/// The report is printed on the console and the UART drained, then sent on
+/// CAN and stored in data flash as configured with [`init`]. Without the
+/// `uart` or `can` feature that step is skipped.
+pub fn hard_fault(frame: &ExceptionFrame) -> ! {
+    let report = Report::capture(frame);
+    #[cfg(feature = "uart")]
+    {
+        crate::sprintln!("{}", report);
+        crate::uart::flush::<SCI2>();
+    }
+
+    let config = critical_section::with(|cs| CONFIG.borrow(cs).get());
+    let bytes = report.to_bytes();
+    #[cfg(feature = "can")]
+    if let Some(id) = config.can_id {
+        for chunk in bytes.chunks(8) {
+            let sent = Frame::new(id, chunk).map(|frame| can::send_unowned(&frame));
+            if !matches!(sent, Some(Ok(()))) {
+                break;
+            }
+        }
+        // Let the last frames go out before the reset
+        cortex_m::asm::delay(can::MODE_TIMEOUT_CYCLES);
+    }
+    if let Some(block) = config.flash_block {
+        let clocks = Clocks::read();
+        let mut flash = Flash::new(clocks.fclk(), clocks.iclk());
+        if flash.erase_data(block, DATA_BLOCK_SIZE).is_ok() {
+            let _ = flash.program_data(block, &bytes);
+        }
+    }
+    crate::reset::software_reset()
+}
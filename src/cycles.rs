@@ -0,0 +1,157 @@
+//! Time from the DWT cycle counter.
+//!
+//! The cycle counter counts CPU clock cycles, so gives short delays and
+//! benchmarks exact to a cycle without a timer. It's 32 bits, wrapping
+//! every 89 s at 48 MHz. [`micros`] and [`nanos`] extend it to 64 bits, as
+//! long as one of them is called at least once per wrap.
+//!
+//! ```ignore
+//! cycles::start(48_000_000);
+//! cycles::busy_wait_ms(20);
+//! let (crc, cycles) = cycles::measure(|| crc16(&data));
+//! ```
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use cortex_m::peripheral::DWT;
+use critical_section::Mutex;
+
+static CORE_HZ: AtomicU32 = AtomicU32::new(48_000_000);
+// Counter value and wraps at the last call to `cycles64`
+static LAST: Mutex<Cell<(u32, u32)>> = Mutex::new(Cell::new((0, 0)));
+
+/// Start the cycle counter from 0, `core_hz` is the CPU clock.
+pub fn start(core_hz: u32) {
+    CORE_HZ.store(core_hz, Ordering::Relaxed);
+    let mut p = unsafe { cortex_m::Peripherals::steal() };
+    p.DCB.enable_trace();
+    p.DWT.set_cycle_count(0);
+    p.DWT.enable_cycle_counter();
+    critical_section::with(|cs| LAST.borrow(cs).set((0, 0)));
+}
+
+/// Whether the cycle counter is running.
+pub fn is_running() -> bool {
+    DWT::cycle_counter_enabled()
+}
+
+/// CPU clock given to [`start`].
+pub fn core_hz() -> u32 {
+    CORE_HZ.load(Ordering::Relaxed)
+}
+
+/// The cycle counter, wraps at 32 bits.
+pub fn now() -> u32 {
+    DWT::cycle_count()
+}
+
+/// Cycles since `start`, a value from [`now`], correct across one wrap.
+pub fn elapsed(start: u32) -> u32 {
+    now().wrapping_sub(start)
+}
+
+/// Cycles since [`start`], extended to 64 bits.
+pub fn cycles64() -> u64 {
+    critical_section::with(|cs| {
+        let last = LAST.borrow(cs);
+        let (previous, mut wraps) = last.get();
+        let count = now();
+        if count < previous {
+            wraps += 1;
+        }
+        last.set((count, wraps));
+        ((wraps as u64) << 32) | count as u64
+    })
+}
+
+/// Microseconds since [`start`].
+pub fn micros() -> u64 {
+    cycles64() * 1_000_000 / core_hz() as u64
+}
+
+/// Nanoseconds since [`start`].
+pub fn nanos() -> u64 {
+    (cycles64() as u128 * 1_000_000_000 / core_hz() as u128) as u64
+}
+
+/// Convert microseconds to cycles of the CPU clock.
+pub fn us_to_cycles(us: u32) -> u32 {
+    (us as u64 * core_hz() as u64 / 1_000_000).min(u32::MAX as u64) as u32
+}
+
+/// Convert cycles of the CPU clock to nanoseconds.
+pub fn cycles_to_ns(cycles: u32) -> u64 {
+    cycles as u64 * 1_000_000_000 / core_hz() as u64
+}
+
+/// Spin for `us` microseconds, up to one wrap of the counter.
+///
+/// Interrupts taken meanwhile count towards the wait rather than adding to
+/// it. Before [`start`], spins on `asm::delay` at [`core_hz`] instead.
+pub fn busy_wait_us(us: u32) {
+    let cycles = us_to_cycles(us);
+    if !is_running() {
+        cortex_m::asm::delay(cycles);
+        return;
+    }
+    let start = now();
+    while elapsed(start) < cycles {}
+}
+
+/// Spin for `ms` milliseconds, see [`busy_wait_us`].
+pub fn busy_wait_ms(ms: u32) {
+    for _ in 0..ms {
+        busy_wait_us(1000);
+    }
+}
+
+/// Run `f` and return its result with the cycles it took.
+pub fn measure<R>(f: impl FnOnce() -> R) -> (R, u32) {
+    let start = now();
+    let result = f();
+    (result, elapsed(start))
+}
+
+/// Busy wait delay, for drivers taking an embedded-hal delay with the
+/// `embedded-hal` or `embedded-hal-02` feature. See [`busy_wait_us`].
+#[cfg(any(feature = "embedded-hal", feature = "embedded-hal-02"))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Delay;
+
+#[cfg(feature = "embedded-hal")]
+impl embedded_hal::delay::DelayNs for Delay {
+    fn delay_ns(&mut self, ns: u32) {
+        busy_wait_us(ns.div_ceil(1000));
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        busy_wait_us(us);
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        busy_wait_ms(ms);
+    }
+}
+
+#[cfg(feature = "embedded-hal-02")]
+macro_rules! impl_delay_02 {
+    ($($ty:ty),*) => {
+        $(
+            impl embedded_hal_02::blocking::delay::DelayUs<$ty> for Delay {
+                fn delay_us(&mut self, us: $ty) {
+                    busy_wait_us(us as u32);
+                }
+            }
+
+            impl embedded_hal_02::blocking::delay::DelayMs<$ty> for Delay {
+                fn delay_ms(&mut self, ms: $ty) {
+                    busy_wait_ms(ms as u32);
+                }
+            }
+        )*
+    };
+}
+
+#[cfg(feature = "embedded-hal-02")]
+impl_delay_02!(u8, u16, u32);
@@ -0,0 +1,192 @@
+//! DMA Controller (DMAC)
+//!
+//! Four channels that move data between memory and peripherals without the
+//! CPU. A channel is started by a peripheral event, linked to it in the ICU
+//! DELSRn register of the same number as the channel.
+//!
+//! Only the transfers needed by drivers in this crate are implemented:
+//! normal mode byte transfers between a fixed peripheral register and a
+//! buffer in memory, and block mode 16-bit reads of a run of peripheral
+//! registers.
+
+use ra4m1::{DMAC0, DMAC1, DMAC2, DMAC3, dmac0};
+
+use crate::clk::{self, Peripheral};
+use crate::events::Event;
+
+/// A DMAC channel.
+pub trait Channel {
+    // Get access to the channel's register block.
+    fn peripheral() -> *const dmac0::RegisterBlock;
+    // Channel number, 0-3
+    fn index() -> u8;
+    // Transfer end interrupt event (DMACn_INT)
+    fn event() -> Event {
+        Event::DMAC0_INT.offset(Self::index())
+    }
+}
+
+macro_rules! impl_channel {
+    ($($inst:ident: $index:literal;)*) => {
+        $(
+            impl Channel for $inst {
+                fn peripheral() -> *const dmac0::RegisterBlock {
+                    $inst::ptr() as *const dmac0::RegisterBlock
+                }
+
+                fn index() -> u8 {
+                    $index
+                }
+            }
+        )*
+    };
+}
+
+impl_channel! {
+    DMAC0: 0;
+    DMAC1: 1;
+    DMAC2: 2;
+    DMAC3: 3;
+}
+
+/// Largest number of bytes in a normal mode transfer.
+pub const MAX_TRANSFER: usize = 0xFFFF;
+/// Largest number of values in a block.
+pub const MAX_BLOCK: usize = 0x3FF;
+/// Largest number of blocks in a block mode transfer.
+pub const MAX_BLOCKS: usize = 0xFFFF;
+
+// DMTMD, activated by peripheral event (DCTG = 01), byte size (SZ = 00),
+// no repeat or block area (DTS = 10), normal mode (MD = 00)
+const DMTMD_NORMAL_BYTE: u16 = (0b10 << 12) | 0b01;
+// DMTMD, activated by peripheral event, 16-bit size (SZ = 01), source is
+// the block area (DTS = 01), block mode (MD = 10)
+const DMTMD_BLOCK_HALFWORD: u16 = (0b10 << 14) | (0b01 << 12) | (0b01 << 8) | 0b01;
+// DMAMD.SM and DMAMD.DM, address incremented after each byte
+const DMAMD_SM_INCREMENT: u16 = 0b10 << 14;
+const DMAMD_DM_INCREMENT: u16 = 0b10 << 6;
+// DMCNT.DTE, transfer enable
+const DMCNT_DTE: u8 = 1 << 0;
+// DMINT.DTIE, transfer end interrupt enable
+const DMINT_DTIE: u8 = 1 << 4;
+// DMSTS.DTIF, transfer end flag
+const DMSTS_DTIF: u8 = 1 << 4;
+
+// Get the registers of a channel from its number.
+// All channels have the same layout and are 0x40 bytes apart.
+fn regs(index: u8) -> &'static dmac0::RegisterBlock {
+    unsafe { &*((DMAC0::ptr() as usize + 0x40 * index as usize) as *const dmac0::RegisterBlock) }
+}
+
+/// Enable the DMAC module and allow channels to be activated.
+pub(crate) fn enable() {
+    let p = unsafe { ra4m1::Peripherals::steal() };
+    // Shared by all channels and the DTC, left running
+    clk::enable_peripheral(Peripheral::Dmac);
+    p.DMA.dmast.write(|w| w.dmst()._1());
+}
+
+/// Link a peripheral event to a channel, the channel then moves one
+/// byte each time the event occurs.
+pub(crate) fn link_event<C: Channel>(event: Event) {
+    let p = unsafe { ra4m1::Peripherals::steal() };
+    p.ICU.delsr[C::index() as usize].write(|w| unsafe { w.dels().bits(event.id()) });
+}
+
+/// Move `len` bytes from a peripheral register into memory.
+///
+/// ## Safety
+/// `dst` must be valid for `len` bytes of writes until the transfer is stopped.
+pub(crate) unsafe fn start_read<C: Channel>(src: *const u8, dst: *mut u8, len: usize) {
+    start::<C>(src as u32, dst as u32, len, DMAMD_DM_INCREMENT);
+}
+
+/// Move `len` bytes from memory into a peripheral register.
+///
+/// ## Safety
+/// `src` must be valid for `len` bytes of reads until the transfer is stopped.
+pub(crate) unsafe fn start_write<C: Channel>(src: *const u8, dst: *mut u8, len: usize) {
+    start::<C>(src as u32, dst as u32, len, DMAMD_SM_INCREMENT);
+}
+
+fn start<C: Channel>(src: u32, dst: u32, len: usize, amd: u16) {
+    let dmac = unsafe { &*C::peripheral() };
+    // Registers can only be written with the channel disabled
+    dmac.dmcnt.write(|w| unsafe { w.bits(0) });
+    dmac.dmsts.write(|w| unsafe { w.bits(0) });
+    dmac.dmsar.write(|w| unsafe { w.bits(src) });
+    dmac.dmdar.write(|w| unsafe { w.bits(dst) });
+    // Normal mode, DMCRAH must be 0
+    dmac.dmcra
+        .write(|w| unsafe { w.bits(len.min(MAX_TRANSFER) as u32) });
+    dmac.dmtmd.write(|w| unsafe { w.bits(DMTMD_NORMAL_BYTE) });
+    dmac.dmamd.write(|w| unsafe { w.bits(amd) });
+    dmac.dmint.write(|w| unsafe { w.bits(0) });
+    dmac.dmcnt.write(|w| unsafe { w.bits(DMCNT_DTE) });
+}
+
+/// Move `blocks` blocks of `block_len` 16-bit values into memory, one block
+/// each time the event occurs.
+///
+/// Each block is read from `block_len` consecutive registers from `src`,
+/// the source returns to `src` for the next block.
+///
+/// ## Safety
+/// `dst` must be valid for `block_len * blocks` values of writes until the
+/// transfer is stopped.
+pub(crate) unsafe fn start_block_read<C: Channel>(
+    src: *const u16,
+    dst: *mut u16,
+    block_len: usize,
+    blocks: usize,
+) {
+    let dmac = unsafe { &*C::peripheral() };
+    dmac.dmcnt.write(|w| unsafe { w.bits(0) });
+    dmac.dmsts.write(|w| unsafe { w.bits(0) });
+    dmac.dmsar.write(|w| unsafe { w.bits(src as u32) });
+    dmac.dmdar.write(|w| unsafe { w.bits(dst as u32) });
+    // Block size in DMCRAL, reloaded from DMCRAH after each block
+    let len = block_len.min(MAX_BLOCK) as u32;
+    dmac.dmcra.write(|w| unsafe { w.bits((len << 16) | len) });
+    dmac.dmcrb
+        .write(|w| unsafe { w.bits(blocks.min(MAX_BLOCKS) as u16) });
+    dmac.dmtmd
+        .write(|w| unsafe { w.bits(DMTMD_BLOCK_HALFWORD) });
+    dmac.dmamd
+        .write(|w| unsafe { w.bits(DMAMD_SM_INCREMENT | DMAMD_DM_INCREMENT) });
+    dmac.dmint.write(|w| unsafe { w.bits(0) });
+    dmac.dmcnt.write(|w| unsafe { w.bits(DMCNT_DTE) });
+}
+
+/// Request the DMACn_INT interrupt when the current transfer ends.
+pub(crate) fn enable_end_interrupt<C: Channel>() {
+    let dmac = unsafe { &*C::peripheral() };
+    dmac.dmcnt.write(|w| unsafe { w.bits(0) });
+    dmac.dmint.write(|w| unsafe { w.bits(DMINT_DTIE) });
+    dmac.dmcnt.write(|w| unsafe { w.bits(DMCNT_DTE) });
+}
+
+/// Clear the transfer end flag, from the DMACn_INT handler.
+pub(crate) fn clear_end_flag<C: Channel>() {
+    let dmac = unsafe { &*C::peripheral() };
+    dmac.dmsts
+        .modify(|r, w| unsafe { w.bits(r.bits() & !DMSTS_DTIF) });
+}
+
+/// Disable a channel, any pending activation is discarded.
+pub(crate) fn stop(index: u8) {
+    let dmac = regs(index);
+    dmac.dmcnt.write(|w| unsafe { w.bits(0) });
+}
+
+/// Number of bytes the channel has not yet moved.
+pub(crate) fn remaining(index: u8) -> usize {
+    let dmac = regs(index);
+    (dmac.dmcra.read().bits() & 0xFFFF) as usize
+}
+
+/// Address the channel writes to next.
+pub(crate) fn destination(index: u8) -> usize {
+    let dmac = regs(index);
+    dmac.dmdar.read().bits() as usize
+}
@@ -0,0 +1,288 @@
+//! DMX512 output.
+//!
+//! The SCI sends the universe at 250 kbaud 8N2 and a DMAC channel feeds it
+//! from memory, so the CPU only handles a few interrupts per packet. The
+//! break and mark after break are timed by a GPT channel while the TXD pin
+//! is driven through SPTR with the transmitter disabled.
+//!
+//! Packets are sent back to back for as long as the driver exists:
+//!
+//! 1. Break, TXD low for `break_us`
+//! 2. Mark after break, TXD high for `mab_us`
+//! 3. Start code and slots, from the universe buffer through the DMAC
+//! 4. Once the last stop bit is sent, start again at 1.
+//!
+//! Only one DMX output can run at a time.
+
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+
+use crate::clk::ClockGuard;
+use crate::dmac::{self, Channel};
+use crate::gpt::{self, Prescaler};
+use crate::interrupts::{Binding, Handler, clear_interrupt, map_and_enable_interrupt};
+use crate::uart::{self, TxPin};
+
+/// Number of slots in a universe, not including the start code.
+pub const SLOTS: usize = 512;
+
+/// DMX configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Frequency of PCLKD in Hz, clocks the GPT
+    pub pclkd_hz: u32,
+    /// Length of the break in microseconds, at least 92
+    pub break_us: u32,
+    /// Length of the mark after break in microseconds, at least 12
+    pub mab_us: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            pclkd_hz: 48_000_000,
+            break_us: 176,
+            mab_us: 16,
+        }
+    }
+}
+
+struct State {
+    universe: AtomicPtr<u8>,
+    len: AtomicUsize,
+    break_ticks: AtomicU32,
+    mab_ticks: AtomicU32,
+    // Set while the mark after break is being timed
+    in_mab: AtomicBool,
+    packets: AtomicU32,
+}
+
+static STATE: State = State {
+    universe: AtomicPtr::new(core::ptr::null_mut()),
+    len: AtomicUsize::new(0),
+    break_ticks: AtomicU32::new(0),
+    mab_ticks: AtomicU32::new(0),
+    in_mab: AtomicBool::new(false),
+    packets: AtomicU32::new(0),
+};
+
+// SCR bits
+const SCR_TEIE: u8 = 1 << 2;
+const SCR_TE: u8 = 1 << 5;
+const SCR_TIE: u8 = 1 << 7;
+
+// GTST.TCFPO, overflow flag
+const GTST_TCFPO: u32 = 1 << 6;
+
+// Address of TDR, used as the DMA destination
+fn tdr<S: uart::Instance>() -> *mut u8 {
+    (S::peripheral() as usize + 0x03) as *mut u8
+}
+
+// Drive TXD low and time the break
+fn start_break<S: uart::Instance, G: gpt::Instance>() {
+    let sci = unsafe { &*S::peripheral() };
+    sci.sptr.modify(|_, w| w.spb2dt()._0());
+    sci.scr()
+        .modify(|r, w| unsafe { w.bits(r.bits() & !(SCR_TE | SCR_TIE | SCR_TEIE)) });
+    let gpt = unsafe { &*G::peripheral() };
+    STATE.in_mab.store(false, Ordering::Relaxed);
+    gpt.gtpr
+        .write(|w| unsafe { w.bits(STATE.break_ticks.load(Ordering::Relaxed)) });
+    gpt.gtcnt.write(|w| unsafe { w.bits(0) });
+    gpt::start::<G>();
+}
+
+/// Triggers at the end of the DMA transfer, the last byte is now in the SCI.
+pub struct DmaEndHandler<S: uart::Instance, C: Channel> {
+    _phantom: PhantomData<(S, C)>,
+}
+
+impl<S: uart::Instance, C: Channel> Handler for DmaEndHandler<S, C> {
+    unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
+        clear_interrupt(interrupt);
+        dmac::clear_end_flag::<C>();
+        // Wait for the final stop bits
+        let sci = unsafe { &*S::peripheral() };
+        sci.scr()
+            .modify(|r, w| unsafe { w.bits((r.bits() & !SCR_TIE) | SCR_TEIE) });
+    }
+}
+
+/// Triggers when the last slot has been sent, starts the break.
+pub struct TeiHandler<S: uart::Instance, G: gpt::Instance> {
+    _phantom: PhantomData<(S, G)>,
+}
+
+impl<S: uart::Instance, G: gpt::Instance> Handler for TeiHandler<S, G> {
+    unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
+        clear_interrupt(interrupt);
+        STATE.packets.fetch_add(1, Ordering::Relaxed);
+        start_break::<S, G>();
+    }
+}
+
+/// Triggers on timer overflow, at the end of the break and of the mark after break.
+pub struct TimerHandler<S: uart::Instance, G: gpt::Instance, C: Channel> {
+    _phantom: PhantomData<(S, G, C)>,
+}
+
+impl<S: uart::Instance, G: gpt::Instance, C: Channel> Handler for TimerHandler<S, G, C> {
+    unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
+        clear_interrupt(interrupt);
+        let gpt = unsafe { &*G::peripheral() };
+        gpt.gtst
+            .modify(|r, w| unsafe { w.bits(r.bits() & !GTST_TCFPO) });
+        let sci = unsafe { &*S::peripheral() };
+
+        if !STATE.in_mab.swap(true, Ordering::Relaxed) {
+            // End of break, counter restarts from 0 for the mark after break
+            sci.sptr.modify(|_, w| w.spb2dt()._1());
+            gpt.gtpr
+                .write(|w| unsafe { w.bits(STATE.mab_ticks.load(Ordering::Relaxed)) });
+            return;
+        }
+
+        // End of mark after break, send the packet
+        gpt::stop::<G>();
+        let universe = STATE.universe.load(Ordering::Relaxed);
+        let len = STATE.len.load(Ordering::Relaxed);
+        // Safety: the universe is 'static and only read by the DMAC
+        unsafe { dmac::start_write::<C>(universe, tdr::<S>(), len) };
+        dmac::enable_end_interrupt::<C>();
+        // TE and TIE together, TXI then activates the DMAC for the first byte
+        sci.scr()
+            .modify(|r, w| unsafe { w.bits(r.bits() | SCR_TE | SCR_TIE) });
+    }
+}
+
+/// DMX512 transmitter.
+pub struct Dmx<S: uart::Instance, G: gpt::Instance, C: Channel> {
+    universe: &'static mut [u8; SLOTS + 1],
+    _clocks: (ClockGuard, ClockGuard),
+    _phantom: PhantomData<(S, G, C)>,
+}
+
+impl<S: uart::Instance, G: gpt::Instance, C: Channel> Dmx<S, G, C> {
+    /// Start sending `universe` continuously.
+    ///
+    /// `universe[0]` is the start code, 0 for dimmer data, followed by the
+    /// 512 slots. It's read by the DMAC for the lifetime of the driver.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<P: TxPin<S>, IRQ>(
+        _instance: S,
+        _tx: P,
+        _timer: G,
+        _dma: C,
+        universe: &'static mut [u8; SLOTS + 1],
+        config: Config,
+        _irq: IRQ,
+    ) -> Self
+    where
+        IRQ: Binding<DmaEndHandler<S, C>>
+            + Binding<TeiHandler<S, G>>
+            + Binding<TimerHandler<S, G, C>>,
+    {
+        let sci_clock = uart::enable_module::<S>();
+        let sci = unsafe { &*S::peripheral() };
+        sci.scr().write(|w| unsafe { w.bits(0) });
+        sci.simr1.write(|w| w.iicm()._0());
+        sci.spmr.write(|w| unsafe { w.bits(0) });
+        // 8 data bits, no parity, 2 stop bits
+        sci.smr().write(|w| {
+            w.cks()
+                ._00()
+                .mp()
+                ._0()
+                .stop()
+                ._1()
+                .pe()
+                ._0()
+                .chr()
+                ._0()
+                .cm()
+                ._0()
+        });
+        sci.scmr
+            .write(|w| w.smif()._0().sinv()._0().sdir()._0().chr1()._1());
+        sci.semr.write(|w| unsafe { w.bits(0) });
+        uart::set_baud_rate::<S>(250_000);
+        // Idle high while TE is clear
+        sci.sptr.write(|w| w.spb2dt()._1().spb2io()._1());
+        uart::connect_pin::<S, P>();
+
+        let gpt_clock = gpt::init::<G>(Prescaler::Div1);
+        let tick_mhz = config.pclkd_hz / 1_000_000;
+        let max = G::max_count();
+        STATE.break_ticks.store(
+            (config.break_us.max(92) * tick_mhz).min(max),
+            Ordering::Relaxed,
+        );
+        STATE.mab_ticks.store(
+            (config.mab_us.max(12) * tick_mhz).min(max),
+            Ordering::Relaxed,
+        );
+        STATE
+            .universe
+            .store(universe.as_mut_ptr(), Ordering::Relaxed);
+        STATE.len.store(universe.len(), Ordering::Relaxed);
+
+        dmac::enable();
+        dmac::link_event::<C>(S::event_base().offset(1));
+        map_and_enable_interrupt(
+            <IRQ as Binding<DmaEndHandler<S, C>>>::interrupt(),
+            C::event(),
+        );
+        map_and_enable_interrupt(
+            <IRQ as Binding<TeiHandler<S, G>>>::interrupt(),
+            S::event_base().offset(2),
+        );
+        // Overflow event of the timer
+        map_and_enable_interrupt(
+            <IRQ as Binding<TimerHandler<S, G, C>>>::interrupt(),
+            G::event_base().offset(6),
+        );
+
+        start_break::<S, G>();
+        Self {
+            universe,
+            _clocks: (sci_clock, gpt_clock),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Set the level of a slot, 1 - 512. Sent from the next packet.
+    pub fn set(&mut self, slot: usize, level: u8) {
+        if (1..=SLOTS).contains(&slot) {
+            self.universe[slot] = level;
+        }
+    }
+
+    /// Level of a slot, 1 - 512.
+    pub fn get(&self, slot: usize) -> Option<u8> {
+        if (1..=SLOTS).contains(&slot) {
+            Some(self.universe[slot])
+        } else {
+            None
+        }
+    }
+
+    /// Set consecutive slots starting at `first`, 1 - 512.
+    pub fn set_slots(&mut self, first: usize, levels: &[u8]) {
+        if first == 0 || first > SLOTS {
+            return;
+        }
+        let len = levels.len().min(SLOTS + 1 - first);
+        self.universe[first..first + len].copy_from_slice(&levels[..len]);
+    }
+
+    /// The whole universe including the start code, being sent while held.
+    pub fn universe_mut(&mut self) -> &mut [u8; SLOTS + 1] {
+        self.universe
+    }
+
+    /// Number of packets sent since the driver was created.
+    pub fn packets(&self) -> u32 {
+        STATE.packets.load(Ordering::Relaxed)
+    }
+}
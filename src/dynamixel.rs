@@ -0,0 +1,598 @@
+//! Dynamixel servo bus, protocol 1.0 and 2.0, on top of the UART driver.
+//!
+//! The servos share one half-duplex data line. Either drive it through a
+//! tri-state buffer (e.g. 74LS241) whose direction pin is given with
+//! [`Bus::direction`], or join TX and RX with a resistor and set
+//! [`Config::echo`], the transmitted bytes then come back on RX and are
+//! checked.
+//!
+//! ```ignore
+//! use uno_r4_rust::dynamixel::{Bus, Config, x};
+//!
+//! let uart = Uart::new(p.SCI2, pins.p302, pins.p301, 57_600, Irq)?;
+//! let mut bus = Bus::new(uart, Config::default()).direction(pins.p303);
+//! let info = bus.ping(1)?;
+//! bus.set(1, x::TORQUE_ENABLE, 1)?;
+//! bus.set(1, x::GOAL_POSITION, 2048)?;
+//! let position = bus.get(1, x::PRESENT_POSITION)?;
+//! // Both servos start moving together
+//! bus.sync_set(x::GOAL_POSITION, &[(1, 1024), (2, 3072)])?;
+//! ```
+//!
+//! Instructions wait for the status packet of the servo, except those sent
+//! to [`BROADCAST_ID`]. Servos set to not return a status for writes
+//! (status return level below 2) time out on [`Bus::write`].
+//!
+//! Register maps of common models are in [`ax`] (AX-12A, AX-18A, protocol
+//! 1.0) and [`x`] (XL430, XM430, XC430, protocol 2.0).
+
+use core::marker::PhantomData;
+
+use embedded_io::{Read, ReadReady, Write};
+
+use crate::clk::Clocks;
+use crate::gpio::{self, Pin};
+use crate::uart::{Instance, Uart};
+
+pub mod ax;
+pub mod x;
+
+/// ID every servo accepts, none of them answer.
+pub const BROADCAST_ID: u8 = 0xFE;
+
+/// Largest number of parameter bytes in an instruction packet.
+pub const MAX_PARAMS: usize = 128;
+
+/// Ping instruction.
+pub const PING: u8 = 0x01;
+/// Read instruction.
+pub const READ: u8 = 0x02;
+/// Write instruction.
+pub const WRITE: u8 = 0x03;
+/// Sync write instruction.
+pub const SYNC_WRITE: u8 = 0x83;
+
+// Instruction of a protocol 2.0 status packet
+const STATUS: u8 = 0x55;
+// Protocol 2.0 header, after which 0xFD is stuffed
+const HEADER: [u8; 3] = [0xFF, 0xFF, 0xFD];
+// Room for the parameters, their byte stuffing and the framing
+const MAX_PACKET: usize = MAX_PARAMS + MAX_PARAMS / 3 + 12;
+// Receive poll interval
+const STEP_US: u32 = 10;
+
+/// Protocol spoken by the servos.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Protocol {
+    /// Protocol 1.0, AX and MX series, 8 bit addresses and a sum checksum
+    V1,
+    /// Protocol 2.0, X series, 16 bit addresses and a CRC-16
+    V2,
+}
+
+/// Bus configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Protocol of the servos, all on a bus must use the same one
+    pub protocol: Protocol,
+    /// Bus rate, 57600 for new X series servos and 1000000 for AX series
+    pub baud: u32,
+    /// Longest wait for a status packet, including the servo's return delay
+    pub timeout_us: u32,
+    /// Transmitted bytes are echoed back on RX
+    pub echo: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            protocol: Protocol::V2,
+            baud: 57_600,
+            timeout_us: 10_000,
+            echo: false,
+        }
+    }
+}
+
+/// Dynamixel errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// No status packet arrived in time
+    Timeout,
+    /// Checksum or CRC of the status packet doesn't match
+    Checksum,
+    /// The status packet came from another ID
+    Id,
+    /// The status packet is malformed or has the wrong number of parameters
+    Length,
+    /// The servo reported an error, the protocol 1.0 error bits or the
+    /// protocol 2.0 error number
+    Status(u8),
+    /// The echo of a transmitted byte differs
+    Echo,
+    /// The instruction has more than [`MAX_PARAMS`] parameter bytes
+    TooLong,
+    /// The address or length doesn't fit in protocol 1.0
+    Range,
+    /// The instruction needs a status packet, which a broadcast doesn't get
+    Broadcast,
+}
+
+/// Model and firmware of a servo, from [`Bus::ping`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Ping {
+    /// Model number, e.g. 12 for the AX-12A and 1060 for the XL430-W250
+    pub model: u16,
+    /// Firmware version
+    pub firmware: u8,
+}
+
+/// Value of a register, little endian on the bus.
+pub trait Value: Copy {
+    /// Size in bytes
+    const SIZE: usize;
+
+    /// Value of the first [`SIZE`](Self::SIZE) bytes
+    fn from_le(bytes: &[u8]) -> Self;
+
+    /// Write the value to the first [`SIZE`](Self::SIZE) bytes
+    fn to_le(self, bytes: &mut [u8]);
+}
+
+macro_rules! impl_value {
+    ($($ty:ty),*) => {
+        $(
+            impl Value for $ty {
+                const SIZE: usize = core::mem::size_of::<$ty>();
+
+                fn from_le(bytes: &[u8]) -> Self {
+                    let mut le = [0; core::mem::size_of::<$ty>()];
+                    le.copy_from_slice(&bytes[..Self::SIZE]);
+                    <$ty>::from_le_bytes(le)
+                }
+
+                fn to_le(self, bytes: &mut [u8]) {
+                    bytes[..Self::SIZE].copy_from_slice(&self.to_le_bytes());
+                }
+            }
+        )*
+    };
+}
+
+impl_value!(u8, i8, u16, i16, u32, i32);
+
+/// Register of a control table, its address and value type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Register<V> {
+    address: u16,
+    _value: PhantomData<V>,
+}
+
+impl<V: Value> Register<V> {
+    /// Register at `address`, for models without a map here.
+    pub const fn new(address: u16) -> Self {
+        Self {
+            address,
+            _value: PhantomData,
+        }
+    }
+
+    /// Address in the control table.
+    pub const fn address(&self) -> u16 {
+        self.address
+    }
+
+    /// Size in bytes.
+    pub const fn size(&self) -> usize {
+        V::SIZE
+    }
+}
+
+/// Protocol 1.0 checksum, the inverted low byte of the sum of the bytes from
+/// the ID to the last parameter.
+pub fn checksum(bytes: &[u8]) -> u8 {
+    !bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// Protocol 2.0 CRC-16, polynomial 0x8005 starting from 0, of the bytes
+/// from the header to the last parameter.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// Instruction packet into `packet`, returning its length
+fn encode(
+    protocol: Protocol,
+    packet: &mut [u8; MAX_PACKET],
+    id: u8,
+    instruction: u8,
+    params: &[u8],
+) -> Option<usize> {
+    if params.len() > MAX_PARAMS {
+        return None;
+    }
+    match protocol {
+        Protocol::V1 => {
+            let end = 5 + params.len();
+            packet[..5].copy_from_slice(&[0xFF, 0xFF, id, params.len() as u8 + 2, instruction]);
+            packet[5..end].copy_from_slice(params);
+            packet[end] = checksum(&packet[2..end]);
+            Some(end + 1)
+        }
+        Protocol::V2 => {
+            packet[..5].copy_from_slice(&[0xFF, 0xFF, 0xFD, 0x00, id]);
+            // Instruction and parameters, with 0xFD stuffed after a header
+            let mut end = 7;
+            for byte in core::iter::once(&instruction).chain(params) {
+                packet[end] = *byte;
+                end += 1;
+                if end >= 10 && packet[end - 3..end] == HEADER {
+                    packet[end] = 0xFD;
+                    end += 1;
+                }
+            }
+            // Length counts the instruction, parameters and CRC
+            let len = (end - 7 + 2) as u16;
+            packet[5..7].copy_from_slice(&len.to_le_bytes());
+            let crc = crc16(&packet[..end]);
+            packet[end..end + 2].copy_from_slice(&crc.to_le_bytes());
+            Some(end + 2)
+        }
+    }
+}
+
+/// Master of a Dynamixel bus.
+pub struct Bus<T: Instance, const TX: usize, const RX: usize> {
+    uart: Uart<T, TX, RX>,
+    config: Config,
+    // Port and pin number of the buffer direction, high to transmit
+    direction: Option<(u8, u8)>,
+    step_cycles: u32,
+    alert: bool,
+}
+
+impl<T: Instance, const TX: usize, const RX: usize> Bus<T, TX, RX> {
+    /// Take over the UART and set the bus rate.
+    pub fn new(mut uart: Uart<T, TX, RX>, config: Config) -> Self {
+        uart.set_baud_rate(config.baud);
+        Self {
+            uart,
+            config,
+            direction: None,
+            step_cycles: Clocks::read().iclk() / 1_000_000 * STEP_US,
+            alert: false,
+        }
+    }
+
+    /// Drive `pin` high while transmitting and low while receiving, for the
+    /// direction of a tri-state buffer.
+    pub fn direction<P: Pin>(mut self, _pin: P) -> Self {
+        gpio::set_output::<P>(false);
+        self.direction = Some((P::port(), P::pin()));
+        self
+    }
+
+    /// Model and firmware of a servo, checking that it answers.
+    ///
+    /// Protocol 1.0 servos don't return these with the ping, they are read
+    /// from the start of the control table.
+    pub fn ping(&mut self, id: u8) -> Result<Ping, Error> {
+        if id == BROADCAST_ID {
+            return Err(Error::Broadcast);
+        }
+        let mut info = [0u8; 3];
+        match self.config.protocol {
+            Protocol::V1 => {
+                self.transact(id, PING, &[], &mut [])?;
+                self.read(id, 0, &mut info)?;
+            }
+            Protocol::V2 => self.transact(id, PING, &[], &mut info)?,
+        }
+        Ok(Ping {
+            model: u16::from_le_bytes([info[0], info[1]]),
+            firmware: info[2],
+        })
+    }
+
+    /// Read `buf.len()` bytes of the control table from `address`.
+    pub fn read(&mut self, id: u8, address: u16, buf: &mut [u8]) -> Result<(), Error> {
+        if id == BROADCAST_ID {
+            return Err(Error::Broadcast);
+        }
+        let mut params = [0u8; 4];
+        let len = self.address_and_len(&mut params, address, buf.len())?;
+        self.transact(id, READ, &params[..len], buf)
+    }
+
+    /// Write `data` to the control table from `address`.
+    pub fn write(&mut self, id: u8, address: u16, data: &[u8]) -> Result<(), Error> {
+        let mut params = [0u8; MAX_PARAMS];
+        let len = self.address(&mut params, address)?;
+        let end = len + data.len();
+        if end > MAX_PARAMS {
+            return Err(Error::TooLong);
+        }
+        params[len..end].copy_from_slice(data);
+        self.transact(id, WRITE, &params[..end], &mut [])
+    }
+
+    /// Write `len` bytes from `address` of several servos with one packet,
+    /// each entry an ID and its `len` bytes. The servos don't answer.
+    pub fn sync_write(
+        &mut self,
+        address: u16,
+        len: usize,
+        data: &[(u8, &[u8])],
+    ) -> Result<(), Error> {
+        let mut params = [0u8; MAX_PARAMS];
+        let mut end = self.address_and_len(&mut params, address, len)?;
+        for (id, bytes) in data {
+            if bytes.len() != len {
+                return Err(Error::Length);
+            }
+            if end + 1 + len > MAX_PARAMS {
+                return Err(Error::TooLong);
+            }
+            params[end] = *id;
+            params[end + 1..end + 1 + len].copy_from_slice(bytes);
+            end += 1 + len;
+        }
+        self.transact(BROADCAST_ID, SYNC_WRITE, &params[..end], &mut [])
+    }
+
+    /// Read a register.
+    pub fn get<V: Value>(&mut self, id: u8, register: Register<V>) -> Result<V, Error> {
+        let mut bytes = [0u8; 4];
+        self.read(id, register.address(), &mut bytes[..V::SIZE])?;
+        Ok(V::from_le(&bytes))
+    }
+
+    /// Write a register.
+    pub fn set<V: Value>(&mut self, id: u8, register: Register<V>, value: V) -> Result<(), Error> {
+        let mut bytes = [0u8; 4];
+        value.to_le(&mut bytes);
+        self.write(id, register.address(), &bytes[..V::SIZE])
+    }
+
+    /// Write a register of several servos with one packet, each entry an ID
+    /// and its value.
+    pub fn sync_set<V: Value>(
+        &mut self,
+        register: Register<V>,
+        values: &[(u8, V)],
+    ) -> Result<(), Error> {
+        let mut params = [0u8; MAX_PARAMS];
+        let mut end = self.address_and_len(&mut params, register.address(), V::SIZE)?;
+        for (id, value) in values {
+            if end + 1 + V::SIZE > MAX_PARAMS {
+                return Err(Error::TooLong);
+            }
+            params[end] = *id;
+            value.to_le(&mut params[end + 1..]);
+            end += 1 + V::SIZE;
+        }
+        self.transact(BROADCAST_ID, SYNC_WRITE, &params[..end], &mut [])
+    }
+
+    /// The alert bit of the last protocol 2.0 status packet, set while the
+    /// servo has a hardware error, see [`x::HARDWARE_ERROR_STATUS`].
+    pub fn alert(&self) -> bool {
+        self.alert
+    }
+
+    /// Release the UART.
+    pub fn free(self) -> Uart<T, TX, RX> {
+        self.uart
+    }
+
+    // Address in the width of the protocol, returning the bytes used
+    fn address(&self, params: &mut [u8], address: u16) -> Result<usize, Error> {
+        match self.config.protocol {
+            Protocol::V1 => {
+                params[0] = u8::try_from(address).map_err(|_| Error::Range)?;
+                Ok(1)
+            }
+            Protocol::V2 => {
+                params[..2].copy_from_slice(&address.to_le_bytes());
+                Ok(2)
+            }
+        }
+    }
+
+    // Address then length, both in the width of the protocol
+    fn address_and_len(&self, params: &mut [u8], address: u16, len: usize) -> Result<usize, Error> {
+        let at = self.address(params, address)?;
+        match self.config.protocol {
+            Protocol::V1 => {
+                params[at] = u8::try_from(len).map_err(|_| Error::Range)?;
+                Ok(at + 1)
+            }
+            Protocol::V2 => {
+                let len = u16::try_from(len).map_err(|_| Error::TooLong)?;
+                params[at..at + 2].copy_from_slice(&len.to_le_bytes());
+                Ok(at + 2)
+            }
+        }
+    }
+
+    // Send an instruction and, unless broadcast, receive its status
+    // parameters into `response`
+    fn transact(
+        &mut self,
+        id: u8,
+        instruction: u8,
+        params: &[u8],
+        response: &mut [u8],
+    ) -> Result<(), Error> {
+        let mut packet = [0u8; MAX_PACKET];
+        let len = encode(self.config.protocol, &mut packet, id, instruction, params)
+            .ok_or(Error::TooLong)?;
+        self.drain();
+        if let Some((port, pin)) = self.direction {
+            gpio::write_at(port, pin, true);
+        }
+        // Writes to the ring buffer can't fail
+        let _ = self.uart.write_all(&packet[..len]);
+        self.uart.wait_idle();
+        if let Some((port, pin)) = self.direction {
+            gpio::write_at(port, pin, false);
+        }
+
+        let mut steps = self.config.timeout_us / STEP_US;
+        if self.config.echo {
+            for byte in &packet[..len] {
+                if self.read_byte(&mut steps)? != *byte {
+                    return Err(Error::Echo);
+                }
+            }
+        }
+        if id == BROADCAST_ID {
+            return Ok(());
+        }
+        match self.config.protocol {
+            Protocol::V1 => self.receive_v1(id, response, &mut steps),
+            Protocol::V2 => self.receive_v2(id, response, &mut steps),
+        }
+    }
+
+    fn receive_v1(&mut self, id: u8, response: &mut [u8], steps: &mut u32) -> Result<(), Error> {
+        // FF FF, then an ID which is never FF
+        let mut ffs = 0;
+        let from = loop {
+            match self.read_byte(steps)? {
+                0xFF => ffs += 1,
+                byte if ffs >= 2 => break byte,
+                _ => ffs = 0,
+            }
+        };
+        // ID, length, error, parameters and checksum
+        let mut raw = [0u8; MAX_PACKET];
+        raw[0] = from;
+        raw[1] = self.read_byte(steps)?;
+        let len = raw[1] as usize;
+        if len < 2 || len + 2 > MAX_PACKET {
+            return Err(Error::Length);
+        }
+        for byte in &mut raw[2..2 + len] {
+            *byte = self.read_byte(steps)?;
+        }
+        if checksum(&raw[..len + 1]) != raw[len + 1] {
+            return Err(Error::Checksum);
+        }
+        if from != id {
+            return Err(Error::Id);
+        }
+        if raw[2] != 0 {
+            return Err(Error::Status(raw[2]));
+        }
+        copy_params(&raw[3..len + 1], response)
+    }
+
+    fn receive_v2(&mut self, id: u8, response: &mut [u8], steps: &mut u32) -> Result<(), Error> {
+        let mut raw = [0u8; MAX_PACKET];
+        let len = loop {
+            self.sync_v2(steps)?;
+            raw[..4].copy_from_slice(&[0xFF, 0xFF, 0xFD, 0x00]);
+            for byte in &mut raw[4..7] {
+                *byte = self.read_byte(steps)?;
+            }
+            let len = u16::from_le_bytes([raw[5], raw[6]]) as usize;
+            // Instruction, error and CRC at least
+            if len < 4 || len + 7 > MAX_PACKET {
+                return Err(Error::Length);
+            }
+            for byte in &mut raw[7..7 + len] {
+                *byte = self.read_byte(steps)?;
+            }
+            // Skip instruction packets from other masters
+            if raw[7] == STATUS {
+                break len;
+            }
+        };
+        let end = 7 + len - 2;
+        if crc16(&raw[..end]) != u16::from_le_bytes([raw[end], raw[end + 1]]) {
+            return Err(Error::Checksum);
+        }
+        if raw[4] != id {
+            return Err(Error::Id);
+        }
+        let error = raw[8];
+        self.alert = error & 0x80 != 0;
+        if error & 0x7F != 0 {
+            return Err(Error::Status(error & 0x7F));
+        }
+
+        // Remove the stuffed 0xFD after each header
+        let mut params = [0u8; MAX_PACKET];
+        let mut count = 0;
+        for (i, byte) in raw.iter().enumerate().take(end).skip(9) {
+            if *byte == 0xFD && i >= 10 && raw[i - 3..i] == HEADER {
+                continue;
+            }
+            params[count] = *byte;
+            count += 1;
+        }
+        copy_params(&params[..count], response)
+    }
+
+    // Wait for FF FF FD 00
+    fn sync_v2(&mut self, steps: &mut u32) -> Result<(), Error> {
+        let mut matched = 0;
+        while matched < 4 {
+            matched = match (matched, self.read_byte(steps)?) {
+                (0 | 1, 0xFF) => matched + 1,
+                (2, 0xFF) => 2,
+                (2, 0xFD) | (3, 0x00) => matched + 1,
+                (_, 0xFF) => 1,
+                _ => 0,
+            };
+        }
+        Ok(())
+    }
+
+    fn read_byte(&mut self, steps: &mut u32) -> Result<u8, Error> {
+        let mut byte = [0u8; 1];
+        loop {
+            if self.uart.read_ready().unwrap_or(false)
+                && self.uart.read(&mut byte).unwrap_or(0) == 1
+            {
+                return Ok(byte[0]);
+            }
+            if *steps == 0 {
+                return Err(Error::Timeout);
+            }
+            *steps -= 1;
+            cortex_m::asm::delay(self.step_cycles);
+        }
+    }
+
+    // Discard anything in the receive buffer
+    fn drain(&mut self) {
+        let mut buf = [0u8; 16];
+        while self.uart.read_ready().unwrap_or(false) {
+            let _ = self.uart.read(&mut buf);
+        }
+    }
+}
+
+fn copy_params(params: &[u8], response: &mut [u8]) -> Result<(), Error> {
+    if params.len() != response.len() {
+        return Err(Error::Length);
+    }
+    response.copy_from_slice(params);
+    Ok(())
+}
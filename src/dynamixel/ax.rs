@@ -0,0 +1,59 @@
+//! Control table of the AX-12A and AX-18A, protocol 1.0.
+//!
+//! Positions are 0 - 1023 over 300 degrees, speeds and loads are 10 bit
+//! magnitudes with the direction in bit 10. EEPROM registers, up to
+//! [`SHUTDOWN`], only take writes while the torque is off.
+
+use super::Register;
+
+// EEPROM
+pub const MODEL_NUMBER: Register<u16> = Register::new(0);
+pub const FIRMWARE_VERSION: Register<u8> = Register::new(2);
+pub const ID: Register<u8> = Register::new(3);
+/// Baud rate = 2000000 / (value + 1)
+pub const BAUD_RATE: Register<u8> = Register::new(4);
+/// Delay before the status packet in units of 2 us
+pub const RETURN_DELAY_TIME: Register<u8> = Register::new(5);
+pub const CW_ANGLE_LIMIT: Register<u16> = Register::new(6);
+pub const CCW_ANGLE_LIMIT: Register<u16> = Register::new(8);
+pub const TEMPERATURE_LIMIT: Register<u8> = Register::new(11);
+pub const MIN_VOLTAGE_LIMIT: Register<u8> = Register::new(12);
+pub const MAX_VOLTAGE_LIMIT: Register<u8> = Register::new(13);
+pub const MAX_TORQUE: Register<u16> = Register::new(14);
+/// 0 answers only pings, 1 also reads, 2 every instruction
+pub const STATUS_RETURN_LEVEL: Register<u8> = Register::new(16);
+pub const ALARM_LED: Register<u8> = Register::new(17);
+pub const SHUTDOWN: Register<u8> = Register::new(18);
+
+// RAM
+pub const TORQUE_ENABLE: Register<u8> = Register::new(24);
+pub const LED: Register<u8> = Register::new(25);
+pub const CW_COMPLIANCE_MARGIN: Register<u8> = Register::new(26);
+pub const CCW_COMPLIANCE_MARGIN: Register<u8> = Register::new(27);
+pub const CW_COMPLIANCE_SLOPE: Register<u8> = Register::new(28);
+pub const CCW_COMPLIANCE_SLOPE: Register<u8> = Register::new(29);
+pub const GOAL_POSITION: Register<u16> = Register::new(30);
+pub const MOVING_SPEED: Register<u16> = Register::new(32);
+pub const TORQUE_LIMIT: Register<u16> = Register::new(34);
+pub const PRESENT_POSITION: Register<u16> = Register::new(36);
+pub const PRESENT_SPEED: Register<u16> = Register::new(38);
+pub const PRESENT_LOAD: Register<u16> = Register::new(40);
+/// In units of 0.1 V
+pub const PRESENT_VOLTAGE: Register<u8> = Register::new(42);
+/// In degrees C
+pub const PRESENT_TEMPERATURE: Register<u8> = Register::new(43);
+pub const REGISTERED: Register<u8> = Register::new(44);
+pub const MOVING: Register<u8> = Register::new(46);
+pub const LOCK: Register<u8> = Register::new(47);
+pub const PUNCH: Register<u16> = Register::new(48);
+
+/// Status error bits.
+pub mod error {
+    pub const INPUT_VOLTAGE: u8 = 1 << 0;
+    pub const ANGLE_LIMIT: u8 = 1 << 1;
+    pub const OVERHEATING: u8 = 1 << 2;
+    pub const RANGE: u8 = 1 << 3;
+    pub const CHECKSUM: u8 = 1 << 4;
+    pub const OVERLOAD: u8 = 1 << 5;
+    pub const INSTRUCTION: u8 = 1 << 6;
+}
@@ -0,0 +1,78 @@
+//! Control table of the X series, e.g. XL430-W250, XM430 and XC430,
+//! protocol 2.0.
+//!
+//! Positions are 0 - 4095 per turn, velocities are in units of 0.229 rpm.
+//! EEPROM registers, up to [`SHUTDOWN`], only take writes while the torque
+//! is off.
+
+use super::Register;
+
+// EEPROM
+pub const MODEL_NUMBER: Register<u16> = Register::new(0);
+pub const MODEL_INFORMATION: Register<u32> = Register::new(2);
+pub const FIRMWARE_VERSION: Register<u8> = Register::new(6);
+pub const ID: Register<u8> = Register::new(7);
+/// 0 is 9600, 1 is 57600, 2 is 115200, 3 is 1 Mbps
+pub const BAUD_RATE: Register<u8> = Register::new(8);
+/// Delay before the status packet in units of 2 us
+pub const RETURN_DELAY_TIME: Register<u8> = Register::new(9);
+pub const DRIVE_MODE: Register<u8> = Register::new(10);
+/// One of the `OPERATING_MODE_*` values
+pub const OPERATING_MODE: Register<u8> = Register::new(11);
+pub const SECONDARY_ID: Register<u8> = Register::new(12);
+pub const PROTOCOL_TYPE: Register<u8> = Register::new(13);
+pub const HOMING_OFFSET: Register<i32> = Register::new(20);
+pub const MOVING_THRESHOLD: Register<u32> = Register::new(24);
+pub const TEMPERATURE_LIMIT: Register<u8> = Register::new(31);
+pub const MAX_VOLTAGE_LIMIT: Register<u16> = Register::new(32);
+pub const MIN_VOLTAGE_LIMIT: Register<u16> = Register::new(34);
+pub const PWM_LIMIT: Register<u16> = Register::new(36);
+pub const VELOCITY_LIMIT: Register<u32> = Register::new(44);
+pub const MAX_POSITION_LIMIT: Register<u32> = Register::new(48);
+pub const MIN_POSITION_LIMIT: Register<u32> = Register::new(52);
+pub const SHUTDOWN: Register<u8> = Register::new(63);
+
+// RAM
+pub const TORQUE_ENABLE: Register<u8> = Register::new(64);
+pub const LED: Register<u8> = Register::new(65);
+/// 0 answers only pings, 1 also reads, 2 every instruction
+pub const STATUS_RETURN_LEVEL: Register<u8> = Register::new(68);
+pub const REGISTERED_INSTRUCTION: Register<u8> = Register::new(69);
+/// Cause of the alert bit, the same bits as [`SHUTDOWN`]
+pub const HARDWARE_ERROR_STATUS: Register<u8> = Register::new(70);
+pub const VELOCITY_I_GAIN: Register<u16> = Register::new(76);
+pub const VELOCITY_P_GAIN: Register<u16> = Register::new(78);
+pub const POSITION_D_GAIN: Register<u16> = Register::new(80);
+pub const POSITION_I_GAIN: Register<u16> = Register::new(82);
+pub const POSITION_P_GAIN: Register<u16> = Register::new(84);
+pub const FEEDFORWARD_2ND_GAIN: Register<u16> = Register::new(88);
+pub const FEEDFORWARD_1ST_GAIN: Register<u16> = Register::new(90);
+/// Torque off after this many 20 ms without an instruction, 0 to disable
+pub const BUS_WATCHDOG: Register<u8> = Register::new(98);
+pub const GOAL_PWM: Register<i16> = Register::new(100);
+pub const GOAL_VELOCITY: Register<i32> = Register::new(104);
+pub const PROFILE_ACCELERATION: Register<u32> = Register::new(108);
+pub const PROFILE_VELOCITY: Register<u32> = Register::new(112);
+pub const GOAL_POSITION: Register<i32> = Register::new(116);
+pub const REALTIME_TICK: Register<u16> = Register::new(120);
+pub const MOVING: Register<u8> = Register::new(122);
+pub const MOVING_STATUS: Register<u8> = Register::new(123);
+pub const PRESENT_PWM: Register<i16> = Register::new(124);
+pub const PRESENT_LOAD: Register<i16> = Register::new(126);
+pub const PRESENT_VELOCITY: Register<i32> = Register::new(128);
+pub const PRESENT_POSITION: Register<i32> = Register::new(132);
+pub const VELOCITY_TRAJECTORY: Register<i32> = Register::new(136);
+pub const POSITION_TRAJECTORY: Register<i32> = Register::new(140);
+/// In units of 0.1 V
+pub const PRESENT_INPUT_VOLTAGE: Register<u16> = Register::new(144);
+/// In degrees C
+pub const PRESENT_TEMPERATURE: Register<u8> = Register::new(146);
+
+/// [`OPERATING_MODE`], [`GOAL_VELOCITY`] is followed.
+pub const OPERATING_MODE_VELOCITY: u8 = 1;
+/// [`OPERATING_MODE`], [`GOAL_POSITION`] within one turn, the default.
+pub const OPERATING_MODE_POSITION: u8 = 3;
+/// [`OPERATING_MODE`], [`GOAL_POSITION`] over several turns.
+pub const OPERATING_MODE_EXTENDED_POSITION: u8 = 4;
+/// [`OPERATING_MODE`], [`GOAL_PWM`] is followed.
+pub const OPERATING_MODE_PWM: u8 = 16;
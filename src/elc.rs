@@ -0,0 +1,86 @@
+//! Event Link Controller (ELC)
+//!
+//! Links an event from one peripheral to another module, which then acts on
+//! it without the CPU, e.g. starting a GPT channel on an ADC conversion end.
+//! Events are the same [`Event`]s as used for interrupts.
+
+use crate::clk::{self, Peripheral};
+use crate::events::Event;
+
+const ELC: usize = 0x4004_1000;
+const ELCR: *mut u8 = ELC as *mut u8;
+// ELSRn are 16 bits, 4 bytes apart
+const ELSR0: usize = ELC + 0x10;
+
+// ELCR.ELCON, all event links enabled
+const ELCR_ELCON: u8 = 1 << 7;
+
+/// ELC input of the GPT, shared by all channels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum GptLink {
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+    G,
+    H,
+}
+
+/// Module an event can be linked to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Target {
+    /// ELC_GPTA - ELC_GPTH
+    Gpt(GptLink),
+    /// ADC140 group A conversion start
+    Adc140A,
+    /// ADC140 group B conversion start
+    Adc140B,
+    Dac12,
+    /// Event input of port 1 - 4
+    Port1,
+    Port2,
+    Port3,
+    Port4,
+    Ctsu,
+}
+
+impl Target {
+    // Number of the ELSRn register
+    fn elsr(self) -> usize {
+        match self {
+            Target::Gpt(link) => link as usize,
+            Target::Adc140A => 8,
+            Target::Adc140B => 9,
+            Target::Dac12 => 12,
+            Target::Port1 => 14,
+            Target::Port2 => 15,
+            Target::Port3 => 16,
+            Target::Port4 => 17,
+            Target::Ctsu => 18,
+        }
+    }
+}
+
+/// Link `event` to `target`, replacing any previous link.
+///
+/// Enables the ELC, which stays enabled.
+pub fn link(target: Target, event: Event) {
+    clk::enable_peripheral(Peripheral::Elc);
+    let elsr = (ELSR0 + 4 * target.elsr()) as *mut u16;
+    unsafe {
+        elsr.write_volatile(event.id() as u16);
+        ELCR.write_volatile(ELCR_ELCON);
+    }
+}
+
+/// Remove the link to `target`.
+pub fn unlink(target: Target) {
+    if clk::is_peripheral_enabled(Peripheral::Elc) {
+        let elsr = (ELSR0 + 4 * target.elsr()) as *mut u16;
+        unsafe { elsr.write_volatile(0) };
+    }
+}
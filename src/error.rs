@@ -0,0 +1,157 @@
+//! Crate level error, for applications using several drivers.
+//!
+//! Each driver has its own error type, e.g. [`uart::Error`] or
+//! [`can::Error`], which converts into [`Error`] so `?` works across them.
+//!
+//! ```ignore
+//! fn log(flash: &mut Flash, can: &Can<Running>, frame: Frame) -> Result<(), uno_r4_rust::Error> {
+//!     flash.erase_data(LOG_ADDR, flash::DATA_BLOCK_SIZE)?;
+//!     can.send_frame(frame)?;
+//!     Ok(())
+//! }
+//! ```
+
+use crate::{
+    bootload, cac, clk, flash, fwupdate, gpio, mpu, shell, shutdown, supervisor, ticker, timeout,
+    xmodem,
+};
+
+/// Error from any driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    #[cfg(feature = "adc")]
+    Adc(crate::adc::Error),
+    Bootload(bootload::Error),
+    Cac(cac::Error),
+    #[cfg(feature = "can")]
+    Can(crate::can::Error),
+    #[cfg(feature = "can")]
+    CanLog(crate::canlog::Error),
+    Clock(clk::Error),
+    #[cfg(feature = "uart")]
+    Dynamixel(crate::dynamixel::Error),
+    Flash(flash::Error),
+    FwUpdate(fwupdate::Error),
+    #[cfg(feature = "can")]
+    Gateway(crate::gateway::Error),
+    #[cfg(feature = "gps")]
+    Gps(crate::gps::Error),
+    Gpio(gpio::Error),
+    #[cfg(feature = "gpt")]
+    Hcsr04(crate::hcsr04::Error),
+    #[cfg(feature = "can")]
+    IsoTp(crate::isotp::Error),
+    #[cfg(feature = "uart")]
+    Lin(crate::lin::Error),
+    #[cfg(feature = "link")]
+    Link(crate::link::Error),
+    Mpu(mpu::Error),
+    #[cfg(feature = "gpt")]
+    Pulse(crate::gpt::pulse::Error),
+    #[cfg(feature = "sdcard")]
+    SdCard(crate::sdcard::Error),
+    Shell(shell::Error),
+    Shutdown(shutdown::Error),
+    #[cfg(feature = "can")]
+    Slcan(crate::slcan::Error),
+    #[cfg(feature = "spi")]
+    Spi(crate::spi::Error),
+    Supervisor(supervisor::Error),
+    Ticker(ticker::Error),
+    Timeout(timeout::Error),
+    #[cfg(feature = "uart")]
+    Uart(crate::uart::Error),
+    #[cfg(feature = "ws2812")]
+    Ws2812(crate::ws2812::Error),
+    Xmodem(xmodem::Error),
+}
+
+macro_rules! impl_from {
+    ($($variant:ident($error:ty),)*) => {
+        $(
+            impl From<$error> for Error {
+                fn from(e: $error) -> Self {
+                    Error::$variant(e)
+                }
+            }
+        )*
+    };
+}
+
+impl_from!(
+    Bootload(bootload::Error),
+    Cac(cac::Error),
+    Clock(clk::Error),
+    Flash(flash::Error),
+    FwUpdate(fwupdate::Error),
+    Gpio(gpio::Error),
+    Mpu(mpu::Error),
+    Shell(shell::Error),
+    Shutdown(shutdown::Error),
+    Supervisor(supervisor::Error),
+    Ticker(ticker::Error),
+    Timeout(timeout::Error),
+    Xmodem(xmodem::Error),
+);
+
+#[cfg(feature = "adc")]
+impl_from!(Adc(crate::adc::Error),);
+
+#[cfg(feature = "can")]
+impl_from!(
+    Can(crate::can::Error),
+    CanLog(crate::canlog::Error),
+    Gateway(crate::gateway::Error),
+    IsoTp(crate::isotp::Error),
+    Slcan(crate::slcan::Error),
+);
+
+#[cfg(feature = "gpt")]
+impl_from!(
+    Hcsr04(crate::hcsr04::Error),
+    Pulse(crate::gpt::pulse::Error),
+);
+
+#[cfg(feature = "spi")]
+impl_from!(Spi(crate::spi::Error),);
+
+#[cfg(feature = "uart")]
+impl_from!(
+    Dynamixel(crate::dynamixel::Error),
+    Lin(crate::lin::Error),
+    Uart(crate::uart::Error),
+);
+
+#[cfg(feature = "gps")]
+impl_from!(Gps(crate::gps::Error),);
+
+#[cfg(feature = "link")]
+impl_from!(Link(crate::link::Error),);
+
+#[cfg(feature = "sdcard")]
+impl_from!(SdCard(crate::sdcard::Error),);
+
+#[cfg(feature = "ws2812")]
+impl_from!(Ws2812(crate::ws2812::Error),);
+
+// Single failure types convert through the driver error
+impl From<clk::InUse> for Error {
+    fn from(e: clk::InUse) -> Self {
+        Error::Clock(e.into())
+    }
+}
+
+#[cfg(feature = "can")]
+impl From<crate::can::QueueFull> for Error {
+    fn from(e: crate::can::QueueFull) -> Self {
+        Error::Can(e.into())
+    }
+}
+
+#[cfg(feature = "can")]
+impl From<crate::can::ModeTimeout> for Error {
+    fn from(e: crate::can::ModeTimeout) -> Self {
+        Error::Can(e.into())
+    }
+}
@@ -0,0 +1,237 @@
+//! Event numbers of the ICU and the ELC.
+//!
+//! Every peripheral event of the RA4M1 has a number, used to route it to an
+//! interrupt ([`map_interrupt`](crate::interrupts::map_interrupt)), a DMAC
+//! channel or another module through the [`elc`](crate::elc). [`Event`]
+//! names them as in the user's manual, so a wrong number can't be written:
+//!
+//! ```ignore
+//! interrupts::map_and_enable_interrupt(Interrupt::IEL5, Event::CAN0_ERS);
+//! // Peripherals with several channels number their events consecutively
+//! let overflow = Event::GPT0_CCMPA.offset(8 * channel + 6);
+//! ```
+//!
+//! Generated from Table 13.4 "Event table" of the RA4M1 User's Manual
+//! (R01UH0887EJ0110 Rev.1.10). Numbers 0x0E, 0x16 and 0x40 aren't used.
+
+macro_rules! events {
+    ($($name:ident = $id:literal,)*) => {
+        /// A peripheral event, its value is the event number.
+        #[allow(non_camel_case_types)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+        #[repr(u8)]
+        pub enum Event {
+            $($name = $id,)*
+        }
+
+        impl Event {
+            /// The event with number `id`, `None` for a number that isn't used.
+            pub const fn from_id(id: u8) -> Option<Self> {
+                match id {
+                    $($id => Some(Event::$name),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+events! {
+    PORT_IRQ0 = 0x01,
+    PORT_IRQ1 = 0x02,
+    PORT_IRQ2 = 0x03,
+    PORT_IRQ3 = 0x04,
+    PORT_IRQ4 = 0x05,
+    PORT_IRQ5 = 0x06,
+    PORT_IRQ6 = 0x07,
+    PORT_IRQ7 = 0x08,
+    PORT_IRQ8 = 0x09,
+    PORT_IRQ9 = 0x0A,
+    PORT_IRQ10 = 0x0B,
+    PORT_IRQ11 = 0x0C,
+    PORT_IRQ12 = 0x0D,
+    PORT_IRQ14 = 0x0F,
+    PORT_IRQ15 = 0x10,
+    DMAC0_INT = 0x11,
+    DMAC1_INT = 0x12,
+    DMAC2_INT = 0x13,
+    DMAC3_INT = 0x14,
+    DTC_COMPLETE = 0x15,
+    ICU_SNZCANCEL = 0x17,
+    FCU_FRDYI = 0x18,
+    LVD_LVD1 = 0x19,
+    LVD_LVD2 = 0x1A,
+    VBATT_LVD = 0x1B,
+    MOSC_STOP = 0x1C,
+    SYSTEM_SNZREQ = 0x1D,
+    AGT0_AGTI = 0x1E,
+    AGT0_AGTCMAI = 0x1F,
+    AGT0_AGTCMBI = 0x20,
+    AGT1_AGTI = 0x21,
+    AGT1_AGTCMAI = 0x22,
+    AGT1_AGTCMBI = 0x23,
+    IWDT_NMIUNDF = 0x24,
+    WDT_NMIUNDF = 0x25,
+    RTC_ALM = 0x26,
+    RTC_PRD = 0x27,
+    RTC_CUP = 0x28,
+    ADC140_ADI = 0x29,
+    ADC140_GBADI = 0x2A,
+    ADC140_CMPAI = 0x2B,
+    ADC140_CMPBI = 0x2C,
+    ADC140_WCMPM = 0x2D,
+    ADC140_WCMPUM = 0x2E,
+    ACMP_LP0 = 0x2F,
+    ACMP_LP1 = 0x30,
+    USBFS_D0FIFO = 0x31,
+    USBFS_D1FIFO = 0x32,
+    USBFS_USBI = 0x33,
+    USBFS_USBR = 0x34,
+    IIC0_RXI = 0x35,
+    IIC0_TXI = 0x36,
+    IIC0_TEI = 0x37,
+    IIC0_EEI = 0x38,
+    IIC0_WUI = 0x39,
+    IIC1_RXI = 0x3A,
+    IIC1_TXI = 0x3B,
+    IIC1_TEI = 0x3C,
+    IIC1_EEI = 0x3D,
+    SSIE0_SSITXI = 0x3E,
+    SSIE0_SSIRXI = 0x3F,
+    SSIE0_SSIF = 0x41,
+    CTSU_CTSUWR = 0x42,
+    CTSU_CTSURD = 0x43,
+    CTSU_CTSUFN = 0x44,
+    KEY_INTKR = 0x45,
+    DOC_DOPCI = 0x46,
+    CAC_FERRI = 0x47,
+    CAC_MENDI = 0x48,
+    CAC_OVFI = 0x49,
+    CAN0_ERS = 0x4A,
+    CAN0_RXF = 0x4B,
+    CAN0_TXF = 0x4C,
+    CAN0_RXM = 0x4D,
+    CAN0_TXM = 0x4E,
+    IOPORT_GROUP1 = 0x4F,
+    IOPORT_GROUP2 = 0x50,
+    IOPORT_GROUP3 = 0x51,
+    IOPORT_GROUP4 = 0x52,
+    ELC_SWEVT0 = 0x53,
+    ELC_SWEVT1 = 0x54,
+    POEG_GROUP0 = 0x55,
+    POEG_GROUP1 = 0x56,
+    GPT0_CCMPA = 0x57,
+    GPT0_CCMPB = 0x58,
+    GPT0_CMPC = 0x59,
+    GPT0_CMPD = 0x5A,
+    GPT0_CMPE = 0x5B,
+    GPT0_CMPF = 0x5C,
+    GPT0_OVF = 0x5D,
+    GPT0_UDF = 0x5E,
+    GPT1_CCMPA = 0x5F,
+    GPT1_CCMPB = 0x60,
+    GPT1_CMPC = 0x61,
+    GPT1_CMPD = 0x62,
+    GPT1_CMPE = 0x63,
+    GPT1_CMPF = 0x64,
+    GPT1_OVF = 0x65,
+    GPT1_UDF = 0x66,
+    GPT2_CCMPA = 0x67,
+    GPT2_CCMPB = 0x68,
+    GPT2_CMPC = 0x69,
+    GPT2_CMPD = 0x6A,
+    GPT2_CMPE = 0x6B,
+    GPT2_CMPF = 0x6C,
+    GPT2_OVF = 0x6D,
+    GPT2_UDF = 0x6E,
+    GPT3_CCMPA = 0x6F,
+    GPT3_CCMPB = 0x70,
+    GPT3_CMPC = 0x71,
+    GPT3_CMPD = 0x72,
+    GPT3_CMPE = 0x73,
+    GPT3_CMPF = 0x74,
+    GPT3_OVF = 0x75,
+    GPT3_UDF = 0x76,
+    GPT4_CCMPA = 0x77,
+    GPT4_CCMPB = 0x78,
+    GPT4_CMPC = 0x79,
+    GPT4_CMPD = 0x7A,
+    GPT4_CMPE = 0x7B,
+    GPT4_CMPF = 0x7C,
+    GPT4_OVF = 0x7D,
+    GPT4_UDF = 0x7E,
+    GPT5_CCMPA = 0x7F,
+    GPT5_CCMPB = 0x80,
+    GPT5_CMPC = 0x81,
+    GPT5_CMPD = 0x82,
+    GPT5_CMPE = 0x83,
+    GPT5_CMPF = 0x84,
+    GPT5_OVF = 0x85,
+    GPT5_UDF = 0x86,
+    GPT6_CCMPA = 0x87,
+    GPT6_CCMPB = 0x88,
+    GPT6_CMPC = 0x89,
+    GPT6_CMPD = 0x8A,
+    GPT6_CMPE = 0x8B,
+    GPT6_CMPF = 0x8C,
+    GPT6_OVF = 0x8D,
+    GPT6_UDF = 0x8E,
+    GPT7_CCMPA = 0x8F,
+    GPT7_CCMPB = 0x90,
+    GPT7_CMPC = 0x91,
+    GPT7_CMPD = 0x92,
+    GPT7_CMPE = 0x93,
+    GPT7_CMPF = 0x94,
+    GPT7_OVF = 0x95,
+    GPT7_UDF = 0x96,
+    GPT_UVWEDGE = 0x97,
+    SCI0_RXI = 0x98,
+    SCI0_TXI = 0x99,
+    SCI0_TEI = 0x9A,
+    SCI0_ERI = 0x9B,
+    SCI0_AM = 0x9C,
+    SCI0_RXI_OR_ERI = 0x9D,
+    SCI1_RXI = 0x9E,
+    SCI1_TXI = 0x9F,
+    SCI1_TEI = 0xA0,
+    SCI1_ERI = 0xA1,
+    SCI1_AM = 0xA2,
+    SCI2_RXI = 0xA3,
+    SCI2_TXI = 0xA4,
+    SCI2_TEI = 0xA5,
+    SCI2_ERI = 0xA6,
+    SCI2_AM = 0xA7,
+    SCI9_RXI = 0xA8,
+    SCI9_TXI = 0xA9,
+    SCI9_TEI = 0xAA,
+    SCI9_ERI = 0xAB,
+    SCI9_AM = 0xAC,
+    SPI0_SPRI = 0xAD,
+    SPI0_SPTI = 0xAE,
+    SPI0_SPII = 0xAF,
+    SPI0_SPEI = 0xB0,
+    SPI0_SPTEND = 0xB1,
+    SPI1_SPRI = 0xB2,
+    SPI1_SPTI = 0xB3,
+    SPI1_SPII = 0xB4,
+    SPI1_SPEI = 0xB5,
+    SPI1_SPTEND = 0xB6,
+}
+
+impl Event {
+    /// The event number, as written to IELSRn, DELSRn and ELSRn.
+    pub const fn id(self) -> u8 {
+        self as u8
+    }
+
+    /// The event `n` numbers after this one, for the consecutive events of
+    /// a peripheral. Panics, at compile time in a constant, if that number
+    /// isn't used.
+    pub const fn offset(self, n: u8) -> Self {
+        match Self::from_id(self as u8 + n) {
+            Some(event) => event,
+            None => panic!("no event at this offset"),
+        }
+    }
+}
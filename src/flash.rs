@@ -0,0 +1,486 @@
+//! Code and data flash programming and erasure.
+//!
+//! The RA4M1 can't read code flash while it's in programming / erasure
+//! (P/E) mode, so each operation runs from RAM with interrupts disabled,
+//! from entering P/E mode until flash is readable again. The vector table
+//! and any interrupt handlers are in flash, so interrupts stay disabled for
+//! the whole operation, about 20 ms per erased block. They're disabled with
+//! PRIMASK, as a BASEPRI critical section would let interrupts above the
+//! ceiling run.
+//!
+//! The data to program must be in RAM.
+//!
+//! Programming is in units of [`WRITE_SIZE`] bytes and erasing in blocks of
+//! [`BLOCK_SIZE`] bytes. Erased flash reads as `0xFF`.
+//!
+//! The 8 KB data flash is programmed a byte at a time and erased in blocks
+//! of [`DATA_BLOCK_SIZE`] bytes. Code flash stays readable meanwhile, so data
+//! flash operations run from flash with interrupts enabled.
+
+/// Size of the code flash.
+pub const FLASH_SIZE: u32 = 256 * 1024;
+
+/// Erase block size.
+pub const BLOCK_SIZE: u32 = 2048;
+
+/// Programming unit, addresses and lengths must be multiples of this.
+pub const WRITE_SIZE: u32 = 8;
+
+/// Start of the data flash, as read by the CPU.
+pub const DATA_FLASH_START: u32 = 0x4010_0000;
+
+/// Size of the data flash.
+pub const DATA_FLASH_SIZE: u32 = 8 * 1024;
+
+/// Data flash erase block size.
+pub const DATA_BLOCK_SIZE: u32 = 1024;
+
+/// Flash errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// Address or length isn't a multiple of the block or write size
+    Alignment,
+    /// The range isn't inside the code or data flash
+    Range,
+    /// The flash reported an erase error
+    Erase,
+    /// The flash reported a programming error
+    Program,
+    /// A command or illegal command error, e.g. outside the access window
+    Command,
+    /// The flash didn't finish in time
+    Timeout,
+}
+
+// Flash application command interface registers (FACI)
+const FACI: usize = 0x407E_C000;
+const FPMCR: *mut u8 = (FACI + 0x100) as *mut u8;
+const FASR: *mut u8 = (FACI + 0x104) as *mut u8;
+const FSARL: *mut u16 = (FACI + 0x108) as *mut u16;
+const FSARH: *mut u16 = (FACI + 0x110) as *mut u16;
+const FCR: *mut u8 = (FACI + 0x114) as *mut u8;
+const FEARL: *mut u16 = (FACI + 0x118) as *mut u16;
+const FEARH: *mut u16 = (FACI + 0x120) as *mut u16;
+const FRESETR: *mut u8 = (FACI + 0x124) as *mut u8;
+const FSTATR1: *mut u8 = (FACI + 0x12C) as *mut u8;
+const FWBL0: *mut u16 = (FACI + 0x130) as *mut u16;
+const FWBH0: *mut u16 = (FACI + 0x138) as *mut u16;
+const FWBL1: *mut u16 = (FACI + 0x140) as *mut u16;
+const FWBH1: *mut u16 = (FACI + 0x144) as *mut u16;
+const FPR: *mut u8 = (FACI + 0x180) as *mut u8;
+const FISR: *mut u8 = (FACI + 0x1D8) as *mut u8;
+const FSTATR2: *mut u16 = (FACI + 0x1F0) as *mut u16;
+const FENTRYR: *mut u16 = 0x407E_FFB0 as *mut u16;
+
+// Flash cache
+const FCACHEE: *mut u16 = 0x4001_C100 as *mut u16;
+const FCACHEIV: *mut u16 = 0x4001_C104 as *mut u16;
+
+// FENTRYR values, key in the upper byte
+const FENTRYR_READ: u16 = 0xAA00;
+const FENTRYR_CF_PE: u16 = 0xAA01;
+const FENTRYR_DF_PE: u16 = 0xAA80;
+
+// Data flash address used by the P/E commands, rather than the read address
+const DATA_FLASH_PE: u32 = 0xFE00_0000;
+
+// FPMCR mode values (FMS2, FMS1, FMS0 and RPDIS)
+const FPMCR_READ: u8 = 0x08;
+const FPMCR_DISCHARGE_1: u8 = 0x12;
+const FPMCR_DISCHARGE_2: u8 = 0x92;
+const FPMCR_CF_PE: u8 = 0x82;
+const FPMCR_DF_PE: u8 = 0x10;
+
+// FCR commands, OPST set to start
+const FCR_PROGRAM: u8 = 0x81;
+const FCR_ERASE: u8 = 0x84;
+
+// FSTATR1.FRDY
+const FSTATR1_FRDY: u8 = 1 << 6;
+
+// FSTATR2 error flags
+const FSTATR2_ERERR: u16 = 1 << 0;
+const FSTATR2_PRGERR: u16 = 1 << 1;
+const FSTATR2_ILGLERR: u16 = 1 << 4;
+const FSTATR2_EILGLERR: u16 = 1 << 5;
+
+// Longest wait for FRDY in polling loops, well above the worst case erase time
+const READY_TIMEOUT: u32 = 10_000_000;
+
+/// Flash driver, for both the code and data flash.
+pub struct Flash {
+    fclk_mhz: u8,
+    iclk_mhz: u8,
+}
+
+impl Flash {
+    /// `fclk_hz` is the frequency of FCLK, 1 - 32 MHz, 24 MHz on the UNO R4
+    /// with the Arduino clock setup. `iclk_hz` is the CPU clock, used to
+    /// time the mode changes.
+    pub fn new(fclk_hz: u32, iclk_hz: u32) -> Self {
+        Self {
+            fclk_mhz: (fclk_hz / 1_000_000).clamp(1, 32) as u8,
+            iclk_mhz: iclk_hz.div_ceil(1_000_000).max(1) as u8,
+        }
+    }
+
+    /// Erase the blocks in `start..start + len`, both multiples of [`BLOCK_SIZE`].
+    pub fn erase(&mut self, start: u32, len: u32) -> Result<(), Error> {
+        check_range(start, len, BLOCK_SIZE)?;
+        let status = cortex_m::interrupt::free(|_| unsafe {
+            ram_erase(self.fclk_mhz, self.iclk_mhz, start, len)
+        });
+        invalidate_cache();
+        decode(status, Error::Erase)
+    }
+
+    /// Program `data` at `addr`, which must already be erased.
+    ///
+    /// `addr` and the length of `data` must be multiples of [`WRITE_SIZE`]
+    /// and `data` must be in RAM.
+    pub fn program(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        check_range(addr, data.len() as u32, WRITE_SIZE)?;
+        let status = cortex_m::interrupt::free(|_| unsafe {
+            ram_program(
+                self.fclk_mhz,
+                self.iclk_mhz,
+                addr,
+                data.as_ptr(),
+                data.len() as u32,
+            )
+        });
+        invalidate_cache();
+        decode(status, Error::Program)
+    }
+
+    /// Erase `dst..dst + len` then copy the same length from `src` into it,
+    /// block by block. Both must be multiples of [`BLOCK_SIZE`] and the
+    /// ranges must not overlap.
+    ///
+    /// Runs entirely from RAM with interrupts disabled, so it can replace
+    /// the code that called it. That code must not be returned to, call
+    /// with `reset` set to reset the MCU once the copy is complete.
+    ///
+    /// ## Safety
+    /// With `reset` clear the caller's own code must be outside `dst`.
+    pub unsafe fn copy(&mut self, dst: u32, src: u32, len: u32, reset: bool) -> Result<(), Error> {
+        check_range(dst, len, BLOCK_SIZE)?;
+        check_range(src, len, BLOCK_SIZE)?;
+        if dst < src + len && src < dst + len {
+            return Err(Error::Range);
+        }
+        let status = cortex_m::interrupt::free(|_| unsafe {
+            ram_copy(self.fclk_mhz, self.iclk_mhz, dst, src, len, reset)
+        });
+        invalidate_cache();
+        decode(status, Error::Program)
+    }
+
+    /// Erase the data flash blocks in `start..start + len`, read addresses
+    /// from [`DATA_FLASH_START`] that are multiples of [`DATA_BLOCK_SIZE`].
+    pub fn erase_data(&mut self, start: u32, len: u32) -> Result<(), Error> {
+        let pe = check_data_range(start, len, DATA_BLOCK_SIZE)?;
+        let status = unsafe {
+            enter_data_pe(self.fclk_mhz, self.iclk_mhz);
+            let mut status = 0;
+            let mut addr = pe;
+            while status == 0 && addr < pe + len {
+                status = erase_range(addr, addr + DATA_BLOCK_SIZE - 1);
+                addr += DATA_BLOCK_SIZE;
+            }
+            exit_data_pe(self.iclk_mhz);
+            status
+        };
+        decode(status, Error::Erase)
+    }
+
+    /// Program `data` at `addr` in the data flash, which must already be
+    /// erased. Any address and length inside the data flash can be used.
+    pub fn program_data(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        let pe = check_data_range(addr, data.len() as u32, 1)?;
+        let status = unsafe {
+            enter_data_pe(self.fclk_mhz, self.iclk_mhz);
+            let mut status = 0;
+            for (offset, byte) in data.iter().enumerate() {
+                let dst = pe + offset as u32;
+                FSARH.write_volatile((dst >> 16) as u16);
+                FSARL.write_volatile(dst as u16);
+                FWBL0.write_volatile(*byte as u16);
+                status = run_command(FCR_PROGRAM);
+                if status != 0 {
+                    break;
+                }
+            }
+            exit_data_pe(self.iclk_mhz);
+            status
+        };
+        decode(status, Error::Program)
+    }
+}
+
+// Check a data flash range and return its P/E address
+fn check_data_range(start: u32, len: u32, align: u32) -> Result<u32, Error> {
+    let offset = start.checked_sub(DATA_FLASH_START).ok_or(Error::Range)?;
+    if offset % align != 0 || len % align != 0 {
+        return Err(Error::Alignment);
+    }
+    match offset.checked_add(len) {
+        Some(end) if end <= DATA_FLASH_SIZE => Ok(DATA_FLASH_PE + offset),
+        _ => Err(Error::Range),
+    }
+}
+
+fn check_range(start: u32, len: u32, align: u32) -> Result<(), Error> {
+    if start % align != 0 || len % align != 0 {
+        return Err(Error::Alignment);
+    }
+    match start.checked_add(len) {
+        Some(end) if end <= FLASH_SIZE => Ok(()),
+        _ => Err(Error::Range),
+    }
+}
+
+// Status returned by the RAM functions, FSTATR2 or TIMEOUT
+const TIMEOUT: u32 = 1 << 16;
+
+fn decode(status: u32, error: Error) -> Result<(), Error> {
+    if status & TIMEOUT != 0 {
+        Err(Error::Timeout)
+    } else if status as u16 & (FSTATR2_ILGLERR | FSTATR2_EILGLERR) != 0 {
+        Err(Error::Command)
+    } else if status as u16 & FSTATR2_ERERR != 0 {
+        Err(Error::Erase)
+    } else if status as u16 & FSTATR2_PRGERR != 0 {
+        Err(error)
+    } else {
+        Ok(())
+    }
+}
+
+// Invalidate the flash cache so the new contents are read
+fn invalidate_cache() {
+    unsafe {
+        FCACHEE.write_volatile(0);
+        FCACHEIV.write_volatile(1);
+        while FCACHEIV.read_volatile() & 1 != 0 {}
+        FCACHEE.write_volatile(1);
+    }
+}
+
+// Everything below runs while code flash can't be read. The functions are
+// placed in .data, which cortex-m-rt copies to RAM at startup, and only
+// call #[inline(always)] helpers so nothing is fetched from flash.
+
+#[inline(always)]
+unsafe fn delay_us(iclk_mhz: u8, us: u32) {
+    // At least one cycle per iteration
+    let mut n = us * iclk_mhz as u32;
+    while n > 0 {
+        unsafe { core::arch::asm!("nop") };
+        n -= 1;
+    }
+}
+
+// FPMCR is protected, written through FPR, value, inverse, value
+#[inline(always)]
+unsafe fn write_fpmcr(value: u8) {
+    unsafe {
+        FPR.write_volatile(0xA5);
+        FPMCR.write_volatile(value);
+        FPMCR.write_volatile(!value);
+        FPMCR.write_volatile(value);
+    }
+}
+
+#[inline(always)]
+unsafe fn enter_pe(fclk_mhz: u8, iclk_mhz: u8) {
+    unsafe {
+        FENTRYR.write_volatile(FENTRYR_CF_PE);
+        // FISR.PCKA, FCLK in MHz - 1
+        FISR.write_volatile((FISR.read_volatile() & !0x1F) | (fclk_mhz - 1));
+        write_fpmcr(FPMCR_DISCHARGE_1);
+        // tDIS
+        delay_us(iclk_mhz, 2);
+        write_fpmcr(FPMCR_DISCHARGE_2);
+        write_fpmcr(FPMCR_CF_PE);
+        // tMS, high-speed mode
+        delay_us(iclk_mhz, 15);
+        // User area
+        FASR.write_volatile(0);
+    }
+}
+
+#[inline(always)]
+unsafe fn exit_pe(iclk_mhz: u8) {
+    unsafe {
+        write_fpmcr(FPMCR_DISCHARGE_2);
+        delay_us(iclk_mhz, 2);
+        write_fpmcr(FPMCR_DISCHARGE_1);
+        write_fpmcr(FPMCR_READ);
+        delay_us(iclk_mhz, 5);
+        FENTRYR.write_volatile(FENTRYR_READ);
+        while FENTRYR.read_volatile() != 0 {}
+    }
+}
+
+// Data flash P/E mode, code flash can still be read
+#[inline(always)]
+unsafe fn enter_data_pe(fclk_mhz: u8, iclk_mhz: u8) {
+    unsafe {
+        FENTRYR.write_volatile(FENTRYR_DF_PE);
+        FISR.write_volatile((FISR.read_volatile() & !0x1F) | (fclk_mhz - 1));
+        write_fpmcr(FPMCR_DF_PE);
+        // tDSTOP
+        delay_us(iclk_mhz, 1);
+        FASR.write_volatile(0);
+    }
+}
+
+#[inline(always)]
+unsafe fn exit_data_pe(iclk_mhz: u8) {
+    unsafe {
+        write_fpmcr(FPMCR_READ);
+        delay_us(iclk_mhz, 5);
+        FENTRYR.write_volatile(FENTRYR_READ);
+        while FENTRYR.read_volatile() != 0 {}
+    }
+}
+
+// Run the command set up in the address and data registers,
+// returns FSTATR2 or TIMEOUT.
+#[inline(always)]
+unsafe fn run_command(command: u8) -> u32 {
+    unsafe {
+        FCR.write_volatile(command);
+        let mut wait = 0;
+        while FSTATR1.read_volatile() & FSTATR1_FRDY == 0 {
+            wait += 1;
+            if wait > READY_TIMEOUT {
+                return TIMEOUT;
+            }
+        }
+        FCR.write_volatile(0);
+        while FSTATR1.read_volatile() & FSTATR1_FRDY != 0 {}
+        let status = FSTATR2.read_volatile();
+        if status != 0 {
+            // Clear the error flags
+            FRESETR.write_volatile(1);
+            FRESETR.write_volatile(0);
+        }
+        status as u32
+    }
+}
+
+// Erase the block from `start` to `end`, inclusive
+#[inline(always)]
+unsafe fn erase_range(start: u32, end: u32) -> u32 {
+    unsafe {
+        FSARH.write_volatile((start >> 16) as u16);
+        FSARL.write_volatile(start as u16);
+        FEARH.write_volatile((end >> 16) as u16);
+        FEARL.write_volatile(end as u16);
+        run_command(FCR_ERASE)
+    }
+}
+
+#[inline(always)]
+unsafe fn erase_blocks(start: u32, len: u32) -> u32 {
+    let mut addr = start;
+    while addr < start + len {
+        let status = unsafe { erase_range(addr, addr + BLOCK_SIZE - 1) };
+        if status != 0 {
+            return status;
+        }
+        addr += BLOCK_SIZE;
+    }
+    0
+}
+
+#[inline(always)]
+unsafe fn program_units(addr: u32, src: *const u16, len: u32) -> u32 {
+    let mut offset = 0;
+    while offset < len {
+        unsafe {
+            let dst = addr + offset;
+            let src = src.add(offset as usize / 2);
+            FSARH.write_volatile((dst >> 16) as u16);
+            FSARL.write_volatile(dst as u16);
+            FWBL0.write_volatile(src.read_unaligned());
+            FWBH0.write_volatile(src.add(1).read_unaligned());
+            FWBL1.write_volatile(src.add(2).read_unaligned());
+            FWBH1.write_volatile(src.add(3).read_unaligned());
+            let status = run_command(FCR_PROGRAM);
+            if status != 0 {
+                return status;
+            }
+        }
+        offset += WRITE_SIZE;
+    }
+    0
+}
+
+#[unsafe(link_section = ".data.flash_ram_erase")]
+#[inline(never)]
+unsafe fn ram_erase(fclk_mhz: u8, iclk_mhz: u8, start: u32, len: u32) -> u32 {
+    unsafe {
+        enter_pe(fclk_mhz, iclk_mhz);
+        let status = erase_blocks(start, len);
+        exit_pe(iclk_mhz);
+        status
+    }
+}
+
+#[unsafe(link_section = ".data.flash_ram_program")]
+#[inline(never)]
+unsafe fn ram_program(fclk_mhz: u8, iclk_mhz: u8, addr: u32, src: *const u8, len: u32) -> u32 {
+    unsafe {
+        enter_pe(fclk_mhz, iclk_mhz);
+        let status = program_units(addr, src as *const u16, len);
+        exit_pe(iclk_mhz);
+        status
+    }
+}
+
+#[unsafe(link_section = ".data.flash_ram_copy")]
+#[inline(never)]
+unsafe fn ram_copy(fclk_mhz: u8, iclk_mhz: u8, dst: u32, src: u32, len: u32, reset: bool) -> u32 {
+    // One block at a time through a RAM buffer, as flash can't be read in P/E mode
+    // Uninitialised, zeroing could call memset in flash
+    let mut buf = core::mem::MaybeUninit::<[u16; BLOCK_SIZE as usize / 2]>::uninit();
+    let buf = buf.as_mut_ptr() as *mut u16;
+    let mut offset = 0;
+    while offset < len {
+        unsafe {
+            let from = (src + offset) as *const u16;
+            let mut i = 0;
+            while i < BLOCK_SIZE as usize / 2 {
+                buf.add(i).write(from.add(i).read_volatile());
+                i += 1;
+            }
+            enter_pe(fclk_mhz, iclk_mhz);
+            let mut status = erase_blocks(dst + offset, BLOCK_SIZE);
+            if status == 0 {
+                status = program_units(dst + offset, buf, BLOCK_SIZE);
+            }
+            exit_pe(iclk_mhz);
+            if status != 0 {
+                return status;
+            }
+        }
+        offset += BLOCK_SIZE;
+    }
+    if reset {
+        // SCB AIRCR, SYSRESETREQ with the key, keeping PRIGROUP
+        let aircr = 0xE000_ED0C as *mut u32;
+        unsafe {
+            #[cfg(target_arch = "arm")]
+            core::arch::asm!("dsb");
+            aircr.write_volatile((0x05FA << 16) | (aircr.read_volatile() & 0x700) | (1 << 2));
+            #[cfg(target_arch = "arm")]
+            core::arch::asm!("dsb");
+        }
+        loop {}
+    }
+    0
+}
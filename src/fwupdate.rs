@@ -0,0 +1,197 @@
+//! Firmware update through the upper half of code flash.
+//!
+//! A new image is streamed into the staging area, the upper 128 KB of flash,
+//! from any transport, e.g. UART bytes or CAN frame data. Once it's complete
+//! and its CRC matches, [`Updater::apply`] copies it over the application and
+//! resets. The copy runs from RAM, so it can replace the code doing the update.
+//!
+//! The application must fit below the staging area, link it with
+//! `examples/memory-fwupdate.x` (the `fwupdate` feature of the examples).
+//!
+//! Interrupts are disabled while flash is erased or programmed, about 20 ms
+//! each time a new block is started, so bytes arriving meanwhile can be lost.
+//! The sender should wait for an acknowledgement after each chunk:
+//!
+//! ```ignore
+//! let mut updater = fwupdate::Updater::new(flash::Flash::new(24_000_000, 48_000_000));
+//! updater.begin(image_len)?;
+//! while let Some(chunk) = receive_chunk(&mut rx) {
+//!     updater.write(&chunk)?;
+//!     tx.write_all(b"K")?;
+//! }
+//! updater.finish(image_crc)?;
+//! unsafe { updater.apply() }
+//! ```
+
+use crate::flash::{self, BLOCK_SIZE, FLASH_SIZE, Flash, WRITE_SIZE};
+
+/// Start of the application, after the Arduino bootloader.
+pub const APP_START: u32 = 0x4000;
+
+/// Start of the staging area.
+pub const STAGING_START: u32 = FLASH_SIZE / 2;
+
+/// Largest image, the application area below the staging area.
+pub const MAX_IMAGE: u32 = STAGING_START - APP_START;
+
+/// Update errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// Programming the staging area failed
+    Flash(flash::Error),
+    /// The image is larger than [`MAX_IMAGE`]
+    TooLarge,
+    /// More or less data was written than the length given to `begin`
+    Length,
+    /// The staged image doesn't match the CRC
+    Crc,
+    /// `begin` hasn't been called, or `finish` hasn't succeeded before `apply`
+    State,
+}
+
+impl From<flash::Error> for Error {
+    fn from(e: flash::Error) -> Self {
+        Error::Flash(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Idle,
+    Receiving,
+    Verified,
+}
+
+/// Receives an image into the staging area and installs it.
+pub struct Updater {
+    flash: Flash,
+    stage: Stage,
+    len: u32,
+    written: u32,
+    // Partial programming unit
+    unit: [u8; WRITE_SIZE as usize],
+    unit_len: usize,
+}
+
+impl Updater {
+    pub fn new(flash: Flash) -> Self {
+        Self {
+            flash,
+            stage: Stage::Idle,
+            len: 0,
+            written: 0,
+            unit: [0xFF; WRITE_SIZE as usize],
+            unit_len: 0,
+        }
+    }
+
+    /// Start receiving an image of `len` bytes, discarding any previous one.
+    pub fn begin(&mut self, len: u32) -> Result<(), Error> {
+        if len == 0 || len > MAX_IMAGE {
+            return Err(Error::TooLarge);
+        }
+        self.stage = Stage::Receiving;
+        self.len = len;
+        self.written = 0;
+        self.unit_len = 0;
+        Ok(())
+    }
+
+    /// Add the next part of the image.
+    pub fn write(&mut self, mut data: &[u8]) -> Result<(), Error> {
+        if self.stage != Stage::Receiving {
+            return Err(Error::State);
+        }
+        if self.written + self.unit_len as u32 + data.len() as u32 > self.len {
+            return Err(Error::Length);
+        }
+        while !data.is_empty() {
+            let n = data.len().min(self.unit.len() - self.unit_len);
+            self.unit[self.unit_len..self.unit_len + n].copy_from_slice(&data[..n]);
+            self.unit_len += n;
+            data = &data[n..];
+            if self.unit_len == self.unit.len() {
+                self.program_unit()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Program the rest of the image and check it against the CRC-32
+    /// (IEEE, as used by zlib and Ethernet) of the whole image.
+    pub fn finish(&mut self, crc: u32) -> Result<(), Error> {
+        if self.stage != Stage::Receiving {
+            return Err(Error::State);
+        }
+        if self.unit_len > 0 {
+            self.unit[self.unit_len..].fill(0xFF);
+            self.program_unit()?;
+        }
+        if self.written < self.len {
+            return Err(Error::Length);
+        }
+        let staged =
+            unsafe { core::slice::from_raw_parts(STAGING_START as *const u8, self.len as usize) };
+        if crc32(staged) != crc {
+            self.stage = Stage::Idle;
+            return Err(Error::Crc);
+        }
+        self.stage = Stage::Verified;
+        Ok(())
+    }
+
+    /// Copy the verified image over the application and reset.
+    ///
+    /// Only returns if the image hasn't been verified with [`finish`](Self::finish).
+    /// Every interrupt is disabled from the start of the copy until the
+    /// reset, see [`Flash::copy`].
+    /// If power is lost during the copy the application is left incomplete
+    /// and has to be reprogrammed through the bootloader.
+    ///
+    /// ## Safety
+    /// Nothing may use flash at the application area afterwards, the MCU
+    /// resets as soon as the copy is done.
+    pub unsafe fn apply(mut self) -> Error {
+        if self.stage != Stage::Verified {
+            return Error::State;
+        }
+        let len = self.len.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        match unsafe { self.flash.copy(APP_START, STAGING_START, len, true) } {
+            Err(e) => Error::Flash(e),
+            // Not reached, the copy resets
+            Ok(()) => Error::State,
+        }
+    }
+
+    fn program_unit(&mut self) -> Result<(), Error> {
+        let addr = STAGING_START + self.written;
+        // Erase each block as it's reached
+        if addr % BLOCK_SIZE == 0 {
+            self.flash.erase(addr, BLOCK_SIZE)?;
+        }
+        let unit = self.unit;
+        self.flash.program(addr, &unit)?;
+        self.written += WRITE_SIZE;
+        self.unit_len = 0;
+        Ok(())
+    }
+}
+
+/// CRC-32 (IEEE 802.3), reflected polynomial 0xEDB88320.
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// Continue a [`crc32`] with more data, starting from 0.
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!crc, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
@@ -0,0 +1,371 @@
+//! Frame routing between CAN and a serial port.
+//!
+//! Frames from each interface go through a table of [`Rule`]s, the first
+//! matching rule decides where a frame goes, with its ID optionally
+//! rewritten and its rate limited. Frames matching no rule are dropped.
+//! Routed frames wait in a bounded queue per destination, so CAN frames can
+//! be routed straight from the receive interrupt:
+//!
+//! ```ignore
+//! use gateway::{Interface, Rewrite, Rule};
+//!
+//! static RULES: [Rule; 3] = [
+//!     // Engine frames to the host, at most every 100 ms
+//!     Rule::new(Interface::Can, Interface::Uart).ids(0x100, 0x1FF).rate_limit_ms(100),
+//!     // Diagnostics from the host, moved to the 0x7E0 range
+//!     Rule::new(Interface::Uart, Interface::Can).ids(0x000, 0x007).rewrite(Rewrite::Offset(0x7E0)),
+//!     Rule::deny(Interface::Can),
+//! ];
+//!
+//! gateway::init(&RULES)?;
+//! can.on_receive(0, Some(gateway::on_can_frame));
+//! can.enable_rx_interrupt(Irq);
+//! let mut bridge = gateway::UartBridge::new(uart);
+//! loop {
+//!     bridge.poll(&can)?;
+//! }
+//! ```
+//!
+//! On the serial port frames use the SLCAN encoding of [`slcan`](crate::slcan),
+//! e.g. `t1230211AA\r`, without commands or replies.
+//!
+//! Rate limits use [`ticker::millis`](crate::ticker::millis), so need the
+//! [`Ticker`](crate::ticker::Ticker) running.
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use embedded_can::{ExtendedId, Frame as _, Id, StandardId};
+use embedded_io::{Read, ReadReady, Write};
+use heapless::Deque;
+
+use crate::can::{Can, Frame, Running};
+use crate::slcan::{self, MAX_LINE};
+
+/// Most rules in a table.
+pub const MAX_RULES: usize = 16;
+
+/// Frames queued for each destination.
+pub const QUEUE_LEN: usize = 32;
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State::new()));
+
+/// Gateway error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// Reading or writing the serial port failed
+    Port,
+    /// The table has more than [`MAX_RULES`] rules
+    TooManyRules,
+}
+
+/// An interface frames are routed between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum Interface {
+    /// The CAN controller
+    Can,
+    /// The serial port of the [`UartBridge`]
+    Uart,
+}
+
+/// Change to the ID of a routed frame, keeping it standard or extended.
+/// Frames whose new ID doesn't fit are dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Rewrite {
+    /// Keep the ID
+    Keep,
+    /// Add to the ID
+    Offset(i32),
+    /// Replace the ID
+    Replace(u32),
+    /// Keep the bits set in `and`, then set the bits in `or`
+    Mask { and: u32, or: u32 },
+}
+
+impl Rewrite {
+    fn apply(&self, id: Id) -> Option<Id> {
+        let raw = match id {
+            Id::Standard(id) => id.as_raw() as u32,
+            Id::Extended(id) => id.as_raw(),
+        };
+        let raw = match *self {
+            Rewrite::Keep => return Some(id),
+            Rewrite::Offset(offset) => raw.checked_add_signed(offset)?,
+            Rewrite::Replace(new) => new,
+            Rewrite::Mask { and, or } => (raw & and) | or,
+        };
+        match id {
+            Id::Standard(_) => StandardId::new(u16::try_from(raw).ok()?).map(Id::from),
+            Id::Extended(_) => ExtendedId::new(raw).map(Id::from),
+        }
+    }
+}
+
+/// Where frames from one interface go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Rule {
+    from: Interface,
+    to: Option<Interface>,
+    // None matches both
+    extended: Option<bool>,
+    first: u32,
+    last: u32,
+    rewrite: Rewrite,
+    min_interval_ms: u32,
+}
+
+impl Rule {
+    /// Route every frame from `from` to `to`.
+    pub const fn new(from: Interface, to: Interface) -> Self {
+        Self {
+            from,
+            to: Some(to),
+            extended: None,
+            first: 0,
+            last: u32::MAX,
+            rewrite: Rewrite::Keep,
+            min_interval_ms: 0,
+        }
+    }
+
+    /// Drop every frame from `from`, e.g. to stop frames reaching later
+    /// rules.
+    pub const fn deny(from: Interface) -> Self {
+        let mut rule = Self::new(from, Interface::Can);
+        rule.to = None;
+        rule
+    }
+
+    /// Only match standard IDs from `first` to `last` inclusive.
+    pub const fn ids(mut self, first: u16, last: u16) -> Self {
+        self.extended = Some(false);
+        self.first = first as u32;
+        self.last = last as u32;
+        self
+    }
+
+    /// Only match extended IDs from `first` to `last` inclusive.
+    pub const fn extended_ids(mut self, first: u32, last: u32) -> Self {
+        self.extended = Some(true);
+        self.first = first;
+        self.last = last;
+        self
+    }
+
+    /// Change the ID of routed frames.
+    pub const fn rewrite(mut self, rewrite: Rewrite) -> Self {
+        self.rewrite = rewrite;
+        self
+    }
+
+    /// Drop frames arriving less than `ms` after the last one routed by
+    /// this rule.
+    pub const fn rate_limit_ms(mut self, ms: u32) -> Self {
+        self.min_interval_ms = ms;
+        self
+    }
+
+    fn matches(&self, from: Interface, frame: &Frame) -> bool {
+        let (extended, raw) = match frame.id() {
+            Id::Standard(id) => (false, id.as_raw() as u32),
+            Id::Extended(id) => (true, id.as_raw()),
+        };
+        self.from == from
+            && self.extended.is_none_or(|e| e == extended)
+            && (self.first..=self.last).contains(&raw)
+    }
+}
+
+/// Frame counts since [`init`], from [`stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Stats {
+    /// Frames queued for their destination
+    pub routed: u32,
+    /// Frames matching no rule, a deny rule, or with a rewritten ID out of range
+    pub filtered: u32,
+    /// Frames dropped by a rate limit
+    pub rate_limited: u32,
+    /// Frames dropped because the destination queue was full
+    pub overflows: u32,
+}
+
+struct State {
+    rules: &'static [Rule],
+    // Time each rule last routed a frame
+    last_ms: [Option<u32>; MAX_RULES],
+    to_can: Deque<Frame, QUEUE_LEN>,
+    to_uart: Deque<Frame, QUEUE_LEN>,
+    stats: Stats,
+}
+
+impl State {
+    const fn new() -> Self {
+        Self {
+            rules: &[],
+            last_ms: [None; MAX_RULES],
+            to_can: Deque::new(),
+            to_uart: Deque::new(),
+            stats: Stats {
+                routed: 0,
+                filtered: 0,
+                rate_limited: 0,
+                overflows: 0,
+            },
+        }
+    }
+
+    fn route(&mut self, from: Interface, frame: &Frame, time_ms: u32) {
+        let rules = self.rules;
+        let Some((index, rule)) = rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.matches(from, frame))
+        else {
+            self.stats.filtered += 1;
+            return;
+        };
+        let (Some(to), Some(id)) = (rule.to, rule.rewrite.apply(frame.id())) else {
+            self.stats.filtered += 1;
+            return;
+        };
+        if rule.min_interval_ms > 0 {
+            if let Some(last) = self.last_ms[index] {
+                if time_ms.wrapping_sub(last) < rule.min_interval_ms {
+                    self.stats.rate_limited += 1;
+                    return;
+                }
+            }
+            self.last_ms[index] = Some(time_ms);
+        }
+        let mut frame = *frame;
+        frame.set_id(id);
+        let queue = match to {
+            Interface::Can => &mut self.to_can,
+            Interface::Uart => &mut self.to_uart,
+        };
+        match queue.push_back(frame) {
+            Ok(()) => self.stats.routed += 1,
+            Err(_) => self.stats.overflows += 1,
+        }
+    }
+}
+
+/// Route with `rules` from now on, emptying the queues and clearing the
+/// [`stats`].
+pub fn init(rules: &'static [Rule]) -> Result<(), Error> {
+    if rules.len() > MAX_RULES {
+        return Err(Error::TooManyRules);
+    }
+    critical_section::with(|cs| {
+        let mut state = STATE.borrow_ref_mut(cs);
+        *state = State::new();
+        state.rules = rules;
+    });
+    Ok(())
+}
+
+/// Route a frame received from `from`. Can be called from interrupts.
+pub fn route(from: Interface, frame: &Frame) {
+    let time_ms = crate::ticker::millis();
+    critical_section::with(|cs| STATE.borrow_ref_mut(cs).route(from, frame, time_ms));
+}
+
+/// Route a frame received on CAN, a callback for
+/// [`Can::on_receive`](crate::can::Can::on_receive).
+pub fn on_can_frame(frame: &Frame) {
+    route(Interface::Can, frame);
+}
+
+/// Take the next frame queued for `to`, for interfaces without a bridge.
+pub fn take(to: Interface) -> Option<Frame> {
+    critical_section::with(|cs| {
+        let mut state = STATE.borrow_ref_mut(cs);
+        match to {
+            Interface::Can => state.to_can.pop_front(),
+            Interface::Uart => state.to_uart.pop_front(),
+        }
+    })
+}
+
+/// Frame counts since [`init`].
+pub fn stats() -> Stats {
+    critical_section::with(|cs| STATE.borrow_ref(cs).stats)
+}
+
+/// The serial side of the gateway, moving frames between the port and the
+/// queues.
+pub struct UartBridge<P: Read + ReadReady + Write> {
+    port: P,
+    line: [u8; MAX_LINE],
+    len: usize,
+    // Line too long, discarded up to the next carriage return
+    overflow: bool,
+}
+
+impl<P: Read + ReadReady + Write> UartBridge<P> {
+    /// Bridge `port`.
+    pub fn new(port: P) -> Self {
+        Self {
+            port,
+            line: [0; MAX_LINE],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Route the frames received on the port, write the frames queued for
+    /// it, and queue the frames routed to CAN on `can`.
+    ///
+    /// Frames the CAN transmit queue has no room for count as overflows.
+    pub fn poll(&mut self, can: &Can<Running>) -> Result<(), Error> {
+        while self.port.read_ready().map_err(|_| Error::Port)? {
+            let mut byte = [0u8; 1];
+            if self.port.read(&mut byte).map_err(|_| Error::Port)? == 0 {
+                break;
+            }
+            match byte[0] {
+                b'\r' | b'\n' => {
+                    if !self.overflow && self.len > 0 {
+                        match slcan::decode(&self.line[..self.len]) {
+                            Some(frame) => route(Interface::Uart, &frame),
+                            None => critical_section::with(|cs| {
+                                STATE.borrow_ref_mut(cs).stats.filtered += 1
+                            }),
+                        }
+                    }
+                    self.len = 0;
+                    self.overflow = false;
+                }
+                byte if self.len < MAX_LINE => {
+                    self.line[self.len] = byte;
+                    self.len += 1;
+                }
+                _ => self.overflow = true,
+            }
+        }
+
+        while let Some(frame) = take(Interface::Uart) {
+            let mut out = [0u8; MAX_LINE];
+            let len = slcan::encode(&frame, None, &mut out);
+            self.port.write_all(&out[..len]).map_err(|_| Error::Port)?;
+        }
+
+        while let Some(frame) = take(Interface::Can) {
+            if can.queue_frame(frame).is_err() {
+                critical_section::with(|cs| STATE.borrow_ref_mut(cs).stats.overflows += 1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Release the port.
+    pub fn free(self) -> P {
+        self.port
+    }
+}
@@ -0,0 +1,587 @@
+//! GPIO pins and pin function selection.
+//!
+//! Each physical pin is a zero sized type that can be taken once from [`Pins`]
+//! and handed to a peripheral constructor, which then routes the pin to the
+//! peripheral through its PmnPFS register.
+//!
+//! Pins of one port can be grouped into a [`PortBus`] and written together,
+//! for parallel interfaces such as a 4-bit LCD or an R-2R DAC:
+//!
+//! ```ignore
+//! let mut bus = PortBus::new((pins.p100, pins.p101, pins.p102, pins.p103))?;
+//! bus.write(0b1010);
+//! ```
+//!
+//! With the `embedded-hal` or `embedded-hal-02` feature every pin also
+//! implements the digital traits of that version. Configure it with
+//! [`configure`] first, the traits only drive and read the level.
+
+/// Address of P000PFS, the first Pin Function Select register.
+///
+/// PmnPFS is at `PFS_BASE + 0x40 * m + 4 * n`.
+const PFS_BASE: usize = 0x4004_0800;
+
+/// PSEL value used to select a peripheral function of a pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum PinFunction {
+    Gpt = 0b00011,
+    SciEven = 0b00100,
+    SciOdd = 0b00101,
+    Spi = 0b00110,
+    /// CLKOUT, ACMPLP VCOUT and RTCOUT
+    Clkout = 0b01001,
+    Can = 0b10000,
+}
+
+/// A physical pin, identified by port and pin number.
+pub trait Pin {
+    /// Port number (m in Pmn)
+    fn port() -> u8;
+    /// Pin number within the port (n in Pmn)
+    fn pin() -> u8;
+}
+
+// Get a ptr to the PmnPFS register of a pin
+// For some reason access to the PSEL bits can't be done in PAC,
+// so the registers are indexed from P000PFS by offset.
+pub(crate) fn pfs(port: u8, pin: u8) -> *mut u32 {
+    (PFS_BASE + 0x40 * port as usize + 4 * pin as usize) as *mut u32
+}
+
+// Allow writes to the PmnPFS registers
+fn unlock_pfs() {
+    let p = unsafe { ra4m1::Peripherals::steal() };
+    // First write to the B0WI bit
+    p.PMISC.pwpr.write(|w| w.b0wi()._0());
+    // Then write to the PFSWE bit
+    p.PMISC.pwpr.write(|w| w.pfswe()._1());
+}
+
+/// Route the pin `P` to a peripheral function.
+pub(crate) fn set_function<P: Pin>(function: PinFunction) {
+    unlock_pfs();
+    let pfs = pfs(P::port(), P::pin());
+    let psel = (function as u32) << 24;
+    unsafe {
+        // Clear first, PSEL must be written while PMR is 0
+        pfs.write_volatile(0);
+        pfs.write_volatile(psel);
+        // Now with PMR = 1
+        pfs.write_volatile(psel | (1 << 16));
+    }
+}
+
+/// Make the pin `P` an analog input or output, for the ADC, DAC, ACMPLP and OPAMP.
+pub(crate) fn set_analog<P: Pin>() {
+    unlock_pfs();
+    // ASEL = 1, input with no pull-up
+    let pfs = pfs(P::port(), P::pin());
+    unsafe { pfs.write_volatile(1 << 15) };
+}
+
+/// Make the pin `P` a general purpose output, driven high or low.
+pub(crate) fn set_output<P: Pin>(high: bool) {
+    unlock_pfs();
+    // PDR = 1, PODR = level, PSEL and PMR = 0
+    let pfs = pfs(P::port(), P::pin());
+    unsafe { pfs.write_volatile((1 << 2) | high as u32) };
+}
+
+/// Drive an output pin high or low.
+pub(crate) fn write<P: Pin>(high: bool) {
+    write_at(P::port(), P::pin(), high);
+}
+
+/// Drive an output pin high or low, by port and pin number.
+pub(crate) fn write_at(port: u8, pin: u8, high: bool) {
+    // PCNTR3 of the port, POSR sets and PORR clears, so no read-modify-write
+    let pcntr3 = pcntr(port, 3);
+    let bit = if high { 1 << pin } else { 1 << (pin + 16) };
+    unsafe { pcntr3.write_volatile(bit) };
+}
+
+// PCNTR1 (PDR, PODR), PCNTR2 (PIDR) and PCNTR3 (POSR, PORR) of a port
+fn pcntr(port: u8, n: usize) -> *mut u32 {
+    (0x4004_0000 + 0x20 * port as usize + 4 * n) as *mut u32
+}
+
+/// GPIO error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The pins of a [`PortBus`] aren't all on the same port
+    MixedPorts,
+}
+
+/// Pins that can form a [`PortBus`], tuples of up to 8 pins.
+///
+/// The first pin of the tuple is bit 0 of the bus.
+pub trait BusPins {
+    /// Number of pins
+    const WIDTH: usize;
+    /// Port and pin number of bus bit `bit`
+    fn position(bit: usize) -> (u8, u8);
+    // Make every pin an output, driven low
+    fn set_outputs();
+}
+
+macro_rules! impl_bus_pins {
+    ($($width:literal: ($($p:ident),*);)*) => {
+        $(
+            impl<$($p: Pin),*> BusPins for ($($p,)*) {
+                const WIDTH: usize = $width;
+
+                fn position(bit: usize) -> (u8, u8) {
+                    [$(($p::port(), $p::pin())),*][bit]
+                }
+
+                fn set_outputs() {
+                    $(set_output::<$p>(false);)*
+                }
+            }
+        )*
+    };
+}
+
+impl_bus_pins! {
+    1: (P0);
+    2: (P0, P1);
+    3: (P0, P1, P2);
+    4: (P0, P1, P2, P3);
+    5: (P0, P1, P2, P3, P4);
+    6: (P0, P1, P2, P3, P4, P5);
+    7: (P0, P1, P2, P3, P4, P5, P6);
+    8: (P0, P1, P2, P3, P4, P5, P6, P7);
+}
+
+/// Output pins of one port written together.
+///
+/// Writes go through PCNTR3, setting and clearing the bus pins in a single
+/// access without a read-modify-write, so other pins of the port can be
+/// driven from interrupts at the same time.
+pub struct PortBus<P: BusPins> {
+    pins: P,
+    port: u8,
+    // Pin number of each bus bit
+    shifts: [u8; 8],
+    // Bus pins in the port
+    mask: u16,
+    // Shift of bit 0 when the pins are consecutive, so values map directly
+    contiguous: Option<u8>,
+}
+
+impl<P: BusPins> PortBus<P> {
+    /// Make the pins outputs, driven low.
+    pub fn new(pins: P) -> Result<Self, Error> {
+        let port = P::position(0).0;
+        let mut shifts = [0; 8];
+        let mut mask = 0;
+        for (bit, shift) in shifts.iter_mut().enumerate().take(P::WIDTH) {
+            let (pin_port, pin) = P::position(bit);
+            if pin_port != port {
+                return Err(Error::MixedPorts);
+            }
+            *shift = pin;
+            mask |= 1 << pin;
+        }
+        let contiguous = (0..P::WIDTH)
+            .all(|bit| shifts[bit] == shifts[0] + bit as u8)
+            .then_some(shifts[0]);
+        P::set_outputs();
+        Ok(Self {
+            pins,
+            port,
+            shifts,
+            mask,
+            contiguous,
+        })
+    }
+
+    /// Drive the pins to `value`, bit 0 to the first pin.
+    pub fn write(&mut self, value: u8) {
+        self.write_port(self.to_port(value), self.mask);
+    }
+
+    /// Drive only the bus bits set in `mask` to `value`.
+    pub fn write_masked(&mut self, value: u8, mask: u8) {
+        let mask = self.to_port(mask);
+        self.write_port(self.to_port(value) & mask, mask);
+    }
+
+    /// Invert the bus bits set in `mask`.
+    pub fn toggle(&mut self, mask: u8) {
+        let mask = self.to_port(mask);
+        // PODR, the levels being driven
+        let podr = (unsafe { pcntr(self.port, 1).read_volatile() } >> 16) as u16;
+        self.write_port(!podr & mask, mask);
+    }
+
+    /// The levels of the pins, read from PIDR.
+    pub fn read(&self) -> u8 {
+        let pidr = unsafe { pcntr(self.port, 2).read_volatile() } as u16;
+        match self.contiguous {
+            Some(shift) => ((pidr & self.mask) >> shift) as u8,
+            None => (0..P::WIDTH).fold(0, |value, bit| {
+                value | ((((pidr >> self.shifts[bit]) & 1) as u8) << bit)
+            }),
+        }
+    }
+
+    /// Release the pins, left as outputs at their current levels.
+    pub fn free(self) -> P {
+        self.pins
+    }
+
+    // Move bus bits to their pins in the port
+    fn to_port(&self, value: u8) -> u16 {
+        match self.contiguous {
+            Some(shift) => ((value as u16) << shift) & self.mask,
+            None => (0..P::WIDTH)
+                .filter(|bit| value & (1 << bit) != 0)
+                .fold(0, |port, bit| port | (1 << self.shifts[bit])),
+        }
+    }
+
+    // POSR sets the pins in `set`, PORR clears the rest of `mask`
+    fn write_port(&self, set: u16, mask: u16) {
+        let bits = set as u32 | (((mask & !set) as u32) << 16);
+        unsafe { pcntr(self.port, 3).write_volatile(bits) };
+    }
+}
+
+// PmnPFS bits
+const PFS_PODR: u32 = 1 << 0;
+const PFS_PIDR: u32 = 1 << 1;
+const PFS_PDR: u32 = 1 << 2;
+const PFS_PCR: u32 = 1 << 4;
+const PFS_NCODR: u32 = 1 << 6;
+const PFS_DSCR: u32 = 1 << 10;
+const PFS_ASEL: u32 = 1 << 15;
+const PFS_PMR: u32 = 1 << 16;
+// PSEL and PMR, kept when configuring a peripheral pin
+const PFS_FUNCTION: u32 = (0x1F << 24) | PFS_PMR;
+
+/// How a pin is used when not routed to a peripheral.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinMode {
+    Input,
+    Output,
+    /// For the ADC, DAC, ACMPLP and OPAMP, with the digital input off
+    Analog,
+}
+
+/// Output drive capacity, DSCR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriveStrength {
+    Low,
+    Middle,
+}
+
+/// Electrical configuration of a pin, applied with [`configure`].
+///
+/// ```ignore
+/// // 1-Wire bus with an external pull-up, released
+/// gpio::configure(&mut pins.p107, PinConfig::output().open_drain().high());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PinConfig {
+    mode: PinMode,
+    high: bool,
+    pull_up: bool,
+    open_drain: bool,
+    drive: DriveStrength,
+}
+
+impl PinConfig {
+    /// Input without a pull-up.
+    pub const fn input() -> Self {
+        Self::new(PinMode::Input)
+    }
+
+    /// Push-pull output driven low, with low drive.
+    pub const fn output() -> Self {
+        Self::new(PinMode::Output)
+    }
+
+    /// Analog pin.
+    pub const fn analog() -> Self {
+        Self::new(PinMode::Analog)
+    }
+
+    const fn new(mode: PinMode) -> Self {
+        PinConfig {
+            mode,
+            high: false,
+            pull_up: false,
+            open_drain: false,
+            drive: DriveStrength::Low,
+        }
+    }
+
+    /// Enable the input pull-up. The hardware disables it while the pin
+    /// is an output, so an open-drain bus needs an external pull-up.
+    pub const fn pull_up(mut self) -> Self {
+        self.pull_up = true;
+        self
+    }
+
+    /// N-channel open-drain output, driven low or released.
+    pub const fn open_drain(mut self) -> Self {
+        self.open_drain = true;
+        self
+    }
+
+    /// Set the output drive capacity.
+    pub const fn drive(mut self, drive: DriveStrength) -> Self {
+        self.drive = drive;
+        self
+    }
+
+    /// Start with the output high, released for an open-drain output.
+    pub const fn high(mut self) -> Self {
+        self.high = true;
+        self
+    }
+
+    // PmnPFS value, without the function bits
+    fn bits(&self) -> u32 {
+        let mode = match self.mode {
+            PinMode::Input => 0,
+            PinMode::Output => PFS_PDR,
+            PinMode::Analog => PFS_ASEL,
+        };
+        let drive = match self.drive {
+            DriveStrength::Low => 0,
+            DriveStrength::Middle => PFS_DSCR,
+        };
+        mode | drive
+            | if self.high { PFS_PODR } else { 0 }
+            | if self.pull_up { PFS_PCR } else { 0 }
+            | if self.open_drain { PFS_NCODR } else { 0 }
+    }
+}
+
+/// Apply `config` to a pin.
+///
+/// A pin already routed to a peripheral stays routed, only its pull-up,
+/// open-drain and drive settings change, e.g. to make SCI pins open-drain
+/// after creating the driver.
+pub fn configure<P: Pin>(_pin: &mut P, config: PinConfig) {
+    configure_at(P::port(), P::pin(), config);
+}
+
+/// Apply `config` to a pin, by port and pin number.
+pub(crate) fn configure_at(port: u8, pin: u8, config: PinConfig) {
+    let pfs = pfs(port, pin);
+    unlock_pfs();
+    unsafe {
+        let function = pfs.read_volatile() & PFS_FUNCTION;
+        if function & PFS_PMR != 0 {
+            let electrical = PFS_PCR | PFS_NCODR | PFS_DSCR;
+            pfs.write_volatile(function | (config.bits() & electrical));
+        } else {
+            pfs.write_volatile(config.bits());
+        }
+    }
+}
+
+/// Drive an output pin high or low, releasing an open-drain output for high.
+pub fn set_level<P: Pin>(_pin: &mut P, high: bool) {
+    write::<P>(high);
+}
+
+/// Whether the level on the pin is high, also for outputs.
+pub fn is_high<P: Pin>(_pin: &P) -> bool {
+    is_high_at(P::port(), P::pin())
+}
+
+/// Whether the level on a pin is high, by port and pin number.
+pub(crate) fn is_high_at(port: u8, pin: u8) -> bool {
+    unsafe { pfs(port, pin).read_volatile() & PFS_PIDR != 0 }
+}
+
+// Whether a pin is driven high, by port and pin number
+#[cfg(any(feature = "embedded-hal", feature = "embedded-hal-02"))]
+fn is_set_high_at(port: u8, pin: u8) -> bool {
+    unsafe { pfs(port, pin).read_volatile() & PFS_PODR != 0 }
+}
+
+// embedded-hal digital traits of a pin, on the level functions above
+macro_rules! hal_pin {
+    ($name:ident, $port:literal, $pin:literal) => {
+        #[cfg(feature = "embedded-hal")]
+        impl embedded_hal::digital::ErrorType for $name {
+            type Error = core::convert::Infallible;
+        }
+
+        #[cfg(feature = "embedded-hal")]
+        impl embedded_hal::digital::OutputPin for $name {
+            fn set_low(&mut self) -> Result<(), Self::Error> {
+                write_at($port, $pin, false);
+                Ok(())
+            }
+
+            fn set_high(&mut self) -> Result<(), Self::Error> {
+                write_at($port, $pin, true);
+                Ok(())
+            }
+        }
+
+        #[cfg(feature = "embedded-hal")]
+        impl embedded_hal::digital::StatefulOutputPin for $name {
+            fn is_set_high(&mut self) -> Result<bool, Self::Error> {
+                Ok(is_set_high_at($port, $pin))
+            }
+
+            fn is_set_low(&mut self) -> Result<bool, Self::Error> {
+                Ok(!is_set_high_at($port, $pin))
+            }
+        }
+
+        #[cfg(feature = "embedded-hal")]
+        impl embedded_hal::digital::InputPin for $name {
+            fn is_high(&mut self) -> Result<bool, Self::Error> {
+                Ok(is_high_at($port, $pin))
+            }
+
+            fn is_low(&mut self) -> Result<bool, Self::Error> {
+                Ok(!is_high_at($port, $pin))
+            }
+        }
+
+        #[cfg(feature = "embedded-hal-02")]
+        impl embedded_hal_02::digital::v2::OutputPin for $name {
+            type Error = core::convert::Infallible;
+
+            fn set_low(&mut self) -> Result<(), Self::Error> {
+                write_at($port, $pin, false);
+                Ok(())
+            }
+
+            fn set_high(&mut self) -> Result<(), Self::Error> {
+                write_at($port, $pin, true);
+                Ok(())
+            }
+        }
+
+        #[cfg(feature = "embedded-hal-02")]
+        impl embedded_hal_02::digital::v2::StatefulOutputPin for $name {
+            fn is_set_high(&self) -> Result<bool, Self::Error> {
+                Ok(is_set_high_at($port, $pin))
+            }
+
+            fn is_set_low(&self) -> Result<bool, Self::Error> {
+                Ok(!is_set_high_at($port, $pin))
+            }
+        }
+
+        #[cfg(feature = "embedded-hal-02")]
+        impl embedded_hal_02::digital::v2::toggleable::Default for $name {}
+
+        #[cfg(feature = "embedded-hal-02")]
+        impl embedded_hal_02::digital::v2::InputPin for $name {
+            type Error = core::convert::Infallible;
+
+            fn is_high(&self) -> Result<bool, Self::Error> {
+                Ok(is_high_at($port, $pin))
+            }
+
+            fn is_low(&self) -> Result<bool, Self::Error> {
+                Ok(!is_high_at($port, $pin))
+            }
+        }
+    };
+}
+
+macro_rules! pins {
+    ($($name:ident, $field:ident: ($port:literal, $pin:literal);)*) => {
+        $(
+            #[doc = concat!("Pin ", stringify!($name))]
+            pub struct $name {
+                _private: (),
+            }
+
+            impl Pin for $name {
+                fn port() -> u8 {
+                    $port
+                }
+
+                fn pin() -> u8 {
+                    $pin
+                }
+            }
+
+            hal_pin!($name, $port, $pin);
+        )*
+
+        /// All pins of the device, each can only be taken once.
+        pub struct Pins {
+            $(
+                pub $field: $name,
+            )*
+        }
+
+        impl Pins {
+            /// Split the pin function select peripheral into individual pins.
+            pub fn new(_pfs: ra4m1::PFS) -> Self {
+                Pins {
+                    $(
+                        $field: $name { _private: () },
+                    )*
+                }
+            }
+        }
+    };
+}
+
+pins! {
+    P000, p000: (0, 0);
+    P001, p001: (0, 1);
+    P002, p002: (0, 2);
+    P003, p003: (0, 3);
+    P004, p004: (0, 4);
+    P010, p010: (0, 10);
+    P011, p011: (0, 11);
+    P012, p012: (0, 12);
+    P013, p013: (0, 13);
+    P014, p014: (0, 14);
+    P015, p015: (0, 15);
+    P100, p100: (1, 0);
+    P101, p101: (1, 1);
+    P102, p102: (1, 2);
+    P103, p103: (1, 3);
+    P104, p104: (1, 4);
+    P105, p105: (1, 5);
+    P106, p106: (1, 6);
+    P107, p107: (1, 7);
+    P108, p108: (1, 8);
+    P109, p109: (1, 9);
+    P110, p110: (1, 10);
+    P111, p111: (1, 11);
+    P112, p112: (1, 12);
+    P113, p113: (1, 13);
+    P200, p200: (2, 0);
+    P201, p201: (2, 1);
+    P204, p204: (2, 4);
+    P205, p205: (2, 5);
+    P206, p206: (2, 6);
+    P212, p212: (2, 12);
+    P213, p213: (2, 13);
+    P300, p300: (3, 0);
+    P301, p301: (3, 1);
+    P302, p302: (3, 2);
+    P303, p303: (3, 3);
+    P304, p304: (3, 4);
+    P400, p400: (4, 0);
+    P401, p401: (4, 1);
+    P402, p402: (4, 2);
+    P407, p407: (4, 7);
+    P408, p408: (4, 8);
+    P409, p409: (4, 9);
+    P410, p410: (4, 10);
+    P411, p411: (4, 11);
+    P500, p500: (5, 0);
+    P501, p501: (5, 1);
+    P502, p502: (5, 2);
+}
@@ -0,0 +1,475 @@
+//! NMEA 0183 parsing for GPS receivers on a UART.
+//!
+//! Lines are reassembled from the receive buffer into a fixed size buffer,
+//! checked against their checksum and the RMC and GGA sentences are parsed
+//! into [`Fix`] values. Other sentences are skipped. Nothing is allocated.
+//!
+//! Latitude and longitude are kept as integers in 1e-7 degrees, an `f32`
+//! can't hold them to better than a few metres.
+//!
+//! ```ignore
+//! let (_, rx) = uart.split();
+//! let mut gps = gps::Gps::new(rx);
+//! loop {
+//!     while let Some(fix) = gps.next() {
+//!         sprintln!("{:?}", fix);
+//!     }
+//! }
+//! ```
+
+use embedded_io::{Read, ReadReady};
+
+use crate::uart::{Instance, UartRx};
+
+/// Longest sentence accepted, NMEA allows 82 characters including `$` and CR LF.
+pub const MAX_LINE: usize = 96;
+
+/// UTC time of day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Time {
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+    pub millis: u16,
+}
+
+/// UTC date.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Date {
+    pub day: u8,
+    pub month: u8,
+    /// Full year, e.g. 2025
+    pub year: u16,
+}
+
+/// Recommended minimum data, from an RMC sentence.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Rmc {
+    pub time: Time,
+    pub date: Date,
+    /// The receiver has a valid position
+    pub valid: bool,
+    /// Latitude in 1e-7 degrees, north positive
+    pub latitude: i32,
+    /// Longitude in 1e-7 degrees, east positive
+    pub longitude: i32,
+    /// Speed over ground in knots
+    pub speed_knots: f32,
+    /// Course over ground in degrees from true north
+    pub course: f32,
+}
+
+/// Position fix data, from a GGA sentence.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Gga {
+    pub time: Time,
+    /// Latitude in 1e-7 degrees, north positive
+    pub latitude: i32,
+    /// Longitude in 1e-7 degrees, east positive
+    pub longitude: i32,
+    /// 0 no fix, 1 GPS, 2 DGPS, 4 RTK fixed, 5 RTK float, 6 estimated
+    pub quality: u8,
+    /// Number of satellites in use
+    pub satellites: u8,
+    /// Horizontal dilution of precision
+    pub hdop: f32,
+    /// Altitude above mean sea level in metres
+    pub altitude: f32,
+}
+
+/// A parsed sentence.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Fix {
+    Rmc(Rmc),
+    Gga(Gga),
+}
+
+/// Parse errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The line doesn't start with `$` or has no checksum
+    Format,
+    /// The checksum doesn't match the sentence
+    Checksum,
+    /// Not an RMC or GGA sentence
+    Unsupported,
+    /// A field couldn't be parsed
+    Field,
+}
+
+/// Parse one sentence, with or without the trailing CR LF.
+pub fn parse(line: &[u8]) -> Result<Fix, Error> {
+    let line = line.trim_ascii_end();
+    let body = line.strip_prefix(b"$").ok_or(Error::Format)?;
+    let star = body.iter().rposition(|b| *b == b'*').ok_or(Error::Format)?;
+    let (body, checksum) = (&body[..star], &body[star + 1..]);
+    let expected = core::str::from_utf8(checksum)
+        .ok()
+        .and_then(|s| u8::from_str_radix(s, 16).ok())
+        .ok_or(Error::Format)?;
+    if body.iter().fold(0u8, |sum, b| sum ^ b) != expected {
+        return Err(Error::Checksum);
+    }
+    // Sentences are ASCII, so fields can be sliced by byte
+    if !body.is_ascii() {
+        return Err(Error::Format);
+    }
+    let body = core::str::from_utf8(body).map_err(|_| Error::Format)?;
+
+    let mut fields = body.split(',');
+    // Talker ID (GP, GN, GL, ...) then the sentence type
+    let kind = fields.next().ok_or(Error::Format)?;
+    if kind.len() != 5 {
+        return Err(Error::Unsupported);
+    }
+    let mut next = || fields.next().ok_or(Error::Field);
+    match &kind[2..] {
+        "RMC" => {
+            let time = parse_time(next()?)?;
+            let valid = next()? == "A";
+            let latitude = parse_coordinate(next()?, next()?)?;
+            let longitude = parse_coordinate(next()?, next()?)?;
+            let speed_knots = parse_f32(next()?)?;
+            let course = parse_f32(next()?)?;
+            let date = parse_date(next()?)?;
+            Ok(Fix::Rmc(Rmc {
+                time,
+                date,
+                valid,
+                latitude,
+                longitude,
+                speed_knots,
+                course,
+            }))
+        }
+        "GGA" => {
+            let time = parse_time(next()?)?;
+            let latitude = parse_coordinate(next()?, next()?)?;
+            let longitude = parse_coordinate(next()?, next()?)?;
+            let quality = parse_u8(next()?)?;
+            let satellites = parse_u8(next()?)?;
+            let hdop = parse_f32(next()?)?;
+            let altitude = parse_f32(next()?)?;
+            Ok(Fix::Gga(Gga {
+                time,
+                latitude,
+                longitude,
+                quality,
+                satellites,
+                hdop,
+                altitude,
+            }))
+        }
+        _ => Err(Error::Unsupported),
+    }
+}
+
+// Empty fields are sent before the receiver has a fix, they parse as 0
+
+fn parse_u8(field: &str) -> Result<u8, Error> {
+    if field.is_empty() {
+        return Ok(0);
+    }
+    field.parse().map_err(|_| Error::Field)
+}
+
+fn parse_f32(field: &str) -> Result<f32, Error> {
+    if field.is_empty() {
+        return Ok(0.0);
+    }
+    field.parse().map_err(|_| Error::Field)
+}
+
+// Two digit number at `at`
+fn digits2(field: &[u8], at: usize) -> Result<u8, Error> {
+    match field.get(at..at + 2) {
+        Some([a, b]) if a.is_ascii_digit() && b.is_ascii_digit() => {
+            Ok((a - b'0') * 10 + (b - b'0'))
+        }
+        _ => Err(Error::Field),
+    }
+}
+
+// hhmmss.sss
+fn parse_time(field: &str) -> Result<Time, Error> {
+    if field.is_empty() {
+        return Ok(Time::default());
+    }
+    let bytes = field.as_bytes();
+    let mut millis = 0u16;
+    let mut scale = 100;
+    for b in bytes.get(7..).unwrap_or(&[]).iter().take(3) {
+        if !b.is_ascii_digit() {
+            return Err(Error::Field);
+        }
+        millis += (b - b'0') as u16 * scale;
+        scale /= 10;
+    }
+    Ok(Time {
+        hours: digits2(bytes, 0)?,
+        minutes: digits2(bytes, 2)?,
+        seconds: digits2(bytes, 4)?,
+        millis,
+    })
+}
+
+// ddmmyy
+fn parse_date(field: &str) -> Result<Date, Error> {
+    if field.is_empty() {
+        return Ok(Date::default());
+    }
+    let bytes = field.as_bytes();
+    Ok(Date {
+        day: digits2(bytes, 0)?,
+        month: digits2(bytes, 2)?,
+        year: 2000 + digits2(bytes, 4)? as u16,
+    })
+}
+
+// (d)ddmm.mmmm and a hemisphere, to 1e-7 degrees
+fn parse_coordinate(field: &str, hemisphere: &str) -> Result<i32, Error> {
+    if field.is_empty() {
+        return Ok(0);
+    }
+    let dot = field.find('.').unwrap_or(field.len());
+    if dot < 2 {
+        return Err(Error::Field);
+    }
+    // Up to 180 degrees, so the result fits an i32
+    let degrees = field.as_bytes()[..dot - 2]
+        .iter()
+        .try_fold(0i64, |degrees, b| match b {
+            b'0'..=b'9' if degrees < 100 => Some(degrees * 10 + (b - b'0') as i64),
+            _ => None,
+        })
+        .filter(|degrees| *degrees <= 180)
+        .ok_or(Error::Field)?;
+    let whole_minutes = digits2(field.as_bytes(), dot - 2)? as i64;
+    // Fractional minutes in 1e-7 minutes
+    let mut frac: i64 = 0;
+    let mut scale = 1_000_000;
+    for b in field
+        .as_bytes()
+        .get(dot + 1..)
+        .unwrap_or(&[])
+        .iter()
+        .take(7)
+    {
+        if !b.is_ascii_digit() {
+            return Err(Error::Field);
+        }
+        frac += (b - b'0') as i64 * scale;
+        scale /= 10;
+    }
+    let minutes_e7 = whole_minutes * 10_000_000 + frac;
+    let value = degrees * 10_000_000 + minutes_e7 / 60;
+    match hemisphere {
+        "N" | "E" => Ok(value as i32),
+        "S" | "W" => Ok(-value as i32),
+        _ => Err(Error::Field),
+    }
+}
+
+/// Reassembles lines from a byte stream.
+#[derive(Debug, Clone)]
+pub struct LineBuffer {
+    buf: [u8; MAX_LINE],
+    len: usize,
+    // Dropping the rest of a line that was too long
+    overflow: bool,
+}
+
+impl LineBuffer {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_LINE],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Add a byte, returns a complete line (without CR LF) when `byte` ends one.
+    pub fn push(&mut self, byte: u8) -> Option<&[u8]> {
+        match byte {
+            // A new sentence always starts a new line
+            b'$' => {
+                self.buf[0] = byte;
+                self.len = 1;
+                self.overflow = false;
+                None
+            }
+            b'\r' => None,
+            b'\n' => {
+                let len = core::mem::take(&mut self.len);
+                if core::mem::take(&mut self.overflow) || len == 0 {
+                    None
+                } else {
+                    Some(&self.buf[..len])
+                }
+            }
+            _ => {
+                if self.len < MAX_LINE {
+                    self.buf[self.len] = byte;
+                    self.len += 1;
+                } else {
+                    self.overflow = true;
+                }
+                None
+            }
+        }
+    }
+}
+
+impl Default for LineBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fixes parsed from a UART receiver.
+///
+/// Iterating yields the fixes in the sentences already received and ends
+/// when the receive buffer is empty, so it doesn't block. Unsupported
+/// sentences are skipped, invalid ones are skipped and counted.
+pub struct Gps<T: Instance> {
+    rx: UartRx<T>,
+    line: LineBuffer,
+    errors: u32,
+}
+
+impl<T: Instance> Gps<T> {
+    pub fn new(rx: UartRx<T>) -> Self {
+        Self {
+            rx,
+            line: LineBuffer::new(),
+            errors: 0,
+        }
+    }
+
+    /// Number of sentences dropped for a bad checksum or field.
+    pub fn errors(&self) -> u32 {
+        self.errors
+    }
+
+    /// Release the UART receiver.
+    pub fn free(self) -> UartRx<T> {
+        self.rx
+    }
+}
+
+impl<T: Instance> Iterator for Gps<T> {
+    type Item = Fix;
+
+    fn next(&mut self) -> Option<Fix> {
+        let mut byte = [0u8; 1];
+        while self.rx.read_ready().unwrap_or(false) {
+            if self.rx.read(&mut byte).unwrap_or(0) == 0 {
+                break;
+            }
+            if let Some(line) = self.line.push(byte[0]) {
+                match parse(line) {
+                    Ok(fix) => return Some(fix),
+                    Err(Error::Unsupported) => {}
+                    Err(_) => self.errors = self.errors.wrapping_add(1),
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `body` between `$` and `*` with its checksum added
+    fn sentence(body: &[u8]) -> Vec<u8> {
+        let checksum = body.iter().fold(0u8, |sum, b| sum ^ b);
+        let mut line = vec![b'$'];
+        line.extend_from_slice(body);
+        line.extend_from_slice(format!("*{checksum:02X}\r\n").as_bytes());
+        line
+    }
+
+    #[test]
+    fn parse_sentences() {
+        let rmc = sentence(b"GPRMC,123519.5,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W");
+        let Ok(Fix::Rmc(rmc)) = parse(&rmc) else {
+            panic!("RMC not parsed");
+        };
+        assert_eq!(rmc.time.millis, 500);
+        assert_eq!((rmc.date.day, rmc.date.month, rmc.date.year), (23, 3, 2094));
+        assert_eq!((rmc.latitude, rmc.longitude), (481_173_000, 115_166_666));
+
+        let gga = sentence(b"GNGGA,123519,4807.038,S,01131.000,W,1,08,0.9,545.4,M,46.9,M,,");
+        let Ok(Fix::Gga(gga)) = parse(&gga) else {
+            panic!("GGA not parsed");
+        };
+        assert_eq!((gga.latitude, gga.longitude), (-481_173_000, -115_166_666));
+        assert_eq!((gga.quality, gga.satellites), (1, 8));
+
+        let mut bad = sentence(b"GPGSV,1,1,00");
+        assert_eq!(parse(&bad), Err(Error::Unsupported));
+        bad[3] ^= 1;
+        assert_eq!(parse(&bad), Err(Error::Checksum));
+        assert_eq!(parse(b"GPRMC,*00"), Err(Error::Format));
+    }
+
+    #[test]
+    fn parse_rejects_non_ascii() {
+        // A valid checksum over a multi-byte character in the talker ID
+        let line = sentence("Gé,MC,123519".as_bytes());
+        assert_eq!(parse(&line), Err(Error::Format));
+        let line = sentence("GéMC,123519".as_bytes());
+        assert_eq!(parse(&line), Err(Error::Format));
+    }
+
+    #[test]
+    fn coordinates() {
+        assert_eq!(parse_coordinate("", ""), Ok(0));
+        assert_eq!(parse_coordinate("18000.0", "E"), Ok(1_800_000_000));
+        assert_eq!(parse_coordinate("0000.6", "W"), Ok(-100_000));
+        // Too many degree digits used to overflow
+        assert_eq!(
+            parse_coordinate("99999999999999999999.0", "N"),
+            Err(Error::Field)
+        );
+        assert_eq!(parse_coordinate("18100.0", "N"), Err(Error::Field));
+        assert_eq!(parse_coordinate("+107.0", "N"), Err(Error::Field));
+        assert_eq!(parse_coordinate("4807.038", "X"), Err(Error::Field));
+        assert_eq!(parse_coordinate("7.0", "N"), Err(Error::Field));
+        let line =
+            sentence(b"GPGGA,123519,99999999999999999999.0,N,01131.000,E,1,08,0.9,545.4,M,,M,,");
+        assert_eq!(parse(&line), Err(Error::Field));
+    }
+
+    #[test]
+    fn line_buffer() {
+        let mut buffer = LineBuffer::new();
+        let mut lines = Vec::new();
+        let stream = [
+            &b"noise"[..],
+            &sentence(b"GPGSV,1,1,00"),
+            &sentence("GéMC,1".as_bytes()),
+            // Restarted by a `$`, then too long
+            b"$GPRMC,12$GPGGA,",
+            &[b'0'; MAX_LINE],
+            b"\r\n",
+        ];
+        for byte in stream.concat() {
+            if let Some(line) = buffer.push(byte) {
+                lines.push(line.to_vec());
+            }
+        }
+        // The noise and the cut off and overlong sentences are dropped
+        assert_eq!(lines.len(), 2);
+        assert_eq!(parse(&lines[0]), Err(Error::Unsupported));
+        assert_eq!(parse(&lines[1]), Err(Error::Format));
+    }
+}
@@ -0,0 +1,237 @@
+//! Input capture for pulse width and frequency measurement.
+//!
+//! The counter free runs over its full range. Rising edges on the pin are
+//! captured into GTCCRA and falling edges into GTCCRB, the interrupt handlers
+//! turn the captured counts into the latest period and high time.
+//!
+//! Periods longer than the counter range (2^16 or 2^32 counts) alias, pick a
+//! prescaler so the slowest expected signal fits.
+
+use core::marker::PhantomData;
+use core::sync::atomic::Ordering;
+
+use super::{GTST_TCFA, GTST_TCFB, Instance, PinA, PinB, Prescaler};
+use crate::clk::ClockGuard;
+use crate::interrupts::{Binding, Handler, clear_interrupt, map_and_enable_interrupt};
+
+/// Capture configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Count clock prescaler
+    pub prescaler: Prescaler,
+    /// Frequency of PCLKD in Hz, used to convert counts to time
+    pub pclkd_hz: u32,
+    /// Enable the pin digital noise filter (3 samples at the count clock)
+    pub noise_filter: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            prescaler: Prescaler::Div1,
+            pclkd_hz: 48_000_000,
+            noise_filter: false,
+        }
+    }
+}
+
+/// Triggers on a rising edge, captured into GTCCRA.
+pub struct RisingHandler<T: Instance> {
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Instance> Handler for RisingHandler<T> {
+    unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
+        clear_interrupt(interrupt);
+        let gpt = unsafe { &*T::peripheral() };
+        gpt.gtst
+            .modify(|r, w| unsafe { w.bits(r.bits() & !GTST_TCFA) });
+        let state = T::state();
+        let now = gpt.gtccra.read().bits();
+        // a holds the previous rising edge, b the period
+        let last = state.a.swap(now, Ordering::Relaxed);
+        state
+            .b
+            .store(now.wrapping_sub(last) & T::max_count(), Ordering::Relaxed);
+        state.count.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Triggers on a falling edge, captured into GTCCRB.
+pub struct FallingHandler<T: Instance> {
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Instance> Handler for FallingHandler<T> {
+    unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
+        clear_interrupt(interrupt);
+        let gpt = unsafe { &*T::peripheral() };
+        gpt.gtst
+            .modify(|r, w| unsafe { w.bits(r.bits() & !GTST_TCFB) });
+        let state = T::state();
+        let now = gpt.gtccrb.read().bits();
+        // c holds the high time, measured from the last rising edge
+        let rise = state.a.load(Ordering::Relaxed);
+        state
+            .c
+            .store(now.wrapping_sub(rise) & T::max_count(), Ordering::Relaxed);
+    }
+}
+
+/// Input capture on one pin of a GPT channel.
+pub struct Capture<T: Instance> {
+    tick_hz: u32,
+    _clock: ClockGuard,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Instance> Capture<T> {
+    /// Measure the signal on the GTIOCnA pin of the channel.
+    pub fn new_a<P: PinA<T>, IRQ>(instance: T, _pin: P, config: Config, irq: IRQ) -> Self
+    where
+        IRQ: Binding<RisingHandler<T>> + Binding<FallingHandler<T>>,
+    {
+        // GTCCRA on GTIOCA rising (ASCARBL | ASCARBH),
+        // GTCCRB on GTIOCA falling (BSCAFBL | BSCAFBH)
+        super::connect_pin::<P>();
+        Self::init(instance, config, irq, 0b11 << 8, 0b11 << 10, 13)
+    }
+
+    /// Measure the signal on the GTIOCnB pin of the channel.
+    pub fn new_b<P: PinB<T>, IRQ>(instance: T, _pin: P, config: Config, irq: IRQ) -> Self
+    where
+        IRQ: Binding<RisingHandler<T>> + Binding<FallingHandler<T>>,
+    {
+        // GTCCRA on GTIOCB rising (ASCBRAL | ASCBRAH),
+        // GTCCRB on GTIOCB falling (BSCBFAL | BSCBFAH)
+        super::connect_pin::<P>();
+        Self::init(instance, config, irq, 0b11 << 12, 0b11 << 14, 29)
+    }
+
+    fn init<IRQ>(_instance: T, config: Config, _irq: IRQ, icasr: u32, icbsr: u32, nf: u32) -> Self
+    where
+        IRQ: Binding<RisingHandler<T>> + Binding<FallingHandler<T>>,
+    {
+        let clock = super::init::<T>(config.prescaler);
+        let gpt = unsafe { &*T::peripheral() };
+        gpt.gticasr.write(|w| unsafe { w.bits(icasr) });
+        gpt.gticbsr.write(|w| unsafe { w.bits(icbsr) });
+        if config.noise_filter {
+            // NFAEN/NFBEN, sampling at the count clock (NFCSx = 00)
+            gpt.gtior.write(|w| unsafe { w.bits(1 << nf) });
+        }
+
+        // Map events to interrupts
+        let event_base = T::event_base();
+        map_and_enable_interrupt(<IRQ as Binding<RisingHandler<T>>>::interrupt(), event_base);
+        map_and_enable_interrupt(
+            <IRQ as Binding<FallingHandler<T>>>::interrupt(),
+            event_base.offset(1),
+        );
+
+        let capture = Self {
+            tick_hz: config.pclkd_hz / config.prescaler.divisor(),
+            _clock: clock,
+            _phantom: PhantomData,
+        };
+        capture.reset();
+        super::start::<T>();
+        capture
+    }
+
+    /// Discard previous measurements.
+    pub fn reset(&self) {
+        let state = T::state();
+        critical_section::with(|_| {
+            state.count.store(0, Ordering::Relaxed);
+            state.b.store(0, Ordering::Relaxed);
+            state.c.store(0, Ordering::Relaxed);
+        });
+    }
+
+    /// Number of rising edges seen since the last reset.
+    pub fn edges(&self) -> u32 {
+        T::state().count.load(Ordering::Acquire)
+    }
+
+    /// Frequency of the count clock in Hz.
+    pub fn tick_hz(&self) -> u32 {
+        self.tick_hz
+    }
+
+    /// Current value of the free running counter.
+    pub fn counter(&self) -> u32 {
+        let gpt = unsafe { &*T::peripheral() };
+        gpt.gtcnt.read().bits()
+    }
+
+    /// Latest period in counts, rising edge to rising edge.
+    ///
+    /// None until two rising edges have been captured.
+    pub fn measure_period_ticks(&self) -> Option<u32> {
+        if self.edges() < 2 {
+            return None;
+        }
+        Some(T::state().b.load(Ordering::Relaxed))
+    }
+
+    /// Latest high time in counts, rising edge to falling edge.
+    ///
+    /// None until a rising edge has been captured.
+    pub fn measure_pulse_width_ticks(&self) -> Option<u32> {
+        if self.edges() < 1 {
+            return None;
+        }
+        Some(T::state().c.load(Ordering::Relaxed))
+    }
+
+    /// Latest period in microseconds.
+    pub fn measure_period(&self) -> Option<u32> {
+        self.measure_period_ticks().map(|t| self.ticks_to_us(t))
+    }
+
+    /// Latest high time in microseconds.
+    pub fn measure_pulse_width(&self) -> Option<u32> {
+        self.measure_pulse_width_ticks()
+            .map(|t| self.ticks_to_us(t))
+    }
+
+    /// Frequency of the signal in Hz.
+    pub fn frequency_hz(&self) -> Option<u32> {
+        match self.measure_period_ticks() {
+            Some(0) | None => None,
+            Some(period) => Some(self.tick_hz / period),
+        }
+    }
+
+    /// Duty cycle in parts per thousand.
+    pub fn duty_cycle_permille(&self) -> Option<u16> {
+        let (period, high) = critical_section::with(|_| {
+            (
+                self.measure_period_ticks(),
+                self.measure_pulse_width_ticks(),
+            )
+        });
+        match (period, high) {
+            (Some(0), _) | (None, _) | (_, None) => None,
+            (Some(period), Some(high)) => {
+                Some(((high as u64 * 1000) / period as u64).min(1000) as u16)
+            }
+        }
+    }
+
+    /// Stop the counter, edges are no longer captured.
+    pub fn stop(&self) {
+        super::stop::<T>();
+    }
+
+    /// Restart the counter after [`stop`](Self::stop).
+    pub fn start(&self) {
+        self.reset();
+        super::start::<T>();
+    }
+
+    fn ticks_to_us(&self, ticks: u32) -> u32 {
+        ((ticks as u64 * 1_000_000) / self.tick_hz as u64) as u32
+    }
+}
@@ -0,0 +1,219 @@
+//! General PWM Timer (GPT)
+//!
+//! The RA4M1 has two 32-bit channels (GPT320, GPT321) and six 16-bit
+//! channels (GPT162 - GPT167). All channels share the same register
+//! layout and are clocked from PCLKD.
+
+use core::sync::atomic::AtomicU32;
+
+use ra4m1::{GPT162, GPT163, GPT164, GPT165, GPT166, GPT167, GPT320, GPT321, gpt320};
+
+use crate::clk::{ClockGuard, Peripheral};
+use crate::events::Event;
+use crate::gpio::{self, Pin};
+
+pub mod capture;
+#[cfg(feature = "rtic")]
+pub mod monotonic;
+pub mod pulse;
+pub mod pwm;
+#[cfg(feature = "embassy")]
+pub mod time_driver;
+pub mod timebase;
+
+/// A GPT channel.
+pub trait Instance {
+    // Get access to the peripheral's register block.
+    // The 16-bit channels have the same layout as the 32-bit ones.
+    fn peripheral() -> *const gpt320::RegisterBlock;
+    fn state() -> &'static State;
+    // Channel number, 0-7
+    fn channel() -> u8;
+    // Largest value of the counter, depends on the counter width
+    fn max_count() -> u32;
+    // First event of this instance (CCMPA)
+    fn event_base() -> Event {
+        Event::GPT0_CCMPA.offset(8 * Self::channel())
+    }
+}
+
+/// Count clock prescaler, dividing PCLKD.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Prescaler {
+    Div1 = 0b000,
+    Div4 = 0b001,
+    Div16 = 0b010,
+    Div64 = 0b011,
+    Div256 = 0b100,
+    Div1024 = 0b101,
+}
+
+impl Prescaler {
+    /// Divisor applied to PCLKD
+    pub const fn divisor(&self) -> u32 {
+        1 << (2 * *self as u32)
+    }
+}
+
+/// Pin that can be used as GTIOCnA of GPT channel `T`.
+pub trait PinA<T: Instance>: Pin {}
+
+/// Pin that can be used as GTIOCnB of GPT channel `T`.
+pub trait PinB<T: Instance>: Pin {}
+
+/// Values shared between a driver and its interrupt handlers.
+pub struct State {
+    pub(crate) a: AtomicU32,
+    pub(crate) b: AtomicU32,
+    pub(crate) c: AtomicU32,
+    pub(crate) count: AtomicU32,
+}
+
+impl State {
+    const fn new() -> Self {
+        State {
+            a: AtomicU32::new(0),
+            b: AtomicU32::new(0),
+            c: AtomicU32::new(0),
+            count: AtomicU32::new(0),
+        }
+    }
+}
+
+// GTST flags, write 0 to clear
+const GTST_TCFA: u32 = 1 << 0;
+const GTST_TCFB: u32 = 1 << 1;
+
+// GTCR.CST, count start
+const GTCR_CST: u32 = 1 << 0;
+
+/// Reset a channel to a stopped, up counting saw-wave timer
+/// over the full counter range.
+///
+/// Enables the module clock and removes write protection. The module
+/// stays enabled while the returned guard is held.
+pub(crate) fn init<T: Instance>(prescaler: Prescaler) -> ClockGuard {
+    // GPT32 and GPT16 channels are stopped in groups
+    let clock = ClockGuard::new(if T::channel() < 2 {
+        Peripheral::Gpt32
+    } else {
+        Peripheral::Gpt16
+    });
+    let gpt = unsafe { &*T::peripheral() };
+    // Disable write protection, PRKEY = A5h
+    gpt.gtwp.write(|w| unsafe { w.bits(0xA5 << 8) });
+    // Stop, saw-wave mode, set prescaler
+    gpt.gtcr
+        .write(|w| unsafe { w.bits((prescaler as u32) << 24) });
+    // Allow start, stop and clear from GTSTR/GTSTP/GTCLR
+    gpt.gtssr.write(|w| unsafe { w.bits(1 << 31) });
+    gpt.gtpsr.write(|w| unsafe { w.bits(1 << 31) });
+    gpt.gtcsr.write(|w| unsafe { w.bits(1 << 31) });
+    // Force up counting (UD = 1, UDF = 1), then release UDF
+    gpt.gtuddtyc.write(|w| unsafe { w.bits(0b11) });
+    gpt.gtuddtyc.write(|w| unsafe { w.bits(0b01) });
+    // No capture sources, no pin output, no buffering
+    gpt.gticasr.write(|w| unsafe { w.bits(0) });
+    gpt.gticbsr.write(|w| unsafe { w.bits(0) });
+    gpt.gtior.write(|w| unsafe { w.bits(0) });
+    gpt.gtber.write(|w| unsafe { w.bits(0) });
+    // Full range and start from 0
+    gpt.gtpr.write(|w| unsafe { w.bits(T::max_count()) });
+    gpt.gtcnt.write(|w| unsafe { w.bits(0) });
+    gpt.gtst.write(|w| unsafe { w.bits(0) });
+    clock
+}
+
+/// Start counting.
+pub(crate) fn start<T: Instance>() {
+    let gpt = unsafe { &*T::peripheral() };
+    gpt.gtcr
+        .modify(|r, w| unsafe { w.bits(r.bits() | GTCR_CST) });
+}
+
+/// Stop counting.
+pub(crate) fn stop<T: Instance>() {
+    let gpt = unsafe { &*T::peripheral() };
+    gpt.gtcr
+        .modify(|r, w| unsafe { w.bits(r.bits() & !GTCR_CST) });
+}
+
+/// Route a pin to the GPT.
+pub(crate) fn connect_pin<P: Pin>() {
+    gpio::set_function::<P>(gpio::PinFunction::Gpt);
+}
+
+macro_rules! impl_instance {
+    ($($inst:ident: $channel:literal, $max:expr;)*) => {
+        $(
+            impl Instance for $inst {
+                fn peripheral() -> *const gpt320::RegisterBlock {
+                    $inst::ptr() as *const gpt320::RegisterBlock
+                }
+
+                fn state() -> &'static State {
+                    static STATE: State = State::new();
+                    &STATE
+                }
+
+                fn channel() -> u8 {
+                    $channel
+                }
+
+                fn max_count() -> u32 {
+                    $max
+                }
+            }
+        )*
+    };
+}
+
+impl_instance! {
+    GPT320: 0, u32::MAX;
+    GPT321: 1, u32::MAX;
+    GPT162: 2, 0xFFFF;
+    GPT163: 3, 0xFFFF;
+    GPT164: 4, 0xFFFF;
+    GPT165: 5, 0xFFFF;
+    GPT166: 6, 0xFFFF;
+    GPT167: 7, 0xFFFF;
+}
+
+macro_rules! impl_pins {
+    ($($pin:ident => $inst:ident $side:ident;)*) => {
+        $(
+            impl $side<$inst> for gpio::$pin {}
+        )*
+    };
+}
+
+impl_pins! {
+    P107 => GPT320 PinA;
+    P106 => GPT320 PinB;
+    P300 => GPT320 PinA;
+    P108 => GPT320 PinB;
+    P213 => GPT320 PinA;
+    P212 => GPT320 PinB;
+    P105 => GPT321 PinA;
+    P104 => GPT321 PinB;
+    P109 => GPT321 PinA;
+    P110 => GPT321 PinB;
+    P103 => GPT162 PinA;
+    P102 => GPT162 PinB;
+    P113 => GPT162 PinA;
+    P111 => GPT163 PinA;
+    P112 => GPT163 PinB;
+    P302 => GPT164 PinA;
+    P301 => GPT164 PinB;
+    P205 => GPT164 PinA;
+    P204 => GPT164 PinB;
+    P409 => GPT165 PinA;
+    P408 => GPT165 PinB;
+    P411 => GPT166 PinA;
+    P410 => GPT166 PinB;
+    P400 => GPT166 PinA;
+    P401 => GPT166 PinB;
+    P304 => GPT167 PinA;
+    P303 => GPT167 PinB;
+}
@@ -0,0 +1,111 @@
+//! RTIC monotonic on a GPT channel, leaving SysTick free.
+//!
+//! Built on the 64-bit [`timebase`](super::timebase), so it never wraps in
+//! practice. Create one with [`gpt_monotonic!`](crate::gpt_monotonic) and bind
+//! its two interrupts:
+//!
+//! ```ignore
+//! uno_r4_rust::gpt_monotonic!(Mono, ra4m1::GPT321, 3_000_000);
+//!
+//! bind_interrupts!(struct Irq {
+//!     IEL9 => gpt::timebase::OverflowHandler<ra4m1::GPT321>;
+//!     IEL10 => gpt::monotonic::AlarmHandler<Mono>;
+//! });
+//!
+//! #[init]
+//! fn init(cx: init::Context) -> (Shared, Local) {
+//!     let p = unsafe { ra4m1::Peripherals::steal() };
+//!     Mono::start(p.GPT321, 48_000_000, Irq);
+//!     // ...
+//! }
+//! ```
+//!
+//! The tick rate must be PCLKD divided by 1, 4, 16, 64, 256 or 1024,
+//! e.g. 3 MHz or 750 kHz from a 48 MHz PCLKD.
+
+use core::marker::PhantomData;
+
+pub use fugit;
+pub use rtic_time;
+use rtic_time::timer_queue::TimerQueueBackend;
+
+use crate::interrupts::{Handler, clear_interrupt};
+
+/// Alarm interrupt of a monotonic created with [`gpt_monotonic!`](crate::gpt_monotonic).
+pub struct AlarmHandler<M: TimerQueueBackend> {
+    _phantom: PhantomData<M>,
+}
+
+impl<M: TimerQueueBackend> Handler for AlarmHandler<M> {
+    unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
+        clear_interrupt(interrupt);
+        unsafe { M::timer_queue().on_monotonic_interrupt() };
+    }
+}
+
+/// Create an RTIC monotonic `$name` on the GPT channel `$gpt`, counting at
+/// `$tick_rate_hz`.
+#[macro_export]
+macro_rules! gpt_monotonic {
+    ($name:ident, $gpt:ty, $tick_rate_hz:expr) => {
+        /// RTIC monotonic on a GPT channel.
+        pub struct $name;
+
+        impl $name {
+            /// Start the timer and the timer queue.
+            ///
+            /// Panics if `$tick_rate_hz` can't be made from `pclkd_hz`.
+            pub fn start<IRQ>(_gpt: $gpt, pclkd_hz: u32, _irq: IRQ)
+            where
+                IRQ: $crate::interrupts::Binding<$crate::gpt::timebase::OverflowHandler<$gpt>>
+                    + $crate::interrupts::Binding<$crate::gpt::monotonic::AlarmHandler<$name>>,
+            {
+                let prescaler = $crate::gpt::timebase::prescaler_for(pclkd_hz, $tick_rate_hz)
+                    .expect("tick rate must be PCLKD / 1, 4, 16, 64, 256 or 1024");
+                $crate::gpt::timebase::start::<$gpt>(
+                    prescaler,
+                    <IRQ as $crate::interrupts::Binding<
+                        $crate::gpt::timebase::OverflowHandler<$gpt>,
+                    >>::interrupt(),
+                    <IRQ as $crate::interrupts::Binding<
+                        $crate::gpt::monotonic::AlarmHandler<$name>,
+                    >>::interrupt(),
+                );
+                <$name as $crate::gpt::monotonic::rtic_time::timer_queue::TimerQueueBackend>::timer_queue()
+                    .initialize($name);
+            }
+        }
+
+        impl $crate::gpt::monotonic::rtic_time::timer_queue::TimerQueueBackend for $name {
+            type Ticks = u64;
+
+            fn now() -> u64 {
+                $crate::gpt::timebase::now::<$gpt>()
+            }
+
+            fn set_compare(instant: u64) {
+                $crate::gpt::timebase::set_alarm::<$gpt>(instant)
+            }
+
+            fn clear_compare_flag() {
+                $crate::gpt::timebase::clear_alarm_flag::<$gpt>()
+            }
+
+            fn pend_interrupt() {
+                $crate::gpt::timebase::pend_alarm::<$gpt>()
+            }
+
+            fn timer_queue() -> &'static $crate::gpt::monotonic::rtic_time::timer_queue::TimerQueue<Self> {
+                static QUEUE: $crate::gpt::monotonic::rtic_time::timer_queue::TimerQueue<$name> =
+                    $crate::gpt::monotonic::rtic_time::timer_queue::TimerQueue::new();
+                &QUEUE
+            }
+        }
+
+        impl $crate::gpt::monotonic::rtic_time::monotonic::TimerQueueBasedMonotonic for $name {
+            type Backend = $name;
+            type Instant = $crate::gpt::monotonic::fugit::Instant<u64, 1, { $tick_rate_hz }>;
+            type Duration = $crate::gpt::monotonic::fugit::Duration<u64, 1, { $tick_rate_hz }>;
+        }
+    };
+}
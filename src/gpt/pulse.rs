@@ -0,0 +1,226 @@
+//! One-pulse output, a single pulse after a delay.
+//!
+//! Each trigger starts the counter from 0. The output goes active at the
+//! compare match after the delay and back at the end of the cycle, where the
+//! overflow event stops and clears the counter through the ELC. Nothing runs
+//! on the CPU once triggered, so the timing is exact to one count clock.
+//!
+//! Triggered from software with [`OnePulse::trigger`], or from any event
+//! linked to an ELC GPT input, e.g. a pin interrupt for a camera trigger:
+//!
+//! ```ignore
+//! let config = pulse::Config {
+//!     trigger: Some(elc::GptLink::B),
+//!     ..Default::default()
+//! };
+//! let mut pulse = pulse::OnePulse::new_a(p.GPT163, pins.p111, config)?;
+//! pulse.set_timing_ns(2_000, 10_000)?;
+//! elc::link(elc::Target::Gpt(elc::GptLink::B), event);
+//! ```
+
+use core::marker::PhantomData;
+
+use super::{Instance, PinA, PinB, Prescaler};
+use crate::clk::ClockGuard;
+use crate::elc::{self, GptLink, Target};
+
+/// One-pulse configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Frequency of PCLKD in Hz
+    pub pclkd_hz: u32,
+    /// Longest delay plus pulse width in nanoseconds, sets the resolution.
+    /// The smallest prescaler that fits it in the counter is used.
+    pub max_ns: u32,
+    /// ELC input the channel's overflow is linked to, to stop the counter.
+    /// Each one-pulse channel needs its own.
+    pub stop_link: GptLink,
+    /// ELC input that also starts a pulse, linked by the user
+    pub trigger: Option<GptLink>,
+    /// Output low during the pulse and high otherwise
+    pub active_low: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            pclkd_hz: 48_000_000,
+            max_ns: 1_000_000,
+            stop_link: GptLink::A,
+            trigger: None,
+            active_low: false,
+        }
+    }
+}
+
+/// One-pulse error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// Delay plus width doesn't fit in the counter at this prescaler
+    TooLong,
+    /// Width is less than one count clock
+    TooShort,
+    /// A pulse is in progress
+    Busy,
+}
+
+// GTIOR.GTIOx: initial low, low at cycle end, high at compare match
+const GTIO_PULSE: u32 = 0b00110;
+// Initial high, high at cycle end, low at compare match
+const GTIO_PULSE_LOW: u32 = 0b11001;
+// GTIOR.OxDFLT, output level while stopped
+const GTIOR_OADFLT: u32 = 1 << 6;
+// GTIOR.OxE, output enable
+const GTIOR_OAE: u32 = 1 << 8;
+// GTIOR B fields are 16 bits above A
+const GTIOR_B_SHIFT: u32 = 16;
+// GTSSR/GTPSR/GTCSR bit for ELC_GPTA, B - H follow
+const SELCA: u32 = 1 << 16;
+// GTSSR.CSTRT/GTPSR.CSTOP/GTCSR.CCLR, software start, stop and clear
+const SOFTWARE: u32 = 1 << 31;
+// Event offset of the overflow from the first event of the channel
+const EVENT_OVF: u8 = 6;
+
+/// One-pulse output on a pin of a GPT channel.
+pub struct OnePulse<T: Instance> {
+    tick_hz: u32,
+    // Output B rather than A
+    b: bool,
+    stop_link: GptLink,
+    _clock: ClockGuard,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Instance> OnePulse<T> {
+    /// Output pulses on the GTIOCnA pin of the channel.
+    pub fn new_a<P: PinA<T>>(instance: T, _pin: P, config: Config) -> Result<Self, Error> {
+        super::connect_pin::<P>();
+        Self::init(instance, config, false)
+    }
+
+    /// Output pulses on the GTIOCnB pin of the channel.
+    pub fn new_b<P: PinB<T>>(instance: T, _pin: P, config: Config) -> Result<Self, Error> {
+        super::connect_pin::<P>();
+        Self::init(instance, config, true)
+    }
+
+    fn init(_instance: T, config: Config, b: bool) -> Result<Self, Error> {
+        let prescalers = [
+            Prescaler::Div1,
+            Prescaler::Div4,
+            Prescaler::Div16,
+            Prescaler::Div64,
+            Prescaler::Div256,
+            Prescaler::Div1024,
+        ];
+        let prescaler = prescalers
+            .into_iter()
+            .find(|p| ns_to_ticks(config.max_ns, config.pclkd_hz / p.divisor()) <= T::max_count())
+            .ok_or(Error::TooLong)?;
+
+        let clock = super::init::<T>(prescaler);
+        let gpt = unsafe { &*T::peripheral() };
+        // Stop and clear on the overflow, start from software or the trigger
+        let stop = SELCA << config.stop_link as u32;
+        let start = config.trigger.map_or(0, |link| SELCA << link as u32);
+        gpt.gtpsr.write(|w| unsafe { w.bits(SOFTWARE | stop) });
+        gpt.gtcsr.write(|w| unsafe { w.bits(SOFTWARE | stop) });
+        gpt.gtssr.write(|w| unsafe { w.bits(SOFTWARE | start) });
+        elc::link(
+            Target::Gpt(config.stop_link),
+            T::event_base().offset(EVENT_OVF),
+        );
+
+        let gtio = if config.active_low {
+            GTIO_PULSE_LOW | GTIOR_OADFLT
+        } else {
+            GTIO_PULSE
+        };
+        let shift = if b { GTIOR_B_SHIFT } else { 0 };
+        gpt.gtior
+            .write(|w| unsafe { w.bits((gtio | GTIOR_OAE) << shift) });
+
+        let mut pulse = Self {
+            tick_hz: config.pclkd_hz / prescaler.divisor(),
+            b,
+            stop_link: config.stop_link,
+            _clock: clock,
+            _phantom: PhantomData,
+        };
+        pulse.set_timing_ticks(1, 1)?;
+        Ok(pulse)
+    }
+
+    /// Frequency of the count clock in Hz.
+    pub fn tick_hz(&self) -> u32 {
+        self.tick_hz
+    }
+
+    /// Length of one count clock in nanoseconds, rounded down.
+    pub fn resolution_ns(&self) -> u32 {
+        1_000_000_000 / self.tick_hz
+    }
+
+    /// Set the delay from the trigger to the pulse and the pulse width in
+    /// nanoseconds, rounded down to whole count clocks.
+    pub fn set_timing_ns(&mut self, delay_ns: u32, width_ns: u32) -> Result<(), Error> {
+        self.set_timing_ticks(
+            ns_to_ticks(delay_ns, self.tick_hz),
+            ns_to_ticks(width_ns, self.tick_hz),
+        )
+    }
+
+    /// Set the delay and width in count clocks.
+    ///
+    /// The delay is at least one count clock, the compare match at 0 is
+    /// missed when the counter starts.
+    pub fn set_timing_ticks(&mut self, delay: u32, width: u32) -> Result<(), Error> {
+        if width == 0 {
+            return Err(Error::TooShort);
+        }
+        if self.is_busy() {
+            return Err(Error::Busy);
+        }
+        let delay = delay.max(1);
+        // Active from the compare match to the end of the cycle at GTPR
+        let end = delay
+            .checked_add(width - 1)
+            .filter(|end| *end <= T::max_count())
+            .ok_or(Error::TooLong)?;
+        let gpt = unsafe { &*T::peripheral() };
+        if self.b {
+            gpt.gtccrb.write(|w| unsafe { w.bits(delay) });
+        } else {
+            gpt.gtccra.write(|w| unsafe { w.bits(delay) });
+        }
+        gpt.gtpr.write(|w| unsafe { w.bits(end) });
+        Ok(())
+    }
+
+    /// Start a pulse from software.
+    pub fn trigger(&self) -> Result<(), Error> {
+        if self.is_busy() {
+            return Err(Error::Busy);
+        }
+        super::start::<T>();
+        Ok(())
+    }
+
+    /// Whether a pulse is in progress, the counter runs until it ends.
+    pub fn is_busy(&self) -> bool {
+        let gpt = unsafe { &*T::peripheral() };
+        gpt.gtcr.read().bits() & super::GTCR_CST != 0
+    }
+}
+
+impl<T: Instance> Drop for OnePulse<T> {
+    fn drop(&mut self) {
+        super::stop::<T>();
+        elc::unlink(Target::Gpt(self.stop_link));
+    }
+}
+
+fn ns_to_ticks(ns: u32, tick_hz: u32) -> u32 {
+    (ns as u64 * tick_hz as u64 / 1_000_000_000).min(u32::MAX as u64) as u32
+}
@@ -0,0 +1,173 @@
+//! Saw-wave PWM output.
+//!
+//! GTIOCnA is driven from GTCCRA and GTIOCnB from GTCCRB. Duty updates are
+//! written to the buffer registers (GTCCRC/GTCCRE) and take effect at the
+//! end of the current cycle, so changes never produce a glitch.
+
+use core::marker::PhantomData;
+
+use super::{Instance, PinA, PinB, Prescaler};
+use crate::clk::ClockGuard;
+
+/// PWM configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Frequency of PCLKD in Hz
+    pub pclkd_hz: u32,
+    /// PWM frequency in Hz
+    pub frequency_hz: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            pclkd_hz: 48_000_000,
+            frequency_hz: 1_000,
+        }
+    }
+}
+
+/// Output pin of a GPT channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    /// GTIOCnA, duty from GTCCRA
+    A,
+    /// GTIOCnB, duty from GTCCRB
+    B,
+}
+
+// GTIOR.GTIOx: initial low, high at cycle end, low at compare match
+const GTIO_PWM: u32 = 0b01001;
+// GTIOR.OxE, output enable
+const GTIOR_OAE: u32 = 1 << 8;
+const GTIOR_OBE: u32 = 1 << 24;
+// GTBER single buffer for GTCCRA (from GTCCRC) and GTCCRB (from GTCCRE)
+const GTBER_CCRA_SINGLE: u32 = 0b01 << 16;
+const GTBER_CCRB_SINGLE: u32 = 0b01 << 18;
+// GTUDDTYC.OxDTY, forced 0% and 100% duty
+const OADTY_SHIFT: u32 = 16;
+const OBDTY_SHIFT: u32 = 24;
+const DTY_0: u32 = 0b10;
+const DTY_100: u32 = 0b11;
+
+/// PWM on the A and B outputs of a GPT channel.
+pub struct Pwm<T: Instance> {
+    period: u32,
+    tick_hz: u32,
+    _clock: ClockGuard,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Instance> Pwm<T> {
+    /// Configure the channel for PWM and start counting.
+    ///
+    /// The smallest prescaler that fits the period in the counter is used.
+    /// Outputs stay disabled until [`enable_a`](Self::enable_a) or
+    /// [`enable_b`](Self::enable_b) is called.
+    pub fn new(_instance: T, config: Config) -> Self {
+        let prescalers = [
+            Prescaler::Div1,
+            Prescaler::Div4,
+            Prescaler::Div16,
+            Prescaler::Div64,
+            Prescaler::Div256,
+            Prescaler::Div1024,
+        ];
+        let (prescaler, period) = prescalers
+            .iter()
+            .map(|p| {
+                let ticks = config.pclkd_hz / p.divisor() / config.frequency_hz.max(1);
+                (*p, ticks)
+            })
+            .find(|(_, ticks)| *ticks <= T::max_count())
+            .unwrap_or((Prescaler::Div1024, T::max_count()));
+
+        let clock = super::init::<T>(prescaler);
+        let gpt = unsafe { &*T::peripheral() };
+        // Counter runs 0..=GTPR
+        gpt.gtpr
+            .write(|w| unsafe { w.bits(period.saturating_sub(1)) });
+        gpt.gtber
+            .write(|w| unsafe { w.bits(GTBER_CCRA_SINGLE | GTBER_CCRB_SINGLE) });
+        // Start at 0% duty on both outputs
+        gpt.gtuddtyc
+            .write(|w| unsafe { w.bits(0b01 | (DTY_0 << OADTY_SHIFT) | (DTY_0 << OBDTY_SHIFT)) });
+        gpt.gtior
+            .write(|w| unsafe { w.bits(GTIO_PWM | (GTIO_PWM << 16)) });
+        super::start::<T>();
+
+        Self {
+            period,
+            tick_hz: config.pclkd_hz / prescaler.divisor(),
+            _clock: clock,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Route the GTIOCnA pin to the timer and enable its output.
+    pub fn enable_a<P: PinA<T>>(&mut self, _pin: P) {
+        super::connect_pin::<P>();
+        let gpt = unsafe { &*T::peripheral() };
+        gpt.gtior
+            .modify(|r, w| unsafe { w.bits(r.bits() | GTIOR_OAE) });
+    }
+
+    /// Route the GTIOCnB pin to the timer and enable its output.
+    pub fn enable_b<P: PinB<T>>(&mut self, _pin: P) {
+        super::connect_pin::<P>();
+        let gpt = unsafe { &*T::peripheral() };
+        gpt.gtior
+            .modify(|r, w| unsafe { w.bits(r.bits() | GTIOR_OBE) });
+    }
+
+    /// Disable an output, the pin is driven low.
+    pub fn disable(&mut self, channel: Channel) {
+        let gpt = unsafe { &*T::peripheral() };
+        let mask = match channel {
+            Channel::A => GTIOR_OAE,
+            Channel::B => GTIOR_OBE,
+        };
+        gpt.gtior.modify(|r, w| unsafe { w.bits(r.bits() & !mask) });
+    }
+
+    /// Duty value corresponding to 100%.
+    pub fn max_duty(&self) -> u32 {
+        self.period
+    }
+
+    /// Frequency of the count clock in Hz.
+    pub fn tick_hz(&self) -> u32 {
+        self.tick_hz
+    }
+
+    /// Set the high time of an output in counts, from 0 to [`max_duty`](Self::max_duty).
+    ///
+    /// Takes effect at the end of the current cycle.
+    pub fn set_duty(&mut self, channel: Channel, duty: u32) {
+        let gpt = unsafe { &*T::peripheral() };
+        let shift = match channel {
+            Channel::A => OADTY_SHIFT,
+            Channel::B => OBDTY_SHIFT,
+        };
+        // 0% and 100% can't be reached with compare match, force them
+        let forced = if duty == 0 {
+            DTY_0
+        } else if duty >= self.period {
+            DTY_100
+        } else {
+            match channel {
+                Channel::A => gpt.gtccrc.write(|w| unsafe { w.bits(duty) }),
+                Channel::B => gpt.gtccre.write(|w| unsafe { w.bits(duty) }),
+            }
+            0
+        };
+        gpt.gtuddtyc
+            .modify(|r, w| unsafe { w.bits((r.bits() & !(0b11 << shift)) | (forced << shift)) });
+    }
+
+    /// Set the high time of an output in microseconds.
+    pub fn set_pulse_us(&mut self, channel: Channel, us: u32) {
+        let duty = (us as u64 * self.tick_hz as u64 / 1_000_000) as u32;
+        self.set_duty(channel, duty);
+    }
+}
@@ -0,0 +1,132 @@
+//! embassy time driver on a GPT channel.
+//!
+//! Enabled with the `embassy` feature, which makes this crate the provider
+//! of the time driver, so `embassy_time::Timer::after()` works in an
+//! `embassy_executor`. Any channel can be used, it's chosen at runtime by
+//! [`init`], which must be called before the executor starts:
+//!
+//! ```ignore
+//! bind_interrupts!(struct Irq {
+//!     IEL9 => gpt::timebase::OverflowHandler<ra4m1::GPT321>;
+//!     IEL10 => gpt::time_driver::AlarmHandler<ra4m1::GPT321>;
+//! });
+//!
+//! let p = unsafe { ra4m1::Peripherals::steal() };
+//! gpt::time_driver::init(p.GPT321, 48_000_000, Irq);
+//! ```
+//!
+//! The embassy-time tick rate (its `tick-hz-*` feature) must be PCLKD divided
+//! by 1, 4, 16, 64, 256 or 1024, e.g. `tick-hz-3_000_000` with a 48 MHz PCLKD.
+//! Time reads 0 until `init` is called.
+
+use core::cell::{Cell, RefCell};
+use core::marker::PhantomData;
+use core::task::Waker;
+
+use critical_section::{CriticalSection, Mutex};
+use embassy_time_driver::{Driver, TICK_HZ};
+use embassy_time_queue_utils::Queue;
+
+use super::Instance;
+use super::timebase::{self, OverflowHandler};
+use crate::interrupts::{Binding, Handler, clear_interrupt};
+
+/// Alarm interrupt of the time driver.
+pub struct AlarmHandler<T: Instance> {
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Instance> Handler for AlarmHandler<T> {
+    unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
+        clear_interrupt(interrupt);
+        DRIVER.on_alarm();
+    }
+}
+
+// Time base functions of the channel chosen in `init`
+#[derive(Clone, Copy)]
+struct Timer {
+    now: fn() -> u64,
+    set_alarm: fn(u64),
+    clear_alarm_flag: fn(),
+}
+
+struct GptDriver {
+    timer: Mutex<Cell<Option<Timer>>>,
+    queue: Mutex<RefCell<Queue>>,
+}
+
+embassy_time_driver::time_driver_impl!(static DRIVER: GptDriver = GptDriver {
+    timer: Mutex::new(Cell::new(None)),
+    queue: Mutex::new(RefCell::new(Queue::new())),
+});
+
+/// Start the time driver on a GPT channel.
+///
+/// Panics if the embassy-time tick rate can't be made from `pclkd_hz`.
+pub fn init<T: Instance, IRQ>(_gpt: T, pclkd_hz: u32, _irq: IRQ)
+where
+    IRQ: Binding<OverflowHandler<T>> + Binding<AlarmHandler<T>>,
+{
+    let prescaler = timebase::prescaler_for(pclkd_hz, TICK_HZ as u32)
+        .expect("tick rate must be PCLKD / 1, 4, 16, 64, 256 or 1024");
+    let timer = Timer {
+        now: timebase::now::<T>,
+        set_alarm: timebase::set_alarm::<T>,
+        clear_alarm_flag: timebase::clear_alarm_flag::<T>,
+    };
+    critical_section::with(|cs| DRIVER.timer.borrow(cs).set(Some(timer)));
+    timebase::start::<T>(
+        prescaler,
+        <IRQ as Binding<OverflowHandler<T>>>::interrupt(),
+        <IRQ as Binding<AlarmHandler<T>>>::interrupt(),
+    );
+}
+
+impl GptDriver {
+    fn timer(&self, cs: CriticalSection) -> Option<Timer> {
+        self.timer.borrow(cs).get()
+    }
+
+    fn on_alarm(&self) {
+        critical_section::with(|cs| {
+            let Some(timer) = self.timer(cs) else {
+                return;
+            };
+            (timer.clear_alarm_flag)();
+            // The alarm may fire early, the queue only wakes expired timers
+            self.rearm(cs, timer);
+        })
+    }
+
+    // Set the alarm for the next expiration, waking timers that expire meanwhile
+    fn rearm(&self, cs: CriticalSection, timer: Timer) {
+        let mut queue = self.queue.borrow(cs).borrow_mut();
+        loop {
+            let next = queue.next_expiration((timer.now)());
+            if next == u64::MAX {
+                return;
+            }
+            (timer.set_alarm)(next);
+            // Passed before the compare was written, go round again
+            if (timer.now)() < next {
+                return;
+            }
+        }
+    }
+}
+
+impl Driver for GptDriver {
+    fn now(&self) -> u64 {
+        critical_section::with(|cs| self.timer(cs)).map_or(0, |timer| (timer.now)())
+    }
+
+    fn schedule_wake(&self, at: u64, waker: &Waker) {
+        critical_section::with(|cs| {
+            let changed = self.queue.borrow(cs).borrow_mut().schedule_wake(at, waker);
+            if let (true, Some(timer)) = (changed, self.timer(cs)) {
+                self.rearm(cs, timer);
+            }
+        })
+    }
+}
@@ -0,0 +1,142 @@
+//! 64-bit time base from a free running GPT channel.
+//!
+//! The counter runs up over its full range and the overflow interrupt counts
+//! the wraps, extending it to 64 bits. Compare A raises an alarm interrupt at
+//! a chosen time. This is the common part of the RTIC monotonic and the
+//! embassy time driver, which leave SysTick free.
+//!
+//! The GPT runs in sleep mode but stops in software standby, so the time
+//! base is kept across `wfi` only while the GPT module clock runs.
+
+use core::marker::PhantomData;
+use core::sync::atomic::Ordering;
+
+use cortex_m::interrupt::InterruptNumber;
+use cortex_m::peripheral::NVIC;
+use ra4m1::Interrupt;
+
+use super::{GTST_TCFA, Instance, Prescaler};
+use crate::interrupts::{Handler, clear_interrupt, map_and_enable_interrupt};
+
+// GTST.TCFPO, overflow flag
+const GTST_TCFPO: u32 = 1 << 6;
+
+// Event offsets from the first event of the channel
+const EVENT_CCMPA: u8 = 0;
+const EVENT_OVF: u8 = 6;
+
+/// Counts wraps of the counter, bind to an interrupt of the channel.
+pub struct OverflowHandler<T: Instance> {
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Instance> Handler for OverflowHandler<T> {
+    unsafe fn on_interrupt(interrupt: Interrupt) {
+        clear_interrupt(interrupt);
+        let gpt = unsafe { &*T::peripheral() };
+        // Flag and count change together, as seen by `now`
+        critical_section::with(|_| {
+            gpt.gtst
+                .modify(|r, w| unsafe { w.bits(r.bits() & !GTST_TCFPO) });
+            T::state().count.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+}
+
+// Pending an interrupt by number, the alarm interrupt is only known at runtime
+#[derive(Clone, Copy)]
+struct Irqn(u16);
+
+unsafe impl InterruptNumber for Irqn {
+    fn number(self) -> u16 {
+        self.0
+    }
+}
+
+/// Prescaler giving `tick_hz` from `pclkd_hz`, if there is one.
+pub fn prescaler_for(pclkd_hz: u32, tick_hz: u32) -> Option<Prescaler> {
+    [
+        Prescaler::Div1,
+        Prescaler::Div4,
+        Prescaler::Div16,
+        Prescaler::Div64,
+        Prescaler::Div256,
+        Prescaler::Div1024,
+    ]
+    .into_iter()
+    .find(|p| pclkd_hz % p.divisor() == 0 && pclkd_hz / p.divisor() == tick_hz)
+}
+
+/// Start the channel as a time base from 0.
+///
+/// Maps the overflow event to `overflow` and compare A to `alarm`, both at
+/// the highest priority that critical sections mask, as [`now`] relies on
+/// them. The alarm handler is up to the user of the time base.
+pub fn start<T: Instance>(prescaler: Prescaler, overflow: Interrupt, alarm: Interrupt) {
+    // The time base runs for good
+    core::mem::forget(super::init::<T>(prescaler));
+    let state = T::state();
+    state.count.store(0, Ordering::Relaxed);
+    // a holds the alarm interrupt number
+    state.a.store(alarm.number() as u32, Ordering::Relaxed);
+
+    let event_base = T::event_base();
+    map_and_enable_interrupt(overflow, event_base.offset(EVENT_OVF));
+    map_and_enable_interrupt(alarm, event_base.offset(EVENT_CCMPA));
+    // BASEPRI critical sections can't mask priority 0
+    #[cfg(not(feature = "critical-section-basepri"))]
+    let priority = 0;
+    #[cfg(feature = "critical-section-basepri")]
+    let priority = crate::basepri::ceiling();
+    unsafe {
+        let mut p = cortex_m::Peripherals::steal();
+        p.NVIC.set_priority(overflow, priority);
+        p.NVIC.set_priority(alarm, priority);
+    }
+    super::start::<T>();
+}
+
+/// Ticks since [`start`].
+pub fn now<T: Instance>() -> u64 {
+    let gpt = unsafe { &*T::peripheral() };
+    critical_section::with(|_| {
+        let mut wraps = T::state().count.load(Ordering::Relaxed);
+        let mut count = gpt.gtcnt.read().bits();
+        // Wrapped but not counted yet, read again as the first read may be
+        // from before the wrap
+        if gpt.gtst.read().bits() & GTST_TCFPO != 0 {
+            wraps = wraps.wrapping_add(1);
+            count = gpt.gtcnt.read().bits();
+        }
+        wraps as u64 * (T::max_count() as u64 + 1) + count as u64
+    })
+}
+
+/// Raise the alarm interrupt when the time base reaches `at`.
+///
+/// Times more than one counter range away fire early, at a compare match
+/// in an earlier wrap, so the caller must check the time in the handler.
+/// Times in the past don't fire until the counter comes round again, use
+/// [`pend_alarm`] for those.
+pub fn set_alarm<T: Instance>(at: u64) {
+    let gpt = unsafe { &*T::peripheral() };
+    let max = T::max_count() as u64;
+    let compare = if at.wrapping_sub(now::<T>()) <= max {
+        (at & max) as u32
+    } else {
+        0
+    };
+    gpt.gtccra.write(|w| unsafe { w.bits(compare) });
+}
+
+/// Clear the compare flag, from the alarm handler.
+pub fn clear_alarm_flag<T: Instance>() {
+    let gpt = unsafe { &*T::peripheral() };
+    gpt.gtst
+        .modify(|r, w| unsafe { w.bits(r.bits() & !GTST_TCFA) });
+}
+
+/// Run the alarm handler now.
+pub fn pend_alarm<T: Instance>() {
+    NVIC::pend(Irqn(T::state().a.load(Ordering::Relaxed) as u16));
+}
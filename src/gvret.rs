@@ -0,0 +1,260 @@
+//! GVRET binary protocol, for SavvyCAN.
+//!
+//! SavvyCAN talks GVRET to ESP32RET and similar adapters. Compared to
+//! [`slcan`](crate::slcan) frames are binary, so about half the size on the
+//! UART, and carry microsecond timestamps. Frames from the host are sent on
+//! the bus, frames from the bus are forwarded to the host.
+//!
+//! ```ignore
+//! cycles::start(48_000_000);
+//! let mut gvret = Gvret::new(uart, can);
+//! loop {
+//!     gvret.poll(cycles::micros() as u32)?;
+//! }
+//! ```
+//!
+//! In SavvyCAN add a serial connection of type GVRET on the UART. The bus
+//! is opened when SavvyCAN sets its speed, there's one bus, number 0.
+
+use embedded_can::{ExtendedId, Frame as _, Id, StandardId};
+use embedded_io::{Read, ReadReady, Write};
+
+use crate::can::{Can, Config, Frame};
+use crate::slcan::Channel;
+pub use crate::slcan::Error;
+
+/// Build number reported to the host.
+pub const BUILD: u16 = 618;
+
+// Start of every command and reply
+const START: u8 = 0xF1;
+
+// Commands
+const BUILD_CAN_FRAME: u8 = 0x00;
+const TIME_SYNC: u8 = 0x01;
+const GET_DIG_INPUTS: u8 = 0x02;
+const GET_ANALOG_INPUTS: u8 = 0x03;
+const SET_DIG_OUTPUTS: u8 = 0x04;
+const SETUP_CANBUS: u8 = 0x05;
+const GET_CANBUS_PARAMS: u8 = 0x06;
+const GET_DEVICE_INFO: u8 = 0x07;
+const SET_SINGLEWIRE_MODE: u8 = 0x08;
+const KEEP_ALIVE: u8 = 0x09;
+const SET_SYSTEM_TYPE: u8 = 0x0A;
+const ECHO_CAN_FRAME: u8 = 0x0B;
+const GET_NUM_BUSES: u8 = 0x0C;
+const GET_EXT_BUSES: u8 = 0x0D;
+const SET_EXT_BUSES: u8 = 0x0E;
+
+// Frame ID bit 31, extended ID
+const ID_EXTENDED: u32 = 1 << 31;
+// SETUP_CANBUS speed word, flags valid, enabled and listen only
+const SPEED_FLAGS: u32 = 1 << 31;
+const SPEED_ENABLE: u32 = 1 << 30;
+const SPEED_LISTEN_ONLY: u32 = 1 << 29;
+const SPEED_MASK: u32 = 0xF_FFFF;
+// GET_CANBUS_PARAMS flags
+const PARAMS_ENABLED: u8 = 1 << 0;
+const PARAMS_LISTEN_ONLY: u8 = 1 << 4;
+
+// Longest command after START, a frame with 8 bytes
+const MAX_COMMAND: usize = 16;
+
+/// GVRET adapter on serial port `P`.
+pub struct Gvret<P: Read + ReadReady + Write> {
+    port: P,
+    channel: Channel,
+    // Command after START, empty when waiting for START
+    command: [u8; MAX_COMMAND],
+    len: usize,
+    receiving: bool,
+    bitrate: u32,
+    listen_only: bool,
+}
+
+impl<P: Read + ReadReady + Write> Gvret<P> {
+    /// Bridge `port` and `can`, closed until the host sets the speed. The
+    /// mailboxes are configured as for [`Slcan`](crate::slcan::Slcan).
+    pub fn new(port: P, can: Can<Config>) -> Self {
+        Self {
+            port,
+            channel: Channel::new(can),
+            command: [0; MAX_COMMAND],
+            len: 0,
+            receiving: false,
+            bitrate: 0,
+            listen_only: false,
+        }
+    }
+
+    /// Handle the commands received and forward frames from the bus.
+    ///
+    /// `time_us` is the time in microseconds, e.g. from
+    /// [`cycles::micros`](crate::cycles::micros), used for the frame
+    /// timestamps and time sync.
+    pub fn poll(&mut self, time_us: u32) -> Result<(), Error> {
+        while self.port.read_ready().map_err(|_| Error::Port)? {
+            let mut byte = [0u8; 1];
+            if self.port.read(&mut byte).map_err(|_| Error::Port)? == 0 {
+                break;
+            }
+            if !self.receiving {
+                // The 0xE7 bytes enabling binary mode, and anything else
+                // between commands, are ignored
+                self.receiving = byte[0] == START;
+                continue;
+            }
+            self.command[self.len] = byte[0];
+            self.len += 1;
+            if command_len(&self.command[..self.len]).is_some_and(|len| self.len >= len) {
+                self.run(time_us)?;
+                self.len = 0;
+                self.receiving = false;
+            }
+        }
+
+        if let Some(can) = self.channel.running() {
+            while let Some(frame) = can.try_receive_frame() {
+                send_frame(&mut self.port, &frame, time_us)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the host has opened the bus.
+    pub fn is_open(&self) -> bool {
+        self.channel.running().is_some()
+    }
+
+    // Run the command in the buffer
+    fn run(&mut self, time_us: u32) -> Result<(), Error> {
+        let command = self.command;
+        let args = &command[1..self.len];
+        match command[0] {
+            BUILD_CAN_FRAME => {
+                if let (Some(frame), Some(can)) = (decode(args), self.channel.running()) {
+                    // Nothing is reported to the host if no mailbox is free
+                    let _ = can.send_frame(frame);
+                }
+            }
+            ECHO_CAN_FRAME => {
+                if let Some(frame) = decode(args) {
+                    send_frame(&mut self.port, &frame, time_us)?;
+                }
+            }
+            TIME_SYNC => {
+                let mut reply = [START, TIME_SYNC, 0, 0, 0, 0];
+                reply[2..].copy_from_slice(&time_us.to_le_bytes());
+                self.reply(&reply)?;
+            }
+            GET_DIG_INPUTS => self.reply(&[START, GET_DIG_INPUTS, 0, 0])?,
+            GET_ANALOG_INPUTS => {
+                let mut reply = [0; 2 + 2 * 7 + 1];
+                reply[..2].copy_from_slice(&[START, GET_ANALOG_INPUTS]);
+                self.reply(&reply)?;
+            }
+            SETUP_CANBUS => {
+                let speed = u32::from_le_bytes([args[0], args[1], args[2], args[3]]);
+                self.setup(speed)?;
+            }
+            GET_CANBUS_PARAMS => {
+                let mut flags = 0;
+                if self.is_open() {
+                    flags |= PARAMS_ENABLED;
+                }
+                if self.listen_only {
+                    flags |= PARAMS_LISTEN_ONLY;
+                }
+                let mut reply = [START, GET_CANBUS_PARAMS, flags, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+                reply[3..7].copy_from_slice(&self.bitrate.to_le_bytes());
+                self.reply(&reply)?;
+            }
+            GET_DEVICE_INFO => {
+                let build = BUILD.to_le_bytes();
+                self.reply(&[START, GET_DEVICE_INFO, build[0], build[1], 0x20, 0, 0, 0])?;
+            }
+            KEEP_ALIVE => self.reply(&[START, KEEP_ALIVE, 0xDE, 0xAD])?,
+            GET_NUM_BUSES => self.reply(&[START, GET_NUM_BUSES, 1])?,
+            GET_EXT_BUSES => {
+                let mut reply = [0; 2 + 15];
+                reply[..2].copy_from_slice(&[START, GET_EXT_BUSES]);
+                self.reply(&reply)?;
+            }
+            // Outputs, single wire CAN, system type and the other buses
+            // don't exist on the board
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Apply the speed word of SETUP_CANBUS for bus 0
+    fn setup(&mut self, speed: u32) -> Result<(), Error> {
+        let (enable, listen_only, bitrate) = if speed & SPEED_FLAGS != 0 {
+            (
+                speed & SPEED_ENABLE != 0,
+                speed & SPEED_LISTEN_ONLY != 0,
+                speed & SPEED_MASK,
+            )
+        } else {
+            (speed != 0, false, speed)
+        };
+        self.channel.close()?;
+        if bitrate != 0 && self.channel.set_bitrate(bitrate)? {
+            self.bitrate = bitrate;
+        }
+        self.listen_only = listen_only;
+        if enable {
+            self.channel.open(listen_only)?;
+        }
+        Ok(())
+    }
+
+    fn reply(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.port.write_all(bytes).map_err(|_| Error::Port)
+    }
+}
+
+// Total length of a command from its first bytes, None until it's known
+fn command_len(command: &[u8]) -> Option<usize> {
+    match command[0] {
+        // ID, bus, length, data then a checksum that isn't checked
+        BUILD_CAN_FRAME | ECHO_CAN_FRAME => command
+            .get(6)
+            .map(|len| 7 + (*len as usize & 0xF).min(8) + 1),
+        SETUP_CANBUS => Some(9),
+        SET_DIG_OUTPUTS | SET_SINGLEWIRE_MODE | SET_SYSTEM_TYPE => Some(2),
+        SET_EXT_BUSES => Some(13),
+        _ => Some(1),
+    }
+}
+
+// Frame from the arguments of BUILD_CAN_FRAME or ECHO_CAN_FRAME
+fn decode(args: &[u8]) -> Option<Frame> {
+    let raw = u32::from_le_bytes([args[0], args[1], args[2], args[3]]);
+    let len = (args[5] as usize & 0xF).min(8);
+    let id: Id = if raw & ID_EXTENDED != 0 {
+        ExtendedId::new(raw & !ID_EXTENDED)?.into()
+    } else {
+        StandardId::new(raw as u16)?.into()
+    };
+    Frame::new(id, &args[6..6 + len])
+}
+
+// Send a frame to the host, from bus 0
+fn send_frame<P: Write>(port: &mut P, frame: &Frame, time_us: u32) -> Result<(), Error> {
+    let id = match frame.id() {
+        Id::Standard(id) => id.as_raw() as u32,
+        Id::Extended(id) => id.as_raw() | ID_EXTENDED,
+    };
+    let data = frame.data();
+    let mut out = [0u8; 2 + 4 + 4 + 1 + 8 + 1];
+    out[0] = START;
+    out[1] = BUILD_CAN_FRAME;
+    out[2..6].copy_from_slice(&time_us.to_le_bytes());
+    out[6..10].copy_from_slice(&id.to_le_bytes());
+    out[10] = data.len() as u8;
+    out[11..11 + data.len()].copy_from_slice(data);
+    // Checksum, not checked by SavvyCAN
+    let len = 11 + data.len() + 1;
+    port.write_all(&out[..len]).map_err(|_| Error::Port)
+}
@@ -0,0 +1,201 @@
+//! HC-SR04 ultrasonic distance sensor.
+//!
+//! A 10 us pulse on TRIG starts a measurement and the sensor then holds ECHO
+//! high for the round trip time of the sound, about 38 ms if nothing is in
+//! range. ECHO is timed with GPT input capture, so the result is exact to one
+//! count clock whatever the interrupt latency, and the timeout is taken from
+//! the same counter.
+//!
+//! ```ignore
+//! bind_interrupts!(struct Irq {
+//!     IEL9 => gpt::capture::RisingHandler<ra4m1::GPT164>;
+//!     IEL10 => gpt::capture::FallingHandler<ra4m1::GPT164>;
+//! });
+//!
+//! let mut sensor = hcsr04::Hcsr04::new(p.GPT164, pins.p303, pins.p302, Default::default(), Irq);
+//! sensor.set_temperature(235);
+//! let cm = sensor.measure_cm()?;
+//! ```
+
+use core::future::poll_fn;
+use core::task::Poll;
+
+use crate::gpio::{self, Pin};
+use crate::gpt::capture::{self, Capture, FallingHandler, RisingHandler};
+use crate::gpt::{Instance, PinA, Prescaler};
+use crate::interrupts::Binding;
+
+/// Echoes this long or longer mean nothing was in range.
+pub const NO_ECHO_US: u32 = 36_000;
+
+/// Length of the trigger pulse.
+const TRIGGER_US: u32 = 10;
+
+/// Sensor configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Frequency of PCLKD in Hz
+    pub pclkd_hz: u32,
+    /// Count clock prescaler. The counter range must be longer than
+    /// `timeout_us`, the default fits a 16-bit channel.
+    pub prescaler: Prescaler,
+    /// Give up waiting for the end of the echo after this long
+    pub timeout_us: u32,
+    /// Air temperature in tenths of a degree C, for the speed of sound
+    pub temperature: i16,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            pclkd_hz: 48_000_000,
+            prescaler: Prescaler::Div64,
+            timeout_us: 60_000,
+            temperature: 200,
+        }
+    }
+}
+
+/// Measurement error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The echo didn't start or end in time, check the wiring
+    Timeout,
+    /// Nothing was in range
+    NoEcho,
+}
+
+/// HC-SR04 with TRIG on any pin and ECHO on the GTIOCnA pin of a GPT channel.
+pub struct Hcsr04<T: Instance, TRIG: Pin> {
+    capture: Capture<T>,
+    timeout_ticks: u32,
+    temperature: i16,
+    // Counter value when the last measurement was triggered
+    started: u32,
+    _trigger: TRIG,
+}
+
+impl<T: Instance, TRIG: Pin> Hcsr04<T, TRIG> {
+    /// Set up the trigger output and the echo capture.
+    pub fn new<ECHO: PinA<T>, IRQ>(
+        gpt: T,
+        trigger: TRIG,
+        echo: ECHO,
+        config: Config,
+        irq: IRQ,
+    ) -> Self
+    where
+        IRQ: Binding<RisingHandler<T>> + Binding<FallingHandler<T>>,
+    {
+        gpio::set_output::<TRIG>(false);
+        let capture = Capture::new_a(
+            gpt,
+            echo,
+            capture::Config {
+                prescaler: config.prescaler,
+                pclkd_hz: config.pclkd_hz,
+                noise_filter: true,
+            },
+            irq,
+        );
+        let timeout_ticks =
+            us_to_ticks(config.timeout_us, capture.tick_hz()).min(T::max_count() - 1);
+        Self {
+            capture,
+            timeout_ticks,
+            temperature: config.temperature,
+            started: 0,
+            _trigger: trigger,
+        }
+    }
+
+    /// Set the air temperature in tenths of a degree C.
+    ///
+    /// The speed of sound changes by about 0.18% per degree, so 10 degrees
+    /// off is 1.8 cm at a metre.
+    pub fn set_temperature(&mut self, temperature: i16) {
+        self.temperature = temperature;
+    }
+
+    /// Measure the distance in millimetres, blocking until the echo ends.
+    pub fn measure_mm(&mut self) -> Result<u32, Error> {
+        self.trigger();
+        loop {
+            if let Poll::Ready(result) = self.poll() {
+                return result;
+            }
+        }
+    }
+
+    /// Measure the distance in centimetres, blocking until the echo ends.
+    pub fn measure_cm(&mut self) -> Result<u32, Error> {
+        self.measure_mm().map(|mm| (mm + 5) / 10)
+    }
+
+    /// Measure the distance in millimetres.
+    ///
+    /// Yields between checks of the echo, so other tasks run meanwhile, but
+    /// the task is woken again straight away rather than by the interrupt.
+    pub async fn measure_mm_async(&mut self) -> Result<u32, Error> {
+        self.trigger();
+        poll_fn(|cx| {
+            let result = self.poll();
+            if result.is_pending() {
+                cx.waker().wake_by_ref();
+            }
+            result
+        })
+        .await
+    }
+
+    /// Measure the distance in centimetres, see [`measure_mm_async`](Self::measure_mm_async).
+    pub async fn measure_cm_async(&mut self) -> Result<u32, Error> {
+        self.measure_mm_async().await.map(|mm| (mm + 5) / 10)
+    }
+
+    // Discard the last echo and send the trigger pulse
+    fn trigger(&mut self) {
+        self.capture.reset();
+        let pulse = us_to_ticks(TRIGGER_US, self.capture.tick_hz()).max(1);
+        let start = self.capture.counter();
+        gpio::write::<TRIG>(true);
+        while self.elapsed(start) <= pulse {}
+        gpio::write::<TRIG>(false);
+        self.started = self.capture.counter();
+    }
+
+    // Check for the end of the echo
+    fn poll(&self) -> Poll<Result<u32, Error>> {
+        // High time is 0 until the falling edge after a rising edge
+        match self.capture.measure_pulse_width_ticks() {
+            Some(width) if width != 0 => {
+                let tick_hz = self.capture.tick_hz();
+                if width >= us_to_ticks(NO_ECHO_US, tick_hz) {
+                    Poll::Ready(Err(Error::NoEcho))
+                } else {
+                    Poll::Ready(Ok(self.ticks_to_mm(width)))
+                }
+            }
+            _ if self.elapsed(self.started) > self.timeout_ticks => {
+                Poll::Ready(Err(Error::Timeout))
+            }
+            _ => Poll::Pending,
+        }
+    }
+
+    // Counts since `start`, correct across one wrap of the counter
+    fn elapsed(&self, start: u32) -> u32 {
+        self.capture.counter().wrapping_sub(start) & T::max_count()
+    }
+
+    // Half the round trip at the speed of sound, 331.3 m/s + 0.606 m/s per degree
+    fn ticks_to_mm(&self, ticks: u32) -> u32 {
+        let mm_per_s = (3_313_000 + 606 * self.temperature as i64) / 10;
+        (ticks as i64 * mm_per_s / (2 * self.capture.tick_hz() as i64)) as u32
+    }
+}
+
+fn us_to_ticks(us: u32, tick_hz: u32) -> u32 {
+    (us as u64 * tick_hz as u64 / 1_000_000).min(u32::MAX as u64) as u32
+}
@@ -0,0 +1,350 @@
+//! Operating envelope supervision, die temperature, VCC and clock accuracy.
+//!
+//! [`Monitor::check`] reads the temperature sensor, voltage monitor 2 and
+//! optionally a [`Cac`] measurement, and compares them with the [`Limits`].
+//! An alarm is raised when a reading leaves the envelope and cleared when it
+//! is back inside by the hysteresis, each change calling the callback set
+//! with [`Monitor::on_event`] or queued for [`Monitor::next_event`]:
+//!
+//! ```ignore
+//! let limits = health::Limits {
+//!     max_temperature: 850,
+//!     low_voltage: Some(health::VoltageLevel::V4_02),
+//!     ..Default::default()
+//! };
+//! let mut monitor = health::Monitor::new(limits);
+//! monitor.on_derate(Some(health::halt_can));
+//! // Once a second
+//! monitor.check(&mut adc, Some(&mut cac));
+//! while let Some(event) = monitor.next_event() {
+//!     sprintln!("{:?}", event);
+//! }
+//! ```
+//!
+//! The derate callback runs with `true` when the first alarm is raised and
+//! `false` when the last one clears, to shed load or stop transmitting
+//! while outside the envelope. Voltage monitor 2 is used so monitor 1 stays
+//! free for the [`shutdown`](crate::shutdown) hooks, set it to a higher
+//! level as an early warning.
+
+use crate::adc::Adc;
+use crate::cac::Cac;
+
+// Voltage monitor 2 registers, in the SYSTEM block
+const SYSTEM: usize = 0x4001_E000;
+const LVD2SR: *const u8 = (SYSTEM + 0x0E3) as *const u8;
+const PRCR: *mut u16 = (SYSTEM + 0x3FE) as *mut u16;
+const LVCMPCR: *mut u8 = (SYSTEM + 0x417) as *mut u8;
+const LVDLVLR: *mut u8 = (SYSTEM + 0x418) as *mut u8;
+const LVD2CR0: *mut u8 = (SYSTEM + 0x41B) as *mut u8;
+
+// PRCR key and PRC3, write enable of the LVD registers
+const PRCR_KEY: u16 = 0xA5 << 8;
+const PRCR_PRC3: u16 = 1 << 3;
+// LVCMPCR.LVD2E
+const LVCMPCR_LVD2E: u8 = 1 << 6;
+// LVDLVLR.LVD2LVL, bits 7:5
+const LVDLVLR_LVD2LVL_SHIFT: u8 = 5;
+// LVD2CR0.CMPE, comparison result output, bit 3 reserved and written as 1
+const LVD2CR0_CMPE: u8 = (1 << 3) | (1 << 2);
+// LVD2SR.MON, VCC is above the level
+const LVD2SR_MON: u8 = 1 << 1;
+// td(E-A), LVD stabilization after enabling, at up to 48 MHz
+const LVD_START_CYCLES: u32 = 300 * 48;
+
+/// Number of [`Event`]s kept for [`Monitor::next_event`].
+pub const EVENTS: usize = 8;
+
+/// Voltage monitor 2 detection level, VCC falling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum VoltageLevel {
+    V4_29 = 0b000,
+    V4_14 = 0b001,
+    V4_02 = 0b010,
+    V3_84 = 0b011,
+}
+
+/// The operating envelope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Limits {
+    /// Lowest die temperature in tenths of a degree C
+    pub min_temperature: i16,
+    /// Highest die temperature in tenths of a degree C
+    pub max_temperature: i16,
+    /// Distance back inside a temperature limit before its alarm clears, in
+    /// tenths of a degree
+    pub hysteresis: i16,
+    /// Alarm when VCC falls below this, `None` to leave VCC unchecked
+    pub low_voltage: Option<VoltageLevel>,
+}
+
+impl Default for Limits {
+    /// -40 to 105 C, the rated range of the RA4M1, with 2 C of hysteresis
+    /// and VCC unchecked.
+    fn default() -> Self {
+        Limits {
+            min_temperature: -400,
+            max_temperature: 1050,
+            hysteresis: 20,
+            low_voltage: None,
+        }
+    }
+}
+
+/// A way of leaving the envelope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Alarm {
+    /// Above [`Limits::max_temperature`]
+    OverTemperature = 1 << 0,
+    /// Below [`Limits::min_temperature`]
+    UnderTemperature = 1 << 1,
+    /// VCC below [`Limits::low_voltage`]
+    LowVoltage = 1 << 2,
+    /// The last CAC measurement was out of tolerance
+    ClockError = 1 << 3,
+}
+
+impl Alarm {
+    const ALL: [Alarm; 4] = [
+        Alarm::OverTemperature,
+        Alarm::UnderTemperature,
+        Alarm::LowVoltage,
+        Alarm::ClockError,
+    ];
+}
+
+/// An alarm changing, from [`Monitor::check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    /// A reading left the envelope
+    Raised(Alarm),
+    /// The reading is back inside the envelope
+    Cleared(Alarm),
+}
+
+/// Readings of the last [`Monitor::check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Reading {
+    /// Die temperature in tenths of a degree C
+    pub temperature: i16,
+    /// VCC is below the [`Limits::low_voltage`] level
+    pub low_voltage: bool,
+    /// Clock deviation of the CAC measurement, `None` without a new one
+    pub clock_error_ppm: Option<i32>,
+    /// The CAC measurement was out of tolerance
+    pub clock_error: bool,
+}
+
+/// Checks the readings against the [`Limits`], see the module docs.
+pub struct Monitor {
+    limits: Limits,
+    // Raised alarms, `Alarm` bits
+    alarms: u8,
+    on_event: Option<fn(Event)>,
+    on_derate: Option<fn(bool)>,
+    events: heapless::Deque<Event, EVENTS>,
+}
+
+impl Monitor {
+    /// Monitor `limits`, starting voltage monitor 2 at the low voltage level
+    /// when there is one.
+    pub fn new(limits: Limits) -> Self {
+        if let Some(level) = limits.low_voltage {
+            enable_lvd2(level);
+        }
+        Monitor {
+            limits,
+            alarms: 0,
+            on_event: None,
+            on_derate: None,
+            events: heapless::Deque::new(),
+        }
+    }
+
+    /// Call `callback` on each alarm change instead of queueing it for
+    /// [`next_event`](Self::next_event), or queue again with `None`.
+    pub fn on_event(&mut self, callback: Option<fn(Event)>) {
+        self.on_event = callback;
+    }
+
+    /// Call `callback` with `true` when the first alarm is raised and
+    /// `false` when the last one clears, e.g. [`halt_can`].
+    pub fn on_derate(&mut self, callback: Option<fn(bool)>) {
+        self.on_derate = callback;
+    }
+
+    /// Take the oldest alarm change. When the queue is full the oldest is
+    /// dropped.
+    pub fn next_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Whether `alarm` is raised.
+    pub fn is_raised(&self, alarm: Alarm) -> bool {
+        self.alarms & alarm as u8 != 0
+    }
+
+    /// Whether every reading was inside the envelope at the last check.
+    pub fn is_healthy(&self) -> bool {
+        self.alarms == 0
+    }
+
+    /// Take the readings and update the alarms. Call periodically, e.g.
+    /// once a second, a temperature conversion blocks for a few
+    /// microseconds.
+    ///
+    /// The clock is only checked when `cac` is given and has a new
+    /// measurement, the alarm keeps its state otherwise.
+    pub fn check(&mut self, adc: &mut Adc, cac: Option<&mut Cac>) -> Reading {
+        let measurement = cac.and_then(|cac| cac.measurement());
+        let reading = Reading {
+            temperature: adc.read_temperature(),
+            low_voltage: self.limits.low_voltage.is_some()
+                && unsafe { LVD2SR.read_volatile() } & LVD2SR_MON == 0,
+            clock_error_ppm: measurement.map(|m| m.error_ppm),
+            clock_error: measurement.is_some_and(|m| m.out_of_tolerance),
+        };
+        let alarms = update(self.alarms, &self.limits, &reading, measurement.is_some());
+        self.apply(alarms);
+        reading
+    }
+
+    // Report the changes from the current alarms to `alarms`
+    fn apply(&mut self, alarms: u8) {
+        let was_healthy = self.alarms == 0;
+        for alarm in Alarm::ALL {
+            let bit = alarm as u8;
+            let event = match (self.alarms & bit != 0, alarms & bit != 0) {
+                (false, true) => Event::Raised(alarm),
+                (true, false) => Event::Cleared(alarm),
+                _ => continue,
+            };
+            match self.on_event {
+                Some(callback) => callback(event),
+                None => {
+                    if self.events.is_full() {
+                        self.events.pop_front();
+                    }
+                    let _ = self.events.push_back(event);
+                }
+            }
+        }
+        self.alarms = alarms;
+        if was_healthy != (alarms == 0) {
+            if let Some(callback) = self.on_derate {
+                callback(alarms != 0);
+            }
+        }
+    }
+}
+
+/// Derate callback that takes the CAN module off the bus in halt mode
+/// when the first alarm is raised, see [`can::halt`](crate::can::halt).
+///
+/// The module stays halted when the alarms clear, the application restarts
+/// it once it has decided the node is fit to transmit again.
+#[cfg(feature = "can")]
+pub fn halt_can(derate: bool) {
+    if derate {
+        let _ = crate::can::halt();
+    }
+}
+
+// The alarms after `reading`, from the `alarms` before. `clock_measured`
+// is false when the CAC had no new measurement.
+fn update(alarms: u8, limits: &Limits, reading: &Reading, clock_measured: bool) -> u8 {
+    let raised = |alarm: Alarm| alarms & alarm as u8 != 0;
+    let t = reading.temperature;
+    let over = if raised(Alarm::OverTemperature) {
+        t > limits.max_temperature - limits.hysteresis
+    } else {
+        t > limits.max_temperature
+    };
+    let under = if raised(Alarm::UnderTemperature) {
+        t < limits.min_temperature + limits.hysteresis
+    } else {
+        t < limits.min_temperature
+    };
+    let clock = if clock_measured {
+        reading.clock_error
+    } else {
+        raised(Alarm::ClockError)
+    };
+    [
+        (Alarm::OverTemperature, over),
+        (Alarm::UnderTemperature, under),
+        (Alarm::LowVoltage, reading.low_voltage),
+        (Alarm::ClockError, clock),
+    ]
+    .into_iter()
+    .filter(|(_, set)| *set)
+    .fold(0, |bits, (alarm, _)| bits | alarm as u8)
+}
+
+// Start voltage monitor 2 at `level` as a monitor only, no interrupt or
+// reset
+fn enable_lvd2(level: VoltageLevel) {
+    unsafe {
+        PRCR.write_volatile(PRCR_KEY | PRCR_PRC3);
+        // The level can only change with the detection circuit off
+        LVCMPCR.write_volatile(LVCMPCR.read_volatile() & !LVCMPCR_LVD2E);
+        LVDLVLR.write_volatile(
+            (LVDLVLR.read_volatile() & !(0b111 << LVDLVLR_LVD2LVL_SHIFT))
+                | (level as u8) << LVDLVLR_LVD2LVL_SHIFT,
+        );
+        LVCMPCR.write_volatile(LVCMPCR.read_volatile() | LVCMPCR_LVD2E);
+        cortex_m::asm::delay(LVD_START_CYCLES);
+        LVD2CR0.write_volatile(LVD2CR0_CMPE);
+        PRCR.write_volatile(PRCR_KEY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(temperature: i16) -> Reading {
+        Reading {
+            temperature,
+            low_voltage: false,
+            clock_error_ppm: None,
+            clock_error: false,
+        }
+    }
+
+    #[test]
+    fn temperature_hysteresis() {
+        let limits = Limits::default();
+        let over = Alarm::OverTemperature as u8;
+        assert_eq!(update(0, &limits, &reading(1050), false), 0);
+        assert_eq!(update(0, &limits, &reading(1051), false), over);
+        // Stays raised until 2 C below the limit
+        assert_eq!(update(over, &limits, &reading(1040), false), over);
+        assert_eq!(update(over, &limits, &reading(1030), false), 0);
+
+        let under = Alarm::UnderTemperature as u8;
+        assert_eq!(update(0, &limits, &reading(-401), false), under);
+        assert_eq!(update(under, &limits, &reading(-390), false), under);
+        assert_eq!(update(under, &limits, &reading(-380), false), 0);
+    }
+
+    #[test]
+    fn clock_alarm_held_without_measurement() {
+        let limits = Limits::default();
+        let clock = Alarm::ClockError as u8;
+        let bad = Reading {
+            clock_error: true,
+            ..reading(250)
+        };
+        assert_eq!(update(0, &limits, &bad, true), clock);
+        assert_eq!(update(clock, &limits, &reading(250), false), clock);
+        assert_eq!(update(clock, &limits, &reading(250), true), 0);
+    }
+}
@@ -0,0 +1,333 @@
+//! Heap for `alloc`, in a RAM region given by the application.
+//!
+//! Enabling the `alloc` feature registers a first-fit free list allocator as
+//! the global allocator. It takes a critical section for each allocation, so
+//! `Box` and `Vec` can be used from interrupt handlers too. Free blocks are
+//! kept in address order and merged with their neighbours when freed.
+//!
+//! ```ignore
+//! extern crate alloc;
+//!
+//! static mut HEAP_MEMORY: [MaybeUninit<u8>; 8192] = [MaybeUninit::uninit(); 8192];
+//!
+//! heap::init(unsafe { &mut *core::ptr::addr_of_mut!(HEAP_MEMORY) });
+//! let mut frames = alloc::vec::Vec::new();
+//! frames.push(frame);
+//! sprintln!("{}", heap::stats());
+//! ```
+//!
+//! With 32 KB of RAM the heap competes with the stack, size it from the
+//! [`Stats::high_water`] of a long run rather than from the free RAM.
+//! [`command`] adds the statistics to a [`shell`](crate::shell).
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::RefCell;
+use core::fmt;
+use core::mem::MaybeUninit;
+use core::ptr;
+
+use critical_section::Mutex;
+
+use crate::shell::CommandError;
+
+// Host tests keep the system allocator and test heaps of their own
+#[cfg_attr(not(test), global_allocator)]
+static HEAP: Heap = Heap::new();
+
+// Granularity of the heap, room for the header of a free block
+const UNIT: usize = core::mem::size_of::<FreeBlock>();
+
+// Header at the start of each free block
+#[repr(C)]
+struct FreeBlock {
+    size: usize,
+    // Address of the next free block, 0 at the end
+    next: usize,
+}
+
+/// The global allocator.
+pub struct Heap {
+    inner: Mutex<RefCell<Inner>>,
+}
+
+// Addresses rather than pointers, so the state is Send
+struct Inner {
+    head: usize,
+    size: usize,
+    used: usize,
+    high_water: usize,
+    allocations: u32,
+    failures: u32,
+}
+
+/// Heap statistics, from [`stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Stats {
+    /// Size of the heap in bytes
+    pub size: usize,
+    /// Bytes allocated, including rounding
+    pub used: usize,
+    /// Most bytes allocated at once
+    pub high_water: usize,
+    /// Number of free blocks
+    pub free_blocks: usize,
+    /// Largest free block, the largest allocation that can succeed
+    pub largest_free: usize,
+    /// Successful allocations
+    pub allocations: u32,
+    /// Allocations that failed for lack of a large enough block
+    pub failures: u32,
+}
+
+impl Stats {
+    /// Bytes not allocated.
+    pub fn free(&self) -> usize {
+        self.size - self.used
+    }
+
+    /// Share of the free memory outside the largest free block, 0 when
+    /// it's all in one block.
+    pub fn fragmentation_percent(&self) -> u8 {
+        match self.free() {
+            0 => 0,
+            free => (100 - self.largest_free * 100 / free) as u8,
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "heap {}/{} bytes used, high water {}, {} free blocks, largest {}, \
+             {}% fragmented, {} allocations, {} failed",
+            self.used,
+            self.size,
+            self.high_water,
+            self.free_blocks,
+            self.largest_free,
+            self.fragmentation_percent(),
+            self.allocations,
+            self.failures
+        )
+    }
+}
+
+/// Give `region` to the heap. Only the first call has an effect, before it
+/// every allocation fails.
+pub fn init(region: &'static mut [MaybeUninit<u8>]) {
+    HEAP.init(region);
+}
+
+/// Current statistics, walking the free list in a critical section.
+pub fn stats() -> Stats {
+    HEAP.stats()
+}
+
+/// Shell command printing the [`stats`], e.g. `"heap", "heap usage" => heap::command`.
+pub fn command(out: &mut dyn fmt::Write, _args: &[&str]) -> Result<(), CommandError> {
+    writeln!(out, "{}", stats())?;
+    Ok(())
+}
+
+impl Heap {
+    const fn new() -> Self {
+        Self {
+            inner: Mutex::new(RefCell::new(Inner::new())),
+        }
+    }
+
+    fn init(&self, region: &'static mut [MaybeUninit<u8>]) {
+        critical_section::with(|cs| {
+            let mut inner = self.inner.borrow_ref_mut(cs);
+            if inner.size != 0 {
+                return;
+            }
+            let start = region.as_mut_ptr() as usize;
+            let aligned = start.next_multiple_of(UNIT);
+            let size = region.len().saturating_sub(aligned - start) / UNIT * UNIT;
+            if size == 0 {
+                return;
+            }
+            unsafe { write_block(aligned, size, 0) };
+            inner.head = aligned;
+            inner.size = size;
+        });
+    }
+
+    fn stats(&self) -> Stats {
+        critical_section::with(|cs| {
+            let inner = self.inner.borrow_ref(cs);
+            let mut stats = Stats {
+                size: inner.size,
+                used: inner.used,
+                high_water: inner.high_water,
+                free_blocks: 0,
+                largest_free: 0,
+                allocations: inner.allocations,
+                failures: inner.failures,
+            };
+            let mut addr = inner.head;
+            while addr != 0 {
+                let block = unsafe { ptr::read(addr as *const FreeBlock) };
+                stats.free_blocks += 1;
+                stats.largest_free = stats.largest_free.max(block.size);
+                addr = block.next;
+            }
+            stats
+        })
+    }
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        critical_section::with(|cs| self.inner.borrow_ref_mut(cs).allocate(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        critical_section::with(|cs| unsafe { self.inner.borrow_ref_mut(cs).free(ptr, layout) })
+    }
+}
+
+impl Inner {
+    const fn new() -> Self {
+        Self {
+            head: 0,
+            size: 0,
+            used: 0,
+            high_water: 0,
+            allocations: 0,
+            failures: 0,
+        }
+    }
+
+    // First fit, the padding for the alignment stays a free block
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let size = layout.size().max(1).next_multiple_of(UNIT);
+        // Addresses in the list are multiples of UNIT, so the padding is too
+        let align = layout.align().max(UNIT);
+        let mut prev = 0;
+        let mut addr = self.head;
+        while addr != 0 {
+            let block = unsafe { ptr::read(addr as *const FreeBlock) };
+            let end = addr + block.size;
+            let start = addr.next_multiple_of(align);
+            if start
+                .checked_add(size)
+                .is_some_and(|alloc_end| alloc_end <= end)
+            {
+                let rest = end - (start + size);
+                let after = if rest > 0 {
+                    unsafe { write_block(start + size, rest, block.next) };
+                    start + size
+                } else {
+                    block.next
+                };
+                if start > addr {
+                    unsafe { write_block(addr, start - addr, after) };
+                } else {
+                    unsafe { self.set_next(prev, after) };
+                }
+                self.used += size;
+                self.high_water = self.high_water.max(self.used);
+                self.allocations += 1;
+                return start as *mut u8;
+            }
+            prev = addr;
+            addr = block.next;
+        }
+        self.failures += 1;
+        ptr::null_mut()
+    }
+
+    // Put the block back in address order, merging it with its neighbours
+    unsafe fn free(&mut self, ptr: *mut u8, layout: Layout) {
+        let addr = ptr as usize;
+        let mut size = layout.size().max(1).next_multiple_of(UNIT);
+        self.used -= size;
+
+        let mut prev = 0;
+        let mut next = self.head;
+        while next != 0 && next < addr {
+            prev = next;
+            next = unsafe { ptr::read(next as *const FreeBlock) }.next;
+        }
+        let mut after = next;
+        if next != 0 && addr + size == next {
+            let block = unsafe { ptr::read(next as *const FreeBlock) };
+            size += block.size;
+            after = block.next;
+        }
+        if prev != 0 {
+            let block = unsafe { ptr::read(prev as *const FreeBlock) };
+            if prev + block.size == addr {
+                unsafe { write_block(prev, block.size + size, after) };
+                return;
+            }
+        }
+        unsafe {
+            write_block(addr, size, after);
+            self.set_next(prev, addr);
+        }
+    }
+
+    // Link the block after `prev`, or make it the head if `prev` is 0
+    unsafe fn set_next(&mut self, prev: usize, next: usize) {
+        if prev == 0 {
+            self.head = next;
+        } else {
+            unsafe { (*(prev as *mut FreeBlock)).next = next };
+        }
+    }
+}
+
+unsafe fn write_block(addr: usize, size: usize, next: usize) {
+    unsafe { ptr::write(addr as *mut FreeBlock, FreeBlock { size, next }) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(align(16))]
+    struct Region([MaybeUninit<u8>; 256]);
+
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, 1).unwrap()
+    }
+
+    #[test]
+    fn first_fit_and_merge() {
+        let heap = Heap::new();
+        assert!(unsafe { heap.alloc(layout(8)) }.is_null());
+        let region = Box::leak(Box::new(Region([MaybeUninit::uninit(); 256])));
+        heap.init(&mut region.0);
+        assert_eq!(heap.stats().size, 256);
+
+        let a = unsafe { heap.alloc(layout(UNIT - 1)) };
+        let b = unsafe { heap.alloc(layout(2 * UNIT)) };
+        let c = unsafe { heap.alloc(layout(UNIT)) };
+        assert!(!a.is_null() && !b.is_null() && !c.is_null());
+        assert_eq!(heap.stats().used, 4 * UNIT);
+
+        // A hole in the middle, then merged with both neighbours
+        unsafe { heap.dealloc(b, layout(2 * UNIT)) };
+        let stats = heap.stats();
+        assert_eq!((stats.free_blocks, stats.largest_free), (2, 256 - 4 * UNIT));
+        assert!(stats.fragmentation_percent() > 0);
+        unsafe { heap.dealloc(a, layout(UNIT - 1)) };
+        assert_eq!(heap.stats().free_blocks, 2);
+        unsafe { heap.dealloc(c, layout(UNIT)) };
+        let stats = heap.stats();
+        assert_eq!((stats.free_blocks, stats.largest_free), (1, 256));
+        assert_eq!((stats.used, stats.high_water), (0, 4 * UNIT));
+
+        // Aligned allocations leave the padding free, too large ones fail
+        let aligned = unsafe { heap.alloc(Layout::from_size_align(8, 64).unwrap()) };
+        assert_eq!(aligned as usize % 64, 0);
+        assert!(unsafe { heap.alloc(layout(512)) }.is_null());
+        let stats = heap.stats();
+        assert_eq!((stats.allocations, stats.failures), (4, 2));
+    }
+}
@@ -0,0 +1,53 @@
+//! Factory programmed MCU information: unique ID, part number and version.
+//!
+//! The values are in flash at an address given by the Factory MCU
+//! Information Flash Root Table register (FMIFRT), and read in 32-bit units
+//! as the manual requires.
+
+/// Address of FMIFRT, holds the base address of the information.
+const FMIFRT: *const u32 = 0x407F_B19C as *const u32;
+
+// Offsets from the base address
+const UIDR0: usize = 0x14;
+const PNR0: usize = 0x24;
+const MCUVER: usize = 0x44;
+
+fn base() -> usize {
+    unsafe { FMIFRT.read_volatile() as usize }
+}
+
+// Read 16 bytes at `offset` as four words, first byte at the lowest address
+fn read_16(offset: usize) -> [u8; 16] {
+    let base = base() + offset;
+    let mut bytes = [0u8; 16];
+    for (i, chunk) in bytes.chunks_exact_mut(4).enumerate() {
+        let word = unsafe { ((base + 4 * i) as *const u32).read_volatile() };
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    bytes
+}
+
+/// The 128-bit ID unique to this MCU (UIDR0 - UIDR3).
+pub fn unique_id() -> [u8; 16] {
+    read_16(UIDR0)
+}
+
+/// The unique ID folded into 32 bits with FNV-1a.
+///
+/// Convenient for serial numbers or as a seed for a CAN node ID, but not
+/// guaranteed unique, mask it down and check for collisions on the bus.
+pub fn unique_id_hash() -> u32 {
+    unique_id().iter().fold(0x811C_9DC5u32, |hash, b| {
+        (hash ^ *b as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Product part number as ASCII, e.g. `R7FA4M1AB3CFM`, followed by padding.
+pub fn part_number() -> [u8; 16] {
+    read_16(PNR0)
+}
+
+/// MCU version (MCUVER), higher is newer.
+pub fn chip_version() -> u8 {
+    unsafe { ((base() + MCUVER) as *const u8).read_volatile() }
+}
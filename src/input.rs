@@ -0,0 +1,220 @@
+//! Debounced push buttons and rotary encoders.
+//!
+//! Pins are sampled each time `poll` is called, which should be at a steady
+//! rate, e.g. every millisecond from [`ticker::millis`](crate::ticker::millis)
+//! changing. Buttons are active low with the internal pull-up and must read
+//! the same level for [`DEBOUNCE_POLLS`] polls before a change is reported.
+//! Encoders are decoded from the gray code of their two pins, a change of
+//! both pins at once is contact bounce and is ignored, so they need no
+//! further debouncing. Changes are pushed to a [`Queue`] as [`Event`]s.
+//!
+//! ```ignore
+//! let mut events = input::Queue::<8>::new();
+//! let mut select = input::Button::new(pins.p104, 0);
+//! let mut volume = input::Encoder::new(pins.p105, pins.p106, 1);
+//! let mut last = ticker::millis();
+//! loop {
+//!     ticker.poll();
+//!     if ticker::millis() != last {
+//!         last = ticker::millis();
+//!         select.poll(&mut events);
+//!         volume.poll(&mut events);
+//!     }
+//!     while let Some(event) = events.next() {
+//!         match event {
+//!             Event::Pressed(0) => menu.select(),
+//!             Event::Turned(1, steps) => menu.scroll(steps),
+//!             _ => {}
+//!         }
+//!     }
+//!     cortex_m::asm::wfi();
+//! }
+//! ```
+
+use heapless::Deque;
+
+use crate::gpio::{self, Pin, PinConfig};
+
+/// Polls a button level must be stable for before a change is reported.
+pub const DEBOUNCE_POLLS: u8 = 5;
+
+/// Gray code changes per detent of a typical encoder.
+pub const STEPS_PER_DETENT: i8 = 4;
+
+// Quarter steps for a change of the encoder pins, indexed by the previous
+// and current levels of B and A, 0 for no change or a skipped state
+const QUADRATURE: [i8; 16] = [0, 1, -1, 0, -1, 0, 0, 1, 1, 0, 0, -1, 0, -1, 1, 0];
+
+/// A change of an input, with the ID it was created with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    /// A button was pressed
+    Pressed(u8),
+    /// A button was released
+    Released(u8),
+    /// An encoder turned by a number of detents, positive when A leads B
+    Turned(u8, i8),
+}
+
+/// Events waiting to be handled, up to `N`.
+pub struct Queue<const N: usize> {
+    events: Deque<Event, N>,
+    overflowed: bool,
+}
+
+impl<const N: usize> Queue<N> {
+    pub const fn new() -> Self {
+        Self {
+            events: Deque::new(),
+            overflowed: false,
+        }
+    }
+
+    /// The oldest event.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Whether events were dropped because the queue was full, clearing
+    /// the flag.
+    pub fn take_overflow(&mut self) -> bool {
+        core::mem::take(&mut self.overflowed)
+    }
+
+    fn push(&mut self, event: Event) {
+        if self.events.push_back(event).is_err() {
+            self.overflowed = true;
+        }
+    }
+}
+
+impl<const N: usize> Default for Queue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A push button between a pin and ground.
+pub struct Button<P: Pin> {
+    pin: P,
+    id: u8,
+    pressed: bool,
+    count: u8,
+    polls: u8,
+}
+
+impl<P: Pin> Button<P> {
+    /// Make `pin` an input with the pull-up, reporting events with `id`.
+    pub fn new(mut pin: P, id: u8) -> Self {
+        gpio::configure(&mut pin, PinConfig::input().pull_up());
+        Self {
+            pressed: !gpio::is_high(&pin),
+            pin,
+            id,
+            count: 0,
+            polls: DEBOUNCE_POLLS,
+        }
+    }
+
+    /// Require the level to be stable for `polls` polls, rather than
+    /// [`DEBOUNCE_POLLS`].
+    pub fn with_debounce(mut self, polls: u8) -> Self {
+        self.polls = polls.max(1);
+        self
+    }
+
+    /// Sample the pin, pushing [`Event::Pressed`] or [`Event::Released`]
+    /// once a change has been stable long enough.
+    pub fn poll<const N: usize>(&mut self, events: &mut Queue<N>) {
+        if !gpio::is_high(&self.pin) == self.pressed {
+            self.count = 0;
+            return;
+        }
+        self.count += 1;
+        if self.count >= self.polls {
+            self.count = 0;
+            self.pressed = !self.pressed;
+            events.push(if self.pressed {
+                Event::Pressed(self.id)
+            } else {
+                Event::Released(self.id)
+            });
+        }
+    }
+
+    /// The debounced state.
+    pub fn is_pressed(&self) -> bool {
+        self.pressed
+    }
+
+    /// Release the pin, still configured as an input.
+    pub fn free(self) -> P {
+        self.pin
+    }
+}
+
+/// A mechanical rotary encoder with its common pin to ground.
+pub struct Encoder<A: Pin, B: Pin> {
+    a: A,
+    b: B,
+    id: u8,
+    state: u8,
+    steps: i8,
+    per_detent: i8,
+}
+
+impl<A: Pin, B: Pin> Encoder<A, B> {
+    /// Make `a` and `b` inputs with the pull-up, reporting events with `id`.
+    pub fn new(mut a: A, mut b: B, id: u8) -> Self {
+        gpio::configure(&mut a, PinConfig::input().pull_up());
+        gpio::configure(&mut b, PinConfig::input().pull_up());
+        let mut encoder = Self {
+            a,
+            b,
+            id,
+            state: 0,
+            steps: 0,
+            per_detent: STEPS_PER_DETENT,
+        };
+        encoder.state = encoder.read();
+        encoder
+    }
+
+    /// Report a detent every `steps` gray code changes, rather than
+    /// [`STEPS_PER_DETENT`], e.g. 2 for half step encoders.
+    pub fn with_steps_per_detent(mut self, steps: i8) -> Self {
+        self.per_detent = steps.max(1);
+        self
+    }
+
+    /// Sample the pins, pushing [`Event::Turned`] for each detent passed.
+    ///
+    /// Must be polled faster than the pins can change, twice per gray code
+    /// step at the fastest turn.
+    pub fn poll<const N: usize>(&mut self, events: &mut Queue<N>) {
+        let state = self.read();
+        let step = QUADRATURE[((self.state << 2) | state) as usize];
+        if step == 0 && state != self.state {
+            // Both pins changed, keep the old state until one settles
+            return;
+        }
+        self.state = state;
+        self.steps += step;
+        if self.steps.abs() >= self.per_detent {
+            let detents = self.steps / self.per_detent;
+            self.steps -= detents * self.per_detent;
+            events.push(Event::Turned(self.id, detents));
+        }
+    }
+
+    /// Release the pins, still configured as inputs.
+    pub fn free(self) -> (A, B) {
+        (self.a, self.b)
+    }
+
+    fn read(&self) -> u8 {
+        ((gpio::is_high(&self.b) as u8) << 1) | gpio::is_high(&self.a) as u8
+    }
+}
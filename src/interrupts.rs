@@ -1,5 +1,9 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use ra4m1::Interrupt;
 
+use crate::events::Event;
+
 #[macro_export]
 macro_rules! bind_interrupts {
     ($(#[$outer:meta])* $vis:vis struct $name:ident {
@@ -22,6 +26,7 @@ macro_rules! bind_interrupts {
             $(#[cfg($cond_irq)])?
             $(#[doc = $doc])*
             unsafe extern "C" fn $irq() {
+                let _trace = $crate::interrupts::TraceGuard::new(ra4m1::Interrupt::$irq);
                 $(
                     $(#[cfg($cond_handler)])?
                     unsafe {<$handler as $crate::interrupts::Handler>::on_interrupt(ra4m1::Interrupt::$irq)};
@@ -47,6 +52,120 @@ macro_rules! bind_interrupts {
     }
 }
 
+/// Defines the vectors of `IELn` interrupts as trampolines to handlers
+/// registered at runtime with [`set_handler`](crate::interrupts::set_handler),
+/// instead of fixed ones from [`bind_interrupts!`].
+///
+/// For applications that swap handlers, e.g. between protocol modes. A
+/// vector can only be defined once, so an interrupt is either bound with
+/// [`bind_interrupts!`] or listed here, and drivers taking a binding still
+/// need [`bind_interrupts!`].
+///
+/// ```ignore
+/// dispatch_interrupts!(IEL8, IEL9);
+///
+/// fn on_edge(interrupt: Interrupt) {
+///     interrupts::clear_interrupt(interrupt);
+///     // ...
+/// }
+///
+/// interrupts::set_handler(Interrupt::IEL8, Some(on_edge));
+/// interrupts::map_and_enable_interrupt(Interrupt::IEL8, Event::PORT_IRQ0);
+/// ```
+#[macro_export]
+macro_rules! dispatch_interrupts {
+    ($($irq:ident),* $(,)?) => {
+        $(
+            #[allow(non_snake_case)]
+            #[unsafe(no_mangle)]
+            unsafe extern "C" fn $irq() {
+                let _trace = $crate::interrupts::TraceGuard::new(ra4m1::Interrupt::$irq);
+                unsafe { $crate::interrupts::dispatch(ra4m1::Interrupt::$irq) };
+            }
+        )*
+    };
+}
+
+// IEL0 - IEL31
+const IEL_COUNT: usize = 32;
+// Handlers registered for dispatch_interrupts!, 0 for none
+static HANDLERS: [AtomicUsize; IEL_COUNT] = [const { AtomicUsize::new(0) }; IEL_COUNT];
+
+/// Register the handler called for `interrupt` when its vector is defined
+/// with [`dispatch_interrupts!`], replacing any previous one. `None` removes
+/// it.
+///
+/// The handler clears the interrupt with [`clear_interrupt`], as a
+/// [`Handler`] does. An interrupt without a handler is cleared and disabled
+/// when it's taken.
+pub fn set_handler(interrupt: Interrupt, handler: Option<fn(Interrupt)>) {
+    HANDLERS[interrupt as usize].store(handler.map_or(0, |f| f as usize), Ordering::Release);
+}
+
+/// Register a driver's [`Handler`] for `interrupt`, see [`set_handler`].
+///
+/// ## Safety
+/// As a binding from [`bind_interrupts!`], the driver must be set up for
+/// this interrupt.
+pub unsafe fn set_driver_handler<H: Handler>(interrupt: Interrupt) {
+    let handler: unsafe fn(Interrupt) = H::on_interrupt;
+    HANDLERS[interrupt as usize].store(handler as usize, Ordering::Release);
+}
+
+/// Call the handler registered for `interrupt`, from the vectors defined by
+/// [`dispatch_interrupts!`].
+///
+/// ## Safety
+/// Only call from the vector of `interrupt`, driver handlers assume they
+/// run in their interrupt.
+#[doc(hidden)]
+pub unsafe fn dispatch(interrupt: Interrupt) {
+    let handler = HANDLERS[interrupt as usize].load(Ordering::Acquire);
+    if handler == 0 {
+        clear_interrupt(interrupt);
+        disable_interrupt(interrupt);
+        return;
+    }
+    // Only `fn(Interrupt)` and `unsafe fn(Interrupt)` pointers are stored
+    let handler: unsafe fn(Interrupt) = unsafe { core::mem::transmute(handler) };
+    unsafe { handler(interrupt) };
+}
+
+/// Times a handler from creation to drop with the `trace` feature, see the
+/// `trace` module. Does nothing without it.
+///
+/// Used by [`bind_interrupts!`], and can be put at the start of handlers
+/// bound some other way, e.g. RTIC hardware tasks.
+pub struct TraceGuard {
+    #[cfg(feature = "trace")]
+    interrupt: Interrupt,
+    #[cfg(feature = "trace")]
+    entry: u32,
+}
+
+impl TraceGuard {
+    #[inline(always)]
+    pub fn new(interrupt: Interrupt) -> Self {
+        #[cfg(not(feature = "trace"))]
+        let _ = interrupt;
+        Self {
+            #[cfg(feature = "trace")]
+            interrupt,
+            #[cfg(feature = "trace")]
+            entry: cortex_m::peripheral::DWT::cycle_count(),
+        }
+    }
+}
+
+#[cfg(feature = "trace")]
+impl Drop for TraceGuard {
+    #[inline(always)]
+    fn drop(&mut self) {
+        let exit = cortex_m::peripheral::DWT::cycle_count();
+        crate::trace::record(self.interrupt, self.entry, exit);
+    }
+}
+
 /// Defines a trait for handling interrupts.
 ///
 /// The on_interrupt method is called when an interrupt occurs
@@ -64,9 +183,25 @@ pub unsafe trait Binding<H: Handler> {
     fn interrupt() -> ra4m1::Interrupt;
 }
 
+// ICU.IELSRn.IR, the request flag of the interrupt
+const IELSR_IR: u32 = 1 << 16;
+
+// IELSRn of `interrupt`, a constant address when `interrupt` is
+#[inline(always)]
+fn ielsr(interrupt: Interrupt) -> *mut u32 {
+    let icu = unsafe { &*ra4m1::ICU::ptr() };
+    icu.ielsr[interrupt as usize].as_ptr()
+}
+
+/// Clear the request flag of `interrupt` in its IELSRn register, so the
+/// next event is taken. Handlers call it first.
+///
+/// Always inlined, so in a vector from [`bind_interrupts!`] it's a load,
+/// a bit clear and a store to a fixed address.
+#[inline(always)]
 pub fn clear_interrupt(interrupt: Interrupt) {
-    let p = unsafe { ra4m1::Peripherals::steal() };
-    p.ICU.ielsr[interrupt as usize].modify(|_, w| w.ir().clear_bit());
+    let ielsr = ielsr(interrupt);
+    unsafe { ielsr.write_volatile(ielsr.read_volatile() & !IELSR_IR) };
 }
 
 pub fn enable_interrupt(interrupt: Interrupt) {
@@ -87,14 +222,206 @@ pub fn pend_interrupt(interrupt: Interrupt) {
     ra4m1::NVIC::pend(interrupt);
 }
 
-pub fn map_interrupt(interrupt: Interrupt, event_id: u8) {
-    // Map the interrupt to a specific priority
-    let p = unsafe { ra4m1::Peripherals::steal() };
-    p.ICU.ielsr[interrupt as usize].write(|w| unsafe { w.iels().bits(event_id) });
+/// Route `event` to `interrupt` through its IELSRn register.
+pub fn map_interrupt(interrupt: Interrupt, event: Event) {
+    unsafe { ielsr(interrupt).write_volatile(event.id() as u32) };
 }
 
-pub fn map_and_enable_interrupt(interrupt: Interrupt, event_id: u8) {
+pub fn map_and_enable_interrupt(interrupt: Interrupt, event: Event) {
     // Map and enable the interrupt
-    map_interrupt(interrupt, event_id);
+    map_interrupt(interrupt, event);
+    #[cfg(feature = "critical-section-basepri")]
+    lower_to_ceiling(interrupt);
     enable_interrupt(interrupt);
 }
+
+// BASEPRI critical sections only exclude handlers at the ceiling or below,
+// so a driver's handler must be too, from the reset priority of 0
+#[cfg(feature = "critical-section-basepri")]
+fn lower_to_ceiling(interrupt: Interrupt) {
+    if crate::basepri::is_allowed_above_ceiling(interrupt) {
+        return;
+    }
+    let ceiling = crate::basepri::ceiling();
+    if cortex_m::peripheral::NVIC::get_priority(interrupt) < ceiling {
+        let mut p = unsafe { cortex_m::Peripherals::steal() };
+        unsafe { p.NVIC.set_priority(interrupt, ceiling) };
+    }
+}
+
+/// Priority bits implemented by the RA4M1, the top bits of each 8 bit
+/// priority field.
+pub const PRIORITY_BITS: u8 = 4;
+
+/// Number of priority levels.
+pub const PRIORITY_LEVELS: u8 = 1 << PRIORITY_BITS;
+
+// AIRCR write key and PRIGROUP field
+const AIRCR_VECTKEY: u32 = 0x05FA << 16;
+const AIRCR_PRIGROUP_SHIFT: u32 = 8;
+
+/// Interrupt priority level, 0 is the highest and 15 the lowest.
+///
+/// ```ignore
+/// interrupts::set_priority(Interrupt::IEL0, Priority::new::<2>());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Priority(u8);
+
+impl Priority {
+    /// Level 0.
+    pub const HIGHEST: Self = Self(0);
+    /// Level 15.
+    pub const LOWEST: Self = Self(PRIORITY_LEVELS - 1);
+
+    /// Level `LEVEL`, which fails to compile if there is no such level.
+    pub const fn new<const LEVEL: u8>() -> Self {
+        const { assert!(LEVEL < PRIORITY_LEVELS, "the RA4M1 has 16 priority levels") };
+        Self(LEVEL)
+    }
+
+    /// Level `level`, `None` if there is no such level.
+    pub const fn from_level(level: u8) -> Option<Self> {
+        if level < PRIORITY_LEVELS {
+            Some(Self(level))
+        } else {
+            None
+        }
+    }
+
+    /// Level from a preemption priority and a sub-priority in `grouping`,
+    /// `None` if either is out of range.
+    pub const fn grouped(grouping: PriorityGrouping, preempt: u8, sub: u8) -> Option<Self> {
+        let sub_bits = grouping.sub_bits();
+        if preempt >= grouping.groups() || sub >= 1 << sub_bits {
+            return None;
+        }
+        Some(Self((preempt << sub_bits) | sub))
+    }
+
+    /// The level, 0 - 15.
+    pub const fn level(self) -> u8 {
+        self.0
+    }
+
+    /// The value in the units of `NVIC::set_priority` and BASEPRI, e.g. for
+    /// [`basepri::set_ceiling`](crate::basepri).
+    pub const fn to_nvic(self) -> u8 {
+        self.0 << (8 - PRIORITY_BITS)
+    }
+
+    /// The level of a value in the units of `NVIC::set_priority`, the
+    /// unimplemented low bits are ignored.
+    pub const fn from_nvic(value: u8) -> Self {
+        Self(value >> (8 - PRIORITY_BITS))
+    }
+}
+
+/// How the priority bits split into a preemption priority, which decides
+/// whether one interrupt interrupts another, and a sub-priority, which only
+/// orders pending interrupts of the same preemption priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PriorityGrouping {
+    /// 16 preemption priorities, no sub-priority, the reset default
+    Preempt16,
+    /// 8 preemption priorities and 2 sub-priorities
+    Preempt8Sub2,
+    /// 4 preemption priorities and 4 sub-priorities
+    Preempt4Sub4,
+    /// 2 preemption priorities and 8 sub-priorities
+    Preempt2Sub8,
+    /// No preemption between interrupts, 16 sub-priorities
+    Sub16,
+}
+
+impl PriorityGrouping {
+    /// Number of preemption priorities.
+    pub const fn groups(self) -> u8 {
+        1 << (PRIORITY_BITS - self.sub_bits())
+    }
+
+    /// Number of sub-priorities in each preemption priority.
+    pub const fn sub_priorities(self) -> u8 {
+        1 << self.sub_bits()
+    }
+
+    const fn sub_bits(self) -> u8 {
+        match self {
+            PriorityGrouping::Preempt16 => 0,
+            PriorityGrouping::Preempt8Sub2 => 1,
+            PriorityGrouping::Preempt4Sub4 => 2,
+            PriorityGrouping::Preempt2Sub8 => 3,
+            PriorityGrouping::Sub16 => 4,
+        }
+    }
+
+    // AIRCR.PRIGROUP, the sub-priority is the bits of the field up to it
+    const fn prigroup(self) -> u32 {
+        (8 - PRIORITY_BITS + self.sub_bits()) as u32 - 1
+    }
+}
+
+/// Set the priority of an interrupt.
+///
+/// RTIC sets the priorities of the interrupts bound to its tasks, only use
+/// this for the others.
+pub fn set_priority(interrupt: Interrupt, priority: Priority) {
+    let mut p = unsafe { cortex_m::Peripherals::steal() };
+    unsafe { p.NVIC.set_priority(interrupt, priority.to_nvic()) };
+}
+
+/// The priority of an interrupt.
+pub fn priority(interrupt: Interrupt) -> Priority {
+    Priority::from_nvic(cortex_m::peripheral::NVIC::get_priority(interrupt))
+}
+
+/// Split the priority bits with `grouping`. Set once at start up, before
+/// interrupts are enabled.
+pub fn set_priority_grouping(grouping: PriorityGrouping) {
+    let p = unsafe { cortex_m::Peripherals::steal() };
+    unsafe {
+        p.SCB
+            .aircr
+            .write(AIRCR_VECTKEY | (grouping.prigroup() << AIRCR_PRIGROUP_SHIFT))
+    };
+}
+
+/// The current split of the priority bits.
+pub fn priority_grouping() -> PriorityGrouping {
+    let p = unsafe { cortex_m::Peripherals::steal() };
+    match (p.SCB.aircr.read() >> AIRCR_PRIGROUP_SHIFT) & 0b111 {
+        4 => PriorityGrouping::Preempt8Sub2,
+        5 => PriorityGrouping::Preempt4Sub4,
+        6 => PriorityGrouping::Preempt2Sub8,
+        7 => PriorityGrouping::Sub16,
+        // 0 - 3 leave all 4 implemented bits for preemption
+        _ => PriorityGrouping::Preempt16,
+    }
+}
+
+/// Point the vector table register (VTOR) at `addr`.
+///
+/// Needed when a bootloader jumps to an application without setting VTOR,
+/// `addr` must be 128-byte aligned.
+///
+/// ## Safety
+/// `addr` must hold a valid vector table for as long as interrupts are taken.
+pub unsafe fn set_vector_table(addr: u32) {
+    let p = unsafe { cortex_m::Peripherals::steal() };
+    unsafe { p.SCB.vtor.write(addr) };
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
+
+/// Point the vector table register at this program's own vector table.
+///
+/// Call first thing in `main` when the program may be started by a
+/// bootloader that leaves VTOR pointing at its own table.
+pub fn relocate_vector_table() {
+    unsafe extern "C" {
+        static __vector_table: u32;
+    }
+    unsafe { set_vector_table(&raw const __vector_table as u32) };
+}
@@ -0,0 +1,470 @@
+//! Infrared remote control, NEC and RC5.
+//!
+//! Transmit modulates a 38 kHz (NEC) or 36 kHz (RC5) carrier from a GPT
+//! channel onto an IR LED. Marks and spaces are timed in carrier cycles by
+//! polling the timer, so [`IrTx::send`] blocks for the frame, about 68 ms
+//! for NEC and 25 ms for RC5, with interrupts left enabled.
+//!
+//! Receive captures the edges from a demodulating receiver such as a TSOP38238
+//! on a GPT pin and decodes frames in the capture interrupts. The receiver
+//! output is low during a mark.
+//!
+//! ```ignore
+//! bind_interrupts!(struct Irq {
+//!     IEL14 => ir::RisingHandler<ra4m1::GPT163>;
+//!     IEL15 => ir::FallingHandler<ra4m1::GPT163>;
+//! });
+//!
+//! let mut tx = ir::IrTx::new(p.GPT320, pins.p107, ir::Protocol::Nec);
+//! tx.send(ir::Command::Nec { address: 0x00, command: 0x45 });
+//!
+//! let rx = ir::IrRx::new(p.GPT163, pins.p111, ir::Protocol::Nec, Some(on_command), Irq);
+//! ```
+
+use core::cell::{Cell, RefCell};
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use critical_section::Mutex;
+
+use crate::clk::{ClockGuard, Clocks};
+use crate::gpt::pwm::{self, Pwm};
+use crate::gpt::{self, Instance, PinA, Prescaler};
+use crate::interrupts::{Binding, Handler, clear_interrupt, map_and_enable_interrupt};
+
+// GTST.TCFPO, overflow flag, and the capture flags
+const GTST_TCFA: u32 = 1 << 0;
+const GTST_TCFB: u32 = 1 << 1;
+const GTST_TCFPO: u32 = 1 << 6;
+
+// NEC timing in microseconds
+const NEC_UNIT: u32 = 562;
+const NEC_LEADER_MARK: u32 = 16 * NEC_UNIT;
+const NEC_LEADER_SPACE: u32 = 8 * NEC_UNIT;
+const NEC_REPEAT_SPACE: u32 = 4 * NEC_UNIT;
+const NEC_ONE_SPACE: u32 = 3 * NEC_UNIT;
+// RC5 half bit in microseconds
+const RC5_HALF: u32 = 889;
+// RC5 frame length in half bits
+const RC5_HALVES: u8 = 28;
+
+static DECODER: Mutex<RefCell<Decoder>> = Mutex::new(RefCell::new(Decoder::new(Protocol::Nec)));
+static CALLBACK: Mutex<Cell<Option<fn(Command)>>> = Mutex::new(Cell::new(None));
+static LAST_COMMAND: Mutex<Cell<Option<Command>>> = Mutex::new(Cell::new(None));
+// Counter value at the last edge
+static LAST_EDGE: AtomicU32 = AtomicU32::new(0);
+// Count clock of the receive timer
+static TICK_HZ: AtomicU32 = AtomicU32::new(1);
+
+/// Remote control protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Protocol {
+    /// NEC, 38 kHz carrier, pulse distance coding
+    Nec,
+    /// Philips RC5, 36 kHz carrier, Manchester coding
+    Rc5,
+}
+
+impl Protocol {
+    /// Carrier frequency in Hz.
+    pub const fn carrier_hz(&self) -> u32 {
+        match self {
+            Protocol::Nec => 38_000,
+            Protocol::Rc5 => 36_000,
+        }
+    }
+}
+
+/// A decoded or sent command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Command {
+    /// NEC frame. An address above 0xFF is sent as extended NEC, without
+    /// the inverted address byte.
+    Nec { address: u16, command: u8 },
+    /// NEC repeat code, the button is held
+    NecRepeat,
+    /// RC5 frame, a new `toggle` on each press, command 0 - 127
+    Rc5 {
+        address: u8,
+        command: u8,
+        toggle: bool,
+    },
+}
+
+// ================ Transmit ================
+
+/// IR LED on the GTIOCnA pin of a GPT channel.
+pub struct IrTx<T: Instance> {
+    pwm: Pwm<T>,
+    carrier_hz: u32,
+}
+
+impl<T: Instance> IrTx<T> {
+    /// Run the carrier for `protocol` from the current PCLKD, with the LED off.
+    pub fn new<P: PinA<T>>(instance: T, pin: P, protocol: Protocol) -> Self {
+        let config = pwm::Config {
+            pclkd_hz: Clocks::read().pclkd(),
+            frequency_hz: protocol.carrier_hz(),
+        };
+        let mut pwm = Pwm::new(instance, config);
+        pwm.enable_a(pin);
+        Self {
+            pwm,
+            carrier_hz: config.frequency_hz,
+        }
+    }
+
+    /// Send a command, blocking until the frame is finished.
+    ///
+    /// The carrier is set up for one protocol, a command of the other
+    /// protocol is sent at the wrong carrier frequency.
+    pub fn send(&mut self, command: Command) {
+        match command {
+            Command::Nec { address, command } => {
+                let address = if address > 0xFF {
+                    address
+                } else {
+                    address | ((!address << 8) & 0xFF00)
+                };
+                let data = (address as u32) | ((command as u32) << 16) | ((!command as u32) << 24);
+                self.mark(NEC_LEADER_MARK);
+                self.space(NEC_LEADER_SPACE);
+                for bit in 0..32 {
+                    self.mark(NEC_UNIT);
+                    self.space(if data & (1 << bit) != 0 {
+                        NEC_ONE_SPACE
+                    } else {
+                        NEC_UNIT
+                    });
+                }
+                self.mark(NEC_UNIT);
+                self.space(0);
+            }
+            Command::NecRepeat => {
+                self.mark(NEC_LEADER_MARK);
+                self.space(NEC_REPEAT_SPACE);
+                self.mark(NEC_UNIT);
+                self.space(0);
+            }
+            Command::Rc5 {
+                address,
+                command,
+                toggle,
+            } => {
+                // S1, S2 as inverted command bit 6, toggle, 5 address and 6 command bits
+                let frame = (1 << 13)
+                    | ((((!command) as u16 >> 6) & 1) << 12)
+                    | ((toggle as u16) << 11)
+                    | (((address as u16) & 0x1F) << 6)
+                    | ((command as u16) & 0x3F);
+                for bit in (0..14).rev() {
+                    // 1 is a space then a mark, 0 a mark then a space
+                    if frame & (1 << bit) != 0 {
+                        self.space(RC5_HALF);
+                        self.mark(RC5_HALF);
+                    } else {
+                        self.mark(RC5_HALF);
+                        self.space(RC5_HALF);
+                    }
+                }
+                self.space(0);
+            }
+        }
+    }
+
+    // Carrier on for `us`, 33% duty
+    fn mark(&mut self, us: u32) {
+        let duty = self.pwm.max_duty() / 3;
+        self.pwm.set_duty(pwm::Channel::A, duty);
+        self.wait_cycles(us);
+    }
+
+    // Carrier off for `us`
+    fn space(&mut self, us: u32) {
+        self.pwm.set_duty(pwm::Channel::A, 0);
+        self.wait_cycles(us);
+    }
+
+    // Wait for the carrier cycles in `us`. Duty changes take effect at the
+    // end of a cycle, so counting overflows keeps marks and spaces aligned.
+    fn wait_cycles(&self, us: u32) {
+        let gpt = unsafe { &*T::peripheral() };
+        let cycles = (us as u64 * self.carrier_hz as u64 / 1_000_000) as u32;
+        for _ in 0..cycles.max(1) {
+            gpt.gtst
+                .modify(|r, w| unsafe { w.bits(r.bits() & !GTST_TCFPO) });
+            while gpt.gtst.read().bits() & GTST_TCFPO == 0 {}
+        }
+    }
+}
+
+// ================ Receive ================
+
+#[derive(Clone, Copy)]
+enum NecState {
+    Idle,
+    // Leader mark received
+    Leader,
+    // Data bits received
+    Data(u8),
+}
+
+enum Decoder {
+    Nec {
+        state: NecState,
+        data: u32,
+    },
+    Rc5 {
+        // Half bits received, 1 for a mark, first in the top bit
+        halves: u32,
+        count: u8,
+    },
+}
+
+impl Decoder {
+    const fn new(protocol: Protocol) -> Self {
+        match protocol {
+            Protocol::Nec => Decoder::Nec {
+                state: NecState::Idle,
+                data: 0,
+            },
+            Protocol::Rc5 => Decoder::Rc5 {
+                halves: 0,
+                count: 0,
+            },
+        }
+    }
+
+    // A mark or space of `us` just ended
+    fn edge(&mut self, mark: bool, us: u32) -> Option<Command> {
+        match self {
+            Decoder::Nec { state, data } => {
+                let (next, command) = match (*state, mark) {
+                    (_, true) if near(us, NEC_LEADER_MARK) => {
+                        *data = 0;
+                        (NecState::Leader, None)
+                    }
+                    // Stop mark
+                    (NecState::Data(32), true) if near(us, NEC_UNIT) => {
+                        (NecState::Idle, nec_command(*data))
+                    }
+                    (NecState::Data(n), true) if near(us, NEC_UNIT) => (NecState::Data(n), None),
+                    (NecState::Leader, false) if near(us, NEC_LEADER_SPACE) => {
+                        (NecState::Data(0), None)
+                    }
+                    (NecState::Leader, false) if near(us, NEC_REPEAT_SPACE) => {
+                        (NecState::Idle, Some(Command::NecRepeat))
+                    }
+                    (NecState::Data(n @ 0..32), false) if near(us, NEC_UNIT) => {
+                        (NecState::Data(n + 1), None)
+                    }
+                    (NecState::Data(n @ 0..32), false) if near(us, NEC_ONE_SPACE) => {
+                        *data |= 1 << n;
+                        (NecState::Data(n + 1), None)
+                    }
+                    _ => (NecState::Idle, None),
+                };
+                *state = next;
+                command
+            }
+            Decoder::Rc5 { halves, count } => {
+                let n = if near(us, RC5_HALF) {
+                    1
+                } else if near(us, 2 * RC5_HALF) {
+                    2
+                } else {
+                    0
+                };
+                if n == 0 || (*count == 0 && !mark) {
+                    // A gap, the next frame starts with the space half of S1
+                    *halves = 0;
+                    *count = 1;
+                    return None;
+                }
+                for _ in 0..n {
+                    *halves = (*halves << 1) | mark as u32;
+                    *count += 1;
+                }
+                // The last half of a final 0 is a space, ended by no edge
+                if mark && *count == RC5_HALVES - 1 {
+                    *halves <<= 1;
+                    *count += 1;
+                }
+                if *count < RC5_HALVES {
+                    return None;
+                }
+                let frame = if *count == RC5_HALVES {
+                    rc5_frame(*halves)
+                } else {
+                    None
+                };
+                *halves = 0;
+                *count = 0;
+                frame
+            }
+        }
+    }
+}
+
+// Within 25% of `nominal`
+fn near(us: u32, nominal: u32) -> bool {
+    us.abs_diff(nominal) <= nominal / 4
+}
+
+fn nec_command(data: u32) -> Option<Command> {
+    let command = (data >> 16) as u8;
+    if (data >> 24) as u8 != !command {
+        return None;
+    }
+    let low = data as u8;
+    let high = (data >> 8) as u8;
+    let address = if high == !low {
+        low as u16
+    } else {
+        data as u16
+    };
+    Some(Command::Nec { address, command })
+}
+
+// Decode 28 half bits, each bit must be a space then a mark or the reverse
+fn rc5_frame(halves: u32) -> Option<Command> {
+    let mut frame = 0u16;
+    for bit in (0..14).rev() {
+        match (halves >> (2 * bit)) & 0b11 {
+            0b01 => frame = (frame << 1) | 1,
+            0b10 => frame <<= 1,
+            _ => return None,
+        }
+    }
+    let command = ((frame & 0x3F) as u8) | ((((frame >> 12) & 1 == 0) as u8) << 6);
+    Some(Command::Rc5 {
+        address: ((frame >> 6) & 0x1F) as u8,
+        command,
+        toggle: frame & (1 << 11) != 0,
+    })
+}
+
+// Feed the time since the last edge to the decoder
+fn on_edge<T: Instance>(now: u32, mark: bool) {
+    let last = LAST_EDGE.swap(now, Ordering::Relaxed);
+    let ticks = now.wrapping_sub(last) & T::max_count();
+    let tick_hz = TICK_HZ.load(Ordering::Relaxed);
+    let us = (ticks as u64 * 1_000_000 / tick_hz as u64) as u32;
+    let command = critical_section::with(|cs| {
+        let command = DECODER.borrow_ref_mut(cs).edge(mark, us);
+        if command.is_some() {
+            LAST_COMMAND.borrow(cs).set(command);
+        }
+        command
+    });
+    let callback = critical_section::with(|cs| CALLBACK.borrow(cs).get());
+    if let (Some(command), Some(callback)) = (command, callback) {
+        callback(command);
+    }
+}
+
+/// Triggers on a rising edge, the end of a mark.
+pub struct RisingHandler<T: Instance> {
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Instance> Handler for RisingHandler<T> {
+    unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
+        clear_interrupt(interrupt);
+        let gpt = unsafe { &*T::peripheral() };
+        gpt.gtst
+            .modify(|r, w| unsafe { w.bits(r.bits() & !GTST_TCFA) });
+        on_edge::<T>(gpt.gtccra.read().bits(), true);
+    }
+}
+
+/// Triggers on a falling edge, the end of a space.
+pub struct FallingHandler<T: Instance> {
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Instance> Handler for FallingHandler<T> {
+    unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
+        clear_interrupt(interrupt);
+        let gpt = unsafe { &*T::peripheral() };
+        gpt.gtst
+            .modify(|r, w| unsafe { w.bits(r.bits() & !GTST_TCFB) });
+        on_edge::<T>(gpt.gtccrb.read().bits(), false);
+    }
+}
+
+/// IR receiver on the GTIOCnA pin of a GPT channel.
+///
+/// Only one receiver can run at a time.
+pub struct IrRx<T: Instance> {
+    _clock: ClockGuard,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Instance> IrRx<T> {
+    /// Decode `protocol` from the receiver on `pin`, calling `callback`
+    /// from the interrupt with each command.
+    ///
+    /// The counter runs at PCLKD / 16 on the 32-bit channels and PCLKD / 256
+    /// on the 16-bit ones, where gaps over 350 ms at 48 MHz alias.
+    pub fn new<P: PinA<T>, IRQ>(
+        _instance: T,
+        _pin: P,
+        protocol: Protocol,
+        callback: Option<fn(Command)>,
+        _irq: IRQ,
+    ) -> Self
+    where
+        IRQ: Binding<RisingHandler<T>> + Binding<FallingHandler<T>>,
+    {
+        let prescaler = if T::max_count() == u32::MAX {
+            Prescaler::Div16
+        } else {
+            Prescaler::Div256
+        };
+        let clock = gpt::init::<T>(prescaler);
+        gpt::connect_pin::<P>();
+        let gpt = unsafe { &*T::peripheral() };
+        // GTCCRA on GTIOCA rising, GTCCRB on GTIOCA falling, noise filter on
+        gpt.gticasr.write(|w| unsafe { w.bits(0b11 << 8) });
+        gpt.gticbsr.write(|w| unsafe { w.bits(0b11 << 10) });
+        gpt.gtior.write(|w| unsafe { w.bits(1 << 13) });
+
+        TICK_HZ.store(
+            Clocks::read().pclkd() / prescaler.divisor(),
+            Ordering::Relaxed,
+        );
+        LAST_EDGE.store(0, Ordering::Relaxed);
+        critical_section::with(|cs| {
+            DECODER.replace(cs, Decoder::new(protocol));
+            CALLBACK.borrow(cs).set(callback);
+            LAST_COMMAND.borrow(cs).set(None);
+        });
+
+        let event_base = T::event_base();
+        map_and_enable_interrupt(<IRQ as Binding<RisingHandler<T>>>::interrupt(), event_base);
+        map_and_enable_interrupt(
+            <IRQ as Binding<FallingHandler<T>>>::interrupt(),
+            event_base.offset(1),
+        );
+        gpt::start::<T>();
+        Self {
+            _clock: clock,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// The last command received since the previous call.
+    pub fn take(&mut self) -> Option<Command> {
+        critical_section::with(|cs| LAST_COMMAND.borrow(cs).take())
+    }
+}
+
+impl<T: Instance> Drop for IrRx<T> {
+    fn drop(&mut self) {
+        gpt::stop::<T>();
+        critical_section::with(|cs| CALLBACK.borrow(cs).set(None));
+    }
+}
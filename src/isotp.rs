@@ -0,0 +1,419 @@
+//! ISO-TP (ISO 15765-2) transport on top of the CAN driver.
+//!
+//! Splits messages of up to [`MAX_PAYLOAD`] bytes into single, first and
+//! consecutive frames with flow control, normal addressing on classic CAN.
+//!
+//! The channel is polled from the main loop with the current time in
+//! milliseconds, which drives the separation time and the N_Bs / N_Cr
+//! timeouts:
+//!
+//! ```ignore
+//! let mut tp = isotp::IsoTp::new(isotp::Config::default());
+//! loop {
+//!     if let Some(request) = tp.poll(&can, now_ms())? {
+//!         let response = handle(request);
+//!         tp.send(&response)?;
+//!     }
+//! }
+//! ```
+//!
+//! [`IsoTp::poll`] drops received frames with other IDs. When the bus is
+//! shared with other users, read the frames in the application, pass the
+//! ones for this channel to [`IsoTp::on_frame`] and call [`IsoTp::poll_tx`].
+//!
+//! At most one consecutive frame is sent per call to `poll`, so frames can't
+//! overtake each other in the transmit mailboxes. Poll at least as often as
+//! the separation time to reach full speed.
+
+use embedded_can::{Frame as _, Id, StandardId};
+
+use crate::can::{Can, Frame};
+
+/// Largest message, the protocol allows 4095 bytes.
+pub const MAX_PAYLOAD: usize = 512;
+
+// Protocol control information, high nibble of the first byte
+const SINGLE: u8 = 0x0;
+const FIRST: u8 = 0x1;
+const CONSECUTIVE: u8 = 0x2;
+const FLOW_CONTROL: u8 = 0x3;
+
+// Flow status
+const CONTINUE: u8 = 0x0;
+const WAIT: u8 = 0x1;
+const OVERFLOW: u8 = 0x2;
+
+/// Channel configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// ID of the frames sent
+    pub tx_id: Id,
+    /// ID of the frames received
+    pub rx_id: Id,
+    /// Functional (broadcast) request ID, only single frames are accepted
+    pub functional_id: Option<Id>,
+    /// Consecutive frames the peer may send before waiting for flow control, 0 for no limit
+    pub block_size: u8,
+    /// Separation time asked of the peer, in the protocol's STmin encoding
+    pub st_min: u8,
+    /// Pad frames to 8 bytes with this value
+    pub padding: Option<u8>,
+    /// Time to wait for a flow control or consecutive frame, in ms
+    pub timeout_ms: u32,
+}
+
+impl Default for Config {
+    /// Physical addressing of the first OBD ECU, 0x7E0 / 0x7E8, and 0x7DF functional.
+    fn default() -> Self {
+        Config {
+            tx_id: StandardId::new(0x7E8).unwrap().into(),
+            rx_id: StandardId::new(0x7E0).unwrap().into(),
+            functional_id: Some(StandardId::new(0x7DF).unwrap().into()),
+            block_size: 0,
+            st_min: 0,
+            padding: Some(0xAA),
+            timeout_ms: 1000,
+        }
+    }
+}
+
+/// ISO-TP errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The message is longer than [`MAX_PAYLOAD`]
+    TooLong,
+    /// A message is still being sent
+    Busy,
+    /// No flow control or consecutive frame arrived in time
+    Timeout,
+    /// A consecutive frame had the wrong sequence number, the message was dropped
+    Sequence,
+    /// The peer can't receive a message this long
+    Overflow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tx {
+    Idle,
+    Single,
+    First,
+    WaitFlowControl {
+        since: u32,
+    },
+    Consecutive {
+        sn: u8,
+        // Frames until the next flow control, 0 for no limit
+        block_left: u8,
+        st_min_ms: u32,
+        last: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rx {
+    Idle,
+    Receiving { sn: u8, block_left: u8, since: u32 },
+}
+
+/// One ISO-TP channel, a pair of transmit and receive IDs.
+pub struct IsoTp {
+    config: Config,
+    tx: Tx,
+    tx_buf: [u8; MAX_PAYLOAD],
+    tx_len: usize,
+    tx_pos: usize,
+    // Sequence number of the next consecutive frame to send after flow control
+    tx_sn: u8,
+    rx: Rx,
+    rx_buf: [u8; MAX_PAYLOAD],
+    rx_len: usize,
+    rx_expected: usize,
+    // Flow status waiting to be sent
+    flow_control: Option<u8>,
+}
+
+impl IsoTp {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            tx: Tx::Idle,
+            tx_buf: [0; MAX_PAYLOAD],
+            tx_len: 0,
+            tx_pos: 0,
+            tx_sn: 0,
+            rx: Rx::Idle,
+            rx_buf: [0; MAX_PAYLOAD],
+            rx_len: 0,
+            rx_expected: 0,
+            flow_control: None,
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Whether a frame belongs to this channel.
+    pub fn accepts(&self, frame: &Frame) -> bool {
+        !frame.is_remote_frame()
+            && (frame.id() == self.config.rx_id || Some(frame.id()) == self.config.functional_id)
+    }
+
+    /// Whether the last message has been sent.
+    pub fn is_idle(&self) -> bool {
+        self.tx == Tx::Idle
+    }
+
+    /// Start sending a message, the frames go out from [`poll_tx`](Self::poll_tx).
+    pub fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        if self.tx != Tx::Idle {
+            return Err(Error::Busy);
+        }
+        if data.len() > MAX_PAYLOAD {
+            return Err(Error::TooLong);
+        }
+        self.tx_buf[..data.len()].copy_from_slice(data);
+        self.tx_len = data.len();
+        self.tx = if data.len() <= 7 {
+            Tx::Single
+        } else {
+            Tx::First
+        };
+        Ok(())
+    }
+
+    /// Give up on the message being sent.
+    pub fn abort(&mut self) {
+        self.tx = Tx::Idle;
+    }
+
+    /// Handle a received frame, returns a message once it's complete.
+    ///
+    /// Frames that [`accepts`](Self::accepts) rejects are ignored.
+    pub fn on_frame(&mut self, frame: &Frame, now_ms: u32) -> Result<Option<&[u8]>, Error> {
+        if self.receive(frame, now_ms)? {
+            Ok(Some(&self.rx_buf[..self.rx_len]))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Send pending frames and check the timeouts.
+    pub fn poll_tx(&mut self, can: &Can, now_ms: u32) -> Result<(), Error> {
+        if let Some(status) = self.flow_control {
+            let data = [
+                (FLOW_CONTROL << 4) | status,
+                self.config.block_size,
+                self.config.st_min,
+            ];
+            if self.send_frame(can, &data) {
+                self.flow_control = None;
+            }
+        }
+
+        if let Rx::Receiving { since, .. } = self.rx {
+            if now_ms.wrapping_sub(since) > self.config.timeout_ms {
+                self.rx = Rx::Idle;
+                return Err(Error::Timeout);
+            }
+        }
+
+        match self.tx {
+            Tx::Idle => {}
+            Tx::Single => {
+                let mut data = [0u8; 8];
+                data[0] = (SINGLE << 4) | self.tx_len as u8;
+                data[1..=self.tx_len].copy_from_slice(&self.tx_buf[..self.tx_len]);
+                if self.send_frame(can, &data[..=self.tx_len]) {
+                    self.tx = Tx::Idle;
+                }
+            }
+            Tx::First => {
+                let mut data = [0u8; 8];
+                data[0] = (FIRST << 4) | (self.tx_len >> 8) as u8;
+                data[1] = self.tx_len as u8;
+                data[2..].copy_from_slice(&self.tx_buf[..6]);
+                if self.send_frame(can, &data) {
+                    self.tx_pos = 6;
+                    self.tx_sn = 1;
+                    self.tx = Tx::WaitFlowControl { since: now_ms };
+                }
+            }
+            Tx::WaitFlowControl { since } => {
+                if now_ms.wrapping_sub(since) > self.config.timeout_ms {
+                    self.tx = Tx::Idle;
+                    return Err(Error::Timeout);
+                }
+            }
+            Tx::Consecutive {
+                sn,
+                block_left,
+                st_min_ms,
+                last,
+            } => {
+                if now_ms.wrapping_sub(last) < st_min_ms {
+                    return Ok(());
+                }
+                let n = (self.tx_len - self.tx_pos).min(7);
+                let mut data = [0u8; 8];
+                data[0] = (CONSECUTIVE << 4) | sn;
+                data[1..=n].copy_from_slice(&self.tx_buf[self.tx_pos..self.tx_pos + n]);
+                if !self.send_frame(can, &data[..=n]) {
+                    return Ok(());
+                }
+                self.tx_pos += n;
+                self.tx_sn = (sn + 1) & 0x0F;
+                self.tx = if self.tx_pos >= self.tx_len {
+                    Tx::Idle
+                } else if block_left == 1 {
+                    Tx::WaitFlowControl { since: now_ms }
+                } else {
+                    Tx::Consecutive {
+                        sn: self.tx_sn,
+                        block_left: block_left.saturating_sub(1),
+                        st_min_ms,
+                        last: now_ms,
+                    }
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Send pending frames and read all received frames from `can`,
+    /// returns a message once it's complete.
+    ///
+    /// Frames with other IDs are discarded.
+    pub fn poll(&mut self, can: &Can, now_ms: u32) -> Result<Option<&[u8]>, Error> {
+        self.poll_tx(can, now_ms)?;
+        while let Some(frame) = can.try_receive_frame() {
+            if self.receive(&frame, now_ms)? {
+                return Ok(Some(&self.rx_buf[..self.rx_len]));
+            }
+        }
+        Ok(None)
+    }
+
+    // Returns true when a message is complete in rx_buf
+    fn receive(&mut self, frame: &Frame, now_ms: u32) -> Result<bool, Error> {
+        if !self.accepts(frame) {
+            return Ok(false);
+        }
+        let data = frame.data();
+        let Some(&pci) = data.first() else {
+            return Ok(false);
+        };
+        let functional = frame.id() != self.config.rx_id;
+        match pci >> 4 {
+            SINGLE => {
+                let len = (pci & 0x0F) as usize;
+                if len == 0 || len >= data.len() {
+                    return Ok(false);
+                }
+                // A new message replaces one being received
+                self.rx = Rx::Idle;
+                self.rx_buf[..len].copy_from_slice(&data[1..=len]);
+                self.rx_len = len;
+                Ok(true)
+            }
+            FIRST if !functional && data.len() == 8 => {
+                let len = ((pci as usize & 0x0F) << 8) | data[1] as usize;
+                if len < 8 {
+                    return Ok(false);
+                }
+                if len > MAX_PAYLOAD {
+                    self.rx = Rx::Idle;
+                    self.flow_control = Some(OVERFLOW);
+                    return Ok(false);
+                }
+                self.rx_buf[..6].copy_from_slice(&data[2..]);
+                self.rx_len = 6;
+                self.rx_expected = len;
+                self.rx = Rx::Receiving {
+                    sn: 1,
+                    block_left: self.config.block_size,
+                    since: now_ms,
+                };
+                self.flow_control = Some(CONTINUE);
+                Ok(false)
+            }
+            CONSECUTIVE if !functional => {
+                let Rx::Receiving { sn, block_left, .. } = self.rx else {
+                    return Ok(false);
+                };
+                if pci & 0x0F != sn {
+                    self.rx = Rx::Idle;
+                    return Err(Error::Sequence);
+                }
+                let n = (self.rx_expected - self.rx_len).min(data.len() - 1);
+                self.rx_buf[self.rx_len..self.rx_len + n].copy_from_slice(&data[1..=n]);
+                self.rx_len += n;
+                if self.rx_len >= self.rx_expected {
+                    self.rx = Rx::Idle;
+                    return Ok(true);
+                }
+                let block_left = match block_left {
+                    0 => 0,
+                    1 => {
+                        self.flow_control = Some(CONTINUE);
+                        self.config.block_size
+                    }
+                    n => n - 1,
+                };
+                self.rx = Rx::Receiving {
+                    sn: (sn + 1) & 0x0F,
+                    block_left,
+                    since: now_ms,
+                };
+                Ok(false)
+            }
+            FLOW_CONTROL if !functional && data.len() >= 3 => {
+                if !matches!(self.tx, Tx::WaitFlowControl { .. }) {
+                    return Ok(false);
+                }
+                match pci & 0x0F {
+                    CONTINUE => {
+                        self.tx = Tx::Consecutive {
+                            sn: self.tx_sn,
+                            block_left: data[1],
+                            st_min_ms: st_min_ms(data[2]),
+                            // Send the first frame straight away
+                            last: now_ms.wrapping_sub(st_min_ms(data[2])),
+                        }
+                    }
+                    WAIT => self.tx = Tx::WaitFlowControl { since: now_ms },
+                    OVERFLOW => {
+                        self.tx = Tx::Idle;
+                        return Err(Error::Overflow);
+                    }
+                    _ => {}
+                }
+                Ok(false)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn send_frame(&self, can: &Can, data: &[u8]) -> bool {
+        let mut buf = [self.config.padding.unwrap_or(0); 8];
+        buf[..data.len()].copy_from_slice(data);
+        let len = if self.config.padding.is_some() {
+            8
+        } else {
+            data.len()
+        };
+        Frame::new(self.config.tx_id, &buf[..len])
+            .is_some_and(|frame| can.send_frame(frame).is_ok())
+    }
+}
+
+/// Separation time in whole ms, 100 - 900 µs values round up to 1 ms.
+fn st_min_ms(st_min: u8) -> u32 {
+    match st_min {
+        0..=0x7F => st_min as u32,
+        0xF1..=0xF9 => 1,
+        // Reserved values mean the maximum
+        _ => 0x7F,
+    }
+}
@@ -0,0 +1,196 @@
+//! Key matrix scanning, e.g. 4×4 or 3×4 membrane keypads.
+//!
+//! Rows are open-drain outputs, released high, and columns are inputs with
+//! the internal pull-up. Each [`Keypad::scan`] pulls one row low at a time
+//! and reads which columns follow it, so a pressed key reads low. Scans
+//! should be at a steady rate, e.g. every millisecond from
+//! [`ticker::millis`](crate::ticker::millis) changing, and a key must read
+//! the same for [`DEBOUNCE_SCANS`] scans before a change is reported.
+//!
+//! ```ignore
+//! let mut keypad: Keypad<_, _, 4, 4, 8> = Keypad::new(
+//!     (pins.p100, pins.p101, pins.p102, pins.p103),
+//!     (pins.p104, pins.p105, pins.p106, pins.p107),
+//! );
+//! loop {
+//!     if ticker::millis() != last {
+//!         last = ticker::millis();
+//!         keypad.scan();
+//!     }
+//!     while let Some(Event::KeyDown(key)) = keypad.next() {
+//!         let digit = KEYS[key.row as usize][key.col as usize];
+//!     }
+//! }
+//! ```
+//!
+//! Without a diode per key, three keys pressed on the corners of a
+//! rectangle connect the fourth corner too. That pattern is ghosting, the
+//! rows involved can't be told apart from a real fourth press, so their
+//! keys keep their last state until it clears, see [`Keypad::is_ghosting`].
+
+use heapless::Deque;
+
+use crate::gpio::{self, BusPins, PinConfig};
+
+/// Scans a key must read the same for before a change is reported.
+pub const DEBOUNCE_SCANS: u8 = 5;
+
+// CPU cycles for the columns to follow a row, 2 us with a 48 MHz ICLK
+const SETTLE_CYCLES: u32 = 96;
+
+/// A key, by the row and column pins it connects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Key {
+    pub row: u8,
+    pub col: u8,
+}
+
+/// A change of a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    KeyDown(Key),
+    KeyUp(Key),
+}
+
+/// A matrix of `ROWS` by `COLS` keys on the pin tuples `R` and `C`,
+/// queuing up to `N` events.
+pub struct Keypad<R: BusPins, C: BusPins, const ROWS: usize, const COLS: usize, const N: usize> {
+    rows: R,
+    cols: C,
+    // Debounced pressed keys, a bit per column
+    pressed: [u8; ROWS],
+    // Scans each key has read differently to `pressed`
+    counts: [[u8; COLS]; ROWS],
+    ghosting: bool,
+    overflowed: bool,
+    events: Deque<Event, N>,
+}
+
+impl<R: BusPins, C: BusPins, const ROWS: usize, const COLS: usize, const N: usize>
+    Keypad<R, C, ROWS, COLS, N>
+{
+    /// Make the `rows` open-drain outputs, released, and the `cols` inputs
+    /// with the pull-up. The first pin of each tuple is row or column 0.
+    pub fn new(rows: R, cols: C) -> Self {
+        const {
+            assert!(ROWS == R::WIDTH && COLS == C::WIDTH);
+        }
+        for row in 0..ROWS {
+            let (port, pin) = R::position(row);
+            gpio::configure_at(port, pin, PinConfig::output().open_drain().high());
+        }
+        for col in 0..COLS {
+            let (port, pin) = C::position(col);
+            gpio::configure_at(port, pin, PinConfig::input().pull_up());
+        }
+        Self {
+            rows,
+            cols,
+            pressed: [0; ROWS],
+            counts: [[0; COLS]; ROWS],
+            ghosting: false,
+            overflowed: false,
+            events: Deque::new(),
+        }
+    }
+
+    /// Read every key once, queuing [`Event::KeyDown`] or [`Event::KeyUp`]
+    /// for each change that has been stable long enough.
+    pub fn scan(&mut self) {
+        let raw = self.read_matrix();
+
+        // Rows sharing two or more pressed columns form a rectangle
+        let mut ghosted = 0u8;
+        for (a, &first) in raw.iter().enumerate() {
+            for (b, &second) in raw.iter().enumerate().skip(a + 1) {
+                if (first & second).count_ones() >= 2 {
+                    ghosted |= (1 << a) | (1 << b);
+                }
+            }
+        }
+        self.ghosting = ghosted != 0;
+
+        for (row, (&raw, counts)) in raw.iter().zip(self.counts.iter_mut()).enumerate() {
+            if ghosted & (1 << row) != 0 {
+                *counts = [0; COLS];
+                continue;
+            }
+            for (col, count) in counts.iter_mut().enumerate() {
+                let bit = 1 << col;
+                if (raw ^ self.pressed[row]) & bit == 0 {
+                    *count = 0;
+                    continue;
+                }
+                *count += 1;
+                if *count >= DEBOUNCE_SCANS {
+                    *count = 0;
+                    self.pressed[row] ^= bit;
+                    let key = Key {
+                        row: row as u8,
+                        col: col as u8,
+                    };
+                    let event = if raw & bit != 0 {
+                        Event::KeyDown(key)
+                    } else {
+                        Event::KeyUp(key)
+                    };
+                    if self.events.push_back(event).is_err() {
+                        self.overflowed = true;
+                    }
+                }
+            }
+        }
+    }
+
+    /// The oldest event.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Whether the debounced state of `key` is pressed.
+    pub fn is_pressed(&self, key: Key) -> bool {
+        (key.col as usize) < COLS
+            && self
+                .pressed
+                .get(key.row as usize)
+                .is_some_and(|row| row & (1 << key.col) != 0)
+    }
+
+    /// Whether the last scan found a ghosting pattern, the keys of the
+    /// rows involved aren't updated until it clears.
+    pub fn is_ghosting(&self) -> bool {
+        self.ghosting
+    }
+
+    /// Whether events were dropped because the queue was full, clearing
+    /// the flag.
+    pub fn take_overflow(&mut self) -> bool {
+        core::mem::take(&mut self.overflowed)
+    }
+
+    /// Release the pins, rows left as released open-drain outputs.
+    pub fn free(self) -> (R, C) {
+        (self.rows, self.cols)
+    }
+
+    // Pressed keys of each row, a bit per column
+    fn read_matrix(&self) -> [u8; ROWS] {
+        let mut raw = [0; ROWS];
+        for (row, raw) in raw.iter_mut().enumerate() {
+            let (port, pin) = R::position(row);
+            gpio::write_at(port, pin, false);
+            cortex_m::asm::delay(SETTLE_CYCLES);
+            for col in 0..COLS {
+                let (port, pin) = C::position(col);
+                if !gpio::is_high_at(port, pin) {
+                    *raw |= 1 << col;
+                }
+            }
+            gpio::write_at(port, pin, true);
+        }
+        raw
+    }
+}
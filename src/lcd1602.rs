@@ -0,0 +1,306 @@
+//! HD44780 character LCDs, such as the 16×2 LCD1602.
+//!
+//! The display is driven in 4-bit mode, either from six GPIO pins with R/W
+//! tied to ground, or through a PCF8574 I2C backpack. Nothing is read back,
+//! the driver waits the longest execution time of each instruction instead.
+//!
+//! ```ignore
+//! let pins = Gpio4::new(pins.p104, pins.p105, pins.p106, pins.p107, pins.p111, pins.p112);
+//! let mut lcd = Lcd::new(pins, 16, 2)?;
+//! write!(lcd, "Speed {:3}", rpm)?;
+//! lcd.set_cursor(0, 1)?;
+//! lcd.create_char(0, [0x04, 0x0E, 0x1F, 0x04, 0x04, 0x04, 0x04, 0x00])?;
+//! lcd.write_byte(0)?;
+//! ```
+//!
+//! There's no I2C driver in the crate yet, so [`Pcf8574`] takes anything
+//! implementing [`I2cWrite`].
+
+use core::convert::Infallible;
+use core::fmt;
+
+use crate::cycles;
+use crate::gpio::{self, Pin};
+
+/// I2C address of PCF8574 backpacks with A0 to A2 open, 0x3F for a PCF8574A.
+pub const PCF8574_ADDRESS: u8 = 0x27;
+
+// Instructions
+const CLEAR: u8 = 0x01;
+const HOME: u8 = 0x02;
+const ENTRY_MODE: u8 = 0x04;
+const ENTRY_INCREMENT: u8 = 0x02;
+const DISPLAY_CONTROL: u8 = 0x08;
+const DISPLAY_ON: u8 = 0x04;
+const CURSOR_ON: u8 = 0x02;
+const BLINK_ON: u8 = 0x01;
+const SHIFT: u8 = 0x10;
+const SHIFT_DISPLAY: u8 = 0x08;
+const SHIFT_RIGHT: u8 = 0x04;
+const FUNCTION_SET: u8 = 0x20;
+const FUNCTION_2_LINES: u8 = 0x08;
+const SET_CGRAM: u8 = 0x40;
+const SET_DDRAM: u8 = 0x80;
+
+// Execution times, for a 190 kHz oscillator, the slowest in the datasheet
+const CLEAR_US: u32 = 2_000;
+const INSTRUCTION_US: u32 = 50;
+const POWER_ON_MS: u32 = 50;
+
+// DDRAM address of the start of each line, lines 2 and 3 continue 0 and 1
+const LINE_ADDRESS: [u8; 4] = [0x00, 0x40, 0x14, 0x54];
+
+// PCF8574 backpack bits, P4 to P7 are D4 to D7
+const PCF_RS: u8 = 1 << 0;
+const PCF_E: u8 = 1 << 2;
+const PCF_BACKLIGHT: u8 = 1 << 3;
+
+/// Writing bytes to an I2C device, for [`Pcf8574`].
+pub trait I2cWrite {
+    type Error;
+    /// Write `bytes` to the device at 7-bit `address` in one transfer
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// A 4-bit connection to the display.
+pub trait Interface {
+    type Error;
+    /// Latch the low 4 bits of `nibble` into the display, as data if `data`
+    /// or else as an instruction.
+    fn write_nibble(&mut self, data: bool, nibble: u8) -> Result<(), Self::Error>;
+}
+
+/// The display on six GPIO pins, R/W tied to ground.
+pub struct Gpio4<RS: Pin, E: Pin, D4: Pin, D5: Pin, D6: Pin, D7: Pin> {
+    pins: (RS, E, D4, D5, D6, D7),
+}
+
+impl<RS: Pin, E: Pin, D4: Pin, D5: Pin, D6: Pin, D7: Pin> Gpio4<RS, E, D4, D5, D6, D7> {
+    /// Make the pins outputs, driven low.
+    pub fn new(rs: RS, e: E, d4: D4, d5: D5, d6: D6, d7: D7) -> Self {
+        gpio::set_output::<RS>(false);
+        gpio::set_output::<E>(false);
+        gpio::set_output::<D4>(false);
+        gpio::set_output::<D5>(false);
+        gpio::set_output::<D6>(false);
+        gpio::set_output::<D7>(false);
+        Self {
+            pins: (rs, e, d4, d5, d6, d7),
+        }
+    }
+
+    /// Release the pins, left as outputs.
+    pub fn free(self) -> (RS, E, D4, D5, D6, D7) {
+        self.pins
+    }
+}
+
+impl<RS: Pin, E: Pin, D4: Pin, D5: Pin, D6: Pin, D7: Pin> Interface
+    for Gpio4<RS, E, D4, D5, D6, D7>
+{
+    type Error = Infallible;
+
+    fn write_nibble(&mut self, data: bool, nibble: u8) -> Result<(), Infallible> {
+        gpio::write::<RS>(data);
+        gpio::write::<D4>(nibble & 0x1 != 0);
+        gpio::write::<D5>(nibble & 0x2 != 0);
+        gpio::write::<D6>(nibble & 0x4 != 0);
+        gpio::write::<D7>(nibble & 0x8 != 0);
+        // E high for over 450 ns, data latched on the falling edge
+        gpio::write::<E>(true);
+        cycles::busy_wait_us(1);
+        gpio::write::<E>(false);
+        cycles::busy_wait_us(1);
+        Ok(())
+    }
+}
+
+/// The display on a PCF8574 I2C backpack.
+pub struct Pcf8574<I: I2cWrite> {
+    i2c: I,
+    address: u8,
+    backlight: u8,
+}
+
+impl<I: I2cWrite> Pcf8574<I> {
+    /// A backpack at `address`, usually [`PCF8574_ADDRESS`], backlight on.
+    pub fn new(i2c: I, address: u8) -> Self {
+        Self {
+            i2c,
+            address,
+            backlight: PCF_BACKLIGHT,
+        }
+    }
+
+    /// Switch the backlight on or off.
+    pub fn set_backlight(&mut self, on: bool) -> Result<(), I::Error> {
+        self.backlight = if on { PCF_BACKLIGHT } else { 0 };
+        self.i2c.write(self.address, &[self.backlight])
+    }
+
+    /// Release the I2C bus.
+    pub fn free(self) -> I {
+        self.i2c
+    }
+}
+
+impl<I: I2cWrite> Interface for Pcf8574<I> {
+    type Error = I::Error;
+
+    fn write_nibble(&mut self, data: bool, nibble: u8) -> Result<(), I::Error> {
+        let byte = (nibble << 4) | self.backlight | if data { PCF_RS } else { 0 };
+        // At 100 kHz each byte takes 90 us, so E is high long enough
+        self.i2c.write(self.address, &[byte | PCF_E, byte])
+    }
+}
+
+/// An HD44780 display on interface `I`.
+pub struct Lcd<I: Interface> {
+    interface: I,
+    columns: u8,
+    lines: u8,
+    display: u8,
+}
+
+impl<I: Interface> Lcd<I> {
+    /// Initialise a display of `columns` by `lines` characters, cleared
+    /// with the cursor hidden.
+    ///
+    /// Takes over 50 ms, the display needs 40 ms after power on.
+    pub fn new(interface: I, columns: u8, lines: u8) -> Result<Self, I::Error> {
+        let mut lcd = Self {
+            interface,
+            columns,
+            lines: lines.clamp(1, 4),
+            display: DISPLAY_ON,
+        };
+        cycles::busy_wait_ms(POWER_ON_MS);
+        // Reset by instruction, the display may be in 4 or 8-bit mode
+        lcd.interface.write_nibble(false, 0x3)?;
+        cycles::busy_wait_us(4_100);
+        lcd.interface.write_nibble(false, 0x3)?;
+        cycles::busy_wait_us(100);
+        lcd.interface.write_nibble(false, 0x3)?;
+        cycles::busy_wait_us(INSTRUCTION_US);
+        lcd.interface.write_nibble(false, 0x2)?;
+        cycles::busy_wait_us(INSTRUCTION_US);
+
+        let function = if lcd.lines > 1 { FUNCTION_2_LINES } else { 0 };
+        lcd.command(FUNCTION_SET | function)?;
+        lcd.command(DISPLAY_CONTROL)?;
+        lcd.clear()?;
+        lcd.command(ENTRY_MODE | ENTRY_INCREMENT)?;
+        lcd.command(DISPLAY_CONTROL | lcd.display)?;
+        Ok(lcd)
+    }
+
+    /// Clear the display and move the cursor to the top left.
+    pub fn clear(&mut self) -> Result<(), I::Error> {
+        self.command(CLEAR)?;
+        cycles::busy_wait_us(CLEAR_US);
+        Ok(())
+    }
+
+    /// Move the cursor to the top left and undo any shift.
+    pub fn home(&mut self) -> Result<(), I::Error> {
+        self.command(HOME)?;
+        cycles::busy_wait_us(CLEAR_US);
+        Ok(())
+    }
+
+    /// Move the cursor to `column` of `line`, both from 0, clamped to the
+    /// display.
+    pub fn set_cursor(&mut self, column: u8, line: u8) -> Result<(), I::Error> {
+        let line = line.min(self.lines - 1);
+        let column = column.min(self.columns.saturating_sub(1));
+        self.command(SET_DDRAM | (LINE_ADDRESS[line as usize] + column))
+    }
+
+    /// Show or hide the underline cursor.
+    pub fn set_cursor_visible(&mut self, visible: bool) -> Result<(), I::Error> {
+        self.set_display_bit(CURSOR_ON, visible)
+    }
+
+    /// Blink the character at the cursor.
+    pub fn set_blink(&mut self, blink: bool) -> Result<(), I::Error> {
+        self.set_display_bit(BLINK_ON, blink)
+    }
+
+    /// Show or blank the display, keeping its contents.
+    pub fn set_display_on(&mut self, on: bool) -> Result<(), I::Error> {
+        self.set_display_bit(DISPLAY_ON, on)
+    }
+
+    /// Shift the whole display one character left or right.
+    pub fn scroll(&mut self, right: bool) -> Result<(), I::Error> {
+        let direction = if right { SHIFT_RIGHT } else { 0 };
+        self.command(SHIFT | SHIFT_DISPLAY | direction)
+    }
+
+    /// Define custom character `index`, 0 to 7, from 8 rows of 5 bits, the
+    /// top row first. It's written with [`write_byte`](Self::write_byte).
+    ///
+    /// Leaves the cursor at the top left.
+    pub fn create_char(&mut self, index: u8, rows: [u8; 8]) -> Result<(), I::Error> {
+        self.command(SET_CGRAM | ((index & 0x7) << 3))?;
+        for row in rows {
+            self.data(row & 0x1F)?;
+        }
+        self.command(SET_DDRAM)
+    }
+
+    /// Write a character code at the cursor, e.g. a custom character.
+    pub fn write_byte(&mut self, byte: u8) -> Result<(), I::Error> {
+        self.data(byte)
+    }
+
+    /// Write ASCII text at the cursor, other characters as `?`.
+    ///
+    /// Text isn't wrapped, the display continues on the line after next.
+    pub fn write_str(&mut self, s: &str) -> Result<(), I::Error> {
+        for c in s.chars() {
+            self.data(if c.is_ascii() { c as u8 } else { b'?' })?;
+        }
+        Ok(())
+    }
+
+    /// The interface, e.g. for [`Pcf8574::set_backlight`].
+    pub fn interface_mut(&mut self) -> &mut I {
+        &mut self.interface
+    }
+
+    /// Release the interface.
+    pub fn free(self) -> I {
+        self.interface
+    }
+
+    fn set_display_bit(&mut self, bit: u8, set: bool) -> Result<(), I::Error> {
+        if set {
+            self.display |= bit;
+        } else {
+            self.display &= !bit;
+        }
+        self.command(DISPLAY_CONTROL | self.display)
+    }
+
+    fn command(&mut self, byte: u8) -> Result<(), I::Error> {
+        self.write(false, byte)
+    }
+
+    fn data(&mut self, byte: u8) -> Result<(), I::Error> {
+        self.write(true, byte)
+    }
+
+    fn write(&mut self, data: bool, byte: u8) -> Result<(), I::Error> {
+        self.interface.write_nibble(data, byte >> 4)?;
+        self.interface.write_nibble(data, byte & 0xF)?;
+        cycles::busy_wait_us(INSTRUCTION_US);
+        Ok(())
+    }
+}
+
+impl<I: Interface> fmt::Write for Lcd<I> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        Lcd::write_str(self, s).map_err(|_| fmt::Error)
+    }
+}
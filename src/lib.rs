@@ -1,7 +1,102 @@
 #![cfg_attr(not(test), no_std)]
 
+#[cfg(all(
+    feature = "critical-section-single-core",
+    feature = "critical-section-basepri"
+))]
+compile_error!(
+    "features `critical-section-single-core` and `critical-section-basepri` are exclusive, \
+     use `default-features = false` for the BASEPRI critical section"
+);
+
+#[cfg(feature = "acmp")]
+pub mod acmp;
+#[cfg(feature = "adapters")]
+pub mod adapters;
+#[cfg(feature = "adc")]
+pub mod adc;
+#[cfg(feature = "critical-section-basepri")]
+pub mod basepri;
+pub mod bootload;
+pub mod cac;
+#[cfg(feature = "can")]
 pub mod can;
+#[cfg(feature = "can")]
+pub mod canlog;
 pub mod clk;
+#[cfg(feature = "uart")]
+pub mod console;
+pub mod crash;
+pub mod cycles;
+pub mod dmac;
+#[cfg(all(feature = "uart", feature = "gpt"))]
+pub mod dmx;
+#[cfg(feature = "uart")]
+pub mod dynamixel;
+pub mod elc;
+pub mod error;
+pub mod events;
+pub mod flash;
+pub mod fwupdate;
+#[cfg(feature = "can")]
+pub mod gateway;
+pub mod gpio;
+#[cfg(feature = "gps")]
+pub mod gps;
+#[cfg(feature = "gpt")]
+pub mod gpt;
+#[cfg(feature = "can")]
+pub mod gvret;
+#[cfg(feature = "gpt")]
+pub mod hcsr04;
+#[cfg(feature = "adc")]
+pub mod health;
+#[cfg(feature = "alloc")]
+pub mod heap;
+pub mod info;
+pub mod input;
 pub mod interrupts;
+#[cfg(feature = "gpt")]
+pub mod ir;
+#[cfg(feature = "can")]
+pub mod isotp;
+pub mod keypad;
+pub mod lcd1602;
+#[cfg(feature = "uart")]
+pub mod lin;
+#[cfg(feature = "link")]
+pub mod link;
+pub mod mpu;
+#[cfg(feature = "adc")]
+pub mod opamp;
+#[cfg(feature = "uart")]
+pub mod rc;
+pub mod reset;
+#[cfg(feature = "sdcard")]
+pub mod sdcard;
+#[cfg(all(feature = "can", feature = "uart"))]
+pub mod selftest;
+#[cfg(feature = "gpt")]
+pub mod servo;
+pub mod shell;
+pub mod shutdown;
+#[cfg(feature = "can")]
+pub mod slcan;
+#[cfg(feature = "spi")]
+pub mod spi;
+pub mod stackcheck;
+pub mod supervisor;
+pub mod ticker;
+pub mod timeout;
+#[cfg(feature = "trace")]
+pub mod trace;
+#[cfg(feature = "can")]
+pub mod uds;
+#[cfg(feature = "ws2812")]
+pub mod ws2812;
+pub mod xmodem;
 
+#[cfg(feature = "uart")]
 pub mod uart;
+
+pub use error::Error;
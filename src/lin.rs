@@ -0,0 +1,465 @@
+//! LIN bus master and slave on top of the UART driver.
+//!
+//! Needs a LIN transceiver (e.g. TJA1021) between the SCI pins and the bus.
+//! Most transceivers echo the transmitted bytes back on RXD, set
+//! [`Config::echo`] to match.
+//!
+//! The break field is generated by sending `0x00` at 9/13 of the bus rate,
+//! which holds the line dominant for 13 bit times followed by a break
+//! delimiter of about 1.4 bit times.
+//!
+//! The master runs a schedule table. [`Master::tick`] is called at a fixed
+//! rate (the time base, typically 1 - 10 ms) from a timer or the main loop,
+//! each schedule entry occupies a whole number of ticks. The response of a
+//! frame is collected at the end of its slot.
+//!
+//! ```ignore
+//! const SCHEDULE: [lin::Entry; 2] = [
+//!     lin::Entry { id: 0x10, frame: lin::FrameKind::Publish(2), slot_ticks: 10 },
+//!     lin::Entry { id: 0x20, frame: lin::FrameKind::Subscribe(4), slot_ticks: 10 },
+//! ];
+//! let mut master = lin::Master::new(uart, lin::Config::default(), SCHEDULE);
+//! master.set_data(0x10, &[0x01, 0x02]);
+//! loop {
+//!     if let Some(lin::Event::Received { id, data, len }) = master.tick() { /* ... */ }
+//!     delay_ms(5);
+//! }
+//! ```
+
+use embedded_io::{Read, ReadReady, Write};
+
+use crate::uart::{Instance, Uart};
+
+/// Sync field, sent after the break.
+pub const SYNC: u8 = 0x55;
+
+/// Largest number of data bytes in a frame.
+pub const MAX_DATA: usize = 8;
+
+/// Checksum over the data only (LIN 1.x) or the data and protected ID (LIN 2.x).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumModel {
+    Classic,
+    Enhanced,
+}
+
+/// LIN configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Bus rate, usually 19200 or 9600
+    pub baud: u32,
+    /// Checksum model for IDs 0 - 0x3B, diagnostic frames always use classic
+    pub checksum: ChecksumModel,
+    /// Transmitted bytes are echoed back by the transceiver
+    pub echo: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            baud: 19_200,
+            checksum: ChecksumModel::Enhanced,
+            echo: true,
+        }
+    }
+}
+
+/// LIN errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// No bytes were received in the response slot
+    NoResponse,
+    /// Fewer bytes than expected were received
+    Incomplete,
+    /// Checksum of the response doesn't match
+    Checksum,
+    /// The echo of a transmitted byte differs, the bus was driven by another node
+    Bit,
+    /// Received protected ID has the wrong parity
+    Parity,
+}
+
+/// Protected identifier of a frame ID, the 6 bit ID with its two parity bits.
+pub const fn pid(id: u8) -> u8 {
+    let id = id & 0x3F;
+    let bit = |n: u8| (id >> n) & 1;
+    let p0 = bit(0) ^ bit(1) ^ bit(2) ^ bit(4);
+    let p1 = !(bit(1) ^ bit(3) ^ bit(4) ^ bit(5)) & 1;
+    id | (p0 << 6) | (p1 << 7)
+}
+
+/// Frame ID of a protected identifier, None if the parity is wrong.
+pub const fn id_from_pid(pid_byte: u8) -> Option<u8> {
+    let id = pid_byte & 0x3F;
+    if pid(id) == pid_byte { Some(id) } else { None }
+}
+
+/// Frame checksum, the inverted sum with carry of the data and,
+/// for the enhanced model, the protected ID.
+///
+/// Diagnostic frames (IDs 0x3C and 0x3D) always use the classic model.
+pub fn checksum(model: ChecksumModel, id: u8, data: &[u8]) -> u8 {
+    let id = id & 0x3F;
+    let mut sum: u16 = match model {
+        ChecksumModel::Enhanced if id < 0x3C => pid(id) as u16,
+        _ => 0,
+    };
+    for byte in data {
+        sum += *byte as u16;
+        if sum > 0xFF {
+            sum -= 0xFF;
+        }
+    }
+    !(sum as u8)
+}
+
+/// Direction of the response of a frame, seen from the master.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    /// The master sends a response of this many bytes
+    Publish(u8),
+    /// A slave sends a response of this many bytes
+    Subscribe(u8),
+}
+
+impl FrameKind {
+    fn len(&self) -> usize {
+        match self {
+            FrameKind::Publish(len) | FrameKind::Subscribe(len) => (*len as usize).min(MAX_DATA),
+        }
+    }
+}
+
+/// Entry of a schedule table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    /// Frame ID, 0 - 0x3F
+    pub id: u8,
+    /// Response direction and length
+    pub frame: FrameKind,
+    /// Length of the slot in ticks, at least 1
+    pub slot_ticks: u16,
+}
+
+/// Something that happened on the bus at the end of a slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    /// A publish frame was sent
+    Sent { id: u8 },
+    /// A slave responded to a subscribe frame
+    Received {
+        id: u8,
+        data: [u8; MAX_DATA],
+        len: u8,
+    },
+    /// A frame failed
+    Error { id: u8, error: Error },
+}
+
+/// Write a break, sync and protected ID.
+fn send_header<T: Instance, const TX: usize, const RX: usize>(
+    uart: &mut Uart<T, TX, RX>,
+    config: &Config,
+    id: u8,
+) {
+    // 0x00 at 9/13 of the rate, start bit and 8 data bits are 13 bit times
+    uart.set_baud_rate(config.baud * 9 / 13);
+    // Writes to the ring buffer can't fail
+    let _ = uart.write_all(&[0x00]);
+    uart.set_baud_rate(config.baud);
+    let _ = uart.write_all(&[SYNC, pid(id)]);
+}
+
+// Discard anything in the receive buffer
+fn drain<T: Instance, const TX: usize, const RX: usize>(uart: &mut Uart<T, TX, RX>) {
+    let mut buf = [0u8; 16];
+    while uart.read_ready().unwrap_or(false) {
+        let _ = uart.read(&mut buf);
+    }
+}
+
+// Read up to `buf.len()` bytes that have already been received
+fn read_available<T: Instance, const TX: usize, const RX: usize>(
+    uart: &mut Uart<T, TX, RX>,
+    buf: &mut [u8],
+) -> usize {
+    let mut len = 0;
+    while len < buf.len() && uart.read_ready().unwrap_or(false) {
+        len += uart.read(&mut buf[len..]).unwrap_or(0);
+    }
+    len
+}
+
+/// Schedule table driven LIN master.
+pub struct Master<T: Instance, const TX: usize, const RX: usize, const N: usize> {
+    uart: Uart<T, TX, RX>,
+    config: Config,
+    schedule: [Entry; N],
+    data: [[u8; MAX_DATA]; N],
+    index: usize,
+    ticks_left: u16,
+    // Schedule index of the frame whose response is awaited
+    pending: Option<usize>,
+}
+
+impl<T: Instance, const TX: usize, const RX: usize, const N: usize> Master<T, TX, RX, N> {
+    /// Take over the UART and set the bus rate. The schedule starts on the first tick.
+    pub fn new(mut uart: Uart<T, TX, RX>, config: Config, schedule: [Entry; N]) -> Self {
+        uart.set_baud_rate(config.baud);
+        Self {
+            uart,
+            config,
+            schedule,
+            data: [[0; MAX_DATA]; N],
+            index: 0,
+            ticks_left: 0,
+            pending: None,
+        }
+    }
+
+    /// Set the response sent in every publish frame with this ID.
+    pub fn set_data(&mut self, id: u8, data: &[u8]) {
+        for (entry, buf) in self.schedule.iter().zip(self.data.iter_mut()) {
+            if entry.id == id {
+                let len = data.len().min(MAX_DATA);
+                buf[..len].copy_from_slice(&data[..len]);
+            }
+        }
+    }
+
+    /// Replace the schedule table, it starts from the first entry on the next tick.
+    pub fn set_schedule(&mut self, schedule: [Entry; N]) {
+        self.schedule = schedule;
+        self.data = [[0; MAX_DATA]; N];
+        self.index = 0;
+        self.ticks_left = 0;
+        self.pending = None;
+    }
+
+    /// Advance the schedule by one time base tick.
+    ///
+    /// At the end of a slot the response of its frame is checked and the
+    /// header of the next frame is sent.
+    pub fn tick(&mut self) -> Option<Event> {
+        if N == 0 {
+            return None;
+        }
+        self.ticks_left = self.ticks_left.saturating_sub(1);
+        if self.ticks_left > 0 {
+            return None;
+        }
+        let event = self.pending.take().map(|index| self.collect(index));
+
+        let index = self.index;
+        self.index = (self.index + 1) % N;
+        let entry = self.schedule[index];
+        self.ticks_left = entry.slot_ticks.max(1);
+        drain(&mut self.uart);
+        send_header(&mut self.uart, &self.config, entry.id);
+        if let FrameKind::Publish(_) = entry.frame {
+            let len = entry.frame.len();
+            let data = &self.data[index][..len];
+            let sum = checksum(self.config.checksum, entry.id, data);
+            let _ = self.uart.write_all(data);
+            let _ = self.uart.write_all(&[sum]);
+        }
+        self.pending = Some(index);
+        event
+    }
+
+    /// Release the UART.
+    pub fn free(self) -> Uart<T, TX, RX> {
+        self.uart
+    }
+
+    fn collect(&mut self, index: usize) -> Event {
+        let entry = self.schedule[index];
+        let id = entry.id;
+        let len = entry.frame.len();
+        // Break, sync and PID, then the response and checksum
+        let mut buf = [0u8; 3 + MAX_DATA + 1];
+        let header = if self.config.echo { 3 } else { 0 };
+        let received = read_available(&mut self.uart, &mut buf[..header + len + 1]);
+        let response = &buf[header.min(received)..received];
+
+        match entry.frame {
+            FrameKind::Publish(_) => {
+                if !self.config.echo {
+                    return Event::Sent { id };
+                }
+                let data = &self.data[index][..len];
+                let sum = checksum(self.config.checksum, id, data);
+                if received < header + len + 1 {
+                    Event::Error {
+                        id,
+                        error: Error::Incomplete,
+                    }
+                } else if buf[1..3] != [SYNC, pid(id)]
+                    || &response[..len] != data
+                    || response[len] != sum
+                {
+                    Event::Error {
+                        id,
+                        error: Error::Bit,
+                    }
+                } else {
+                    Event::Sent { id }
+                }
+            }
+            FrameKind::Subscribe(_) => {
+                let error = if response.is_empty() {
+                    Some(Error::NoResponse)
+                } else if response.len() < len + 1 {
+                    Some(Error::Incomplete)
+                } else if checksum(self.config.checksum, id, &response[..len]) != response[len] {
+                    Some(Error::Checksum)
+                } else {
+                    None
+                };
+                match error {
+                    Some(error) => Event::Error { id, error },
+                    None => {
+                        let mut data = [0; MAX_DATA];
+                        data[..len].copy_from_slice(&response[..len]);
+                        Event::Received {
+                            id,
+                            data,
+                            len: len as u8,
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Something a slave saw on the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SlaveEvent {
+    /// A header with this ID, respond with [`Slave::respond`] or
+    /// receive the response with [`Slave::listen`]
+    Header { id: u8 },
+    /// A response received after [`Slave::listen`]
+    Received {
+        id: u8,
+        data: [u8; MAX_DATA],
+        len: u8,
+    },
+    /// A header or response was invalid
+    Error { id: u8, error: Error },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SlaveState {
+    // Waiting for the sync byte
+    Idle,
+    // Sync seen, next byte is the PID
+    Sync,
+    // Receiving a response for `id`
+    Response { id: u8, len: u8, received: u8 },
+}
+
+/// LIN slave, reacts to headers sent by a master.
+///
+/// The break is seen by the SCI as a framing error and dropped,
+/// so a header is detected from the sync byte and protected ID.
+pub struct Slave<T: Instance, const TX: usize, const RX: usize> {
+    uart: Uart<T, TX, RX>,
+    config: Config,
+    state: SlaveState,
+    buf: [u8; MAX_DATA + 1],
+}
+
+impl<T: Instance, const TX: usize, const RX: usize> Slave<T, TX, RX> {
+    /// Take over the UART and set the bus rate.
+    pub fn new(mut uart: Uart<T, TX, RX>, config: Config) -> Self {
+        uart.set_baud_rate(config.baud);
+        Self {
+            uart,
+            config,
+            state: SlaveState::Idle,
+            buf: [0; MAX_DATA + 1],
+        }
+    }
+
+    /// Process received bytes, call often enough not to miss the
+    /// time between a header and its response.
+    pub fn poll(&mut self) -> Option<SlaveEvent> {
+        let mut byte = [0u8; 1];
+        while read_available(&mut self.uart, &mut byte) == 1 {
+            let byte = byte[0];
+            match self.state {
+                SlaveState::Idle => {
+                    if byte == SYNC {
+                        self.state = SlaveState::Sync;
+                    }
+                }
+                SlaveState::Sync => {
+                    self.state = SlaveState::Idle;
+                    return Some(match id_from_pid(byte) {
+                        Some(id) => SlaveEvent::Header { id },
+                        None => SlaveEvent::Error {
+                            id: byte & 0x3F,
+                            error: Error::Parity,
+                        },
+                    });
+                }
+                SlaveState::Response { id, len, received } => {
+                    self.buf[received as usize] = byte;
+                    let received = received + 1;
+                    if received <= len {
+                        self.state = SlaveState::Response { id, len, received };
+                        continue;
+                    }
+                    self.state = SlaveState::Idle;
+                    let len = len as usize;
+                    let data = &self.buf[..len];
+                    if checksum(self.config.checksum, id, data) != self.buf[len] {
+                        return Some(SlaveEvent::Error {
+                            id,
+                            error: Error::Checksum,
+                        });
+                    }
+                    let mut out = [0; MAX_DATA];
+                    out[..len].copy_from_slice(data);
+                    return Some(SlaveEvent::Received {
+                        id,
+                        data: out,
+                        len: len as u8,
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    /// Send the response to a header just received with [`SlaveEvent::Header`].
+    pub fn respond(&mut self, id: u8, data: &[u8]) {
+        let data = &data[..data.len().min(MAX_DATA)];
+        let sum = checksum(self.config.checksum, id, data);
+        let _ = self.uart.write_all(data);
+        let _ = self.uart.write_all(&[sum]);
+        self.uart.wait_idle();
+        if self.config.echo {
+            drain(&mut self.uart);
+        }
+    }
+
+    /// Receive the `len` byte response to a header just received with
+    /// [`SlaveEvent::Header`], reported by a later [`poll`](Self::poll).
+    pub fn listen(&mut self, id: u8, len: u8) {
+        self.state = SlaveState::Response {
+            id,
+            len: len.min(MAX_DATA as u8),
+            received: 0,
+        };
+    }
+
+    /// Release the UART.
+    pub fn free(self) -> Uart<T, TX, RX> {
+        self.uart
+    }
+}
@@ -0,0 +1,229 @@
+//! Framed request / response channel over a serial port.
+//!
+//! The host sends requests serialized with postcard, the MCU answers each
+//! one through a [`Handler`]. Frames are COBS encoded and end with a zero
+//! byte, so the receiver resynchronises after noise or a lost byte, and
+//! carry a CRC-32 so damaged frames are dropped instead of decoded:
+//!
+//! ```text
+//! request:  COBS(seq, postcard(request), crc32 LE) 00
+//! response: COBS(seq, status, postcard(response), crc32 LE) 00
+//! ```
+//!
+//! The CRC is [`fwupdate::crc32`](crate::fwupdate::crc32) of the bytes
+//! before it. `seq` is copied from the request, `status` is [`STATUS_OK`],
+//! or [`STATUS_BAD_REQUEST`] with no response when the request doesn't
+//! deserialize. Frames failing the CRC get no answer, the host retries.
+//!
+//! ```ignore
+//! #[derive(Deserialize)]
+//! enum Request { Ping, ReadAdc(u8) }
+//!
+//! struct Board { adc: Adc }
+//!
+//! impl link::Handler for Board {
+//!     type Request = Request;
+//!     type Response = u16;
+//!
+//!     fn handle(&mut self, request: Request) -> u16 {
+//!         match request {
+//!             Request::Ping => 0,
+//!             Request::ReadAdc(channel) => self.adc.read(channel),
+//!         }
+//!     }
+//! }
+//!
+//! let mut server: Server<_, _, 128> = Server::new(uart, Board { adc });
+//! loop {
+//!     server.poll()?;
+//! }
+//! ```
+
+use embedded_io::{Read, ReadReady, Write};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::fwupdate::crc32;
+
+/// Response status, the request was handled.
+pub const STATUS_OK: u8 = 0;
+/// Response status, the request didn't deserialize.
+pub const STATUS_BAD_REQUEST: u8 = 1;
+
+// Sequence number and status before the response, CRC after it
+const HEADER: usize = 2;
+const CRC: usize = 4;
+
+/// Link error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// Reading or writing the serial port failed
+    Port,
+    /// The response didn't serialize into the frame buffer
+    Encode,
+}
+
+/// Answers the requests of a [`Server`].
+pub trait Handler {
+    /// Request sent by the host
+    type Request: DeserializeOwned;
+    /// Response sent back for each request
+    type Response: Serialize;
+
+    /// Answer a request.
+    fn handle(&mut self, request: Self::Request) -> Self::Response;
+}
+
+/// Server side of the link on serial port `P`, with `N` byte frames.
+///
+/// `N` bounds both the encoded request and the unencoded response,
+/// including the sequence number, status and CRC.
+pub struct Server<P: Read + ReadReady + Write, H: Handler, const N: usize> {
+    port: P,
+    handler: H,
+    frame: [u8; N],
+    len: usize,
+    // Frame too long, discarded up to the next zero byte
+    overflow: bool,
+    dropped: u32,
+}
+
+impl<P: Read + ReadReady + Write, H: Handler, const N: usize> Server<P, H, N> {
+    /// Serve the requests received on `port` with `handler`.
+    pub fn new(port: P, handler: H) -> Self {
+        const { assert!(N > HEADER + CRC, "frames too short for the header and CRC") };
+        Self {
+            port,
+            handler,
+            frame: [0; N],
+            len: 0,
+            overflow: false,
+            dropped: 0,
+        }
+    }
+
+    /// Handle the frames received, answering each request.
+    pub fn poll(&mut self) -> Result<(), Error> {
+        while self.port.read_ready().map_err(|_| Error::Port)? {
+            let mut byte = [0u8; 1];
+            if self.port.read(&mut byte).map_err(|_| Error::Port)? == 0 {
+                break;
+            }
+            match byte[0] {
+                0 => {
+                    if self.overflow {
+                        self.dropped += 1;
+                    } else if self.len > 0 {
+                        self.receive()?;
+                    }
+                    self.len = 0;
+                    self.overflow = false;
+                }
+                byte if self.len < N => {
+                    self.frame[self.len] = byte;
+                    self.len += 1;
+                }
+                _ => self.overflow = true,
+            }
+        }
+        Ok(())
+    }
+
+    /// Frames dropped since the server was created, for being too long,
+    /// badly encoded or failing the CRC.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// The handler, e.g. to update the state it answers from.
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    /// Release the serial port and the handler.
+    pub fn free(self) -> (P, H) {
+        (self.port, self.handler)
+    }
+
+    // Check and answer the frame in the buffer
+    fn receive(&mut self) -> Result<(), Error> {
+        let Some(len) = cobs_decode(&mut self.frame[..self.len]) else {
+            self.dropped += 1;
+            return Ok(());
+        };
+        if len < 1 + CRC {
+            self.dropped += 1;
+            return Ok(());
+        }
+        let (body, crc) = self.frame[..len].split_at(len - CRC);
+        if crc32(body) != u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]) {
+            self.dropped += 1;
+            return Ok(());
+        }
+
+        let mut reply = [0u8; N];
+        reply[0] = body[0];
+        let mut len = HEADER;
+        match postcard::from_bytes::<H::Request>(&body[1..]) {
+            Ok(request) => {
+                let response = self.handler.handle(request);
+                len += postcard::to_slice(&response, &mut reply[HEADER..N - CRC])
+                    .map_err(|_| Error::Encode)?
+                    .len();
+                reply[1] = STATUS_OK;
+            }
+            Err(_) => reply[1] = STATUS_BAD_REQUEST,
+        }
+        let crc = crc32(&reply[..len]).to_le_bytes();
+        reply[len..len + CRC].copy_from_slice(&crc);
+        cobs_write(&mut self.port, &reply[..len + CRC]).map_err(|_| Error::Port)
+    }
+}
+
+// Decode a COBS frame in place, without the zero byte, returning its length
+fn cobs_decode(data: &mut [u8]) -> Option<usize> {
+    let mut read = 0;
+    let mut write = 0;
+    while read < data.len() {
+        let code = data[read] as usize;
+        let end = read + code;
+        if code == 0 || end > data.len() {
+            return None;
+        }
+        data.copy_within(read + 1..end, write);
+        write += code - 1;
+        read = end;
+        // A block shorter than the maximum was followed by a zero
+        if code < 0xFF && read < data.len() {
+            data[write] = 0;
+            write += 1;
+        }
+    }
+    Some(write)
+}
+
+// COBS encode `data` to the port, followed by the zero byte
+fn cobs_write<P: Write>(port: &mut P, data: &[u8]) -> Result<(), P::Error> {
+    let mut rest = data;
+    loop {
+        match rest.iter().take(254).position(|&byte| byte == 0) {
+            Some(n) => {
+                port.write_all(&[n as u8 + 1])?;
+                port.write_all(&rest[..n])?;
+                rest = &rest[n + 1..];
+            }
+            None if rest.len() >= 254 => {
+                port.write_all(&[0xFF])?;
+                port.write_all(&rest[..254])?;
+                rest = &rest[254..];
+            }
+            None => {
+                port.write_all(&[rest.len() as u8 + 1])?;
+                port.write_all(rest)?;
+                break;
+            }
+        }
+    }
+    port.write_all(&[0])
+}
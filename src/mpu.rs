@@ -0,0 +1,298 @@
+//! Memory Protection Unit regions.
+//!
+//! The Cortex-M4 MPU has 8 regions, each a power of two in size from 32
+//! bytes and aligned to its size. Where regions overlap the higher numbered
+//! one applies. Without a region the default memory map applies, so a few
+//! regions can catch common faults without describing all of memory:
+//!
+//! ```ignore
+//! mpu::Config::new()
+//!     .region(mpu::Region::null_trap())?
+//!     .region(mpu::Region::peripherals())?
+//!     .apply();
+//! stackcheck::enable_guard();
+//! ```
+//!
+//! The [`stackcheck`](crate::stackcheck) stack guard uses region 7, which
+//! a [`Config`] with fewer than 8 regions leaves alone.
+//!
+//! Code runs from RAM while the flash is programmed, so RAM must stay
+//! executable.
+
+use cortex_m::peripheral::MPU;
+
+/// Number of MPU regions.
+pub const REGIONS: usize = 8;
+
+/// Smallest region size in bytes.
+pub const MIN_SIZE: u32 = 32;
+
+/// Size of [`Region::null_trap`].
+pub const NULL_TRAP_SIZE: u32 = 256;
+
+const CTRL_ENABLE: u32 = 1 << 0;
+const CTRL_PRIVDEFENA: u32 = 1 << 2;
+const RBAR_VALID: u32 = 1 << 4;
+const RASR_ENABLE: u32 = 1 << 0;
+const RASR_XN: u32 = 1 << 28;
+
+/// MPU error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The size isn't a power of two of at least [`MIN_SIZE`]
+    Size,
+    /// The base address isn't a multiple of the size
+    Alignment,
+    /// A [`Config`] already has [`REGIONS`] regions
+    TooManyRegions,
+}
+
+/// Access allowed to a region, in privileged / unprivileged mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Access {
+    /// Any access faults
+    None,
+    /// Read and write in privileged mode only
+    Privileged,
+    /// Read only
+    ReadOnly,
+    /// Read and write
+    ReadWrite,
+}
+
+/// Memory type of a region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Memory {
+    /// Flash and RAM, accesses can be merged and reordered
+    Normal,
+    /// Peripheral registers, accesses are done in order
+    Device,
+    /// Accesses are done in order and not buffered
+    StronglyOrdered,
+}
+
+/// One MPU region, made with [`Region::new`] or a preset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Region {
+    base: u32,
+    size_log2: u8,
+    access: Access,
+    memory: Memory,
+    execute: bool,
+    disabled_subregions: u8,
+}
+
+impl Region {
+    /// Read-write, executable normal memory of `size` bytes at `base`.
+    pub const fn new(base: u32, size: u32) -> Result<Self, Error> {
+        if !size.is_power_of_two() || size < MIN_SIZE {
+            return Err(Error::Size);
+        }
+        if base % size != 0 {
+            return Err(Error::Alignment);
+        }
+        Ok(Self {
+            base,
+            size_log2: size.trailing_zeros() as u8,
+            access: Access::ReadWrite,
+            memory: Memory::Normal,
+            execute: true,
+            disabled_subregions: 0,
+        })
+    }
+
+    /// Trap accesses through null pointers: the first [`NULL_TRAP_SIZE`]
+    /// bytes, inside the Arduino bootloader, can't be accessed.
+    pub const fn null_trap() -> Self {
+        Self {
+            base: 0,
+            size_log2: NULL_TRAP_SIZE.trailing_zeros() as u8,
+            access: Access::None,
+            memory: Memory::StronglyOrdered,
+            execute: false,
+            disabled_subregions: 0,
+        }
+    }
+
+    /// The peripheral area, 0x4000_0000 - 0x5FFF_FFFF, read-write device
+    /// memory that can't be executed. Includes the data flash.
+    pub const fn peripherals() -> Self {
+        Self {
+            base: 0x4000_0000,
+            size_log2: 29,
+            access: Access::ReadWrite,
+            memory: Memory::Device,
+            execute: false,
+            disabled_subregions: 0,
+        }
+    }
+
+    /// Set the access allowed.
+    pub const fn access(mut self, access: Access) -> Self {
+        self.access = access;
+        self
+    }
+
+    /// Set the memory type.
+    pub const fn memory(mut self, memory: Memory) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    /// Fault on instruction fetches from the region.
+    pub const fn no_execute(mut self) -> Self {
+        self.execute = false;
+        self
+    }
+
+    /// Leave out eighths of a region of 256 bytes or more, bit n for the
+    /// nth eighth from the base, so a lower region or the default map
+    /// applies there.
+    pub const fn disable_subregions(mut self, mask: u8) -> Self {
+        self.disabled_subregions = mask;
+        self
+    }
+
+    /// Base address.
+    pub const fn base(&self) -> u32 {
+        self.base
+    }
+
+    /// Size in bytes.
+    pub const fn size(&self) -> u32 {
+        1 << self.size_log2
+    }
+
+    // Region Attribute and Size Register value
+    fn rasr(&self) -> u32 {
+        let ap = match self.access {
+            Access::None => 0b000,
+            Access::Privileged => 0b001,
+            Access::ReadWrite => 0b011,
+            Access::ReadOnly => 0b110,
+        };
+        // TEX, S, C, B
+        let attributes = match self.memory {
+            Memory::Normal => 0b001_0_0_0,
+            Memory::Device => 0b000_1_0_1,
+            Memory::StronglyOrdered => 0b000_0_0_0,
+        };
+        let subregions = if self.size_log2 >= 8 {
+            self.disabled_subregions as u32
+        } else {
+            0
+        };
+        (if self.execute { 0 } else { RASR_XN })
+            | (ap << 24)
+            | (attributes << 16)
+            | (subregions << 8)
+            | ((self.size_log2 as u32 - 1) << 1)
+            | RASR_ENABLE
+    }
+}
+
+/// Regions applied together, numbered from 0 in the order they're added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    regions: [Option<Region>; REGIONS],
+    len: usize,
+    background: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Config {
+    /// No regions, with the default memory map for everything else.
+    pub const fn new() -> Self {
+        Self {
+            regions: [None; REGIONS],
+            len: 0,
+            background: true,
+        }
+    }
+
+    /// Add the next region, it takes priority over the ones before.
+    pub const fn region(mut self, region: Region) -> Result<Self, Error> {
+        if self.len == REGIONS {
+            return Err(Error::TooManyRegions);
+        }
+        self.regions[self.len] = Some(region);
+        self.len += 1;
+        Ok(self)
+    }
+
+    /// Whether privileged code can access memory outside every region
+    /// through the default memory map, the default. Without it only the
+    /// regions can be accessed.
+    pub const fn background(mut self, background: bool) -> Self {
+        self.background = background;
+        self
+    }
+
+    /// Program the regions and enable the MPU.
+    pub fn apply(&self) {
+        cortex_m::interrupt::free(|_| {
+            for (number, region) in self.regions[..self.len].iter().enumerate() {
+                set_region(number, region.as_ref());
+            }
+            enable(self.background);
+        });
+    }
+}
+
+/// Program region `number`, or disable it with `None`. Takes effect
+/// straight away if the MPU is enabled.
+pub fn set_region(number: usize, region: Option<&Region>) {
+    let mpu = unsafe { &*MPU::PTR };
+    cortex_m::interrupt::free(|_| unsafe {
+        match region {
+            Some(region) => {
+                mpu.rbar
+                    .write(region.base | RBAR_VALID | (number as u32 & 0xF));
+                mpu.rasr.write(region.rasr());
+            }
+            None => {
+                mpu.rnr.write(number as u32);
+                mpu.rasr.write(0);
+            }
+        }
+        cortex_m::asm::dsb();
+        cortex_m::asm::isb();
+    });
+}
+
+/// Enable the MPU, with the default memory map as background for
+/// privileged code if `background` is set.
+///
+/// The MPU stays off for the HardFault and NMI handlers, so they can run on
+/// an overflowed stack.
+pub fn enable(background: bool) {
+    let ctrl = CTRL_ENABLE | if background { CTRL_PRIVDEFENA } else { 0 };
+    unsafe {
+        (*MPU::PTR).ctrl.write(ctrl);
+    }
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
+
+/// Whether the MPU is enabled.
+pub fn is_enabled() -> bool {
+    unsafe { (*MPU::PTR).ctrl.read() & CTRL_ENABLE != 0 }
+}
+
+/// Disable the MPU, the default memory map applies everywhere.
+pub fn disable() {
+    unsafe {
+        (*MPU::PTR).ctrl.write(0);
+    }
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
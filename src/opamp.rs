@@ -0,0 +1,259 @@
+//! Operational amplifiers (OPAMP)
+//!
+//! Each op-amp has fixed AMPn+, AMPn- and AMPnO pins. The output pin is
+//! also an ADC input, so an amplified signal can be read through
+//! [`Opamp::output_channel`] without wiring it anywhere else. Op-amp 3 has
+//! no pins on the 64-pin package of the UNO R4 and is left out.
+//!
+//! There are no feedback resistors or switches inside the op-amps, the
+//! gain comes from parts on the pins:
+//!
+//! - [`Feedback::Follower`]: AMPnO wired to AMPn-, gain 1, to buffer a high
+//!   impedance sensor.
+//! - [`Feedback::NonInverting`]: a resistor from AMPnO to AMPn- and one from
+//!   AMPn- to ground, e.g. to amplify the drop across a current sense
+//!   resistor.
+//!
+//! The feedback is recorded so results read from the output can be scaled
+//! back to the input with [`Opamp::input_value`].
+//!
+//! ```ignore
+//! let (mut amp, _, _) = opamp::split(p.OPAMP, Default::default());
+//! // A1 in, A2 to ground through 1k and to A3 through 10k
+//! amp.enable(pins.p000, pins.p001, pins.p002, opamp::Feedback::NonInverting {
+//!     feedback_ohms: 10_000,
+//!     ground_ohms: 1_000,
+//! });
+//! let value = amp.input_value(adc.read(amp.output_channel()));
+//! ```
+
+use core::marker::PhantomData;
+
+use crate::adc;
+use crate::clk::{ClockGuard, Peripheral};
+use crate::gpio::{self, Pin};
+
+const OPAMP: usize = 0x4008_6000;
+const AMPMC: *mut u8 = (OPAMP + 0x08) as *mut u8;
+const AMPTRM: *mut u8 = (OPAMP + 0x09) as *mut u8;
+const AMPC: *mut u8 = (OPAMP + 0x0B) as *mut u8;
+const AMPMON: *const u8 = (OPAMP + 0x0C) as *const u8;
+
+// AMPMC.AMPSP, high-speed mode
+const AMPMC_AMPSP: u8 = 1 << 7;
+// AMPC.AMPE0, op-amp 0 enable, the others follow
+const AMPC_AMPE0: u8 = 1 << 0;
+// AMPC.IREFE, reference current circuit enable
+const AMPC_IREFE: u8 = 1 << 7;
+
+/// Op-amp speed, shared by all op-amps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Speed {
+    /// Lowest current, 650 us to start and 750 us to settle
+    LowPower,
+    /// 13 us to start and settle
+    High,
+}
+
+impl Speed {
+    // Stabilization wait after enabling
+    fn start_us(self) -> u32 {
+        match self {
+            Speed::LowPower => 650,
+            Speed::High => 13,
+        }
+    }
+}
+
+/// Op-amp configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub speed: Speed,
+    /// Frequency of ICLK in Hz, to wait for an op-amp to start
+    pub iclk_hz: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            speed: Speed::High,
+            iclk_hz: 48_000_000,
+        }
+    }
+}
+
+/// External feedback network of an op-amp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feedback {
+    /// AMPnO wired to AMPn-, gain 1
+    Follower,
+    /// `feedback_ohms` from AMPnO to AMPn- and `ground_ohms` from AMPn- to
+    /// ground, gain 1 + feedback / ground
+    NonInverting {
+        feedback_ohms: u32,
+        ground_ohms: u32,
+    },
+}
+
+impl Feedback {
+    /// Gain in thousandths.
+    pub fn gain_milli(self) -> u32 {
+        match self {
+            Feedback::Follower => 1000,
+            Feedback::NonInverting {
+                feedback_ohms,
+                ground_ohms,
+            } => {
+                let ratio = feedback_ohms as u64 * 1000 / ground_ohms.max(1) as u64;
+                (1000 + ratio).min(u32::MAX as u64) as u32
+            }
+        }
+    }
+}
+
+/// An op-amp, [`Amp0`], [`Amp1`] or [`Amp2`].
+pub trait Unit {
+    // Unit number, 0-2
+    fn index() -> u8;
+    /// ADC channel of the AMPnO pin.
+    fn output_channel() -> u8;
+}
+
+/// Op-amp 0, AMP0+ on A1, AMP0- on A2 and AMP0O on A3 of the UNO R4
+pub struct Amp0;
+/// Op-amp 1
+pub struct Amp1;
+/// Op-amp 2
+pub struct Amp2;
+
+macro_rules! impl_unit {
+    ($($unit:ident: $index:literal, $channel:literal;)*) => {
+        $(
+            impl Unit for $unit {
+                fn index() -> u8 {
+                    $index
+                }
+
+                fn output_channel() -> u8 {
+                    $channel
+                }
+            }
+        )*
+    };
+}
+
+impl_unit! {
+    Amp0: 0, 2;
+    Amp1: 1, 3;
+    Amp2: 2, 4;
+}
+
+/// Pin that can be used as AMPn+ of op-amp `U`.
+pub trait PlusPin<U: Unit>: Pin {}
+
+/// Pin that can be used as AMPn- of op-amp `U`.
+pub trait MinusPin<U: Unit>: Pin {}
+
+/// Pin that can be used as AMPnO of op-amp `U`.
+pub trait OutputPin<U: Unit>: Pin {}
+
+impl PlusPin<Amp0> for gpio::P000 {}
+impl MinusPin<Amp0> for gpio::P001 {}
+impl OutputPin<Amp0> for gpio::P002 {}
+impl PlusPin<Amp1> for gpio::P013 {}
+impl MinusPin<Amp1> for gpio::P012 {}
+impl OutputPin<Amp1> for gpio::P003 {}
+impl PlusPin<Amp2> for gpio::P011 {}
+impl MinusPin<Amp2> for gpio::P010 {}
+impl OutputPin<Amp2> for gpio::P004 {}
+
+/// One of the op-amps.
+pub struct Opamp<U: Unit> {
+    config: Config,
+    feedback: Feedback,
+    _clock: ClockGuard,
+    _phantom: PhantomData<U>,
+}
+
+/// Take the op-amps, stopped.
+///
+/// The speed can only be changed while all are stopped, so is set once here.
+pub fn split(_opamp: ra4m1::OPAMP, config: Config) -> (Opamp<Amp0>, Opamp<Amp1>, Opamp<Amp2>) {
+    let clock = ClockGuard::new(Peripheral::Opamp);
+    unsafe {
+        AMPC.write_volatile(0);
+        // Started and stopped from software only
+        AMPTRM.write_volatile(0);
+        AMPMC.write_volatile(if config.speed == Speed::High {
+            AMPMC_AMPSP
+        } else {
+            0
+        });
+    }
+    (
+        Opamp::new(config, clock.clone()),
+        Opamp::new(config, clock.clone()),
+        Opamp::new(config, clock),
+    )
+}
+
+impl<U: Unit> Opamp<U> {
+    fn new(config: Config, clock: ClockGuard) -> Self {
+        Self {
+            config,
+            feedback: Feedback::Follower,
+            _clock: clock,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Route the pins and start the op-amp, waiting for it to stabilize.
+    ///
+    /// `feedback` describes the parts fitted between the pins.
+    pub fn enable<PP, PM, PO>(&mut self, _plus: PP, _minus: PM, _output: PO, feedback: Feedback)
+    where
+        PP: PlusPin<U>,
+        PM: MinusPin<U>,
+        PO: OutputPin<U>,
+    {
+        gpio::set_analog::<PP>();
+        gpio::set_analog::<PM>();
+        gpio::set_analog::<PO>();
+        self.feedback = feedback;
+        // The reference current circuit starts with the first op-amp
+        unsafe {
+            AMPC.write_volatile(AMPC.read_volatile() | AMPC_IREFE | (AMPC_AMPE0 << U::index()));
+        }
+        let us = self.config.speed.start_us();
+        cortex_m::asm::delay(self.config.iclk_hz / 1_000_000 * us);
+    }
+
+    /// Stop the op-amp, and the reference current once all are stopped.
+    pub fn disable(&mut self) {
+        unsafe {
+            let ampc = AMPC.read_volatile() & !(AMPC_AMPE0 << U::index());
+            let running = ampc & !AMPC_IREFE != 0;
+            AMPC.write_volatile(if running { ampc } else { 0 });
+        }
+    }
+
+    /// Whether the op-amp is running.
+    pub fn is_running(&self) -> bool {
+        unsafe { AMPMON.read_volatile() & (1 << U::index()) != 0 }
+    }
+
+    /// ADC channel of the output pin.
+    pub fn output_channel(&self) -> adc::Channel {
+        adc::Channel::new(U::output_channel())
+    }
+
+    /// Gain of the feedback given to [`enable`](Self::enable), in thousandths.
+    pub fn gain_milli(&self) -> u32 {
+        self.feedback.gain_milli()
+    }
+
+    /// Scale a result read from the output back to the input.
+    pub fn input_value(&self, output: u16) -> u16 {
+        (output as u32 * 1000 / self.gain_milli()) as u16
+    }
+}
@@ -0,0 +1,250 @@
+//! RC receiver protocols, SBUS and IBUS, decoded from a UART receiver.
+//!
+//! SBUS is 100 kbaud 8E2 and sent inverted. The SCI inverts the data bits
+//! ([`Config::invert`]), flipping the parity sense to match, but not the
+//! start and stop bits, so the line itself still needs a one transistor
+//! inverter or a receiver with an uninverted SBUS output. IBUS is 115200 8N1
+//! and not inverted.
+//!
+//! ```ignore
+//! let (_, rx) = uart.split();
+//! let mut rc = rc::Receiver::new(rx, rc::Protocol::Sbus);
+//! for frame in &mut rc {
+//!     if !frame.failsafe {
+//!         steer(frame.channels[0]);
+//!     }
+//! }
+//! ```
+
+use embedded_io::{Read, ReadReady};
+
+use crate::uart::{Instance, Parity, StopBits, Uart, UartRx};
+
+/// Largest number of proportional channels in a frame.
+pub const MAX_CHANNELS: usize = 16;
+
+/// Receiver protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Futaba SBUS, 16 11-bit channels and 2 digital channels
+    Sbus,
+    /// FlySky IBUS, 14 channels from 1000 to 2000
+    Ibus,
+}
+
+/// UART configuration for a protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Protocol to receive
+    pub protocol: Protocol,
+    /// Invert the data bits, see the module docs
+    pub invert: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            protocol: Protocol::Sbus,
+            invert: false,
+        }
+    }
+}
+
+/// Set the baud rate and frame format of a UART for a protocol.
+pub fn configure<T: Instance, const TX: usize, const RX: usize>(
+    uart: &mut Uart<T, TX, RX>,
+    config: Config,
+) {
+    match config.protocol {
+        Protocol::Sbus => {
+            uart.set_baud_rate(100_000);
+            uart.set_format(Parity::Even, StopBits::Two);
+        }
+        Protocol::Ibus => {
+            uart.set_baud_rate(115_200);
+            uart.set_format(Parity::None, StopBits::One);
+        }
+    }
+    uart.set_data_inversion(config.invert);
+}
+
+/// Decoded channel values of one frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Channels {
+    /// Raw channel values, 0 - 2047 for SBUS and 1000 - 2000 for IBUS
+    pub channels: [u16; MAX_CHANNELS],
+    /// Number of valid entries in `channels`
+    pub count: u8,
+    /// SBUS digital channels 17 and 18
+    pub digital: [bool; 2],
+    /// The receiver missed a frame from the transmitter
+    pub frame_lost: bool,
+    /// The receiver has lost the transmitter and is sending failsafe values
+    pub failsafe: bool,
+}
+
+impl Channels {
+    /// Valid channel values.
+    pub fn as_slice(&self) -> &[u16] {
+        &self.channels[..self.count as usize]
+    }
+}
+
+const SBUS_LEN: usize = 25;
+const SBUS_HEADER: u8 = 0x0F;
+const IBUS_LEN: usize = 32;
+const IBUS_HEADER: [u8; 2] = [0x20, 0x40];
+
+/// Reassembles frames from a byte stream.
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    protocol: Protocol,
+    buf: [u8; IBUS_LEN],
+    len: usize,
+}
+
+impl Decoder {
+    pub const fn new(protocol: Protocol) -> Self {
+        Self {
+            protocol,
+            buf: [0; IBUS_LEN],
+            len: 0,
+        }
+    }
+
+    /// Add a received byte, returns the channels when it completes a valid frame.
+    pub fn push(&mut self, byte: u8) -> Option<Channels> {
+        // Resynchronise on the header
+        let synced = match (self.protocol, self.len) {
+            (Protocol::Sbus, 0) => byte == SBUS_HEADER,
+            (Protocol::Ibus, 0) => byte == IBUS_HEADER[0],
+            (Protocol::Ibus, 1) => byte == IBUS_HEADER[1],
+            _ => true,
+        };
+        if !synced {
+            self.len = 0;
+            return None;
+        }
+        self.buf[self.len] = byte;
+        self.len += 1;
+
+        let frame_len = match self.protocol {
+            Protocol::Sbus => SBUS_LEN,
+            Protocol::Ibus => IBUS_LEN,
+        };
+        if self.len < frame_len {
+            return None;
+        }
+        self.len = 0;
+        match self.protocol {
+            Protocol::Sbus => decode_sbus(&self.buf[..SBUS_LEN]),
+            Protocol::Ibus => decode_ibus(&self.buf),
+        }
+    }
+
+    /// Drop a partly received frame.
+    pub fn reset(&mut self) {
+        self.len = 0;
+    }
+}
+
+fn decode_sbus(frame: &[u8]) -> Option<Channels> {
+    // 0x00 for SBUS, SBUS2 uses 0x04, 0x14, 0x24 and 0x34
+    let footer = frame[24];
+    if footer != 0x00 && footer & 0x0F != 0x04 {
+        return None;
+    }
+    let mut channels = [0u16; MAX_CHANNELS];
+    // 16 channels of 11 bits, packed LSB first in bytes 1 - 22
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    let mut channel = 0;
+    for byte in &frame[1..23] {
+        acc |= (*byte as u32) << bits;
+        bits += 8;
+        if bits >= 11 {
+            channels[channel] = (acc & 0x7FF) as u16;
+            acc >>= 11;
+            bits -= 11;
+            channel += 1;
+        }
+    }
+    let flags = frame[23];
+    Some(Channels {
+        channels,
+        count: MAX_CHANNELS as u8,
+        digital: [flags & 0x01 != 0, flags & 0x02 != 0],
+        frame_lost: flags & 0x04 != 0,
+        failsafe: flags & 0x08 != 0,
+    })
+}
+
+fn decode_ibus(frame: &[u8; IBUS_LEN]) -> Option<Channels> {
+    // Checksum is 0xFFFF minus the sum of all other bytes
+    let sum = frame[..30]
+        .iter()
+        .fold(0xFFFFu16, |sum, byte| sum.wrapping_sub(*byte as u16));
+    if sum != u16::from_le_bytes([frame[30], frame[31]]) {
+        return None;
+    }
+    let mut channels = [0u16; MAX_CHANNELS];
+    for (channel, bytes) in channels.iter_mut().zip(frame[2..30].chunks_exact(2)) {
+        // Upper nibble carries extra data on some receivers
+        *channel = u16::from_le_bytes([bytes[0], bytes[1]]) & 0x0FFF;
+    }
+    Some(Channels {
+        channels,
+        count: 14,
+        digital: [false; 2],
+        frame_lost: false,
+        // Receivers stop sending when the transmitter is lost
+        failsafe: false,
+    })
+}
+
+/// Frames decoded from a UART receiver.
+///
+/// Iterating yields the frames already received and ends when the receive
+/// buffer is empty, so it can be polled from a loop without blocking.
+pub struct Receiver<T: Instance> {
+    rx: UartRx<T>,
+    decoder: Decoder,
+}
+
+impl<T: Instance> Receiver<T> {
+    /// Decode frames from `rx`, which must already be configured with [`configure`].
+    pub fn new(rx: UartRx<T>, protocol: Protocol) -> Self {
+        Self {
+            rx,
+            decoder: Decoder::new(protocol),
+        }
+    }
+
+    /// Most recent complete frame in the receive buffer, if any.
+    pub fn latest(&mut self) -> Option<Channels> {
+        self.by_ref().last()
+    }
+
+    /// Release the UART receiver.
+    pub fn free(self) -> UartRx<T> {
+        self.rx
+    }
+}
+
+impl<T: Instance> Iterator for Receiver<T> {
+    type Item = Channels;
+
+    fn next(&mut self) -> Option<Channels> {
+        let mut byte = [0u8; 1];
+        while self.rx.read_ready().unwrap_or(false) {
+            if self.rx.read(&mut byte).unwrap_or(0) == 0 {
+                break;
+            }
+            if let Some(channels) = self.decoder.push(byte[0]) {
+                return Some(channels);
+            }
+        }
+        None
+    }
+}
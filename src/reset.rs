@@ -0,0 +1,108 @@
+//! Reset cause and software reset.
+//!
+//! The reset detect flags in RSTSR0 / RSTSR1 keep their value across most
+//! resets, so they should be read early in `main` and then cleared with
+//! [`clear_reset_flags`], otherwise the next reset also reports the old cause.
+//!
+//! ```ignore
+//! let reason = reset::reset_reason();
+//! reset::clear_reset_flags();
+//! if reason == reset::ResetReason::IndependentWatchdog {
+//!     enter_safe_mode();
+//! }
+//! ```
+
+/// Cause of the last reset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ResetReason {
+    /// Power was applied
+    PowerOn,
+    /// The RES pin, e.g. the reset button, or a debugger
+    Pin,
+    /// Independent watchdog timer (IWDT)
+    IndependentWatchdog,
+    /// Watchdog timer (WDT)
+    Watchdog,
+    /// [`software_reset`] or SYSRESETREQ
+    Software,
+    /// Voltage monitor 0, 1 or 2 saw VCC below its threshold
+    VoltageMonitor(u8),
+    /// SRAM parity error
+    SramParity,
+    /// SRAM ECC error
+    SramEcc,
+    /// Bus slave MPU error
+    BusSlaveMpu,
+    /// Bus master MPU error
+    BusMasterMpu,
+    /// Stack pointer monitor error
+    StackPointer,
+}
+
+// RSTSR0 bits
+const PORF: u8 = 1 << 0;
+const LVD0RF: u8 = 1 << 1;
+const LVD1RF: u8 = 1 << 2;
+const LVD2RF: u8 = 1 << 3;
+
+// RSTSR1 bits
+const IWDTRF: u16 = 1 << 0;
+const WDTRF: u16 = 1 << 1;
+const SWRF: u16 = 1 << 2;
+const RPERF: u16 = 1 << 8;
+const REERF: u16 = 1 << 9;
+const BUSSRF: u16 = 1 << 10;
+const BUSMRF: u16 = 1 << 11;
+const SPERF: u16 = 1 << 12;
+
+// RSTSR2.CWSF, 0 after a power-on reset, set by software
+const CWSF: u8 = 1 << 0;
+
+/// Decode the cause of the last reset from the reset detect flags.
+///
+/// When more than one flag is set, e.g. they weren't cleared after the
+/// previous reset, the most specific cause is reported.
+pub fn reset_reason() -> ResetReason {
+    let p = unsafe { ra4m1::Peripherals::steal() };
+    let rstsr0 = p.SYSTEM.rstsr0.read().bits();
+    let rstsr1 = p.SYSTEM.rstsr1.read().bits();
+    let rstsr2 = p.SYSTEM.rstsr2.read().bits();
+
+    let causes = [
+        (rstsr1 & IWDTRF != 0, ResetReason::IndependentWatchdog),
+        (rstsr1 & WDTRF != 0, ResetReason::Watchdog),
+        (rstsr1 & SWRF != 0, ResetReason::Software),
+        (rstsr1 & SPERF != 0, ResetReason::StackPointer),
+        (rstsr1 & BUSMRF != 0, ResetReason::BusMasterMpu),
+        (rstsr1 & BUSSRF != 0, ResetReason::BusSlaveMpu),
+        (rstsr1 & REERF != 0, ResetReason::SramEcc),
+        (rstsr1 & RPERF != 0, ResetReason::SramParity),
+        (rstsr0 & LVD0RF != 0, ResetReason::VoltageMonitor(0)),
+        (rstsr0 & LVD1RF != 0, ResetReason::VoltageMonitor(1)),
+        (rstsr0 & LVD2RF != 0, ResetReason::VoltageMonitor(2)),
+        (rstsr0 & PORF != 0, ResetReason::PowerOn),
+    ];
+    match causes.iter().find(|(set, _)| *set) {
+        Some((_, reason)) => *reason,
+        // A pin reset clears every flag but not CWSF
+        None if rstsr2 & CWSF != 0 => ResetReason::Pin,
+        None => ResetReason::PowerOn,
+    }
+}
+
+/// Clear the reset detect flags and mark the next start as a warm start.
+pub fn clear_reset_flags() {
+    let p = unsafe { ra4m1::Peripherals::steal() };
+    // Flags are cleared by writing 0 after reading 1
+    let _ = p.SYSTEM.rstsr0.read().bits();
+    let _ = p.SYSTEM.rstsr1.read().bits();
+    p.SYSTEM.rstsr0.write(|w| unsafe { w.bits(0) });
+    p.SYSTEM.rstsr1.write(|w| unsafe { w.bits(0) });
+    p.SYSTEM.rstsr2.write(|w| unsafe { w.bits(CWSF) });
+}
+
+/// Reset the MCU, reported as [`ResetReason::Software`] after the restart.
+pub fn software_reset() -> ! {
+    cortex_m::peripheral::SCB::sys_reset()
+}
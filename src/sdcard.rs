@@ -0,0 +1,393 @@
+//! SD cards in SPI mode, as a block device for `embedded-sdmmc`.
+//!
+//! [`SdCard::new`] runs the SPI mode initialisation at 400 kHz, as cards
+//! require until they leave the idle state, then switches the SPI to the
+//! rate given. SDSC, SDHC and SDXC cards are supported, blocks are always
+//! 512 bytes. Blocks are moved one command at a time with the blocking
+//! [`SpiMaster`], so interrupts stay enabled throughout.
+//!
+//! ```ignore
+//! let spi = SpiMaster::new(p.SPI1, pins.p111, pins.p109, pins.p110, Default::default());
+//! let card = SdCard::new(spi, pins.p112, 12_000_000)?;
+//! let mut volumes = VolumeManager::new(card, FixedTime::default());
+//! let volume = volumes.open_volume(VolumeIdx(0))?;
+//! let root = volume.open_root_dir()?;
+//! let file = root.open_file_in_dir("LOG.CSV", Mode::ReadWriteCreateOrAppend)?;
+//! file.write(b"time,rpm\n")?;
+//! ```
+
+use core::cell::RefCell;
+
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx, TimeSource, Timestamp};
+
+use crate::cycles;
+use crate::gpio::{self, Pin};
+use crate::spi::master::SpiMaster;
+use crate::spi::{self, Instance};
+
+/// SPI rate during initialisation.
+pub const INIT_FREQUENCY: u32 = 400_000;
+
+/// Time allowed for the card to leave the idle state.
+pub const INIT_TIMEOUT_MS: u32 = 1_000;
+
+/// Time allowed for a block to be read or written.
+pub const BLOCK_TIMEOUT_MS: u32 = 500;
+
+const BLOCK_SIZE: usize = 512;
+
+// Commands, ACMD41 follows CMD55
+const CMD0: u8 = 0;
+const CMD8: u8 = 8;
+const CMD9: u8 = 9;
+const CMD16: u8 = 16;
+const CMD17: u8 = 17;
+const CMD24: u8 = 24;
+const CMD55: u8 = 55;
+const CMD58: u8 = 58;
+const ACMD41: u8 = 41;
+
+// R1 bits
+const R1_IDLE: u8 = 1 << 0;
+const R1_ILLEGAL_COMMAND: u8 = 1 << 2;
+// CMD8 argument, 2.7 - 3.6 V and a check pattern echoed back
+const CMD8_ARG: u32 = 0x1AA;
+// ACMD41 HCS, the host supports high capacity cards
+const ACMD41_HCS: u32 = 1 << 30;
+// OCR CCS, the card is high capacity and addressed in blocks
+const OCR_CCS: u32 = 1 << 30;
+// Start of a data block, and the data response for an accepted write
+const DATA_TOKEN: u8 = 0xFE;
+const DATA_ACCEPTED: u8 = 0b0_0101;
+
+/// SD card error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The SPI transfer failed
+    Spi(spi::Error),
+    /// The card didn't answer or finish in time
+    Timeout,
+    /// The card didn't accept the voltage range or isn't an SD card
+    Unsupported,
+    /// A command was rejected, with the R1 response
+    Command(u8),
+    /// A read ended with an error token
+    Read(u8),
+    /// A write wasn't accepted, with the data response
+    Write(u8),
+}
+
+impl From<spi::Error> for Error {
+    fn from(e: spi::Error) -> Self {
+        Error::Spi(e)
+    }
+}
+
+/// How the card is addressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CardType {
+    /// Version 1 standard capacity, addressed in bytes
+    Sd1,
+    /// Version 2 standard capacity, addressed in bytes
+    Sd2,
+    /// High or extended capacity, addressed in blocks
+    Sdhc,
+}
+
+struct Inner<T: Instance, CS: Pin> {
+    spi: SpiMaster<T>,
+    cs: CS,
+    card_type: CardType,
+}
+
+/// An SD card on an SPI master, slave select on pin `CS`.
+pub struct SdCard<T: Instance, CS: Pin> {
+    inner: RefCell<Inner<T, CS>>,
+}
+
+impl<T: Instance, CS: Pin> SdCard<T, CS> {
+    /// Initialise the card, then run the SPI at `frequency`, up to 25 MHz.
+    pub fn new(spi: SpiMaster<T>, cs: CS, frequency: u32) -> Result<Self, Error> {
+        gpio::set_output::<CS>(true);
+        let mut inner = Inner {
+            spi,
+            cs,
+            card_type: CardType::Sd1,
+        };
+        inner.spi.set_frequency(INIT_FREQUENCY);
+        let result = inner.init();
+        inner.deselect()?;
+        result?;
+        inner.spi.set_frequency(frequency.min(25_000_000));
+        Ok(Self {
+            inner: RefCell::new(inner),
+        })
+    }
+
+    /// How the card is addressed, found during initialisation.
+    pub fn card_type(&self) -> CardType {
+        self.inner.borrow().card_type
+    }
+
+    /// Release the SPI master and slave select pin.
+    pub fn free(self) -> (SpiMaster<T>, CS) {
+        let inner = self.inner.into_inner();
+        (inner.spi, inner.cs)
+    }
+}
+
+impl<T: Instance, CS: Pin> BlockDevice for SdCard<T, CS> {
+    type Error = Error;
+
+    fn read(&self, blocks: &mut [Block], start_block_idx: BlockIdx) -> Result<(), Error> {
+        let mut inner = self.inner.borrow_mut();
+        for (n, block) in blocks.iter_mut().enumerate() {
+            let address = inner.address(start_block_idx.0 + n as u32);
+            let result = inner.read(CMD17, address, &mut block.contents);
+            inner.deselect()?;
+            result?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Error> {
+        let mut inner = self.inner.borrow_mut();
+        for (n, block) in blocks.iter().enumerate() {
+            let address = inner.address(start_block_idx.0 + n as u32);
+            let result = inner.write_block(address, &block.contents);
+            inner.deselect()?;
+            result?;
+        }
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, Error> {
+        let mut inner = self.inner.borrow_mut();
+        let mut csd = [0; 16];
+        let result = inner.read(CMD9, 0, &mut csd);
+        inner.deselect()?;
+        result?;
+        Ok(BlockCount(csd_blocks(&csd)))
+    }
+}
+
+impl<T: Instance, CS: Pin> Inner<T, CS> {
+    fn init(&mut self) -> Result<(), Error> {
+        // 80 clocks with slave select high to start the card
+        self.spi.write(&[0xFF; 10])?;
+
+        // CMD0 with slave select low enters SPI mode
+        let mut idle = false;
+        for _ in 0..10 {
+            match self.command(CMD0, 0) {
+                Ok(R1_IDLE) => {
+                    idle = true;
+                    break;
+                }
+                Ok(_) | Err(Error::Timeout) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !idle {
+            return Err(Error::Timeout);
+        }
+
+        // CMD8 is only known to version 2 cards
+        let r1 = self.command(CMD8, CMD8_ARG)?;
+        let version2 = r1 & R1_ILLEGAL_COMMAND == 0;
+        if version2 {
+            let mut r7 = [0; 4];
+            self.spi.read(&mut r7)?;
+            if u32::from_be_bytes(r7) & 0xFFF != CMD8_ARG {
+                return Err(Error::Unsupported);
+            }
+        }
+
+        let hcs = if version2 { ACMD41_HCS } else { 0 };
+        let mut waited = 0;
+        loop {
+            self.command(CMD55, 0)?;
+            let r1 = self.command(ACMD41, hcs)?;
+            if r1 == 0 {
+                break;
+            }
+            if r1 & !R1_IDLE != 0 {
+                return Err(Error::Unsupported);
+            }
+            if waited >= INIT_TIMEOUT_MS {
+                return Err(Error::Timeout);
+            }
+            cycles::busy_wait_ms(10);
+            waited += 10;
+        }
+
+        self.card_type = if version2 {
+            let r1 = self.command(CMD58, 0)?;
+            if r1 != 0 {
+                return Err(Error::Command(r1));
+            }
+            let mut ocr = [0; 4];
+            self.spi.read(&mut ocr)?;
+            if u32::from_be_bytes(ocr) & OCR_CCS != 0 {
+                CardType::Sdhc
+            } else {
+                CardType::Sd2
+            }
+        } else {
+            CardType::Sd1
+        };
+        if self.card_type != CardType::Sdhc {
+            let r1 = self.command(CMD16, BLOCK_SIZE as u32)?;
+            if r1 != 0 {
+                return Err(Error::Command(r1));
+            }
+        }
+        Ok(())
+    }
+
+    // Address argument of a block, standard capacity cards take bytes
+    fn address(&self, block: u32) -> u32 {
+        match self.card_type {
+            CardType::Sdhc => block,
+            _ => block * BLOCK_SIZE as u32,
+        }
+    }
+
+    // Send a command answered with a data block, a block or a register
+    fn read(&mut self, command: u8, argument: u32, data: &mut [u8]) -> Result<(), Error> {
+        let r1 = self.command(command, argument)?;
+        if r1 != 0 {
+            return Err(Error::Command(r1));
+        }
+        self.read_data(data)
+    }
+
+    fn write_block(&mut self, address: u32, block: &[u8; BLOCK_SIZE]) -> Result<(), Error> {
+        let r1 = self.command(CMD24, address)?;
+        if r1 != 0 {
+            return Err(Error::Command(r1));
+        }
+        self.spi.write(&[0xFF, DATA_TOKEN])?;
+        self.spi.write(block)?;
+        // CRC, ignored in SPI mode
+        self.spi.write(&[0xFF, 0xFF])?;
+        let response = self.spi.transfer_byte(0xFF)? & 0x1F;
+        if response != DATA_ACCEPTED {
+            return Err(Error::Write(response));
+        }
+        // The card holds MISO low while programming
+        self.wait(BLOCK_TIMEOUT_MS, |byte| byte == 0xFF)
+    }
+
+    // Wait for the data token, then read `data` and discard the CRC
+    fn read_data(&mut self, data: &mut [u8]) -> Result<(), Error> {
+        let mut token = 0xFF;
+        self.wait(BLOCK_TIMEOUT_MS, |byte| {
+            token = byte;
+            byte != 0xFF
+        })?;
+        if token != DATA_TOKEN {
+            return Err(Error::Read(token));
+        }
+        self.spi.read(data)?;
+        self.spi.read(&mut [0; 2])?;
+        Ok(())
+    }
+
+    // Select the card and send a command, returning its R1 response
+    fn command(&mut self, command: u8, argument: u32) -> Result<u8, Error> {
+        gpio::write::<CS>(false);
+        // A card that's still busy holds MISO low, CMD0 is sent regardless
+        if command != CMD0 {
+            self.wait(BLOCK_TIMEOUT_MS, |byte| byte == 0xFF)?;
+        }
+        let mut frame = [0; 6];
+        frame[0] = 0x40 | command;
+        frame[1..5].copy_from_slice(&argument.to_be_bytes());
+        frame[5] = (crc7(&frame[..5]) << 1) | 1;
+        self.spi.write(&frame)?;
+        // R1 within 8 bytes, its top bit is always 0
+        for _ in 0..8 {
+            let r1 = self.spi.transfer_byte(0xFF)?;
+            if r1 & 0x80 == 0 {
+                return Ok(r1);
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    // Release slave select, with a byte so the card releases MISO
+    fn deselect(&mut self) -> Result<(), Error> {
+        gpio::write::<CS>(true);
+        self.spi.transfer_byte(0xFF)?;
+        Ok(())
+    }
+
+    // Read bytes until `done` or `ms` have passed at the current rate
+    fn wait(&mut self, ms: u32, mut done: impl FnMut(u8) -> bool) -> Result<(), Error> {
+        let bytes = ms * (self.spi.frequency() / 8_000).max(1);
+        for _ in 0..bytes {
+            if done(self.spi.transfer_byte(0xFF)?) {
+                return Ok(());
+            }
+        }
+        Err(Error::Timeout)
+    }
+}
+
+// Number of blocks from the CSD register
+fn csd_blocks(csd: &[u8; 16]) -> u32 {
+    if csd[0] >> 6 == 1 {
+        // CSD version 2, C_SIZE counts 512 KiB
+        let c_size = (((csd[7] & 0x3F) as u32) << 16) | ((csd[8] as u32) << 8) | csd[9] as u32;
+        (c_size + 1) * 1024
+    } else {
+        let read_bl_len = (csd[5] & 0xF) as u32;
+        let c_size =
+            (((csd[6] & 0x3) as u32) << 10) | ((csd[7] as u32) << 2) | (csd[8] >> 6) as u32;
+        let c_size_mult = (((csd[9] & 0x3) as u32) << 1) | (csd[10] >> 7) as u32;
+        (((c_size + 1) << (c_size_mult + 2)) << read_bl_len) >> 9
+    }
+}
+
+// CRC7 of a command, only checked by the card for CMD0 and CMD8 in SPI mode
+fn crc7(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in bytes {
+        for bit in (0..8).rev() {
+            let feedback = ((crc >> 6) ^ (byte >> bit)) & 1;
+            crc = (crc << 1) & 0x7F;
+            if feedback != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc
+}
+
+/// A [`TimeSource`] giving the same time for every file, for boards
+/// without a clock.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedTime(pub Timestamp);
+
+impl Default for FixedTime {
+    /// 2024-01-01 00:00:00
+    fn default() -> Self {
+        FixedTime(Timestamp {
+            year_since_1970: 54,
+            zero_indexed_month: 0,
+            zero_indexed_day: 0,
+            hours: 0,
+            minutes: 0,
+            seconds: 0,
+        })
+    }
+}
+
+impl TimeSource for FixedTime {
+    fn get_timestamp(&self) -> Timestamp {
+        self.0
+    }
+}
@@ -0,0 +1,251 @@
+//! Board self test, for checking boards after assembly.
+//!
+//! The CAN test uses the internal loopback mode (self-test mode 1), so it
+//! needs no transceiver or bus and nothing is driven onto CTX0. The UART
+//! test sends a pattern and expects it back, so it needs TXD wired to RXD,
+//! e.g. a jumper between D0 and D1.
+//!
+//! ```ignore
+//! let passed = selftest::run_with_echo(&mut can, &mut uart, &mut report)?;
+//! ```
+//!
+//! The report is written as one line per test:
+//!
+//! ```text
+//! CAN loopback: ok, 8 frames
+//! UART echo: FAIL Timeout
+//! ```
+//!
+//! [`power_on`] is a shorter test for every startup, before the CAN
+//! controller is started: a pattern test of the mailbox RAM and a check of
+//! the ring buffer the drivers use wrapping around. It needs no wiring.
+//!
+//! The tests reconfigure the peripherals. Afterwards the CAN driver is left
+//! in halt mode with test mode disabled, mailboxes must be configured again
+//! before [`Can::start`](crate::can::Can::start). The tests take the driver
+//! before it's started, in [`Config`].
+
+use embassy_hal_internal::atomic_ring_buffer::RingBuffer;
+use embedded_can::Frame as _;
+use embedded_io::{Read, ReadReady, Write, WriteFmtError};
+
+use crate::can::{Can, Config, Frame, MailboxConfig};
+use crate::clk::Clocks;
+use crate::uart::{Instance, Uart};
+
+/// Number of frames sent by [`can_loopback`] from [`run`].
+pub const CAN_FRAMES: u8 = 8;
+
+/// Pattern sent by [`uart_echo`].
+pub const UART_PATTERN: &[u8] = b"\x55\xAA\x00\xFFselftest";
+
+/// Milliseconds to wait for a frame or byte, timed on the configured ICLK.
+pub const TIMEOUT_MS: u32 = 10;
+
+/// Size of the buffer used by [`ring_buffer`].
+pub const RING_SIZE: usize = 8;
+
+/// Bytes passed through the buffer by [`ring_buffer`], wrapping it several times.
+pub const RING_BYTES: usize = 5 * RING_SIZE;
+
+const POLL_CYCLES: u32 = 1_000;
+
+/// Reason a test failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Failure {
+    /// No mailbox was free to send a frame
+    CanSend,
+    /// The CAN module didn't change mode in time
+    CanMode,
+    /// A frame or byte wasn't received in time
+    Timeout,
+    /// A frame was received with a different ID or data, at index `n`
+    CanMismatch(u8),
+    /// A frame had the same timestamp as the one before, at index `n`
+    CanTimestamp(u8),
+    /// A byte was received with a different value, at index `n`
+    UartMismatch(u8),
+    /// Mailbox `n` didn't read back the pattern written
+    CanMailboxRam(u8),
+    /// A byte read from the ring buffer was wrong, at index `n`
+    RingBuffer(u8),
+    /// The ring buffer didn't report full at its size
+    RingBufferFull,
+}
+
+/// Send `frames` frames through the internal loopback and check each is
+/// received with the same ID and data and a new timestamp.
+pub fn can_loopback(can: &mut Can<Config>, frames: u8) -> Result<(), Failure> {
+    // Mailbox 0 receives everything, the rest transmit
+    let mut config = MailboxConfig::default();
+    config.set_mailbox_receiver(0);
+    can.configure_mailboxes(config)
+        .map_err(|_| Failure::CanMode)?;
+    can.internal_self_test();
+
+    let result = can
+        .while_running(|can| {
+            let mut last_ts = None;
+            for n in 0..frames {
+                let sent = Frame::new_standard(0x100 + n as u16, &[n, !n, 0x55, 0xAA])
+                    .ok_or(Failure::CanSend)?;
+                can.send_frame(sent).map_err(|_| Failure::CanSend)?;
+                let received = wait_for(|| can.try_receive_frame()).ok_or(Failure::Timeout)?;
+                if received.id() != sent.id() || received.data() != sent.data() {
+                    return Err(Failure::CanMismatch(n));
+                }
+                // The timer counts bit times, so consecutive frames always differ
+                if last_ts == Some(received.timestamp()) {
+                    return Err(Failure::CanTimestamp(n));
+                }
+                last_ts = Some(received.timestamp());
+            }
+            Ok(())
+        })
+        .unwrap_or(Err(Failure::CanMode));
+
+    can.disable_test_mode();
+    result
+}
+
+/// Send [`UART_PATTERN`] and check it's received back, needs TXD wired to RXD.
+///
+/// Anything already in the receive buffer is discarded first.
+pub fn uart_echo<T: Instance, const TX: usize, const RX: usize>(
+    uart: &mut Uart<T, TX, RX>,
+) -> Result<(), Failure> {
+    let mut byte = [0u8; 1];
+    while uart.read_ready().unwrap_or(false) {
+        let _ = uart.read(&mut byte);
+    }
+    let _ = uart.write_all(UART_PATTERN);
+    let _ = uart.flush();
+
+    for (n, expected) in UART_PATTERN.iter().enumerate() {
+        wait_for(|| uart.read_ready().unwrap_or(false).then_some(())).ok_or(Failure::Timeout)?;
+        let _ = uart.read(&mut byte);
+        if byte[0] != *expected {
+            return Err(Failure::UartMismatch(n as u8));
+        }
+    }
+    Ok(())
+}
+
+/// Pattern test the CAN mailbox RAM, see [`Can::test_mailbox_ram`].
+pub fn mailbox_ram(can: &mut Can<Config>) -> Result<(), Failure> {
+    can.test_mailbox_ram().map_err(Failure::CanMailboxRam)
+}
+
+/// Pass [`RING_BYTES`] through a [`RING_SIZE`] ring buffer in uneven chunks,
+/// checking the order across the wrap, then fill it and check it's full.
+pub fn ring_buffer() -> Result<(), Failure> {
+    let mut storage = [0u8; RING_SIZE];
+    let ring = RingBuffer::new();
+    unsafe { ring.init(storage.as_mut_ptr(), storage.len()) };
+    let mut writer = unsafe { ring.writer() };
+    let mut reader = unsafe { ring.reader() };
+
+    let mut sent = 0;
+    let mut received = 0;
+    while received < RING_BYTES {
+        // 3 in then 2 out at a time, so the ends wrap at different points
+        let before = (sent, received);
+        for _ in 0..3 {
+            if sent < RING_BYTES && writer.push_one(sent as u8) {
+                sent += 1;
+            }
+        }
+        for _ in 0..2 {
+            let (ptr, len) = reader.pop_buf();
+            if len == 0 {
+                break;
+            }
+            let byte = unsafe { *ptr };
+            if byte != received as u8 {
+                return Err(Failure::RingBuffer(received as u8));
+            }
+            reader.pop_done(1);
+            received += 1;
+        }
+        // Neither end moved, the buffer is stuck
+        if (sent, received) == before {
+            return Err(Failure::RingBuffer(received as u8));
+        }
+    }
+
+    let filled = (0..RING_SIZE).all(|i| writer.push_one(i as u8));
+    let result = if filled && !writer.push_one(0) {
+        Ok(())
+    } else {
+        Err(Failure::RingBufferFull)
+    };
+    unsafe { ring.deinit() };
+    result
+}
+
+/// Run the [`mailbox_ram`] and [`ring_buffer`] tests and write the results
+/// to `out`, for every startup before the CAN controller is started.
+///
+/// Returns whether both passed.
+pub fn power_on<W: Write>(
+    can: &mut Can<Config>,
+    out: &mut W,
+) -> Result<bool, WriteFmtError<W::Error>> {
+    let mailboxes = mailbox_ram(can);
+    report(out, "CAN mailbox RAM", mailboxes, 32, "mailboxes")?;
+    let ring = ring_buffer();
+    report(out, "Ring buffer", ring, RING_BYTES, "bytes")?;
+    Ok(mailboxes.is_ok() && ring.is_ok())
+}
+
+/// Run the CAN loopback test and write the result to `out`.
+///
+/// Returns whether the test passed.
+pub fn run<W: Write>(can: &mut Can<Config>, out: &mut W) -> Result<bool, WriteFmtError<W::Error>> {
+    let result = can_loopback(can, CAN_FRAMES);
+    report(out, "CAN loopback", result, CAN_FRAMES as usize, "frames")?;
+    Ok(result.is_ok())
+}
+
+/// Run the CAN loopback and UART echo tests and write the results to `out`.
+///
+/// `out` can't be `uart`, use another UART or e.g. an RTT channel.
+/// Returns whether all tests passed.
+pub fn run_with_echo<T: Instance, W: Write, const TX: usize, const RX: usize>(
+    can: &mut Can<Config>,
+    uart: &mut Uart<T, TX, RX>,
+    out: &mut W,
+) -> Result<bool, WriteFmtError<W::Error>> {
+    let can_ok = run(can, out)?;
+    let result = uart_echo(uart);
+    report(out, "UART echo", result, UART_PATTERN.len(), "bytes")?;
+    Ok(can_ok && result.is_ok())
+}
+
+fn report<W: Write>(
+    out: &mut W,
+    name: &str,
+    result: Result<(), Failure>,
+    count: usize,
+    unit: &str,
+) -> Result<(), WriteFmtError<W::Error>> {
+    match result {
+        Ok(()) => out.write_fmt(format_args!("{}: ok, {} {}\n", name, count, unit)),
+        Err(failure) => out.write_fmt(format_args!("{}: FAIL {:?}\n", name, failure)),
+    }
+}
+
+// Poll `f` until it returns a value or the timeout expires
+fn wait_for<R>(mut f: impl FnMut() -> Option<R>) -> Option<R> {
+    let timeout_cycles = Clocks::read().iclk() / 1000 * TIMEOUT_MS;
+    let mut waited = 0;
+    while waited < timeout_cycles {
+        if let Some(r) = f() {
+            return Some(r);
+        }
+        cortex_m::asm::delay(POLL_CYCLES);
+        waited += POLL_CYCLES;
+    }
+    None
+}
@@ -0,0 +1,120 @@
+//! Hobby servo control on top of GPT PWM.
+//!
+//! Each GPT channel drives up to two servos, one on each of its output pins.
+//! The frame rate is shared by both, the pulse width is set per output.
+//!
+//! ```ignore
+//! let mut servo = servo::Servo::new(p.GPT162, servo::Config::default());
+//! servo.attach_a(pins.p103);
+//! servo.set_angle(Channel::A, 90);
+//! ```
+
+use crate::gpt::pwm::{self, Channel, Pwm};
+use crate::gpt::{Instance, PinA, PinB};
+
+/// Servo timing configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Frequency of PCLKD in Hz
+    pub pclkd_hz: u32,
+    /// Frame rate in Hz, 50 for most analog servos
+    pub frame_hz: u32,
+    /// Pulse width at 0 degrees, in microseconds
+    pub min_us: u16,
+    /// Pulse width at `max_angle`, in microseconds
+    pub max_us: u16,
+    /// Angle reached at `max_us`, in degrees
+    pub max_angle: u16,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            pclkd_hz: 48_000_000,
+            frame_hz: 50,
+            min_us: 1000,
+            max_us: 2000,
+            max_angle: 180,
+        }
+    }
+}
+
+/// Up to two servos on the outputs of a GPT channel.
+pub struct Servo<T: Instance> {
+    pwm: Pwm<T>,
+    config: Config,
+    pulse_us: [u16; 2],
+}
+
+impl<T: Instance> Servo<T> {
+    /// Start the frame timer. No pulses are output until a pin is attached.
+    ///
+    /// Panics if `min_us` is above `max_us`.
+    pub fn new(instance: T, config: Config) -> Self {
+        assert!(
+            config.min_us <= config.max_us,
+            "servo min_us is above max_us"
+        );
+        let pwm = Pwm::new(
+            instance,
+            pwm::Config {
+                pclkd_hz: config.pclkd_hz,
+                frequency_hz: config.frame_hz,
+            },
+        );
+        Self {
+            pwm,
+            config,
+            pulse_us: [0; 2],
+        }
+    }
+
+    /// Drive a servo from the GTIOCnA pin, starting at the centre position.
+    pub fn attach_a<P: PinA<T>>(&mut self, pin: P) {
+        self.center(Channel::A);
+        self.pwm.enable_a(pin);
+    }
+
+    /// Drive a servo from the GTIOCnB pin, starting at the centre position.
+    pub fn attach_b<P: PinB<T>>(&mut self, pin: P) {
+        self.center(Channel::B);
+        self.pwm.enable_b(pin);
+    }
+
+    /// Stop sending pulses on an output, most servos then go limp.
+    pub fn detach(&mut self, channel: Channel) {
+        self.pwm.disable(channel);
+    }
+
+    /// Set the pulse width in microseconds, clamped to `min_us..=max_us`.
+    pub fn set_pulse_us(&mut self, channel: Channel, us: u16) {
+        let us = us.clamp(self.config.min_us, self.config.max_us);
+        self.pulse_us[channel as usize] = us;
+        self.pwm.set_pulse_us(channel, us as u32);
+    }
+
+    /// Set the angle in degrees, clamped to `0..=max_angle`.
+    pub fn set_angle(&mut self, channel: Channel, degrees: u16) {
+        let degrees = degrees.min(self.config.max_angle) as u32;
+        let span = (self.config.max_us - self.config.min_us) as u32;
+        let us = self.config.min_us as u32 + span * degrees / self.config.max_angle.max(1) as u32;
+        self.set_pulse_us(channel, us as u16);
+    }
+
+    /// The last pulse width set on an output, in microseconds.
+    pub fn pulse_us(&self, channel: Channel) -> u16 {
+        self.pulse_us[channel as usize]
+    }
+
+    /// The last angle set on an output, in degrees.
+    pub fn angle(&self, channel: Channel) -> u16 {
+        let span = (self.config.max_us - self.config.min_us).max(1) as u32;
+        let offset = self.pulse_us(channel).saturating_sub(self.config.min_us) as u32;
+        (offset * self.config.max_angle as u32 / span) as u16
+    }
+
+    fn center(&mut self, channel: Channel) {
+        let us = (self.config.min_us + self.config.max_us) / 2;
+        self.set_pulse_us(channel, us);
+    }
+}
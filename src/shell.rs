@@ -0,0 +1,402 @@
+//! Command shell over a serial port, for interactive bring-up.
+//!
+//! Lines typed in a terminal are split into words and run as commands from
+//! a static table, made with the [`commands!`](crate::commands!) macro.
+//! The [`BUILTINS`] are always available, with `help` listing everything:
+//!
+//! ```ignore
+//! fn blink(out: &mut dyn fmt::Write, args: &[&str]) -> Result<(), CommandError> {
+//!     let ms: u32 = args.first().and_then(|a| a.parse().ok()).ok_or(CommandError::Usage)?;
+//!     writeln!(out, "blinking every {} ms", ms)?;
+//!     Ok(())
+//! }
+//!
+//! static COMMANDS: &[Command] = uno_r4_rust::commands![
+//!     "blink <ms>", "blink the LED" => blink,
+//! ];
+//!
+//! let (tx, rx) = uart.split();
+//! let mut shell = Shell::new(rx, tx, COMMANDS);
+//! loop {
+//!     shell.poll()?;
+//! }
+//! ```
+//!
+//! Editing is kept to what a plain terminal needs: backspace, Ctrl-U to
+//! erase the line, Ctrl-C to abandon it and the up arrow to recall the
+//! previous line.
+
+use core::fmt::{self, Write as _};
+
+use embedded_io::{Read, ReadReady, Write};
+
+#[cfg(feature = "can")]
+use crate::can;
+use crate::clk::Clocks;
+use crate::gpio::{self, PinConfig};
+
+/// Longest line, longer input is refused with a bell.
+pub const MAX_LINE: usize = 64;
+
+/// Most words in a line, the command and its arguments.
+pub const MAX_ARGS: usize = 8;
+
+const PROMPT: &str = "> ";
+
+/// Shell error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// Reading or writing the serial port failed
+    Port,
+}
+
+/// Failure of a command, reported by the shell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CommandError {
+    /// Wrong arguments, the usage is printed
+    Usage,
+    /// The command failed, the message is printed
+    Failed(&'static str),
+    /// Writing the output failed
+    Output,
+}
+
+impl From<fmt::Error> for CommandError {
+    fn from(_: fmt::Error) -> Self {
+        CommandError::Output
+    }
+}
+
+/// Function run by a command, with the words after the command name.
+pub type Run = fn(&mut dyn fmt::Write, &[&str]) -> Result<(), CommandError>;
+
+/// Entry in a command table.
+#[derive(Clone, Copy)]
+pub struct Command {
+    /// Name and arguments, e.g. `"poke <pin> <0|1>"`
+    pub usage: &'static str,
+    /// One line description for `help`
+    pub help: &'static str,
+    pub run: Run,
+}
+
+impl Command {
+    /// The first word of the usage.
+    pub fn name(&self) -> &'static str {
+        self.usage.split(' ').next().unwrap_or("")
+    }
+}
+
+/// Make a `&[Command]` table from usage, help and function triples.
+///
+/// ```ignore
+/// static COMMANDS: &[Command] = commands![
+///     "blink <ms>", "blink the LED" => blink,
+///     "stop", "stop blinking" => stop,
+/// ];
+/// ```
+#[macro_export]
+macro_rules! commands {
+    ($($usage:literal, $help:literal => $run:expr),* $(,)?) => {
+        &[$($crate::shell::Command {
+            usage: $usage,
+            help: $help,
+            run: $run,
+        }),*]
+    };
+}
+
+/// Commands available in every shell, after the application's.
+pub static BUILTINS: &[Command] = crate::commands![
+    "clocks", "system clock frequencies" => clocks,
+    "can", "CAN mode, error state and counters" => can_status,
+    "peek <pin>", "level of a pin, e.g. P102" => peek,
+    "poke <pin> <0|1>", "make a pin an output and drive it" => poke,
+    "reset", "reset the MCU" => reset,
+];
+
+// Position in an escape sequence from the terminal
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    Escape,
+    Csi,
+}
+
+/// Shell reading from `R` and echoing and printing to `W`.
+pub struct Shell<R: Read + ReadReady, W: Write> {
+    rx: R,
+    tx: W,
+    commands: &'static [Command],
+    line: [u8; MAX_LINE],
+    len: usize,
+    history: [u8; MAX_LINE],
+    history_len: usize,
+    escape: Escape,
+    // Last byte was a carriage return, so a following line feed is skipped
+    carriage_return: bool,
+    prompted: bool,
+}
+
+impl<R: Read + ReadReady, W: Write> Shell<R, W> {
+    /// Shell with the application's `commands` and the [`BUILTINS`]. The
+    /// prompt is printed by the first [`poll`](Self::poll).
+    pub fn new(rx: R, tx: W, commands: &'static [Command]) -> Self {
+        Self {
+            rx,
+            tx,
+            commands,
+            line: [0; MAX_LINE],
+            len: 0,
+            history: [0; MAX_LINE],
+            history_len: 0,
+            escape: Escape::None,
+            carriage_return: false,
+            prompted: false,
+        }
+    }
+
+    /// Handle the bytes received, running each complete line.
+    pub fn poll(&mut self) -> Result<(), Error> {
+        if !self.prompted {
+            self.print(PROMPT)?;
+            self.prompted = true;
+        }
+        while self.rx.read_ready().map_err(|_| Error::Port)? {
+            let mut byte = [0u8; 1];
+            if self.rx.read(&mut byte).map_err(|_| Error::Port)? == 0 {
+                break;
+            }
+            self.input(byte[0])?;
+        }
+        Ok(())
+    }
+
+    /// Release the serial port halves.
+    pub fn free(self) -> (R, W) {
+        (self.rx, self.tx)
+    }
+
+    fn input(&mut self, byte: u8) -> Result<(), Error> {
+        let carriage_return = self.carriage_return;
+        self.carriage_return = byte == b'\r';
+        match (self.escape, byte) {
+            (Escape::Escape, b'[') => {
+                self.escape = Escape::Csi;
+                return Ok(());
+            }
+            (Escape::Csi, b'A') => {
+                self.escape = Escape::None;
+                return self.recall();
+            }
+            // Parameters of other sequences, e.g. `ESC [ 1 ; 5 C`
+            (Escape::Csi, 0x20..=0x3F) => return Ok(()),
+            (Escape::Escape | Escape::Csi, _) => {
+                self.escape = Escape::None;
+                return Ok(());
+            }
+            (Escape::None, _) => {}
+        }
+
+        match byte {
+            b'\n' if carriage_return => Ok(()),
+            b'\r' | b'\n' => {
+                self.print("\r\n")?;
+                self.execute()?;
+                self.len = 0;
+                self.print(PROMPT)
+            }
+            // Backspace and delete
+            0x08 | 0x7F => {
+                if self.len > 0 {
+                    self.len -= 1;
+                    self.print("\x08 \x08")?;
+                }
+                Ok(())
+            }
+            // Ctrl-C
+            0x03 => {
+                self.len = 0;
+                self.print("^C\r\n")?;
+                self.print(PROMPT)
+            }
+            // Ctrl-U
+            0x15 => self.erase(),
+            0x1B => {
+                self.escape = Escape::Escape;
+                Ok(())
+            }
+            0x20..=0x7E if self.len < MAX_LINE => {
+                self.line[self.len] = byte;
+                self.len += 1;
+                self.write(&[byte])
+            }
+            0x20..=0x7E => self.write(b"\x07"),
+            _ => Ok(()),
+        }
+    }
+
+    // Erase the line on the terminal and in the buffer
+    fn erase(&mut self) -> Result<(), Error> {
+        for _ in 0..self.len {
+            self.print("\x08 \x08")?;
+        }
+        self.len = 0;
+        Ok(())
+    }
+
+    // Replace the line with the previous one
+    fn recall(&mut self) -> Result<(), Error> {
+        self.erase()?;
+        self.line = self.history;
+        self.len = self.history_len;
+        let history = self.history;
+        self.write(&history[..self.history_len])
+    }
+
+    // Run the line in the buffer
+    fn execute(&mut self) -> Result<(), Error> {
+        let buffer = self.line;
+        // Only printable ASCII is stored
+        let line = core::str::from_utf8(&buffer[..self.len]).unwrap_or("");
+        let mut words = [""; MAX_ARGS];
+        let mut count = 0;
+        for word in line.split_ascii_whitespace() {
+            if count == MAX_ARGS {
+                return self.print("too many arguments\r\n");
+            }
+            words[count] = word;
+            count += 1;
+        }
+        if count == 0 {
+            return Ok(());
+        }
+        self.history = buffer;
+        self.history_len = self.len;
+
+        let mut out = Output(&mut self.tx);
+        let name = words[0];
+        if name == "help" {
+            return help(&mut out, self.commands).map_err(|_| Error::Port);
+        }
+        let Some(command) = self
+            .commands
+            .iter()
+            .chain(BUILTINS)
+            .find(|command| command.name() == name)
+        else {
+            return writeln!(out, "unknown command {}, try help", name).map_err(|_| Error::Port);
+        };
+        let result = match (command.run)(&mut out, &words[1..count]) {
+            Ok(()) => Ok(()),
+            Err(CommandError::Usage) => writeln!(out, "usage: {}", command.usage),
+            Err(CommandError::Failed(message)) => writeln!(out, "error: {}", message),
+            Err(CommandError::Output) => Err(fmt::Error),
+        };
+        result.map_err(|_| Error::Port)
+    }
+
+    fn print(&mut self, s: &str) -> Result<(), Error> {
+        self.write(s.as_bytes())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.tx.write_all(bytes).map_err(|_| Error::Port)
+    }
+}
+
+// Command output, with line feeds sent as CR LF for terminals
+struct Output<'a, W: Write>(&'a mut W);
+
+impl<W: Write> fmt::Write for Output<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (n, part) in s.split('\n').enumerate() {
+            if n > 0 {
+                self.0.write_all(b"\r\n").map_err(|_| fmt::Error)?;
+            }
+            self.0.write_all(part.as_bytes()).map_err(|_| fmt::Error)?;
+        }
+        Ok(())
+    }
+}
+
+fn help(out: &mut dyn fmt::Write, commands: &[Command]) -> fmt::Result {
+    for command in commands.iter().chain(BUILTINS) {
+        writeln!(out, "{:<20} {}", command.usage, command.help)?;
+    }
+    writeln!(out, "{:<20} {}", "help", "this list")
+}
+
+// Parse a pin name, e.g. P102 for port 1 pin 2
+fn parse_pin(name: &str) -> Option<(u8, u8)> {
+    let digits = name.strip_prefix(['P', 'p'])?;
+    if digits.len() != 3 {
+        return None;
+    }
+    let port = digits[..1].parse::<u8>().ok()?;
+    let pin = digits[1..].parse::<u8>().ok()?;
+    (pin < 16).then_some((port, pin))
+}
+
+fn clocks(out: &mut dyn fmt::Write, _args: &[&str]) -> Result<(), CommandError> {
+    let clocks = Clocks::read();
+    writeln!(out, "source {} Hz", clocks.source())?;
+    writeln!(out, "ICLK   {} Hz", clocks.iclk())?;
+    writeln!(out, "FCLK   {} Hz", clocks.fclk())?;
+    writeln!(out, "PCLKA  {} Hz", clocks.pclka())?;
+    writeln!(out, "PCLKB  {} Hz", clocks.pclkb())?;
+    writeln!(out, "PCLKC  {} Hz", clocks.pclkc())?;
+    writeln!(out, "PCLKD  {} Hz", clocks.pclkd())?;
+    Ok(())
+}
+
+#[cfg(feature = "can")]
+fn can_status(out: &mut dyn fmt::Write, _args: &[&str]) -> Result<(), CommandError> {
+    let Some(snap) = can::snapshot() else {
+        writeln!(out, "CAN stopped")?;
+        return Ok(());
+    };
+    writeln!(out, "mode {} ({})", snap.mode_name(), snap.error_state())?;
+    writeln!(out, "TEC {} REC {}", snap.tecr, snap.recr)?;
+    writeln!(out, "ECSR {:02X} EIFR {:02X}", snap.ecsr, snap.eifr)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "can"))]
+fn can_status(out: &mut dyn fmt::Write, _args: &[&str]) -> Result<(), CommandError> {
+    writeln!(out, "CAN not built, enable the can feature")?;
+    Ok(())
+}
+
+fn peek(out: &mut dyn fmt::Write, args: &[&str]) -> Result<(), CommandError> {
+    let [name] = args else {
+        return Err(CommandError::Usage);
+    };
+    let (port, pin) = parse_pin(name).ok_or(CommandError::Failed("bad pin name"))?;
+    let level = u8::from(gpio::is_high_at(port, pin));
+    writeln!(out, "{} {}", name, level)?;
+    Ok(())
+}
+
+fn poke(_out: &mut dyn fmt::Write, args: &[&str]) -> Result<(), CommandError> {
+    let [name, level] = args else {
+        return Err(CommandError::Usage);
+    };
+    let (port, pin) = parse_pin(name).ok_or(CommandError::Failed("bad pin name"))?;
+    let high = match *level {
+        "0" => false,
+        "1" => true,
+        _ => return Err(CommandError::Usage),
+    };
+    // A pin routed to a peripheral keeps its function, as for gpio::configure
+    let config = PinConfig::output();
+    gpio::configure_at(port, pin, if high { config.high() } else { config });
+    gpio::write_at(port, pin, high);
+    Ok(())
+}
+
+fn reset(_out: &mut dyn fmt::Write, _args: &[&str]) -> Result<(), CommandError> {
+    crate::reset::software_reset()
+}
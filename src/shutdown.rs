@@ -0,0 +1,333 @@
+//! Shutdown hooks, run once when power is failing.
+//!
+//! Voltage monitor 1 interrupts when VCC falls below a level set above the
+//! brownout reset, which with the bulk capacitance of the board leaves a few
+//! milliseconds to leave things in a clean state. Hooks registered with
+//! [`on_shutdown`] are then called in order, with interrupts disabled.
+//!
+//! The same hooks can be run from a watchdog interrupt or NMI handler with
+//! [`run`], so a hung application still gets its log flushed.
+//!
+//! Hooks for the usual work are provided:
+//!
+//! - [`flush_uart`]: send what's left in a UART transmit buffer, with the
+//!   `uart` feature.
+//! - [`halt_can`]: finish the frame on the bus and go off the bus, rather
+//!   than cutting a frame off as the supply drops, with the `can` feature.
+//! - [`write_record`]: append a record of the shutdown to the data flash,
+//!   read back after the next reset with [`last_record`].
+//!
+//! Erasing the data flash takes far longer than there is time for, so the
+//! record block is erased at startup by [`init_records`] when it fills up.
+//!
+//! ```ignore
+//! bind_interrupts!(struct Irq {
+//!     IEL12 => shutdown::LvdHandler;
+//! });
+//!
+//! let flash = flash::Flash::new(24_000_000, 48_000_000);
+//! if let Some(record) = shutdown::init_records(flash, shutdown::RECORD_BLOCK)? {
+//!     // record.cause, record.data
+//! }
+//! shutdown::on_shutdown(shutdown::flush_uart::<ra4m1::SCI2>)?;
+//! shutdown::on_shutdown(shutdown::halt_can)?;
+//! shutdown::on_shutdown(shutdown::write_record)?;
+//! shutdown::enable_lvd(Default::default(), Irq);
+//! ```
+
+use core::cell::{Cell, RefCell};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use critical_section::Mutex;
+
+use crate::events::Event;
+use crate::flash::{self, DATA_BLOCK_SIZE, DATA_FLASH_SIZE, DATA_FLASH_START, Flash};
+use crate::interrupts::{Binding, Handler, clear_interrupt, map_and_enable_interrupt};
+#[cfg(feature = "uart")]
+use crate::uart;
+
+/// Number of hooks that can be registered.
+pub const MAX_HOOKS: usize = 8;
+
+/// Default data flash block for shutdown records, the last one.
+pub const RECORD_BLOCK: u32 = DATA_FLASH_START + DATA_FLASH_SIZE - DATA_BLOCK_SIZE;
+
+/// Size of a shutdown record in the data flash.
+pub const RECORD_SIZE: u32 = 8;
+
+const SYSTEM: usize = 0x4001_E000;
+const LVD1CR1: *mut u8 = (SYSTEM + 0x0E0) as *mut u8;
+const LVD1SR: *mut u8 = (SYSTEM + 0x0E1) as *mut u8;
+const PRCR: *mut u16 = (SYSTEM + 0x3FE) as *mut u16;
+const LVCMPCR: *mut u8 = (SYSTEM + 0x417) as *mut u8;
+const LVDLVLR: *mut u8 = (SYSTEM + 0x418) as *mut u8;
+const LVD1CR0: *mut u8 = (SYSTEM + 0x41A) as *mut u8;
+
+// PRCR key and PRC3, write enable of the LVD registers
+const PRCR_KEY: u16 = 0xA5 << 8;
+const PRCR_PRC3: u16 = 1 << 3;
+// LVCMPCR.LVD1E
+const LVCMPCR_LVD1E: u8 = 1 << 5;
+// LVD1CR1, maskable interrupt (IRQSEL) when VCC falls (IDTSEL 01)
+const LVD1CR1_FALL_IRQ: u8 = (1 << 2) | 0b01;
+// LVD1CR0.RIE and CMPE, bit 3 is reserved and written as 1
+const LVD1CR0_ENABLE: u8 = (1 << 3) | (1 << 2) | (1 << 0);
+// LVD1SR.DET
+const LVD1SR_DET: u8 = 1 << 0;
+// td(E-A), LVD stabilization after enabling
+const LVD_START_US: u32 = 300;
+
+// First byte of a record, erased flash reads 0xFF
+const RECORD_MAGIC: u8 = 0x5D;
+
+static HOOKS: Mutex<Cell<[Option<fn(Cause)>; MAX_HOOKS]>> =
+    Mutex::new(Cell::new([None; MAX_HOOKS]));
+static RAN: AtomicBool = AtomicBool::new(false);
+static RECORD_DATA: AtomicU32 = AtomicU32::new(0);
+static RECORDS: Mutex<RefCell<Option<(Flash, u32)>>> = Mutex::new(RefCell::new(None));
+
+/// Why the hooks ran.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Cause {
+    /// VCC fell below the voltage monitor 1 level
+    LowVoltage = 1,
+    /// From a watchdog interrupt
+    Watchdog = 2,
+    /// From the application, e.g. before a planned reset
+    Software = 3,
+}
+
+impl Cause {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Cause::LowVoltage),
+            2 => Some(Cause::Watchdog),
+            3 => Some(Cause::Software),
+            _ => None,
+        }
+    }
+}
+
+/// Shutdown error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// [`MAX_HOOKS`] are already registered
+    Full,
+}
+
+/// Voltage monitor 1 detection level, VCC falling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Level {
+    V4_29 = 0x0,
+    V4_14 = 0x1,
+    V4_02 = 0x2,
+    V3_84 = 0x3,
+    V3_10 = 0x4,
+    V3_00 = 0x5,
+    V2_90 = 0x6,
+    V2_79 = 0x7,
+    V2_68 = 0x8,
+    V2_58 = 0x9,
+    V2_48 = 0xA,
+    V2_20 = 0xB,
+    V1_96 = 0xC,
+    V1_86 = 0xD,
+    V1_75 = 0xE,
+    V1_65 = 0xF,
+}
+
+/// Voltage monitor configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Run the hooks when VCC falls below this. Must be above the brownout
+    /// reset level, and below the lowest normal supply.
+    pub level: Level,
+    /// Frequency of ICLK in Hz, to wait for the monitor to start
+    pub iclk_hz: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            level: Level::V4_14,
+            iclk_hz: 48_000_000,
+        }
+    }
+}
+
+/// A shutdown record from the data flash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Record {
+    pub cause: Cause,
+    /// Value last given to [`set_record_data`]
+    pub data: u32,
+}
+
+/// Register `hook` to run on shutdown, after those already registered.
+///
+/// Hooks run with interrupts disabled and little time left, so should only
+/// do what must be done before power is lost.
+pub fn on_shutdown(hook: fn(Cause)) -> Result<(), Error> {
+    critical_section::with(|cs| {
+        let cell = HOOKS.borrow(cs);
+        let mut hooks = cell.get();
+        let slot = hooks.iter_mut().find(|h| h.is_none()).ok_or(Error::Full)?;
+        *slot = Some(hook);
+        cell.set(hooks);
+        Ok(())
+    })
+}
+
+/// Run the hooks, unless they have already run.
+///
+/// Called by [`LvdHandler`], and to be called from a watchdog interrupt or
+/// NMI handler with [`Cause::Watchdog`].
+pub fn run(cause: Cause) {
+    if RAN.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    critical_section::with(|cs| {
+        for hook in HOOKS.borrow(cs).get().into_iter().flatten() {
+            hook(cause);
+        }
+    });
+}
+
+/// Whether the hooks have run.
+pub fn has_run() -> bool {
+    RAN.load(Ordering::Acquire)
+}
+
+/// Handler for the voltage monitor 1 interrupt, runs the hooks.
+pub struct LvdHandler;
+
+impl Handler for LvdHandler {
+    unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
+        unsafe {
+            PRCR.write_volatile(PRCR_KEY | PRCR_PRC3);
+            LVD1SR.write_volatile(LVD1SR.read_volatile() & !LVD1SR_DET);
+            PRCR.write_volatile(PRCR_KEY);
+        }
+        clear_interrupt(interrupt);
+        run(Cause::LowVoltage);
+    }
+}
+
+/// Start voltage monitor 1 and map its interrupt to [`LvdHandler`].
+///
+/// Voltage monitor 1 must not already be in use as a reset.
+pub fn enable_lvd<IRQ: Binding<LvdHandler>>(config: Config, _irq: IRQ) {
+    unsafe {
+        PRCR.write_volatile(PRCR_KEY | PRCR_PRC3);
+        // The level can only change with the detection circuit off
+        LVCMPCR.write_volatile(LVCMPCR.read_volatile() & !LVCMPCR_LVD1E);
+        LVDLVLR.write_volatile((LVDLVLR.read_volatile() & !0x1F) | config.level as u8);
+        LVCMPCR.write_volatile(LVCMPCR.read_volatile() | LVCMPCR_LVD1E);
+        cortex_m::asm::delay(config.iclk_hz / 1_000_000 * LVD_START_US);
+        // Interrupt rather than reset
+        LVD1CR0.write_volatile(1 << 3);
+        LVD1CR1.write_volatile(LVD1CR1_FALL_IRQ);
+        LVD1SR.write_volatile(LVD1SR.read_volatile() & !LVD1SR_DET);
+        LVD1CR0.write_volatile(LVD1CR0_ENABLE);
+        PRCR.write_volatile(PRCR_KEY);
+    }
+    let interrupt = <IRQ as Binding<LvdHandler>>::interrupt();
+    clear_interrupt(interrupt);
+    map_and_enable_interrupt(interrupt, Event::LVD_LVD1);
+}
+
+/// Hook to send what's left in the transmit buffer of UART `T`.
+#[cfg(feature = "uart")]
+pub fn flush_uart<T: uart::Instance>(_cause: Cause) {
+    uart::flush::<T>();
+}
+
+/// Hook to take the CAN module off the bus in halt mode.
+#[cfg(feature = "can")]
+pub fn halt_can(_cause: Cause) {
+    let _ = crate::can::halt();
+}
+
+/// Use `block` of the data flash for shutdown records, and return the last
+/// record written.
+///
+/// Erases the block when it's full, so there's always room for the record
+/// of the next shutdown.
+pub fn init_records(mut flash: Flash, block: u32) -> Result<Option<Record>, flash::Error> {
+    let offset = block
+        .checked_sub(DATA_FLASH_START)
+        .filter(|offset| *offset < DATA_FLASH_SIZE)
+        .ok_or(flash::Error::Range)?;
+    if offset % DATA_BLOCK_SIZE != 0 {
+        return Err(flash::Error::Alignment);
+    }
+    let last = last_record_in(block);
+    if free_slot(block).is_none() {
+        flash.erase_data(block, DATA_BLOCK_SIZE)?;
+    }
+    critical_section::with(|cs| RECORDS.borrow_ref_mut(cs).replace((flash, block)));
+    Ok(last)
+}
+
+/// Set the value stored in the next shutdown record, e.g. uptime or state.
+pub fn set_record_data(data: u32) {
+    RECORD_DATA.store(data, Ordering::Relaxed);
+}
+
+/// Hook to append a shutdown record to the block given to [`init_records`].
+pub fn write_record(cause: Cause) {
+    critical_section::with(|cs| {
+        let mut records = RECORDS.borrow_ref_mut(cs);
+        let Some((flash, block)) = records.as_mut() else {
+            return;
+        };
+        let Some(slot) = free_slot(*block) else {
+            return;
+        };
+        let data = RECORD_DATA.load(Ordering::Relaxed).to_le_bytes();
+        let record = [
+            RECORD_MAGIC,
+            cause as u8,
+            0xFF,
+            0xFF,
+            data[0],
+            data[1],
+            data[2],
+            data[3],
+        ];
+        let _ = flash.program_data(slot, &record);
+    });
+}
+
+/// The last record in the block given to [`init_records`].
+pub fn last_record() -> Option<Record> {
+    let block = critical_section::with(|cs| RECORDS.borrow_ref(cs).as_ref().map(|r| r.1))?;
+    last_record_in(block)
+}
+
+fn last_record_in(block: u32) -> Option<Record> {
+    let mut last = None;
+    for addr in (block..block + DATA_BLOCK_SIZE).step_by(RECORD_SIZE as usize) {
+        let bytes = unsafe { (addr as *const [u8; RECORD_SIZE as usize]).read_volatile() };
+        if bytes[0] != RECORD_MAGIC {
+            break;
+        }
+        if let Some(cause) = Cause::from_u8(bytes[1]) {
+            let data = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+            last = Some(Record { cause, data });
+        }
+    }
+    last
+}
+
+// Address of the first erased record in `block`
+fn free_slot(block: u32) -> Option<u32> {
+    (block..block + DATA_BLOCK_SIZE)
+        .step_by(RECORD_SIZE as usize)
+        .find(|addr| unsafe { (*addr as *const u8).read_volatile() } == 0xFF)
+}
@@ -0,0 +1,334 @@
+//! SLCAN (Lawicel) serial CAN adapter.
+//!
+//! Bridges a UART and the CAN driver with the ASCII protocol understood by
+//! `slcand` from can-utils, so the board works as a USB-CAN adapter through
+//! the USB serial bridge:
+//!
+//! ```text
+//! slcand -o -s6 -t hw -S 115200 /dev/ttyACM0 can0
+//! ip link set can0 up
+//! ```
+//!
+//! ```ignore
+//! let can = Can::new(p.CAN0, pins.p102, pins.p103, BitConfig::from_bitrate(500_000)?, Irq)?;
+//! let mut slcan = Slcan::new(uart, can);
+//! loop {
+//!     slcan.poll(ticker::millis())?;
+//! }
+//! ```
+//!
+//! Supported commands are `O` open, `L` open listen only, `C` close, `S0`
+//! to `S8` bitrate, `t` / `T` / `r` / `R` send, `Z0` / `Z1` timestamps,
+//! `F` status, `V` version and `N` serial number. Commands end with a
+//! carriage return, answered with a carriage return or a bell on error.
+
+use embedded_can::{ExtendedId, Frame as _, Id, StandardId};
+use embedded_io::{Read, ReadReady, Write};
+
+use crate::can::{BitConfig, Can, Config, Frame, MailboxConfig, Running};
+
+/// Longest command, an extended frame with 8 bytes and a timestamp.
+pub const MAX_LINE: usize = 32;
+
+/// Bitrates selected by `S0` to `S8`.
+pub const BITRATES: [u32; 9] = [
+    10_000, 20_000, 50_000, 100_000, 125_000, 250_000, 500_000, 800_000, 1_000_000,
+];
+
+/// Receive mailboxes set up by [`Slcan::new`], the rest transmit.
+pub const RX_MAILBOXES: usize = 16;
+
+const OK: &[u8] = b"\r";
+const BELL: &[u8] = b"\x07";
+const VERSION: &[u8] = b"V1013\r";
+const SERIAL: &[u8] = b"NR4R4\r";
+
+/// SLCAN error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// Reading or writing the serial port failed
+    Port,
+    /// The CAN module didn't change mode in time
+    CanMode,
+}
+
+// The driver in either mode, moved between them by open and close
+enum Bus {
+    Closed(Can<Config>),
+    Open(Can<Running>),
+}
+
+// The CAN driver as the adapters use it, also by the GVRET adapter
+pub(crate) struct Channel {
+    bus: Option<Bus>,
+}
+
+impl Channel {
+    // Configure the mailboxes, RX_MAILBOXES receivers accepting everything
+    pub(crate) fn new(mut can: Can<Config>) -> Self {
+        let mut config = MailboxConfig::default();
+        for index in 0..RX_MAILBOXES {
+            config.set_mailbox_receiver(index);
+        }
+        // Always valid, and only changes mode after another ID mode
+        let _ = can.configure_mailboxes(config);
+        Self {
+            bus: Some(Bus::Closed(can)),
+        }
+    }
+
+    pub(crate) fn running(&self) -> Option<&Can<Running>> {
+        match &self.bus {
+            Some(Bus::Open(can)) => Some(can),
+            _ => None,
+        }
+    }
+
+    // Join the bus, false if it's already open
+    pub(crate) fn open(&mut self, listen_only: bool) -> Result<bool, Error> {
+        let can = match self.bus.take() {
+            Some(Bus::Closed(can)) => can,
+            bus => {
+                self.bus = bus;
+                return Ok(false);
+            }
+        };
+        if listen_only {
+            can.listen_only_mode();
+        } else {
+            can.disable_test_mode();
+        }
+        match can.start() {
+            Ok(can) => {
+                self.bus = Some(Bus::Open(can));
+                Ok(true)
+            }
+            Err(can) => {
+                self.bus = Some(Bus::Closed(can));
+                Err(Error::CanMode)
+            }
+        }
+    }
+
+    // Leave the bus, false if it's already closed
+    pub(crate) fn close(&mut self) -> Result<bool, Error> {
+        let can = match self.bus.take() {
+            Some(Bus::Open(can)) => can,
+            bus => {
+                self.bus = bus;
+                return Ok(false);
+            }
+        };
+        match can.stop() {
+            Ok(can) => {
+                can.disable_test_mode();
+                self.bus = Some(Bus::Closed(can));
+                Ok(true)
+            }
+            Err(can) => {
+                self.bus = Some(Bus::Open(can));
+                Err(Error::CanMode)
+            }
+        }
+    }
+
+    // Change the bitrate while closed, false if open or not possible
+    pub(crate) fn set_bitrate(&mut self, bitrate: u32) -> Result<bool, Error> {
+        let Some(Bus::Closed(can)) = &mut self.bus else {
+            return Ok(false);
+        };
+        match BitConfig::from_bitrate(bitrate) {
+            Ok(bit_config) => {
+                can.set_bit_timing(bit_config).map_err(|_| Error::CanMode)?;
+                Ok(true)
+            }
+            Err(_) => Ok(false),
+        }
+    }
+}
+
+/// SLCAN adapter on serial port `P`.
+pub struct Slcan<P: Read + ReadReady + Write> {
+    port: P,
+    channel: Channel,
+    line: [u8; MAX_LINE],
+    len: usize,
+    // Line too long, discarded up to the next carriage return
+    overflow: bool,
+    timestamps: bool,
+}
+
+impl<P: Read + ReadReady + Write> Slcan<P> {
+    /// Bridge `port` and `can`, closed until the host opens it. The
+    /// mailboxes are configured with [`RX_MAILBOXES`] receivers accepting
+    /// every frame.
+    pub fn new(port: P, can: Can<Config>) -> Self {
+        Self {
+            port,
+            channel: Channel::new(can),
+            line: [0; MAX_LINE],
+            len: 0,
+            overflow: false,
+            timestamps: false,
+        }
+    }
+
+    /// Handle the commands received and forward frames from the bus.
+    ///
+    /// `time_ms` is the time in milliseconds, e.g. from
+    /// [`ticker::millis`](crate::ticker::millis), sent modulo 60000 when
+    /// timestamps are enabled.
+    pub fn poll(&mut self, time_ms: u32) -> Result<(), Error> {
+        while self.port.read_ready().map_err(|_| Error::Port)? {
+            let mut byte = [0u8; 1];
+            if self.port.read(&mut byte).map_err(|_| Error::Port)? == 0 {
+                break;
+            }
+            match byte[0] {
+                b'\r' => {
+                    if !self.overflow && self.len > 0 {
+                        let reply = self.command()?;
+                        self.port.write_all(reply).map_err(|_| Error::Port)?;
+                    } else if self.overflow {
+                        self.port.write_all(BELL).map_err(|_| Error::Port)?;
+                    }
+                    self.len = 0;
+                    self.overflow = false;
+                }
+                // Some hosts end lines with CR LF
+                b'\n' => {}
+                byte if self.len < MAX_LINE => {
+                    self.line[self.len] = byte;
+                    self.len += 1;
+                }
+                _ => self.overflow = true,
+            }
+        }
+
+        if let Some(can) = self.channel.running() {
+            while let Some(frame) = can.try_receive_frame() {
+                let mut out = [0u8; MAX_LINE];
+                let len = encode(
+                    &frame,
+                    self.timestamps.then_some(time_ms % 60_000),
+                    &mut out,
+                );
+                self.port.write_all(&out[..len]).map_err(|_| Error::Port)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the host has opened the channel.
+    pub fn is_open(&self) -> bool {
+        self.channel.running().is_some()
+    }
+
+    // Run the command in the line buffer, returning the reply
+    fn command(&mut self) -> Result<&'static [u8], Error> {
+        let buffer = self.line;
+        let line = &buffer[..self.len];
+        let done = match (line[0], &line[1..]) {
+            (b'O', []) => self.channel.open(false)?,
+            (b'L', []) => self.channel.open(true)?,
+            (b'C', []) => self.channel.close()?,
+            (b'S', [n @ b'0'..=b'8']) => self.channel.set_bitrate(BITRATES[(n - b'0') as usize])?,
+            (b'Z', [b'0']) => {
+                self.timestamps = false;
+                true
+            }
+            (b'Z', [b'1']) => {
+                self.timestamps = true;
+                true
+            }
+            (b'V', []) => return Ok(VERSION),
+            (b'N', []) => return Ok(SERIAL),
+            (b'F', []) => return Ok(b"F00\r"),
+            (kind @ (b't' | b'T' | b'r' | b'R'), _) => {
+                let sent = match (decode(line), self.channel.running()) {
+                    (Some(frame), Some(can)) => can.send_frame(frame).is_ok(),
+                    _ => false,
+                };
+                return Ok(match (sent, kind.is_ascii_uppercase()) {
+                    (false, _) => BELL,
+                    (true, true) => b"Z\r",
+                    (true, false) => b"z\r",
+                });
+            }
+            _ => false,
+        };
+        Ok(if done { OK } else { BELL })
+    }
+}
+
+// Parse a t, T, r or R command
+pub(crate) fn decode(line: &[u8]) -> Option<Frame> {
+    let (extended, remote) = match line[0] {
+        b't' => (false, false),
+        b'T' => (true, false),
+        b'r' => (false, true),
+        b'R' => (true, true),
+        _ => return None,
+    };
+    let id_len = if extended { 8 } else { 3 };
+    let id = hex(line.get(1..1 + id_len)?)?;
+    let dlc = hex(line.get(1 + id_len..2 + id_len)?)? as usize;
+    let id: Id = if extended {
+        ExtendedId::new(id)?.into()
+    } else {
+        StandardId::new(id as u16)?.into()
+    };
+    if remote {
+        return Frame::new_remote(id, dlc);
+    }
+    let hex_data = line.get(2 + id_len..2 + id_len + 2 * dlc)?;
+    let mut data = [0u8; 8];
+    for (byte, pair) in data.iter_mut().zip(hex_data.chunks_exact(2)) {
+        *byte = hex(pair)? as u8;
+    }
+    Frame::new(id, data.get(..dlc)?)
+}
+
+// Format a received frame as a t, T, r or R line, returning its length
+pub(crate) fn encode(frame: &Frame, timestamp: Option<u32>, out: &mut [u8; MAX_LINE]) -> usize {
+    let remote = frame.is_remote_frame();
+    let (kind, id, id_len) = match (frame.id(), remote) {
+        (Id::Standard(id), false) => (b't', id.as_raw() as u32, 3),
+        (Id::Standard(id), true) => (b'r', id.as_raw() as u32, 3),
+        (Id::Extended(id), false) => (b'T', id.as_raw(), 8),
+        (Id::Extended(id), true) => (b'R', id.as_raw(), 8),
+    };
+    out[0] = kind;
+    let mut len = 1;
+    len += put_hex(&mut out[len..], id, id_len);
+    len += put_hex(&mut out[len..], frame.dlc() as u32, 1);
+    if !remote {
+        for &byte in frame.data() {
+            len += put_hex(&mut out[len..], byte as u32, 2);
+        }
+    }
+    if let Some(timestamp) = timestamp {
+        len += put_hex(&mut out[len..], timestamp, 4);
+    }
+    out[len] = b'\r';
+    len + 1
+}
+
+// Write the low `digits` hex digits of `value`, returning `digits`
+fn put_hex(out: &mut [u8], value: u32, digits: usize) -> usize {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    for (n, digit) in out[..digits].iter_mut().enumerate() {
+        let shift = 4 * (digits - 1 - n);
+        *digit = HEX[((value >> shift) & 0xF) as usize];
+    }
+    digits
+}
+
+// Parse hex digits, upper or lower case
+fn hex(digits: &[u8]) -> Option<u32> {
+    digits.iter().try_fold(0u32, |value, &c| {
+        let digit = (c as char).to_digit(16)?;
+        Some((value << 4) | digit)
+    })
+}
@@ -0,0 +1,243 @@
+//! Blocking SPI master.
+//!
+//! Bytes are moved one at a time by polling SPTEF and SPRF, which suits
+//! short transactions to sensors, displays and memory cards. Slave select
+//! isn't driven by the SPI, use a GPIO pin so one transaction can span
+//! several transfers.
+//!
+//! ```ignore
+//! let mut spi = SpiMaster::new(p.SPI1, pins.p111, pins.p109, pins.p110, Config::default());
+//! gpio::set_level(&mut cs, false);
+//! spi.write(&[0x9F])?;
+//! spi.read(&mut id)?;
+//! gpio::set_level(&mut cs, true);
+//! ```
+//!
+//! The master is an embedded-hal 1.0 `SpiBus` with the `embedded-hal`
+//! feature, wrap it with the pin in an `ExclusiveDevice` from
+//! `embedded-hal-bus` for drivers taking an `SpiDevice`, as the `adapters`
+//! module does with the `adapters` feature. The
+//! `embedded-hal-02` feature adds the 0.2 blocking `Transfer` and `Write`.
+
+use core::marker::PhantomData;
+
+use super::{
+    Error, Instance, MisoPin, Mode, MosiPin, SPCMD_LSBF, SPCMD_SPB_8, SPCR_SPE, SPDCR_SPBYT,
+    SPSR_MODF, SPSR_OVRF, SckPin,
+};
+use crate::clk::{ClockGuard, Clocks};
+
+// SPCR, master (MSTR), clock synchronous (SPMS) so SSL isn't used
+const SPCR_SPMS: u8 = 1 << 0;
+const SPCR_MSTR: u8 = 1 << 3;
+// SPSR, transmit buffer empty (SPTEF) and receive buffer full (SPRF)
+const SPSR_SPTEF: u8 = 1 << 5;
+const SPSR_SPRF: u8 = 1 << 7;
+// SPCMD0.BRDV, bit rate divided by 2^BRDV
+const SPCMD_BRDV_SHIFT: u16 = 2;
+
+/// Master configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Clock polarity and phase
+    pub mode: Mode,
+    /// Bit rate in Hz, the nearest rate not above it is used
+    pub frequency: u32,
+    /// Send and receive the least significant bit first
+    pub lsb_first: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            mode: Mode::Mode0,
+            frequency: 1_000_000,
+            lsb_first: false,
+        }
+    }
+}
+
+/// SPI master on channel `T`, slave select on a GPIO pin.
+pub struct SpiMaster<T: Instance> {
+    config: Config,
+    _clock: ClockGuard,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Instance> SpiMaster<T> {
+    /// Configure the channel as a master with the bit rate from the current
+    /// PCLKA.
+    pub fn new<SCK: SckPin<T>, MOSI: MosiPin<T>, MISO: MisoPin<T>>(
+        _instance: T,
+        _sck: SCK,
+        _mosi: MOSI,
+        _miso: MISO,
+        config: Config,
+    ) -> Self {
+        let clock = super::init::<T>();
+        super::connect_pin::<SCK>();
+        super::connect_pin::<MOSI>();
+        super::connect_pin::<MISO>();
+
+        let spi = unsafe { &*T::peripheral() };
+        spi.sppcr.write(|w| unsafe { w.bits(0) });
+        spi.spdcr.write(|w| unsafe { w.bits(SPDCR_SPBYT) });
+        spi.spscr.write(|w| unsafe { w.bits(0) });
+        spi.spcr2.write(|w| unsafe { w.bits(0) });
+
+        let mut master = Self {
+            config,
+            _clock: clock,
+            _phantom: PhantomData,
+        };
+        master.apply();
+        master
+    }
+
+    /// Change the bit rate, e.g. to speed up once a memory card is
+    /// initialised. Returns the rate used.
+    pub fn set_frequency(&mut self, frequency: u32) -> u32 {
+        self.config.frequency = frequency;
+        self.apply()
+    }
+
+    /// The bit rate being used, in Hz.
+    pub fn frequency(&self) -> u32 {
+        let spi = unsafe { &*T::peripheral() };
+        let spbr = spi.spbr.read().bits() as u32;
+        let brdv = (spi.spcmd0.read().bits() >> SPCMD_BRDV_SHIFT) & 0b11;
+        Clocks::read().pclka() / ((2 * (spbr + 1)) << brdv)
+    }
+
+    /// Send and receive one byte.
+    pub fn transfer_byte(&mut self, byte: u8) -> Result<u8, Error> {
+        let spi = unsafe { &*T::peripheral() };
+        while spi.spsr.read().bits() & SPSR_SPTEF == 0 {}
+        unsafe { super::spdr::<T>().write_volatile(byte) };
+        loop {
+            let spsr = spi.spsr.read().bits();
+            if spsr & SPSR_OVRF != 0 {
+                spi.spsr
+                    .write(|w| unsafe { w.bits(spsr & !(SPSR_OVRF | SPSR_MODF)) });
+                return Err(Error::Overrun);
+            }
+            if spsr & SPSR_SPRF != 0 {
+                return Ok(unsafe { super::spdr::<T>().read_volatile() });
+            }
+        }
+    }
+
+    /// Send `words` and replace them with the bytes received.
+    pub fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Error> {
+        for word in words {
+            *word = self.transfer_byte(*word)?;
+        }
+        Ok(())
+    }
+
+    /// Send `words`, discarding what's received.
+    pub fn write(&mut self, words: &[u8]) -> Result<(), Error> {
+        for &word in words {
+            self.transfer_byte(word)?;
+        }
+        Ok(())
+    }
+
+    /// Fill `words` with bytes received while sending 0xFF.
+    pub fn read(&mut self, words: &mut [u8]) -> Result<(), Error> {
+        for word in words {
+            *word = self.transfer_byte(0xFF)?;
+        }
+        Ok(())
+    }
+
+    // Write the configuration with the SPI stopped, SPBR can't change while
+    // it's enabled. Returns the bit rate.
+    fn apply(&mut self) -> u32 {
+        let spi = unsafe { &*T::peripheral() };
+        spi.spcr.write(|w| unsafe { w.bits(0) });
+
+        // Smallest divider that doesn't exceed the frequency, SPBR first
+        let pclka = Clocks::read().pclka();
+        let frequency = self.config.frequency.clamp(1, pclka / 2);
+        let (spbr, brdv) = (0..4u32)
+            .map(|brdv| {
+                let divider = pclka.div_ceil((2 * frequency) << brdv);
+                (divider.max(1) - 1, brdv)
+            })
+            .find(|&(spbr, _)| spbr <= 255)
+            .unwrap_or((255, 3));
+        spi.spbr.write(|w| unsafe { w.bits(spbr as u8) });
+
+        let lsbf = if self.config.lsb_first { SPCMD_LSBF } else { 0 };
+        let spcmd =
+            SPCMD_SPB_8 | lsbf | ((brdv as u16) << SPCMD_BRDV_SHIFT) | self.config.mode as u16;
+        spi.spcmd0.write(|w| unsafe { w.bits(spcmd) });
+        spi.spcr
+            .write(|w| unsafe { w.bits(SPCR_MSTR | SPCR_SPMS | SPCR_SPE) });
+        self.frequency()
+    }
+}
+
+impl<T: Instance> Drop for SpiMaster<T> {
+    fn drop(&mut self) {
+        let spi = unsafe { &*T::peripheral() };
+        spi.spcr.write(|w| unsafe { w.bits(0) });
+    }
+}
+
+#[cfg(feature = "embedded-hal")]
+impl<T: Instance> embedded_hal::spi::ErrorType for SpiMaster<T> {
+    type Error = Error;
+}
+
+#[cfg(feature = "embedded-hal")]
+impl<T: Instance> embedded_hal::spi::SpiBus for SpiMaster<T> {
+    fn read(&mut self, words: &mut [u8]) -> Result<(), Error> {
+        SpiMaster::read(self, words)
+    }
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Error> {
+        SpiMaster::write(self, words)
+    }
+
+    /// Sends 0xFF past the end of `write`, discards what's received past
+    /// the end of `read`.
+    fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Error> {
+        for i in 0..read.len().max(write.len()) {
+            let byte = self.transfer_byte(write.get(i).copied().unwrap_or(0xFF))?;
+            if let Some(word) = read.get_mut(i) {
+                *word = byte;
+            }
+        }
+        Ok(())
+    }
+
+    fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Error> {
+        SpiMaster::transfer_in_place(self, words)
+    }
+
+    // Every byte has been received by the time a transfer returns
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "embedded-hal-02")]
+impl<T: Instance> embedded_hal_02::blocking::spi::Transfer<u8> for SpiMaster<T> {
+    type Error = Error;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Error> {
+        self.transfer_in_place(words)?;
+        Ok(words)
+    }
+}
+
+#[cfg(feature = "embedded-hal-02")]
+impl<T: Instance> embedded_hal_02::blocking::spi::Write<u8> for SpiMaster<T> {
+    type Error = Error;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Error> {
+        SpiMaster::write(self, words)
+    }
+}
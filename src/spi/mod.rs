@@ -0,0 +1,215 @@
+//! Serial Peripheral Interface (SPI)
+//!
+//! The RA4M1 has two SPI channels, SPI0 and SPI1. On the UNO R4 the SPI
+//! header pins D10 - D13 are SSLB0, MOSIB, MISOB and RSPCKB of SPI1.
+//!
+//! [`master::SpiMaster`] is a blocking master with slave select on a GPIO
+//! pin, [`slave::SpiSlave`] moves whole transactions with the DMAC.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32};
+
+use critical_section::Mutex;
+use ra4m1::{SPI0, SPI1, spi0};
+
+use crate::clk::{ClockGuard, Peripheral};
+use crate::events::Event;
+use crate::gpio::{self, Pin};
+
+pub mod master;
+pub mod slave;
+
+/// An SPI channel.
+pub trait Instance {
+    // Get access to the peripheral's register block.
+    fn peripheral() -> *const spi0::RegisterBlock;
+    fn state() -> &'static State;
+    // Channel number, 0 or 1
+    fn index() -> u8;
+    // First event of this instance (SPRI)
+    fn event_base() -> Event;
+}
+
+/// Pin that can be used as RSPCK of SPI channel `T`.
+pub trait SckPin<T: Instance>: Pin {}
+
+/// Pin that can be used as MOSI of SPI channel `T`.
+pub trait MosiPin<T: Instance>: Pin {}
+
+/// Pin that can be used as MISO of SPI channel `T`.
+pub trait MisoPin<T: Instance>: Pin {}
+
+/// Pin that can be used as SSL0 of SPI channel `T`.
+pub trait SsPin<T: Instance>: Pin {}
+
+/// Clock polarity and phase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum Mode {
+    /// Clock idle low, sample on rising edge
+    Mode0 = 0b00,
+    /// Clock idle low, sample on falling edge
+    Mode1 = 0b01,
+    /// Clock idle high, sample on falling edge
+    Mode2 = 0b10,
+    /// Clock idle high, sample on rising edge
+    Mode3 = 0b11,
+}
+
+/// SPI errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// A byte was received before the previous one was read
+    Overrun,
+    /// The host clocked data before any was ready to send
+    Underrun,
+    /// Slave select was released in the middle of a byte
+    ModeFault,
+    /// A transfer is already in progress
+    Busy,
+    /// Buffer is empty or longer than a single DMA transfer
+    BufferSize,
+}
+
+#[cfg(feature = "embedded-hal")]
+impl embedded_hal::spi::Error for Error {
+    fn kind(&self) -> embedded_hal::spi::ErrorKind {
+        match self {
+            Error::Overrun => embedded_hal::spi::ErrorKind::Overrun,
+            Error::ModeFault => embedded_hal::spi::ErrorKind::ModeFault,
+            _ => embedded_hal::spi::ErrorKind::Other,
+        }
+    }
+}
+
+/// Values shared between a driver and its interrupt handlers.
+pub struct State {
+    pub(crate) busy: AtomicBool,
+    pub(crate) rx_channel: AtomicU8,
+    pub(crate) tx_channel: AtomicU8,
+    pub(crate) rx_len: AtomicU32,
+    pub(crate) received: AtomicU32,
+    // 0 for no error, otherwise Error as u8 + 1
+    pub(crate) error: AtomicU8,
+    pub(crate) callback: Mutex<Cell<Option<fn(slave::Transfer)>>>,
+}
+
+impl State {
+    const fn new() -> Self {
+        State {
+            busy: AtomicBool::new(false),
+            rx_channel: AtomicU8::new(0),
+            tx_channel: AtomicU8::new(0),
+            rx_len: AtomicU32::new(0),
+            received: AtomicU32::new(0),
+            error: AtomicU8::new(0),
+            callback: Mutex::new(Cell::new(None)),
+        }
+    }
+}
+
+// SPCR bits
+const SPCR_MODFEN: u8 = 1 << 2;
+const SPCR_SPEIE: u8 = 1 << 4;
+const SPCR_SPTIE: u8 = 1 << 5;
+const SPCR_SPE: u8 = 1 << 6;
+const SPCR_SPRIE: u8 = 1 << 7;
+
+// SPSR flags, write 0 to clear
+const SPSR_OVRF: u8 = 1 << 0;
+const SPSR_MODF: u8 = 1 << 2;
+const SPSR_UDRF: u8 = 1 << 4;
+
+// SPDCR.SPBYT, SPDR is accessed in bytes
+const SPDCR_SPBYT: u8 = 1 << 6;
+
+// SPCMD0.SPB, 8 bit data
+const SPCMD_SPB_8: u16 = 0b0111 << 8;
+// SPCMD0.LSBF
+const SPCMD_LSBF: u16 = 1 << 12;
+
+/// Address of the data register, used as the DMA source and destination.
+pub(crate) fn spdr<T: Instance>() -> *mut u8 {
+    (T::peripheral() as usize + 0x04) as *mut u8
+}
+
+/// Enable the module and disable the channel. The module stays enabled
+/// while the returned guard is held.
+pub(crate) fn init<T: Instance>() -> ClockGuard {
+    let clock = ClockGuard::new(if T::index() == 0 {
+        Peripheral::Spi0
+    } else {
+        Peripheral::Spi1
+    });
+    let spi = unsafe { &*T::peripheral() };
+    spi.spcr.write(|w| unsafe { w.bits(0) });
+    clock
+}
+
+/// Route a pin to the SPI.
+pub(crate) fn connect_pin<P: Pin>() {
+    gpio::set_function::<P>(gpio::PinFunction::Spi);
+}
+
+impl Instance for SPI0 {
+    fn peripheral() -> *const spi0::RegisterBlock {
+        SPI0::ptr()
+    }
+
+    fn state() -> &'static State {
+        static STATE: State = State::new();
+        &STATE
+    }
+
+    fn index() -> u8 {
+        0
+    }
+
+    fn event_base() -> Event {
+        Event::SPI0_SPRI
+    }
+}
+
+impl Instance for SPI1 {
+    fn peripheral() -> *const spi0::RegisterBlock {
+        SPI1::ptr() as *const spi0::RegisterBlock
+    }
+
+    fn state() -> &'static State {
+        static STATE: State = State::new();
+        &STATE
+    }
+
+    fn index() -> u8 {
+        1
+    }
+
+    fn event_base() -> Event {
+        Event::SPI1_SPRI
+    }
+}
+
+macro_rules! impl_pins {
+    ($($pin:ident => $inst:ident $kind:ident;)*) => {
+        $(
+            impl $kind<$inst> for gpio::$pin {}
+        )*
+    };
+}
+
+impl_pins! {
+    P102 => SPI0 SckPin;
+    P101 => SPI0 MosiPin;
+    P411 => SPI0 MosiPin;
+    P100 => SPI0 MisoPin;
+    P410 => SPI0 MisoPin;
+    P103 => SPI0 SsPin;
+    P111 => SPI1 SckPin;
+    P204 => SPI1 SckPin;
+    P109 => SPI1 MosiPin;
+    P110 => SPI1 MisoPin;
+    P112 => SPI1 SsPin;
+    P108 => SPI1 SsPin;
+    P205 => SPI1 SsPin;
+}
@@ -0,0 +1,293 @@
+//! SPI slave with DMA transfers.
+//!
+//! A transaction is framed by the host asserting and releasing slave select.
+//! Before the host starts, a transmit and a receive buffer are handed to
+//! [`SpiSlave::prepare`]. Two DMAC channels then feed SPDR from the transmit
+//! buffer and drain it into the receive buffer without the CPU, so the host
+//! can clock at the full rate of the peripheral.
+//!
+//! The end of a transaction is reported by the SPTEND event, which in slave
+//! mode occurs when slave select is released once the transmit data has been
+//! shifted out. If the host may clock fewer bytes than the transmit buffer
+//! holds, the transmit buffer is never empty and SPTEND never occurs. In that
+//! case connect slave select to an external interrupt pin as well and call
+//! [`end_transaction`] from its handler.
+//!
+//! ```ignore
+//! let mut slave = SpiSlave::new(
+//!     p.SPI1, pins.p111, pins.p109, pins.p110, pins.p112,
+//!     p.DMAC0, p.DMAC1, Config::default(), Irqs,
+//! );
+//! slave.set_callback(Some(on_transfer));
+//! slave.prepare(tx_buf, rx_buf).unwrap();
+//! ```
+
+use core::marker::PhantomData;
+use core::sync::atomic::Ordering;
+
+use super::{
+    Error, Instance, MisoPin, Mode, MosiPin, SPCMD_LSBF, SPCMD_SPB_8, SPCR_MODFEN, SPCR_SPE,
+    SPCR_SPEIE, SPCR_SPRIE, SPCR_SPTIE, SPDCR_SPBYT, SPSR_MODF, SPSR_OVRF, SPSR_UDRF, SckPin,
+    SsPin,
+};
+use crate::clk::ClockGuard;
+use crate::dmac::{self, Channel};
+use crate::interrupts::{Binding, Handler, clear_interrupt, map_and_enable_interrupt};
+
+/// Slave configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Clock polarity and phase
+    pub mode: Mode,
+    /// Send and receive the least significant bit first
+    pub lsb_first: bool,
+    /// Slave select is active high instead of active low
+    pub ss_active_high: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            mode: Mode::Mode0,
+            lsb_first: false,
+            ss_active_high: false,
+        }
+    }
+}
+
+/// Outcome of a transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Transfer {
+    /// Number of bytes written into the receive buffer
+    pub received: usize,
+    /// Error that ended the transaction, if any
+    pub error: Option<Error>,
+}
+
+/// Triggers on SPTEND, slave select released with all data shifted out.
+pub struct EndHandler<T: Instance> {
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Instance> Handler for EndHandler<T> {
+    unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
+        clear_interrupt(interrupt);
+        finish::<T>(None);
+    }
+}
+
+/// Triggers on SPEI, an overrun, underrun or mode fault.
+pub struct ErrorHandler<T: Instance> {
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Instance> Handler for ErrorHandler<T> {
+    unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
+        clear_interrupt(interrupt);
+        let spi = unsafe { &*T::peripheral() };
+        let spsr = spi.spsr.read().bits();
+        // MODF is also set on underrun, check UDRF first
+        let error = if spsr & SPSR_UDRF != 0 {
+            Error::Underrun
+        } else if spsr & SPSR_MODF != 0 {
+            Error::ModeFault
+        } else if spsr & SPSR_OVRF != 0 {
+            Error::Overrun
+        } else {
+            return;
+        };
+        spi.spsr
+            .write(|w| unsafe { w.bits(spsr & !(SPSR_UDRF | SPSR_MODF | SPSR_OVRF)) });
+        finish::<T>(Some(error));
+    }
+}
+
+// End the current transaction, if there is one.
+fn finish<T: Instance>(error: Option<Error>) {
+    let state = T::state();
+    critical_section::with(|cs| {
+        if !state.busy.load(Ordering::Relaxed) {
+            return;
+        }
+        // Stop the SPI before the DMA so no more bytes are moved,
+        // this also discards anything left in the transmit buffer
+        let spi = unsafe { &*T::peripheral() };
+        spi.spcr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(SPCR_SPE | SPCR_SPTIE | SPCR_SPRIE)) });
+        let rx_channel = state.rx_channel.load(Ordering::Relaxed);
+        dmac::stop(rx_channel);
+        dmac::stop(state.tx_channel.load(Ordering::Relaxed));
+
+        let rx_len = state.rx_len.load(Ordering::Relaxed) as usize;
+        let received = rx_len - dmac::remaining(rx_channel).min(rx_len);
+        state.received.store(received as u32, Ordering::Relaxed);
+        state
+            .error
+            .store(error.map_or(0, |e| e as u8 + 1), Ordering::Relaxed);
+        state.busy.store(false, Ordering::Release);
+
+        if let Some(callback) = state.callback.borrow(cs).get() {
+            callback(Transfer { received, error });
+        }
+    });
+}
+
+/// SPI slave using the DMAC channels `RX` and `TX`.
+pub struct SpiSlave<T: Instance, RX: Channel, TX: Channel> {
+    tx_buf: Option<&'static [u8]>,
+    rx_buf: Option<&'static mut [u8]>,
+    _clock: ClockGuard,
+    _phantom: PhantomData<(T, RX, TX)>,
+}
+
+impl<T: Instance, RX: Channel, TX: Channel> SpiSlave<T, RX, TX> {
+    /// Configure the channel as a slave. Nothing is sent or received until
+    /// buffers are given with [`prepare`](Self::prepare).
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<SCK, MOSI, MISO, SS, IRQ>(
+        _instance: T,
+        _sck: SCK,
+        _mosi: MOSI,
+        _miso: MISO,
+        _ss: SS,
+        _rx_dma: RX,
+        _tx_dma: TX,
+        config: Config,
+        _irq: IRQ,
+    ) -> Self
+    where
+        SCK: SckPin<T>,
+        MOSI: MosiPin<T>,
+        MISO: MisoPin<T>,
+        SS: SsPin<T>,
+        IRQ: Binding<EndHandler<T>> + Binding<ErrorHandler<T>>,
+    {
+        let clock = super::init::<T>();
+        dmac::enable();
+        super::connect_pin::<SCK>();
+        super::connect_pin::<MOSI>();
+        super::connect_pin::<MISO>();
+        super::connect_pin::<SS>();
+
+        let spi = unsafe { &*T::peripheral() };
+        spi.sslp
+            .write(|w| unsafe { w.bits(config.ss_active_high as u8) });
+        spi.sppcr.write(|w| unsafe { w.bits(0) });
+        spi.spdcr.write(|w| unsafe { w.bits(SPDCR_SPBYT) });
+        spi.spcr2.write(|w| unsafe { w.bits(0) });
+        let lsbf = if config.lsb_first { SPCMD_LSBF } else { 0 };
+        spi.spcmd0
+            .write(|w| unsafe { w.bits(SPCMD_SPB_8 | lsbf | config.mode as u16) });
+        // Slave, 4-wire, full duplex. MODFEN detects SS released mid-byte.
+        spi.spcr
+            .write(|w| unsafe { w.bits(SPCR_MODFEN | SPCR_SPEIE) });
+
+        // SPRI and SPTI activate the DMA, SPEI and SPTEND go to the CPU
+        let event_base = T::event_base();
+        dmac::link_event::<RX>(event_base);
+        dmac::link_event::<TX>(event_base.offset(1));
+        map_and_enable_interrupt(
+            <IRQ as Binding<ErrorHandler<T>>>::interrupt(),
+            event_base.offset(3),
+        );
+        map_and_enable_interrupt(
+            <IRQ as Binding<EndHandler<T>>>::interrupt(),
+            event_base.offset(4),
+        );
+
+        let state = T::state();
+        state.rx_channel.store(RX::index(), Ordering::Relaxed);
+        state.tx_channel.store(TX::index(), Ordering::Relaxed);
+
+        Self {
+            tx_buf: None,
+            rx_buf: None,
+            _clock: clock,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Set a function called from the interrupt handler when a transaction ends.
+    ///
+    /// Use it to wake a task or flag the main loop to prepare the next transaction.
+    pub fn set_callback(&mut self, callback: Option<fn(Transfer)>) {
+        critical_section::with(|cs| T::state().callback.borrow(cs).set(callback));
+    }
+
+    /// Arm the next transaction.
+    ///
+    /// `tx` is sent while up to `rx.len()` bytes are received. Both buffers
+    /// are held until the transaction ends and can be taken back with
+    /// [`take_buffers`](Self::take_buffers).
+    pub fn prepare(&mut self, tx: &'static [u8], rx: &'static mut [u8]) -> Result<(), Error> {
+        let state = T::state();
+        if state.busy.load(Ordering::Acquire) {
+            return Err(Error::Busy);
+        }
+        let valid = 1..=dmac::MAX_TRANSFER;
+        if !valid.contains(&tx.len()) || !valid.contains(&rx.len()) {
+            return Err(Error::BufferSize);
+        }
+
+        let spdr = super::spdr::<T>();
+        // Safety: the buffers are 'static and held by self until the channels are stopped
+        unsafe {
+            dmac::start_read::<RX>(spdr, rx.as_mut_ptr(), rx.len());
+            dmac::start_write::<TX>(tx.as_ptr(), spdr, tx.len());
+        }
+        state.rx_len.store(rx.len() as u32, Ordering::Relaxed);
+        state.error.store(0, Ordering::Relaxed);
+        state.busy.store(true, Ordering::Release);
+        self.tx_buf = Some(tx);
+        self.rx_buf = Some(rx);
+
+        let spi = unsafe { &*T::peripheral() };
+        spi.spsr.write(|w| unsafe { w.bits(0) });
+        // Setting SPE with SPTIE loads the first byte through the TX channel
+        spi.spcr.write(|w| unsafe {
+            w.bits(SPCR_MODFEN | SPCR_SPEIE | SPCR_SPTIE | SPCR_SPRIE | SPCR_SPE)
+        });
+        Ok(())
+    }
+
+    /// True from [`prepare`](Self::prepare) until the transaction ends.
+    pub fn is_busy(&self) -> bool {
+        T::state().busy.load(Ordering::Acquire)
+    }
+
+    /// Outcome of the last transaction, None while one is in progress
+    /// or if none has been prepared.
+    pub fn result(&self) -> Option<Transfer> {
+        if self.is_busy() || self.rx_buf.is_none() {
+            return None;
+        }
+        let state = T::state();
+        let error = match state.error.load(Ordering::Relaxed) {
+            0 => None,
+            1 => Some(Error::Overrun),
+            2 => Some(Error::Underrun),
+            _ => Some(Error::ModeFault),
+        };
+        Some(Transfer {
+            received: state.received.load(Ordering::Relaxed) as usize,
+            error,
+        })
+    }
+
+    /// Take back the transmit and receive buffers once the transaction has ended.
+    pub fn take_buffers(&mut self) -> Option<(&'static [u8], &'static mut [u8])> {
+        if self.is_busy() {
+            return None;
+        }
+        self.tx_buf.take().zip(self.rx_buf.take())
+    }
+}
+
+/// End the current transaction on channel `T`.
+///
+/// Call from an interrupt on the slave select pin when the host may
+/// clock fewer bytes than the transmit buffer holds.
+pub fn end_transaction<T: Instance>() {
+    finish::<T>(None);
+}
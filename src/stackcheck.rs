@@ -0,0 +1,174 @@
+//! Stack usage measurement and overflow detection.
+//!
+//! [`paint`] fills the unused stack with a pattern at boot, and
+//! [`stack_high_watermark`] later finds how much of it has been overwritten,
+//! the most stack used so far including interrupt handlers.
+//!
+//! The stack grows down towards `.bss`, so an overflow silently corrupts
+//! statics. [`enable_guard`] makes the lowest [`GUARD_SIZE`] bytes of the
+//! stack inaccessible through the MPU, so an overflow faults instead, and
+//! [`hard_fault`] reports it on the [`console`](crate::console):
+//!
+//! ```ignore
+//! #[entry]
+//! fn main() -> ! {
+//!     stackcheck::paint();
+//!     stackcheck::enable_guard();
+//!     // ...
+//!     sprintln!("stack {} of {} bytes", stackcheck::stack_high_watermark(), stackcheck::stack_size());
+//! }
+//!
+//! #[exception]
+//! unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+//!     stackcheck::hard_fault(frame)
+//! }
+//! ```
+//!
+//! The fault handler runs on the stack that overflowed, into the guard as
+//! the MPU is off for HardFault, so a deep handler can still reach `.bss`.
+//! [`hard_fault`] only formats a few lines before resetting.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::ExceptionFrame;
+#[cfg(feature = "uart")]
+use ra4m1::SCI2;
+
+use crate::mpu::{self, Access, Region};
+
+/// Value painted over the unused stack.
+pub const PATTERN: u32 = 0xCCCC_CCCC;
+
+/// Size of the MPU guard at the bottom of the stack.
+pub const GUARD_SIZE: usize = 64;
+
+// Stack left unpainted below the stack pointer, for the painting itself
+const MARGIN: usize = 64;
+
+// MPU region used for the guard, the highest so it overrides the others
+const GUARD_REGION: usize = mpu::REGIONS - 1;
+
+// CFSR MemManage status, stacking error, data access violation and a
+// valid fault address
+const MMFSR_DACCVIOL: u32 = 1 << 1;
+const MMFSR_MSTKERR: u32 = 1 << 4;
+const MMFSR_MMARVALID: u32 = 1 << 7;
+
+unsafe extern "C" {
+    // Top of the stack, from cortex-m-rt
+    static _stack_start: u32;
+    // Bottom of the stack, the end of .bss and .uninit
+    static _stack_end: u32;
+}
+
+// End of the guard while it's enabled, the lowest address that can be read
+static GUARD_END: AtomicUsize = AtomicUsize::new(0);
+
+fn top() -> usize {
+    unsafe { &_stack_start as *const u32 as usize }
+}
+
+// Lowest stack address that can be read
+fn bottom() -> usize {
+    let end = unsafe { &_stack_end as *const u32 as usize };
+    end.max(GUARD_END.load(Ordering::Relaxed))
+        .next_multiple_of(4)
+}
+
+/// Size of the stack area in bytes, excluding the guard.
+pub fn stack_size() -> usize {
+    top() - bottom()
+}
+
+/// Paint the stack below the current stack pointer with [`PATTERN`].
+///
+/// Call first thing in `main`, stack used before is counted as used.
+/// Interrupts are disabled while painting.
+pub fn paint() {
+    cortex_m::interrupt::free(|_| {
+        let sp = cortex_m::register::msp::read() as usize;
+        let mut addr = bottom();
+        while addr + MARGIN < sp {
+            unsafe { (addr as *mut u32).write_volatile(PATTERN) };
+            addr += 4;
+        }
+    });
+}
+
+/// Most stack used since [`paint`], in bytes.
+///
+/// Found by scanning up from the bottom for the first word that isn't
+/// [`PATTERN`], so a function that reserved stack without writing all of it
+/// can be under-counted by that much.
+pub fn stack_high_watermark() -> usize {
+    let mut addr = bottom();
+    let top = top();
+    while addr < top && unsafe { (addr as *const u32).read_volatile() } == PATTERN {
+        addr += 4;
+    }
+    top - addr
+}
+
+/// Make the bottom [`GUARD_SIZE`] bytes of the stack inaccessible, so an
+/// overflow raises a HardFault.
+///
+/// Uses MPU region 7, other regions can be set up around it with
+/// [`mpu::Config`]. The rest of the memory map keeps its default access.
+pub fn enable_guard() {
+    let end = unsafe { &_stack_end as *const u32 as usize };
+    let base = end.next_multiple_of(GUARD_SIZE);
+    let guard = Region::new(base as u32, GUARD_SIZE as u32)
+        .expect("GUARD_SIZE is a power of two")
+        .access(Access::None)
+        .no_execute();
+    mpu::set_region(GUARD_REGION, Some(&guard));
+    if !mpu::is_enabled() {
+        mpu::enable(true);
+    }
+    GUARD_END.store(base + GUARD_SIZE, Ordering::Relaxed);
+}
+
+/// Whether the last fault was an access to the stack guard.
+pub fn is_guard_fault() -> bool {
+    let guard_end = GUARD_END.load(Ordering::Relaxed);
+    if guard_end == 0 {
+        return false;
+    }
+    let scb = unsafe { &*SCB::PTR };
+    let mmfsr = scb.cfsr.read() & 0xFF;
+    if mmfsr & MMFSR_MSTKERR != 0 {
+        return true;
+    }
+    let address = scb.mmfar.read() as usize;
+    mmfsr & (MMFSR_DACCVIOL | MMFSR_MMARVALID) == MMFSR_DACCVIOL | MMFSR_MMARVALID
+        && (guard_end - GUARD_SIZE..guard_end).contains(&address)
+}
+
+/// Report a HardFault on the console, then reset.
+///
+/// Prints whether the stack overflowed into the guard, the fault status
+/// registers and the faulting PC and LR, and waits for the console UART to
+/// send it all. Without the `uart` feature it only resets.
+#[cfg_attr(not(feature = "uart"), allow(unused_variables))]
+pub fn hard_fault(frame: &ExceptionFrame) -> ! {
+    #[cfg(feature = "uart")]
+    {
+        let scb = unsafe { &*SCB::PTR };
+        if is_guard_fault() {
+            crate::sprintln!("HardFault: stack overflow");
+        } else {
+            crate::sprintln!("HardFault");
+        }
+        crate::sprintln!(
+            "  CFSR {:08X} HFSR {:08X} MMFAR {:08X} BFAR {:08X}",
+            scb.cfsr.read(),
+            scb.hfsr.read(),
+            scb.mmfar.read(),
+            scb.bfar.read()
+        );
+        crate::sprintln!("  PC {:08X} LR {:08X}", frame.pc(), frame.lr());
+        crate::uart::flush::<SCI2>();
+    }
+    crate::reset::software_reset()
+}
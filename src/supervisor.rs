@@ -0,0 +1,167 @@
+//! Independent watchdog (IWDT) fed only while every task is alive.
+//!
+//! Each task of the application, e.g. the UART pump, the CAN pump and the
+//! main loop, is registered with a deadline and must [`check_in`] within it.
+//! [`check`], called periodically from an interrupt, refreshes the IWDT
+//! while they all have. When one hasn't, it prints the task's name on the
+//! [`console`](crate::console), runs the [`shutdown`](crate::shutdown)
+//! hooks and stops refreshing, so the IWDT resets the MCU:
+//!
+//! ```ignore
+//! let uart_task = supervisor::register("uart", 50)?;
+//! let app_task = supervisor::register("app", 500)?;
+//! // SysTick every 10 ms
+//! syst.set_reload(48_000_000 / 100 - 1);
+//! syst.enable_interrupt();
+//! syst.enable_counter();
+//! loop {
+//!     pump_uart();
+//!     supervisor::check_in(uart_task);
+//!     run_app();
+//!     supervisor::check_in(app_task);
+//! }
+//!
+//! #[exception]
+//! fn SysTick() {
+//!     supervisor::check();
+//! }
+//! ```
+//!
+//! ```text
+//! supervisor: task app stalled for 512 ms
+//! ```
+//!
+//! The IWDT can only be started by the OFS0 option bytes, with its timeout
+//! and reset rather than NMI on underflow; without it [`check`] only
+//! reports. Times come from [`ticker::millis`](crate::ticker::millis), so the
+//! [`Ticker`](crate::ticker::Ticker) must be running. After the reset
+//! [`reset::reset_reason`](crate::reset::reset_reason) is
+//! [`IndependentWatchdog`](crate::reset::ResetReason::IndependentWatchdog).
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+#[cfg(feature = "uart")]
+use ra4m1::SCI2;
+
+use crate::shutdown::{self, Cause};
+use crate::ticker;
+
+/// Number of tasks that can be registered.
+pub const MAX_TASKS: usize = 8;
+
+// IWDT Refresh Register, written 0x00 then 0xFF
+const IWDTRR: *mut u8 = 0x4004_4400 as *mut u8;
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    tasks: [None; MAX_TASKS],
+    stalled: None,
+}));
+
+/// Supervisor error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// [`MAX_TASKS`] are already registered
+    Full,
+}
+
+/// A registered task, to [`check_in`] with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TaskId(usize);
+
+#[derive(Clone, Copy)]
+struct Task {
+    name: &'static str,
+    deadline_ms: u32,
+    last_ms: u32,
+}
+
+struct State {
+    tasks: [Option<Task>; MAX_TASKS],
+    stalled: Option<&'static str>,
+}
+
+/// Supervise a task that must check in every `deadline_ms`, counting from
+/// now.
+pub fn register(name: &'static str, deadline_ms: u32) -> Result<TaskId, Error> {
+    let now = ticker::millis();
+    critical_section::with(|cs| {
+        let mut state = STATE.borrow_ref_mut(cs);
+        let index = state
+            .tasks
+            .iter()
+            .position(Option::is_none)
+            .ok_or(Error::Full)?;
+        state.tasks[index] = Some(Task {
+            name,
+            deadline_ms,
+            last_ms: now,
+        });
+        Ok(TaskId(index))
+    })
+}
+
+/// Stop supervising a task, e.g. one that has finished.
+pub fn unregister(id: TaskId) {
+    critical_section::with(|cs| STATE.borrow_ref_mut(cs).tasks[id.0] = None);
+}
+
+/// Report a task as alive, starting its deadline again.
+pub fn check_in(id: TaskId) {
+    let now = ticker::millis();
+    critical_section::with(|cs| {
+        if let Some(task) = STATE.borrow_ref_mut(cs).tasks[id.0].as_mut() {
+            task.last_ms = now;
+        }
+    });
+}
+
+/// Refresh the IWDT if every task is within its deadline, otherwise
+/// report the first stalled one and stop refreshing for good.
+///
+/// Call from a periodic interrupt more often than the IWDT timeout.
+/// Returns the name of the stalled task, when one is first found.
+pub fn check() -> Option<&'static str> {
+    let now = ticker::millis();
+    let stalled = critical_section::with(|cs| {
+        let mut state = STATE.borrow_ref_mut(cs);
+        if state.stalled.is_some() {
+            return None;
+        }
+        let stalled = state.tasks.iter().flatten().find_map(|task| {
+            let late = now.wrapping_sub(task.last_ms);
+            (late > task.deadline_ms).then_some((task.name, late))
+        });
+        match stalled {
+            Some((name, _)) => state.stalled = Some(name),
+            None => refresh(),
+        }
+        stalled
+    });
+
+    let (name, late) = stalled?;
+    #[cfg(feature = "uart")]
+    {
+        crate::sprintln!("supervisor: task {} stalled for {} ms", name, late);
+        crate::uart::flush::<SCI2>();
+    }
+    #[cfg(not(feature = "uart"))]
+    let _ = late;
+    shutdown::run(Cause::Watchdog);
+    Some(name)
+}
+
+/// The task that stalled, once [`check`] has found one.
+pub fn stalled() -> Option<&'static str> {
+    critical_section::with(|cs| STATE.borrow_ref(cs).stalled)
+}
+
+/// Refresh the IWDT directly, bypassing the tasks.
+pub fn refresh() {
+    unsafe {
+        IWDTRR.write_volatile(0x00);
+        IWDTRR.write_volatile(0xFF);
+    }
+}
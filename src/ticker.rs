@@ -0,0 +1,186 @@
+//! Periodic callbacks for bare-metal main loops.
+//!
+//! AGT0 interrupts every millisecond to count time. Callbacks registered
+//! with [`Ticker::every_ms`] run from [`Ticker::poll`] in the main loop, not
+//! the interrupt, so they can take their time and share state with the loop
+//! without locks. A callback that's late runs once, rather than catching up
+//! on every period it missed.
+//!
+//! ```ignore
+//! bind_interrupts!(struct Irq {
+//!     IEL9 => ticker::TickHandler;
+//! });
+//!
+//! let mut ticker = ticker::Ticker::new(p.AGT0, Irq);
+//! ticker.every_ms(500, || toggle_led())?;
+//! ticker.every_ms(10, || poll_buttons())?;
+//! loop {
+//!     ticker.poll();
+//!     cortex_m::asm::wfi();
+//! }
+//! ```
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::clk::{ClockGuard, Clocks, Peripheral};
+use crate::events::Event;
+use crate::interrupts::{Binding, Handler, clear_interrupt, map_and_enable_interrupt};
+
+/// Number of callbacks that can be registered.
+pub const MAX_TASKS: usize = 8;
+
+pub(crate) const AGT0: usize = 0x4008_4000;
+pub(crate) const AGT1: usize = 0x4008_4100;
+// Offsets of AGT, AGTCR and AGTMR1 in a channel
+const AGT: usize = 0x00;
+const AGTCR: usize = 0x08;
+const AGTMR1: usize = 0x09;
+
+// AGTCR.TSTART and TCSTF
+const AGTCR_TSTART: u8 = 1 << 0;
+const AGTCR_TCSTF: u8 = 1 << 1;
+// AGTMR1 timer mode, TCK PCLKB / 8
+const AGTMR1_PCLKB_8: u8 = 0b001 << 4;
+
+static MILLIS: AtomicU32 = AtomicU32::new(0);
+
+/// Ticker error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// [`MAX_TASKS`] are already registered
+    Full,
+}
+
+/// A registered callback, to [`cancel`](Ticker::cancel) it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TaskId(usize);
+
+#[derive(Clone, Copy)]
+struct Task {
+    period: u32,
+    next: u32,
+    callback: fn(),
+}
+
+/// Counts a millisecond on each AGT0 underflow.
+pub struct TickHandler;
+
+impl Handler for TickHandler {
+    unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
+        clear_interrupt(interrupt);
+        MILLIS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Milliseconds since the [`Ticker`] started, wrapping every 49 days.
+pub fn millis() -> u32 {
+    MILLIS.load(Ordering::Relaxed)
+}
+
+/// Runs registered callbacks at their periods from [`poll`](Self::poll).
+pub struct Ticker {
+    tasks: [Option<Task>; MAX_TASKS],
+    _clock: ClockGuard,
+}
+
+impl Ticker {
+    /// Start AGT0 counting milliseconds from the current PCLKB.
+    pub fn new<IRQ: Binding<TickHandler>>(_agt: ra4m1::AGT0, _irq: IRQ) -> Self {
+        let clock = ClockGuard::new(Peripheral::Agt0);
+        MILLIS.store(0, Ordering::Relaxed);
+        start_millisecond_timer(AGT0, <IRQ as Binding<TickHandler>>::interrupt());
+        Self {
+            tasks: [None; MAX_TASKS],
+            _clock: clock,
+        }
+    }
+
+    /// Call `callback` every `period_ms` from [`poll`](Self::poll), first
+    /// after one period.
+    pub fn every_ms(&mut self, period_ms: u32, callback: fn()) -> Result<TaskId, Error> {
+        let index = self
+            .tasks
+            .iter()
+            .position(Option::is_none)
+            .ok_or(Error::Full)?;
+        let period = period_ms.max(1);
+        self.tasks[index] = Some(Task {
+            period,
+            next: millis().wrapping_add(period),
+            callback,
+        });
+        Ok(TaskId(index))
+    }
+
+    /// Stop calling a callback.
+    pub fn cancel(&mut self, id: TaskId) {
+        self.tasks[id.0] = None;
+    }
+
+    /// Run the callbacks that are due, in the order they were registered.
+    pub fn poll(&mut self) {
+        let now = millis();
+        for task in self.tasks.iter_mut().flatten() {
+            if (now.wrapping_sub(task.next) as i32) < 0 {
+                continue;
+            }
+            (task.callback)();
+            task.next = task.next.wrapping_add(task.period);
+            // Too far behind, skip the missed periods
+            if (now.wrapping_sub(task.next) as i32) >= 0 {
+                task.next = now.wrapping_add(task.period);
+            }
+        }
+    }
+
+    /// Milliseconds until the next callback is due, 0 if one is.
+    pub fn next_due(&self) -> Option<u32> {
+        let now = millis();
+        self.tasks
+            .iter()
+            .flatten()
+            .map(|task| (task.next.wrapping_sub(now) as i32).max(0) as u32)
+            .min()
+    }
+
+    /// Poll forever, sleeping between ticks.
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.poll();
+            cortex_m::asm::wfi();
+        }
+    }
+}
+
+impl Drop for Ticker {
+    fn drop(&mut self) {
+        stop_timer(AGT0);
+    }
+}
+
+// Underflow the AGT channel at `base` every millisecond of PCLKB / 8 and map
+// its interrupt. The channel must be enabled.
+pub(crate) fn start_millisecond_timer(base: usize, interrupt: ra4m1::Interrupt) {
+    let agtcr = (base + AGTCR) as *mut u8;
+    let reload = (Clocks::read().pclkb() / 8 / 1000).clamp(1, 0x1_0000) - 1;
+    unsafe {
+        agtcr.write_volatile(0);
+        while agtcr.read_volatile() & AGTCR_TCSTF != 0 {}
+        ((base + AGTMR1) as *mut u8).write_volatile(AGTMR1_PCLKB_8);
+        ((base + AGT) as *mut u16).write_volatile(reload as u16);
+    }
+    let event = if base == AGT1 {
+        Event::AGT1_AGTI
+    } else {
+        Event::AGT0_AGTI
+    };
+    clear_interrupt(interrupt);
+    map_and_enable_interrupt(interrupt, event);
+    unsafe { agtcr.write_volatile(AGTCR_TSTART) };
+}
+
+pub(crate) fn stop_timer(base: usize) {
+    unsafe { ((base + AGTCR) as *mut u8).write_volatile(0) };
+}
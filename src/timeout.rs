@@ -0,0 +1,324 @@
+//! One-shot timeouts from a single hardware timer.
+//!
+//! AGT1 ticks every millisecond and advances a hierarchical timer wheel of
+//! four levels of 64 slots, so arming, cancelling and each tick take the same
+//! time however many timeouts are pending, up to [`MAX_TIMEOUTS`]. Timeouts
+//! up to about 4.6 hours can be set, longer ones are clamped.
+//!
+//! Drivers and applications share the wheel for delays such as CAN bus-off
+//! recovery, UART inter-character timeouts or the Modbus RTU 3.5 character
+//! gap. A timeout either calls a function from the tick interrupt, or is
+//! awaited as a [`Timeout`] future.
+//!
+//! ```ignore
+//! bind_interrupts!(struct Irq {
+//!     IEL10 => timeout::TickHandler;
+//! });
+//!
+//! timeout::init(p.AGT1, Irq);
+//! let gap = timeout::after_ms(2, end_of_frame)?;
+//! // A byte arrived, start the gap again
+//! timeout::restart(gap, 2);
+//!
+//! // In an async task
+//! timeout::Timeout::after_ms(100)?.await;
+//! ```
+
+use core::cell::RefCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use critical_section::Mutex;
+
+use crate::clk::{ClockGuard, Peripheral};
+use crate::interrupts::{Binding, Handler, clear_interrupt};
+use crate::ticker::{AGT1, start_millisecond_timer};
+
+/// Number of timeouts that can be pending at once.
+pub const MAX_TIMEOUTS: usize = 16;
+
+/// Longest timeout in milliseconds, longer ones are clamped to this.
+pub const MAX_MS: u32 = (1 << (LEVEL_BITS * LEVELS as u32)) - 1;
+
+const LEVELS: usize = 4;
+const LEVEL_BITS: u32 = 6;
+const SLOTS: usize = 1 << LEVEL_BITS;
+const SLOT_MASK: u32 = SLOTS as u32 - 1;
+// End of a slot list
+const NONE: u8 = 0xFF;
+
+static WHEEL: Mutex<RefCell<Wheel>> = Mutex::new(RefCell::new(Wheel::new()));
+
+/// Timeout error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// [`MAX_TIMEOUTS`] are already pending
+    Full,
+}
+
+/// A pending timeout, to [`cancel`] or [`restart`] it.
+///
+/// Handles of timeouts that expired or were cancelled are stale, using them
+/// does nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Handle {
+    index: u8,
+    generation: u16,
+}
+
+enum Action {
+    Call(fn()),
+    // Wakes a `Timeout`, the waker is set by its first poll
+    Wake(Option<Waker>),
+}
+
+struct Entry {
+    generation: u16,
+    armed: bool,
+    expires: u32,
+    // Level and slot the entry is listed in, and the next in that list
+    level: u8,
+    slot: u8,
+    next: u8,
+    action: Action,
+}
+
+impl Entry {
+    const fn new() -> Self {
+        Entry {
+            generation: 0,
+            armed: false,
+            expires: 0,
+            level: 0,
+            slot: 0,
+            next: NONE,
+            action: Action::Wake(None),
+        }
+    }
+}
+
+struct Wheel {
+    now: u32,
+    entries: [Entry; MAX_TIMEOUTS],
+    heads: [[u8; SLOTS]; LEVELS],
+}
+
+impl Wheel {
+    const fn new() -> Self {
+        Wheel {
+            now: 0,
+            entries: [const { Entry::new() }; MAX_TIMEOUTS],
+            heads: [[NONE; SLOTS]; LEVELS],
+        }
+    }
+
+    fn arm(&mut self, ms: u32, action: Action) -> Result<Handle, Error> {
+        let index = self
+            .entries
+            .iter()
+            .position(|entry| !entry.armed)
+            .ok_or(Error::Full)?;
+        let entry = &mut self.entries[index];
+        entry.generation = entry.generation.wrapping_add(1);
+        entry.armed = true;
+        entry.action = action;
+        let handle = Handle {
+            index: index as u8,
+            generation: entry.generation,
+        };
+        self.insert(index, self.now.wrapping_add(ms.clamp(1, MAX_MS)));
+        Ok(handle)
+    }
+
+    fn get(&self, handle: Handle) -> Option<usize> {
+        let entry = self.entries.get(handle.index as usize)?;
+        (entry.armed && entry.generation == handle.generation).then_some(handle.index as usize)
+    }
+
+    // List an entry in the slot for `expires`, the lowest level it's within
+    fn insert(&mut self, index: usize, expires: u32) {
+        let delta = expires.wrapping_sub(self.now);
+        let level = (0..LEVELS)
+            .find(|level| delta >> (LEVEL_BITS * (*level as u32 + 1)) == 0)
+            .unwrap_or(LEVELS - 1);
+        let slot = ((expires >> (LEVEL_BITS * level as u32)) & SLOT_MASK) as usize;
+        let entry = &mut self.entries[index];
+        entry.expires = expires;
+        entry.level = level as u8;
+        entry.slot = slot as u8;
+        entry.next = self.heads[level][slot];
+        self.heads[level][slot] = index as u8;
+    }
+
+    fn unlink(&mut self, index: usize) {
+        let (level, slot) = {
+            let entry = &self.entries[index];
+            (entry.level as usize, entry.slot as usize)
+        };
+        let next = self.entries[index].next;
+        if self.heads[level][slot] == index as u8 {
+            self.heads[level][slot] = next;
+            return;
+        }
+        let mut i = self.heads[level][slot];
+        while i != NONE {
+            if self.entries[i as usize].next == index as u8 {
+                self.entries[i as usize].next = next;
+                return;
+            }
+            i = self.entries[i as usize].next;
+        }
+    }
+
+    // Take the list of a slot
+    fn take(&mut self, level: usize, slot: usize) -> u8 {
+        core::mem::replace(&mut self.heads[level][slot], NONE)
+    }
+
+    // Advance a millisecond, moving entries down a level as their slot comes
+    // round. Returns the list of expired entries, still armed.
+    fn tick(&mut self) -> u8 {
+        self.now = self.now.wrapping_add(1);
+        for level in 1..LEVELS {
+            // A level's slot comes round when all the levels below wrap
+            if self.now & ((1 << (LEVEL_BITS * level as u32)) - 1) != 0 {
+                break;
+            }
+            let slot = ((self.now >> (LEVEL_BITS * level as u32)) & SLOT_MASK) as usize;
+            let mut i = self.take(level, slot);
+            while i != NONE {
+                let next = self.entries[i as usize].next;
+                let expires = self.entries[i as usize].expires;
+                self.insert(i as usize, expires);
+                i = next;
+            }
+        }
+        self.take(0, (self.now & SLOT_MASK) as usize)
+    }
+}
+
+/// Advances the wheel on each AGT1 underflow and runs expired timeouts.
+pub struct TickHandler;
+
+impl Handler for TickHandler {
+    unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
+        clear_interrupt(interrupt);
+        let mut calls: [Option<fn()>; MAX_TIMEOUTS] = [None; MAX_TIMEOUTS];
+        critical_section::with(|cs| {
+            let mut wheel = WHEEL.borrow_ref_mut(cs);
+            let mut i = wheel.tick();
+            let mut n = 0;
+            while i != NONE {
+                let entry = &mut wheel.entries[i as usize];
+                i = entry.next;
+                entry.armed = false;
+                match core::mem::replace(&mut entry.action, Action::Wake(None)) {
+                    Action::Call(f) => {
+                        calls[n] = Some(f);
+                        n += 1;
+                    }
+                    Action::Wake(waker) => {
+                        if let Some(waker) = waker {
+                            waker.wake();
+                        }
+                    }
+                }
+            }
+        });
+        // Outside the critical section, so callbacks can arm timeouts
+        for f in calls.iter().flatten() {
+            f();
+        }
+    }
+}
+
+/// Start AGT1 ticking the wheel every millisecond from the current PCLKB.
+///
+/// The module stays enabled, timeouts are used for the whole program.
+pub fn init<IRQ: Binding<TickHandler>>(_agt: ra4m1::AGT1, _irq: IRQ) {
+    core::mem::forget(ClockGuard::new(Peripheral::Agt1));
+    start_millisecond_timer(AGT1, <IRQ as Binding<TickHandler>>::interrupt());
+}
+
+/// Call `f` from the tick interrupt after `ms` milliseconds.
+pub fn after_ms(ms: u32, f: fn()) -> Result<Handle, Error> {
+    critical_section::with(|cs| WHEEL.borrow_ref_mut(cs).arm(ms, Action::Call(f)))
+}
+
+/// Stop a timeout, false if it already expired or was cancelled.
+pub fn cancel(handle: Handle) -> bool {
+    critical_section::with(|cs| {
+        let mut wheel = WHEEL.borrow_ref_mut(cs);
+        let Some(index) = wheel.get(handle) else {
+            return false;
+        };
+        wheel.unlink(index);
+        wheel.entries[index].armed = false;
+        true
+    })
+}
+
+/// Set a pending timeout to expire `ms` from now instead, false if it
+/// already expired or was cancelled.
+pub fn restart(handle: Handle, ms: u32) -> bool {
+    critical_section::with(|cs| {
+        let mut wheel = WHEEL.borrow_ref_mut(cs);
+        let Some(index) = wheel.get(handle) else {
+            return false;
+        };
+        wheel.unlink(index);
+        let expires = wheel.now.wrapping_add(ms.clamp(1, MAX_MS));
+        wheel.insert(index, expires);
+        true
+    })
+}
+
+/// Whether a timeout is still pending.
+pub fn is_pending(handle: Handle) -> bool {
+    critical_section::with(|cs| WHEEL.borrow_ref(cs).get(handle).is_some())
+}
+
+/// Future that completes after a delay, cancelled when dropped.
+pub struct Timeout {
+    handle: Handle,
+}
+
+impl Timeout {
+    /// Complete after `ms` milliseconds.
+    pub fn after_ms(ms: u32) -> Result<Self, Error> {
+        let handle =
+            critical_section::with(|cs| WHEEL.borrow_ref_mut(cs).arm(ms, Action::Wake(None)))?;
+        Ok(Self { handle })
+    }
+
+    /// The timeout, e.g. to [`restart`] it.
+    pub fn handle(&self) -> Handle {
+        self.handle
+    }
+}
+
+impl Future for Timeout {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        critical_section::with(|cs| {
+            let mut wheel = WHEEL.borrow_ref_mut(cs);
+            match wheel.get(self.handle) {
+                Some(index) => {
+                    wheel.entries[index].action = Action::Wake(Some(cx.waker().clone()));
+                    Poll::Pending
+                }
+                None => Poll::Ready(()),
+            }
+        })
+    }
+}
+
+impl Drop for Timeout {
+    fn drop(&mut self) {
+        cancel(self.handle);
+    }
+}
@@ -0,0 +1,201 @@
+//! Interrupt handler timing, with the `trace` feature.
+//!
+//! Every handler bound with [`bind_interrupts!`](crate::bind_interrupts)
+//! reads the DWT cycle counter on entry and exit. The time spent in a
+//! handler is the latency it adds to interrupts of the same or lower
+//! priority, so the per-vector minimum, maximum and average show which
+//! handlers to shorten or move to a lower priority. Time spent in higher
+//! priority handlers that preempt it is included.
+//!
+//! The recording is lock-free, so it doesn't change the priorities being
+//! measured: counters are atomics, and each handler claims a slot of a ring
+//! of recent entries with one atomic add.
+//!
+//! ```ignore
+//! trace::start();
+//! // ... run for a while
+//! trace::dump(&mut uart, 48_000_000)?;
+//! ```
+//!
+//! ```text
+//! IEL4: 1200 calls, min 96 max 412 avg 130 cycles, max 8 us
+//! IEL8: 37 calls, min 240 max 2210 avg 502 cycles, max 46 us
+//! ```
+
+use core::sync::atomic::{AtomicU8, AtomicU32, Ordering};
+
+use cortex_m::peripheral::DWT;
+use embedded_io::{Write, WriteFmtError};
+use ra4m1::Interrupt;
+
+/// Number of ICU interrupt vectors, IEL0 - IEL31.
+pub const VECTORS: usize = 32;
+
+/// Number of recent handler runs kept, see [`recent`].
+pub const EVENTS: usize = 64;
+
+struct Stats {
+    count: AtomicU32,
+    min: AtomicU32,
+    max: AtomicU32,
+    // Wraps after 2^32 cycles in total, 89 s at 48 MHz
+    total: AtomicU32,
+}
+
+impl Stats {
+    const fn new() -> Self {
+        Self {
+            count: AtomicU32::new(0),
+            min: AtomicU32::new(u32::MAX),
+            max: AtomicU32::new(0),
+            total: AtomicU32::new(0),
+        }
+    }
+}
+
+struct Slot {
+    interrupt: AtomicU8,
+    entry: AtomicU32,
+    exit: AtomicU32,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            interrupt: AtomicU8::new(0),
+            entry: AtomicU32::new(0),
+            exit: AtomicU32::new(0),
+        }
+    }
+}
+
+static STATS: [Stats; VECTORS] = [const { Stats::new() }; VECTORS];
+static SLOTS: [Slot; EVENTS] = [const { Slot::new() }; EVENTS];
+// Total number of runs recorded in SLOTS, the next slot is this modulo EVENTS
+static NEXT: AtomicU32 = AtomicU32::new(0);
+
+/// Timing of one vector's handler, in CPU cycles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VectorStats {
+    pub count: u32,
+    pub min_cycles: u32,
+    pub max_cycles: u32,
+    pub avg_cycles: u32,
+}
+
+/// One run of a handler, cycle counter values on entry and exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Event {
+    /// Vector number, IELn
+    pub vector: u8,
+    pub entry: u32,
+    pub exit: u32,
+}
+
+impl Event {
+    /// Cycles from entry to exit.
+    pub fn cycles(&self) -> u32 {
+        self.exit.wrapping_sub(self.entry)
+    }
+}
+
+/// Start the DWT cycle counter and clear what was recorded.
+///
+/// Nothing is timed until the counter runs.
+pub fn start() {
+    let mut p = unsafe { cortex_m::Peripherals::steal() };
+    p.DCB.enable_trace();
+    p.DWT.enable_cycle_counter();
+    reset();
+}
+
+/// Clear the statistics and recent runs.
+pub fn reset() {
+    for stats in &STATS {
+        stats.count.store(0, Ordering::Relaxed);
+        stats.min.store(u32::MAX, Ordering::Relaxed);
+        stats.max.store(0, Ordering::Relaxed);
+        stats.total.store(0, Ordering::Relaxed);
+    }
+    NEXT.store(0, Ordering::Relaxed);
+}
+
+/// Current value of the cycle counter, to time other code on the same clock.
+pub fn now() -> u32 {
+    DWT::cycle_count()
+}
+
+// Called on exit from a handler, through `interrupts::TraceGuard`
+pub(crate) fn record(interrupt: Interrupt, entry: u32, exit: u32) {
+    let vector = interrupt as usize;
+    let cycles = exit.wrapping_sub(entry);
+    if let Some(stats) = STATS.get(vector) {
+        stats.count.fetch_add(1, Ordering::Relaxed);
+        stats.min.fetch_min(cycles, Ordering::Relaxed);
+        stats.max.fetch_max(cycles, Ordering::Relaxed);
+        stats.total.fetch_add(cycles, Ordering::Relaxed);
+    }
+    let slot = &SLOTS[NEXT.fetch_add(1, Ordering::Relaxed) as usize % EVENTS];
+    slot.interrupt.store(vector as u8, Ordering::Relaxed);
+    slot.entry.store(entry, Ordering::Relaxed);
+    slot.exit.store(exit, Ordering::Relaxed);
+}
+
+/// Timing of the handler of `interrupt`, `None` if it hasn't run.
+pub fn stats(interrupt: Interrupt) -> Option<VectorStats> {
+    vector_stats(interrupt as usize)
+}
+
+fn vector_stats(vector: usize) -> Option<VectorStats> {
+    let stats = &STATS[vector];
+    let count = stats.count.load(Ordering::Relaxed);
+    if count == 0 {
+        return None;
+    }
+    Some(VectorStats {
+        count,
+        min_cycles: stats.min.load(Ordering::Relaxed),
+        max_cycles: stats.max.load(Ordering::Relaxed),
+        avg_cycles: stats.total.load(Ordering::Relaxed) / count,
+    })
+}
+
+/// Copy the most recent handler runs into `buf`, oldest first, and return
+/// how many were copied.
+///
+/// Runs recorded while copying may overwrite the oldest ones.
+pub fn recent(buf: &mut [Event]) -> usize {
+    let next = NEXT.load(Ordering::Relaxed) as usize;
+    let len = buf.len().min(next).min(EVENTS);
+    for (i, event) in buf[..len].iter_mut().enumerate() {
+        let slot = &SLOTS[(next - len + i) % EVENTS];
+        *event = Event {
+            vector: slot.interrupt.load(Ordering::Relaxed),
+            entry: slot.entry.load(Ordering::Relaxed),
+            exit: slot.exit.load(Ordering::Relaxed),
+        };
+    }
+    len
+}
+
+/// Write one line per vector that has run, with the longest time in
+/// microseconds at `cpu_hz`.
+pub fn dump<W: Write>(out: &mut W, cpu_hz: u32) -> Result<(), WriteFmtError<W::Error>> {
+    let cycles_per_us = (cpu_hz / 1_000_000).max(1);
+    for vector in 0..VECTORS {
+        if let Some(stats) = vector_stats(vector) {
+            out.write_fmt(format_args!(
+                "IEL{}: {} calls, min {} max {} avg {} cycles, max {} us\n",
+                vector,
+                stats.count,
+                stats.min_cycles,
+                stats.max_cycles,
+                stats.avg_cycles,
+                stats.max_cycles.div_ceil(cycles_per_us)
+            ))?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,148 @@
+//! Baud rate detection from a 0x55 character, see [`Uart::autobaud`].
+//!
+//! 0x55, `U`, sent LSB first is a start bit and 8 data bits alternating
+//! with the stop bit, 10 edges 9 bit times apart. A GPT channel captures
+//! the edges on the RX pin, the rate is the one that fits them, snapped to
+//! a [`STANDARD_RATES`] entry within 3%.
+//!
+//! ```ignore
+//! sprintln!("press U");
+//! match uart.autobaud(&mut p.GPT164, 10_000) {
+//!     Some(baud) => sprintln!("{} baud", baud),
+//!     None => sprintln!("no 0x55 received"),
+//! }
+//! ```
+//!
+//! The GPT counts PCLKD and its captures are polled, from 1200 baud up to
+//! about 460800 at a 48 MHz PCLKD.
+
+use super::{Instance, RxPin, SCR_RE, Uart, connect_pin, set_baud_rate};
+use crate::clk::Clocks;
+use crate::gpio;
+use crate::gpt::{self, Prescaler};
+
+/// Rates a measurement is snapped to.
+pub const STANDARD_RATES: [u32; 12] = [
+    1200, 2400, 4800, 9600, 14400, 19200, 38400, 57600, 115200, 230400, 460800, 921600,
+];
+
+// GTST flags, capture A and overflow
+const GTST_TCFA: u32 = 1 << 0;
+const GTST_TCFPO: u32 = 1 << 6;
+// Edges of a 0x55 frame, start bit to stop bit
+const EDGES: usize = 10;
+// Counter wraps without an edge for the line to count as idle
+const IDLE_WRAPS: u32 = 2;
+
+/// GPT channel that can capture the RX pin of SCI channel `T`.
+pub trait AutobaudGpt<T: Instance>: gpt::Instance {
+    /// The RX pin, a GTIOC pin of this channel
+    type Pin: RxPin<T>;
+    /// GTICASR value capturing both edges of the pin into GTCCRA
+    const BOTH_EDGES: u32;
+}
+
+impl AutobaudGpt<ra4m1::SCI2> for ra4m1::GPT164 {
+    type Pin = gpio::P301;
+    // GTIOCB rising (ASCBRAL | ASCBRAH) and falling (ASCBFAL | ASCBFAH)
+    const BOTH_EDGES: u32 = 0b1111 << 12;
+}
+
+impl<T: Instance, const TX: usize, const RX: usize> Uart<T, TX, RX> {
+    /// Measure the baud rate of a 0x55 character sent by the other side
+    /// and switch to it, returning the rate. `None` if none arrived within
+    /// `timeout_ms`, the rate is then unchanged.
+    ///
+    /// The line must be idle before the character. Reception is off and the
+    /// RX pin is routed to `gpt` while measuring, so the character isn't
+    /// received.
+    pub fn autobaud<G: AutobaudGpt<T>>(&mut self, _gpt: &mut G, timeout_ms: u32) -> Option<u32> {
+        self.tx.wait_idle();
+        let sci = unsafe { &*T::peripheral() };
+        let scr = sci.scr().read().bits();
+        critical_section::with(|_| {
+            sci.scr()
+                .modify(|r, w| unsafe { w.bits(r.bits() & !SCR_RE) })
+        });
+
+        let pclkd = Clocks::read().pclkd();
+        let clock = gpt::init::<G>(Prescaler::Div1);
+        let reg = unsafe { &*G::peripheral() };
+        reg.gticasr.write(|w| unsafe { w.bits(G::BOTH_EDGES) });
+        gpt::connect_pin::<G::Pin>();
+        gpt::start::<G>();
+        let timeout_wraps = (timeout_ms as u64 * pclkd as u64 / 1000 / (G::max_count() as u64 + 1))
+            .clamp(1, u32::MAX as u64) as u32;
+        let frame_ticks = measure::<G>(timeout_wraps);
+        gpt::stop::<G>();
+        drop(clock);
+        connect_pin::<T, G::Pin>();
+
+        // 9 bit times from the first to the last edge
+        let baud = frame_ticks.map(|ticks| snap(((pclkd as u64 * 9 + ticks / 2) / ticks) as u32));
+        if let Some(baud) = baud {
+            set_baud_rate::<T>(baud);
+        }
+        critical_section::with(|_| sci.scr().write(|w| unsafe { w.bits(scr) }));
+        baud
+    }
+}
+
+// Ticks from the start bit to the stop bit of a 0x55 frame
+fn measure<G: gpt::Instance>(timeout_wraps: u32) -> Option<u64> {
+    let reg = unsafe { &*G::peripheral() };
+    let max = G::max_count();
+    let mut wraps = 0;
+    let mut last_edge_wrap = 0;
+    // Wait for the line to be idle before taking an edge as a start bit
+    let mut idle = false;
+    let mut edges = [0u32; EDGES];
+    let mut count = 0;
+    while wraps < timeout_wraps {
+        let gtst = reg.gtst.read().bits();
+        if gtst & GTST_TCFPO != 0 {
+            reg.gtst
+                .modify(|r, w| unsafe { w.bits(r.bits() & !GTST_TCFPO) });
+            wraps += 1;
+            if wraps - last_edge_wrap >= IDLE_WRAPS {
+                idle = true;
+                count = 0;
+            }
+        }
+        if gtst & GTST_TCFA == 0 {
+            continue;
+        }
+        reg.gtst
+            .modify(|r, w| unsafe { w.bits(r.bits() & !GTST_TCFA) });
+        let at = reg.gtccra.read().bits();
+        last_edge_wrap = wraps;
+        if !idle {
+            continue;
+        }
+        edges[count] = at;
+        count += 1;
+        if count < EDGES {
+            continue;
+        }
+        count = 0;
+        idle = false;
+        let mut bits = edges
+            .windows(2)
+            .map(|pair| pair[1].wrapping_sub(pair[0]) & max);
+        let total: u64 = bits.clone().map(u64::from).sum();
+        let bit = total / 9;
+        // Every bit close to the average, otherwise it wasn't 0x55
+        if bit > 0 && bits.all(|ticks| (ticks as u64).abs_diff(bit) <= bit / 4) {
+            return Some(total);
+        }
+    }
+    None
+}
+
+// The standard rate within 3% of `baud`, or `baud`
+fn snap(baud: u32) -> u32 {
+    STANDARD_RATES
+        .into_iter()
+        .find(|&rate| baud.abs_diff(rate) * 100 <= rate * 3)
+        .unwrap_or(baud)
+}
@@ -0,0 +1,308 @@
+//! UART receive in chunks through the DMAC, without per-byte interrupts.
+//!
+//! RXI activates a DMAC channel that moves each byte into one of two
+//! buffers. The buffers alternate: when one fills, or the line has been idle
+//! for [`Config::idle_ms`] with data in it, the DMAC is switched to the other
+//! and the received bytes are handed to a callback. For high rate protocols
+//! the CPU then handles one interrupt per chunk rather than per byte.
+//!
+//! The SCI has no idle line detection, so the idle check polls the DMAC on
+//! the [`timeout`](crate::timeout) wheel, which must be started first.
+//!
+//! ```ignore
+//! bind_interrupts!(struct Irq {
+//!     IEL7 => uart::ERI_Handler<ra4m1::SCI2>;
+//!     IEL11 => uart::chunked::DmaEndHandler<ra4m1::SCI2, ra4m1::DMAC0>;
+//! });
+//!
+//! let buffers = cortex_m::singleton!(: [[u8; 256]; 2] = [[0; 256]; 2]).unwrap();
+//! let rx = ChunkedRx::new(p.SCI2, pins.p301, p.DMAC0, buffers, Config::default(), on_chunk, Irq)?;
+//! ```
+//!
+//! The callback runs from the DMA end interrupt or the tick interrupt of the
+//! timeout wheel, and must finish with the chunk before the other buffer
+//! fills. Only one chunked receiver can run at a time.
+
+use core::cell::Cell;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicU32, AtomicUsize, Ordering};
+
+use critical_section::Mutex;
+
+use super::{
+    ERI_Handler, Error, Instance, RxPin, SSR_FER, SSR_FLAGS, SSR_ORER, SSR_PER, connect_pin,
+    enable_module, set_baud_rate,
+};
+use crate::clk::ClockGuard;
+use crate::dmac::{self, Channel};
+use crate::interrupts::{Binding, Handler, clear_interrupt, map_and_enable_interrupt};
+use crate::timeout::{self, Handle};
+
+/// Receiver configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Baud rate, the frame format is 8N1
+    pub baud: u32,
+    /// Time without a byte after which a partly filled buffer is handed
+    /// over, in milliseconds
+    pub idle_ms: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            baud: 115_200,
+            idle_ms: 2,
+        }
+    }
+}
+
+struct State {
+    buffers: [AtomicPtr<u8>; 2],
+    len: AtomicUsize,
+    // Buffer the DMAC is writing to
+    active: AtomicU8,
+    // DMAC destination at the last idle check
+    last_dest: AtomicUsize,
+    idle_ms: AtomicU32,
+    running: AtomicBool,
+}
+
+static STATE: State = State {
+    buffers: [
+        AtomicPtr::new(core::ptr::null_mut()),
+        AtomicPtr::new(core::ptr::null_mut()),
+    ],
+    len: AtomicUsize::new(0),
+    active: AtomicU8::new(0),
+    last_dest: AtomicUsize::new(0),
+    idle_ms: AtomicU32::new(0),
+    running: AtomicBool::new(false),
+};
+
+static CALLBACK: Mutex<Cell<Option<fn(&[u8])>>> = Mutex::new(Cell::new(None));
+static IDLE_TIMEOUT: Mutex<Cell<Option<Handle>>> = Mutex::new(Cell::new(None));
+
+// SCR bits
+const SCR_RE: u8 = 1 << 4;
+const SCR_RIE: u8 = 1 << 6;
+// SSR.RDRF, a byte is waiting in RDR
+const SSR_RDRF: u8 = 1 << 6;
+
+// Address of RDR, used as the DMA source
+fn rdr<T: Instance>() -> *const u8 {
+    (T::peripheral() as usize + 0x05) as *const u8
+}
+
+// Point the DMAC at buffer `index` from `offset`
+fn start_buffer<T: Instance, C: Channel>(index: u8, offset: usize) {
+    let dest = unsafe {
+        STATE.buffers[index as usize]
+            .load(Ordering::Relaxed)
+            .add(offset)
+    };
+    let len = STATE.len.load(Ordering::Relaxed);
+    STATE.active.store(index, Ordering::Relaxed);
+    STATE.last_dest.store(dest as usize, Ordering::Relaxed);
+    // Safety: the buffers are 'static and only written by the DMAC while active
+    unsafe { dmac::start_read::<C>(rdr::<T>(), dest, len - offset) };
+    dmac::enable_end_interrupt::<C>();
+}
+
+// Move the DMAC to the other buffer and return the bytes received in the
+// one it was writing, `None` if it was empty. A byte left in RDR while the
+// channel was stopped is added to the old buffer, or the new one when full.
+fn swap<T: Instance, C: Channel>() -> Option<&'static [u8]> {
+    let len = STATE.len.load(Ordering::Relaxed);
+    dmac::stop(C::index());
+    let received = len - dmac::remaining(C::index()).min(len);
+    let old = STATE.active.load(Ordering::Relaxed);
+    let buffer = STATE.buffers[old as usize].load(Ordering::Relaxed);
+    let next = STATE.buffers[old as usize ^ 1].load(Ordering::Relaxed);
+
+    let sci = unsafe { &*T::peripheral() };
+    let pending = (sci.ssr().read().bits() & SSR_RDRF != 0).then(|| {
+        let byte = unsafe { rdr::<T>().read_volatile() };
+        sci.ssr()
+            .modify(|r, w| unsafe { w.bits((r.bits() | SSR_FLAGS) & !SSR_RDRF) });
+        byte
+    });
+    let mut count = received;
+    let mut offset = 0;
+    match pending {
+        Some(byte) if count < len => {
+            unsafe { buffer.add(count).write(byte) };
+            count += 1;
+        }
+        Some(byte) => {
+            // The new buffer's first byte, the DMAC continues after it
+            unsafe { next.write(byte) };
+            offset = 1;
+        }
+        None => {}
+    }
+    start_buffer::<T, C>(old ^ 1, offset);
+
+    // Safety: the DMAC now writes the other buffer, this one is left alone
+    // until the next swap
+    (count > 0).then(|| unsafe { core::slice::from_raw_parts(buffer, count) })
+}
+
+fn hand_over(chunk: Option<&[u8]>) {
+    let callback = critical_section::with(|cs| CALLBACK.borrow(cs).get());
+    if let (Some(chunk), Some(callback)) = (chunk, callback) {
+        callback(chunk);
+    }
+}
+
+// Run from the timeout wheel every `idle_ms`, swaps when the DMAC hasn't
+// moved since the last check and has written something
+fn check_idle<T: Instance, C: Channel>() {
+    if !STATE.running.load(Ordering::Relaxed) {
+        return;
+    }
+    let chunk = critical_section::with(|_| {
+        let dest = dmac::destination(C::index());
+        let start = STATE.buffers[STATE.active.load(Ordering::Relaxed) as usize]
+            .load(Ordering::Relaxed) as usize;
+        let last = STATE.last_dest.swap(dest, Ordering::Relaxed);
+        if dest == last && dest != start {
+            swap::<T, C>()
+        } else {
+            None
+        }
+    });
+    hand_over(chunk);
+    let idle_ms = STATE.idle_ms.load(Ordering::Relaxed);
+    let handle = timeout::after_ms(idle_ms, check_idle::<T, C>).ok();
+    critical_section::with(|cs| IDLE_TIMEOUT.borrow(cs).set(handle));
+}
+
+/// Triggers when a buffer is full, hands it over and continues in the other.
+pub struct DmaEndHandler<T: Instance, C: Channel> {
+    _phantom: PhantomData<(T, C)>,
+}
+
+impl<T: Instance, C: Channel> Handler for DmaEndHandler<T, C> {
+    unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
+        clear_interrupt(interrupt);
+        dmac::clear_end_flag::<C>();
+        // The idle check may have swapped while this was pending
+        let chunk = critical_section::with(|_| {
+            (dmac::remaining(C::index()) == 0)
+                .then(swap::<T, C>)
+                .flatten()
+        });
+        hand_over(chunk);
+    }
+}
+
+/// UART receiver handing over data in chunks.
+pub struct ChunkedRx<T: Instance, C: Channel, const N: usize> {
+    _buffers: &'static mut [[u8; N]; 2],
+    _clock: ClockGuard,
+    _phantom: PhantomData<(T, C)>,
+}
+
+impl<T: Instance, C: Channel, const N: usize> ChunkedRx<T, C, N> {
+    /// Start receiving into `buffers`, calling `on_chunk` with each chunk.
+    ///
+    /// `N` is the largest chunk and at most [`dmac::MAX_TRANSFER`]. Fails if
+    /// the timeout wheel has no room for the idle check.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<RXD: RxPin<T>, IRQ>(
+        _instance: T,
+        _rx: RXD,
+        _dma: C,
+        buffers: &'static mut [[u8; N]; 2],
+        config: Config,
+        on_chunk: fn(&[u8]),
+        _irq: IRQ,
+    ) -> Result<Self, timeout::Error>
+    where
+        IRQ: Binding<DmaEndHandler<T, C>> + Binding<ERI_Handler<T>>,
+    {
+        const { assert!(N >= 2 && N <= dmac::MAX_TRANSFER, "chunk size out of range") };
+        let clock = enable_module::<T>();
+        let sci = unsafe { &*T::peripheral() };
+        sci.scr().write(|w| unsafe { w.bits(0) });
+        sci.simr1.write(|w| w.iicm()._0());
+        sci.spmr.write(|w| unsafe { w.bits(0) });
+        // 8 data bits, no parity, 1 stop bit
+        sci.smr().write(|w| unsafe { w.bits(0) });
+        sci.scmr
+            .write(|w| w.smif()._0().sinv()._0().sdir()._0().chr1()._1());
+        sci.semr.write(|w| unsafe { w.bits(0) });
+        set_baud_rate::<T>(config.baud);
+        connect_pin::<T, RXD>();
+
+        T::state().errors.store(0, Ordering::Relaxed);
+        critical_section::with(|cs| CALLBACK.borrow(cs).set(Some(on_chunk)));
+        STATE.buffers[0].store(buffers[0].as_mut_ptr(), Ordering::Relaxed);
+        STATE.buffers[1].store(buffers[1].as_mut_ptr(), Ordering::Relaxed);
+        STATE.len.store(N, Ordering::Relaxed);
+        STATE
+            .idle_ms
+            .store(config.idle_ms.max(1), Ordering::Relaxed);
+
+        dmac::enable();
+        dmac::link_event::<C>(T::event_base());
+        map_and_enable_interrupt(
+            <IRQ as Binding<DmaEndHandler<T, C>>>::interrupt(),
+            C::event(),
+        );
+        map_and_enable_interrupt(
+            <IRQ as Binding<ERI_Handler<T>>>::interrupt(),
+            T::event_base().offset(3),
+        );
+        start_buffer::<T, C>(0, 0);
+
+        STATE.running.store(true, Ordering::Relaxed);
+        let handle = timeout::after_ms(config.idle_ms.max(1), check_idle::<T, C>)?;
+        critical_section::with(|cs| IDLE_TIMEOUT.borrow(cs).set(Some(handle)));
+        // RXI activates the DMAC, it isn't mapped to a CPU interrupt
+        sci.scr()
+            .modify(|r, w| unsafe { w.bits(r.bits() | SCR_RE | SCR_RIE) });
+
+        Ok(Self {
+            _buffers: buffers,
+            _clock: clock,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Hand over what has been received so far without waiting for the
+    /// line to go idle.
+    pub fn flush(&mut self) {
+        let chunk = critical_section::with(|_| swap::<T, C>());
+        hand_over(chunk);
+    }
+
+    /// The receive error seen since the last call, see
+    /// [`UartRx::take_error`](super::UartRx::take_error).
+    pub fn take_error(&mut self) -> Option<Error> {
+        let errors = T::state().errors.swap(0, Ordering::Relaxed);
+        if errors & SSR_ORER != 0 {
+            Some(Error::Overrun)
+        } else if errors & SSR_FER != 0 {
+            Some(Error::Framing)
+        } else if errors & SSR_PER != 0 {
+            Some(Error::Parity)
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: Instance, C: Channel, const N: usize> Drop for ChunkedRx<T, C, N> {
+    fn drop(&mut self) {
+        STATE.running.store(false, Ordering::Relaxed);
+        let sci = unsafe { &*T::peripheral() };
+        sci.scr().write(|w| unsafe { w.bits(0) });
+        dmac::stop(C::index());
+        if let Some(handle) = critical_section::with(|cs| IDLE_TIMEOUT.borrow(cs).take()) {
+            timeout::cancel(handle);
+        }
+        critical_section::with(|cs| CALLBACK.borrow(cs).set(None));
+    }
+}
@@ -1,15 +1,131 @@
+//! Serial Communications Interface (SCI) in asynchronous mode (UART)
+//!
+//! Interrupt driven, with ring buffers for both directions. Multiprocessor
+//! mode is supported for multi-drop buses, see [`Uart::set_multiprocessor`].
+//!
+//! The buffer sizes are part of the driver type, `Uart<SCI2, 128, 16>` has a
+//! 128 byte transmit and 16 byte receive buffer. Each SCI channel has
+//! [`BUFFER_CAPACITY`] bytes of static storage that the two are taken from,
+//! sizes that don't fit fail to compile. [`UartTx::stats`] and
+//! [`UartRx::stats`] report the most each buffer has held, to size them from
+//! measurements.
+//!
+//! The RA4M1 SCI has no IrDA interface, unlike the SCI of the RX family,
+//! so there is no IrDA option. An IrDA SIR transceiver needs an external
+//! encoder / decoder between it and TXD / RXD, which then look like a plain
+//! UART to this driver.
+//!
+//! Reads and writes go through the `embedded_io` traits. The
+//! `embedded-hal-02` feature adds the non-blocking embedded-hal 0.2
+//! `serial::Read` and `serial::Write` and the blocking
+//! `blocking::serial::Write`, for older driver crates.
+
+use core::cell::{Cell, RefCell, UnsafeCell};
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicU8, AtomicU16, AtomicU32, AtomicUsize, Ordering};
+use core::task::{Poll, Waker};
+
+use critical_section::Mutex;
+
 use embassy_hal_internal::atomic_ring_buffer::RingBuffer;
-use ra4m1::{SCI2, sci2};
+use ra4m1::{SCI0, SCI1, SCI2, SCI9, sci2};
+
+use cortex_m::peripheral::SCB;
+use cortex_m::peripheral::scb::VectActive;
+
+use crate::clk::{ClockGuard, Clocks, Peripheral};
+use crate::events::Event;
+use crate::gpio::{self, Pin};
+use crate::interrupts::{Binding, Handler, clear_interrupt, map_interrupt};
 
-use crate::interrupts::{Binding, Handler};
+#[cfg(feature = "gpt")]
+pub mod autobaud;
+pub mod chunked;
+pub mod shared;
 
 /// An SCI UART instance.
 pub trait Instance {
     // Get access to the peripheral's register block.
     fn peripheral() -> *const sci2::RegisterBlock;
     fn state() -> &'static State;
-    // Event ID of first event in this instance (RXI)
-    fn event_base() -> u8;
+    fn storage() -> &'static Storage;
+    // First event of this instance (RXI)
+    fn event_base() -> Event;
+    // Channel number, SCIn
+    fn channel() -> u8;
+}
+
+/// Pin with a function of SCI channel `T`, see [`TxPin`] and [`RxPin`].
+pub trait SciPin<T: Instance>: Pin {
+    // PSEL is 0b00101 rather than 0b00100, usually the odd channels but
+    // e.g. TXD2 on P102 too
+    #[doc(hidden)]
+    const ODD_PSEL: bool;
+}
+
+/// Pin that can be used as TXD of SCI channel `T`.
+pub trait TxPin<T: Instance>: SciPin<T> {}
+
+/// Pin that can be used as RXD of SCI channel `T`.
+pub trait RxPin<T: Instance>: SciPin<T> {}
+
+macro_rules! impl_pins {
+    ($($pin:ident => $inst:ident $kind:ident $psel:ident;)*) => {
+        $(
+            impl SciPin<$inst> for gpio::$pin {
+                const ODD_PSEL: bool = impl_pins!(@$psel);
+            }
+            impl $kind<$inst> for gpio::$pin {}
+        )*
+    };
+    (@even) => { false };
+    (@odd) => { true };
+}
+
+// From the RA4M1 pin function tables, pins of the 64 pin package
+impl_pins! {
+    P100 => SCI0 RxPin even;
+    P104 => SCI0 RxPin even;
+    P206 => SCI0 RxPin even;
+    P410 => SCI0 RxPin even;
+    P101 => SCI0 TxPin even;
+    P205 => SCI0 TxPin even;
+    P411 => SCI0 TxPin even;
+    P212 => SCI1 RxPin odd;
+    P402 => SCI1 RxPin odd;
+    P502 => SCI1 RxPin odd;
+    P213 => SCI1 TxPin odd;
+    P401 => SCI1 TxPin odd;
+    P501 => SCI1 TxPin odd;
+    P301 => SCI2 RxPin even;
+    P302 => SCI2 TxPin even;
+    P112 => SCI2 TxPin even;
+    P102 => SCI2 TxPin odd;
+    P110 => SCI9 RxPin odd;
+    P408 => SCI9 RxPin odd;
+    P109 => SCI9 TxPin odd;
+    P409 => SCI9 TxPin odd;
+}
+
+/// Release the SCI channel from the module stop state.
+pub(crate) fn enable_module<T: Instance>() -> ClockGuard {
+    let peripheral = match T::channel() {
+        0 => Peripheral::Sci0,
+        1 => Peripheral::Sci1,
+        2 => Peripheral::Sci2,
+        _ => Peripheral::Sci9,
+    };
+    ClockGuard::new(peripheral)
+}
+
+/// Route a pin to the SCI channel `T`.
+pub(crate) fn connect_pin<T: Instance, P: SciPin<T>>() {
+    let function = if P::ODD_PSEL {
+        gpio::PinFunction::SciOdd
+    } else {
+        gpio::PinFunction::SciEven
+    };
+    gpio::set_function::<P>(function);
 }
 
 pub struct TXI_Handler<T: Instance> {
@@ -19,20 +135,15 @@ pub struct TXI_Handler<T: Instance> {
 impl<T: Instance> Handler for TXI_Handler<T> {
     unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
         let sci = unsafe { &*T::peripheral() };
-        // clear the interrupt flag
-        let p = unsafe { ra4m1::Peripherals::steal() };
-        p.ICU.ielsr[interrupt as usize].modify(|_, w| w.ir()._0());
-        // Grab a byte from the transmit buffer
+        clear_interrupt(interrupt);
+        // A write from a higher priority context may have filled TDR or
+        // sent the rest of the buffer by polling while this was pending
+        let ssr = sci.ssr().read();
+        if sci.scr().read().tie().bit_is_clear() || ssr.tdre().bit_is_clear() {
+            return;
+        }
         let state = T::state();
-        let mut reader = unsafe { state.tx_buf.reader() };
-
-        let data = reader.pop_slice();
-
-        if !data.is_empty() {
-            // Write the byte to the transmit data register
-            sci.tdr.write(|w| unsafe { w.bits(data[0]) });
-            // Inform the reader that we popped a byte
-            reader.pop_done(1);
+        if write_next::<T>(sci, ssr.bits()) {
             // Check the buffer len here not the reader slice as the
             // reader slice may be a single byte at the end of the buffer
             if state.tx_buf.is_empty() {
@@ -41,7 +152,7 @@ impl<T: Instance> Handler for TXI_Handler<T> {
             }
         } else {
             // This shouldnt happen, but if it does, disable the TX interrupts
-            sci.scr().modify(|_, w| w.tie()._0().teie()._0().te()._0());
+            end_transmit::<T>(sci);
         }
     }
 }
@@ -52,12 +163,14 @@ pub struct TEI_Handler<T: Instance> {
 
 impl<T: Instance> Handler for TEI_Handler<T> {
     unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
-        // Clear the interrupt flag
-        let p = unsafe { ra4m1::Peripherals::steal() };
-        p.ICU.ielsr[interrupt as usize].modify(|_, w| w.ir()._0());
-        // Disable the TEI and TX interrupts and end transmission
+        clear_interrupt(interrupt);
+        // Disable the TEI and TX interrupts and end transmission.
+        // Skip if TEIE is already clear, as a non-blocking write may have
+        // ended the transmission itself and started a new one.
         let sci = unsafe { &*T::peripheral() };
-        sci.scr().modify(|_, w| w.teie()._0().tie()._0().te()._0());
+        if sci.scr().read().teie().bit_is_set() {
+            end_transmit::<T>(sci);
+        }
     }
 }
 
@@ -67,19 +180,31 @@ pub struct RXI_Handler<T: Instance> {
 
 impl<T: Instance> Handler for RXI_Handler<T> {
     unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
-        // Clear the interrupt flag
-        let p = unsafe { ra4m1::Peripherals::steal() };
-        p.ICU.ielsr[interrupt as usize].modify(|_, w| w.ir()._0());
+        clear_interrupt(interrupt);
         // Get data, do stuff
         let sci = unsafe { &*T::peripheral() };
         let state = T::state();
+        let ssr = sci.ssr().read().bits();
         let byte = sci.rdr.read().bits();
+        let address = state.mp_address.load(Ordering::Relaxed);
+        if address != NO_ADDRESS && ssr & SSR_MPB != 0 {
+            // An ID frame. The hardware cleared MPIE to deliver it, set it
+            // again to skip the data that follows unless it's for us.
+            if byte as u16 != address {
+                sci.scr()
+                    .modify(|r, w| unsafe { w.bits(r.bits() | SCR_MPIE) });
+            }
+            return;
+        }
         // Get writer for the RX buffer
         let mut writer = unsafe { state.rx_buf.writer() };
-        // Try write to buffer
-        // Should probably indicate the user if this fails
-        // indicating a buffer overflow
-        writer.push_one(byte);
+        // Try write to buffer, reported by `take_error` if full
+        if writer.push_one(byte) {
+            state.rx_usage.pushed(1);
+        } else {
+            state.errors.fetch_or(ERROR_BUFFER_FULL, Ordering::Relaxed);
+            state.rx_usage.dropped(1);
+        }
     }
 }
 
@@ -89,18 +214,42 @@ pub struct ERI_Handler<T: Instance> {
 
 impl<T: Instance> Handler for ERI_Handler<T> {
     unsafe fn on_interrupt(interrupt: ra4m1::Interrupt) {
-        // Clear the interrupt flag
-        let p = unsafe { ra4m1::Peripherals::steal() };
-        p.ICU.ielsr[interrupt as usize].modify(|_, w| w.ir()._0());
-        // Clear error flags
+        clear_interrupt(interrupt);
+        // Record and clear error flags
         let sci = unsafe { &*T::peripheral() };
-        sci.ssr().modify(|_, w| w.orer()._0().fer()._0().per()._0());
+        let ssr = sci.ssr().read().bits();
+        let errors = ssr & (SSR_ORER | SSR_FER | SSR_PER);
+        let state = T::state();
+        state.errors.fetch_or(errors, Ordering::Relaxed);
+        // An overrun loses the byte that arrived before RDR was read
+        if errors & SSR_ORER != 0 {
+            state.rx_usage.dropped(1);
+        }
+        // Clear them from the value read, rather than reading SSR again
+        sci.ssr()
+            .write(|w| unsafe { w.bits((ssr | SSR_FLAGS) & !errors) });
     }
 }
 
+// Atomics and critical section cells, so shared with the handlers without
+// unsafe Send / Sync impls. The ring buffers allow one reader and one writer
+// at a time, which `&mut self` on the driver methods and the single handler
+// for each direction ensure.
 struct State {
     tx_buf: RingBuffer,
     rx_buf: RingBuffer,
+    tx_usage: Usage,
+    rx_usage: Usage,
+    // Own multiprocessor address, NO_ADDRESS when not in multiprocessor mode
+    mp_address: AtomicU16,
+    // Address to send as an ID frame before the buffered data
+    mp_pending: AtomicU16,
+    // Receive errors since the last `take_error`, SSR flags and ERROR_BUFFER_FULL
+    errors: AtomicU8,
+    // Called and woken when a transmission ends, from `on_idle` and
+    // `flush_async`
+    on_idle: Mutex<Cell<Option<fn()>>>,
+    idle_waker: Mutex<RefCell<Option<Waker>>>,
 }
 
 impl State {
@@ -108,43 +257,170 @@ impl State {
         State {
             tx_buf: RingBuffer::new(),
             rx_buf: RingBuffer::new(),
+            tx_usage: Usage::new(),
+            rx_usage: Usage::new(),
+            mp_address: AtomicU16::new(NO_ADDRESS),
+            mp_pending: AtomicU16::new(NO_ADDRESS),
+            errors: AtomicU8::new(0),
+            on_idle: Mutex::new(Cell::new(None)),
+            idle_waker: Mutex::new(RefCell::new(None)),
+        }
+    }
+}
+
+/// Use of a transmit or receive buffer, from [`UartTx::stats`] and
+/// [`UartRx::stats`], for sizing the buffers from measurements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BufferStats {
+    /// Size of the buffer in bytes
+    pub capacity: usize,
+    /// Bytes in the buffer now
+    pub len: usize,
+    /// Most bytes in the buffer at once since the driver was created
+    pub high_water: usize,
+    /// Bytes lost since the driver was created. Received bytes arriving to
+    /// a full buffer or overrun, or bytes [`UartTx::try_write`] and
+    /// [`UartTx::try_write_vectored`] had no room for
+    pub dropped: u32,
+}
+
+// Fill level, watermark and losses of a ring buffer, which doesn't report
+// its fill level itself. Counted up before the bytes are pushed and down
+// before they're popped, so the level never falls below 0 when a handler
+// preempts between the two.
+struct Usage {
+    capacity: AtomicUsize,
+    len: AtomicUsize,
+    high_water: AtomicUsize,
+    dropped: AtomicU32,
+}
+
+impl Usage {
+    const fn new() -> Self {
+        Usage {
+            capacity: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            high_water: AtomicUsize::new(0),
+            dropped: AtomicU32::new(0),
+        }
+    }
+
+    // Start counting for an empty buffer of `capacity` bytes
+    fn init(&self, capacity: usize) {
+        self.capacity.store(capacity, Ordering::Relaxed);
+        self.len.store(0, Ordering::Relaxed);
+        self.high_water.store(0, Ordering::Relaxed);
+        self.dropped.store(0, Ordering::Relaxed);
+    }
+
+    fn pushed(&self, n: usize) {
+        let len = self.len.fetch_add(n, Ordering::Relaxed) + n;
+        self.high_water.fetch_max(len, Ordering::Relaxed);
+    }
+
+    fn popped(&self, n: usize) {
+        self.len.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    fn dropped(&self, n: usize) {
+        self.dropped.fetch_add(n as u32, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> BufferStats {
+        BufferStats {
+            capacity: self.capacity.load(Ordering::Relaxed),
+            len: self.len.load(Ordering::Relaxed),
+            high_water: self.high_water.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
         }
     }
 }
 
-unsafe impl Send for State {}
-unsafe impl Sync for State {}
+/// Bytes of buffer storage of each SCI channel, shared by the transmit and
+/// receive buffers.
+pub const BUFFER_CAPACITY: usize = 512;
+
+// Static backing for the ring buffers of one channel. Only the driver of the
+// channel, which owns the peripheral, hands it out.
+struct Storage(UnsafeCell<[u8; BUFFER_CAPACITY]>);
 
-unsafe impl Sync for Uart<SCI2> {}
-unsafe impl Send for Uart<SCI2> {}
+unsafe impl Sync for Storage {}
+
+impl Storage {
+    const fn new() -> Self {
+        Storage(UnsafeCell::new([0; BUFFER_CAPACITY]))
+    }
+}
+
+const NO_ADDRESS: u16 = 0xFFFF;
+// Unused bit of SSR, as the errors are kept in SSR layout
+const ERROR_BUFFER_FULL: u8 = 1 << 2;
 
 /// Interface for UART operations.
-pub struct Uart<T: Instance> {
+///
+/// The driver and its halves are `Send` when the peripheral type is, so
+/// they can be moved to the context that uses them, e.g. an RTIC resource.
+/// All methods that use the buffers take `&mut self`, so sharing one between
+/// priorities needs a lock.
+pub struct Uart<T: Instance, const TX: usize, const RX: usize> {
     tx: UartTx<T>,
     rx: UartRx<T>,
 }
 
 pub struct UartTx<T: Instance> {
     state: &'static State,
+    _clock: ClockGuard,
     _phantom: core::marker::PhantomData<T>,
 }
 
-unsafe impl<T: Instance> Send for UartTx<T> {}
-unsafe impl<T: Instance> Sync for UartTx<T> {}
-
 pub struct UartRx<T: Instance> {
     state: &'static State,
+    _clock: ClockGuard,
     _phantom: core::marker::PhantomData<T>,
 }
 
-impl<T: Instance> Uart<T> {
-    pub fn new<IRQ>(_instance: T, tx_buf: &mut [u8], rx_buf: &mut [u8], _irq: IRQ) -> Self
+impl<T: Instance, const TX: usize, const RX: usize> Uart<T, TX, RX> {
+    /// Size of the transmit buffer in bytes.
+    pub const TX_CAPACITY: usize = TX;
+    /// Size of the receive buffer in bytes.
+    pub const RX_CAPACITY: usize = RX;
+
+    /// Create a new UART driver with a `TX` byte transmit buffer and `RX`
+    /// byte receive buffer.
+    ///
+    /// `rx` and `tx` can be any pins with the RXD and TXD functions of the
+    /// SCI channel, other pins don't compile. On the UNO R4 D0 / D1 are P301
+    /// and P302 on SCI2, which can also transmit on D5 (P102) or D10 (P112).
+    /// Other channels keep D0 / D1 free, e.g. SCI9 on D12 / D11 (P110 /
+    /// P109), or SCI1 transmitting on P401, the SDA pad of the Qwiic
+    /// connector on the UNO R4 WiFi.
+    ///
+    /// The buffers are taken from the channel's static storage, so together
+    /// they must fit in [`BUFFER_CAPACITY`]:
+    ///
+    /// ```ignore
+    /// let uart: Uart<SCI2, 128, 16> = Uart::new(p.SCI2, pins.p301, pins.p302, Irq);
+    /// ```
+    pub fn new<RXD: RxPin<T>, TXD: TxPin<T>, IRQ>(
+        _instance: T,
+        _rx: RXD,
+        _tx: TXD,
+        _irq: IRQ,
+    ) -> Self
     where
         IRQ: Binding<TEI_Handler<T>>
             + Binding<TXI_Handler<T>>
             + Binding<RXI_Handler<T>>
             + Binding<ERI_Handler<T>>,
     {
+        const {
+            assert!(TX > 0 && RX > 0, "UART buffers can't be empty");
+            assert!(
+                TX + RX <= BUFFER_CAPACITY,
+                "UART buffers don't fit in BUFFER_CAPACITY"
+            );
+        }
         let sci = unsafe { &*T::peripheral() };
         let state = T::state();
 
@@ -161,28 +437,36 @@ impl<T: Instance> Uart<T> {
             ra4m1::NVIC::unmask(tei);
             ra4m1::NVIC::unmask(eri);
         }
-        let p = unsafe { ra4m1::Peripherals::steal() };
-        // Event number of RXI
+        // Map RXI, TXI, TEI and ERI to their interrupts
         let event_base = T::event_base();
-        // Map events to interrupts
-        p.ICU.ielsr[rxi as usize].write(|w| unsafe { w.iels().bits(event_base) });
-        p.ICU.ielsr[txi as usize].write(|w| unsafe { w.iels().bits(event_base + 1) });
-        p.ICU.ielsr[tei as usize].write(|w| unsafe { w.iels().bits(event_base + 2) });
-        p.ICU.ielsr[eri as usize].write(|w| unsafe { w.iels().bits(event_base + 3) });
-
-        // Initialise the buffers
-        unsafe { state.tx_buf.init(tx_buf.as_mut_ptr(), tx_buf.len()) };
-        unsafe { state.rx_buf.init(rx_buf.as_mut_ptr(), rx_buf.len()) };
+        map_interrupt(rxi, event_base);
+        map_interrupt(txi, event_base.offset(1));
+        map_interrupt(tei, event_base.offset(2));
+        map_interrupt(eri, event_base.offset(3));
+
+        // Initialise the buffers, transmit then receive in the storage. Owning
+        // the peripheral means no other driver is using it.
+        let storage = T::storage().0.get() as *mut u8;
+        unsafe { state.tx_buf.init(storage, TX) };
+        unsafe { state.rx_buf.init(storage.add(TX), RX) };
+        state.tx_usage.init(TX);
+        state.rx_usage.init(RX);
         // Configure the SCI peripheral
-        init(&p, sci);
+        let clock = init::<T>(sci);
+        connect_pin::<T, RXD>();
+        connect_pin::<T, TXD>();
+        // Start receiving with interrupts
+        sci.scr().modify(|_, w| w.re()._1().rie()._1());
 
         Self {
             tx: UartTx {
                 state,
+                _clock: clock.clone(),
                 _phantom: core::marker::PhantomData,
             },
             rx: UartRx {
                 state,
+                _clock: clock,
                 _phantom: core::marker::PhantomData,
             },
         }
@@ -192,14 +476,653 @@ impl<T: Instance> Uart<T> {
     pub fn split(self) -> (UartTx<T>, UartRx<T>) {
         (self.tx, self.rx)
     }
+
+    /// Change the baud rate, calculated from the current PCLKB frequency.
+    ///
+    /// Waits for a transmission in progress to finish. A byte being
+    /// received while the rate changes is lost. Set the rate again after
+    /// changing the system clock.
+    pub fn set_baud_rate(&mut self, baud: u32) {
+        self.tx.wait_idle();
+        set_baud_rate::<T>(baud);
+    }
+
+    /// Wait until the transmit buffer is empty and the final stop bit has been sent.
+    pub fn wait_idle(&self) {
+        self.tx.wait_idle();
+    }
+
+    /// See [`UartTx::flush_nb`].
+    pub fn flush_nb(&mut self) -> nb::Result<(), Error> {
+        self.tx.flush_nb()
+    }
+
+    /// See [`UartTx::flush_async`].
+    pub async fn flush_async(&mut self) {
+        self.tx.flush_async().await
+    }
+
+    /// See [`UartTx::stats`].
+    pub fn tx_stats(&self) -> BufferStats {
+        self.tx.stats()
+    }
+
+    /// See [`UartRx::stats`].
+    pub fn rx_stats(&self) -> BufferStats {
+        self.rx.stats()
+    }
+
+    /// See [`UartTx::on_idle`].
+    pub fn on_idle(&mut self, callback: Option<fn()>) {
+        self.tx.on_idle(callback);
+    }
+
+    /// Apply the baud rate and frame format of `config` at once, as
+    /// [`Config::render`] gives them.
+    ///
+    /// Waits for a transmission in progress to finish.
+    pub fn configure(&mut self, config: &Config) {
+        self.tx.wait_idle();
+        let image = config.render();
+        T::state().mp_address.store(
+            config.address.map_or(NO_ADDRESS, u16::from),
+            Ordering::Relaxed,
+        );
+        reconfigure::<T>(|sci| {
+            sci.smr().write(|w| unsafe { w.bits(image.smr) });
+            sci.scmr.write(|w| unsafe { w.bits(image.scmr) });
+            sci.semr.write(|w| unsafe { w.bits(image.semr) });
+            sci.brr.write(|w| unsafe { w.brr().bits(image.brr) });
+        });
+        critical_section::with(|_| {
+            let sci = unsafe { &*T::peripheral() };
+            sci.scr().modify(|r, w| unsafe {
+                w.bits(if config.address.is_some() {
+                    r.bits() | SCR_MPIE
+                } else {
+                    r.bits() & !SCR_MPIE
+                })
+            });
+        });
+    }
+
+    /// Change the parity and number of stop bits, data is always 8 bits.
+    ///
+    /// Waits for a transmission in progress to finish.
+    pub fn set_format(&mut self, parity: Parity, stop_bits: StopBits) {
+        self.tx.wait_idle();
+        reconfigure::<T>(|sci| {
+            let invert = sci.scmr.read().sinv().bit_is_set();
+            let smr = parity_bits(parity, invert)
+                | match stop_bits {
+                    StopBits::One => 0,
+                    StopBits::Two => SMR_STOP,
+                };
+            sci.smr()
+                .modify(|r, w| unsafe { w.bits((r.bits() & !(SMR_PE | SMR_PM | SMR_STOP)) | smr) });
+        });
+    }
+
+    /// Invert the transmitted and received data bits (SCMR.SINV).
+    ///
+    /// Only the data bits are inverted, start and stop bits are not, so
+    /// this can't be used to receive a fully inverted line. SINV leaves the
+    /// parity bit alone, so SMR.PM is flipped with it to check the parity
+    /// of an inverted line, e.g. SBUS through an inverter.
+    pub fn set_data_inversion(&mut self, invert: bool) {
+        self.tx.wait_idle();
+        reconfigure::<T>(|sci| {
+            if sci.scmr.read().sinv().bit_is_set() == invert {
+                return;
+            }
+            sci.scmr.modify(|_, w| w.sinv().bit(invert));
+            sci.smr().modify(|r, w| unsafe {
+                w.bits(if r.bits() & SMR_PE != 0 {
+                    r.bits() ^ SMR_PM
+                } else {
+                    r.bits()
+                })
+            });
+        });
+    }
+
+    /// Use multiprocessor mode with our own `address`, or leave it with
+    /// `None`.
+    ///
+    /// Each frame carries an extra bit marking it as an ID frame, holding
+    /// an address, or data. Only data following an ID frame with `address`
+    /// is received, the hardware skips the rest without interrupts. Send
+    /// with [`UartTx::send_to`]. There is no parity in this mode.
+    ///
+    /// Waits for a transmission in progress to finish.
+    pub fn set_multiprocessor(&mut self, address: Option<u8>) {
+        self.tx.wait_idle();
+        let state = T::state();
+        state
+            .mp_address
+            .store(address.map_or(NO_ADDRESS, u16::from), Ordering::Relaxed);
+        reconfigure::<T>(|sci| {
+            sci.smr().modify(|r, w| unsafe {
+                w.bits(if address.is_some() {
+                    r.bits() | SMR_MP
+                } else {
+                    r.bits() & !SMR_MP
+                })
+            });
+        });
+        // Wait for an ID frame before receiving anything
+        critical_section::with(|_| {
+            let sci = unsafe { &*T::peripheral() };
+            sci.scr().modify(|r, w| unsafe {
+                w.bits(if address.is_some() {
+                    r.bits() | SCR_MPIE
+                } else {
+                    r.bits() & !SCR_MPIE
+                })
+            });
+        });
+    }
+
+    /// The receive error seen since the last call, see
+    /// [`UartRx::take_error`].
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.rx.take_error()
+    }
+
+    /// Write every slice of `bufs` in order, see
+    /// [`UartTx::write_vectored`].
+    pub fn write_vectored(&mut self, bufs: &[&[u8]]) {
+        self.tx.write_vectored(bufs);
+    }
+
+    /// Send an ID frame with `address` then `data`, see
+    /// [`UartTx::send_to`].
+    pub fn send_to(&mut self, address: u8, data: &[u8]) -> Result<(), Error> {
+        self.tx.send_to(address, data)
+    }
+}
+
+/// Parity bit of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+/// Number of stop bits in a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+/// Baud rate and frame format, see [`Uart::configure`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub baud: u32,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    /// Invert the data bits, see [`Uart::set_data_inversion`]
+    pub invert: bool,
+    /// Own address in multiprocessor mode, see [`Uart::set_multiprocessor`]
+    pub address: Option<u8>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            baud: 115_200,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            invert: false,
+            address: None,
+        }
+    }
+}
+
+impl Config {
+    /// The register values [`Uart::configure`] writes, with the baud rate
+    /// from the current PCLKB frequency.
+    pub fn render(&self) -> RegisterImage {
+        self.render_with(Clocks::read().pclkb())
+    }
+
+    /// The register values [`Uart::configure`] writes, with the baud rate
+    /// from a PCLKB of `pclkb_hz`.
+    pub fn render_with(&self, pclkb_hz: u32) -> RegisterImage {
+        let (cks, brr) = baud_divisor(pclkb_hz, self.baud);
+        let smr =
+            cks | match self.parity {
+                // Multiprocessor mode has no parity
+                _ if self.address.is_some() => SMR_MP,
+                // SINV doesn't invert the parity bit, PM is flipped instead
+                parity => parity_bits(parity, self.invert),
+            } | match self.stop_bits {
+                StopBits::One => 0,
+                StopBits::Two => SMR_STOP,
+            };
+        RegisterImage {
+            smr,
+            scmr: SCMR_RESET | if self.invert { SCMR_SINV } else { 0 },
+            semr: 0,
+            brr,
+        }
+    }
 }
 
+/// Register values of a [`Config`], from [`Config::render`].
+///
+/// Displays as one line, e.g. for a shell command.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct Error {}
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RegisterImage {
+    /// Serial Mode Register
+    pub smr: u8,
+    /// Smart Card Mode Register
+    pub scmr: u8,
+    /// Serial Extended Mode Register
+    pub semr: u8,
+    /// Bit Rate Register
+    pub brr: u8,
+}
+
+impl core::fmt::Display for RegisterImage {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "SMR: {:02X} SCMR: {:02X} SEMR: {:02X} BRR: {:02X}",
+            self.smr, self.scmr, self.semr, self.brr
+        )
+    }
+}
+
+// SMR bits
+const SMR_MP: u8 = 1 << 2;
+const SMR_STOP: u8 = 1 << 3;
+const SMR_PM: u8 = 1 << 4;
+const SMR_PE: u8 = 1 << 5;
+// SCMR after reset, reserved bits and CHR1 set for 8 bit data
+const SCMR_RESET: u8 = 0xF2;
+const SCMR_SINV: u8 = 1 << 2;
+// SCR.MPIE, skip data frames until an ID frame
+const SCR_MPIE: u8 = 1 << 3;
+// SCR.RE, receive enable, cleared while autobauding
+#[cfg(feature = "gpt")]
+const SCR_RE: u8 = 1 << 4;
+// SSR bits, MPBT to send an ID frame and MPB set on receiving one
+const SSR_MPBT: u8 = 1 << 0;
+const SSR_MPB: u8 = 1 << 1;
+// SSR error flags
+const SSR_PER: u8 = 1 << 3;
+const SSR_FER: u8 = 1 << 4;
+const SSR_ORER: u8 = 1 << 5;
+// SSR flags cleared by writing 0, written as 1 to leave them
+const SSR_FLAGS: u8 = 0xF8;
+
+// Run `f` with TE and RE cleared, as needed to write SMR, SCMR and BRR.
+// In a critical section so a handler can't change SCR in between.
+fn reconfigure<T: Instance>(f: impl FnOnce(&sci2::RegisterBlock)) {
+    critical_section::with(|_| {
+        let sci = unsafe { &*T::peripheral() };
+        let scr = sci.scr().read().bits();
+        sci.scr()
+            .modify(|r, w| unsafe { w.bits(r.bits() & !((1 << 5) | (1 << 4))) });
+        f(sci);
+        sci.scr().write(|w| unsafe { w.bits(scr) });
+    });
+}
+
+// SMR.PE and SMR.PM for `parity`, the sense flipped when SCMR.SINV inverts
+// the data but not the parity bit
+fn parity_bits(parity: Parity, invert: bool) -> u8 {
+    match (parity, invert) {
+        (Parity::None, _) => 0,
+        (Parity::Even, false) | (Parity::Odd, true) => SMR_PE,
+        (Parity::Even, true) | (Parity::Odd, false) => SMR_PE | SMR_PM,
+    }
+}
+
+// SMR.CKS and BRR for the closest rate to `baud` from PCLKB.
+fn baud_divisor(pclkb_hz: u32, baud: u32) -> (u8, u8) {
+    let baud = baud.max(1);
+    // N = PCLKB / (64 * 2^(2n - 1) * B) - 1 with ABCS clear, pick the
+    // smallest n where N fits
+    let (cks, brr) = (0..4u32)
+        .map(|n| {
+            let div = 32 * (1 << (2 * n)) * baud;
+            (n, (pclkb_hz + div / 2) / div)
+        })
+        .find(|(_, brr)| *brr <= 256)
+        .unwrap_or((3, 256));
+    (cks as u8, brr.saturating_sub(1) as u8)
+}
+
+// Set SMR.CKS and BRR for the closest rate to `baud` from PCLKB.
+pub(crate) fn set_baud_rate<T: Instance>(baud: u32) {
+    let (cks, brr) = baud_divisor(Clocks::read().pclkb(), baud);
+    reconfigure::<T>(|sci| {
+        sci.smr()
+            .modify(|r, w| unsafe { w.bits((r.bits() & !0b11) | cks) });
+        sci.brr.write(|w| unsafe { w.brr().bits(brr) });
+    });
+}
+
+/// UART receive errors, from [`UartRx::take_error`].
+///
+/// Reads and writes through the `embedded_io` traits never fail, errors
+/// don't stop reception so are collected until taken instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// A byte arrived before the previous one was moved to the buffer
+    Overrun,
+    /// A stop bit was missing, e.g. a wrong baud rate or a break
+    Framing,
+    /// The parity bit didn't match the data
+    Parity,
+    /// The receive buffer was full, bytes were dropped
+    BufferFull,
+}
 
 impl embedded_io::Error for Error {
     fn kind(&self) -> embedded_io::ErrorKind {
-        embedded_io::ErrorKind::Other
+        match self {
+            Error::Framing | Error::Parity => embedded_io::ErrorKind::InvalidData,
+            Error::Overrun | Error::BufferFull => embedded_io::ErrorKind::OutOfMemory,
+        }
+    }
+}
+
+// True if the SCI interrupt handlers may not be able to run until the
+// caller returns: interrupts are masked, or the caller is itself an
+// interrupt handler which may have the same or a higher priority.
+fn handlers_blocked() -> bool {
+    cortex_m::register::primask::read().is_inactive()
+        || cortex_m::register::basepri::read() != 0
+        || SCB::vect_active() != VectActive::ThreadMode
+}
+
+// Make sure data pushed to the transmit buffer will be sent.
+//
+// In a critical section so TXI can't empty the buffer and set TEIE between
+// reading and writing SCR, which would leave the new data in the buffer.
+fn start_transmit<T: Instance>() {
+    critical_section::with(|_| {
+        let sci = unsafe { &*T::peripheral() };
+        let reg = sci.scr().read();
+        if reg.te().bit_is_clear() {
+            sci.scr().modify(|_, w| w.tie()._1().teie()._0().te()._1());
+        } else if reg.teie().bit_is_set() {
+            // Final byte in flight, wait for it to finish then end the
+            // transmission here rather than in the TEI handler.
+            while sci.ssr().read().tend().bit_is_clear() {}
+            sci.scr().modify(|_, w| w.teie()._0().tie()._0().te()._0());
+            sci.scr().modify(|_, w| w.tie()._1().teie()._0().te()._1());
+        }
+    });
+}
+
+// End the transmission after the final stop bit, and tell `on_idle` and
+// `flush_async`
+fn end_transmit<T: Instance>(sci: &sci2::RegisterBlock) {
+    sci.scr().modify(|_, w| w.teie()._0().tie()._0().te()._0());
+    let state = T::state();
+    let (callback, waker) = critical_section::with(|cs| {
+        (
+            state.on_idle.borrow(cs).get(),
+            state.idle_waker.borrow_ref_mut(cs).take(),
+        )
+    });
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+    if let Some(callback) = callback {
+        callback();
+    }
+}
+
+// Write the next byte to TDR, an ID frame from `send_to` before the data,
+// with `ssr` as the caller read it. Returns false if there was nothing to
+// send.
+fn write_next<T: Instance>(sci: &sci2::RegisterBlock, ssr: u8) -> bool {
+    let state = T::state();
+    let pending = state.mp_pending.swap(NO_ADDRESS, Ordering::Relaxed);
+    if pending != NO_ADDRESS {
+        set_mpbt(sci, true);
+        sci.tdr.write(|w| unsafe { w.bits(pending as u8) });
+        return true;
+    }
+    let mut reader = unsafe { state.tx_buf.reader() };
+    let Some(byte) = reader.pop_slice().first().copied() else {
+        return false;
+    };
+    if ssr & SSR_MPBT != 0 {
+        set_mpbt(sci, false);
+    }
+    sci.tdr.write(|w| unsafe { w.bits(byte) });
+    state.tx_usage.popped(1);
+    reader.pop_done(1);
+    true
+}
+
+// Set the multiprocessor bit sent with the next frame written to TDR.
+fn set_mpbt(sci: &sci2::RegisterBlock, id: bool) {
+    sci.ssr().modify(|r, w| unsafe {
+        let bits = (r.bits() | SSR_FLAGS) & !SSR_MPBT;
+        w.bits(if id { bits | SSR_MPBT } else { bits })
+    });
+}
+
+// Do the work of the TXI and TEI handlers by polling the status flags.
+fn poll_transmit<T: Instance>() {
+    critical_section::with(|_| {
+        let sci = unsafe { &*T::peripheral() };
+        let scr = sci.scr().read();
+        let ssr = sci.ssr().read();
+        if scr.tie().bit_is_set() && ssr.tdre().bit_is_set() {
+            write_next::<T>(sci, ssr.bits());
+            if T::state().tx_buf.is_empty() {
+                sci.scr().modify(|_, w| w.teie()._1().tie()._0());
+            }
+        } else if scr.teie().bit_is_set() && ssr.tend().bit_is_set() {
+            end_transmit::<T>(sci);
+        }
+    });
+}
+
+/// Send everything in the transmit buffer of `T` and wait for the final
+/// stop bit, without a [`UartTx`].
+///
+/// Polls the status flags rather than waiting for the interrupts, so works
+/// with interrupts disabled, e.g. from a [`shutdown`](crate::shutdown) hook.
+pub fn flush<T: Instance>() {
+    let sci = unsafe { &*T::peripheral() };
+    while sci.scr().read().te().bit_is_set() {
+        poll_transmit::<T>();
+    }
+}
+
+impl<T: Instance> UartTx<T> {
+    /// Copy as much of `buf` as fits into the transmit buffer and start
+    /// transmission, without waiting for space.
+    ///
+    /// Safe to call from any interrupt priority or with interrupts disabled:
+    /// if the final byte of a previous transmission is in flight, the
+    /// transmit end flag is polled instead of waiting for the TEI interrupt.
+    ///
+    /// Returns the number of bytes written, 0 if the buffer is full. The
+    /// rest count as dropped in [`stats`](Self::stats).
+    pub fn try_write(&mut self, buf: &[u8]) -> usize {
+        let written = self.write_some(buf);
+        self.state.tx_usage.dropped(buf.len() - written);
+        written
+    }
+
+    // `try_write` for the blocking writes, which retry what didn't fit
+    fn write_some(&mut self, buf: &[u8]) -> usize {
+        let written = self.push(buf);
+        if written == 0 {
+            return 0;
+        }
+
+        start_transmit::<T>();
+        written
+    }
+
+    /// Copy as much of `bufs` as fits into the transmit buffer, in order,
+    /// and start transmission once, without waiting for space.
+    ///
+    /// For protocols that build headers and payloads separately. Returns
+    /// the number of bytes written over all the slices.
+    pub fn try_write_vectored(&mut self, bufs: &[&[u8]]) -> usize {
+        let mut written = 0;
+        for buf in bufs {
+            let len = self.push(buf);
+            written += len;
+            if len < buf.len() {
+                break;
+            }
+        }
+        let total: usize = bufs.iter().map(|buf| buf.len()).sum();
+        self.state.tx_usage.dropped(total - written);
+        if written > 0 {
+            start_transmit::<T>();
+        }
+        written
+    }
+
+    /// Write every slice of `bufs` in order, starting transmission once
+    /// they're all buffered, or earlier when the buffer fills.
+    ///
+    /// Blocks like [`embedded_io::Write::write_all`] until the last byte is
+    /// buffered.
+    pub fn write_vectored(&mut self, bufs: &[&[u8]]) {
+        for buf in bufs {
+            self.write_buffered(buf);
+        }
+        if !self.state.tx_buf.is_empty() {
+            start_transmit::<T>();
+        }
+    }
+
+    // Copy as much of `buf` as fits into the transmit buffer, without
+    // starting transmission. Returns the number of bytes copied.
+    fn push(&mut self, buf: &[u8]) -> usize {
+        let mut writer = unsafe { self.state.tx_buf.writer() };
+        let mut written = 0;
+        // Twice as the free space may wrap around the end of the buffer
+        for _ in 0..2 {
+            let data = writer.push_slice();
+            let len = data.len().min(buf.len() - written);
+            data[..len].copy_from_slice(&buf[written..written + len]);
+            self.state.tx_usage.pushed(len);
+            writer.push_done(len);
+            written += len;
+        }
+        written
+    }
+
+    // Copy all of `buf` into the transmit buffer, only starting transmission
+    // to wait for space when it's full
+    fn write_buffered(&mut self, mut buf: &[u8]) {
+        while !buf.is_empty() {
+            let len = self.push(buf);
+            buf = &buf[len..];
+            if !buf.is_empty() {
+                start_transmit::<T>();
+                self.wait();
+            }
+        }
+    }
+
+    /// Send an ID frame with `address` then `data`, in multiprocessor mode
+    /// set with [`Uart::set_multiprocessor`].
+    ///
+    /// Waits for a transmission in progress to finish first, so the ID frame
+    /// comes straight before `data`. Returns once `data` is buffered.
+    pub fn send_to(&mut self, address: u8, data: &[u8]) -> Result<(), Error> {
+        self.wait_idle();
+        self.state
+            .mp_pending
+            .store(address as u16, Ordering::Relaxed);
+        if data.is_empty() {
+            start_transmit::<T>();
+            Ok(())
+        } else {
+            embedded_io::Write::write_all(self, data)
+        }
+    }
+
+    /// Wait until the transmit buffer is empty and the final stop bit has
+    /// been sent, when the TEI handler ends the transmission.
+    pub fn wait_idle(&self) {
+        let sci = unsafe { &*T::peripheral() };
+        while sci.scr().read().te().bit_is_set() {
+            self.wait();
+        }
+    }
+
+    /// [`wait_idle`](Self::wait_idle) without blocking, `WouldBlock` until
+    /// the final stop bit has been sent.
+    pub fn flush_nb(&mut self) -> nb::Result<(), Error> {
+        let sci = unsafe { &*T::peripheral() };
+        if sci.scr().read().te().bit_is_clear() {
+            return Ok(());
+        }
+        if handlers_blocked() {
+            poll_transmit::<T>();
+        }
+        Err(nb::Error::WouldBlock)
+    }
+
+    /// Wait until the final stop bit has been sent, woken by the TEI
+    /// handler.
+    pub async fn flush_async(&mut self) {
+        poll_fn(|cx| {
+            let sci = unsafe { &*T::peripheral() };
+            if handlers_blocked() {
+                poll_transmit::<T>();
+                cx.waker().wake_by_ref();
+            } else {
+                critical_section::with(|cs| {
+                    *self.state.idle_waker.borrow_ref_mut(cs) = Some(cx.waker().clone());
+                });
+            }
+            // After storing the waker, so an end in between isn't missed
+            if sci.scr().read().te().bit_is_clear() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Fill level, high watermark and refused bytes of the transmit buffer.
+    pub fn stats(&self) -> BufferStats {
+        self.state.tx_usage.stats()
+    }
+
+    /// Call `callback` each time a transmission ends with its final stop
+    /// bit, or stop with `None`.
+    ///
+    /// Runs in the TEI handler, or wherever the transmission is polled when
+    /// the handlers are blocked, so it should be short, e.g. switching an
+    /// RS-485 transceiver back to receive.
+    pub fn on_idle(&mut self, callback: Option<fn()>) {
+        critical_section::with(|cs| self.state.on_idle.borrow(cs).set(callback));
+    }
+
+    // Let the transmission progress. Sleeps until the next interrupt when
+    // the SCI handlers can run, otherwise does their work by polling so a
+    // write from a high priority handler can't deadlock.
+    fn wait(&self) {
+        if handlers_blocked() {
+            poll_transmit::<T>();
+        } else {
+            cortex_m::asm::wfi();
+        }
     }
 }
 
@@ -207,77 +1130,58 @@ impl<T: Instance> embedded_io::ErrorType for UartTx<T> {
     type Error = Error;
 }
 
+/// Blocking writes. These can be used from any interrupt priority, when the
+/// SCI interrupt handlers can't run the transmission is driven by polling.
 impl<T: Instance> embedded_io::Write for UartTx<T> {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
         loop {
-            let state = self.state;
-            let mut writer = unsafe { state.tx_buf.writer() };
-            let data = writer.push_slice();
-            if !data.is_empty() {
-                // Copy data to the buffer
-                let len = data.len().min(buf.len());
-                data[..len].copy_from_slice(&buf[..len]);
-                // Inform the writer that we pushed some data
-                writer.push_done(len);
-                // Check if transmission is already in progress
-                let sci = unsafe { &*T::peripheral() };
-                let reg = sci.scr().read();
-                // If te is clear, TEI has triggered and we need to start transmission
-                if reg.te().bit_is_clear() {
-                    sci.scr().modify(|_, w| w.tie()._1().teie()._0().te()._1());
-                } else if reg.teie().bit_is_set() {
-                    // final byte is in flight, wait until done then start a new transmission
-                    // This can't be done in the TEI interrupt handler as it seems
-                    // to cause a data race and bytes are lost.
-                    loop {
-                        // Wait for the TEI interrupt to be triggered
-                        cortex_m::asm::wfi();
-                        // Check if the TEI interrupt has been triggered
-                        let reg = sci.scr().read();
-                        if reg.teie().bit_is_clear() && reg.te().bit_is_clear() {
-                            // TEI has been triggered, we can start a new transmission
-                            break;
-                        }
-                    }
-                    // Start transmission
-                    sci.scr().modify(|_, w| w.tie()._1().teie()._0().te()._1());
-                }
-
-                // Return the number of bytes written
-                return Ok(len);
-            } else {
-                // No space in the buffer.
-                // Make sure transmission is started
-                let sci = unsafe { &*T::peripheral() };
-                let reg = sci.scr().read();
-                if reg.te().bit_is_clear() {
-                    sci.scr().modify(|_, w| w.tie()._1().teie()._0().te()._1());
-                }
-                // Wait for space in the buffer
-                cortex_m::asm::wfi();
+            let written = self.write_some(buf);
+            if written > 0 {
+                return Ok(written);
             }
+            // No space in the buffer, wait for some to be sent
+            self.wait();
         }
     }
 
     fn flush(&mut self) -> Result<(), Self::Error> {
-        loop {
-            let state = self.state;
-            if state.tx_buf.is_empty() {
-                // Buffer is empty, we can flush
-                return Ok(());
-            } else {
-                // Wait for the buffer to be empty
-                cortex_m::asm::wfi();
+        while !self.state.tx_buf.is_empty() {
+            self.wait();
+        }
+        Ok(())
+    }
+
+    // Format straight into the transmit buffer and start transmission once,
+    // rather than for each piece of the output
+    fn write_fmt(
+        &mut self,
+        args: core::fmt::Arguments<'_>,
+    ) -> Result<(), embedded_io::WriteFmtError<Self::Error>> {
+        struct Buffered<'a, T: Instance>(&'a mut UartTx<T>);
+
+        impl<T: Instance> core::fmt::Write for Buffered<'_, T> {
+            fn write_str(&mut self, s: &str) -> core::fmt::Result {
+                self.0.write_buffered(s.as_bytes());
+                Ok(())
             }
         }
+
+        let result = core::fmt::write(&mut Buffered(self), args);
+        if !self.state.tx_buf.is_empty() {
+            start_transmit::<T>();
+        }
+        result.map_err(|_| embedded_io::WriteFmtError::FmtError)
     }
 }
 
-impl<T: Instance> embedded_io::ErrorType for Uart<T> {
+impl<T: Instance, const TX: usize, const RX: usize> embedded_io::ErrorType for Uart<T, TX, RX> {
     type Error = Error;
 }
 
-impl<T: Instance> embedded_io::Write for Uart<T> {
+impl<T: Instance, const TX: usize, const RX: usize> embedded_io::Write for Uart<T, TX, RX> {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
         self.tx.write(buf)
     }
@@ -285,6 +1189,150 @@ impl<T: Instance> embedded_io::Write for Uart<T> {
     fn flush(&mut self) -> Result<(), Self::Error> {
         self.tx.flush()
     }
+
+    fn write_fmt(
+        &mut self,
+        args: core::fmt::Arguments<'_>,
+    ) -> Result<(), embedded_io::WriteFmtError<Self::Error>> {
+        self.tx.write_fmt(args)
+    }
+}
+
+impl<T: Instance> UartRx<T> {
+    /// The receive error seen since the last call, the first of overrun,
+    /// buffer full, framing and parity if there were several.
+    pub fn take_error(&mut self) -> Option<Error> {
+        let errors = self.state.errors.swap(0, Ordering::Relaxed);
+        if errors & SSR_ORER != 0 {
+            Some(Error::Overrun)
+        } else if errors & ERROR_BUFFER_FULL != 0 {
+            Some(Error::BufferFull)
+        } else if errors & SSR_FER != 0 {
+            Some(Error::Framing)
+        } else if errors & SSR_PER != 0 {
+            Some(Error::Parity)
+        } else {
+            None
+        }
+    }
+
+    /// Fill level, high watermark and lost bytes of the receive buffer.
+    pub fn stats(&self) -> BufferStats {
+        self.state.rx_usage.stats()
+    }
+
+    /// Received data in place in the receive buffer, without waiting.
+    ///
+    /// Returns the contiguous part of the received data, which may be less
+    /// than all of it when the data wraps around the end of the buffer.
+    /// Empty if nothing has been received. Mark bytes as read with
+    /// [`consume`](Self::consume).
+    pub fn try_fill_buf(&mut self) -> &[u8] {
+        let mut reader = unsafe { self.state.rx_buf.reader() };
+        let (ptr, len) = reader.pop_buf();
+        // The RX handler only writes to the free part of the buffer,
+        // these bytes stay in place until they are consumed.
+        unsafe { core::slice::from_raw_parts(ptr, len) }
+    }
+
+    /// Received data in place in the receive buffer, waiting until there is some.
+    ///
+    /// Like [`try_fill_buf`](Self::try_fill_buf) but never empty.
+    pub fn fill_buf(&mut self) -> &[u8] {
+        while self.state.rx_buf.is_empty() {
+            cortex_m::asm::wfi();
+        }
+        self.try_fill_buf()
+    }
+
+    /// Mark `amt` bytes returned by [`fill_buf`](Self::fill_buf) as read,
+    /// freeing their space for new data.
+    pub fn consume(&mut self, amt: usize) {
+        let mut reader = unsafe { self.state.rx_buf.reader() };
+        let (_, len) = reader.pop_buf();
+        let amt = amt.min(len);
+        self.state.rx_usage.popped(amt);
+        reader.pop_done(amt);
+    }
+
+    /// Copy received data into `buf` without consuming it, for lookahead,
+    /// returning the number of bytes copied. Unlike
+    /// [`try_fill_buf`](Self::try_fill_buf) this includes data wrapped
+    /// around the end of the buffer.
+    ///
+    /// There's no pushing back consumed bytes, as the RX handler may reuse
+    /// their space straight away. Peek, then [`skip`](Self::skip) what was
+    /// used:
+    ///
+    /// ```ignore
+    /// let mut header = [0; 6];
+    /// if rx.peek(&mut header) == 6 && &header == b"$GPGGA" {
+    ///     if let Some(end) = rx.position(b'\n') {
+    ///         // read end + 1 bytes of sentence
+    ///     }
+    /// } else {
+    ///     rx.skip(1);
+    /// }
+    /// ```
+    pub fn peek(&mut self, buf: &mut [u8]) -> usize {
+        let (first, wrapped) = self.buffered();
+        let len = first.len().min(buf.len());
+        buf[..len].copy_from_slice(&first[..len]);
+        let rest = wrapped.len().min(buf.len() - len);
+        buf[len..len + rest].copy_from_slice(&wrapped[..rest]);
+        len + rest
+    }
+
+    /// Offset of the first `byte` in the received data, without consuming
+    /// it, e.g. to find the end of a line before reading it.
+    pub fn position(&mut self, byte: u8) -> Option<usize> {
+        let (first, wrapped) = self.buffered();
+        first.iter().chain(wrapped).position(|&b| b == byte)
+    }
+
+    /// Consume up to `n` bytes of received data, including data wrapped
+    /// around the end of the buffer, returning the number consumed.
+    pub fn skip(&mut self, n: usize) -> usize {
+        let mut skipped = 0;
+        // Twice as the data may wrap around the end of the buffer
+        for _ in 0..2 {
+            let len = self.try_fill_buf().len().min(n - skipped);
+            self.consume(len);
+            skipped += len;
+        }
+        skipped
+    }
+
+    // The received data in place, as the part up to the end of the buffer
+    // and the part wrapped around to its start
+    fn buffered(&mut self) -> (&[u8], &[u8]) {
+        let mut reader = unsafe { self.state.rx_buf.reader() };
+        let (ptr, len) = reader.pop_buf();
+        // The receive buffer follows the transmit buffer in the storage
+        let start = unsafe {
+            (T::storage().0.get() as *const u8)
+                .add(self.state.tx_usage.capacity.load(Ordering::Relaxed))
+        };
+        let capacity = self.state.rx_usage.capacity.load(Ordering::Relaxed);
+        // The fill level counts bytes after they're pushed, so never more
+        // than are there
+        let wrapped = if ptr.wrapping_add(len) == start.wrapping_add(capacity) {
+            self.state
+                .rx_usage
+                .len
+                .load(Ordering::Relaxed)
+                .saturating_sub(len)
+        } else {
+            0
+        };
+        // As in `try_fill_buf`, these bytes stay in place until consumed
+        unsafe {
+            (
+                core::slice::from_raw_parts(ptr, len),
+                core::slice::from_raw_parts(start, wrapped),
+            )
+        }
+    }
 }
 
 // ================ Read Traits ================
@@ -302,6 +1350,7 @@ impl<T: Instance> embedded_io::Read for UartRx<T> {
                 let len = data.len().min(buf.len());
                 buf[..len].copy_from_slice(&data[..len]);
                 // Inform the reader that we popped some data
+                self.state.rx_usage.popped(len);
                 reader.pop_done(len);
                 // Return the number of bytes read
                 return Ok(len);
@@ -319,36 +1368,156 @@ impl<T: Instance> embedded_io::ReadReady for UartRx<T> {
     }
 }
 
-impl<T: Instance> embedded_io::Read for Uart<T> {
+impl<T: Instance> embedded_io::BufRead for UartRx<T> {
+    fn fill_buf(&mut self) -> Result<&[u8], Self::Error> {
+        Ok(UartRx::fill_buf(self))
+    }
+
+    fn consume(&mut self, amt: usize) {
+        UartRx::consume(self, amt);
+    }
+}
+
+impl<T: Instance, const TX: usize, const RX: usize> embedded_io::Read for Uart<T, TX, RX> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
         self.rx.read(buf)
     }
 }
 
-impl<T: Instance> embedded_io::ReadReady for Uart<T> {
+impl<T: Instance, const TX: usize, const RX: usize> embedded_io::ReadReady for Uart<T, TX, RX> {
     fn read_ready(&mut self) -> Result<bool, Self::Error> {
         self.rx.read_ready()
     }
 }
 
-impl Instance for SCI2 {
-    fn peripheral() -> *const sci2::RegisterBlock {
-        SCI2::ptr()
+impl<T: Instance, const TX: usize, const RX: usize> embedded_io::BufRead for Uart<T, TX, RX> {
+    fn fill_buf(&mut self) -> Result<&[u8], Self::Error> {
+        Ok(self.rx.fill_buf())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.rx.consume(amt);
+    }
+}
+
+// ================ embedded-hal 0.2 ================
+/// A receive error seen since the last read is returned once, the byte
+/// after it is returned by the next read.
+#[cfg(feature = "embedded-hal-02")]
+impl<T: Instance> embedded_hal_02::serial::Read<u8> for UartRx<T> {
+    type Error = Error;
+
+    fn read(&mut self) -> nb::Result<u8, Error> {
+        if let Some(error) = self.take_error() {
+            return Err(nb::Error::Other(error));
+        }
+        let byte = *self.try_fill_buf().first().ok_or(nb::Error::WouldBlock)?;
+        self.consume(1);
+        Ok(byte)
     }
+}
+
+#[cfg(feature = "embedded-hal-02")]
+impl<T: Instance> embedded_hal_02::serial::Write<u8> for UartTx<T> {
+    type Error = Error;
 
-    fn state() -> &'static State {
-        static STATE: State = State::new();
-        &STATE
+    fn write(&mut self, word: u8) -> nb::Result<(), Error> {
+        if self.write_some(&[word]) == 0 {
+            if handlers_blocked() {
+                poll_transmit::<T>();
+            }
+            return Err(nb::Error::WouldBlock);
+        }
+        Ok(())
     }
 
-    fn event_base() -> u8 {
-        0xA3
+    fn flush(&mut self) -> nb::Result<(), Error> {
+        if !self.state.tx_buf.is_empty() {
+            if handlers_blocked() {
+                poll_transmit::<T>();
+            }
+            return Err(nb::Error::WouldBlock);
+        }
+        Ok(())
     }
 }
 
-fn init(p: &ra4m1::Peripherals, sci: &sci2::RegisterBlock) {
+#[cfg(feature = "embedded-hal-02")]
+impl<T: Instance> embedded_hal_02::blocking::serial::write::Default<u8> for UartTx<T> {}
+
+#[cfg(feature = "embedded-hal-02")]
+impl<T: Instance, const TX: usize, const RX: usize> embedded_hal_02::serial::Read<u8>
+    for Uart<T, TX, RX>
+{
+    type Error = Error;
+
+    fn read(&mut self) -> nb::Result<u8, Error> {
+        embedded_hal_02::serial::Read::read(&mut self.rx)
+    }
+}
+
+#[cfg(feature = "embedded-hal-02")]
+impl<T: Instance, const TX: usize, const RX: usize> embedded_hal_02::serial::Write<u8>
+    for Uart<T, TX, RX>
+{
+    type Error = Error;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), Error> {
+        embedded_hal_02::serial::Write::write(&mut self.tx, word)
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Error> {
+        embedded_hal_02::serial::Write::flush(&mut self.tx)
+    }
+}
+
+#[cfg(feature = "embedded-hal-02")]
+impl<T: Instance, const TX: usize, const RX: usize>
+    embedded_hal_02::blocking::serial::write::Default<u8> for Uart<T, TX, RX>
+{
+}
+
+macro_rules! impl_instance {
+    ($($inst:ident: $channel:literal, $event:ident;)*) => {
+        $(
+            impl Instance for $inst {
+                fn peripheral() -> *const sci2::RegisterBlock {
+                    $inst::ptr() as *const sci2::RegisterBlock
+                }
+
+                fn state() -> &'static State {
+                    static STATE: State = State::new();
+                    &STATE
+                }
+
+                fn storage() -> &'static Storage {
+                    static STORAGE: Storage = Storage::new();
+                    &STORAGE
+                }
+
+                fn event_base() -> Event {
+                    Event::$event
+                }
+
+                fn channel() -> u8 {
+                    $channel
+                }
+            }
+        )*
+    };
+}
+
+// The asynchronous mode registers are at the same offsets in every channel
+impl_instance! {
+    SCI0: 0, SCI0_RXI;
+    SCI1: 1, SCI1_RXI;
+    SCI2: 2, SCI2_RXI;
+    SCI9: 9, SCI9_RXI;
+}
+
+fn init<T: Instance>(sci: &sci2::RegisterBlock) -> ClockGuard {
     // Enable SCI
-    p.MSTP.mstpcrb.modify(|_, w| w.mstpb29()._0()); // Enable SCI2
+    let clock = enable_module::<T>();
     // Reset scr
     sci.scr().write(|w| unsafe { w.bits(0) });
     // In theory set FCR.FM to 0 but the default is 0
@@ -394,25 +1563,67 @@ fn init(p: &ra4m1::Peripherals, sci: &sci2::RegisterBlock) {
 
     // Set TE = 0 output level to 1
     sci.sptr.write(|w| w.spb2dt()._1().spb2io()._1());
-    // First write to the B0WI bit
-    p.PMISC.pwpr.write(|w| w.b0wi()._0());
-    // Then write to the PFSWE bit
-    p.PMISC.pwpr.write(|w| w.pfswe()._1());
-    // Set RX pin PSEL to 00100 (SCI2_RXD)
-    p.PFS.p301pfs().write(|w| unsafe { w.bits(0) });
-    p.PFS.p301pfs().write(|w| w.psel().variant(0b00100));
-    p.PFS.p301pfs().modify(|_, w| w.pmr()._1());
-
-    // TX as output high
-    p.PFS.p302pfs().write(|w| unsafe { w.bits(0) });
-    p.PFS.p302pfs().write(|w| w.pdr()._1().podr()._1());
-
-    // Set P302 as TX pin
-    p.PFS
-        .p302pfs()
-        .modify(|_, w| unsafe { w.psel().bits(0b00100) });
-    p.PFS.p302pfs().modify(|_, w| w.pmr()._1());
-
-    // Start receiving with interrupts
-    sci.scr().modify(|_, w| w.re()._1().rie()._1());
+    clock
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_render() {
+        let config = Config {
+            baud: 9600,
+            parity: Parity::Even,
+            stop_bits: StopBits::Two,
+            invert: true,
+            address: None,
+        };
+        let image = config.render_with(24_000_000);
+        assert_eq!(
+            image,
+            RegisterImage {
+                smr: SMR_PE | SMR_PM | SMR_STOP,
+                scmr: 0xF6,
+                semr: 0,
+                brr: 77,
+            }
+        );
+        assert_eq!(image.to_string(), "SMR: 38 SCMR: F6 SEMR: 00 BRR: 4D");
+
+        // No parity in multiprocessor mode, slow rates need a prescaler
+        let config = Config {
+            baud: 300,
+            address: Some(3),
+            ..config
+        };
+        let image = config.render_with(24_000_000);
+        assert_eq!(image.smr, 2 | SMR_MP | SMR_STOP);
+        assert_eq!(image.brr, 155);
+    }
+
+    #[test]
+    fn buffer_usage() {
+        let usage = Usage::new();
+        usage.init(64);
+        usage.pushed(10);
+        usage.pushed(30);
+        usage.popped(25);
+        usage.pushed(5);
+        usage.dropped(3);
+        assert_eq!(
+            usage.stats(),
+            BufferStats {
+                capacity: 64,
+                len: 20,
+                high_water: 40,
+                dropped: 3,
+            }
+        );
+
+        // A new driver starts counting again
+        usage.init(16);
+        assert_eq!(usage.stats().high_water, 0);
+        assert_eq!(usage.stats().dropped, 0);
+    }
 }
@@ -0,0 +1,73 @@
+//! A [`UartTx`] shared between tasks and interrupts, see [`SharedUartTx`].
+//!
+//! Writes go through `&SharedUartTx`, each one in a critical section, so a
+//! message from an interrupt can't land in the middle of one from a task:
+//!
+//! ```ignore
+//! let (tx, rx) = uart.split();
+//! let log: &'static SharedUartTx<SCI2> =
+//!     cortex_m::singleton!(: SharedUartTx<SCI2> = SharedUartTx::new(tx)).unwrap();
+//! // In any task or interrupt
+//! write!(&mut &*log, "adc {}\n", value)?;
+//! ```
+//!
+//! A message is copied into the transmit buffer with interrupts masked.
+//! One longer than the free space keeps them masked while the rest is sent
+//! by polling, so keep messages short or the buffer large.
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use embedded_io::Write;
+
+use super::{Error, Instance, UartTx};
+
+/// [`UartTx`] shared by reference, each write is sent whole.
+pub struct SharedUartTx<T: Instance> {
+    tx: Mutex<RefCell<UartTx<T>>>,
+}
+
+impl<T: Instance> SharedUartTx<T> {
+    /// Share `tx`, e.g. from a `static`.
+    pub const fn new(tx: UartTx<T>) -> Self {
+        Self {
+            tx: Mutex::new(RefCell::new(tx)),
+        }
+    }
+
+    /// Run `f` with the transmitter in a critical section, e.g. to write
+    /// several messages with nothing in between.
+    pub fn lock<R>(&self, f: impl FnOnce(&mut UartTx<T>) -> R) -> R {
+        critical_section::with(|cs| f(&mut self.tx.borrow_ref_mut(cs)))
+    }
+
+    /// Give the transmitter back.
+    pub fn free(self) -> UartTx<T> {
+        self.tx.into_inner().into_inner()
+    }
+}
+
+impl<T: Instance> embedded_io::ErrorType for &SharedUartTx<T> {
+    type Error = Error;
+}
+
+impl<T: Instance> Write for &SharedUartTx<T> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.lock(|tx| tx.write(buf))
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        self.lock(|tx| tx.write_all(buf))
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.lock(|tx| tx.flush())
+    }
+
+    fn write_fmt(
+        &mut self,
+        args: core::fmt::Arguments<'_>,
+    ) -> Result<(), embedded_io::WriteFmtError<Self::Error>> {
+        self.lock(|tx| tx.write_fmt(args))
+    }
+}
@@ -0,0 +1,423 @@
+//! Minimal UDS (ISO 14229) diagnostic server on top of ISO-TP.
+//!
+//! Implements diagnostic session control (0x10), tester present (0x3E),
+//! read data by identifier (0x22) and security access (0x27). Data
+//! identifiers and the seed / key algorithm are provided by the application
+//! as [`Callbacks`], everything else gets a `serviceNotSupported` response.
+//!
+//! ```ignore
+//! fn read_data(did: u16, _status: uds::Status, buf: &mut [u8]) -> Result<usize, uds::Nrc> {
+//!     match did {
+//!         0xF190 => { buf[..17].copy_from_slice(b"UNOR4000000000001"); Ok(17) }
+//!         _ => Err(uds::Nrc::RequestOutOfRange),
+//!     }
+//! }
+//!
+//! let callbacks = uds::Callbacks { read_data, ..Default::default() };
+//! let mut server = uds::Server::new(isotp::Config::default(), uds::Config::default(), callbacks);
+//! loop {
+//!     let _ = server.poll(&can, now_ms());
+//! }
+//! ```
+//!
+//! A non-default session falls back to the default session, and security
+//! access is locked again, when no request arrives for [`Config::s3_ms`].
+
+use crate::can::{Can, Frame};
+use crate::isotp::{self, IsoTp, MAX_PAYLOAD};
+
+/// Longest security access seed.
+pub const MAX_SEED: usize = 16;
+
+// Service IDs, the positive response is the service ID + 0x40
+const DIAGNOSTIC_SESSION_CONTROL: u8 = 0x10;
+const READ_DATA_BY_IDENTIFIER: u8 = 0x22;
+const SECURITY_ACCESS: u8 = 0x27;
+const TESTER_PRESENT: u8 = 0x3E;
+const NEGATIVE_RESPONSE: u8 = 0x7F;
+const POSITIVE: u8 = 0x40;
+
+// Sub-function bit asking for no positive response
+const SUPPRESS_POSITIVE: u8 = 0x80;
+
+/// Diagnostic session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Session {
+    Default = 0x01,
+    Programming = 0x02,
+    Extended = 0x03,
+}
+
+impl Session {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x01 => Some(Session::Default),
+            0x02 => Some(Session::Programming),
+            0x03 => Some(Session::Extended),
+            _ => None,
+        }
+    }
+}
+
+/// Negative response codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Nrc {
+    GeneralReject = 0x10,
+    ServiceNotSupported = 0x11,
+    SubFunctionNotSupported = 0x12,
+    IncorrectMessageLength = 0x13,
+    ResponseTooLong = 0x14,
+    ConditionsNotCorrect = 0x22,
+    RequestSequenceError = 0x24,
+    RequestOutOfRange = 0x31,
+    SecurityAccessDenied = 0x33,
+    InvalidKey = 0x35,
+    ExceededNumberOfAttempts = 0x36,
+    RequiredTimeDelayNotExpired = 0x37,
+    SubFunctionNotSupportedInActiveSession = 0x7E,
+    ServiceNotSupportedInActiveSession = 0x7F,
+}
+
+/// Session and security state passed to the callbacks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Status {
+    pub session: Session,
+    /// Unlocked security level, 0 when locked
+    pub security_level: u8,
+}
+
+/// Application hooks.
+///
+/// Security levels are numbered as in the request seed sub-function,
+/// 1, 3, 5, ..., the matching send key sub-function is one higher.
+#[derive(Clone, Copy)]
+pub struct Callbacks {
+    /// Write the value of a data identifier to the buffer and return its
+    /// length, a length past the buffer is answered with
+    /// [`Nrc::ResponseTooLong`]
+    pub read_data: fn(did: u16, status: Status, buf: &mut [u8]) -> Result<usize, Nrc>,
+    /// Asked before a session change, an error is sent as the response
+    pub session_change: Option<fn(from: Session, to: Session) -> Result<(), Nrc>>,
+    /// Write a seed for a security level to the buffer and return its length,
+    /// security access isn't supported without it
+    pub security_seed: Option<fn(level: u8, seed: &mut [u8]) -> Result<usize, Nrc>>,
+    /// Whether the key is right for the seed
+    pub security_key: Option<fn(level: u8, seed: &[u8], key: &[u8]) -> bool>,
+}
+
+impl Default for Callbacks {
+    /// No data identifiers and no security access.
+    fn default() -> Self {
+        Callbacks {
+            read_data: |_, _, _| Err(Nrc::RequestOutOfRange),
+            session_change: None,
+            security_seed: None,
+            security_key: None,
+        }
+    }
+}
+
+/// Server timing and security settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Time without requests before returning to the default session, in ms
+    pub s3_ms: u32,
+    /// Response time reported to the tester (P2server_max), in ms
+    pub p2_ms: u16,
+    /// Extended response time reported to the tester (P2*server_max), in ms
+    pub p2_extended_ms: u32,
+    /// Wrong keys accepted before security access is delayed
+    pub max_attempts: u8,
+    /// Delay after too many wrong keys, in ms
+    pub lockout_ms: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            s3_ms: 5000,
+            p2_ms: 50,
+            p2_extended_ms: 5000,
+            max_attempts: 3,
+            lockout_ms: 10_000,
+        }
+    }
+}
+
+// Everything except the ISO-TP channel, so requests can borrow the channel
+// while being handled
+struct State {
+    config: Config,
+    callbacks: Callbacks,
+    session: Session,
+    security_level: u8,
+    // Level of the seed last sent, 0 if none
+    seed_level: u8,
+    seed: [u8; MAX_SEED],
+    seed_len: usize,
+    attempts: u8,
+    locked_until: Option<u32>,
+    last_request: u32,
+}
+
+/// UDS server on one ISO-TP channel.
+pub struct Server {
+    tp: IsoTp,
+    state: State,
+    response: [u8; MAX_PAYLOAD],
+}
+
+impl Server {
+    pub fn new(tp_config: isotp::Config, config: Config, callbacks: Callbacks) -> Self {
+        Self {
+            tp: IsoTp::new(tp_config),
+            state: State {
+                config,
+                callbacks,
+                session: Session::Default,
+                security_level: 0,
+                seed_level: 0,
+                seed: [0; MAX_SEED],
+                seed_len: 0,
+                attempts: 0,
+                locked_until: None,
+                last_request: 0,
+            },
+            response: [0; MAX_PAYLOAD],
+        }
+    }
+
+    /// Current session and security level.
+    pub fn status(&self) -> Status {
+        self.state.status()
+    }
+
+    /// The ISO-TP channel.
+    pub fn transport(&mut self) -> &mut IsoTp {
+        &mut self.tp
+    }
+
+    /// Receive and answer requests and handle the session timeout.
+    ///
+    /// Frames with other IDs are discarded, as in [`IsoTp::poll`].
+    pub fn poll(&mut self, can: &Can, now_ms: u32) -> Result<(), isotp::Error> {
+        self.state.check_timeout(now_ms);
+        let len = match self.tp.poll(can, now_ms)? {
+            Some(request) => self.state.handle(request, &mut self.response, now_ms),
+            None => return Ok(()),
+        };
+        self.respond(len)
+    }
+
+    /// Handle a frame read by the application, for buses shared with other
+    /// users. [`poll_tx`](Self::poll_tx) must also be called regularly.
+    pub fn on_frame(&mut self, frame: &Frame, now_ms: u32) -> Result<(), isotp::Error> {
+        self.state.check_timeout(now_ms);
+        let len = match self.tp.on_frame(frame, now_ms)? {
+            Some(request) => self.state.handle(request, &mut self.response, now_ms),
+            None => return Ok(()),
+        };
+        self.respond(len)
+    }
+
+    /// Send pending frames, see [`IsoTp::poll_tx`].
+    pub fn poll_tx(&mut self, can: &Can, now_ms: u32) -> Result<(), isotp::Error> {
+        self.state.check_timeout(now_ms);
+        self.tp.poll_tx(can, now_ms)
+    }
+
+    fn respond(&mut self, len: usize) -> Result<(), isotp::Error> {
+        if len > 0 {
+            self.tp.send(&self.response[..len])?;
+        }
+        Ok(())
+    }
+}
+
+impl State {
+    fn status(&self) -> Status {
+        Status {
+            session: self.session,
+            security_level: self.security_level,
+        }
+    }
+
+    fn check_timeout(&mut self, now_ms: u32) {
+        if self.session != Session::Default
+            && now_ms.wrapping_sub(self.last_request) > self.config.s3_ms
+        {
+            if let Some(change) = self.callbacks.session_change {
+                let _ = change(self.session, Session::Default);
+            }
+            self.enter_session(Session::Default);
+        }
+        if let Some(until) = self.locked_until {
+            if (now_ms.wrapping_sub(until) as i32) >= 0 {
+                self.locked_until = None;
+                self.attempts = 0;
+            }
+        }
+    }
+
+    fn enter_session(&mut self, session: Session) {
+        self.session = session;
+        self.security_level = 0;
+        self.seed_level = 0;
+    }
+
+    // Write the response and return its length, 0 for no response
+    fn handle(&mut self, request: &[u8], response: &mut [u8], now_ms: u32) -> usize {
+        let Some(&sid) = request.first() else {
+            return 0;
+        };
+        self.last_request = now_ms;
+        let result = match sid {
+            DIAGNOSTIC_SESSION_CONTROL => self.session_control(request, response),
+            TESTER_PRESENT => self.tester_present(request, response),
+            READ_DATA_BY_IDENTIFIER => self.read_data(request, response),
+            SECURITY_ACCESS => self.security_access(request, response, now_ms),
+            _ => Err(Nrc::ServiceNotSupported),
+        };
+        match result {
+            Ok(len) => len,
+            Err(nrc) => {
+                response[..3].copy_from_slice(&[NEGATIVE_RESPONSE, sid, nrc as u8]);
+                3
+            }
+        }
+    }
+
+    fn session_control(&mut self, request: &[u8], response: &mut [u8]) -> Result<usize, Nrc> {
+        let [_, sub] = request else {
+            return Err(Nrc::IncorrectMessageLength);
+        };
+        let session =
+            Session::from_u8(sub & !SUPPRESS_POSITIVE).ok_or(Nrc::SubFunctionNotSupported)?;
+        if let Some(change) = self.callbacks.session_change {
+            change(self.session, session)?;
+        }
+        self.enter_session(session);
+
+        if sub & SUPPRESS_POSITIVE != 0 {
+            return Ok(0);
+        }
+        let p2 = self.config.p2_ms.to_be_bytes();
+        // P2* is sent in units of 10 ms
+        let p2_extended = ((self.config.p2_extended_ms / 10).min(0xFFFF) as u16).to_be_bytes();
+        response[..6].copy_from_slice(&[
+            DIAGNOSTIC_SESSION_CONTROL + POSITIVE,
+            session as u8,
+            p2[0],
+            p2[1],
+            p2_extended[0],
+            p2_extended[1],
+        ]);
+        Ok(6)
+    }
+
+    fn tester_present(&mut self, request: &[u8], response: &mut [u8]) -> Result<usize, Nrc> {
+        let [_, sub] = request else {
+            return Err(Nrc::IncorrectMessageLength);
+        };
+        match sub & !SUPPRESS_POSITIVE {
+            0 if sub & SUPPRESS_POSITIVE != 0 => Ok(0),
+            0 => {
+                response[..2].copy_from_slice(&[TESTER_PRESENT + POSITIVE, 0]);
+                Ok(2)
+            }
+            _ => Err(Nrc::SubFunctionNotSupported),
+        }
+    }
+
+    fn read_data(&mut self, request: &[u8], response: &mut [u8]) -> Result<usize, Nrc> {
+        let dids = &request[1..];
+        if dids.is_empty() || dids.len() % 2 != 0 {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        response[0] = READ_DATA_BY_IDENTIFIER + POSITIVE;
+        let mut len = 1;
+        for did in dids.chunks_exact(2) {
+            if response.len() - len < 2 {
+                return Err(Nrc::ResponseTooLong);
+            }
+            response[len..len + 2].copy_from_slice(did);
+            len += 2;
+            let did = u16::from_be_bytes([did[0], did[1]]);
+            let buf = &mut response[len..];
+            let room = buf.len();
+            let written = (self.callbacks.read_data)(did, self.status(), buf)?;
+            // A length past the buffer is a bug in the callback, not sent
+            if written > room {
+                return Err(Nrc::ResponseTooLong);
+            }
+            len += written;
+        }
+        Ok(len)
+    }
+
+    fn security_access(
+        &mut self,
+        request: &[u8],
+        response: &mut [u8],
+        now_ms: u32,
+    ) -> Result<usize, Nrc> {
+        let (Some(seed_fn), Some(key_fn)) =
+            (self.callbacks.security_seed, self.callbacks.security_key)
+        else {
+            return Err(Nrc::ServiceNotSupported);
+        };
+        if self.session == Session::Default {
+            return Err(Nrc::ServiceNotSupportedInActiveSession);
+        }
+        let Some(&sub) = request.get(1) else {
+            return Err(Nrc::IncorrectMessageLength);
+        };
+        if sub == 0 || sub > 0x7E {
+            return Err(Nrc::SubFunctionNotSupported);
+        }
+
+        if sub % 2 == 1 {
+            // Request seed
+            if request.len() != 2 {
+                return Err(Nrc::IncorrectMessageLength);
+            }
+            if self.locked_until.is_some() {
+                return Err(Nrc::RequiredTimeDelayNotExpired);
+            }
+            response[..2].copy_from_slice(&[SECURITY_ACCESS + POSITIVE, sub]);
+            if self.security_level == sub {
+                // Already unlocked, answered with a zero seed
+                response[2..6].fill(0);
+                self.seed_level = 0;
+                return Ok(6);
+            }
+            let len = seed_fn(sub, &mut self.seed)?.min(MAX_SEED);
+            self.seed_len = len;
+            self.seed_level = sub;
+            response[2..2 + len].copy_from_slice(&self.seed[..len]);
+            Ok(2 + len)
+        } else {
+            // Send key
+            let level = sub - 1;
+            if self.seed_level != level {
+                return Err(Nrc::RequestSequenceError);
+            }
+            self.seed_level = 0;
+            if !key_fn(level, &self.seed[..self.seed_len], &request[2..]) {
+                self.attempts = self.attempts.saturating_add(1);
+                if self.attempts >= self.config.max_attempts {
+                    self.locked_until = Some(now_ms.wrapping_add(self.config.lockout_ms));
+                    return Err(Nrc::ExceededNumberOfAttempts);
+                }
+                return Err(Nrc::InvalidKey);
+            }
+            self.attempts = 0;
+            self.security_level = level;
+            response[..2].copy_from_slice(&[SECURITY_ACCESS + POSITIVE, sub]);
+            Ok(2)
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! WS2812 / NeoPixel LED strips on an SPI MOSI pin.
+//!
+//! Each bit of colour is encoded as four SPI bits at 3 MHz, `1000` for a 0
+//! and `1100` for a 1, so one SPI byte carries two LED bits and always ends
+//! low. The SPI inserts a short delay between bytes, which only stretches
+//! the low part of a bit. A DMAC channel feeds the SPI from the encoded
+//! buffer, so interrupts stay enabled for the whole strip and no handlers
+//! are needed. The buffer ends in zeros to hold the line low for the reset.
+//!
+//! ```ignore
+//! let buffer = cortex_m::singleton!(: [u8; ws2812::buffer_len(8)] = [0; ws2812::buffer_len(8)])
+//!     .unwrap();
+//! let mut strip = Ws2812::new(p.SPI0, pins.p101, p.DMAC1, buffer);
+//! strip.write(&[RGB8::new(255, 0, 0); 8])?;
+//! ```
+//!
+//! Only MOSI is routed, the clock pin of the channel stays free.
+
+use core::marker::PhantomData;
+
+use crate::clk::{ClockGuard, Clocks};
+use crate::dmac::{self, Channel};
+use crate::spi::{self, Instance, MosiPin};
+
+/// SPI bytes per LED, 24 bits of colour at two bits per byte.
+pub const BYTES_PER_LED: usize = 12;
+
+/// Zero bytes after the colours, over 300 us low for the reset.
+pub const RESET_BYTES: usize = 120;
+
+/// SPI bit rate, four SPI bits per LED bit give the 800 kHz LED rate.
+const BIT_RATE: u32 = 3_000_000;
+
+// SPCR, master (MSTR), transmit only (TXMD), clock synchronous (SPMS)
+const SPCR_SPMS: u8 = 1 << 0;
+const SPCR_TXMD: u8 = 1 << 1;
+const SPCR_MSTR: u8 = 1 << 3;
+const SPCR_SPTIE: u8 = 1 << 5;
+const SPCR_SPE: u8 = 1 << 6;
+// SPPCR.MOIFE, MOSI idles at MOIFV = 0
+const SPPCR_MOIFE: u8 = 1 << 5;
+// SPDCR.SPBYT, SPDR is accessed in bytes
+const SPDCR_SPBYT: u8 = 1 << 6;
+// SPCMD0.SPB, 8 bit data
+const SPCMD_SPB_8: u16 = 0b0111 << 8;
+// SPSR.IDLNF, a transfer is in progress
+const SPSR_IDLNF: u8 = 1 << 1;
+
+// SPI bits for a pair of LED bits, indexed by the pair
+const PAIRS: [u8; 4] = [0b1000_1000, 0b1000_1100, 0b1100_1000, 0b1100_1100];
+
+/// Length of the buffer for `leds` LEDs.
+pub const fn buffer_len(leds: usize) -> usize {
+    leds * BYTES_PER_LED + RESET_BYTES
+}
+
+/// Colour of one LED.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RGB8 {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl RGB8 {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        RGB8 { r, g, b }
+    }
+}
+
+/// WS2812 error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The buffer is too short for the colours, see [`buffer_len`]
+    BufferSize,
+}
+
+/// A strip of WS2812 LEDs on the MOSI pin of SPI channel `T`.
+pub struct Ws2812<T: Instance, C: Channel> {
+    buffer: &'static mut [u8],
+    _clock: ClockGuard,
+    _phantom: PhantomData<(T, C)>,
+}
+
+impl<T: Instance, C: Channel> Ws2812<T, C> {
+    /// Set up the SPI as a transmit only master at 3 MHz from the current
+    /// PCLKA. The strip is written from `buffer`, see [`buffer_len`].
+    pub fn new<MOSI: MosiPin<T>>(
+        _instance: T,
+        _mosi: MOSI,
+        _dma: C,
+        buffer: &'static mut [u8],
+    ) -> Self {
+        let clock = spi::init::<T>();
+        dmac::enable();
+        spi::connect_pin::<MOSI>();
+
+        let spi = unsafe { &*T::peripheral() };
+        let spbr = (Clocks::read().pclka() / (2 * BIT_RATE)).clamp(1, 256) - 1;
+        spi.spbr.write(|w| unsafe { w.bits(spbr as u8) });
+        spi.sppcr.write(|w| unsafe { w.bits(SPPCR_MOIFE) });
+        spi.spdcr.write(|w| unsafe { w.bits(SPDCR_SPBYT) });
+        spi.spscr.write(|w| unsafe { w.bits(0) });
+        spi.spcr2.write(|w| unsafe { w.bits(0) });
+        spi.spcmd0.write(|w| unsafe { w.bits(SPCMD_SPB_8) });
+        spi.spcr
+            .write(|w| unsafe { w.bits(SPCR_MSTR | SPCR_TXMD | SPCR_SPMS) });
+        // SPTI activates the DMA
+        dmac::link_event::<C>(T::event_base().offset(1));
+
+        buffer.fill(0);
+        Self {
+            buffer,
+            _clock: clock,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Send `colors` to the strip, the first to the LED nearest the pin.
+    ///
+    /// Waits for the previous write to finish, including its reset, then
+    /// returns as soon as the transfer has started.
+    pub fn write(&mut self, colors: &[RGB8]) -> Result<(), Error> {
+        let len = buffer_len(colors.len());
+        if len > self.buffer.len() || len > dmac::MAX_TRANSFER {
+            return Err(Error::BufferSize);
+        }
+        self.wait();
+
+        for (color, out) in colors
+            .iter()
+            .zip(self.buffer.chunks_exact_mut(BYTES_PER_LED))
+        {
+            // Green, red then blue, most significant bit first
+            for (byte, value) in [color.g, color.r, color.b].iter().enumerate() {
+                for pair in 0..4 {
+                    out[byte * 4 + pair] = PAIRS[((value >> (6 - 2 * pair)) & 0b11) as usize];
+                }
+            }
+        }
+        self.buffer[len - RESET_BYTES..len].fill(0);
+
+        let spi = unsafe { &*T::peripheral() };
+        spi.spcr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(SPCR_SPE | SPCR_SPTIE)) });
+        // Safety: the buffer is 'static and held by self, `wait` stops
+        // writes to it until the transfer is finished
+        unsafe { dmac::start_write::<C>(self.buffer.as_ptr(), spi::spdr::<T>(), len) };
+        // Setting SPE with SPTIE loads the first byte through the DMAC
+        spi.spcr
+            .modify(|r, w| unsafe { w.bits(r.bits() | SPCR_SPE | SPCR_SPTIE) });
+        Ok(())
+    }
+
+    /// True while a write is being sent.
+    pub fn is_busy(&self) -> bool {
+        let spi = unsafe { &*T::peripheral() };
+        dmac::remaining(C::index()) != 0 || spi.spsr.read().bits() & SPSR_IDLNF != 0
+    }
+
+    /// Wait for the previous write to finish.
+    pub fn wait(&self) {
+        while self.is_busy() {}
+    }
+}
+
+impl<T: Instance, C: Channel> Drop for Ws2812<T, C> {
+    fn drop(&mut self) {
+        self.wait();
+        let spi = unsafe { &*T::peripheral() };
+        spi.spcr.write(|w| unsafe { w.bits(0) });
+        dmac::stop(C::index());
+    }
+}
@@ -0,0 +1,567 @@
+//! XMODEM and YMODEM file transfer over a serial port.
+//!
+//! Lets terminal programs (Tera Term, minicom, `sx` / `sb` from lrzsz)
+//! push files to the board. The [`Receiver`] asks for CRC-16 blocks and
+//! falls back to the 8-bit checksum for old XMODEM senders, accepts 128 and
+//! 1024 byte blocks, and in YMODEM mode reports each file's name and size.
+//! The [`Sender`] sends XMODEM-CRC, e.g. to read a log back.
+//!
+//! Both are polled with the time in milliseconds. A received block is only
+//! acknowledged at the next [`poll`](Receiver::poll), so the sender waits
+//! while the data is written, e.g. while a [`fwupdate`](crate::fwupdate)
+//! block is erased:
+//!
+//! ```ignore
+//! let mut rx = xmodem::Receiver::new(uart, Mode::Ymodem);
+//! while !rx.is_done() {
+//!     match rx.poll(ticker::millis())? {
+//!         Some(Event::File { size, .. }) => updater.begin(size.ok_or(MissingSize)?)?,
+//!         Some(Event::Data(data)) => {
+//!             if updater.write(data).is_err() {
+//!                 rx.cancel()?;
+//!             }
+//!         }
+//!         Some(Event::End) => updater.finish(crc)?,
+//!         None => {}
+//!     }
+//! }
+//! ```
+//!
+//! XMODEM has no file size, the last block is padded with 0x1A.
+
+use embedded_io::{Read, ReadReady, Write};
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const CRC_REQUEST: u8 = b'C';
+const PAD: u8 = 0x1A;
+
+/// Data in a 128 byte block, also the size of every [`Sender`] block.
+pub const BLOCK: usize = 128;
+/// Data in a 1024 byte block.
+pub const BLOCK_1K: usize = 1024;
+
+// Start, block number and its complement, data, CRC
+const MAX_PACKET: usize = 3 + BLOCK_1K + 2;
+
+/// Interval between the requests starting a transfer.
+pub const START_INTERVAL_MS: u32 = 3_000;
+/// Wait for the next byte of a block before asking for it again.
+pub const BYTE_TIMEOUT_MS: u32 = 1_000;
+/// Wait for the next block, or an acknowledgement, before retrying.
+pub const BLOCK_TIMEOUT_MS: u32 = 10_000;
+
+// Requests sent while waiting for the sender, a minute
+const START_TRIES: u8 = 20;
+// CRC requests before falling back to checksums, as in the XMODEM-CRC spec
+const CRC_TRIES: u8 = 3;
+// Retries of a block
+const MAX_RETRIES: u8 = 10;
+// CAN bytes sent to cancel, the other side needs two in a row
+const CANCEL: [u8; 5] = [CAN; 5];
+
+/// XMODEM error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// Reading or writing the serial port failed
+    Port,
+    /// Nothing was received after the retries, the transfer is cancelled
+    Timeout,
+    /// The other side cancelled the transfer
+    Cancelled,
+    /// A block was skipped, the transfer is cancelled
+    Sequence,
+}
+
+/// Transfer protocol of a [`Receiver`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Mode {
+    /// One file without a name or size
+    Xmodem,
+    /// Batches of files, each with a header block
+    Ymodem,
+}
+
+/// Progress of a [`Receiver`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event<'a> {
+    /// YMODEM header of the next file, the size if the sender gave it
+    File { name: &'a str, size: Option<u32> },
+    /// The next data of the file, without the padding past a YMODEM size
+    Data(&'a [u8]),
+    /// The file is complete
+    End,
+}
+
+// Event without the borrow of the block buffer
+#[derive(Clone, Copy)]
+enum Received {
+    File,
+    Data(usize),
+    End,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // Asking for the first block, or the next YMODEM header
+    Start,
+    Blocks,
+    Done,
+}
+
+/// Receives files on serial port `P`.
+pub struct Receiver<P: Read + ReadReady + Write> {
+    port: P,
+    ymodem: bool,
+    crc: bool,
+    state: State,
+    packet: [u8; MAX_PACKET],
+    len: usize,
+    expected: u8,
+    retries: u8,
+    cancels: u8,
+    last_ms: Option<u32>,
+    // Size left of the YMODEM file
+    remaining: Option<u32>,
+    // Sent at the next poll, once the caller has the block
+    reply: &'static [u8],
+    // First YMODEM EOT, answered with NAK
+    eot: bool,
+}
+
+impl<P: Read + ReadReady + Write> Receiver<P> {
+    /// Receive on `port`, the first request is sent by the first
+    /// [`poll`](Self::poll).
+    pub fn new(port: P, mode: Mode) -> Self {
+        let ymodem = mode == Mode::Ymodem;
+        Self {
+            port,
+            ymodem,
+            crc: true,
+            state: State::Start,
+            packet: [0; MAX_PACKET],
+            len: 0,
+            expected: if ymodem { 0 } else { 1 },
+            retries: 0,
+            cancels: 0,
+            last_ms: None,
+            remaining: None,
+            reply: &[],
+            eot: false,
+        }
+    }
+
+    /// Handle the bytes received, returning the next event.
+    ///
+    /// Call until [`is_done`](Self::is_done), an error ends the transfer.
+    pub fn poll(&mut self, time_ms: u32) -> Result<Option<Event<'_>>, Error> {
+        if self.state == State::Done {
+            return Ok(None);
+        }
+        if !self.reply.is_empty() {
+            let reply = core::mem::take(&mut self.reply);
+            self.send(reply)?;
+            self.last_ms = Some(time_ms);
+        }
+        while self.port.read_ready().map_err(|_| Error::Port)? {
+            let mut byte = [0u8; 1];
+            if self.port.read(&mut byte).map_err(|_| Error::Port)? == 0 {
+                break;
+            }
+            self.last_ms = Some(time_ms);
+            if let Some(received) = self.receive(byte[0])? {
+                return Ok(Some(self.event(received)));
+            }
+        }
+        if self.state == State::Done {
+            return Ok(None);
+        }
+
+        let timeout = match self.state {
+            State::Start => START_INTERVAL_MS,
+            _ if self.len > 0 => BYTE_TIMEOUT_MS,
+            _ => BLOCK_TIMEOUT_MS,
+        };
+        let idle = self.last_ms.map(|last| time_ms.wrapping_sub(last));
+        if idle.is_none_or(|idle| idle >= timeout) {
+            self.last_ms = Some(time_ms);
+            self.len = 0;
+            if idle.is_some() {
+                self.retries += 1;
+                let tries = if self.state == State::Start {
+                    START_TRIES
+                } else {
+                    MAX_RETRIES
+                };
+                if self.retries >= tries {
+                    self.cancel()?;
+                    return Err(Error::Timeout);
+                }
+            }
+            if self.state == State::Start {
+                if !self.ymodem && self.retries == CRC_TRIES {
+                    self.crc = false;
+                }
+                self.send(&[if self.crc { CRC_REQUEST } else { NAK }])?;
+            } else {
+                self.send(&[NAK])?;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether the transfer is over, after the XMODEM file or the YMODEM
+    /// batch, or after an error or [`cancel`](Self::cancel).
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Cancel the transfer, e.g. when the data can't be stored.
+    pub fn cancel(&mut self) -> Result<(), Error> {
+        self.state = State::Done;
+        self.send(&CANCEL)
+    }
+
+    /// Release the serial port.
+    pub fn free(self) -> P {
+        self.port
+    }
+
+    fn receive(&mut self, byte: u8) -> Result<Option<Received>, Error> {
+        if self.len == 0 {
+            // Between blocks
+            if byte == CAN {
+                self.cancels += 1;
+                if self.cancels >= 2 {
+                    self.state = State::Done;
+                    return Err(Error::Cancelled);
+                }
+                return Ok(None);
+            }
+            self.cancels = 0;
+            match byte {
+                SOH | STX => {
+                    self.packet[0] = byte;
+                    self.len = 1;
+                }
+                EOT => return self.end_of_file(),
+                // Noise, or the rest of a block that was given up on
+                _ => {}
+            }
+            return Ok(None);
+        }
+        self.packet[self.len] = byte;
+        self.len += 1;
+        if self.len < 3 + self.data_len() + if self.crc { 2 } else { 1 } {
+            return Ok(None);
+        }
+        self.len = 0;
+        self.check_packet()
+    }
+
+    fn data_len(&self) -> usize {
+        if self.packet[0] == STX {
+            BLOCK_1K
+        } else {
+            BLOCK
+        }
+    }
+
+    // Check and acknowledge the complete packet
+    fn check_packet(&mut self) -> Result<Option<Received>, Error> {
+        let data_len = self.data_len();
+        let number = self.packet[1];
+        let (data, check) = self.packet[3..].split_at(data_len);
+        let valid = number == !self.packet[2]
+            && if self.crc {
+                crc16(data) == u16::from_be_bytes([check[0], check[1]])
+            } else {
+                checksum(data) == check[0]
+            };
+        if !valid {
+            return self.retry();
+        }
+        if self.state == State::Blocks && number == self.expected.wrapping_sub(1) {
+            // Our acknowledgement was lost, the block was sent again
+            self.send(&[ACK])?;
+            return Ok(None);
+        }
+        if number != self.expected {
+            self.cancel()?;
+            return Err(Error::Sequence);
+        }
+        self.retries = 0;
+        self.expected = self.expected.wrapping_add(1);
+
+        if self.ymodem && self.state == State::Start {
+            if self.packet[3] == 0 {
+                // Empty header, the batch is complete
+                self.send(&[ACK])?;
+                self.state = State::Done;
+                return Ok(None);
+            }
+            self.state = State::Blocks;
+            self.remaining = header_size(&self.packet[3..3 + data_len]);
+            self.reply = &[ACK, CRC_REQUEST];
+            return Ok(Some(Received::File));
+        }
+        self.state = State::Blocks;
+        let mut len = data_len;
+        if let Some(remaining) = &mut self.remaining {
+            len = len.min(*remaining as usize);
+            *remaining -= len as u32;
+        }
+        self.reply = &[ACK];
+        Ok(Some(Received::Data(len)))
+    }
+
+    fn retry(&mut self) -> Result<Option<Received>, Error> {
+        self.retries += 1;
+        if self.retries >= MAX_RETRIES {
+            self.cancel()?;
+            return Err(Error::Timeout);
+        }
+        self.send(&[NAK])?;
+        Ok(None)
+    }
+
+    fn end_of_file(&mut self) -> Result<Option<Received>, Error> {
+        if self.state != State::Blocks {
+            return Ok(None);
+        }
+        // YMODEM senders expect the first EOT to be refused
+        if self.ymodem && !self.eot {
+            self.eot = true;
+            self.send(&[NAK])?;
+            return Ok(None);
+        }
+        self.eot = false;
+        self.send(&[ACK])?;
+        if self.ymodem {
+            // Ask for the next header straight away
+            self.state = State::Start;
+            self.expected = 0;
+            self.remaining = None;
+            self.last_ms = None;
+        } else {
+            self.state = State::Done;
+        }
+        Ok(Some(Received::End))
+    }
+
+    fn event(&self, received: Received) -> Event<'_> {
+        match received {
+            Received::File => {
+                let header = &self.packet[3..3 + self.data_len()];
+                let name_len = header.iter().position(|&b| b == 0).unwrap_or(0);
+                Event::File {
+                    name: core::str::from_utf8(&header[..name_len]).unwrap_or(""),
+                    size: header_size(header),
+                }
+            }
+            Received::Data(len) => Event::Data(&self.packet[3..3 + len]),
+            Received::End => Event::End,
+        }
+    }
+
+    fn send(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.port.write_all(bytes).map_err(|_| Error::Port)
+    }
+}
+
+// Decimal size after the name in a YMODEM header
+fn header_size(header: &[u8]) -> Option<u32> {
+    let name_len = header.iter().position(|&b| b == 0)?;
+    let digits = &header[name_len + 1..];
+    let end = digits
+        .iter()
+        .position(|b| !b.is_ascii_digit())
+        .unwrap_or(digits.len());
+    core::str::from_utf8(&digits[..end]).ok()?.parse().ok()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SendState {
+    // Waiting for the receiver to ask for CRC or checksum blocks
+    Start,
+    Block,
+    Eot,
+    Done,
+}
+
+/// Sends one file with XMODEM-CRC on serial port `P`, in 128 byte blocks.
+///
+/// ```ignore
+/// let mut tx = xmodem::Sender::new(uart);
+/// let mut rest = &log[..];
+/// while !tx.poll(ticker::millis(), |block| {
+///     let n = rest.len().min(block.len());
+///     block[..n].copy_from_slice(&rest[..n]);
+///     rest = &rest[n..];
+///     n
+/// })? {}
+/// ```
+pub struct Sender<P: Read + ReadReady + Write> {
+    port: P,
+    crc: bool,
+    state: SendState,
+    block: [u8; BLOCK],
+    number: u8,
+    retries: u8,
+    cancels: u8,
+    last_ms: Option<u32>,
+}
+
+impl<P: Read + ReadReady + Write> Sender<P> {
+    /// Send on `port` once the receiver starts the transfer.
+    pub fn new(port: P) -> Self {
+        Self {
+            port,
+            crc: true,
+            state: SendState::Start,
+            block: [0; BLOCK],
+            number: 0,
+            retries: 0,
+            cancels: 0,
+            last_ms: None,
+        }
+    }
+
+    /// Handle the replies received, returning whether the file is sent.
+    ///
+    /// `fill` copies the next data into the block it's given and returns
+    /// the length, 0 at the end of the file.
+    pub fn poll(
+        &mut self,
+        time_ms: u32,
+        mut fill: impl FnMut(&mut [u8]) -> usize,
+    ) -> Result<bool, Error> {
+        if self.state == SendState::Done {
+            return Ok(true);
+        }
+        let last = *self.last_ms.get_or_insert(time_ms);
+        while self.port.read_ready().map_err(|_| Error::Port)? {
+            let mut byte = [0u8; 1];
+            if self.port.read(&mut byte).map_err(|_| Error::Port)? == 0 {
+                break;
+            }
+            self.last_ms = Some(time_ms);
+            if byte[0] == CAN {
+                self.cancels += 1;
+                if self.cancels >= 2 {
+                    self.state = SendState::Done;
+                    return Err(Error::Cancelled);
+                }
+                continue;
+            }
+            self.cancels = 0;
+            match (self.state, byte[0]) {
+                (SendState::Start, CRC_REQUEST | NAK) => {
+                    self.crc = byte[0] == CRC_REQUEST;
+                    self.next_block(&mut fill)?;
+                }
+                (SendState::Block, ACK) => {
+                    self.retries = 0;
+                    self.next_block(&mut fill)?;
+                }
+                (SendState::Block, NAK) => self.resend()?,
+                (SendState::Eot, ACK) => {
+                    self.state = SendState::Done;
+                    return Ok(true);
+                }
+                (SendState::Eot, NAK) => self.resend()?,
+                _ => {}
+            }
+        }
+
+        let timeout = match self.state {
+            SendState::Start => START_INTERVAL_MS * START_TRIES as u32,
+            _ => BLOCK_TIMEOUT_MS,
+        };
+        if self.last_ms == Some(last) && time_ms.wrapping_sub(last) >= timeout {
+            self.last_ms = Some(time_ms);
+            if self.state == SendState::Start {
+                self.state = SendState::Done;
+                return Err(Error::Timeout);
+            }
+            self.resend()?;
+        }
+        Ok(false)
+    }
+
+    /// Cancel the transfer.
+    pub fn cancel(&mut self) -> Result<(), Error> {
+        self.state = SendState::Done;
+        self.port.write_all(&CANCEL).map_err(|_| Error::Port)
+    }
+
+    /// Release the serial port.
+    pub fn free(self) -> P {
+        self.port
+    }
+
+    fn next_block(&mut self, fill: &mut impl FnMut(&mut [u8]) -> usize) -> Result<(), Error> {
+        let len = fill(&mut self.block).min(BLOCK);
+        if len == 0 {
+            self.state = SendState::Eot;
+        } else {
+            self.block[len..].fill(PAD);
+            self.number = self.number.wrapping_add(1);
+            self.state = SendState::Block;
+        }
+        self.send()
+    }
+
+    // Send the block or EOT again
+    fn resend(&mut self) -> Result<(), Error> {
+        self.retries += 1;
+        if self.retries >= MAX_RETRIES {
+            self.cancel()?;
+            return Err(Error::Timeout);
+        }
+        self.send()
+    }
+
+    fn send(&mut self) -> Result<(), Error> {
+        let result = if self.state == SendState::Eot {
+            self.port.write_all(&[EOT])
+        } else {
+            let header = [SOH, self.number, !self.number];
+            let check = if self.crc {
+                crc16(&self.block).to_be_bytes()
+            } else {
+                [checksum(&self.block), 0]
+            };
+            let check_len = if self.crc { 2 } else { 1 };
+            self.port
+                .write_all(&header)
+                .and_then(|()| self.port.write_all(&self.block))
+                .and_then(|()| self.port.write_all(&check[..check_len]))
+        };
+        result.map_err(|_| Error::Port)
+    }
+}
+
+/// CRC-16/XMODEM, polynomial 0x1021 from 0.
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, byte| {
+        (0..8).fold(crc ^ ((*byte as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+// 8-bit sum of the original XMODEM
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}